@@ -1,7 +1,7 @@
-use echo::node::EchoNode;
-use maelstrom::node::run_node;
+use echo::node::{EchoNode, Latency};
+use maelstrom::run_node;
 
 #[tokio::main]
 async fn main() {
-    run_node(EchoNode).await;
+    run_node(EchoNode::new().with_latency(Latency::from_env())).await;
 }
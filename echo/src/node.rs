@@ -1,73 +1,134 @@
-use maelstrom::{
-    Message, MessageBody,
-    node::{MessageHandler, Node},
-};
+use maelstrom::config::env_number;
+use maelstrom::{Message, MessageBody, MessageBodyDispatch, MessageHandler, Node};
+use rand::Rng;
+use std::time::Duration;
+
+/// Artificial latency for echo replies: `delay`, plus up to `jitter` more drawn per reply, so
+/// clients' timeouts and retries can be exercised against a slow node
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Latency {
+    pub delay: Duration,
+    pub jitter: Duration,
+}
+
+impl Latency {
+    /// Read from `ECHO_DELAY_MS` and `ECHO_JITTER_MS`; `None` when both are unset or zero
+    pub fn from_env() -> Option<Self> {
+        Self::from_lookup(|name| std::env::var(name).ok())
+    }
+
+    /// Like [`Latency::from_env`], reading variables through `lookup`; unparsable values are
+    /// logged as warnings and ignored
+    pub fn from_lookup(lookup: impl Fn(&str) -> Option<String>) -> Option<Self> {
+        let millis = |name: &str| env_number(&lookup, name).map(Duration::from_millis);
+        let latency = Self {
+            delay: millis("ECHO_DELAY_MS").unwrap_or_default(),
+            jitter: millis("ECHO_JITTER_MS").unwrap_or_default(),
+        };
+        (latency != Self::default()).then_some(latency)
+    }
+
+    /// How long to hold one reply back
+    fn sample(&self, rng: &mut impl Rng) -> Duration {
+        self.delay + self.jitter.mul_f64(rng.random::<f64>())
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct EchoNode {
+    latency: Option<Latency>,
+}
 
-pub struct EchoNode;
+impl EchoNode {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Hold each reply back by `latency`, on a `send_after` timer so simulated runs see the
+    /// delay too
+    pub fn with_latency(mut self, latency: Option<Latency>) -> Self {
+        self.latency = latency;
+        self
+    }
+}
 
 impl MessageHandler for EchoNode {
     fn handle(&mut self, node: &mut Node, message: Message) -> Vec<Message> {
-        let mut out: Vec<Message> = Vec::new();
-        match message.body {
-            MessageBody::Init {
+        self.dispatch(node, message)
+    }
+}
+
+impl MessageBodyDispatch for EchoNode {
+    fn on_init(
+        &mut self,
+        node: &mut Node,
+        src: String,
+        msg_id: u64,
+        node_id: String,
+        node_ids: Vec<String>,
+    ) -> Vec<Message> {
+        node.handle_init(node_id, node_ids);
+        vec![node.init_ok(src, msg_id)]
+    }
+
+    fn on_echo(&mut self, node: &mut Node, src: String, msg_id: u64, echo: String) -> Vec<Message> {
+        let reply = node.answer(src, msg_id, |msg_id, in_reply_to| MessageBody::EchoOk {
+            msg_id,
+            in_reply_to,
+            echo,
+        });
+        self.reply(node, reply)
+    }
+
+    fn on_echo_batch(
+        &mut self,
+        node: &mut Node,
+        src: String,
+        msg_id: u64,
+        echoes: Vec<String>,
+    ) -> Vec<Message> {
+        let reply = node.answer(src, msg_id, |msg_id, in_reply_to| {
+            MessageBody::EchoBatchOk {
                 msg_id,
-                node_id,
-                node_ids,
-            } => {
-                node.handle_init(node_id, node_ids);
-                out.push(node.init_ok(message.src, msg_id));
-            }
-            MessageBody::Echo { msg_id, echo } => {
-                let response_msg_id = node.next_msg_id();
-                out.push(node.reply(
-                    message.src,
-                    MessageBody::EchoOk {
-                        msg_id: response_msg_id,
-                        in_reply_to: msg_id,
-                        echo,
-                    },
-                ));
+                in_reply_to,
+                echoes,
             }
-            _ => {}
-        }
-        out
+        });
+        self.reply(node, reply)
+    }
+}
+
+impl EchoNode {
+    /// Send `reply` now, or after the configured latency
+    fn reply(&self, node: &mut Node, reply: Message) -> Vec<Message> {
+        let Some(latency) = self.latency else {
+            return vec![reply];
+        };
+        let delay = latency.sample(&mut node.rng);
+        node.send_after(delay, reply);
+        vec![]
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use maelstrom::sim::Sim;
+    use maelstrom_testkit::{self as testkit, TestNode, expect_body};
 
     #[test]
     fn test_echo_node_handles_init_message() {
-        let mut handler = EchoNode;
+        let mut handler = EchoNode::new();
         let mut node = Node::new();
 
-        let init_message = Message {
-            src: "c1".to_string(),
-            dest: "n1".to_string(),
-            body: MessageBody::Init {
-                msg_id: 1,
-                node_id: "n1".to_string(),
-                node_ids: vec!["n1".to_string(), "n2".to_string(), "n3".to_string()],
-            },
-        };
+        let init_message = testkit::init("n1", ["n1", "n2", "n3"]);
 
         let responses = handler.handle(&mut node, init_message);
 
-        assert_eq!(responses.len(), 1);
-        assert_eq!(responses[0].src, "n1");
-        assert_eq!(responses[0].dest, "c1");
+        testkit::assert_route(testkit::only(&responses), "n1", "c1");
 
-        match &responses[0].body {
-            MessageBody::InitOk {
-                msg_id: _,
-                in_reply_to,
-            } => {
-                assert_eq!(*in_reply_to, 1);
-            }
-            _ => panic!("Expected InitOk message"),
-        }
+        let in_reply_to = expect_body!(&responses[0].body, InitOk { in_reply_to, .. });
+        assert_eq!(*in_reply_to, 1);
 
         // Verify node state was updated
         assert_eq!(node.id, "n1");
@@ -76,49 +137,37 @@ mod tests {
 
     #[test]
     fn test_echo_node_handles_echo_message() {
-        let mut handler = EchoNode;
-        let mut node = Node::new();
-
-        // Initialize node first
-        node.handle_init("n1".to_string(), vec!["n1".to_string()]);
-
-        let echo_message = Message {
-            src: "c1".to_string(),
-            dest: "n1".to_string(),
-            body: MessageBody::Echo {
-                msg_id: 42,
-                echo: "Hello, World!".to_string(),
-            },
-        };
-
-        let responses = handler.handle(&mut node, echo_message);
-
-        assert_eq!(responses.len(), 1);
-        assert_eq!(responses[0].src, "n1");
-        assert_eq!(responses[0].dest, "c1");
+        TestNode::new(EchoNode::new())
+            .init(1)
+            .send("c1", testkit::echo("Hello, World!").with_msg_id(42))
+            .expect_reply(testkit::echo_ok("Hello, World!"))
+            .expect_quiet();
+    }
 
-        match &responses[0].body {
-            MessageBody::EchoOk {
-                msg_id: _,
-                in_reply_to,
-                echo,
-            } => {
-                assert_eq!(*in_reply_to, 42);
-                assert_eq!(echo, "Hello, World!");
-            }
-            _ => panic!("Expected EchoOk message"),
-        }
+    #[test]
+    fn test_echo_batch_answers_every_payload_in_one_reply() {
+        TestNode::new(EchoNode::new())
+            .init(1)
+            .send("c1", testkit::echo_batch(["a", "b", "a"]).with_msg_id(7))
+            .expect_reply(testkit::echo_batch_ok(["a", "b", "a"]))
+            .send("c1", testkit::echo_batch([]).with_msg_id(8))
+            .expect_reply(testkit::echo_batch_ok([]))
+            .expect_quiet();
     }
 
     #[test]
     fn test_echo_node_ignores_unknown_messages() {
-        let mut handler = EchoNode;
+        let mut handler = EchoNode::new();
         let mut node = Node::new();
 
         let unknown_message = Message {
             src: "c1".to_string(),
             dest: "n1".to_string(),
-            body: MessageBody::Generate { msg_id: 1 },
+            body: MessageBody::EchoOk {
+                msg_id: 1,
+                in_reply_to: 1,
+                echo: "test".to_string(),
+            },
         };
 
         let responses = handler.handle(&mut node, unknown_message);
@@ -128,96 +177,111 @@ mod tests {
 
     #[test]
     fn test_echo_node_multiple_echo_messages() {
-        let mut handler = EchoNode;
-        let mut node = Node::new();
-
+        let mut handler = EchoNode::new();
         // Initialize node first
-        node.handle_init("n1".to_string(), vec!["n1".to_string()]);
+        let mut node = testkit::node("n1", ["n1"]);
 
         // First echo
-        let echo1 = Message {
-            src: "c1".to_string(),
-            dest: "n1".to_string(),
-            body: MessageBody::Echo {
-                msg_id: 1,
-                echo: "First".to_string(),
-            },
-        };
+        let echo1 = testkit::echo("First");
 
         let responses1 = handler.handle(&mut node, echo1);
         assert_eq!(responses1.len(), 1);
 
         // Second echo
-        let echo2 = Message {
-            src: "c2".to_string(),
-            dest: "n1".to_string(),
-            body: MessageBody::Echo {
-                msg_id: 2,
-                echo: "Second".to_string(),
-            },
-        };
+        let echo2 = testkit::echo("Second").src("c2").with_msg_id(2);
 
         let responses2 = handler.handle(&mut node, echo2);
         assert_eq!(responses2.len(), 1);
 
         // Verify both responses are correct
-        match &responses1[0].body {
-            MessageBody::EchoOk {
-                msg_id: _,
+        let (in_reply_to, echo) = expect_body!(
+            &responses1[0].body,
+            EchoOk {
                 in_reply_to,
                 echo,
-            } => {
-                assert_eq!(*in_reply_to, 1);
-                assert_eq!(echo, "First");
+                ..
             }
-            _ => panic!("Expected EchoOk message"),
-        }
+        );
+        assert_eq!(*in_reply_to, 1);
+        assert_eq!(echo, "First");
 
-        match &responses2[0].body {
-            MessageBody::EchoOk {
-                msg_id: _,
+        let (in_reply_to, echo) = expect_body!(
+            &responses2[0].body,
+            EchoOk {
                 in_reply_to,
                 echo,
-            } => {
-                assert_eq!(*in_reply_to, 2);
-                assert_eq!(echo, "Second");
+                ..
             }
-            _ => panic!("Expected EchoOk message"),
-        }
+        );
+        assert_eq!(*in_reply_to, 2);
+        assert_eq!(echo, "Second");
     }
 
     #[test]
     fn test_echo_node_generates_unique_msg_ids() {
-        let mut handler = EchoNode;
-        let mut node = Node::new();
-
+        let mut handler = EchoNode::new();
         // Initialize node first
-        node.handle_init("n1".to_string(), vec!["n1".to_string()]);
+        let mut node = testkit::node("n1", ["n1"]);
 
-        let echo_message = Message {
-            src: "c1".to_string(),
-            dest: "n1".to_string(),
-            body: MessageBody::Echo {
-                msg_id: 1,
-                echo: "test".to_string(),
-            },
-        };
+        let echo_message = testkit::echo("test");
 
         let responses1 = handler.handle(&mut node, echo_message.clone());
         let responses2 = handler.handle(&mut node, echo_message);
 
         // Extract msg_ids from responses
-        let msg_id1 = match &responses1[0].body {
-            MessageBody::EchoOk { msg_id, .. } => *msg_id,
-            _ => panic!("Expected EchoOk message"),
-        };
+        let msg_id1 = *expect_body!(&responses1[0].body, EchoOk { msg_id, .. });
 
-        let msg_id2 = match &responses2[0].body {
-            MessageBody::EchoOk { msg_id, .. } => *msg_id,
-            _ => panic!("Expected EchoOk message"),
-        };
+        let msg_id2 = *expect_body!(&responses2[0].body, EchoOk { msg_id, .. });
 
         assert_ne!(msg_id1, msg_id2);
         assert_eq!(msg_id2, msg_id1 + 1);
     }
+
+    #[test]
+    fn test_latency_comes_from_the_environment() {
+        let lookup = |vars: &'static [(&'static str, &'static str)]| {
+            move |name: &str| {
+                vars.iter()
+                    .find(|(var, _)| *var == name)
+                    .map(|(_, value)| value.to_string())
+            }
+        };
+        assert_eq!(Latency::from_lookup(lookup(&[])), None);
+        assert_eq!(
+            Latency::from_lookup(lookup(&[("ECHO_DELAY_MS", "0")])),
+            None
+        );
+        assert_eq!(
+            Latency::from_lookup(lookup(&[("ECHO_DELAY_MS", "50"), ("ECHO_JITTER_MS", "x")])),
+            Some(Latency {
+                delay: Duration::from_millis(50),
+                jitter: Duration::ZERO,
+            })
+        );
+    }
+
+    #[test]
+    fn test_delayed_replies_arrive_after_the_latency() {
+        let latency = Latency {
+            delay: Duration::from_millis(20),
+            jitter: Duration::from_millis(10),
+        };
+        let mut sim = Sim::new(1, |_| EchoNode::new().with_latency(Some(latency)));
+
+        let sent = sim.now();
+        let reply = sim.call(testkit::echo("late")).unwrap();
+        let elapsed = sim.now() - sent;
+        assert!(elapsed >= latency.delay, "replied after {elapsed:?}");
+
+        let (in_reply_to, echo) = expect_body!(
+            &reply.body,
+            EchoOk {
+                in_reply_to,
+                echo,
+                ..
+            }
+        );
+        assert_eq!(*in_reply_to, 1);
+        assert_eq!(echo, "late");
+    }
 }
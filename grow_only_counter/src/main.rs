@@ -1,72 +1,7 @@
 use grow_only_counter::node::GrowOnlyCounterNode;
-use maelstrom::{
-    Message,
-    node::{MessageHandler, Node},
-};
-use std::io::Write as _;
-use tokio::{
-    io::{self, AsyncBufReadExt, BufReader},
-    sync::mpsc,
-    time::{Duration, interval},
-};
+use maelstrom::run_node;
 
 #[tokio::main]
 async fn main() {
-    let mut handler = GrowOnlyCounterNode::new();
-    let mut node = Node::new();
-    let (tx, mut rx) = mpsc::channel::<Message>(32);
-    let mut gossip_timer = interval(Duration::from_millis(100));
-
-    // Spawn stdin reader
-    let stdin_tx = tx.clone();
-    tokio::spawn(async move {
-        let reader = BufReader::new(io::stdin());
-        let mut lines = reader.lines();
-        while let Ok(Some(line)) = lines.next_line().await {
-            match serde_json::from_str::<Message>(&line) {
-                Ok(msg) => {
-                    if stdin_tx.send(msg).await.is_err() {
-                        break;
-                    }
-                }
-                Err(e) => eprintln!("decode error: {e:?} line={line}"),
-            }
-        }
-    });
-
-    loop {
-        tokio::select! {
-            _ = gossip_timer.tick() => {
-                let msgs = handler.gossip(&mut node);
-                for msg in msgs {
-                    match serde_json::to_vec(&msg) {
-                        Ok(mut bytes) => {
-                            bytes.push(b'\n');
-                            if let Err(e) = std::io::stdout().write_all(&bytes) {
-                                eprintln!("stdout write error: {e:?} for response: {:?}", msg);
-                            }
-                        }
-                        Err(e) => {
-                            eprintln!("serialize error: {e:?} for response: {:?}", msg);
-                        }
-                    }
-                }
-            }
-            Some(msg) = rx.recv() => {
-                for response in handler.handle(&mut node, msg) {
-                    match serde_json::to_vec(&response) {
-                        Ok(mut bytes) => {
-                            bytes.push(b'\n');
-                            if let Err(e) = std::io::stdout().write_all(&bytes) {
-                                eprintln!("stdout write error: {e:?} for response: {:?}", response);
-                            }
-                        }
-                        Err(e) => {
-                            eprintln!("serialize error: {e:?} for response: {:?}", response);
-                        }
-                    }
-                }
-            }
-        }
-    }
+    run_node(GrowOnlyCounterNode::new()).await;
 }
@@ -1,15 +1,19 @@
-use maelstrom::kv::{Counter, KV};
+use maelstrom::delta::DeltaState;
+use maelstrom::kv::{Counter, KV, NamedKV};
+use maelstrom::persist::{self, Persistent};
 use maelstrom::{
-    Message, MessageBody,
-    node::{MessageHandler, Node},
+    Message, MessageBody, MessageBodyDispatch, MessageHandler, Node, admin::Introspect,
 };
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
+use std::time::Duration;
+
+/// Name the workload's own counter goes by among the named ones
+const WORKLOAD: &str = "";
 
 pub struct GrowOnlyCounterNode {
-    /// Key-value store
-    kv: KV,
-    /// For each peer, what versions we believe they already know per node_id
-    peer_known_versions: HashMap<String, HashMap<String, u64>>,
+    /// Every counter, the workload's under [`WORKLOAD`] and the rest by name, with the
+    /// entries each peer has yet to acknowledge
+    kv: DeltaState<NamedKV>,
 }
 
 impl Default for GrowOnlyCounterNode {
@@ -21,134 +25,449 @@ impl Default for GrowOnlyCounterNode {
 impl GrowOnlyCounterNode {
     pub fn new() -> Self {
         Self {
-            kv: KV::new(),
-            peer_known_versions: HashMap::new(),
+            kv: DeltaState::new(NamedKV::new(), &[]),
         }
     }
 
+    /// Send every peer the entries it has not acknowledged, until it does
     pub fn gossip(&mut self, node: &mut Node) -> Vec<Message> {
         let mut out: Vec<Message> = Vec::new();
-        if node.id.is_empty() || node.peers.is_empty() || self.kv.is_empty() {
+        if node.id.is_empty() {
             return out;
         }
 
-        let peers = node.peers.clone();
-        for peer in peers.iter() {
-            let peer_versions = self.peer_known_versions.entry(peer.clone()).or_default();
-
-            // Compute versioned delta for this peer
-            let mut delta: HashMap<String, Counter> = HashMap::new();
-            for (node_id, counter) in self.kv.counters.iter() {
-                let known_version = peer_versions.get(node_id).copied().unwrap_or(0);
-                if counter.version > known_version {
-                    delta.insert(node_id.clone(), counter.clone());
-                }
-            }
-
-            if delta.is_empty() {
+        // Peers that joined since are owed everything, and departed ones nothing
+        self.kv.set_peers(&node.peers);
+        for peer in node.peers.clone() {
+            let Some((seq, delta)) = self.kv.outgoing(&peer) else {
                 continue;
-            }
-
-            // Update what we believe peer knows (optimistically) to avoid resending unchanged
-            for (node_id, counter) in delta.iter() {
-                let entry = peer_versions.entry(node_id.clone()).or_insert(0);
-                if counter.version > *entry {
-                    *entry = counter.version;
-                }
-            }
-
-            out.push(Message {
-                src: node.id.clone(),
-                dest: peer.clone(),
-                body: MessageBody::CounterGossip {
-                    msg_id: node.next_msg_id(),
-                    counters: delta,
+            };
+            let mut named: HashMap<_, _> = delta
+                .counters
+                .into_iter()
+                .map(|(key, kv)| (key, kv.counters))
+                .collect();
+            let counters = named.remove(WORKLOAD).unwrap_or_default();
+            out.push(node.send(
+                peer,
+                MessageBody::CounterGossip {
+                    msg_id: None,
+                    counters,
+                    named,
+                    seq: Some(seq),
                 },
-            });
+            ));
         }
         out
     }
 
     pub fn handle_add(&mut self, node: &Node, delta: u64) {
-        self.kv.add(node.id.clone(), delta);
+        self.handle_add_to(node, WORKLOAD, delta);
+    }
+
+    /// Add to the counter named `key`
+    pub fn handle_add_to(&mut self, node: &Node, key: &str, delta: u64) {
+        self.kv.mutate(|kv| {
+            kv.add(key, node.id.clone(), delta);
+            kv.slice(key, &node.id)
+        });
     }
 
     pub fn handle_read(&self) -> u64 {
-        self.kv.read()
+        self.kv.state().read(WORKLOAD)
     }
 
     pub fn handle_counter_gossip(&mut self, from_peer: String, counters: HashMap<String, Counter>) {
-        // Merge new info into our KV
-        // Clone because we also use counters to update knowledge below
-        let incoming = counters.clone();
-        self.kv.merge(counters);
-
-        // Update our knowledge about what the peer knows based on their advertised versions
-        let peer_versions = self.peer_known_versions.entry(from_peer).or_default();
-        for (node_id, counter) in incoming.into_iter() {
-            let entry = peer_versions.entry(node_id).or_insert(0);
-            if counter.version > *entry {
-                *entry = counter.version;
-            }
-        }
+        self.handle_gossip(from_peer, WORKLOAD, counters);
+    }
+
+    /// Merge a peer's entries of the counter named `key`
+    pub fn handle_gossip(
+        &mut self,
+        from_peer: String,
+        key: &str,
+        counters: HashMap<String, Counter>,
+    ) {
+        let mut delta = NamedKV::new();
+        delta.merge(key, counters);
+        self.kv.receive(&from_peer, delta);
     }
 }
 
 impl MessageHandler for GrowOnlyCounterNode {
     fn handle(&mut self, node: &mut Node, msg: Message) -> Vec<Message> {
-        let mut out: Vec<Message> = Vec::new();
-        match msg.body {
-            MessageBody::Init {
+        self.dispatch(node, msg)
+    }
+
+    fn on_tick(&mut self, node: &mut Node) -> Vec<Message> {
+        self.gossip(node)
+    }
+
+    fn tick_interval(&self) -> Option<Duration> {
+        Some(Duration::from_millis(100))
+    }
+
+    fn introspect(&self) -> Option<&dyn Introspect> {
+        Some(self)
+    }
+}
+
+/// The read value broken down by node, so a stale read shows whose adds have not arrived,
+/// and how many deltas each peer has yet to acknowledge
+impl Introspect for GrowOnlyCounterNode {
+    fn dump_state(&self, node: &Node) -> serde_json::Value {
+        let by_node = |key: &str| -> BTreeMap<_, _> {
+            self.kv
+                .state()
+                .get(key)
+                .map(|kv| kv.counters.iter().collect())
+                .unwrap_or_default()
+        };
+        let named: BTreeMap<_, _> = self
+            .kv
+            .state()
+            .counters
+            .keys()
+            .filter(|key| *key != WORKLOAD)
+            .map(|key| {
+                let state = serde_json::json!({
+                    "value": self.kv.state().read(key),
+                    "counters": by_node(key),
+                });
+                (key, state)
+            })
+            .collect();
+        let unacked: BTreeMap<_, _> = node
+            .peers
+            .iter()
+            .map(|peer| (peer, self.kv.unacked(peer)))
+            .collect();
+        serde_json::json!({
+            "value": self.handle_read(),
+            "counters": by_node(WORKLOAD),
+            "named": named,
+            "unacked": unacked,
+        })
+    }
+}
+
+impl MessageBodyDispatch for GrowOnlyCounterNode {
+    fn on_init(
+        &mut self,
+        node: &mut Node,
+        src: String,
+        msg_id: u64,
+        node_id: String,
+        node_ids: Vec<String>,
+    ) -> Vec<Message> {
+        // Initialize Node identity and peers
+        node.handle_init(node_id, node_ids.clone());
+        self.kv.set_peers(&node.peers);
+
+        // Pre-initialize counters for all nodes; zeroes are nothing to replicate
+        self.kv.mutate(|kv| {
+            kv.entry(WORKLOAD).init(node_ids);
+            NamedKV::new()
+        });
+
+        // Ask every peer for its counters in case we are a restarted or late node and the
+        // cluster is already counting; a peer that has not been initialized yet stays quiet
+        // and the call is retried
+        let mut out = vec![node.init_ok(src, msg_id)];
+        for peer in node.peers.clone() {
+            let sync = node.rpc(peer, MessageBody::StateSync { msg_id: 0 });
+            out.push(sync.expect("a state_sync carries a msg_id"));
+        }
+        out
+    }
+
+    fn on_add(
+        &mut self,
+        node: &mut Node,
+        src: String,
+        msg_id: u64,
+        delta: u64,
+        key: Option<String>,
+    ) -> Vec<Message> {
+        self.handle_add_to(node, key.as_deref().unwrap_or(WORKLOAD), delta);
+        vec![
+            node.answer(src, msg_id, |msg_id, in_reply_to| MessageBody::AddOk {
                 msg_id,
-                node_id,
-                node_ids,
-            } => {
-                // Pre-initialize counters for all nodes
-                self.kv.init(node_ids.clone());
-
-                // Initialize Node identity and peers
-                node.handle_init(node_id.clone(), node_ids.clone());
-
-                // Prepare per-peer known versions map
-                for peer in node_ids.into_iter().filter(|n| n != &node_id) {
-                    self.peer_known_versions
-                        .entry(peer)
-                        .or_insert_with(HashMap::new);
-                }
-                out.push(node.init_ok(msg.src, msg_id));
-            }
-            MessageBody::Add { msg_id, delta } => {
-                self.handle_add(node, delta);
-                let reply_msg_id = node.next_msg_id();
-                out.push(node.reply(
-                    msg.src,
-                    MessageBody::AddOk {
-                        msg_id: reply_msg_id,
-                        in_reply_to: msg_id,
-                    },
-                ));
-            }
-            MessageBody::Read { msg_id } => {
-                let value = self.handle_read();
-                let reply_msg_id = node.next_msg_id();
-                out.push(node.reply(
-                    msg.src,
-                    MessageBody::ReadOk {
-                        msg_id: reply_msg_id,
-                        in_reply_to: msg_id,
-                        messages: None,
-                        value: Some(value),
-                    },
-                ));
+                in_reply_to,
+            }),
+        ]
+    }
+
+    fn on_read(
+        &mut self,
+        node: &mut Node,
+        src: String,
+        msg_id: u64,
+        key: Option<String>,
+        context: Option<HashMap<String, u64>>,
+    ) -> Vec<Message> {
+        let never_added = KV::new();
+        let kv = self
+            .kv
+            .state()
+            .get(key.as_deref().unwrap_or(WORKLOAD))
+            .unwrap_or(&never_added);
+        // A client that read from a replica further along must not see the sum go backwards
+        // here; it can retry once gossip has caught us up, or ask another replica
+        if context.is_some_and(|context| !kv.covers(&context)) {
+            return vec![node.temporarily_unavailable(
+                src,
+                msg_id,
+                "this replica has not caught up with the read context",
+            )];
+        }
+        let (value, context) = (kv.read(), kv.versions());
+        vec![
+            node.answer(src, msg_id, |msg_id, in_reply_to| MessageBody::ReadOk {
+                msg_id,
+                in_reply_to,
+                messages: None,
+                value: Some(value),
+                context: Some(context),
+            }),
+        ]
+    }
+
+    fn on_counter_gossip(
+        &mut self,
+        node: &mut Node,
+        src: String,
+        _msg_id: Option<u64>,
+        counters: HashMap<String, Counter>,
+        named: HashMap<String, HashMap<String, Counter>>,
+        seq: Option<u64>,
+    ) -> Vec<Message> {
+        let mut delta = NamedKV::new();
+        delta.merge(WORKLOAD, counters);
+        for (key, counters) in named {
+            delta.merge(&key, counters);
+        }
+        self.kv.receive(&src, delta);
+        seq.map(|seq| node.send(src, MessageBody::CounterGossipOk { msg_id: None, seq }))
+            .into_iter()
+            .collect()
+    }
+
+    fn on_counter_gossip_ok(
+        &mut self,
+        _node: &mut Node,
+        src: String,
+        _msg_id: Option<u64>,
+        seq: u64,
+    ) -> Vec<Message> {
+        self.kv.ack(&src, seq);
+        Vec::new()
+    }
+
+    fn on_state_sync(&mut self, node: &mut Node, src: String, msg_id: u64) -> Vec<Message> {
+        if node.id.is_empty() {
+            return Vec::new();
+        }
+        let state = persist::encode(&self.kv.state().snapshot());
+        vec![node.answer(src, msg_id, |msg_id, in_reply_to| {
+            MessageBody::StateSyncOk {
+                msg_id,
+                in_reply_to,
+                state,
             }
-            MessageBody::CounterGossip {
-                msg_id: _,
-                counters,
-            } => {
-                self.handle_counter_gossip(msg.src.clone(), counters);
+        })]
+    }
+
+    fn on_state_sync_ok(
+        &mut self,
+        node: &mut Node,
+        src: String,
+        _msg_id: u64,
+        _in_reply_to: u64,
+        state: String,
+    ) -> Vec<Message> {
+        let mut peer_kv = NamedKV::new();
+        match persist::decode(&state).and_then(|snapshot| peer_kv.restore(&snapshot)) {
+            Ok(()) => {
+                self.kv.receive(&src, peer_kv);
             }
-            _ => {}
+            Err(e) => node.log(&format!("ignoring state from {src}: {e}")),
         }
-        out
+        Vec::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use maelstrom::process;
+    use maelstrom::sim::Sim;
+    use maelstrom_testkit::{self as testkit, expect_body};
+
+    #[test]
+    fn test_new_node_bootstraps_from_a_peer_snapshot() {
+        let ids = ["n1", "n2"];
+        let mut counting = GrowOnlyCounterNode::new();
+        let mut node1 = testkit::node("n1", ids);
+        counting.handle_add(&node1, 5);
+        counting.handle_add_to(&node1, "hits", 2);
+
+        let mut joining = GrowOnlyCounterNode::new();
+        let mut node2 = Node::new();
+        let out = joining.handle(&mut node2, testkit::init("n2", ids));
+        assert_eq!(out.len(), 2);
+        let request = out[1].clone();
+        testkit::assert_route(&request, "n2", "n1");
+
+        let response = counting.handle(&mut node1, request);
+        let reply = testkit::only(&response).clone();
+        assert!(matches!(reply.body, MessageBody::StateSyncOk { .. }));
+        joining.handle(&mut node2, reply);
+        assert_eq!(joining.handle_read(), 5);
+        assert_eq!(joining.kv.state().read("hits"), 2);
+        // n1 already has everything it sent, so it is not gossiped back
+        assert!(joining.gossip(&mut node2).is_empty());
+    }
+
+    #[test]
+    fn test_dump_state_breaks_the_value_down_by_node() {
+        let mut handler = GrowOnlyCounterNode::new();
+        let mut node = Node::new();
+        process(&mut handler, &mut node, testkit::init("n1", ["n1", "n2"]));
+        handler.handle_add(&node, 3);
+        handler.handle_counter_gossip(
+            "n2".to_string(),
+            HashMap::from([(
+                "n2".to_string(),
+                Counter {
+                    version: 2,
+                    value: 4,
+                },
+            )]),
+        );
+
+        let out = process(&mut handler, &mut node, testkit::dump_state());
+        let state = expect_body!(&out[0].body, DumpStateOk { state, .. });
+        assert_eq!(state["value"], 7);
+        assert_eq!(state["counters"]["n1"]["value"], 3);
+        assert_eq!(state["counters"]["n2"]["version"], 2);
+        assert_eq!(state["unacked"]["n2"], 1);
+    }
+
+    #[test]
+    fn test_gossip_is_resent_until_acknowledged() {
+        let ids = ["n1", "n2"];
+        let mut a = GrowOnlyCounterNode::new();
+        let mut b = GrowOnlyCounterNode::new();
+        let mut node_a = Node::new();
+        let mut node_b = Node::new();
+        process(&mut a, &mut node_a, testkit::init("n1", ids));
+        process(&mut b, &mut node_b, testkit::init("n2", ids));
+        process(
+            &mut a,
+            &mut node_a,
+            testkit::add(3).dest("n1").with_msg_id(2),
+        );
+
+        // The first round is lost; the entry goes out again until n2 acknowledges it
+        let _lost = a.gossip(&mut node_a);
+        let gossip = a.gossip(&mut node_a);
+        let seq = expect_body!(&testkit::only(&gossip).body, CounterGossip { seq, .. });
+        assert!(seq.is_some());
+
+        let ack = process(&mut b, &mut node_b, gossip[0].clone());
+        testkit::assert_route(testkit::only(&ack), "n2", "n1");
+        assert_eq!(b.handle_read(), 3);
+        process(&mut a, &mut node_a, ack[0].clone());
+        assert!(a.gossip(&mut node_a).is_empty());
+    }
+
+    #[test]
+    fn test_state_sync_is_retried_while_the_peer_is_uninitialized() {
+        let mut joining = GrowOnlyCounterNode::new();
+        let mut node = Node::new();
+        let out = process(&mut joining, &mut node, testkit::init("n2", ["n1", "n2"]));
+        let request = out[1].clone();
+        assert!(matches!(request.body, MessageBody::StateSync { .. }));
+        assert!(node.calls.contains(request.body.msg_id().unwrap()));
+
+        // A peer that has not had its init yet cannot answer from its id, so it stays quiet
+        let mut waiting = GrowOnlyCounterNode::new();
+        assert!(process(&mut waiting, &mut Node::new(), request).is_empty());
+    }
+
+    #[test]
+    fn test_named_counters_are_counted_and_gossiped_apart() {
+        let ids = ["n1", "n2"];
+        let mut a = GrowOnlyCounterNode::new();
+        let mut b = GrowOnlyCounterNode::new();
+        let mut node_a = testkit::node("n1", ids);
+        let mut node_b = testkit::node("n2", ids);
+        process(&mut a, &mut node_a, testkit::add_to("hits", 4));
+        process(&mut a, &mut node_a, testkit::add(1).with_msg_id(2));
+        process(&mut b, &mut node_b, testkit::add_to("misses", 2));
+
+        let gossip = a.gossip(&mut node_a);
+        let named = expect_body!(&testkit::only(&gossip).body, CounterGossip { named, .. });
+        assert_eq!(named.keys().collect::<Vec<_>>(), vec!["hits"]);
+        process(&mut b, &mut node_b, gossip[0].clone());
+
+        let read = |handler: &mut GrowOnlyCounterNode, node: &mut Node, request| {
+            let out = process(handler, node, request);
+            *expect_body!(&out[0].body, ReadOk { value, .. })
+        };
+        assert_eq!(
+            read(&mut b, &mut node_b, testkit::read_key("hits")),
+            Some(4)
+        );
+        assert_eq!(
+            read(&mut b, &mut node_b, testkit::read_key("misses")),
+            Some(2)
+        );
+        assert_eq!(read(&mut b, &mut node_b, testkit::read()), Some(1));
+    }
+
+    #[test]
+    fn test_reads_with_a_context_never_go_backwards() {
+        let ids = ["n1", "n2"];
+        let mut a = GrowOnlyCounterNode::new();
+        let mut b = GrowOnlyCounterNode::new();
+        let mut node_a = testkit::node("n1", ids);
+        let mut node_b = testkit::node("n2", ids);
+        process(&mut a, &mut node_a, testkit::add(3));
+
+        let out = process(&mut a, &mut node_a, testkit::read().with_msg_id(2));
+        let context = expect_body!(&out[0].body, ReadOk { context, .. }).clone();
+        let context = context.unwrap();
+        assert_eq!(context, HashMap::from([("n1".to_string(), 1)]));
+
+        // n2 has not heard of the add yet, so it refuses instead of answering 0
+        let out = process(&mut b, &mut node_b, testkit::read_after(context.clone()));
+        let code = expect_body!(&out[0].body, Error { code, .. });
+        assert_eq!(*code, maelstrom::ErrorCode::TemporarilyUnavailable);
+
+        let gossip = a.gossip(&mut node_a);
+        process(&mut b, &mut node_b, gossip[0].clone());
+        let out = process(
+            &mut b,
+            &mut node_b,
+            testkit::read_after(context).with_msg_id(2),
+        );
+        assert_eq!(*expect_body!(&out[0].body, ReadOk { value, .. }), Some(3));
+    }
+
+    #[test]
+    fn test_simulated_counter_converges_to_acknowledged_adds() {
+        let mut sim = Sim::new(3, |_| GrowOnlyCounterNode::new());
+        sim.latency(Duration::from_millis(1), Duration::from_millis(20));
+        for (i, delta) in [5, 1, 7, 2, 9, 3].into_iter().enumerate() {
+            let dest = format!("n{}", i % 3 + 1);
+            sim.send(testkit::add(delta).dest(&dest).with_msg_id(i as u64 + 1));
+        }
+
+        let report = testkit::check_counter(&mut sim, Duration::from_secs(2));
+        report.assert_valid();
+        assert_eq!(report.acknowledged, 27);
+        assert!(report.reads.values().all(|read| *read == 27));
     }
 }
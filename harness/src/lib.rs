@@ -0,0 +1,357 @@
+//! End-to-end harness: spawn a workload's real binary and speak the stdio protocol with it,
+//! playing Maelstrom's part for clients and for any other nodes. Unlike the handler unit
+//! tests, this covers the wiring in each `main.rs`: which handler runs, how it is configured,
+//! and the runtime underneath.
+//!
+//! Binaries are looked up next to the test executable (`target/<profile>/`), or in
+//! `HARNESS_BIN_DIR` when set, and built with cargo if they are missing.
+
+use maelstrom::{
+    Message, MessageBody,
+    trace::{self, Envelope},
+};
+use std::env;
+use std::path::{Path, PathBuf};
+use std::process::{ExitStatus, Stdio};
+use std::time::Duration;
+use tokio::io::{self, AsyncBufReadExt, AsyncWriteExt, BufReader, Lines};
+use tokio::process::{Child, ChildStdin, ChildStdout, Command};
+use tokio::time;
+
+/// How long to wait for a node to write before giving up
+pub const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The client every request is sent from unless stated otherwise
+pub const CLIENT: &str = "c1";
+
+/// A running node binary
+pub struct Harness {
+    child: Child,
+    stdin: Option<ChildStdin>,
+    stdout: Lines<BufReader<ChildStdout>>,
+    /// The node's id once initialized
+    pub id: String,
+    next_msg_id: u64,
+}
+
+impl Harness {
+    /// Start `binary` without initializing it, with extra environment variables
+    pub async fn spawn(binary: &str, envs: &[(&str, &str)]) -> io::Result<Self> {
+        let mut child = Command::new(locate(binary).await?)
+            .envs(envs.iter().copied())
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .kill_on_drop(true)
+            .spawn()?;
+        let stdin = child.stdin.take();
+        let stdout = child.stdout.take().map(|out| BufReader::new(out).lines());
+        let Some(stdout) = stdout else {
+            return Err(io::Error::other("child has no stdout"));
+        };
+        Ok(Self {
+            child,
+            stdin,
+            stdout,
+            id: String::new(),
+            next_msg_id: 0,
+        })
+    }
+
+    /// Start `binary` and initialize it as `node_id` in a cluster of `node_ids`
+    pub async fn start(binary: &str, node_id: &str, node_ids: &[&str]) -> io::Result<Self> {
+        let mut node = Self::spawn(binary, &[]).await?;
+        node.init(node_id, node_ids).await?;
+        Ok(node)
+    }
+
+    /// Send `init` and wait for `init_ok`
+    pub async fn init(&mut self, node_id: &str, node_ids: &[&str]) -> io::Result<()> {
+        let reply = self
+            .request_from(
+                "c0",
+                MessageBody::Init {
+                    msg_id: 0,
+                    node_id: node_id.to_string(),
+                    node_ids: node_ids.iter().map(|id| id.to_string()).collect(),
+                },
+            )
+            .await?;
+        match reply {
+            MessageBody::InitOk { .. } => {
+                self.id = node_id.to_string();
+                Ok(())
+            }
+            other => Err(unexpected("init_ok", &other)),
+        }
+    }
+
+    /// Write `body` to the node as if sent by `src`
+    pub async fn send(&mut self, src: &str, body: MessageBody) -> io::Result<()> {
+        let message = Message {
+            src: src.to_string(),
+            dest: self.id.clone(),
+            body,
+        };
+        let line = trace::encode(&message, &Envelope::default()).map_err(io::Error::other)?;
+        let Some(stdin) = &mut self.stdin else {
+            return Err(io::Error::from(io::ErrorKind::BrokenPipe));
+        };
+        stdin.write_all(&line).await?;
+        stdin.flush().await
+    }
+
+    /// The next message the node writes
+    pub async fn recv(&mut self) -> io::Result<Message> {
+        loop {
+            let line = time::timeout(TIMEOUT, self.stdout.next_line())
+                .await
+                .map_err(|_| io::Error::from(io::ErrorKind::TimedOut))??;
+            let Some(line) = line else {
+                return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+            };
+            if line.trim().is_empty() {
+                continue;
+            }
+            let (message, _) = trace::decode(line.as_bytes())
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{e:?}")))?;
+            return Ok(message);
+        }
+    }
+
+    /// The next message the node writes that satisfies `wanted`, skipping the rest
+    pub async fn recv_matching(
+        &mut self,
+        mut wanted: impl FnMut(&Message) -> bool,
+    ) -> io::Result<Message> {
+        time::timeout(TIMEOUT, async {
+            loop {
+                let message = self.recv().await?;
+                if wanted(&message) {
+                    return Ok(message);
+                }
+            }
+        })
+        .await
+        .map_err(|_| io::Error::from(io::ErrorKind::TimedOut))?
+    }
+
+    /// Send `body` from [`CLIENT`] with a fresh `msg_id` and wait for the reply to it
+    pub async fn request(&mut self, body: MessageBody) -> io::Result<MessageBody> {
+        self.request_from(CLIENT, body).await
+    }
+
+    /// Like [`Harness::request`], from `src`; traffic to anyone else meanwhile is skipped
+    pub async fn request_from(
+        &mut self,
+        src: &str,
+        mut body: MessageBody,
+    ) -> io::Result<MessageBody> {
+        self.next_msg_id += 1;
+        let msg_id = self.next_msg_id;
+        body.set_msg_id(msg_id);
+        self.send(src, body).await?;
+        let reply = self
+            .recv_matching(|message| {
+                message.dest == src && message.body.in_reply_to() == Some(msg_id)
+            })
+            .await?;
+        Ok(reply.body)
+    }
+
+    /// Close the node's stdin and wait for it to exit
+    pub async fn shutdown(mut self) -> io::Result<ExitStatus> {
+        drop(self.stdin.take());
+        time::timeout(TIMEOUT, self.child.wait())
+            .await
+            .map_err(|_| io::Error::from(io::ErrorKind::TimedOut))?
+    }
+}
+
+fn unexpected(wanted: &str, body: &MessageBody) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("expected {wanted}, got {body:?}"),
+    )
+}
+
+/// Path to the workspace binary `binary`, building it first if needed
+async fn locate(binary: &str) -> io::Result<PathBuf> {
+    if let Some(dir) = env::var_os("HARNESS_BIN_DIR") {
+        return Ok(Path::new(&dir).join(binary));
+    }
+    // Test executables live in target/<profile>/deps
+    let exe = env::current_exe()?;
+    let Some(dir) = exe.parent().and_then(Path::parent) else {
+        return Err(io::Error::other("cannot find the target directory"));
+    };
+    let path = dir.join(binary);
+    if !path.exists() {
+        let mut cargo = Command::new(env::var("CARGO").unwrap_or_else(|_| "cargo".into()));
+        cargo.args([
+            "build",
+            "--offline",
+            "--quiet",
+            "-p",
+            binary,
+            "--bin",
+            binary,
+        ]);
+        if dir.file_name().is_some_and(|profile| profile == "release") {
+            cargo.arg("--release");
+        }
+        if !cargo.status().await?.success() {
+            return Err(io::Error::other(format!("cannot build {binary}")));
+        }
+    }
+    Ok(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::{HashMap, HashSet};
+
+    #[tokio::test]
+    async fn test_echo_answers_over_stdio() {
+        let mut node = Harness::start("echo", "n1", &["n1"]).await.unwrap();
+        let reply = node
+            .request(MessageBody::Echo {
+                msg_id: 0,
+                echo: "hello".to_string(),
+            })
+            .await
+            .unwrap();
+        match reply {
+            MessageBody::EchoOk { echo, .. } => assert_eq!(echo, "hello"),
+            _ => panic!("Expected EchoOk message"),
+        }
+        assert!(node.shutdown().await.unwrap().success());
+    }
+
+    #[tokio::test]
+    async fn test_uniqueids_never_repeat() {
+        let mut node = Harness::start("uniqueids", "n1", &["n1", "n2"])
+            .await
+            .unwrap();
+        let mut ids = HashSet::new();
+        for _ in 0..20 {
+            match node.request(MessageBody::Generate { msg_id: 0 }).await {
+                Ok(MessageBody::GenerateOk { id, .. }) => assert!(ids.insert(id)),
+                _ => panic!("Expected GenerateOk message"),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_multi_node_broadcast_gossips_to_and_learns_from_peers() {
+        let mut node = Harness::start("multi_node_broadcast", "n1", &["n1", "n2"])
+            .await
+            .unwrap();
+        let topology = HashMap::from([
+            ("n1".to_string(), vec!["n2".to_string()]),
+            ("n2".to_string(), vec!["n1".to_string()]),
+        ]);
+        let reply = node
+            .request(MessageBody::Topology {
+                msg_id: 0,
+                topology,
+            })
+            .await
+            .unwrap();
+        assert!(matches!(reply, MessageBody::TopologyOk { .. }));
+
+        let reply = node
+            .request(MessageBody::Broadcast {
+                msg_id: 0,
+                message: 7,
+            })
+            .await
+            .unwrap();
+        assert!(matches!(reply, MessageBody::BroadcastOk { .. }));
+        let gossip = node
+            .recv_matching(|message| message.dest == "n2")
+            .await
+            .unwrap();
+        match gossip.body {
+            MessageBody::BroadcastGossip { messages, .. } => assert!(messages.contains(&7)),
+            _ => panic!("Expected BroadcastGossip message"),
+        }
+
+        node.send(
+            "n2",
+            MessageBody::BroadcastGossip {
+                msg_id: None,
+                messages: vec![8],
+            },
+        )
+        .await
+        .unwrap();
+        // Client requests are served ahead of peer traffic, so the gossip may land after a read
+        let mut read = HashSet::new();
+        for _ in 0..50 {
+            match node
+                .request(MessageBody::Read {
+                    msg_id: 0,
+                    key: None,
+                    context: None,
+                })
+                .await
+            {
+                Ok(MessageBody::ReadOk { messages, .. }) => {
+                    read = messages.unwrap_or_default().into_iter().collect();
+                }
+                _ => panic!("Expected ReadOk message"),
+            }
+            if read.contains(&8) {
+                break;
+            }
+            time::sleep(Duration::from_millis(20)).await;
+        }
+        assert_eq!(read, HashSet::from([7, 8]));
+    }
+
+    #[tokio::test]
+    async fn test_single_node_kafka_polls_what_was_sent() {
+        let mut node = Harness::start("single_node_kafka", "n1", &["n1"])
+            .await
+            .unwrap();
+        let offset = match node
+            .request(MessageBody::Send {
+                msg_id: 0,
+                key: "k1".to_string(),
+                msg: 42,
+            })
+            .await
+        {
+            Ok(MessageBody::SendOk { offset, .. }) => offset,
+            _ => panic!("Expected SendOk message"),
+        };
+        let reply = node
+            .request(MessageBody::Poll {
+                msg_id: 0,
+                offsets: HashMap::from([("k1".to_string(), 0)]),
+            })
+            .await
+            .unwrap();
+        match reply {
+            MessageBody::PollOk { msgs, .. } => assert_eq!(msgs["k1"], vec![(offset, 42)]),
+            _ => panic!("Expected PollOk message"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_environment_reaches_the_binary() {
+        let path = env::temp_dir().join(format!("harness-tee-{}.jsonl", std::process::id()));
+        let tee = path.to_string_lossy().into_owned();
+        let mut node = Harness::spawn("echo", &[("MAELSTROM_TEE", &tee)])
+            .await
+            .unwrap();
+        node.init("n1", &["n1"]).await.unwrap();
+        assert!(node.shutdown().await.unwrap().success());
+
+        let capture = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(capture.lines().count(), 2);
+    }
+}
@@ -0,0 +1,184 @@
+//! Benchmarks for the library's hot paths: polling large kafka logs, encoding and decoding
+//! big gossip bodies, answering echo batches, merging counter state, and generating unique ids
+//! from several threads at once. Run with `cargo bench -p maelstrom`.
+
+use criterion::{BatchSize, BenchmarkId, Criterion, Throughput, criterion_group, criterion_main};
+use maelstrom::ids::{AtomicIdGen, IdGen};
+use maelstrom::kv::{Counter, KV};
+use maelstrom::log::Logs;
+use maelstrom::trace::{self, Envelope};
+use maelstrom::{Message, MessageBody};
+use std::collections::HashMap;
+use std::hint::black_box;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+fn logs(keys: usize, entries: u64) -> Logs {
+    let mut logs = Logs::new();
+    for key in 0..keys {
+        for msg in 0..entries {
+            logs.append_local(&format!("k{key}"), msg).unwrap();
+        }
+    }
+    logs
+}
+
+fn poll(c: &mut Criterion) {
+    let mut group = c.benchmark_group("logs_poll");
+    let logs = logs(4, 100_000);
+    // From the start of every log, from near the tail, as a caught-up consumer would
+    for (name, from) in [("from_start", 0), ("from_tail", 99_900)] {
+        let offsets: HashMap<String, u64> = (0..4).map(|key| (format!("k{key}"), from)).collect();
+        group.throughput(Throughput::Elements(4 * (100_000 - from)));
+        group.bench_function(name, |b| b.iter(|| logs.poll(black_box(&offsets)).unwrap()));
+    }
+    group.finish();
+}
+
+fn gossip(len: u64) -> Message {
+    Message {
+        src: "n1".to_string(),
+        dest: "n2".to_string(),
+        body: MessageBody::BroadcastGossip {
+            msg_id: None,
+            messages: (0..len).map(|i| i * 7919).collect(),
+        },
+    }
+}
+
+fn codec(c: &mut Criterion) {
+    let mut group = c.benchmark_group("broadcast_gossip_codec");
+    for len in [1_000, 100_000] {
+        let message = gossip(len);
+        let envelope = Envelope::default();
+        let line = trace::encode(&message, &envelope).unwrap();
+        group.throughput(Throughput::Bytes(line.len() as u64));
+        group.bench_with_input(BenchmarkId::new("encode", len), &message, |b, message| {
+            b.iter(|| trace::encode(black_box(message), &envelope).unwrap())
+        });
+        group.bench_with_input(BenchmarkId::new("decode", len), &line, |b, line| {
+            b.iter(|| trace::decode(black_box(line)).unwrap())
+        });
+    }
+    group.finish();
+}
+
+fn echo_batch(len: u64) -> Message {
+    Message {
+        src: "c1".to_string(),
+        dest: "n1".to_string(),
+        body: MessageBody::EchoBatch {
+            msg_id: 1,
+            echoes: (0..len).map(|i| format!("payload-{i}")).collect(),
+        },
+    }
+}
+
+/// Decode an `echo_batch` line, answer it, and encode the reply, as the echo node would
+fn echo(c: &mut Criterion) {
+    let mut group = c.benchmark_group("echo_batch");
+    for len in [1, 1_000] {
+        let envelope = Envelope::default();
+        let line = trace::encode(&echo_batch(len), &envelope).unwrap();
+        group.throughput(Throughput::Elements(len));
+        group.bench_with_input(BenchmarkId::from_parameter(len), &line, |b, line| {
+            b.iter(|| {
+                let (request, _) = trace::decode(black_box(line)).unwrap();
+                let MessageBody::EchoBatch { msg_id, echoes } = request.body else {
+                    unreachable!("encoded an echo_batch");
+                };
+                let reply = Message {
+                    src: request.dest,
+                    dest: request.src,
+                    body: MessageBody::EchoBatchOk {
+                        msg_id: 2,
+                        in_reply_to: msg_id,
+                        echoes,
+                    },
+                };
+                trace::encode(&reply, &envelope).unwrap()
+            })
+        });
+    }
+    group.finish();
+}
+
+fn counters(nodes: usize, version: u64) -> HashMap<String, Counter> {
+    (0..nodes)
+        .map(|node| {
+            let counter = Counter {
+                version,
+                value: version * 10,
+            };
+            (format!("n{node}"), counter)
+        })
+        .collect()
+}
+
+fn merge(c: &mut Criterion) {
+    let mut group = c.benchmark_group("kv_merge");
+    for nodes in [25, 1_000] {
+        let mut kv = KV::new();
+        kv.counters = counters(nodes, 1);
+        // Every counter newer, then every counter stale
+        for (name, version) in [("newer", 2), ("stale", 0)] {
+            let incoming = counters(nodes, version);
+            group.bench_function(BenchmarkId::new(name, nodes), |b| {
+                b.iter_batched(
+                    || (kv.counters.clone(), incoming.clone()),
+                    |(current, incoming)| {
+                        let mut kv = KV { counters: current };
+                        kv.merge(incoming);
+                        kv
+                    },
+                    BatchSize::SmallInput,
+                )
+            });
+        }
+    }
+    group.finish();
+}
+
+/// Time `threads` threads each calling `generate` `iters` times
+fn generate_on(threads: u64, iters: u64, generate: impl Fn() -> u64 + Sync) -> Duration {
+    let start = Instant::now();
+    std::thread::scope(|scope| {
+        for _ in 0..threads {
+            scope.spawn(|| {
+                for _ in 0..iters {
+                    black_box(generate());
+                }
+            });
+        }
+    });
+    start.elapsed()
+}
+
+/// `IdGen` behind a lock, as a handler shared between workers would hold it, against
+/// `AtomicIdGen`; with the wall clock, and without it to leave only the synchronization
+fn ids(c: &mut Criterion) {
+    let mut group = c.benchmark_group("generate");
+    for threads in [1, 4] {
+        group.throughput(Throughput::Elements(threads));
+        let clocks = [
+            ("wall", IdGen::new(3)),
+            ("counter", IdGen::new(3).without_clock()),
+        ];
+        for (clock, id_gen) in clocks {
+            let locked = Mutex::new(id_gen.clone());
+            group.bench_function(BenchmarkId::new(format!("locked-{clock}"), threads), |b| {
+                b.iter_custom(|iters| {
+                    generate_on(threads, iters, || locked.lock().unwrap().generate())
+                })
+            });
+            let atomic = AtomicIdGen::from(id_gen);
+            group.bench_function(BenchmarkId::new(format!("atomic-{clock}"), threads), |b| {
+                b.iter_custom(|iters| generate_on(threads, iters, || atomic.generate()))
+            });
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(benches, poll, codec, echo, merge, ids);
+criterion_main!(benches);
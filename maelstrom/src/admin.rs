@@ -0,0 +1,219 @@
+//! Control-plane messages answered by the framework rather than the workload: `stats`,
+//! `dump_state`, `set_config`, and `membership`. They are meant for operators poking at a
+//! running cluster, so they skip deduplication and validation.
+
+use crate::{
+    Message, MessageBody,
+    runtime::{MessageHandler, Node},
+};
+use serde_json::Value;
+use std::time::Duration;
+
+/// Lets a handler show its state to `dump_state`; return it from
+/// [`MessageHandler::introspect`] to participate
+pub trait Introspect {
+    /// JSON snapshot of whatever the handler considers its state
+    fn dump_state(&self, node: &Node) -> Value;
+}
+
+/// Answer an admin request; `None` if the message is not one
+pub(crate) fn handle<H: MessageHandler>(
+    handler: &mut H,
+    node: &mut Node,
+    message: &Message,
+) -> Option<Vec<Message>> {
+    let src = message.src.clone();
+    let out = match message.body {
+        MessageBody::Stats { msg_id } => {
+            let mut stats = node.metrics.to_json();
+            if let Some(handler_stats) = handler.stats(node) {
+                stats["handler"] = handler_stats;
+            }
+            vec![
+                node.answer(src, msg_id, |msg_id, in_reply_to| MessageBody::StatsOk {
+                    msg_id,
+                    in_reply_to,
+                    stats,
+                }),
+            ]
+        }
+        MessageBody::DumpState { msg_id } => {
+            let Some(state) = handler.introspect().map(|i| i.dump_state(node)) else {
+                return Some(vec![node.not_supported(
+                    src,
+                    msg_id,
+                    "this node does not expose its state",
+                )]);
+            };
+            vec![node.answer(src, msg_id, |msg_id, in_reply_to| {
+                MessageBody::DumpStateOk {
+                    msg_id,
+                    in_reply_to,
+                    state,
+                }
+            })]
+        }
+        MessageBody::SetConfig {
+            msg_id,
+            tick_ms,
+            fanout,
+        } => {
+            if let Some(tick_ms) = tick_ms {
+                node.config.tick_interval = Some(Duration::from_millis(tick_ms.max(1)));
+            }
+            if let Some(fanout) = fanout {
+                node.config.fanout = fanout;
+            }
+            let mut out = handler.on_config_change(node);
+            out.push(node.answer(src, msg_id, |msg_id, in_reply_to| {
+                MessageBody::SetConfigOk {
+                    msg_id,
+                    in_reply_to,
+                }
+            }));
+            out
+        }
+        MessageBody::Membership {
+            msg_id,
+            ref added,
+            ref removed,
+        } => {
+            let mut out = apply_membership(handler, node, added.clone(), removed.clone());
+            out.push(node.answer(src, msg_id, |msg_id, in_reply_to| {
+                MessageBody::MembershipOk {
+                    msg_id,
+                    in_reply_to,
+                }
+            }));
+            out
+        }
+        _ => return None,
+    };
+    Some(out)
+}
+
+/// Update `node.peers`, greet newcomers, and tell the handler if anything changed
+pub(crate) fn apply_membership<H: MessageHandler>(
+    handler: &mut H,
+    node: &mut Node,
+    added: Vec<String>,
+    removed: Vec<String>,
+) -> Vec<Message> {
+    let change = node.update_membership(added, removed);
+    if change.is_empty() {
+        return Vec::new();
+    }
+    node.log(&format!(
+        "membership changed: +{:?} -{:?}",
+        change.added, change.removed
+    ));
+    let mut out: Vec<Message> = change
+        .added
+        .iter()
+        .map(|peer| node.hello(peer.clone()))
+        .collect();
+    out.extend(handler.on_membership_change(node, &change));
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ErrorCode, testing};
+
+    #[derive(Default)]
+    struct Counter {
+        value: u64,
+        config_changes: usize,
+    }
+
+    impl Introspect for Counter {
+        fn dump_state(&self, node: &Node) -> Value {
+            serde_json::json!({ "node": node.id, "value": self.value })
+        }
+    }
+
+    impl MessageHandler for Counter {
+        fn handle(&mut self, _node: &mut Node, _message: Message) -> Vec<Message> {
+            Vec::new()
+        }
+
+        fn introspect(&self) -> Option<&dyn Introspect> {
+            Some(self)
+        }
+
+        fn on_config_change(&mut self, _node: &mut Node) -> Vec<Message> {
+            self.config_changes += 1;
+            Vec::new()
+        }
+    }
+
+    struct Opaque;
+
+    impl MessageHandler for Opaque {
+        fn handle(&mut self, _node: &mut Node, _message: Message) -> Vec<Message> {
+            Vec::new()
+        }
+    }
+
+    fn node() -> Node {
+        let mut node = Node::new();
+        node.handle_init("n1".to_string(), vec!["n1".to_string()]);
+        node
+    }
+
+    #[test]
+    fn test_dump_state_returns_the_handler_snapshot() {
+        let mut node = node();
+        let mut handler = Counter {
+            value: 7,
+            ..Counter::default()
+        };
+        let request = testing::dump_state();
+
+        let out = handle(&mut handler, &mut node, &request).unwrap();
+        match &out[0].body {
+            MessageBody::DumpStateOk {
+                in_reply_to, state, ..
+            } => {
+                assert_eq!(*in_reply_to, 1);
+                assert_eq!(state["node"], "n1");
+                assert_eq!(state["value"], 7);
+            }
+            _ => panic!("Expected DumpStateOk message"),
+        }
+
+        let out = handle(&mut Opaque, &mut node, &request).unwrap();
+        match &out[0].body {
+            MessageBody::Error { code, .. } => assert_eq!(*code, ErrorCode::NotSupported),
+            _ => panic!("Expected Error message"),
+        }
+    }
+
+    #[test]
+    fn test_set_config_updates_the_node_and_tells_the_handler() {
+        let mut node = node();
+        let mut handler = Counter::default();
+
+        let out = handle(
+            &mut handler,
+            &mut node,
+            &testing::set_config(Some(25), Some(2)),
+        )
+        .unwrap();
+        assert!(matches!(out[0].body, MessageBody::SetConfigOk { .. }));
+        assert_eq!(node.config.tick_interval, Some(Duration::from_millis(25)));
+        assert_eq!(node.config.fanout, 2);
+        assert_eq!(handler.config_changes, 1);
+
+        // Fields left out keep their current value
+        handle(&mut handler, &mut node, &testing::set_config(None, Some(3))).unwrap();
+        assert_eq!(node.config.tick_interval, Some(Duration::from_millis(25)));
+        assert_eq!(node.config.fanout, 3);
+    }
+
+    #[test]
+    fn test_other_messages_are_left_alone() {
+        assert!(handle(&mut Opaque, &mut node(), &testing::read()).is_none());
+    }
+}
@@ -0,0 +1,118 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// Where a peer's circuit stands
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Circuit {
+    /// Traffic flows normally
+    Closed,
+    /// Too many consecutive failures; traffic to the peer is dropped until the cooldown ends
+    Open,
+    /// Cooldown over; traffic flows again, and the next failure reopens the circuit at once
+    HalfOpen,
+}
+
+#[derive(Default)]
+struct PeerCircuit {
+    failures: u32,
+    opened_at: Option<Instant>,
+}
+
+/// Per-peer circuit breakers fed by RPC outcomes. After `threshold` calls to a peer fail in
+/// a row its circuit opens for `cooldown`, so a partitioned peer stops costing
+/// retransmissions.
+pub struct Breakers {
+    threshold: Option<u32>,
+    cooldown: Duration,
+    peers: HashMap<String, PeerCircuit>,
+}
+
+impl Breakers {
+    /// `None` never opens a circuit
+    pub fn new(threshold: Option<u32>, cooldown: Duration) -> Self {
+        Self {
+            threshold,
+            cooldown,
+            peers: HashMap::new(),
+        }
+    }
+
+    /// A call to `peer` got no reply; true if this opened its circuit
+    pub fn record_failure(&mut self, peer: &str, now: Instant) -> bool {
+        let Some(threshold) = self.threshold else {
+            return false;
+        };
+        let circuit = self.peers.entry(peer.to_string()).or_default();
+        circuit.failures += 1;
+        let reopened = circuit.failures >= threshold.max(1)
+            && circuit
+                .opened_at
+                .is_none_or(|opened| now.saturating_duration_since(opened) >= self.cooldown);
+        if reopened {
+            circuit.opened_at = Some(now);
+        }
+        reopened
+    }
+
+    /// `peer` answered a call, closing its circuit
+    pub fn record_success(&mut self, peer: &str) {
+        self.peers.remove(peer);
+    }
+
+    pub fn state(&self, peer: &str, now: Instant) -> Circuit {
+        match self.peers.get(peer).and_then(|circuit| circuit.opened_at) {
+            None => Circuit::Closed,
+            Some(opened) if now.saturating_duration_since(opened) < self.cooldown => Circuit::Open,
+            Some(_) => Circuit::HalfOpen,
+        }
+    }
+
+    /// Whether traffic to `peer` should go out at `now`
+    pub fn allows(&self, peer: &str, now: Instant) -> bool {
+        self.state(peer, now) != Circuit::Open
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_circuit_opens_after_consecutive_failures_and_recovers() {
+        let start = Instant::now();
+        let cooldown = Duration::from_millis(100);
+        let mut breakers = Breakers::new(Some(2), cooldown);
+
+        assert!(!breakers.record_failure("n2", start));
+        assert_eq!(breakers.state("n2", start), Circuit::Closed);
+        assert!(breakers.record_failure("n2", start));
+        assert!(!breakers.allows("n2", start));
+        assert!(breakers.allows("n3", start));
+
+        // Failures while open do not extend the cooldown
+        assert!(!breakers.record_failure("n2", start + Duration::from_millis(50)));
+        let later = start + cooldown;
+        assert_eq!(breakers.state("n2", later), Circuit::HalfOpen);
+
+        // A failed probe reopens at once, a successful one closes
+        assert!(breakers.record_failure("n2", later));
+        assert_eq!(breakers.state("n2", later), Circuit::Open);
+        breakers.record_success("n2");
+        assert_eq!(breakers.state("n2", later), Circuit::Closed);
+    }
+
+    #[test]
+    fn test_success_resets_the_count() {
+        let now = Instant::now();
+        let mut breakers = Breakers::new(Some(2), Duration::from_secs(1));
+        breakers.record_failure("n2", now);
+        breakers.record_success("n2");
+        assert!(!breakers.record_failure("n2", now));
+
+        let mut disabled = Breakers::new(None, Duration::from_secs(1));
+        for _ in 0..10 {
+            assert!(!disabled.record_failure("n2", now));
+        }
+        assert!(disabled.allows("n2", now));
+    }
+}
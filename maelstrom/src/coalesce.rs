@@ -0,0 +1,291 @@
+use crate::Message;
+#[cfg(any(feature = "broadcast", feature = "counter"))]
+use crate::MessageBody;
+#[cfg(feature = "counter")]
+use crate::kv::Counter;
+#[cfg(feature = "counter")]
+use std::collections::HashMap;
+#[cfg(feature = "broadcast")]
+use std::collections::HashSet;
+use std::time::{Duration, Instant};
+
+/// Whether a message is fire-and-forget gossip that can be folded into another to the same peer
+pub fn mergeable(message: &Message) -> bool {
+    match &message.body {
+        #[cfg(feature = "broadcast")]
+        MessageBody::BroadcastGossip { msg_id: None, .. } => true,
+        #[cfg(feature = "broadcast")]
+        MessageBody::BroadcastGossipOk { msg_id: None, .. } => true,
+        #[cfg(feature = "counter")]
+        MessageBody::CounterGossip { msg_id: None, .. } => true,
+        _ => false,
+    }
+}
+
+/// Fold `next` into `held` when both are the same kind of gossip; hands `next` back otherwise
+fn merge(held: &mut Message, next: Message) -> Option<Message> {
+    match (&mut held.body, next.body) {
+        #[cfg(feature = "broadcast")]
+        (
+            MessageBody::BroadcastGossip {
+                msg_id: None,
+                messages,
+            },
+            MessageBody::BroadcastGossip {
+                msg_id: None,
+                messages: more,
+            },
+        ) => {
+            extend_new(messages, more);
+            None
+        }
+        #[cfg(feature = "broadcast")]
+        (
+            MessageBody::BroadcastGossipOk {
+                msg_id: None,
+                messages,
+            },
+            MessageBody::BroadcastGossipOk {
+                msg_id: None,
+                messages: more,
+            },
+        ) => {
+            extend_new(messages, more);
+            None
+        }
+        #[cfg(feature = "counter")]
+        (
+            MessageBody::CounterGossip {
+                msg_id: None,
+                counters,
+                named,
+                seq,
+            },
+            MessageBody::CounterGossip {
+                msg_id: None,
+                counters: more,
+                named: more_named,
+                seq: more_seq,
+            },
+        ) => {
+            keep_newest(counters, more);
+            for (key, more) in more_named {
+                keep_newest(named.entry(key).or_default(), more);
+            }
+            // Each gossip carries every entry its peer has not acknowledged, so the later
+            // sequence number covers the merged entries too
+            *seq = (*seq).max(more_seq);
+            None
+        }
+        (_, body) => Some(Message {
+            src: next.src,
+            dest: next.dest,
+            body,
+        }),
+    }
+}
+
+/// Append the values of `more` that `messages` does not hold yet
+#[cfg(feature = "broadcast")]
+fn extend_new(messages: &mut Vec<u64>, more: Vec<u64>) {
+    let known: HashSet<u64> = messages.iter().copied().collect();
+    messages.extend(more.into_iter().filter(|m| !known.contains(m)));
+}
+
+/// Fold later per-node counters into earlier ones from the same node's gossip
+#[cfg(feature = "counter")]
+fn keep_newest(counters: &mut HashMap<String, Counter>, more: HashMap<String, Counter>) {
+    for (node_id, counter) in more {
+        let held = counters.entry(node_id).or_default();
+        if counter.version >= held.version {
+            *held = counter;
+        }
+    }
+}
+
+/// Merge gossip headed to the same peer into one message per kind; everything else passes
+/// through in order
+pub fn coalesce(messages: Vec<Message>) -> Vec<Message> {
+    let mut out: Vec<Message> = Vec::with_capacity(messages.len());
+    for message in messages {
+        if !mergeable(&message) {
+            out.push(message);
+            continue;
+        }
+        let held = out.iter_mut().find(|held| {
+            held.dest == message.dest
+                && held.src == message.src
+                && mergeable(held)
+                && std::mem::discriminant(&held.body) == std::mem::discriminant(&message.body)
+        });
+        match held {
+            Some(held) => {
+                if let Some(rest) = merge(held, message) {
+                    out.push(rest);
+                }
+            }
+            None => out.push(message),
+        }
+    }
+    out
+}
+
+/// Holds outgoing gossip for a short window so bursts to the same peer go out as one message
+pub struct Coalescer {
+    window: Option<Duration>,
+    pending: Vec<Message>,
+    due: Option<Instant>,
+}
+
+impl Coalescer {
+    /// Without a window, only messages produced together are merged
+    pub fn new(window: Option<Duration>) -> Self {
+        Self {
+            window,
+            pending: Vec::new(),
+            due: None,
+        }
+    }
+
+    /// Take a batch of outgoing messages; returns what should be written now
+    pub fn push(&mut self, messages: Vec<Message>, now: Instant) -> Vec<Message> {
+        let Some(window) = self.window else {
+            return coalesce(messages);
+        };
+        let (held, now_out): (Vec<_>, Vec<_>) = messages.into_iter().partition(mergeable);
+        if !held.is_empty() {
+            self.pending.extend(held);
+            self.due.get_or_insert(now + window);
+        }
+        now_out
+    }
+
+    /// When the held messages need to go out
+    pub fn due(&self) -> Option<Instant> {
+        self.due
+    }
+
+    /// Release everything held, merged
+    pub fn flush(&mut self) -> Vec<Message> {
+        self.due = None;
+        coalesce(std::mem::take(&mut self.pending))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kv::Counter;
+    use std::collections::HashMap;
+
+    fn gossip(dest: &str, messages: Vec<u64>) -> Message {
+        Message {
+            src: "n1".to_string(),
+            dest: dest.to_string(),
+            body: MessageBody::BroadcastGossip {
+                msg_id: None,
+                messages,
+            },
+        }
+    }
+
+    fn counters(dest: &str, node_id: &str, version: u64, value: u64) -> Message {
+        Message {
+            src: "n1".to_string(),
+            dest: dest.to_string(),
+            body: MessageBody::CounterGossip {
+                msg_id: None,
+                counters: HashMap::from([(node_id.to_string(), Counter { version, value })]),
+                named: HashMap::new(),
+                seq: Some(version),
+            },
+        }
+    }
+
+    #[test]
+    fn test_gossip_to_the_same_peer_is_merged() {
+        let reply = crate::testing::read().src("n1").dest("c1");
+        let out = coalesce(vec![
+            gossip("n2", vec![1, 2]),
+            reply,
+            gossip("n3", vec![1]),
+            gossip("n2", vec![2, 3]),
+        ]);
+        assert_eq!(out.len(), 3);
+        assert_eq!(out[1].dest, "c1");
+        match &out[0].body {
+            MessageBody::BroadcastGossip { messages, .. } => assert_eq!(messages, &vec![1, 2, 3]),
+            _ => panic!("Expected BroadcastGossip message"),
+        }
+        assert_eq!(out[2].dest, "n3");
+    }
+
+    #[test]
+    fn test_gossip_acks_merge_apart_from_gossip() {
+        let ack = |messages: Vec<u64>| Message {
+            body: MessageBody::BroadcastGossipOk {
+                msg_id: None,
+                messages,
+            },
+            ..gossip("n2", Vec::new())
+        };
+        let out = coalesce(vec![ack(vec![1]), gossip("n2", vec![5]), ack(vec![1, 2])]);
+        assert_eq!(out.len(), 2);
+        match &out[0].body {
+            MessageBody::BroadcastGossipOk { messages, .. } => assert_eq!(messages, &vec![1, 2]),
+            _ => panic!("Expected BroadcastGossipOk message"),
+        }
+    }
+
+    #[test]
+    fn test_counter_gossip_keeps_newest_version() {
+        let out = coalesce(vec![
+            counters("n2", "n1", 3, 30),
+            counters("n2", "n1", 2, 20),
+            counters("n2", "n3", 1, 5),
+        ]);
+        assert_eq!(out.len(), 1);
+        match &out[0].body {
+            MessageBody::CounterGossip { counters, seq, .. } => {
+                assert_eq!(counters["n1"].value, 30);
+                assert_eq!(counters["n3"].value, 5);
+                assert_eq!(seq, &Some(3));
+            }
+            _ => panic!("Expected CounterGossip message"),
+        }
+    }
+
+    #[test]
+    fn test_tracked_messages_are_never_merged() {
+        let mut tracked = gossip("n2", vec![1]);
+        tracked.body.set_msg_id(4);
+        let out = coalesce(vec![tracked, gossip("n2", vec![2])]);
+        assert_eq!(out.len(), 2);
+    }
+
+    #[test]
+    fn test_window_holds_gossip_until_due() {
+        let start = Instant::now();
+        let mut coalescer = Coalescer::new(Some(Duration::from_millis(5)));
+
+        let reply = crate::testing::read().src("n1").dest("c1");
+        let now = coalescer.push(vec![gossip("n2", vec![1]), reply], start);
+        assert_eq!(now.len(), 1);
+        assert_eq!(now[0].dest, "c1");
+        assert_eq!(coalescer.due(), Some(start + Duration::from_millis(5)));
+
+        // A later burst joins the held batch without pushing the deadline out
+        let later = start + Duration::from_millis(2);
+        assert!(
+            coalescer
+                .push(vec![gossip("n2", vec![2])], later)
+                .is_empty()
+        );
+        assert_eq!(coalescer.due(), Some(start + Duration::from_millis(5)));
+
+        let flushed = coalescer.flush();
+        assert_eq!(flushed.len(), 1);
+        assert_eq!(coalescer.due(), None);
+        assert!(coalescer.flush().is_empty());
+    }
+}
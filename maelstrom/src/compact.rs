@@ -0,0 +1,81 @@
+use crate::MessageBody;
+use base64::{Engine as _, engine::general_purpose::STANDARD};
+
+/// First protocol version that understands `packed` bodies
+pub const PACKED_SINCE: u32 = 2;
+
+/// Encode a body as base64 MessagePack for the `data` field of a `packed` body
+pub fn pack(body: &MessageBody) -> Result<String, rmp_serde::encode::Error> {
+    let bytes = rmp_serde::to_vec_named(body)?;
+    Ok(STANDARD.encode(bytes))
+}
+
+/// Recover the body carried by a `packed` body
+pub fn unpack(data: &str) -> Result<MessageBody, Box<dyn std::error::Error + Send + Sync>> {
+    let bytes = STANDARD.decode(data)?;
+    Ok(rmp_serde::from_slice(&bytes)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ErrorCode, kv::Counter};
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_pack_round_trips_bodies() {
+        let gossip = MessageBody::BroadcastGossip {
+            msg_id: None,
+            messages: (0..200).collect(),
+        };
+        match unpack(&pack(&gossip).unwrap()).unwrap() {
+            MessageBody::BroadcastGossip { msg_id, messages } => {
+                assert_eq!(msg_id, None);
+                assert_eq!(messages, (0..200).collect::<Vec<_>>());
+            }
+            _ => panic!("Expected BroadcastGossip message"),
+        }
+
+        let counters = MessageBody::CounterGossip {
+            msg_id: None,
+            counters: HashMap::from([("n1".to_string(), Counter::default())]),
+            named: HashMap::new(),
+            seq: None,
+        };
+        match unpack(&pack(&counters).unwrap()).unwrap() {
+            MessageBody::CounterGossip { counters, .. } => assert!(counters.contains_key("n1")),
+            _ => panic!("Expected CounterGossip message"),
+        }
+
+        let error = MessageBody::Error {
+            msg_id: 2,
+            in_reply_to: 1,
+            code: ErrorCode::TxnConflict,
+            text: Some("conflict".to_string()),
+            extra: None,
+        };
+        match unpack(&pack(&error).unwrap()).unwrap() {
+            MessageBody::Error { code, text, .. } => {
+                assert_eq!(code, ErrorCode::TxnConflict);
+                assert_eq!(text.as_deref(), Some("conflict"));
+            }
+            _ => panic!("Expected Error message"),
+        }
+    }
+
+    #[test]
+    fn test_packed_gossip_is_smaller_than_json() {
+        let gossip = MessageBody::BroadcastGossip {
+            msg_id: None,
+            messages: (100_000..101_000).collect(),
+        };
+        let json = serde_json::to_string(&gossip).unwrap();
+        assert!(pack(&gossip).unwrap().len() < json.len());
+    }
+
+    #[test]
+    fn test_unpack_rejects_garbage() {
+        assert!(unpack("not base64!").is_err());
+        assert!(unpack(&STANDARD.encode([0xc1])).is_err());
+    }
+}
@@ -0,0 +1,578 @@
+use crate::{logging, rpc::RetryPolicy, writer::FlushPolicy};
+use std::net::SocketAddr;
+use std::num::NonZeroUsize;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+
+/// What to do with a message addressed to some other node
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MisroutePolicy {
+    /// Handle it as if it were ours
+    #[default]
+    Accept,
+    /// Handle it, logging a warning
+    Warn,
+    /// Discard it
+    Drop,
+    /// Discard it, answering requests with `NodeNotFound`
+    Reject,
+}
+
+/// How a node exchanges messages with the outside world
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Transport {
+    /// Newline-delimited JSON on stdin and stdout, as Maelstrom runs nodes
+    #[default]
+    Stdio,
+    /// The same protocol over TCP connections accepted on this address (see [`crate::tcp`])
+    Tcp(SocketAddr),
+}
+
+/// Runtime tuning for a node, read by `run_node` and exposed to handlers as `node.config`
+#[derive(Debug, Clone, PartialEq)]
+pub struct NodeConfig {
+    /// Capacity of the channel between the stdin reader and the message loop
+    pub channel_capacity: usize,
+    /// Overrides the period of handlers that tick; `None` keeps the handler's own interval
+    pub tick_interval: Option<Duration>,
+    /// Number of peers each node gossips with
+    pub fanout: usize,
+    /// Timeout and retry policy applied to new `rpc` calls
+    pub retry: RetryPolicy,
+    /// When the stdout writer flushes
+    pub flush: FlushPolicy,
+    /// How many recent `(src, msg_id)` pairs to remember, with their replies, so redelivered
+    /// requests are answered from cache instead of handled again; `None` turns this off
+    pub dedup: Option<NonZeroUsize>,
+    /// How long to hold outgoing gossip so bursts to the same peer merge; `None` merges only
+    /// messages produced by the same handler call
+    pub coalesce_window: Option<Duration>,
+    /// How often messages sent with `deliver` are resent while unacknowledged
+    pub retransmit_interval: Duration,
+    /// Inbound queue depth above which new client requests are refused with
+    /// `TemporarilyUnavailable` instead of queued; `None` never sheds
+    pub shed_above: Option<usize>,
+    /// How long a peer may stay silent before `alive_peers` leaves it out; `None` presumes
+    /// every peer alive. Pair it with `ping_interval` so quiet but healthy peers are not
+    /// suspected.
+    pub suspect_after: Option<Duration>,
+    /// How often to ping peers we have not heard from lately; `None` relies on regular
+    /// traffic alone
+    pub ping_interval: Option<Duration>,
+    /// Consecutive RPC failures to a peer that open its circuit; `None` never opens one
+    pub breaker_failures: Option<u32>,
+    /// How long an open circuit holds traffic back before letting a probe through
+    pub breaker_cooldown: Duration,
+    /// Seed for `node.rng` and retry jitter, making runs reproducible; `None` seeds from the OS
+    pub seed: Option<u64>,
+    /// Treatment of messages whose `dest` is not this node, checked once it has an id
+    pub misroute: MisroutePolicy,
+    /// Log filter such as `debug` or `maelstrom=trace`, `None` for `info`. Without the
+    /// `tracing` feature only `off`, `error` and `warn` matter: they silence `node.log`.
+    pub log_level: Option<String>,
+    /// Where messages come from and go to
+    pub transport: Transport,
+    /// Unix socket of a viewer to stream events to (see [`crate::events`]); `None` streams
+    /// nothing
+    pub events: Option<PathBuf>,
+    /// File to append every inbound and outbound line to (see [`crate::tee`]); `None`
+    /// captures nothing
+    pub tee: Option<PathBuf>,
+    /// Directory for state a handler keeps on disk across restarts, such as the kafka logs
+    /// (see [`crate::log::Logs::open`]); `None` keeps everything in memory
+    pub data_dir: Option<PathBuf>,
+}
+
+impl Default for NodeConfig {
+    fn default() -> Self {
+        Self {
+            channel_capacity: 32,
+            tick_interval: None,
+            fanout: 4,
+            retry: RetryPolicy::default(),
+            flush: FlushPolicy::default(),
+            dedup: None,
+            coalesce_window: None,
+            retransmit_interval: Duration::from_millis(500),
+            shed_above: None,
+            suspect_after: None,
+            ping_interval: None,
+            breaker_failures: None,
+            breaker_cooldown: Duration::from_secs(1),
+            seed: None,
+            misroute: MisroutePolicy::default(),
+            log_level: None,
+            transport: Transport::default(),
+            events: None,
+            tee: None,
+            data_dir: None,
+        }
+    }
+}
+
+impl NodeConfig {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn channel_capacity(mut self, capacity: usize) -> Self {
+        self.channel_capacity = capacity.max(1);
+        self
+    }
+
+    /// Tick every `interval`, clamped to at least a millisecond since a zero period panics
+    pub fn tick_interval(mut self, interval: Duration) -> Self {
+        self.tick_interval = Some(interval.max(Duration::from_millis(1)));
+        self
+    }
+
+    pub fn fanout(mut self, fanout: usize) -> Self {
+        self.fanout = fanout;
+        self
+    }
+
+    pub fn retry(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    pub fn flush(mut self, flush: FlushPolicy) -> Self {
+        self.flush = flush;
+        self
+    }
+
+    /// Replay the cached reply (or drop) requests whose `(src, msg_id)` is among the last
+    /// `capacity` seen; 0 turns it off
+    pub fn dedup(mut self, capacity: usize) -> Self {
+        self.dedup = NonZeroUsize::new(capacity);
+        self
+    }
+
+    pub fn coalesce_window(mut self, window: Duration) -> Self {
+        self.coalesce_window = Some(window);
+        self
+    }
+
+    pub fn retransmit_interval(mut self, interval: Duration) -> Self {
+        self.retransmit_interval = interval;
+        self
+    }
+
+    pub fn shed_above(mut self, depth: usize) -> Self {
+        self.shed_above = Some(depth);
+        self
+    }
+
+    pub fn suspect_after(mut self, after: Duration) -> Self {
+        self.suspect_after = Some(after);
+        self
+    }
+
+    /// Ping quiet peers every `interval`, clamped to at least a millisecond like `tick_interval`
+    pub fn ping_interval(mut self, interval: Duration) -> Self {
+        self.ping_interval = Some(interval.max(Duration::from_millis(1)));
+        self
+    }
+
+    /// Open a peer's circuit after `failures` consecutive failed calls, for `cooldown`
+    pub fn breaker(mut self, failures: u32, cooldown: Duration) -> Self {
+        self.breaker_failures = Some(failures);
+        self.breaker_cooldown = cooldown;
+        self
+    }
+
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.seed = Some(seed);
+        self
+    }
+
+    pub fn misroute(mut self, policy: MisroutePolicy) -> Self {
+        self.misroute = policy;
+        self
+    }
+
+    pub fn log_level(mut self, level: impl Into<String>) -> Self {
+        self.log_level = Some(level.into());
+        self
+    }
+
+    pub fn transport(mut self, transport: Transport) -> Self {
+        self.transport = transport;
+        self
+    }
+
+    pub fn events(mut self, socket: impl Into<PathBuf>) -> Self {
+        self.events = Some(socket.into());
+        self
+    }
+
+    pub fn tee(mut self, path: impl Into<PathBuf>) -> Self {
+        self.tee = Some(path.into());
+        self
+    }
+
+    pub fn data_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.data_dir = Some(dir.into());
+        self
+    }
+
+    /// What `run_node` uses: defaults, then environment variables, then command-line flags
+    pub fn load() -> Self {
+        Self::from_env().args(std::env::args().skip(1))
+    }
+
+    /// Defaults overridden by any `MAELSTROM_*` environment variables that are set:
+    ///
+    /// - `MAELSTROM_CHANNEL_CAPACITY`: inbound channel capacity
+    /// - `MAELSTROM_TICK_MS`: tick interval in milliseconds
+    /// - `MAELSTROM_FANOUT`: gossip fanout
+    /// - `MAELSTROM_RPC_ATTEMPTS`: sends per RPC, including the first
+    /// - `MAELSTROM_RPC_TIMEOUT_MS`: wait after the first send of an RPC
+    /// - `MAELSTROM_RPC_RTT_FACTOR`: multiple of a peer's p99 round trip to wait instead, 0
+    ///   to always use the fixed timeout
+    /// - `MAELSTROM_FLUSH`: `every` or `idle`
+    /// - `MAELSTROM_DEDUP`: de-duplication capacity, 0 to disable
+    /// - `MAELSTROM_COALESCE_MS`: gossip coalescing window in milliseconds
+    /// - `MAELSTROM_RETRANSMIT_MS`: resend interval for unacknowledged deliveries
+    /// - `MAELSTROM_SHED_ABOVE`: inbound queue depth above which client requests are refused
+    /// - `MAELSTROM_SUSPECT_MS`: silence after which a peer is presumed dead
+    /// - `MAELSTROM_PING_MS`: ping interval for quiet peers
+    /// - `MAELSTROM_BREAKER_FAILURES`: consecutive failed calls that open a peer's circuit
+    /// - `MAELSTROM_BREAKER_COOLDOWN_MS`: how long an open circuit stays open
+    /// - `MAELSTROM_SEED`: seed for every random decision the node makes
+    /// - `MAELSTROM_MISROUTE`: `accept`, `warn`, `drop`, or `reject` messages for other nodes
+    /// - `MAELSTROM_LOG`: log level or filter
+    /// - `MAELSTROM_LISTEN`: address to accept TCP connections on instead of using stdio
+    /// - `MAELSTROM_EVENTS`: Unix socket to stream events to
+    /// - `MAELSTROM_TEE`: file to capture every inbound and outbound line in
+    /// - `MAELSTROM_DATA_DIR`: directory for state kept on disk across restarts
+    pub fn from_env() -> Self {
+        Self::from_lookup(|name| std::env::var(name).ok())
+    }
+
+    /// Like [`NodeConfig::from_env`], reading variables through `lookup`; unparsable values
+    /// are reported on stderr and ignored
+    pub fn from_lookup(lookup: impl Fn(&str) -> Option<String>) -> Self {
+        let mut config = Self::default();
+        if let Some(capacity) = env_number(&lookup, "MAELSTROM_CHANNEL_CAPACITY") {
+            config = config.channel_capacity(capacity);
+        }
+        if let Some(ms) = env_number(&lookup, "MAELSTROM_TICK_MS") {
+            config = config.tick_interval(Duration::from_millis(ms));
+        }
+        if let Some(fanout) = env_number(&lookup, "MAELSTROM_FANOUT") {
+            config = config.fanout(fanout);
+        }
+        if let Some(attempts) = env_number(&lookup, "MAELSTROM_RPC_ATTEMPTS") {
+            config.retry.max_attempts = attempts;
+        }
+        if let Some(ms) = env_number(&lookup, "MAELSTROM_RPC_TIMEOUT_MS") {
+            config.retry.initial_backoff = Duration::from_millis(ms);
+        }
+        if let Some(factor) = env_number::<f64>(&lookup, "MAELSTROM_RPC_RTT_FACTOR") {
+            config.retry.rtt_factor = (factor > 0.0).then_some(factor);
+        }
+        if let Some(capacity) = env_number(&lookup, "MAELSTROM_DEDUP") {
+            config = config.dedup(capacity);
+        }
+        if let Some(ms) = env_number(&lookup, "MAELSTROM_COALESCE_MS") {
+            config = config.coalesce_window(Duration::from_millis(ms));
+        }
+        if let Some(ms) = env_number(&lookup, "MAELSTROM_RETRANSMIT_MS") {
+            config = config.retransmit_interval(Duration::from_millis(ms));
+        }
+        if let Some(depth) = env_number(&lookup, "MAELSTROM_SHED_ABOVE") {
+            config = config.shed_above(depth);
+        }
+        if let Some(ms) = env_number(&lookup, "MAELSTROM_SUSPECT_MS") {
+            config = config.suspect_after(Duration::from_millis(ms));
+        }
+        if let Some(ms) = env_number(&lookup, "MAELSTROM_PING_MS") {
+            config = config.ping_interval(Duration::from_millis(ms));
+        }
+        if let Some(failures) = env_number(&lookup, "MAELSTROM_BREAKER_FAILURES") {
+            config.breaker_failures = Some(failures);
+        }
+        if let Some(ms) = env_number(&lookup, "MAELSTROM_BREAKER_COOLDOWN_MS") {
+            config.breaker_cooldown = Duration::from_millis(ms);
+        }
+        if let Some(seed) = env_number(&lookup, "MAELSTROM_SEED") {
+            config = config.seed(seed);
+        }
+        if let Some(level) = lookup("MAELSTROM_LOG") {
+            config = config.log_level(level);
+        }
+        if let Some(socket) = lookup("MAELSTROM_EVENTS") {
+            config = config.events(socket);
+        }
+        if let Some(path) = lookup("MAELSTROM_TEE") {
+            config = config.tee(path);
+        }
+        if let Some(dir) = lookup("MAELSTROM_DATA_DIR") {
+            config = config.data_dir(dir);
+        }
+        if let Some(addr) = lookup("MAELSTROM_LISTEN") {
+            config = config.listen("MAELSTROM_LISTEN", &addr);
+        }
+        let flush = [
+            ("every", FlushPolicy::EveryMessage),
+            ("idle", FlushPolicy::WhenIdle),
+        ];
+        if let Some(flush) = env_choice(&lookup, "MAELSTROM_FLUSH", &flush) {
+            config.flush = flush;
+        }
+        let misroute = [
+            ("accept", MisroutePolicy::Accept),
+            ("warn", MisroutePolicy::Warn),
+            ("drop", MisroutePolicy::Drop),
+            ("reject", MisroutePolicy::Reject),
+        ];
+        if let Some(misroute) = env_choice(&lookup, "MAELSTROM_MISROUTE", &misroute) {
+            config.misroute = misroute;
+        }
+        config
+    }
+
+    /// Override settings from command-line flags, given as `--flag value` or `--flag=value`;
+    /// unknown flags and unparsable values are reported on stderr and ignored
+    ///
+    /// - `--gossip-interval <ms>`: tick interval in milliseconds
+    /// - `--fanout <n>`: gossip fanout
+    /// - `--channel-capacity <n>`: inbound channel capacity
+    /// - `--log-level <filter>`: log level or filter
+    /// - `--listen <addr>`: accept TCP connections on `addr` instead of using stdio
+    pub fn args(mut self, args: impl IntoIterator<Item = String>) -> Self {
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            let (flag, inline) = match arg.split_once('=') {
+                Some((flag, value)) => (flag.to_string(), Some(value.to_string())),
+                None => (arg, None),
+            };
+            if !matches!(
+                flag.as_str(),
+                "--gossip-interval"
+                    | "--fanout"
+                    | "--channel-capacity"
+                    | "--log-level"
+                    | "--listen"
+            ) {
+                logging::log_warn!("ignoring unknown argument {flag}");
+                continue;
+            }
+            let Some(value) = inline.or_else(|| args.next()) else {
+                logging::log_warn!("ignoring {flag}: missing value");
+                break;
+            };
+            match flag.as_str() {
+                "--gossip-interval" => match parse_value(&flag, &value) {
+                    Some(0) => {
+                        logging::log_warn!("ignoring {flag}=0: the interval must be positive")
+                    }
+                    Some(ms) => self = self.tick_interval(Duration::from_millis(ms)),
+                    None => {}
+                },
+                "--fanout" => {
+                    if let Some(fanout) = parse_value(&flag, &value) {
+                        self = self.fanout(fanout);
+                    }
+                }
+                "--channel-capacity" => {
+                    if let Some(capacity) = parse_value(&flag, &value) {
+                        self = self.channel_capacity(capacity);
+                    }
+                }
+                "--listen" => self = self.listen(&flag, &value),
+                _ => self = self.log_level(value),
+            }
+        }
+        self
+    }
+
+    /// Switch to TCP on `addr`, set by `source`; an unparsable address is reported and ignored
+    fn listen(self, source: &str, addr: &str) -> Self {
+        match addr.trim().parse() {
+            Ok(addr) => self.transport(Transport::Tcp(addr)),
+            Err(_) => {
+                logging::log_warn!("ignoring {source}={addr}: not a socket address");
+                self
+            }
+        }
+    }
+}
+
+/// Read the variable `name` through `lookup` as a number, as [`NodeConfig::from_lookup`] reads
+/// its own; a value that does not parse is logged as a warning and ignored
+pub fn env_number<T: FromStr>(lookup: impl Fn(&str) -> Option<String>, name: &str) -> Option<T> {
+    parse_value(name, &lookup(name)?)
+}
+
+/// Read the variable `name` through `lookup` as the value paired with its spelling in
+/// `choices`; a value naming none of them is logged as a warning and ignored
+pub fn env_choice<T: Copy>(
+    lookup: impl Fn(&str) -> Option<String>,
+    name: &str,
+    choices: &[(&str, T)],
+) -> Option<T> {
+    let raw = lookup(name)?;
+    let chosen = choices
+        .iter()
+        .find(|(spelling, _)| *spelling == raw.trim())
+        .map(|&(_, value)| value);
+    if chosen.is_none() {
+        let spellings: Vec<&str> = choices.iter().map(|&(spelling, _)| spelling).collect();
+        let expected = match spellings.split_last() {
+            Some((last, [])) => last.to_string(),
+            Some((last, [first])) => format!("{first} or {last}"),
+            Some((last, rest)) => format!("{}, or {last}", rest.join(", ")),
+            None => "nothing".to_string(),
+        };
+        logging::log_warn!("ignoring {name}={raw}: expected {expected}");
+    }
+    chosen
+}
+
+fn parse_value<T: FromStr>(name: &str, raw: &str) -> Option<T> {
+    match raw.trim().parse() {
+        Ok(value) => Some(value),
+        Err(_) => {
+            logging::log_warn!("ignoring {name}={raw}: not a valid number");
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn lookup(vars: &[(&str, &str)]) -> impl Fn(&str) -> Option<String> {
+        let vars: HashMap<String, String> = vars
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect();
+        move |name| vars.get(name).cloned()
+    }
+
+    #[test]
+    fn test_builder_overrides_defaults() {
+        let config = NodeConfig::new()
+            .channel_capacity(0)
+            .tick_interval(Duration::from_millis(50))
+            .fanout(2)
+            .flush(FlushPolicy::EveryMessage);
+        assert_eq!(config.channel_capacity, 1);
+        assert_eq!(config.tick_interval, Some(Duration::from_millis(50)));
+        assert_eq!(config.fanout, 2);
+        assert_eq!(config.retry, RetryPolicy::default());
+        assert_eq!(config.flush, FlushPolicy::EveryMessage);
+        assert_eq!(config.dedup, None);
+    }
+
+    #[test]
+    fn test_zero_tick_interval_is_clamped() {
+        let config = NodeConfig::new().tick_interval(Duration::ZERO);
+        assert_eq!(config.tick_interval, Some(Duration::from_millis(1)));
+
+        let config = NodeConfig::from_lookup(lookup(&[("MAELSTROM_TICK_MS", "0")]));
+        assert_eq!(config.tick_interval, Some(Duration::from_millis(1)));
+    }
+
+    #[test]
+    fn test_env_vars_override_defaults_and_bad_values_are_ignored() {
+        let config = NodeConfig::from_lookup(lookup(&[
+            ("MAELSTROM_CHANNEL_CAPACITY", "128"),
+            ("MAELSTROM_TICK_MS", "250"),
+            ("MAELSTROM_FANOUT", "many"),
+            ("MAELSTROM_RPC_ATTEMPTS", "3"),
+            ("MAELSTROM_RPC_TIMEOUT_MS", "500"),
+            ("MAELSTROM_FLUSH", "every"),
+            ("MAELSTROM_DEDUP", "1024"),
+            ("MAELSTROM_COALESCE_MS", "10"),
+            ("MAELSTROM_SHED_ABOVE", "64"),
+            ("MAELSTROM_SUSPECT_MS", "1000"),
+            ("MAELSTROM_PING_MS", "200"),
+            ("MAELSTROM_RPC_RTT_FACTOR", "0"),
+            ("MAELSTROM_BREAKER_FAILURES", "3"),
+            ("MAELSTROM_SEED", "42"),
+            ("MAELSTROM_MISROUTE", "reject"),
+            ("MAELSTROM_EVENTS", "/tmp/viewer.sock"),
+            ("MAELSTROM_TEE", "/tmp/n1.tee.jsonl"),
+            ("MAELSTROM_DATA_DIR", "/var/lib/kafka"),
+        ]));
+        assert_eq!(config.channel_capacity, 128);
+        assert_eq!(config.tick_interval, Some(Duration::from_millis(250)));
+        assert_eq!(config.fanout, 4);
+        assert_eq!(config.retry.max_attempts, 3);
+        assert_eq!(config.retry.initial_backoff, Duration::from_millis(500));
+        assert_eq!(config.flush, FlushPolicy::EveryMessage);
+        assert_eq!(config.dedup, NonZeroUsize::new(1024));
+        assert_eq!(config.coalesce_window, Some(Duration::from_millis(10)));
+        assert_eq!(config.shed_above, Some(64));
+        assert_eq!(config.suspect_after, Some(Duration::from_secs(1)));
+        assert_eq!(config.retry.rtt_factor, None);
+        assert_eq!(config.breaker_failures, Some(3));
+        assert_eq!(config.seed, Some(42));
+        assert_eq!(config.misroute, MisroutePolicy::Reject);
+        assert_eq!(config.events, Some(PathBuf::from("/tmp/viewer.sock")));
+        assert_eq!(config.tee, Some(PathBuf::from("/tmp/n1.tee.jsonl")));
+        assert_eq!(config.data_dir, Some(PathBuf::from("/var/lib/kafka")));
+        assert_eq!(config.ping_interval, Some(Duration::from_millis(200)));
+
+        assert_eq!(NodeConfig::from_lookup(lookup(&[])), NodeConfig::default());
+    }
+
+    #[test]
+    fn test_env_choices_match_their_spelling_or_are_ignored() {
+        let choices = [("on", true), ("off", false)];
+        let vars = lookup(&[("A", " off "), ("B", "maybe")]);
+        assert_eq!(env_choice(&vars, "A", &choices), Some(false));
+        assert_eq!(env_choice(&vars, "B", &choices), None);
+        assert_eq!(env_choice(&vars, "C", &choices), None);
+        assert_eq!(env_number::<u64>(&vars, "A"), None);
+    }
+
+    #[test]
+    fn test_flags_override_env_vars() {
+        let args = [
+            "--gossip-interval",
+            "75",
+            "--fanout=6",
+            "--channel-capacity",
+            "lots",
+            "--verbose",
+            "--log-level=debug",
+        ];
+        let config = NodeConfig::from_lookup(lookup(&[
+            ("MAELSTROM_FANOUT", "2"),
+            ("MAELSTROM_CHANNEL_CAPACITY", "64"),
+            ("MAELSTROM_LOG", "warn"),
+        ]))
+        .args(args.map(String::from));
+        assert_eq!(config.tick_interval, Some(Duration::from_millis(75)));
+        assert_eq!(config.fanout, 6);
+        assert_eq!(config.channel_capacity, 64);
+        assert_eq!(config.log_level.as_deref(), Some("debug"));
+        assert_eq!(config.transport, Transport::Stdio);
+
+        // A zero interval is refused and the env var's value kept
+        let config = NodeConfig::from_lookup(lookup(&[("MAELSTROM_TICK_MS", "40")]))
+            .args(["--gossip-interval=0".to_string()]);
+        assert_eq!(config.tick_interval, Some(Duration::from_millis(40)));
+
+        let config = NodeConfig::from_lookup(lookup(&[("MAELSTROM_PING_MS", "0")]));
+        assert_eq!(config.ping_interval, Some(Duration::from_millis(1)));
+
+        // A trailing flag without its value is ignored
+        let config = NodeConfig::new().args(["--fanout".to_string()]);
+        assert_eq!(config.fanout, 4);
+
+        let config = NodeConfig::from_lookup(lookup(&[("MAELSTROM_LISTEN", "nowhere")]))
+            .args(["--listen".to_string(), "127.0.0.1:7000".to_string()]);
+        assert_eq!(
+            config.transport,
+            Transport::Tcp("127.0.0.1:7000".parse().unwrap())
+        );
+    }
+}
@@ -0,0 +1,101 @@
+use crate::Message;
+use lru::LruCache;
+use std::num::NonZeroUsize;
+
+/// Remembers the most recent `(src, msg_id)` pairs so redelivered requests reach the handler
+/// only once, along with the last reply sent for each so a redelivery can be answered again
+pub struct Dedup {
+    seen: LruCache<(String, u64), Option<Message>>,
+}
+
+impl Dedup {
+    /// Track up to `capacity` pairs, forgetting the least recently seen first
+    pub fn new(capacity: NonZeroUsize) -> Self {
+        Self {
+            seen: LruCache::new(capacity),
+        }
+    }
+
+    /// Record a delivery; returns false if this pair was already seen
+    pub fn first_delivery(&mut self, src: &str, msg_id: u64) -> bool {
+        let key = (src.to_string(), msg_id);
+        if self.seen.get(&key).is_some() {
+            return false;
+        }
+        self.seen.put(key, None);
+        true
+    }
+
+    /// Keep `reply` as the answer to the request it replies to, if that request is tracked
+    pub fn remember_reply(&mut self, reply: &Message) {
+        let Some(in_reply_to) = reply.body.in_reply_to() else {
+            return;
+        };
+        if let Some(cached) = self.seen.peek_mut(&(reply.dest.clone(), in_reply_to)) {
+            *cached = Some(reply.clone());
+        }
+    }
+
+    /// The last reply sent for a request, if one has been sent yet
+    pub fn cached_reply(&self, src: &str, msg_id: u64) -> Option<&Message> {
+        self.seen.peek(&(src.to_string(), msg_id))?.as_ref()
+    }
+
+    pub fn len(&self) -> usize {
+        self.seen.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.seen.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MessageBody;
+
+    #[test]
+    fn test_repeats_are_caught_per_sender() {
+        let mut dedup = Dedup::new(NonZeroUsize::new(8).unwrap());
+        assert!(dedup.first_delivery("c1", 1));
+        assert!(!dedup.first_delivery("c1", 1));
+        assert!(dedup.first_delivery("c2", 1));
+        assert!(dedup.first_delivery("c1", 2));
+        assert_eq!(dedup.len(), 3);
+    }
+
+    #[test]
+    fn test_least_recently_seen_pairs_are_forgotten() {
+        let mut dedup = Dedup::new(NonZeroUsize::new(2).unwrap());
+        dedup.first_delivery("c1", 1);
+        dedup.first_delivery("c1", 2);
+        // Seeing 1 again keeps it fresh, so 2 is evicted next
+        assert!(!dedup.first_delivery("c1", 1));
+        dedup.first_delivery("c1", 3);
+        assert!(!dedup.first_delivery("c1", 1));
+        assert!(dedup.first_delivery("c1", 2));
+    }
+
+    #[test]
+    fn test_replies_are_kept_for_tracked_requests() {
+        let mut dedup = Dedup::new(NonZeroUsize::new(8).unwrap());
+        let reply = |dest: &str, in_reply_to| Message {
+            src: "n1".to_string(),
+            dest: dest.to_string(),
+            body: MessageBody::AddOk {
+                msg_id: 9,
+                in_reply_to,
+            },
+        };
+
+        dedup.first_delivery("c1", 1);
+        assert!(dedup.cached_reply("c1", 1).is_none());
+        dedup.remember_reply(&reply("c1", 1));
+        dedup.remember_reply(&reply("c2", 1));
+        assert_eq!(dedup.cached_reply("c1", 1).unwrap().body.msg_id(), Some(9));
+        // Replies to requests we never saw are not kept
+        assert!(dedup.cached_reply("c2", 1).is_none());
+        assert_eq!(dedup.len(), 1);
+    }
+}
@@ -0,0 +1,253 @@
+//! Delta-state replication: rather than shipping whole states, or guessing what a peer
+//! already has, each mutation yields a small delta that is kept for every peer until that
+//! peer acknowledges it. Unacknowledged deltas are resent joined into one, so a partition only
+//! delays them, and merging is idempotent, so resends and duplicates are harmless.
+//!
+//! ```ignore
+//! let mut values = DeltaState::new(GSet::new(), &node.peers);
+//! values.apply(GSet::from_iter([7]));
+//! for peer in node.peers.clone() {
+//!     if let Some((seq, delta)) = values.outgoing(&peer) {
+//!         // Send both; the peer calls `receive(&node.id, delta)` and answers with `seq`,
+//!         // which goes to `values.ack(&peer, seq)`
+//!     }
+//! }
+//! ```
+
+use crate::kv::{GSet, KV, LWWMap, NamedKV, ORSet, PNCounter};
+use std::collections::{BTreeMap, HashMap};
+
+/// A state that merges: joining in the same delta twice, or deltas in any order, ends in the
+/// same state
+pub trait DeltaCrdt: Clone + Default + PartialEq {
+    fn merge(&mut self, delta: Self);
+}
+
+impl<T: Ord + Clone> DeltaCrdt for GSet<T> {
+    fn merge(&mut self, delta: Self) {
+        GSet::merge(self, delta);
+    }
+}
+
+impl<T: Ord + Clone> DeltaCrdt for ORSet<T> {
+    fn merge(&mut self, delta: Self) {
+        ORSet::merge(self, delta);
+    }
+}
+
+impl<V: Clone + PartialEq> DeltaCrdt for LWWMap<V> {
+    fn merge(&mut self, delta: Self) {
+        LWWMap::merge(self, delta);
+    }
+}
+
+impl DeltaCrdt for KV {
+    fn merge(&mut self, delta: Self) {
+        KV::merge(self, delta.counters);
+    }
+}
+
+impl DeltaCrdt for NamedKV {
+    fn merge(&mut self, delta: Self) {
+        // Counters the delta does not touch are left out rather than created empty
+        for (key, kv) in delta.counters.into_iter().filter(|(_, kv)| !kv.is_empty()) {
+            NamedKV::merge(self, &key, kv.counters);
+        }
+    }
+}
+
+impl DeltaCrdt for PNCounter {
+    fn merge(&mut self, delta: Self) {
+        PNCounter::merge(self, delta.increments.counters, delta.decrements.counters);
+    }
+}
+
+/// A replicated state and, for every peer, the deltas it has not acknowledged yet
+pub struct DeltaState<C> {
+    state: C,
+    /// Unacknowledged deltas per peer, by sequence number
+    pending: HashMap<String, BTreeMap<u64, C>>,
+    /// Sequence number of the last delta buffered
+    seq: u64,
+}
+
+impl<C: DeltaCrdt> DeltaState<C> {
+    pub fn new(state: C, peers: &[String]) -> Self {
+        let mut delta_state = Self {
+            state,
+            pending: HashMap::new(),
+            seq: 0,
+        };
+        delta_state.set_peers(peers);
+        delta_state
+    }
+
+    pub fn state(&self) -> &C {
+        &self.state
+    }
+
+    /// Replicate to `peers` from now on; a new peer is owed the whole state, and departed
+    /// peers' buffers are dropped
+    pub fn set_peers(&mut self, peers: &[String]) {
+        self.pending.retain(|peer, _| peers.contains(peer));
+        let empty = self.state == C::default();
+        for peer in peers {
+            if self.pending.contains_key(peer) {
+                continue;
+            }
+            let mut pending = BTreeMap::new();
+            if !empty {
+                self.seq += 1;
+                pending.insert(self.seq, self.state.clone());
+            }
+            self.pending.insert(peer.clone(), pending);
+        }
+    }
+
+    /// Make a local change described by `delta`
+    pub fn apply(&mut self, delta: C) {
+        self.state.merge(delta.clone());
+        self.buffer(delta, None);
+    }
+
+    /// Make a local change with `mutate`, which changes the state and returns the delta
+    /// describing what it did, for changes (like [`ORSet::insert`]) that need the state
+    pub fn mutate(&mut self, mutate: impl FnOnce(&mut C) -> C) {
+        let delta = mutate(&mut self.state);
+        self.buffer(delta, None);
+    }
+
+    /// Merge a delta received from `from`. If it taught us anything it is passed on to the
+    /// other peers, so deltas reach nodes `from` does not talk to; returns whether it did.
+    pub fn receive(&mut self, from: &str, delta: C) -> bool {
+        let before = self.state.clone();
+        self.state.merge(delta.clone());
+        if self.state == before {
+            return false;
+        }
+        self.buffer(delta, Some(from));
+        true
+    }
+
+    /// Everything `peer` has not acknowledged, joined into one delta, and the sequence number
+    /// to acknowledge it with; `None` when it is up to date. The joined delta replaces the
+    /// ones it covers, so the buffer stays small while a peer is unreachable.
+    pub fn outgoing(&mut self, peer: &str) -> Option<(u64, C)> {
+        let pending = self.pending.get_mut(peer)?;
+        let (&seq, _) = pending.last_key_value()?;
+        let mut joined = C::default();
+        for (_, delta) in std::mem::take(pending) {
+            joined.merge(delta);
+        }
+        pending.insert(seq, joined.clone());
+        Some((seq, joined))
+    }
+
+    /// `peer` merged everything up to `seq`
+    pub fn ack(&mut self, peer: &str, seq: u64) {
+        if let Some(pending) = self.pending.get_mut(peer) {
+            pending.retain(|&pending_seq, _| pending_seq > seq);
+        }
+    }
+
+    /// Deltas `peer` has yet to acknowledge
+    pub fn unacked(&self, peer: &str) -> usize {
+        self.pending.get(peer).map_or(0, BTreeMap::len)
+    }
+
+    fn buffer(&mut self, delta: C, except: Option<&str>) {
+        // A change that needs no replicating, such as setting up entries, owes peers nothing
+        if delta == C::default() {
+            return;
+        }
+        self.seq += 1;
+        for (peer, pending) in &mut self.pending {
+            if Some(peer.as_str()) != except {
+                pending.insert(self.seq, delta.clone());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kv::Stamp;
+
+    fn peers(ids: &[&str]) -> Vec<String> {
+        ids.iter().map(|id| id.to_string()).collect()
+    }
+
+    fn set<const N: usize>(values: [u64; N]) -> GSet<u64> {
+        GSet::from_iter(values)
+    }
+
+    #[test]
+    fn test_deltas_are_resent_until_acknowledged() {
+        let mut a = DeltaState::new(GSet::new(), &peers(&["n2"]));
+        let mut b = DeltaState::new(GSet::new(), &peers(&["n1"]));
+        assert_eq!(a.outgoing("n2"), None);
+
+        // The first delta is lost in a partition; the resend carries it with the next
+        a.apply(set([1]));
+        let _lost = a.outgoing("n2");
+        a.apply(set([2]));
+        let (seq, delta) = a.outgoing("n2").unwrap();
+        assert_eq!(delta, set([1, 2]));
+        assert_eq!(a.unacked("n2"), 1);
+
+        assert!(b.receive("n1", delta.clone()));
+        assert!(!b.receive("n1", delta));
+        a.ack("n2", seq);
+        assert_eq!(a.outgoing("n2"), None);
+        assert_eq!(b.state(), &set([1, 2]));
+    }
+
+    #[test]
+    fn test_received_deltas_travel_on_but_not_back() {
+        let mut b = DeltaState::new(LWWMap::new(), &peers(&["n1", "n3"]));
+        let mut delta = LWWMap::new();
+        delta.set("x", Some(1), Stamp::new(1, "n1"));
+        assert!(b.receive("n1", delta.clone()));
+        assert_eq!(b.outgoing("n1"), None);
+        assert_eq!(b.outgoing("n3").unwrap().1, delta);
+    }
+
+    #[test]
+    fn test_new_peers_are_owed_the_whole_state() {
+        let mut a = DeltaState::new(ORSet::new(), &peers(&["n3"]));
+        a.mutate(|state| {
+            let before = state.clone();
+            state.insert("n1", 4);
+            state.delta(&before)
+        });
+        let (seq, _) = a.outgoing("n3").unwrap();
+        a.ack("n3", seq);
+
+        a.set_peers(&peers(&["n2"]));
+        assert_eq!(a.unacked("n3"), 0);
+        let (_, everything) = a.outgoing("n2").unwrap();
+        assert!(everything.contains(&4));
+    }
+
+    #[test]
+    fn test_counters_replicate_one_entry_at_a_time() {
+        let mut a = DeltaState::new(NamedKV::new(), &peers(&["n2"]));
+        let mut b = DeltaState::new(NamedKV::new(), &peers(&["n1"]));
+        a.mutate(|kv| {
+            kv.entry("").init(peers(&["n1", "n2"]));
+            NamedKV::new()
+        });
+        assert_eq!(a.outgoing("n2"), None);
+
+        a.mutate(|kv| {
+            kv.add("hits", "n1".to_string(), 3);
+            kv.slice("hits", "n1")
+        });
+        let (_, delta) = a.outgoing("n2").unwrap();
+        assert_eq!(delta.counters.keys().collect::<Vec<_>>(), vec!["hits"]);
+        assert!(b.receive("n1", delta));
+        assert_eq!(b.state().read("hits"), 3);
+        assert!(b.state().get("").is_none());
+    }
+}
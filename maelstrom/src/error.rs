@@ -0,0 +1,113 @@
+use crate::{ErrorCode, Message, Node, validate::Invalid};
+use std::fmt;
+
+/// A failed request, turned into an `error` reply to its sender by the framework
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MaelstromError {
+    pub code: ErrorCode,
+    pub text: String,
+}
+
+impl MaelstromError {
+    pub fn new(code: ErrorCode, text: impl Into<String>) -> Self {
+        Self {
+            code,
+            text: text.into(),
+        }
+    }
+
+    pub fn timeout(text: impl Into<String>) -> Self {
+        Self::new(ErrorCode::Timeout, text)
+    }
+
+    pub fn not_supported(text: impl Into<String>) -> Self {
+        Self::new(ErrorCode::NotSupported, text)
+    }
+
+    pub fn temporarily_unavailable(text: impl Into<String>) -> Self {
+        Self::new(ErrorCode::TemporarilyUnavailable, text)
+    }
+
+    pub fn malformed_message(text: impl Into<String>) -> Self {
+        Self::new(ErrorCode::MalformedMessage, text)
+    }
+
+    pub fn crash(text: impl Into<String>) -> Self {
+        Self::new(ErrorCode::Crash, text)
+    }
+
+    pub fn abort(text: impl Into<String>) -> Self {
+        Self::new(ErrorCode::Abort, text)
+    }
+
+    pub fn key_does_not_exist(text: impl Into<String>) -> Self {
+        Self::new(ErrorCode::KeyDoesNotExist, text)
+    }
+
+    pub fn key_already_exists(text: impl Into<String>) -> Self {
+        Self::new(ErrorCode::KeyAlreadyExists, text)
+    }
+
+    pub fn precondition_failed(text: impl Into<String>) -> Self {
+        Self::new(ErrorCode::PreconditionFailed, text)
+    }
+
+    pub fn txn_conflict(text: impl Into<String>) -> Self {
+        Self::new(ErrorCode::TxnConflict, text)
+    }
+
+    /// The `error` reply answering request `in_reply_to` from `dest`
+    pub fn reply(self, node: &mut Node, dest: String, in_reply_to: u64) -> Message {
+        node.error(dest, in_reply_to, self.code, self.text)
+    }
+}
+
+impl fmt::Display for MaelstromError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}: {}", self.code, self.text)
+    }
+}
+
+impl std::error::Error for MaelstromError {}
+
+impl From<Invalid> for MaelstromError {
+    fn from(invalid: Invalid) -> Self {
+        Self::malformed_message(invalid.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MessageBody;
+
+    #[test]
+    fn test_error_becomes_reply() {
+        let mut node = Node::new();
+        node.handle_init("n1".to_string(), vec!["n1".to_string()]);
+
+        let reply =
+            MaelstromError::key_does_not_exist("no key 3").reply(&mut node, "c1".to_string(), 8);
+        assert_eq!(reply.dest, "c1");
+        match reply.body {
+            MessageBody::Error {
+                in_reply_to,
+                code,
+                text,
+                ..
+            } => {
+                assert_eq!(in_reply_to, 8);
+                assert_eq!(code, ErrorCode::KeyDoesNotExist);
+                assert_eq!(text.as_deref(), Some("no key 3"));
+            }
+            _ => panic!("Expected Error message"),
+        }
+    }
+
+    #[test]
+    fn test_invalid_converts_to_malformed_message() {
+        let error: MaelstromError = Invalid::new("body.key", "must not be empty").into();
+        assert_eq!(error.code, ErrorCode::MalformedMessage);
+        assert_eq!(error.text, "body.key: must not be empty");
+    }
+}
@@ -0,0 +1,127 @@
+//! Optional feed of what a node is doing, for external viewers that animate a run: every
+//! message received and sent, and the handler's state (see [`Introspect`]) each time it
+//! changes. Records are JSON lines written to a Unix socket the viewer listens on, so every
+//! node of a cluster can feed the same viewer.
+//!
+//! [`Introspect`]: crate::admin::Introspect
+
+use crate::{
+    Message,
+    writer::{FlushPolicy, Writer, spawn_writer},
+};
+use serde::Serialize;
+use serde_json::Value;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::{io, task::JoinHandle};
+
+/// One record on the stream
+#[derive(Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+enum Event<'a> {
+    Received { message: &'a Message },
+    Sent { message: &'a Message },
+    State { state: &'a Value },
+}
+
+#[derive(Serialize)]
+struct Record<'a> {
+    /// Wall-clock microseconds, so records from different nodes interleave
+    ts_us: u128,
+    node: &'a str,
+    #[serde(flatten)]
+    event: Event<'a>,
+}
+
+/// Sends event records to the viewer; attach one with `Node::attach_events`
+pub struct EventStream {
+    writer: Writer,
+    last_state: Option<Value>,
+}
+
+impl EventStream {
+    pub fn new(writer: Writer) -> Self {
+        Self {
+            writer,
+            last_state: None,
+        }
+    }
+
+    /// Connect to the viewer's socket at `path`
+    #[cfg(unix)]
+    pub async fn connect(path: &Path) -> io::Result<(Self, JoinHandle<()>)> {
+        let stream = tokio::net::UnixStream::connect(path).await?;
+        let (writer, task) = spawn_writer(stream, FlushPolicy::WhenIdle);
+        Ok((Self::new(writer), task))
+    }
+
+    #[cfg(not(unix))]
+    pub async fn connect(_path: &Path) -> io::Result<(Self, JoinHandle<()>)> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "event streams need Unix sockets",
+        ))
+    }
+
+    pub fn received(&self, node: &str, message: &Message) {
+        self.record(node, Event::Received { message });
+    }
+
+    pub fn sent(&self, node: &str, message: &Message) {
+        self.record(node, Event::Sent { message });
+    }
+
+    /// Record `state` unless it is what was recorded last
+    pub fn state(&mut self, node: &str, state: Value) {
+        if self.last_state.as_ref() == Some(&state) {
+            return;
+        }
+        self.record(node, Event::State { state: &state });
+        self.last_state = Some(state);
+    }
+
+    fn record(&self, node: &str, event: Event) {
+        let ts_us = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_micros());
+        // A viewer that went away should not take the node with it, so failures are ignored
+        if let Ok(line) = serde_json::to_vec(&Record { ts_us, node, event }) {
+            self.writer.send(line);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+    use tokio::io::{AsyncBufReadExt, BufReader};
+
+    #[tokio::test]
+    async fn test_records_are_json_lines_and_unchanged_state_is_skipped() {
+        let (sink, viewer) = io::duplex(4096);
+        let (writer, task) = spawn_writer(sink, FlushPolicy::WhenIdle);
+        let mut events = EventStream::new(writer);
+
+        let echo = testing::echo("hi");
+        events.received("n1", &echo);
+        events.state("n1", serde_json::json!({ "seen": 1 }));
+        events.state("n1", serde_json::json!({ "seen": 1 }));
+        events.sent("n1", &echo);
+        drop(events);
+        task.await.unwrap();
+
+        let mut lines = BufReader::new(viewer).lines();
+        let mut records = Vec::new();
+        while let Some(line) = lines.next_line().await.unwrap() {
+            records.push(serde_json::from_str::<Value>(&line).unwrap());
+        }
+        assert_eq!(records.len(), 3);
+        assert_eq!(records[0]["event"], "received");
+        assert_eq!(records[0]["node"], "n1");
+        assert_eq!(records[0]["message"]["body"]["type"], "echo");
+        assert_eq!(records[1]["event"], "state");
+        assert_eq!(records[1]["state"]["seen"], 1);
+        assert_eq!(records[2]["event"], "sent");
+    }
+}
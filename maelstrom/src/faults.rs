@@ -0,0 +1,134 @@
+//! Nemesis scripts for the simulator: faults scheduled at simulated times, so a test can
+//! say what goes wrong and when, then assert the cluster copes.
+//!
+//! A [`Faults`] script reads top to bottom; `at` moves the cursor and every fault after it
+//! strikes at that time:
+//!
+//! - `partition(nodes(["n1", "n2"]) | nodes(["n3"]))` cuts the network into groups
+//! - `drop(0.1)` loses each message between nodes with that probability
+//! - `duplicate(0.05)` delivers that share of messages twice
+//! - `reorder(window)` holds each message back by up to `window`, so later ones overtake it
+//! - `heal()` or `heal_at(t)` ends the partition; `calm()` also turns the others off
+//!
+//! Hand the script to [`Sim::inject`](crate::sim::Sim::inject).
+
+use std::ops::BitOr;
+use std::time::Duration;
+
+/// Nodes on one side of a partition
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Groups(pub Vec<Vec<String>>);
+
+/// One side of a partition; combine sides with `|`
+pub fn nodes<'a>(ids: impl IntoIterator<Item = &'a str>) -> Groups {
+    Groups(vec![ids.into_iter().map(str::to_string).collect()])
+}
+
+impl BitOr for Groups {
+    type Output = Groups;
+
+    fn bitor(mut self, other: Groups) -> Groups {
+        self.0.extend(other.0);
+        self
+    }
+}
+
+/// A change to the simulated network
+#[derive(Debug, Clone, PartialEq)]
+pub enum Fault {
+    Partition(Groups),
+    Heal,
+    Drop(f64),
+    Duplicate(f64),
+    Reorder(Duration),
+    /// Heal and turn off loss, duplication, and reordering
+    Calm,
+}
+
+/// Faults in the order they strike
+#[derive(Debug, Clone, Default)]
+pub struct Faults {
+    cursor: Duration,
+    scheduled: Vec<(Duration, Fault)>,
+}
+
+impl Faults {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Schedule the following faults at `time` after the simulation started
+    pub fn at(mut self, time: Duration) -> Self {
+        self.cursor = time;
+        self
+    }
+
+    pub fn partition(self, groups: Groups) -> Self {
+        self.push(Fault::Partition(groups))
+    }
+
+    pub fn heal(self) -> Self {
+        self.push(Fault::Heal)
+    }
+
+    /// `at(time).heal()`
+    pub fn heal_at(self, time: Duration) -> Self {
+        self.at(time).heal()
+    }
+
+    pub fn drop(self, rate: f64) -> Self {
+        self.push(Fault::Drop(rate))
+    }
+
+    pub fn duplicate(self, rate: f64) -> Self {
+        self.push(Fault::Duplicate(rate))
+    }
+
+    pub fn reorder(self, window: Duration) -> Self {
+        self.push(Fault::Reorder(window))
+    }
+
+    pub fn calm(self) -> Self {
+        self.push(Fault::Calm)
+    }
+
+    fn push(mut self, fault: Fault) -> Self {
+        self.scheduled.push((self.cursor, fault));
+        self
+    }
+
+    /// The script's faults with their times
+    pub fn into_schedule(self) -> Vec<(Duration, Fault)> {
+        self.scheduled
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_script_schedules_faults_at_the_cursor() {
+        let schedule = Faults::new()
+            .at(Duration::from_secs(1))
+            .partition(nodes(["n1", "n2"]) | nodes(["n3"]))
+            .drop(0.1)
+            .heal_at(Duration::from_secs(3))
+            .into_schedule();
+
+        assert_eq!(
+            schedule,
+            vec![
+                (
+                    Duration::from_secs(1),
+                    Fault::Partition(Groups(vec![
+                        vec!["n1".to_string(), "n2".to_string()],
+                        vec!["n3".to_string()],
+                    ]))
+                ),
+                (Duration::from_secs(1), Fault::Drop(0.1)),
+                (Duration::from_secs(3), Fault::Heal),
+            ]
+        );
+    }
+}
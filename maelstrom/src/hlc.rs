@@ -0,0 +1,143 @@
+//! Hybrid logical clock: timestamps that stay close to wall time yet never go backwards and
+//! always follow the timestamps a node has seen, so they order causally related events across
+//! nodes the way a Lamport clock would while still reading as roughly physical time.
+//!
+//! Physical time is passed in (milliseconds since the epoch, e.g. from [`wall_ms`]) so tests
+//! and the simulator can drive the clock.
+
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// A hybrid timestamp: the greatest wall time seen, and a counter ordering events within it
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize,
+)]
+pub struct Timestamp {
+    pub wall: u64,
+    pub logical: u32,
+}
+
+/// A peer's timestamp ran further ahead of our wall time than the clock allows
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClockDrift {
+    pub remote: Timestamp,
+    pub physical: u64,
+}
+
+impl fmt::Display for ClockDrift {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "timestamp {} ms ahead of local wall time",
+            self.remote.wall.saturating_sub(self.physical)
+        )
+    }
+}
+
+impl std::error::Error for ClockDrift {}
+
+pub struct Hlc {
+    last: Timestamp,
+    max_drift: Duration,
+}
+
+impl Hlc {
+    /// A clock that refuses timestamps more than `max_drift` ahead of its wall time, so one
+    /// node with a runaway clock cannot drag the others along
+    pub fn new(max_drift: Duration) -> Self {
+        Self {
+            last: Timestamp::default(),
+            max_drift,
+        }
+    }
+
+    /// The last timestamp handed out or observed
+    pub fn last(&self) -> Timestamp {
+        self.last
+    }
+
+    /// Timestamp a local event or send at wall time `physical`
+    pub fn tick(&mut self, physical: u64) -> Timestamp {
+        self.last = if physical > self.last.wall {
+            Timestamp {
+                wall: physical,
+                logical: 0,
+            }
+        } else {
+            Timestamp {
+                wall: self.last.wall,
+                logical: self.last.logical + 1,
+            }
+        };
+        self.last
+    }
+
+    /// Take in `remote`, a timestamp received at wall time `physical`, and timestamp the
+    /// receipt after both it and everything before
+    pub fn observe(&mut self, remote: Timestamp, physical: u64) -> Result<Timestamp, ClockDrift> {
+        if remote.wall > physical.saturating_add(self.max_drift.as_millis() as u64) {
+            return Err(ClockDrift { remote, physical });
+        }
+        let wall = self.last.wall.max(remote.wall).max(physical);
+        let logical = match (wall == self.last.wall, wall == remote.wall) {
+            (true, true) => self.last.logical.max(remote.logical) + 1,
+            (true, false) => self.last.logical + 1,
+            (false, true) => remote.logical + 1,
+            (false, false) => 0,
+        };
+        self.last = Timestamp { wall, logical };
+        Ok(self.last)
+    }
+}
+
+/// Milliseconds since the Unix epoch by the system clock
+pub fn wall_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_millis() as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ts(wall: u64, logical: u32) -> Timestamp {
+        Timestamp { wall, logical }
+    }
+
+    #[test]
+    fn test_ticks_never_go_backwards_with_the_wall_clock() {
+        let mut clock = Hlc::new(Duration::from_secs(1));
+        assert_eq!(clock.tick(100), ts(100, 0));
+        assert_eq!(clock.tick(100), ts(100, 1));
+        // The wall clock stepped back
+        assert_eq!(clock.tick(90), ts(100, 2));
+        assert_eq!(clock.tick(101), ts(101, 0));
+    }
+
+    #[test]
+    fn test_received_timestamps_are_followed() {
+        let mut clock = Hlc::new(Duration::from_secs(1));
+        clock.tick(100);
+        // A peer slightly ahead: its wall time is adopted and its counter passed
+        assert_eq!(clock.observe(ts(150, 3), 120), Ok(ts(150, 4)));
+        assert_eq!(clock.observe(ts(150, 9), 120), Ok(ts(150, 10)));
+        // A peer behind changes nothing but the counter
+        assert_eq!(clock.observe(ts(50, 0), 130), Ok(ts(150, 11)));
+        assert_eq!(clock.tick(200), ts(200, 0));
+        assert!(clock.tick(200) > ts(200, 0));
+    }
+
+    #[test]
+    fn test_timestamps_too_far_ahead_are_refused() {
+        let mut clock = Hlc::new(Duration::from_millis(500));
+        let err = clock.observe(ts(2_000, 0), 1_000).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "timestamp 1000 ms ahead of local wall time"
+        );
+        assert_eq!(clock.last(), Timestamp::default());
+        assert!(clock.observe(ts(1_500, 0), 1_000).is_ok());
+    }
+}
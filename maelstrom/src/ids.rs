@@ -0,0 +1,796 @@
+//! Snowflake-style unique ids: by default 42 bits of milliseconds since the epoch, 10 node
+//! bits and a 12-bit sequence within the millisecond, so ids from one node only ever grow and
+//! ids from different nodes of a cluster never meet. [`IdLayout`] moves the split and the
+//! epoch; [`IdParts`] takes an id apart again, for checking where it came from and how it
+//! orders.
+//!
+//! The generator also lays the same parts out as UUIDv7 strings, when 64 bits will not do.
+
+use crate::{MaelstromError, Node};
+use std::fmt;
+use std::fs;
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// 42 bits for millis, 10 bits for node id, 12 bits for per-ms sequence, by default
+pub const TIME_BITS: u64 = 42;
+pub const NODE_BITS: u64 = 10;
+pub const SEQ_BITS: u64 = 12;
+pub const TIME_MASK: u64 = (1u64 << TIME_BITS) - 1; // 0..(2^42-1)
+pub const NODE_MASK: u64 = (1u64 << NODE_BITS) - 1;
+pub const SEQ_MAX: u16 = (1 << SEQ_BITS) - 1;
+// UUIDv7: 48 bits for millis, the version, 12 bits then 62 bits after the variant, which hold
+// the sequence, then the node bits, then random bits
+const UUID_TIME_MASK: u64 = (1u64 << 48) - 1;
+const UUID_TAIL_BITS: u64 = 12 + 62;
+/// How far past the newest id a [`HighWater`] reserves, so it is written about once a second
+pub const RESERVE_MS: u64 = 1000;
+
+/// How a snowflake id splits its 64 bits between milliseconds, node bits and sequence, and
+/// the epoch its milliseconds count from; the milliseconds take whatever the other two leave
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IdLayout {
+    pub node_bits: u64,
+    pub seq_bits: u64,
+    /// Unix time in milliseconds that ids count from; a recent one leaves the time bits
+    /// longer before they run out
+    pub epoch_ms: u64,
+}
+
+impl Default for IdLayout {
+    fn default() -> Self {
+        Self {
+            node_bits: NODE_BITS,
+            seq_bits: SEQ_BITS,
+            epoch_ms: 0,
+        }
+    }
+}
+
+impl IdLayout {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn node_bits(mut self, bits: u64) -> Self {
+        self.node_bits = bits;
+        self
+    }
+
+    pub fn seq_bits(mut self, bits: u64) -> Self {
+        self.seq_bits = bits;
+        self
+    }
+
+    pub fn epoch_ms(mut self, epoch_ms: u64) -> Self {
+        self.epoch_ms = epoch_ms;
+        self
+    }
+
+    pub fn time_bits(&self) -> u64 {
+        64u64.saturating_sub(self.node_bits + self.seq_bits)
+    }
+
+    /// Check the layout leaves room for time, gives each of `cluster_size` nodes its own
+    /// node bits, and has an epoch that is not still ahead of the clock
+    pub fn validate(&self, cluster_size: usize) -> Result<(), LayoutError> {
+        self.validate_at(cluster_size, now_ms())
+    }
+
+    /// [`validate`](Self::validate) with the clock reading `now_ms`
+    pub fn validate_at(&self, cluster_size: usize, now_ms: u64) -> Result<(), LayoutError> {
+        if self.epoch_ms > now_ms {
+            return Err(LayoutError::FutureEpoch {
+                epoch_ms: self.epoch_ms,
+                now_ms,
+            });
+        }
+        if self.time_bits() == 0 {
+            return Err(LayoutError::NoTimeBits {
+                node_bits: self.node_bits,
+                seq_bits: self.seq_bits,
+            });
+        }
+        if cluster_size as u64 > self.node_mask() + 1 {
+            return Err(LayoutError::ClusterTooBig {
+                nodes: cluster_size,
+                node_bits: self.node_bits,
+            });
+        }
+        Ok(())
+    }
+
+    pub fn decode(&self, id: u64) -> IdParts {
+        let since_epoch = id.checked_shr((self.node_bits + self.seq_bits) as u32);
+        IdParts {
+            timestamp_ms: since_epoch.unwrap_or(0) + self.epoch_ms,
+            node_bits: (id >> self.seq_bits) & self.node_mask(),
+            seq: id & self.seq_max(),
+        }
+    }
+
+    /// The id `parts` make, each cut to the width of its field
+    pub fn encode(&self, parts: &IdParts) -> u64 {
+        let since_epoch = parts.timestamp_ms.saturating_sub(self.epoch_ms) & mask(self.time_bits());
+        since_epoch
+            .checked_shl((self.node_bits + self.seq_bits) as u32)
+            .unwrap_or(0)
+            | ((parts.node_bits & self.node_mask()) << self.seq_bits)
+            | (parts.seq & self.seq_max())
+    }
+
+    /// Node bits for `node_id`: its index among the sorted `node_ids`, so no two nodes of the
+    /// cluster share them, or a hash of the id once the index no longer fits
+    pub fn node_bits_for(&self, node_id: &str, node_ids: &[&str]) -> u64 {
+        let mut sorted = node_ids.to_vec();
+        sorted.sort_unstable();
+        sorted.dedup();
+        match sorted.binary_search(&node_id) {
+            Ok(index) if (index as u64) <= self.node_mask() => index as u64,
+            _ => xxhash_rust::xxh3::xxh3_64(node_id.as_bytes()) & self.node_mask(),
+        }
+    }
+
+    fn node_mask(&self) -> u64 {
+        mask(self.node_bits)
+    }
+
+    fn seq_max(&self) -> u64 {
+        mask(self.seq_bits)
+    }
+}
+
+/// The lowest `bits` bits set
+fn mask(bits: u64) -> u64 {
+    1u64.checked_shl(bits as u32)
+        .map_or(u64::MAX, |bit| bit - 1)
+}
+
+/// An [`IdLayout`] that cannot serve the cluster
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LayoutError {
+    /// Node and sequence bits take all 64
+    NoTimeBits { node_bits: u64, seq_bits: u64 },
+    /// More nodes than the node bits tell apart
+    ClusterTooBig { nodes: usize, node_bits: u64 },
+    /// An epoch the clock has not reached, which every id would be stamped before
+    FutureEpoch { epoch_ms: u64, now_ms: u64 },
+}
+
+impl fmt::Display for LayoutError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NoTimeBits {
+                node_bits,
+                seq_bits,
+            } => write!(
+                f,
+                "{node_bits} node bits and {seq_bits} sequence bits leave none for time"
+            ),
+            Self::ClusterTooBig { nodes, node_bits } => {
+                write!(f, "{nodes} nodes do not fit in {node_bits} node bits")
+            }
+            Self::FutureEpoch { epoch_ms, now_ms } => {
+                write!(f, "epoch {epoch_ms}ms is later than the clock's {now_ms}ms")
+            }
+        }
+    }
+}
+
+impl std::error::Error for LayoutError {}
+
+impl From<LayoutError> for MaelstromError {
+    fn from(err: LayoutError) -> Self {
+        Self::crash(err.to_string())
+    }
+}
+
+/// The fields of a snowflake id
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct IdParts {
+    pub timestamp_ms: u64,
+    pub node_bits: u64,
+    pub seq: u64,
+}
+
+impl IdParts {
+    /// The parts of `id`, laid out as by default
+    pub fn decode(id: u64) -> Self {
+        IdLayout::default().decode(id)
+    }
+
+    /// The id these parts make in the default layout
+    pub fn encode(&self) -> u64 {
+        IdLayout::default().encode(self)
+    }
+}
+
+/// Node bits for `node_id` in the default layout; see [`IdLayout::node_bits_for`]
+pub fn node_bits(node_id: &str, node_ids: &[&str]) -> u64 {
+    IdLayout::default().node_bits_for(node_id, node_ids)
+}
+
+/// Hands out unique ids for one node
+#[derive(Debug, Clone)]
+pub struct IdGen {
+    layout: IdLayout,
+    node_bits: u64,
+    /// Unix milliseconds of the last id, which may run ahead of the clock
+    last_ms: u64,
+    seq: u64,
+    /// Latest wall-clock millisecond read, which `last_ms` may run ahead of
+    clock_ms: u64,
+    /// Backs [`IdGen::clock_regressions`]
+    clock_regressions: u64,
+    /// Whether ids follow the wall clock, or only count up from the last
+    wall_clock: bool,
+}
+
+impl IdGen {
+    pub fn new(node_bits: u64) -> Self {
+        Self::with_layout(IdLayout::default(), node_bits)
+    }
+
+    pub fn with_layout(layout: IdLayout, node_bits: u64) -> Self {
+        Self {
+            layout,
+            node_bits: node_bits & layout.node_mask(),
+            last_ms: 0,
+            seq: 0,
+            clock_ms: 0,
+            clock_regressions: 0,
+            wall_clock: true,
+        }
+    }
+
+    /// Never read the wall clock: the time field becomes a counter that moves on whenever a
+    /// sequence is used up, so ids are the node bits and a local counter alone, unique and
+    /// increasing on hosts whose clocks cannot be trusted. Ids no longer tell when they were
+    /// issued, and the counter starts from the layout's epoch.
+    pub fn without_clock(mut self) -> Self {
+        self.wall_clock = false;
+        self.last_ms = self.last_ms.max(self.layout.epoch_ms);
+        self
+    }
+
+    pub fn follows_clock(&self) -> bool {
+        self.wall_clock
+    }
+
+    /// A generator for `node` with ids laid out as `layout`, placed among its peers once it
+    /// is initialized; refused when the layout cannot tell every node of the cluster apart
+    pub fn for_node(node: &Node, layout: IdLayout) -> Result<Self, LayoutError> {
+        let node_ids: Vec<&str> = node
+            .peers
+            .iter()
+            .map(String::as_str)
+            .chain([node.id.as_str()])
+            .collect();
+        layout.validate(node_ids.len())?;
+        Ok(Self::with_layout(
+            layout,
+            layout.node_bits_for(&node.id, &node_ids),
+        ))
+    }
+
+    pub fn layout(&self) -> IdLayout {
+        self.layout
+    }
+
+    /// Unix milliseconds of the newest id, which may run ahead of the clock
+    pub fn last_ms(&self) -> u64 {
+        self.last_ms
+    }
+
+    /// Issue no more ids below `ms`, e.g. a [`HighWater`] reservation from before a restart
+    pub fn resume_at(&mut self, ms: u64) {
+        if ms > self.last_ms {
+            // As if the millisecond before were used up
+            self.last_ms = ms - 1;
+            self.seq = self.layout.seq_max();
+        }
+    }
+
+    /// Issue every later id above `id`, seen from another node, like a Lamport clock; for ids
+    /// in this generator's layout
+    pub fn observe(&mut self, id: u64) {
+        self.resume_at(self.layout.decode(id).timestamp_ms + 1);
+    }
+
+    pub fn node_bits(&self) -> u64 {
+        self.node_bits
+    }
+
+    /// Times the wall clock was read behind an earlier reading. Ids carry on from the newest
+    /// millisecond used either way, so this only tells how often the clock stepped back.
+    pub fn clock_regressions(&self) -> u64 {
+        self.clock_regressions
+    }
+
+    pub fn generate(&mut self) -> u64 {
+        self.generate_at(self.now_ms())
+    }
+
+    /// The next id at wall-clock millisecond `now_ms`, in Unix time
+    pub fn generate_at(&mut self, now_ms: u64) -> u64 {
+        let (ms, seq) = self.tick(now_ms);
+        self.layout.encode(&IdParts {
+            timestamp_ms: ms,
+            node_bits: self.node_bits,
+            seq,
+        })
+    }
+
+    /// The next id as a UUIDv7 string, filling its random bits from `random`
+    pub fn generate_uuid(&mut self, random: u64) -> String {
+        uuid_string(self.uuid_at(self.now_ms() & UUID_TIME_MASK, random))
+    }
+
+    /// The wall clock, or just the newest millisecond used when not following it
+    fn now_ms(&self) -> u64 {
+        if self.wall_clock {
+            now_ms()
+        } else {
+            self.last_ms
+        }
+    }
+
+    fn uuid_at(&mut self, now_ms: u64, random: u64) -> u128 {
+        let (ms, seq) = self.tick(now_ms);
+        let (seq_bits, node_bits) = (self.layout.seq_bits, self.layout.node_bits);
+        let random_bits = UUID_TAIL_BITS - seq_bits - node_bits;
+        let tail = (seq as u128) << (UUID_TAIL_BITS - seq_bits)
+            | (self.node_bits as u128) << random_bits
+            | (random as u128 & ((1 << random_bits) - 1));
+        (ms as u128) << 80 | 0x7 << 76 | (tail >> 62) << 64 | 0b10 << 62 | (tail & ((1 << 62) - 1))
+    }
+
+    /// The millisecond and sequence number of the next id at wall-clock millisecond `now_ms`.
+    /// Once a millisecond's sequence is used up, ids carry on in the next one rather than wait
+    /// for the clock to get there, and the clock catching up resumes from wherever they left
+    /// off. A clock stepping backwards is counted and otherwise ignored: ids keep counting up
+    /// from the newest millisecond used.
+    fn tick(&mut self, now_ms: u64) -> (u64, u64) {
+        if now_ms < self.clock_ms {
+            self.clock_regressions += 1;
+        }
+        self.clock_ms = self.clock_ms.max(now_ms);
+        if now_ms > self.last_ms {
+            self.last_ms = now_ms;
+            self.seq = 0;
+        } else if self.seq < self.layout.seq_max() {
+            self.seq += 1;
+        } else {
+            self.last_ms += 1;
+            self.seq = 0;
+        }
+        (self.last_ms, self.seq)
+    }
+}
+
+/// An [`IdGen`] that hands out ids through `&self`, for handlers shared between workers (see
+/// [`crate::shard`]). The millisecond and sequence of the next id are one counter, taken with a
+/// `fetch_add` and raised to the clock with a `fetch_max`, so concurrent callers never wait on
+/// one another. Each caller's ids still only grow, and no two callers get the same one.
+#[derive(Debug)]
+pub struct AtomicIdGen {
+    layout: IdLayout,
+    node_bits: u64,
+    wall_clock: bool,
+    /// Milliseconds since the layout's epoch above the sequence bits, for the next id
+    next: AtomicU64,
+    clock_ms: AtomicU64,
+    clock_regressions: AtomicU64,
+}
+
+impl AtomicIdGen {
+    pub fn new(node_bits: u64) -> Self {
+        IdGen::new(node_bits).into()
+    }
+
+    pub fn with_layout(layout: IdLayout, node_bits: u64) -> Self {
+        IdGen::with_layout(layout, node_bits).into()
+    }
+
+    /// See [`IdGen::for_node`]
+    pub fn for_node(node: &Node, layout: IdLayout) -> Result<Self, LayoutError> {
+        IdGen::for_node(node, layout).map(Self::from)
+    }
+
+    /// See [`IdGen::without_clock`]
+    pub fn without_clock(self) -> Self {
+        Self {
+            wall_clock: false,
+            ..self
+        }
+    }
+
+    pub fn layout(&self) -> IdLayout {
+        self.layout
+    }
+
+    pub fn node_bits(&self) -> u64 {
+        self.node_bits
+    }
+
+    /// Unix milliseconds of the newest id, which may run ahead of the clock
+    pub fn last_ms(&self) -> u64 {
+        let last = self.next.load(Ordering::Relaxed).saturating_sub(1);
+        (last >> self.layout.seq_bits) + self.layout.epoch_ms
+    }
+
+    /// See [`IdGen::clock_regressions`]
+    pub fn clock_regressions(&self) -> u64 {
+        self.clock_regressions.load(Ordering::Relaxed)
+    }
+
+    /// See [`IdGen::resume_at`]
+    pub fn resume_at(&self, ms: u64) {
+        self.next.fetch_max(self.counter_at(ms), Ordering::Relaxed);
+    }
+
+    /// See [`IdGen::observe`]
+    pub fn observe(&self, id: u64) {
+        self.resume_at(self.layout.decode(id).timestamp_ms + 1);
+    }
+
+    pub fn generate(&self) -> u64 {
+        if self.wall_clock {
+            self.generate_at(now_ms())
+        } else {
+            self.take()
+        }
+    }
+
+    /// The next id at wall-clock millisecond `now_ms`, in Unix time
+    pub fn generate_at(&self, now_ms: u64) -> u64 {
+        if self.clock_ms.fetch_max(now_ms, Ordering::Relaxed) > now_ms {
+            self.clock_regressions.fetch_add(1, Ordering::Relaxed);
+        }
+        self.next
+            .fetch_max(self.counter_at(now_ms), Ordering::Relaxed);
+        self.take()
+    }
+
+    /// Take the next counter value, which carries a used-up sequence into the next millisecond
+    /// just as [`IdGen`] does
+    fn take(&self) -> u64 {
+        let counter = self.next.fetch_add(1, Ordering::Relaxed);
+        self.layout.encode(&IdParts {
+            timestamp_ms: (counter >> self.layout.seq_bits) + self.layout.epoch_ms,
+            node_bits: self.node_bits,
+            seq: counter & self.layout.seq_max(),
+        })
+    }
+
+    /// The counter value of the first id at `ms`
+    fn counter_at(&self, ms: u64) -> u64 {
+        let since_epoch = ms.saturating_sub(self.layout.epoch_ms) & mask(self.layout.time_bits());
+        since_epoch << self.layout.seq_bits
+    }
+}
+
+/// Carries on after the last id `id_gen` issued
+impl From<IdGen> for AtomicIdGen {
+    fn from(id_gen: IdGen) -> Self {
+        let generator = Self {
+            layout: id_gen.layout,
+            node_bits: id_gen.node_bits,
+            wall_clock: id_gen.wall_clock,
+            next: AtomicU64::new(0),
+            clock_ms: AtomicU64::new(id_gen.clock_ms),
+            clock_regressions: AtomicU64::new(id_gen.clock_regressions),
+        };
+        let last = generator.counter_at(id_gen.last_ms) | id_gen.seq;
+        generator.next.store(last + 1, Ordering::Relaxed);
+        generator
+    }
+}
+
+/// A millisecond above every id a generator has issued, kept in a file so a restarted node can
+/// [`IdGen::resume_at`] it. It is reserved [`RESERVE_MS`] ahead and written, durably, before
+/// any id reaches it, so a crash at any moment loses nothing and no shutdown write is needed.
+#[derive(Debug)]
+pub struct HighWater {
+    path: PathBuf,
+    reserved_ms: u64,
+}
+
+impl HighWater {
+    /// Read the mark kept at `path`, 0 if there is none yet
+    pub fn open(path: impl Into<PathBuf>) -> io::Result<Self> {
+        let path = path.into();
+        let reserved_ms = match fs::read_to_string(&path) {
+            Ok(text) => text
+                .trim()
+                .parse()
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => 0,
+            Err(err) => return Err(err),
+        };
+        Ok(Self { path, reserved_ms })
+    }
+
+    pub fn reserved_ms(&self) -> u64 {
+        self.reserved_ms
+    }
+
+    /// Make sure the mark is above an id issued at `ms`, reserving further ahead if not; an id
+    /// must not be handed out unless this succeeds
+    pub fn cover(&mut self, ms: u64) -> io::Result<()> {
+        if ms < self.reserved_ms {
+            return Ok(());
+        }
+        let reserved_ms = ms + RESERVE_MS;
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        // Swapped in whole, so a crash leaves either the old mark or the new one
+        let staged = self.path.with_extension("tmp");
+        let mut file = fs::File::create(&staged)?;
+        writeln!(file, "{reserved_ms}")?;
+        file.sync_all()?;
+        fs::rename(&staged, &self.path)?;
+        self.reserved_ms = reserved_ms;
+        Ok(())
+    }
+}
+
+/// Milliseconds since the epoch; a clock set before it reads as the epoch, and counts as
+/// going backwards
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+/// `uuid` in the usual hyphenated form
+fn uuid_string(uuid: u128) -> String {
+    format!(
+        "{:08x}-{:04x}-{:04x}-{:04x}-{:012x}",
+        uuid >> 96,
+        (uuid >> 80) & 0xffff,
+        (uuid >> 64) & 0xffff,
+        (uuid >> 48) & 0xffff,
+        uuid & 0xffff_ffff_ffff
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    #[test]
+    fn test_decoding_gives_back_the_parts_an_id_was_made_of() {
+        let mut id_gen = IdGen::new(0x2a5);
+        let first = id_gen.generate_at(1_700_000_000_000);
+        let second = id_gen.generate_at(1_700_000_000_000);
+        let parts = IdParts::decode(second);
+        assert_eq!(
+            parts,
+            IdParts {
+                timestamp_ms: 1_700_000_000_000,
+                node_bits: 0x2a5,
+                seq: 1,
+            }
+        );
+        assert_eq!(parts.encode(), second);
+        assert!(IdParts::decode(first) < parts);
+        assert_eq!(IdParts::decode(u64::MAX).seq, SEQ_MAX as u64);
+    }
+
+    #[test]
+    fn test_layouts_move_the_split_and_the_epoch() {
+        let layout = IdLayout::new()
+            .node_bits(16)
+            .seq_bits(8)
+            .epoch_ms(1_600_000_000_000);
+        assert_eq!(layout.time_bits(), 40);
+        let mut id_gen = IdGen::with_layout(layout, 40_000);
+        let id = id_gen.generate_at(1_700_000_000_000);
+        assert_eq!(id >> 24, 100_000_000_000);
+        assert_eq!(
+            layout.decode(id),
+            IdParts {
+                timestamp_ms: 1_700_000_000_000,
+                node_bits: 40_000,
+                seq: 0,
+            }
+        );
+        // Eight sequence bits run out after 256 ids
+        let ids: Vec<u64> = (0..256)
+            .map(|_| id_gen.generate_at(1_700_000_000_000))
+            .collect();
+        assert_eq!(layout.decode(ids[254]).seq, 255);
+        assert_eq!(layout.decode(ids[255]).timestamp_ms, 1_700_000_000_001);
+
+        assert_eq!(layout.validate(65_536), Ok(()));
+        assert_eq!(
+            layout.validate(65_537),
+            Err(LayoutError::ClusterTooBig {
+                nodes: 65_537,
+                node_bits: 16,
+            })
+        );
+        assert_eq!(
+            IdLayout::new().node_bits(32).seq_bits(32).validate(1),
+            Err(LayoutError::NoTimeBits {
+                node_bits: 32,
+                seq_bits: 32,
+            })
+        );
+    }
+
+    #[test]
+    fn test_layouts_refuse_an_epoch_ahead_of_the_clock() {
+        let layout = IdLayout::new().epoch_ms(1_700_000_000_000);
+        assert_eq!(layout.validate_at(3, 1_700_000_000_000), Ok(()));
+        let err = layout.validate_at(3, 1_699_999_999_999).unwrap_err();
+        assert_eq!(
+            err,
+            LayoutError::FutureEpoch {
+                epoch_ms: 1_700_000_000_000,
+                now_ms: 1_699_999_999_999,
+            }
+        );
+        assert_eq!(MaelstromError::from(err).code, crate::ErrorCode::Crash);
+        assert!(IdLayout::new().epoch_ms(u64::MAX).validate(3).is_err());
+    }
+
+    #[test]
+    fn test_node_bits_follow_the_sorted_cluster_and_hash_past_the_budget() {
+        let names: Vec<String> = (0..1500).map(|i| format!("n{i:04}")).collect();
+        let node_ids: Vec<&str> = names.iter().rev().map(String::as_str).collect();
+
+        let bits: HashSet<u64> = node_ids[..100]
+            .iter()
+            .map(|id| node_bits(id, &node_ids[..100]))
+            .collect();
+        assert_eq!(bits.len(), 100);
+        assert_eq!(node_bits("n0000", &node_ids), 0);
+        assert_eq!(node_bits("n1023", &node_ids), 1023);
+
+        let hashed = xxhash_rust::xxh3::xxh3_64(b"n1024") & NODE_MASK;
+        assert_eq!(node_bits("n1024", &node_ids), hashed);
+        let hashed = xxhash_rust::xxh3::xxh3_64(b"stray") & NODE_MASK;
+        assert_eq!(node_bits("stray", &node_ids), hashed);
+    }
+
+    #[test]
+    fn test_a_used_up_millisecond_moves_ids_into_the_next() {
+        let mut id_gen = IdGen::new(3);
+        let ids: Vec<u64> = (0..=SEQ_MAX as u64 + 1)
+            .map(|_| id_gen.generate_at(10))
+            .collect();
+        assert!(ids.windows(2).all(|pair| pair[0] < pair[1]));
+        assert_eq!(IdParts::decode(ids[SEQ_MAX as usize]).timestamp_ms, 10);
+        assert_eq!(IdParts::decode(ids[SEQ_MAX as usize + 1]).timestamp_ms, 11);
+
+        // The clock reaching the borrowed millisecond carries on after the ids taken in it
+        let next = id_gen.generate_at(11);
+        assert_eq!(next, ids[SEQ_MAX as usize + 1] + 1);
+        assert_eq!(IdParts::decode(id_gen.generate_at(12)).timestamp_ms, 12);
+    }
+
+    #[test]
+    fn test_a_clock_stepping_back_reuses_no_ids_and_is_counted() {
+        let mut id_gen = IdGen::new(3);
+        let before = id_gen.generate_at(100);
+        let stepped_back = id_gen.generate_at(40);
+        assert_eq!(stepped_back, before + 1);
+        assert!(id_gen.generate_at(41) > stepped_back);
+        // Catching up with the newest reading again is no regression
+        assert!(id_gen.generate_at(100) > stepped_back);
+        assert_eq!(id_gen.clock_regressions(), 2);
+    }
+
+    #[test]
+    fn test_the_atomic_generator_issues_what_the_plain_one_does() {
+        let layout = IdLayout::default().seq_bits(3).epoch_ms(1_000);
+        let mut plain = IdGen::with_layout(layout, 5);
+        let atomic = AtomicIdGen::with_layout(layout, 5);
+        // Borrowing ahead, the clock catching up, and the clock stepping back
+        let readings = [[1_010; 10].as_slice(), &[1_011, 1_012, 1_005, 1_006, 1_030]].concat();
+        for now_ms in readings {
+            assert_eq!(atomic.generate_at(now_ms), plain.generate_at(now_ms));
+        }
+        assert_eq!(atomic.last_ms(), plain.last_ms());
+        assert_eq!(atomic.clock_regressions(), 2);
+
+        // Converted midway, it carries on where the plain one stopped
+        let atomic = AtomicIdGen::from(plain.clone());
+        assert_eq!(atomic.generate_at(1_030), plain.generate_at(1_030));
+        atomic.resume_at(2_000);
+        assert_eq!(layout.decode(atomic.generate_at(1_031)).timestamp_ms, 2_000);
+    }
+
+    #[test]
+    fn test_the_atomic_generator_repeats_no_id_across_threads() {
+        let id_gen = AtomicIdGen::new(3);
+        let ids: Vec<Vec<u64>> = std::thread::scope(|scope| {
+            let workers: Vec<_> = (0..4)
+                .map(|_| scope.spawn(|| (0..5_000).map(|_| id_gen.generate()).collect()))
+                .collect();
+            workers.into_iter().map(|w| w.join().unwrap()).collect()
+        });
+        assert!(
+            ids.iter()
+                .all(|ids| ids.windows(2).all(|pair| pair[0] < pair[1]))
+        );
+        let unique: HashSet<u64> = ids.iter().flatten().copied().collect();
+        assert_eq!(unique.len(), 20_000);
+
+        let counter = AtomicIdGen::new(3).without_clock();
+        assert_eq!(IdParts::decode(counter.generate()).timestamp_ms, 0);
+    }
+
+    #[test]
+    fn test_a_clock_free_generator_counts_up_from_the_epoch() {
+        let layout = IdLayout::default().seq_bits(2).epoch_ms(1_000);
+        let mut id_gen = IdGen::with_layout(layout, 3).without_clock();
+        let ids: Vec<u64> = (0..9).map(|_| id_gen.generate()).collect();
+        assert!(ids.windows(2).all(|pair| pair[0] < pair[1]));
+        let parts: Vec<IdParts> = ids.iter().map(|&id| layout.decode(id)).collect();
+        assert!(parts.iter().all(|part| part.node_bits == 3));
+        assert_eq!(parts[0].timestamp_ms, 1_000);
+        assert_eq!(parts[8].timestamp_ms, 1_002);
+        assert_eq!(id_gen.clock_regressions(), 0);
+
+        // Seeing a peer's id moves the counter past it
+        let peer = layout.encode(&IdParts {
+            timestamp_ms: 1_050,
+            node_bits: 5,
+            seq: 3,
+        });
+        id_gen.observe(peer);
+        let next = id_gen.generate();
+        assert!(next > peer);
+        assert_eq!(layout.decode(next).timestamp_ms, 1_051);
+        // An older one changes nothing
+        id_gen.observe(ids[0]);
+        assert_eq!(id_gen.generate(), next + 1);
+    }
+
+    #[test]
+    fn test_a_restarted_generator_starts_above_the_high_water_mark() {
+        let path = std::env::temp_dir()
+            .join(format!("ids-{}", std::process::id()))
+            .join("high-water");
+        let _ = fs::remove_file(&path);
+
+        let mut high_water = HighWater::open(&path).unwrap();
+        assert_eq!(high_water.reserved_ms(), 0);
+        let mut id_gen = IdGen::new(3);
+        let mut issued = Vec::new();
+        for now_ms in [5_000, 5_000, 5_400] {
+            issued.push(id_gen.generate_at(now_ms));
+            high_water.cover(id_gen.last_ms()).unwrap();
+        }
+        // One write covers every id of the following second
+        assert_eq!(high_water.reserved_ms(), 5_000 + RESERVE_MS);
+
+        // Back up with the clock behind where it was
+        let mut id_gen = IdGen::new(3);
+        id_gen.resume_at(HighWater::open(&path).unwrap().reserved_ms());
+        let id = id_gen.generate_at(4_000);
+        assert_eq!(IdParts::decode(id).timestamp_ms, 5_000 + RESERVE_MS);
+        assert!(issued.iter().all(|&before| before < id));
+
+        let _ = fs::remove_dir_all(path.parent().unwrap());
+    }
+
+    #[test]
+    fn test_uuids_carry_the_version_variant_time_and_node_bits() {
+        let mut id_gen = IdGen::new(0x2a5);
+        let uuid = id_gen.uuid_at(0x0123_4567_89ab, u64::MAX);
+        assert_eq!(uuid_string(uuid), "01234567-89ab-7000-aa5f-ffffffffffff");
+        // The sequence orders ids within a millisecond, as with the integer ids
+        let next = id_gen.uuid_at(0x0123_4567_89ab, 0);
+        assert_eq!(uuid_string(next), "01234567-89ab-7001-aa50-000000000000");
+        assert!(next > uuid);
+    }
+}
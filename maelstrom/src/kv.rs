@@ -1,12 +1,14 @@
+use crate::persist::{Persistent, RestoreError};
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 
-#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[derive(Default, Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct Counter {
     pub version: u64,
     pub value: u64,
 }
 
+#[derive(Debug, Clone, PartialEq)]
 pub struct KV {
     pub counters: HashMap<String, Counter>,
 }
@@ -52,21 +54,15 @@ impl KV {
         sum
     }
 
+    /// Take in a peer's per-node counters. Each node's entry keeps whichever of the two is
+    /// greater by version, then value, so replicas converge whatever order states arrive in,
+    /// even when two copies of a node's counter reached the same version differently.
     pub fn merge(&mut self, incoming: HashMap<String, Counter>) {
         for (node_id, incoming_counter) in incoming {
-            let current_counter = self.counters.get(&node_id);
-            match current_counter {
-                Some(c) => {
-                    if incoming_counter.version > c.version {
-                        self.counters.entry(node_id).and_modify(|counter| {
-                            counter.version = incoming_counter.version;
-                            counter.value = incoming_counter.value;
-                        });
-                    }
-                }
-                None => {
-                    self.counters.insert(node_id, incoming_counter);
-                }
+            let counter = self.counters.entry(node_id).or_default();
+            if (incoming_counter.version, incoming_counter.value) > (counter.version, counter.value)
+            {
+                *counter = incoming_counter;
             }
         }
     }
@@ -74,4 +70,680 @@ impl KV {
     pub fn is_empty(&self) -> bool {
         self.counters.is_empty()
     }
+
+    /// Version of each node's entry: a version vector of everything a read here includes
+    pub fn versions(&self) -> HashMap<String, u64> {
+        self.counters
+            .iter()
+            .filter(|(_, counter)| counter.version > 0)
+            .map(|(node_id, counter)| (node_id.clone(), counter.version))
+            .collect()
+    }
+
+    /// Whether every entry is at least as new as in `versions`, so a read here is no lower
+    /// than the one that returned them
+    pub fn covers(&self, versions: &HashMap<String, u64>) -> bool {
+        versions.iter().all(|(node_id, &version)| {
+            self.counters
+                .get(node_id)
+                .is_some_and(|counter| counter.version >= version)
+                || version == 0
+        })
+    }
+}
+
+/// Snapshots are the per-node counters as MessagePack, a fraction of their JSON size
+impl Persistent for KV {
+    fn snapshot(&self) -> Vec<u8> {
+        rmp_serde::to_vec(&self.counters).unwrap_or_default()
+    }
+
+    fn restore(&mut self, snapshot: &[u8]) -> Result<(), RestoreError> {
+        self.counters = rmp_serde::from_slice(snapshot)?;
+        Ok(())
+    }
+}
+
+/// Any number of grow-only counters told apart by name, each a [`KV`] of per-node entries
+/// and merged like one
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct NamedKV {
+    pub counters: HashMap<String, KV>,
+}
+
+impl NamedKV {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The counter named `key`, created empty if need be
+    pub fn entry(&mut self, key: &str) -> &mut KV {
+        self.counters.entry(key.to_string()).or_default()
+    }
+
+    pub fn get(&self, key: &str) -> Option<&KV> {
+        self.counters.get(key)
+    }
+
+    pub fn add(&mut self, key: &str, node_id: String, delta: u64) {
+        self.entry(key).add(node_id, delta);
+    }
+
+    /// Value of the counter named `key`; 0 for one never added to
+    pub fn read(&self, key: &str) -> u64 {
+        self.get(key).map_or(0, KV::read)
+    }
+
+    /// Take in a peer's per-node entries of the counter named `key`
+    pub fn merge(&mut self, key: &str, incoming: HashMap<String, Counter>) {
+        self.entry(key).merge(incoming);
+    }
+
+    /// Just `node_id`'s entry of the counter named `key`, as a delta to replicate
+    pub fn slice(&self, key: &str, node_id: &str) -> NamedKV {
+        let counters = self
+            .get(key)
+            .and_then(|kv| kv.counters.get_key_value(node_id))
+            .map(|(node_id, counter)| HashMap::from([(node_id.clone(), counter.clone())]))
+            .unwrap_or_default();
+        NamedKV {
+            counters: HashMap::from([(key.to_string(), KV { counters })]),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.counters.values().all(KV::is_empty)
+    }
+}
+
+impl Persistent for NamedKV {
+    fn snapshot(&self) -> Vec<u8> {
+        let counters: HashMap<_, _> = self
+            .counters
+            .iter()
+            .map(|(key, kv)| (key, &kv.counters))
+            .collect();
+        rmp_serde::to_vec(&counters).unwrap_or_default()
+    }
+
+    fn restore(&mut self, snapshot: &[u8]) -> Result<(), RestoreError> {
+        let counters: HashMap<String, HashMap<String, Counter>> = rmp_serde::from_slice(snapshot)?;
+        self.counters = counters
+            .into_iter()
+            .map(|(key, counters)| (key, KV { counters }))
+            .collect();
+        Ok(())
+    }
+}
+
+/// Counter that can also go down: increments and decrements are kept as two grow-only
+/// counters, each versioned per node and merged like [`KV`], and the value is their
+/// difference
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PNCounter {
+    pub increments: KV,
+    pub decrements: KV,
+}
+
+impl PNCounter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn init(&mut self, node_ids: Vec<String>) {
+        self.increments.init(node_ids.clone());
+        self.decrements.init(node_ids);
+    }
+
+    /// Count `delta` for `node_id`, on the side its sign says
+    pub fn add(&mut self, node_id: String, delta: i64) {
+        match delta {
+            0 => {}
+            1.. => self.increments.add(node_id, delta.unsigned_abs()),
+            _ => self.decrements.add(node_id, delta.unsigned_abs()),
+        }
+    }
+
+    pub fn read(&self) -> i64 {
+        self.increments.read() as i64 - self.decrements.read() as i64
+    }
+
+    /// Take in a peer's per-node counters for both sides
+    pub fn merge(
+        &mut self,
+        increments: HashMap<String, Counter>,
+        decrements: HashMap<String, Counter>,
+    ) {
+        self.increments.merge(increments);
+        self.decrements.merge(decrements);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.increments.is_empty() && self.decrements.is_empty()
+    }
+}
+
+/// Grow-only set: elements are never removed, so merging is a union and replicas that have
+/// seen the same inserts agree
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct GSet<T: Ord> {
+    elements: BTreeSet<T>,
+}
+
+impl<T: Ord> Default for GSet<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T: Ord> GSet<T> {
+    pub fn new() -> Self {
+        Self {
+            elements: BTreeSet::new(),
+        }
+    }
+
+    /// Add `value`; false if it was already there
+    pub fn insert(&mut self, value: T) -> bool {
+        self.elements.insert(value)
+    }
+
+    pub fn contains(&self, value: &T) -> bool {
+        self.elements.contains(value)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &T> {
+        self.elements.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.elements.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.elements.is_empty()
+    }
+
+    /// Take in everything `other` has; returns how many elements were new
+    pub fn merge(&mut self, other: GSet<T>) -> usize {
+        let before = self.elements.len();
+        self.elements.extend(other.elements);
+        self.elements.len() - before
+    }
+}
+
+impl<T: Ord> FromIterator<T> for GSet<T> {
+    fn from_iter<I: IntoIterator<Item = T>>(values: I) -> Self {
+        Self {
+            elements: values.into_iter().collect(),
+        }
+    }
+}
+
+impl<T: Ord + Clone> GSet<T> {
+    /// What `known` (a peer's last known state) lacks, to send instead of the whole set
+    pub fn delta(&self, known: &GSet<T>) -> GSet<T> {
+        GSet {
+            elements: self.elements.difference(&known.elements).cloned().collect(),
+        }
+    }
+}
+
+/// The unique identity of one insert into an [`ORSet`]: the inserting node and its count of
+/// inserts so far
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct Tag(pub String, pub u64);
+
+/// Observed-remove set: every insert is tagged, and a remove cancels only the tags its
+/// replica had seen, so an insert concurrent with a remove survives the merge (add wins).
+/// Removed tags are kept as tombstones so merges cannot bring them back.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ORSet<T: Ord> {
+    /// Live tags of each element present
+    entries: BTreeMap<T, BTreeSet<Tag>>,
+    /// Tags of removed inserts
+    removed: BTreeSet<Tag>,
+    /// Inserts made through this replica, numbering its tags; kept past every tag merged in,
+    /// so a replica restored from a peer's state never reuses one of its old tags
+    inserts: u64,
+}
+
+impl<T: Ord> Default for ORSet<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T: Ord> ORSet<T> {
+    pub fn new() -> Self {
+        Self {
+            entries: BTreeMap::new(),
+            removed: BTreeSet::new(),
+            inserts: 0,
+        }
+    }
+
+    /// Insert `value` on behalf of `node_id`, returning the new insert's tag
+    pub fn insert(&mut self, node_id: &str, value: T) -> Tag {
+        self.inserts += 1;
+        let tag = Tag(node_id.to_string(), self.inserts);
+        self.entries.entry(value).or_default().insert(tag.clone());
+        tag
+    }
+
+    /// Remove `value` as this replica has seen it; false if it was not present
+    pub fn remove(&mut self, value: &T) -> bool {
+        let Some(tags) = self.entries.remove(value) else {
+            return false;
+        };
+        self.removed.extend(tags);
+        true
+    }
+
+    pub fn contains(&self, value: &T) -> bool {
+        self.entries.contains_key(value)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &T> {
+        self.entries.keys()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Take in `other`'s inserts and removes
+    pub fn merge(&mut self, other: ORSet<T>) {
+        let highest = other
+            .entries
+            .values()
+            .flatten()
+            .chain(&other.removed)
+            .map(|Tag(_, seq)| *seq)
+            .max();
+        self.inserts = self.inserts.max(other.inserts).max(highest.unwrap_or(0));
+        self.removed.extend(other.removed);
+        for (value, tags) in other.entries {
+            self.entries.entry(value).or_default().extend(tags);
+        }
+        let removed = &self.removed;
+        self.entries.retain(|_, tags| {
+            tags.retain(|tag| !removed.contains(tag));
+            !tags.is_empty()
+        });
+    }
+}
+
+impl<T: Ord + Clone> ORSet<T> {
+    /// Inserts and removes `known` (a peer's last known state) has not seen; merging the delta
+    /// into `known` gives the same set as merging all of `self`
+    pub fn delta(&self, known: &ORSet<T>) -> ORSet<T> {
+        let entries = self
+            .entries
+            .iter()
+            .filter_map(|(value, tags)| {
+                let seen = known.entries.get(value);
+                let unseen: BTreeSet<Tag> = tags
+                    .iter()
+                    .filter(|tag| !seen.is_some_and(|seen| seen.contains(*tag)))
+                    .cloned()
+                    .collect();
+                (!unseen.is_empty()).then(|| (value.clone(), unseen))
+            })
+            .collect();
+        ORSet {
+            entries,
+            removed: self.removed.difference(&known.removed).cloned().collect(),
+            inserts: 0,
+        }
+    }
+}
+
+/// When a write was made: a timestamp (a Lamport clock, say) and the writing node. Stamps
+/// order by timestamp, then by node id, so concurrent writes resolve the same way everywhere.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct Stamp {
+    pub ts: u64,
+    pub node: String,
+}
+
+impl Stamp {
+    pub fn new(ts: u64, node: &str) -> Self {
+        Self {
+            ts,
+            node: node.to_string(),
+        }
+    }
+}
+
+/// The latest write to one key of an [`LWWMap`]; `None` records a delete
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Register<V> {
+    pub stamp: Stamp,
+    pub value: Option<V>,
+}
+
+/// Map of last-writer-wins registers: each key holds the write with the greatest [`Stamp`],
+/// deletes included, so replicas that have seen the same writes agree in any merge order
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct LWWMap<V> {
+    registers: BTreeMap<String, Register<V>>,
+}
+
+impl<V> Default for LWWMap<V> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<V> LWWMap<V> {
+    pub fn new() -> Self {
+        Self {
+            registers: BTreeMap::new(),
+        }
+    }
+
+    /// Write `value` (`None` to delete) at `stamp`; false, and nothing changes, if the key
+    /// already holds a write stamped at or after it
+    pub fn set(&mut self, key: &str, value: Option<V>, stamp: Stamp) -> bool {
+        if self
+            .registers
+            .get(key)
+            .is_some_and(|current| current.stamp >= stamp)
+        {
+            return false;
+        }
+        self.registers
+            .insert(key.to_string(), Register { stamp, value });
+        true
+    }
+
+    /// The value under `key`, unless it was never written or was deleted
+    pub fn get(&self, key: &str) -> Option<&V> {
+        self.registers.get(key)?.value.as_ref()
+    }
+
+    /// Stamp of the last write to `key`, deletes included
+    pub fn stamp(&self, key: &str) -> Option<&Stamp> {
+        self.registers.get(key).map(|register| &register.stamp)
+    }
+
+    /// Keys with a value, in order, with their values
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &V)> {
+        self.registers
+            .iter()
+            .filter_map(|(key, register)| Some((key.as_str(), register.value.as_ref()?)))
+    }
+
+    /// Every register, deletes included, as a merge would read them
+    pub fn registers(&self) -> &BTreeMap<String, Register<V>> {
+        &self.registers
+    }
+
+    /// Take in `other`'s writes; returns how many keys changed
+    pub fn merge(&mut self, other: LWWMap<V>) -> usize {
+        let mut changed = 0;
+        for (key, register) in other.registers {
+            if self.set(&key, register.value, register.stamp) {
+                changed += 1;
+            }
+        }
+        changed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::collection::{hash_map, vec};
+    use proptest::prelude::*;
+
+    fn counter(version: u64, value: u64) -> Counter {
+        Counter { version, value }
+    }
+
+    fn merged(states: impl IntoIterator<Item = HashMap<String, Counter>>) -> KV {
+        let mut kv = KV::new();
+        for state in states {
+            kv.merge(state);
+        }
+        kv
+    }
+
+    #[test]
+    fn test_equal_versions_merge_to_the_larger_value() {
+        let ours = HashMap::from([("n1".to_string(), counter(2, 3))]);
+        let theirs = HashMap::from([("n1".to_string(), counter(2, 5))]);
+        let a = merged([ours.clone(), theirs.clone()]);
+        let b = merged([theirs, ours]);
+        assert_eq!(a.counters, b.counters);
+        assert_eq!(a.read(), 5);
+    }
+
+    proptest! {
+        #[test]
+        fn prop_kv_replicas_converge_whatever_the_merge_order(
+            states in vec(
+                hash_map(
+                    prop::sample::select(vec!["n1", "n2", "n3"]).prop_map(String::from),
+                    (0..4u64, 0..10u64).prop_map(|(version, value)| counter(version, value)),
+                    0..3,
+                ),
+                1..6,
+            ),
+        ) {
+            let forward = merged(states.clone());
+            let backward = merged(states.iter().rev().cloned());
+            // Merging everything again is a no-op
+            let twice = merged(states.iter().chain(&states).cloned());
+            prop_assert_eq!(&forward.counters, &backward.counters);
+            prop_assert_eq!(&forward.counters, &twice.counters);
+        }
+    }
+
+    #[test]
+    fn test_snapshots_restore_the_counters_and_beat_json() {
+        let mut kv = KV::new();
+        for node in 1..=5 {
+            kv.add(format!("n{node}"), node * 100);
+        }
+        let snapshot = kv.snapshot();
+        assert!(snapshot.len() < serde_json::to_vec(&kv.counters).unwrap().len());
+
+        let mut restored = KV::new();
+        restored.add("n9".to_string(), 1);
+        restored.restore(&snapshot).unwrap();
+        assert_eq!(restored.counters, kv.counters);
+        assert!(restored.restore(b"garbage").is_err());
+    }
+
+    #[test]
+    fn test_named_counters_count_and_merge_apart() {
+        let mut a = NamedKV::new();
+        let mut b = NamedKV::new();
+        a.add("hits", "n1".to_string(), 3);
+        a.add("misses", "n1".to_string(), 1);
+        b.add("hits", "n2".to_string(), 4);
+
+        b.merge("hits", a.get("hits").unwrap().counters.clone());
+        b.merge("misses", a.get("misses").unwrap().counters.clone());
+        assert_eq!(b.read("hits"), 7);
+        assert_eq!(b.read("misses"), 1);
+        assert_eq!(b.read("nothing"), 0);
+
+        let mut restored = NamedKV::new();
+        restored.restore(&b.snapshot()).unwrap();
+        assert_eq!(restored.read("hits"), 7);
+        assert_eq!(
+            restored.get("misses").unwrap().counters,
+            b.get("misses").unwrap().counters
+        );
+    }
+
+    #[test]
+    fn test_versions_cover_only_what_was_merged() {
+        let mut ahead = KV::new();
+        ahead.add("n1".to_string(), 2);
+        ahead.add("n1".to_string(), 2);
+        ahead.add("n2".to_string(), 1);
+        let context = ahead.versions();
+        assert_eq!(
+            context,
+            HashMap::from([("n1".to_string(), 2), ("n2".to_string(), 1)])
+        );
+
+        let mut behind = KV::new();
+        behind.init(vec!["n1".to_string(), "n2".to_string()]);
+        assert!(behind.versions().is_empty());
+        assert!(!behind.covers(&context));
+        behind.merge(HashMap::from([("n1".to_string(), counter(2, 4))]));
+        assert!(!behind.covers(&context));
+        behind.merge(ahead.counters.clone());
+        assert!(behind.covers(&context));
+        assert!(KV::new().covers(&HashMap::from([("n3".to_string(), 0)])));
+    }
+
+    #[test]
+    fn test_pn_counter_reads_increments_minus_decrements() {
+        let mut counter = PNCounter::new();
+        counter.init(vec!["n1".to_string(), "n2".to_string()]);
+        counter.add("n1".to_string(), 5);
+        counter.add("n2".to_string(), -8);
+        counter.add("n1".to_string(), 0);
+        assert_eq!(counter.read(), -3);
+        assert_eq!(counter.increments.counters["n1"].version, 1);
+        assert_eq!(counter.decrements.counters["n1"].version, 0);
+    }
+
+    #[test]
+    fn test_pn_counters_converge_whatever_the_merge_order() {
+        let mut a = PNCounter::new();
+        let mut b = PNCounter::new();
+        a.add("n1".to_string(), 4);
+        a.add("n1".to_string(), -1);
+        b.add("n2".to_string(), -6);
+
+        let (a_inc, a_dec) = (a.increments.counters.clone(), a.decrements.counters.clone());
+        a.merge(b.increments.counters.clone(), b.decrements.counters.clone());
+        b.merge(a_inc.clone(), a_dec.clone());
+        // Stale or repeated state changes nothing
+        b.merge(a_inc, a_dec);
+        assert_eq!(a.read(), -3);
+        assert_eq!(b.read(), -3);
+    }
+
+    #[test]
+    fn test_g_set_deltas_carry_only_what_the_peer_lacks() {
+        let mut ours = GSet::new();
+        let mut theirs = GSet::new();
+        for value in [1, 2, 3] {
+            ours.insert(value);
+        }
+        theirs.insert(2);
+
+        let delta = ours.delta(&theirs);
+        assert_eq!(delta.iter().copied().collect::<Vec<_>>(), vec![1, 3]);
+        assert_eq!(theirs.merge(delta), 2);
+        assert_eq!(theirs, ours);
+        assert_eq!(serde_json::to_string(&ours).unwrap(), "[1,2,3]");
+    }
+
+    #[test]
+    fn test_or_set_inserts_win_over_concurrent_removes() {
+        let mut a = ORSet::new();
+        a.insert("n1", 7);
+        let mut b = a.clone();
+
+        // b removes the 7 it saw while a inserts it again
+        assert!(b.remove(&7));
+        a.insert("n1", 7);
+        a.merge(b.clone());
+        b.merge(a.clone());
+        assert!(a.contains(&7) && b.contains(&7));
+
+        // A remove that saw every insert sticks, even against stale state
+        let stale = a.clone();
+        b.remove(&7);
+        a.merge(b.clone());
+        a.merge(stale);
+        assert!(!a.contains(&7));
+    }
+
+    #[test]
+    fn test_or_set_restored_from_a_peer_never_reuses_a_tag() {
+        let mut before = ORSet::new();
+        before.insert("n1", 7);
+        before.remove(&7);
+
+        // n1 restarts empty and takes the cluster's state back, tombstone and all
+        let mut restored = ORSet::new();
+        restored.merge(before);
+        restored.insert("n1", 7);
+        let mut peer = ORSet::new();
+        peer.merge(restored.clone());
+        assert!(restored.contains(&7) && peer.contains(&7));
+    }
+
+    #[test]
+    fn test_or_set_deltas_match_full_merges_and_round_trip() {
+        let mut ours = ORSet::new();
+        ours.insert("n1", 1);
+        ours.insert("n1", 2);
+        let mut theirs = ours.clone();
+        ours.remove(&1);
+        ours.insert("n1", 3);
+
+        let mut by_delta = theirs.clone();
+        by_delta.merge(ours.delta(&theirs));
+        theirs.merge(ours.clone());
+        assert_eq!(by_delta.iter().collect::<Vec<_>>(), vec![&2, &3]);
+        assert_eq!(
+            by_delta.iter().collect::<Vec<_>>(),
+            theirs.iter().collect::<Vec<_>>()
+        );
+
+        let json = serde_json::to_string(&ours).unwrap();
+        assert_eq!(serde_json::from_str::<ORSet<u64>>(&json).unwrap(), ours);
+    }
+
+    #[test]
+    fn test_lww_map_keeps_the_latest_write_and_breaks_ties_by_node() {
+        let mut map = LWWMap::new();
+        assert!(map.set("x", Some(1), Stamp::new(2, "n1")));
+        assert!(!map.set("x", Some(2), Stamp::new(1, "n3")));
+        assert!(map.set("x", Some(3), Stamp::new(2, "n2")));
+        assert!(!map.set("x", Some(4), Stamp::new(2, "n2")));
+        assert_eq!(map.get("x"), Some(&3));
+
+        // A later delete hides the value but keeps its stamp
+        assert!(map.set("x", None, Stamp::new(3, "n1")));
+        assert_eq!(map.get("x"), None);
+        assert_eq!(map.stamp("x"), Some(&Stamp::new(3, "n1")));
+        assert_eq!(map.iter().count(), 0);
+    }
+
+    #[test]
+    fn test_lww_maps_converge_and_round_trip() {
+        let mut a = LWWMap::new();
+        let mut b = LWWMap::new();
+        a.set("x", Some(1), Stamp::new(1, "n1"));
+        a.set("y", Some(2), Stamp::new(5, "n1"));
+        b.set("x", Some(9), Stamp::new(1, "n2"));
+        b.set("y", None, Stamp::new(4, "n2"));
+
+        let a_before = a.clone();
+        assert_eq!(a.merge(b.clone()), 1);
+        assert_eq!(b.merge(a_before), 1);
+        assert_eq!(a, b);
+        assert_eq!(a.iter().collect::<Vec<_>>(), vec![("x", &9), ("y", &2)]);
+
+        let json = serde_json::to_string(&a).unwrap();
+        assert_eq!(serde_json::from_str::<LWWMap<u64>>(&json).unwrap(), a);
+    }
 }
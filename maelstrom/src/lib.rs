@@ -1,14 +1,141 @@
+// Lets macro-generated `::maelstrom::...` paths resolve inside this crate too
+extern crate self as maelstrom;
+
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
+#[cfg(any(
+    feature = "broadcast",
+    feature = "counter",
+    feature = "kafka",
+    feature = "kv-service"
+))]
 use std::collections::HashMap;
 
+pub mod admin;
+pub mod breaker;
+pub mod coalesce;
+pub mod compact;
+pub mod config;
+pub mod dedup;
+pub mod delta;
+pub mod error;
+pub mod events;
+pub mod faults;
+pub mod hlc;
+#[cfg(feature = "unique-ids")]
+pub mod ids;
 pub mod kv;
+pub mod liveness;
 pub mod log;
-pub mod node;
-pub mod simple_log;
+pub mod logging;
+pub mod metrics;
+pub mod middleware;
+pub mod outbox;
+pub mod persist;
+pub mod prelude;
+#[cfg(feature = "raft")]
+pub mod raft;
+pub mod replay;
+pub mod ring;
+pub mod rpc;
+mod runtime;
+#[cfg(feature = "kv-service")]
+pub mod services;
+pub mod shard;
+pub mod sim;
+pub mod tcp;
+pub mod tee;
+pub mod testing;
+pub mod timer;
+pub mod trace;
+pub mod validate;
+pub mod writer;
+
+// The node runtime is exported from the crate root only; this is the canonical path for
+// everything below
+pub use config::NodeConfig;
+pub use error::MaelstromError;
+pub use maelstrom_macros::maelstrom_workload;
+pub use middleware::{Middleware, run_node_with};
+pub use runtime::{
+    Deferral, Deferred, MembershipChange, MessageHandler, Node, PROTOCOL_VERSION, poll_calls,
+    process, run_node, run_node_with_config, spawn_split_stdin_reader, spawn_stdin_reader,
+};
+pub use validate::Invalid;
+
+/// Former home of the node runtime, kept so existing imports still compile. Items here
+/// forward to their crate-root counterparts.
+pub mod node {
+    use crate::{Message, NodeConfig, runtime, trace::Envelope};
+    use std::time::Instant;
+    use tokio::sync::mpsc;
+
+    // Trait re-exports cannot carry a deprecation; implement `maelstrom::MessageHandler`
+    pub use crate::runtime::MessageHandler;
+
+    #[deprecated(note = "use `maelstrom::Node` or `maelstrom::prelude`")]
+    pub type Node = runtime::Node;
+
+    #[deprecated(note = "use `maelstrom::MembershipChange`")]
+    pub type MembershipChange = runtime::MembershipChange;
+
+    #[deprecated(note = "use `maelstrom::Deferred`")]
+    pub type Deferred = runtime::Deferred;
+
+    #[deprecated(note = "use `maelstrom::PROTOCOL_VERSION`")]
+    pub const PROTOCOL_VERSION: u32 = runtime::PROTOCOL_VERSION;
+
+    #[deprecated(note = "use `maelstrom::run_node` or `maelstrom::prelude`")]
+    pub async fn run_node<H: MessageHandler>(handler: H) {
+        runtime::run_node(handler).await;
+    }
+
+    #[deprecated(note = "use `maelstrom::run_node_with_config`")]
+    pub async fn run_node_with_config<H: MessageHandler>(handler: H, config: NodeConfig) {
+        runtime::run_node_with_config(handler, config).await;
+    }
+
+    #[deprecated(note = "use `maelstrom::process`")]
+    pub fn process<H: MessageHandler>(
+        handler: &mut H,
+        node: &mut runtime::Node,
+        message: Message,
+    ) -> Vec<Message> {
+        runtime::process(handler, node, message)
+    }
+
+    #[deprecated(note = "use `maelstrom::poll_calls`")]
+    pub fn poll_calls<H: MessageHandler>(
+        handler: &mut H,
+        node: &mut runtime::Node,
+        now: Instant,
+    ) -> Vec<Message> {
+        runtime::poll_calls(handler, node, now)
+    }
+
+    #[deprecated(note = "use `maelstrom::spawn_stdin_reader`")]
+    pub fn spawn_stdin_reader(tx: mpsc::Sender<(Message, Envelope)>) {
+        runtime::spawn_stdin_reader(tx);
+    }
+
+    #[deprecated(note = "use `maelstrom::spawn_split_stdin_reader`")]
+    pub fn spawn_split_stdin_reader(
+        clients: mpsc::Sender<(Message, Envelope)>,
+        peers: mpsc::Sender<(Message, Envelope)>,
+    ) {
+        runtime::spawn_split_stdin_reader(clients, peers);
+    }
+}
+
+/// Former home of the in-memory kafka logs, now merged into [`log`], which also covers the
+/// disk-backed ones. Kept so existing imports still compile.
+pub mod simple_log {
+    #[deprecated(note = "use `maelstrom::log::Logs`")]
+    pub type Logs = crate::log::Logs;
 
-// Re-export key types from modules
-pub use node::{MessageHandler, Node, run_node};
+    #[deprecated(note = "use `maelstrom::log::Log`")]
+    pub type Log = crate::log::Log;
+}
 
 #[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug, Serialize, Deserialize)]
 pub struct Version {
@@ -23,9 +150,21 @@ pub struct Message {
     pub body: MessageBody,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-#[serde(tag = "type")]
-#[serde(rename_all = "snake_case")]
+/// Every message body understood by the workspace, tagged by `type` on the wire.
+///
+/// Variants are grouped by workload and compiled only when the matching cargo feature
+/// (`echo`, `unique-ids`, `broadcast`, `counter`, `kafka`, `txn`, `raft`) is enabled, so a
+/// binary only pattern-matches the messages it can actually receive. `Init`, `InitOk`, the
+/// `Hello` handshake, `Ack`, the admin messages, and `Error` are always available.
+///
+/// Fire-and-forget internal bodies (gossip, forwarded sends, txn replication) are never
+/// answered, so their `msg_id` is optional and senders leave it unset rather than spending an
+/// id on them.
+///
+/// Handlers implement the generated [`MessageBodyDispatch`] trait to receive one method call per
+/// variant instead of matching on the body themselves.
+#[maelstrom_workload]
+#[derive(Debug, Clone)]
 pub enum MessageBody {
     Init {
         msg_id: u64,
@@ -36,38 +175,151 @@ pub enum MessageBody {
         msg_id: u64,
         in_reply_to: u64,
     },
+    /// Peer handshake announcing the sender's internal protocol version
+    Hello {
+        msg_id: u64,
+        version: u32,
+    },
+    HelloOk {
+        msg_id: u64,
+        in_reply_to: u64,
+        version: u32,
+    },
+    /// Receipt for a message sent with [`Node::deliver`]; handled by the framework
+    Ack {
+        in_reply_to: u64,
+    },
+    /// Admin request for the node's metrics, answered by the framework
+    Stats {
+        msg_id: u64,
+    },
+    StatsOk {
+        msg_id: u64,
+        in_reply_to: u64,
+        stats: Value,
+    },
+    /// Admin request for the handler's state (see [`admin::Introspect`])
+    DumpState {
+        msg_id: u64,
+    },
+    DumpStateOk {
+        msg_id: u64,
+        in_reply_to: u64,
+        state: Value,
+    },
+    /// Admin request to retune a running node; omitted settings are left alone
+    SetConfig {
+        msg_id: u64,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        tick_ms: Option<u64>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        fanout: Option<usize>,
+    },
+    SetConfigOk {
+        msg_id: u64,
+        in_reply_to: u64,
+    },
+    /// Nodes joining or leaving the cluster after init
+    Membership {
+        msg_id: u64,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        added: Vec<String>,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        removed: Vec<String>,
+    },
+    MembershipOk {
+        msg_id: u64,
+        in_reply_to: u64,
+    },
+    /// Request for a peer's whole state, so a restarted or newly joined node can start from
+    /// it rather than from nothing
+    StateSync {
+        msg_id: u64,
+    },
+    StateSyncOk {
+        msg_id: u64,
+        in_reply_to: u64,
+        /// The peer's [`persist::Persistent::snapshot`], in [`persist::encode`] form
+        state: String,
+    },
+    /// Another body in compact form (see [`compact`]); unpacked by the decoder, so handlers
+    /// never see it
+    Packed {
+        data: String,
+    },
+    #[cfg(feature = "echo")]
     Echo {
         msg_id: u64,
         echo: String,
     },
+    #[cfg(feature = "echo")]
     EchoOk {
         msg_id: u64,
         in_reply_to: u64,
         echo: String,
     },
+    /// Many payloads echoed in one round trip, in order
+    #[cfg(feature = "echo")]
+    EchoBatch {
+        msg_id: u64,
+        echoes: Vec<String>,
+    },
+    #[cfg(feature = "echo")]
+    EchoBatchOk {
+        msg_id: u64,
+        in_reply_to: u64,
+        echoes: Vec<String>,
+    },
+    #[cfg(feature = "unique-ids")]
     Generate {
         msg_id: u64,
     },
+    #[cfg(feature = "unique-ids")]
     GenerateOk {
         msg_id: u64,
         in_reply_to: u64,
-        id: u64,
+        id: UniqueId,
     },
+    #[cfg(feature = "broadcast")]
     Broadcast {
         msg_id: u64,
         message: u64,
     },
+    #[cfg(feature = "broadcast")]
     BroadcastOk {
         msg_id: u64,
         in_reply_to: u64,
     },
+    #[cfg(feature = "broadcast")]
     BroadcastGossip {
-        msg_id: u64,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        msg_id: Option<u64>,
+        messages: Vec<u64>,
+    },
+    /// Acknowledges the values of the `broadcast_gossip`s a peer sent since the receiver's
+    /// last tick, sent with that tick's gossip rather than once per gossip. Fire-and-forget
+    /// like the gossip itself, so it names no `in_reply_to` and is never retried: a lost ack
+    /// costs the sender one more round of gossip with the same values, which are then
+    /// acknowledged again
+    #[cfg(feature = "broadcast")]
+    BroadcastGossipOk {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        msg_id: Option<u64>,
         messages: Vec<u64>,
     },
+    #[cfg(any(feature = "broadcast", feature = "counter", feature = "kv-service"))]
     Read {
         msg_id: u64,
+        /// Named counter, or key-value service key, to read; the workload's own counter when
+        /// absent
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        key: Option<String>,
+        /// Counter versions per node the client has already read, from an earlier `read_ok`;
+        /// a replica that has not caught up to them refuses rather than read lower
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        context: Option<HashMap<String, u64>>,
     },
+    #[cfg(any(feature = "broadcast", feature = "counter", feature = "kv-service"))]
     ReadOk {
         msg_id: u64,
         in_reply_to: u64,
@@ -75,98 +327,202 @@ pub enum MessageBody {
         messages: Option<Vec<u64>>,
         #[serde(skip_serializing_if = "Option::is_none")]
         value: Option<u64>,
+        /// Counter versions per node behind `value`, to pass on to the next `read`
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        context: Option<HashMap<String, u64>>,
     },
+    #[cfg(feature = "broadcast")]
     Topology {
         msg_id: u64,
         topology: HashMap<String, Vec<String>>,
     },
+    #[cfg(feature = "broadcast")]
     TopologyOk {
         msg_id: u64,
         in_reply_to: u64,
     },
+    #[cfg(feature = "counter")]
     Add {
         msg_id: u64,
         delta: u64,
+        /// Named counter to add to; the workload's own counter when absent
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        key: Option<String>,
     },
+    #[cfg(feature = "counter")]
     AddOk {
         msg_id: u64,
         in_reply_to: u64,
     },
+    #[cfg(feature = "counter")]
     CounterGossip {
-        msg_id: u64,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        msg_id: Option<u64>,
         counters: HashMap<String, kv::Counter>,
+        /// Per-node entries of named counters, by name
+        #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+        named: HashMap<String, HashMap<String, kv::Counter>>,
+        /// What the receiver acknowledges with `counter_gossip_ok` (see [`delta::DeltaState`])
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        seq: Option<u64>,
+    },
+    /// Acknowledges every `counter_gossip` up to `seq`; fire-and-forget like the gossip, so a
+    /// lost ack costs one more round of gossip with the same entries
+    #[cfg(feature = "counter")]
+    CounterGossipOk {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        msg_id: Option<u64>,
+        seq: u64,
+    },
+    /// Key-value service (`seq-kv`, `lin-kv`) write; see [`services`]
+    #[cfg(feature = "kv-service")]
+    Write {
+        msg_id: u64,
+        key: String,
+        value: u64,
+    },
+    #[cfg(feature = "kv-service")]
+    WriteOk {
+        msg_id: u64,
+        in_reply_to: u64,
+    },
+    /// Key-value service compare-and-set
+    #[cfg(feature = "kv-service")]
+    Cas {
+        msg_id: u64,
+        key: String,
+        from: u64,
+        to: u64,
+        #[serde(default)]
+        create_if_not_exists: bool,
+    },
+    #[cfg(feature = "kv-service")]
+    CasOk {
+        msg_id: u64,
+        in_reply_to: u64,
     },
+    #[cfg(feature = "kafka")]
     Send {
         msg_id: u64,
         key: String,
         msg: u64,
     },
+    #[cfg(feature = "kafka")]
     SendOk {
         msg_id: u64,
         in_reply_to: u64,
         offset: u64,
     },
+    #[cfg(feature = "kafka")]
     ForwardSend {
-        msg_id: u64,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        msg_id: Option<u64>,
         orig_src: String,
         orig_msg_id: u64,
         key: String,
         msg: u64,
     },
+    #[cfg(feature = "kafka")]
     Replicate {
         msg_id: u64,
         key: String,
         msg: u64,
         offset: u64,
     },
+    /// Fills an offset of `key`'s log that was claimed for a send whose entry was never
+    /// written, so the gap does not hold back later entries
+    #[cfg(feature = "kafka")]
+    Tombstone {
+        msg_id: u64,
+        key: String,
+        offset: u64,
+    },
+    #[cfg(feature = "kafka")]
     ReplicateOk {
         msg_id: u64,
         in_reply_to: u64,
+        /// The log the offset is in, since every log counts its offsets from 0
+        key: String,
         offset: u64,
     },
+    #[cfg(feature = "kafka")]
     Poll {
         msg_id: u64,
         offsets: HashMap<String, u64>,
     },
+    #[cfg(feature = "kafka")]
     PollOk {
         msg_id: u64,
         in_reply_to: u64,
-        msgs: HashMap<String, Vec<(u64, u64)>>,
-    },
+        msgs: HashMap<String, crate::log::Entries>,
+        /// Offset to poll next for each log the node's poll limits cut short
+        #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+        next_offsets: HashMap<String, u64>,
+        /// Whether any log has entries past those in `msgs`
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        has_more: bool,
+    },
+    #[cfg(feature = "kafka")]
     CommitOffsets {
         msg_id: u64,
         offsets: HashMap<String, u64>,
+        /// Client the commit is for, when a follower passes it on to the leader; the sender
+        /// when absent
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        consumer: Option<String>,
     },
+    #[cfg(feature = "kafka")]
     CommitOffsetsOk {
         msg_id: u64,
         in_reply_to: u64,
     },
+    #[cfg(feature = "kafka")]
     ListCommittedOffsets {
         msg_id: u64,
         keys: Vec<String>,
+        /// Client whose own commits to list; the highest offset anyone committed when absent
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        consumer: Option<String>,
     },
+    #[cfg(feature = "kafka")]
     ListCommittedOffsetsOk {
         msg_id: u64,
         in_reply_to: u64,
         offsets: HashMap<String, u64>,
     },
+    #[cfg(feature = "txn")]
     Txn {
         msg_id: u64,
         txn: Vec<(String, u64, Option<u64>)>,
     },
+    #[cfg(feature = "txn")]
     TxnOk {
         msg_id: u64,
         in_reply_to: u64,
         txn: Vec<(String, u64, Option<u64>)>,
     },
+    #[cfg(feature = "txn")]
     TarutReplicate {
-        msg_id: u64,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        msg_id: Option<u64>,
         txn: Vec<(String, u64, Option<u64>, u64)>,
     },
+    #[cfg(feature = "txn")]
     TarctReplicate {
-        msg_id: u64,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        msg_id: Option<u64>,
         txn: Vec<(String, u64, Option<u64>, Version)>,
     },
+    #[cfg(feature = "raft")]
+    RequestVote(raft::RequestVote),
+    #[cfg(feature = "raft")]
+    RequestVoteOk(raft::RequestVoteOk),
+    #[cfg(feature = "raft")]
+    AppendEntries(raft::AppendEntries),
+    #[cfg(feature = "raft")]
+    AppendEntriesOk(raft::AppendEntriesOk),
+    #[cfg(feature = "raft")]
+    InstallSnapshot(raft::InstallSnapshot),
     Error {
         msg_id: u64,
         in_reply_to: u64,
@@ -179,7 +535,40 @@ pub enum MessageBody {
     },
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+/// An id handed out by `generate`: a number, or a string for formats wider than 64 bits
+#[cfg(feature = "unique-ids")]
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum UniqueId {
+    Int(u64),
+    Text(String),
+}
+
+#[cfg(feature = "unique-ids")]
+impl From<u64> for UniqueId {
+    fn from(id: u64) -> Self {
+        Self::Int(id)
+    }
+}
+
+#[cfg(feature = "unique-ids")]
+impl From<String> for UniqueId {
+    fn from(id: String) -> Self {
+        Self::Text(id)
+    }
+}
+
+#[cfg(feature = "unique-ids")]
+impl std::fmt::Display for UniqueId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Int(id) => write!(f, "{id}"),
+            Self::Text(id) => f.write_str(id),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum ErrorCode {
     Timeout = 0,
     NodeNotFound = 1,
@@ -194,3 +583,181 @@ pub enum ErrorCode {
     TxnConflict = 30,
     Other = 999,
 }
+
+impl ErrorCode {
+    /// Numeric code used on the wire
+    pub fn code(self) -> u64 {
+        self as u64
+    }
+
+    /// Map a wire code back to a known variant, treating unknown codes as `Other`
+    pub fn from_code(code: u64) -> Self {
+        match code {
+            0 => Self::Timeout,
+            1 => Self::NodeNotFound,
+            10 => Self::NotSupported,
+            11 => Self::TemporarilyUnavailable,
+            12 => Self::MalformedMessage,
+            13 => Self::Crash,
+            14 => Self::Abort,
+            20 => Self::KeyDoesNotExist,
+            21 => Self::KeyAlreadyExists,
+            22 => Self::PreconditionFailed,
+            30 => Self::TxnConflict,
+            _ => Self::Other,
+        }
+    }
+}
+
+impl Serialize for ErrorCode {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_u64(self.code())
+    }
+}
+
+impl<'de> Deserialize<'de> for ErrorCode {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        u64::deserialize(deserializer).map(Self::from_code)
+    }
+}
+
+impl Message {
+    /// Attach a structured diagnostic field to an `Error` body; other bodies are left untouched
+    pub fn with_extra(mut self, key: &str, value: impl Into<Value>) -> Self {
+        if let MessageBody::Error { extra, .. } = &mut self.body {
+            let fields = extra.get_or_insert_with(|| Value::Object(Default::default()));
+            if let Value::Object(map) = fields {
+                map.insert(key.to_string(), value.into());
+            }
+        }
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_error_code_uses_numeric_wire_format() {
+        assert_eq!(
+            serde_json::to_value(ErrorCode::TxnConflict).unwrap(),
+            json!(30)
+        );
+        let code: ErrorCode = serde_json::from_value(json!(11)).unwrap();
+        assert_eq!(code, ErrorCode::TemporarilyUnavailable);
+        let unknown: ErrorCode = serde_json::from_value(json!(1234)).unwrap();
+        assert_eq!(unknown, ErrorCode::Other);
+    }
+
+    #[test]
+    fn test_generated_reply_pairing_and_accessors() {
+        let send = MessageBody::Send {
+            msg_id: 3,
+            key: "k1".to_string(),
+            msg: 9,
+        };
+        assert_eq!(send.type_name(), "send");
+        assert_eq!(send.reply_type(), Some("send_ok"));
+        assert_eq!(send.msg_id(), Some(3));
+        assert_eq!(send.in_reply_to(), None);
+        assert!(!send.is_reply());
+
+        let list_ok = MessageBody::ListCommittedOffsetsOk {
+            msg_id: 4,
+            in_reply_to: 3,
+            offsets: HashMap::new(),
+        };
+        assert_eq!(list_ok.type_name(), "list_committed_offsets_ok");
+        assert_eq!(list_ok.reply_type(), None);
+        assert_eq!(list_ok.in_reply_to(), Some(3));
+        assert!(list_ok.is_reply());
+
+        let gossip = MessageBody::BroadcastGossip {
+            msg_id: Some(5),
+            messages: vec![],
+        };
+        assert_eq!(gossip.reply_type(), Some("broadcast_gossip_ok"));
+        // Its acknowledgment names no request, so it does not count as a reply
+        let ack = MessageBody::BroadcastGossipOk {
+            msg_id: None,
+            messages: vec![],
+        };
+        assert_eq!(ack.reply_type(), None);
+        assert!(!ack.is_reply());
+    }
+
+    struct Counting {
+        echoes: Vec<String>,
+    }
+
+    impl MessageBodyDispatch for Counting {
+        fn on_echo(
+            &mut self,
+            node: &mut Node,
+            src: String,
+            msg_id: u64,
+            echo: String,
+        ) -> Vec<Message> {
+            self.echoes.push(echo.clone());
+            vec![node.reply(
+                src,
+                MessageBody::EchoOk {
+                    msg_id: 1,
+                    in_reply_to: msg_id,
+                    echo,
+                },
+            )]
+        }
+    }
+
+    #[test]
+    fn test_dispatch_routes_to_variant_method() {
+        let mut handler = Counting { echoes: vec![] };
+        let mut node = Node::new();
+
+        let echo = Message {
+            src: "c1".to_string(),
+            dest: "n1".to_string(),
+            body: MessageBody::Echo {
+                msg_id: 2,
+                echo: "hi".to_string(),
+            },
+        };
+        let replies = handler.dispatch(&mut node, echo);
+        assert_eq!(replies.len(), 1);
+        assert_eq!(replies[0].dest, "c1");
+        assert_eq!(handler.echoes, vec!["hi"]);
+
+        // Variants without an override fall through to the default, which emits nothing
+        let generate = Message {
+            src: "c1".to_string(),
+            dest: "n1".to_string(),
+            body: MessageBody::Generate { msg_id: 3 },
+        };
+        assert!(handler.dispatch(&mut node, generate).is_empty());
+    }
+
+    #[test]
+    fn test_with_extra_adds_fields_to_error_body() {
+        let message = Message {
+            src: "n1".to_string(),
+            dest: "c1".to_string(),
+            body: MessageBody::Error {
+                msg_id: 1,
+                in_reply_to: 2,
+                code: ErrorCode::PreconditionFailed,
+                text: None,
+                extra: None,
+            },
+        }
+        .with_extra("key", "k1")
+        .with_extra("offset", 7);
+
+        let encoded = serde_json::to_value(&message).unwrap();
+        assert_eq!(encoded["body"]["code"], json!(22));
+        assert_eq!(encoded["body"]["key"], json!("k1"));
+        assert_eq!(encoded["body"]["offset"], json!(7));
+    }
+}
@@ -0,0 +1,87 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// Failure detector fed by peer traffic: a peer is alive if we heard from it within the last
+/// `suspect_after`. Peers we have never heard from get the same grace period from `start`.
+/// Without a `suspect_after` every peer is presumed alive.
+pub struct Liveness {
+    suspect_after: Option<Duration>,
+    start: Instant,
+    last_heard: HashMap<String, Instant>,
+}
+
+impl Liveness {
+    pub fn new(suspect_after: Option<Duration>) -> Self {
+        Self {
+            suspect_after,
+            start: Instant::now(),
+            last_heard: HashMap::new(),
+        }
+    }
+
+    /// Restart the grace period for peers not heard from yet, e.g. once init names them
+    pub fn reset(&mut self, now: Instant) {
+        self.start = now;
+    }
+
+    /// Record traffic from `peer`
+    pub fn heard_from(&mut self, peer: &str, now: Instant) {
+        match self.last_heard.get_mut(peer) {
+            Some(last) => *last = (*last).max(now),
+            None => {
+                self.last_heard.insert(peer.to_string(), now);
+            }
+        }
+    }
+
+    /// When we last heard from `peer`, if ever
+    pub fn last_heard(&self, peer: &str) -> Option<Instant> {
+        self.last_heard.get(peer).copied()
+    }
+
+    pub fn is_alive(&self, peer: &str, now: Instant) -> bool {
+        let Some(suspect_after) = self.suspect_after else {
+            return true;
+        };
+        let since = self.last_heard(peer).unwrap_or(self.start);
+        now.saturating_duration_since(since) < suspect_after
+    }
+
+    /// Forget a peer that left the cluster
+    pub fn forget(&mut self, peer: &str) {
+        self.last_heard.remove(peer);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_peers_are_suspected_after_going_quiet() {
+        let start = Instant::now();
+        let mut liveness = Liveness::new(Some(Duration::from_millis(100)));
+        liveness.reset(start);
+
+        // Unheard peers get a grace period
+        assert!(liveness.is_alive("n2", start + Duration::from_millis(50)));
+        assert!(!liveness.is_alive("n2", start + Duration::from_millis(100)));
+
+        liveness.heard_from("n2", start + Duration::from_millis(80));
+        assert!(liveness.is_alive("n2", start + Duration::from_millis(150)));
+        assert!(!liveness.is_alive("n2", start + Duration::from_millis(180)));
+
+        // Late-arriving older traffic does not move the clock back
+        liveness.heard_from("n2", start);
+        assert_eq!(
+            liveness.last_heard("n2"),
+            Some(start + Duration::from_millis(80))
+        );
+    }
+
+    #[test]
+    fn test_everyone_is_alive_without_a_timeout() {
+        let liveness = Liveness::new(None);
+        assert!(liveness.is_alive("n2", Instant::now() + Duration::from_secs(3600)));
+    }
+}
@@ -1,8 +1,323 @@
+use crate::MaelstromError;
+#[cfg(feature = "kv-service")]
+use crate::services::LinKv;
+use serde::{Deserialize, Serialize};
 use std::collections::BTreeMap;
 use std::collections::HashMap;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+#[cfg(feature = "kv-service")]
+use std::sync::Mutex;
 
+mod segments;
+
+pub use segments::Entries;
+use segments::Segments;
+
+/// Kafka-style logs by key, kept in memory ([`Logs::new`]) or written ahead to disk
+/// ([`Logs::open`]). Serializing takes the entries and commits only, so a snapshot restores
+/// as in-memory logs.
+#[derive(Serialize, Deserialize)]
 pub struct Logs {
     inner: HashMap<String, Log>,
+    /// A setting of the node, not state, so not part of a snapshot
+    #[serde(skip)]
+    limits: PollLimits,
+    /// Accept commits past the end of a log rather than refuse them; also a setting
+    #[serde(skip)]
+    lenient_commits: bool,
+    /// How polls answer for keys without a log; also a setting
+    #[serde(skip)]
+    unknown_keys: UnknownKeys,
+    /// Hold entries inserted beyond a gap back from polls until the gap is filled; also a
+    /// setting
+    #[serde(skip)]
+    staging: bool,
+    /// Where changes are written ahead of being applied, when disk-backed
+    #[serde(skip)]
+    wal: Option<Wal>,
+    /// Corrupt records found and dropped when the logs were opened
+    #[serde(skip)]
+    recovered: Vec<Corruption>,
+}
+
+/// Data that failed its checksum, in a disk-backed [`Logs`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Corruption {
+    /// An entry read for a poll no longer matches the checksum taken when it was written
+    Entry { key: String, offset: u64 },
+    /// A record in `key`'s write-ahead log, starting at byte `at`, failed its checksum on
+    /// replay; it and everything after it were dropped
+    Record { key: String, at: u64 },
+}
+
+impl fmt::Display for Corruption {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Entry { key, offset } => {
+                write!(f, "entry {offset} of log {key} fails its checksum")
+            }
+            Self::Record { key, at } => write!(
+                f,
+                "write-ahead log for {key} is corrupt from byte {at}; the rest was dropped"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for Corruption {}
+
+impl From<Corruption> for MaelstromError {
+    fn from(corruption: Corruption) -> Self {
+        Self::crash(corruption.to_string())
+    }
+}
+
+/// A commit of an offset no entry of the log ever had
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PastEnd {
+    pub offset: u64,
+    /// Offset the log's next append gets; every offset below it was handed out
+    pub next_offset: u64,
+}
+
+impl fmt::Display for PastEnd {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.next_offset {
+            0 => write!(f, "offset {} is past the end of an empty log", self.offset),
+            next => write!(
+                f,
+                "offset {} is past the newest offset, {}",
+                self.offset,
+                next - 1
+            ),
+        }
+    }
+}
+
+impl std::error::Error for PastEnd {}
+
+/// Why [`Logs::commit_offsets`] refused a commit; nothing was committed
+#[derive(Debug)]
+pub enum CommitError {
+    /// The commit for `key` was past the end of its log, and the logs are not lenient
+    PastEnd { key: String, past: PastEnd },
+    /// Writing the commit ahead failed
+    Io(io::Error),
+}
+
+impl fmt::Display for CommitError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::PastEnd { key, past } => write!(f, "cannot commit to log {key}: {past}"),
+            Self::Io(err) => write!(f, "log write failed: {err}"),
+        }
+    }
+}
+
+impl std::error::Error for CommitError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::PastEnd { past, .. } => Some(past),
+            Self::Io(err) => Some(err),
+        }
+    }
+}
+
+impl From<io::Error> for CommitError {
+    fn from(err: io::Error) -> Self {
+        Self::Io(err)
+    }
+}
+
+impl From<CommitError> for MaelstromError {
+    fn from(err: CommitError) -> Self {
+        match err {
+            CommitError::PastEnd { .. } => Self::precondition_failed(err.to_string()),
+            CommitError::Io(_) => Self::temporarily_unavailable(err.to_string()),
+        }
+    }
+}
+
+/// Caps on one `poll` response, so a client far behind catches up over several polls instead
+/// of one enormous `poll_ok`. `None` leaves a dimension unbounded, the default.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PollLimits {
+    /// Most entries returned for any one key
+    pub per_key: Option<usize>,
+    /// Most entries returned across all keys
+    pub entries: Option<usize>,
+    /// Approximate size of the returned entries as JSON, in bytes
+    pub bytes: Option<usize>,
+}
+
+impl PollLimits {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn per_key(mut self, max: usize) -> Self {
+        self.per_key = Some(max);
+        self
+    }
+
+    pub fn entries(mut self, max: usize) -> Self {
+        self.entries = Some(max);
+        self
+    }
+
+    pub fn bytes(mut self, max: usize) -> Self {
+        self.bytes = Some(max);
+        self
+    }
+
+    /// Read each `(key, entries from the polled offset)`, in key order so the same poll always
+    /// gets the same answer, until a limit is reached. The byte cap never empties a response
+    /// that has an entry to give, so a client always makes progress.
+    pub(crate) fn read(&self, mut logs: Vec<(&String, Entries)>) -> Polled {
+        logs.sort_by(|a, b| a.0.cmp(b.0));
+        let mut polled = Polled::default();
+        let (mut entries, mut bytes) = (0, 0);
+        for (key, log) in logs {
+            // `"key":[],`
+            bytes += key.len() + 6;
+            let mut taken = 0;
+            for &(offset, msg) in &log {
+                let size = entry_size(offset, msg);
+                if self.per_key.is_some_and(|max| taken >= max)
+                    || self.entries.is_some_and(|max| entries >= max)
+                    || (entries > 0 && self.bytes.is_some_and(|max| bytes + size > max))
+                {
+                    polled.next_offsets.insert(key.clone(), offset);
+                    break;
+                }
+                taken += 1;
+                entries += 1;
+                bytes += size;
+            }
+            polled.msgs.insert(key.clone(), log.take(taken));
+        }
+        polled
+    }
+}
+
+/// What a `poll` answers for a key no message was ever sent to
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum UnknownKeys {
+    /// Leave the key out of `msgs`
+    #[default]
+    Omit,
+    /// List the key with no entries, for checkers that expect every polled key back
+    Empty,
+}
+
+/// The answer to a `poll`: entries for each log, and where to go on from for the logs that
+/// had more than the poll limits let through
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Polled {
+    pub msgs: HashMap<String, Entries>,
+    /// Offset to poll next for each log with entries past those in `msgs`
+    pub next_offsets: HashMap<String, u64>,
+}
+
+impl Polled {
+    /// Whether some log was cut short, so polling on from `next_offsets` returns more
+    pub fn has_more(&self) -> bool {
+        !self.next_offsets.is_empty()
+    }
+}
+
+/// One read of a log, and where the next read picks up
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Page {
+    pub entries: Entries,
+    /// Offset to read from next: just past the last entry returned, or where this read
+    /// started when it returned none
+    pub next: u64,
+    /// Entries at or after `next` exist already
+    pub has_more: bool,
+}
+
+impl Page {
+    /// Read up to `max` entries of `entries` from `from`
+    pub(crate) fn read(entries: &Segments, from: u64, max: Option<usize>) -> Self {
+        let all = entries.range(from..u64::MAX);
+        let entries = all.clone().take(max.unwrap_or(usize::MAX));
+        Self {
+            next: entries.last().map_or(from, |&(offset, _)| offset + 1),
+            has_more: entries.len() < all.len(),
+            entries,
+        }
+    }
+}
+
+/// Figures for one log, as reported by [`Logs::stats`]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct KeyStats {
+    /// Entries held, after compaction
+    pub entries: usize,
+    /// Approximate size of the entries as JSON, in bytes
+    pub bytes: usize,
+    /// Offset of the newest entry
+    pub last_offset: Option<u64>,
+    /// Highest offset any consumer committed
+    pub committed: u64,
+    /// How far the committed offset of the consumer furthest behind trails the newest entry
+    pub lag: u64,
+}
+
+/// How much of each log compaction keeps; an entry is dropped only when no rule keeps it, and
+/// nothing is dropped by default
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Retention {
+    /// Keep the newest this many entries
+    pub keep_last: Option<usize>,
+    /// Keep entries at or above the committed offset less this many; with several consumers,
+    /// the offset of the one furthest behind
+    pub committed_window: Option<u64>,
+}
+
+impl Retention {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn keep_last(mut self, entries: usize) -> Self {
+        self.keep_last = Some(entries);
+        self
+    }
+
+    pub fn committed_window(mut self, offsets: u64) -> Self {
+        self.committed_window = Some(offsets);
+        self
+    }
+
+    /// First offset to keep of `entries`, a log committed up to `committed`
+    pub(crate) fn cutoff(&self, entries: &Segments, committed: u64) -> u64 {
+        let by_count = self.keep_last.map(|keep| match keep {
+            0 => u64::MAX,
+            keep => entries
+                .iter()
+                .nth_back(keep - 1)
+                .map_or(0, |&(offset, _)| offset),
+        });
+        let by_commit = self
+            .committed_window
+            .map(|window| committed.saturating_sub(window));
+        match (by_count, by_commit) {
+            (Some(by_count), Some(by_commit)) => by_count.min(by_commit),
+            (by_count, by_commit) => by_count.or(by_commit).unwrap_or(0),
+        }
+    }
+}
+
+/// Length of `[offset,msg],` in JSON
+fn entry_size(offset: u64, msg: u64) -> usize {
+    let digits = |n: u64| n.checked_ilog10().map_or(1, |d| d as usize + 1);
+    digits(offset) + digits(msg) + 4
 }
 
 impl Default for Logs {
@@ -15,70 +330,335 @@ impl Logs {
     pub fn new() -> Self {
         Self {
             inner: HashMap::new(),
+            limits: PollLimits::default(),
+            lenient_commits: false,
+            unknown_keys: UnknownKeys::Omit,
+            staging: true,
+            wal: None,
+            recovered: Vec::new(),
+        }
+    }
+
+    /// Logs backed by a write-ahead log per key in `dir`, starting from whatever earlier runs
+    /// left there, so acknowledged sends survive the process being killed. Every change is
+    /// handed to the OS before it is applied; nothing is fsynced, so a crash of the machine
+    /// itself can still lose the newest.
+    ///
+    /// Records carry a checksum. Replay stops at the first that fails it, dropping the rest of
+    /// that file, and the drop is listed in [`Logs::recovered_corruption`].
+    pub fn open(dir: impl AsRef<Path>) -> io::Result<Self> {
+        let dir = dir.as_ref();
+        fs::create_dir_all(dir)?;
+        let (mut inner, mut recovered) = (HashMap::new(), Vec::new());
+        for file in fs::read_dir(dir)? {
+            let path = file?.path();
+            if path.extension().is_some_and(|ext| ext == "wal")
+                && let Some(key) = path.file_stem().and_then(|stem| key_of(stem.to_str()?))
+            {
+                let (log, read, corrupt) = Log::replay(BufReader::new(File::open(&path)?))?;
+                // Cut a torn or corrupt tail off so later appends start on a line of their own
+                let file = OpenOptions::new().write(true).open(&path)?;
+                if file.metadata()?.len() > read {
+                    file.set_len(read)?;
+                }
+                if corrupt {
+                    recovered.push(Corruption::Record {
+                        key: key.clone(),
+                        at: read,
+                    });
+                }
+                inner.insert(key, log);
+            }
         }
+        Ok(Self {
+            inner,
+            limits: PollLimits::default(),
+            lenient_commits: false,
+            unknown_keys: UnknownKeys::Omit,
+            staging: true,
+            wal: Some(Wal {
+                dir: dir.to_path_buf(),
+                files: HashMap::new(),
+            }),
+            recovered,
+        })
+    }
+
+    /// Corrupt records [`Logs::open`] dropped
+    pub fn recovered_corruption(&self) -> &[Corruption] {
+        &self.recovered
+    }
+
+    /// Cap what each `poll` returns from now on
+    pub fn set_poll_limits(&mut self, limits: PollLimits) {
+        self.limits = limits;
+    }
+
+    pub fn poll_limits(&self) -> PollLimits {
+        self.limits
+    }
+
+    /// Accept commits past the end of a log from now on, as the logs once did, rather than
+    /// refuse them
+    pub fn set_lenient_commits(&mut self, lenient: bool) {
+        self.lenient_commits = lenient;
+    }
+
+    pub fn lenient_commits(&self) -> bool {
+        self.lenient_commits
+    }
+
+    /// Answer polls of keys without a log as `unknown_keys` says from now on
+    pub fn set_unknown_keys(&mut self, unknown_keys: UnknownKeys) {
+        self.unknown_keys = unknown_keys;
+    }
+
+    pub fn unknown_keys(&self) -> UnknownKeys {
+        self.unknown_keys
+    }
+
+    /// Whether entries inserted beyond a gap wait for it to be filled, as they do by default.
+    /// Turning staging off releases whatever is staged, gaps and all: for logs whose offsets
+    /// are handed out elsewhere, where a gap may never be filled.
+    pub fn set_staging(&mut self, staging: bool) {
+        self.staging = staging;
+        if !staging {
+            self.inner.values_mut().for_each(Log::unstage);
+        }
+    }
+
+    pub fn staging(&self) -> bool {
+        self.staging
+    }
+
+    /// Take on every setting of `from`, e.g. for logs restored or reopened in its place
+    pub fn copy_settings(&mut self, from: &Logs) {
+        self.limits = from.limits;
+        self.lenient_commits = from.lenient_commits;
+        self.unknown_keys = from.unknown_keys;
+        self.set_staging(from.staging);
     }
 
     fn get_or_create(&mut self, key: &str) -> &mut Log {
         self.inner.entry(key.to_string()).or_default()
     }
 
-    pub fn append_local(&mut self, key: &str, msg: u64) -> u64 {
-        let log = self.get_or_create(key);
-        let off = log.next_offset;
-        log.entries.insert(off, msg);
-        log.next_offset += 1;
-        off
+    /// Whether any message has been placed under `key`
+    pub fn contains_key(&self, key: &str) -> bool {
+        self.inner.contains_key(key)
+    }
+
+    /// Append `msg` to `key`'s log at its next offset; fails only when disk-backed and the
+    /// write does, leaving the log as it was
+    pub fn append_local(&mut self, key: &str, msg: u64) -> io::Result<u64> {
+        let off = self.next_offset(key);
+        self.insert_at(key, off, msg)?;
+        Ok(off)
     }
 
-    pub fn insert_at(&mut self, key: &str, offset: u64, msg: u64) {
+    /// Offset the next append to `key`'s log gets: past every entry, staged ones included
+    pub fn next_offset(&self, key: &str) -> u64 {
+        self.inner.get(key).map_or(0, |log| {
+            let staged = log.staged.last_key_value().map(|(&offset, _)| offset + 1);
+            staged.unwrap_or(0).max(log.next_offset)
+        })
+    }
+
+    /// Offsets missing from `key`'s log below entries staged past them, in order; empty when
+    /// nothing is staged
+    pub fn missing(&self, key: &str) -> Vec<Range<u64>> {
+        self.inner.get(key).map_or_else(Vec::new, Log::missing)
+    }
+
+    /// Let polls of `key`'s log see entries below `offset`; see [`Log::set_hwm`]
+    pub fn set_hwm(&mut self, key: &str, offset: u64) {
+        self.get_or_create(key).set_hwm(offset);
+    }
+
+    /// The high-watermark of `key`'s log, 0 when there is none
+    pub fn hwm(&self, key: &str) -> u64 {
+        self.inner.get(key).map_or(0, Log::hwm)
+    }
+
+    /// Place `msg` at `offset` in `key`'s log, as a replica told where it goes. With staging
+    /// on, an entry arriving beyond a gap is held back from polls until the gap is filled.
+    pub fn insert_at(&mut self, key: &str, offset: u64, msg: u64) -> io::Result<()> {
+        let (durable, staging) = (self.wal.is_some(), self.staging);
+        if let Some(wal) = &mut self.wal {
+            wal.write(key, &format!("e {offset} {msg}"))?;
+        }
         let log = self.get_or_create(key);
-        log.entries.insert(offset, msg);
-        if offset >= log.next_offset {
-            log.next_offset = offset + 1;
+        log.place(offset, msg, staging);
+        if durable {
+            log.checksums.insert(offset, entry_crc(offset, msg));
         }
+        Ok(())
     }
 
-    /// Handle `poll`: for each requested log, read from that offset
-    pub fn poll(&self, offsets: &HashMap<String, u64>) -> HashMap<String, Vec<(u64, u64)>> {
-        let mut result = HashMap::new();
-        for (key, &off) in offsets {
-            if let Some(log) = self.inner.get(key) {
-                let entries = log.entries.range(off..).map(|(&o, &m)| (o, m)).collect();
-                result.insert(key.clone(), entries);
+    /// Fill `offset` in `key`'s log with a tombstone: an offset that was handed out but will
+    /// never hold an entry, so the gap it would leave does not hold back the entries staged
+    /// past it. An entry already at `offset` stays. Unlike an entry, the tombstone is applied
+    /// even when writing it ahead fails, since an unfilled gap would stall polls for good.
+    pub fn insert_tombstone(&mut self, key: &str, offset: u64) -> io::Result<()> {
+        let written = match &mut self.wal {
+            Some(wal) => wal.write(key, &format!("t {offset}")),
+            None => Ok(()),
+        };
+        let staging = self.staging;
+        self.get_or_create(key).fill(offset, None, staging);
+        written
+    }
+
+    /// Handle `poll`: for each requested log, read from that offset up to its high-watermark,
+    /// within the poll limits; keys without a log are answered per [`UnknownKeys`]. A
+    /// disk-backed log checks every entry against its checksum rather than serve it wrong.
+    pub fn poll(&self, offsets: &HashMap<String, u64>) -> Result<Polled, Corruption> {
+        let logs = offsets
+            .iter()
+            .filter_map(|(key, &off)| {
+                let log = self.inner.get(key)?;
+                Some((key, log.entries.range(off..log.hwm().max(off))))
+            })
+            .collect();
+        let mut polled = self.limits.read(logs);
+        if self.wal.is_some() {
+            for (key, entries) in &polled.msgs {
+                let checksums = &self.inner[key].checksums;
+                if let Some(&(offset, _)) = entries
+                    .iter()
+                    .find(|&&(offset, msg)| checksums.get(&offset) != Some(&entry_crc(offset, msg)))
+                {
+                    return Err(Corruption::Entry {
+                        key: key.clone(),
+                        offset,
+                    });
+                }
             }
         }
-        result
+        if self.unknown_keys == UnknownKeys::Empty {
+            for key in offsets.keys() {
+                polled.msgs.entry(key.clone()).or_default();
+            }
+        }
+        Ok(polled)
+    }
+
+    /// Drop what `retention` does not keep from every log, returning how many entries went.
+    /// Offsets are never reused, and a poll from a dropped offset starts at the oldest kept.
+    /// A disk-backed log's file is rewritten to what is left.
+    pub fn compact(&mut self, retention: Retention) -> io::Result<usize> {
+        let mut dropped = 0;
+        for (key, log) in &mut self.inner {
+            let truncated = log.truncate_before(retention.cutoff(&log.entries, log.slowest()));
+            if truncated > 0
+                && let Some(wal) = &mut self.wal
+            {
+                wal.rewrite(key, log)?;
+            }
+            dropped += truncated;
+        }
+        Ok(dropped)
     }
 
-    /// Handle `commit_offsets`
-    pub fn commit_offsets(&mut self, offsets: HashMap<String, u64>) {
+    /// Handle `commit_offsets` from `consumer`, which only ever moves its own offsets forward.
+    /// Unless the logs are lenient, an offset past the end of its log refuses the whole
+    /// commit; keys without a log are skipped.
+    pub fn commit_offsets(
+        &mut self,
+        consumer: &str,
+        offsets: HashMap<String, u64>,
+    ) -> Result<(), CommitError> {
+        if !self.lenient_commits {
+            for (key, &off) in &offsets {
+                if let Some(log) = self.inner.get(key) {
+                    log.check_commit(off).map_err(|past| CommitError::PastEnd {
+                        key: key.clone(),
+                        past,
+                    })?;
+                }
+            }
+        }
         for (key, off) in offsets {
-            if let Some(log) = self.inner.get_mut(&key) {
-                if off > log.committed {
-                    log.committed = off
-                };
+            if let Some(log) = self.inner.get_mut(&key)
+                && log
+                    .committed_by(consumer)
+                    .is_none_or(|committed| off > committed)
+            {
+                if let Some(wal) = &mut self.wal {
+                    wal.write(&key, &format!("c {off} {consumer}"))?;
+                }
+                log.record_commit(Some(consumer), off);
             }
         }
+        Ok(())
     }
 
-    /// Handle `list_committed_offsets`
+    /// Figures for every log, by key, for diagnosing a hot or lagging key
+    pub fn stats(&self) -> BTreeMap<String, KeyStats> {
+        self.inner
+            .iter()
+            .map(|(key, log)| {
+                let last_offset = log.entries.last().map(|(offset, _)| offset);
+                let stats = KeyStats {
+                    entries: log.entries.len(),
+                    bytes: log.entries.iter().map(|&(o, m)| entry_size(o, m)).sum(),
+                    last_offset,
+                    committed: log.committed,
+                    lag: last_offset.map_or(0, |last| last.saturating_sub(log.slowest())),
+                };
+                (key.clone(), stats)
+            })
+            .collect()
+    }
+
+    /// Handle `list_committed_offsets`: the highest offset any consumer committed, for the
+    /// keys that have a log
     pub fn list_committed_offsets(&self, keys: &[String]) -> HashMap<String, u64> {
         let mut result = HashMap::new();
         for key in keys {
-            let off = self.inner.get(key).map(|l| l.committed).unwrap_or(0);
-            result.insert(key.clone(), off);
+            if let Some(log) = self.inner.get(key) {
+                result.insert(key.clone(), log.committed);
+            }
+        }
+        result
+    }
+
+    /// Handle `list_committed_offsets` for one consumer: what it committed itself, 0 where it
+    /// has not, for the keys that have a log
+    pub fn committed_offsets_of(&self, consumer: &str, keys: &[String]) -> HashMap<String, u64> {
+        let mut result = HashMap::new();
+        for key in keys {
+            if let Some(log) = self.inner.get(key) {
+                result.insert(key.clone(), log.committed_by(consumer).unwrap_or(0));
+            }
         }
         result
     }
 }
 
 /// A single append-only log
+#[derive(Serialize, Deserialize)]
 pub struct Log {
     /// `entries` - for clients to "poll" from any arbitrary offset, even if messages weren't
-    /// written at every integer in between
-    entries: BTreeMap<u64, u64>,
+    /// written at every integer in between; polls share its segments rather than copy them
+    entries: Segments,
+    /// Entries inserted beyond a gap, waiting for it to be filled; `None` for tombstones
+    #[serde(default)]
+    staged: BTreeMap<u64, Option<u64>>,
+    /// Checksum of each entry, staged ones too, kept only for disk-backed logs
+    #[serde(skip)]
+    checksums: BTreeMap<u64, u32>,
+    /// One past the newest entry polls can see
     next_offset: u64,
+    /// Highest offset committed by anyone
     committed: u64,
+    /// Offset each consumer committed, by client id
+    #[serde(default)]
+    consumers: HashMap<String, u64>,
+    /// Polls see only entries below this; every entry until it is first set. Not written
+    /// ahead, so a reopened log shows everything it replayed.
+    #[serde(default)]
+    hwm: Option<u64>,
 }
 
 impl Default for Log {
@@ -91,43 +671,799 @@ impl Log {
     /// Create a new log starting at offset 0
     pub fn new() -> Self {
         Self {
-            entries: BTreeMap::new(),
+            entries: Segments::default(),
+            staged: BTreeMap::new(),
+            checksums: BTreeMap::new(),
             next_offset: 0,
             committed: 0,
+            consumers: HashMap::new(),
+            hwm: None,
         }
     }
 
     /// Append a message, returning its unique offset
     pub fn append(&mut self, msg: u64) -> u64 {
         let offset = self.next_offset;
-        self.entries.insert(offset, msg);
-        self.next_offset += 1;
+        self.place(offset, msg, true);
         offset
     }
 
-    /// Return all entries at or after `from_offset`, up to `max` items if specified
-    pub fn read_from(&self, from_offset: u64, max: Option<usize>) -> Vec<(u64, u64)> {
-        let mut out = Vec::new();
-        for (&off, &msg) in self.entries.range(from_offset..) {
-            out.push((off, msg));
-            if let Some(limit) = max {
-                if out.len() >= limit {
-                    break;
+    /// Put `msg` at `offset`: among the entries, or if `staging` and it is beyond a gap, with
+    /// the staged ones. Entries staged right after the new end follow it in.
+    fn place(&mut self, offset: u64, msg: u64, staging: bool) {
+        self.fill(offset, Some(msg), staging);
+    }
+
+    /// Like `place`, with `None` filling `offset` with a tombstone: it moves the end of the
+    /// log past `offset` but adds no entry, and never replaces one
+    fn fill(&mut self, offset: u64, msg: Option<u64>, staging: bool) {
+        if staging && offset > self.next_offset {
+            match msg {
+                Some(msg) => {
+                    self.staged.insert(offset, Some(msg));
                 }
+                None => {
+                    self.staged.entry(offset).or_insert(None);
+                }
+            }
+            return;
+        }
+        if let Some(msg) = msg {
+            self.entries.insert(offset, msg);
+        }
+        self.next_offset = self.next_offset.max(offset + 1);
+        while let Some(msg) = self.staged.remove(&self.next_offset) {
+            if let Some(msg) = msg {
+                self.entries.insert(self.next_offset, msg);
+            }
+            self.next_offset += 1;
+        }
+    }
+
+    /// Release every staged entry, gaps and all
+    fn unstage(&mut self) {
+        for (offset, msg) in std::mem::take(&mut self.staged) {
+            self.fill(offset, msg, false);
+        }
+    }
+
+    /// Ranges of offsets between the end of the entries and the staged ones
+    pub fn missing(&self) -> Vec<Range<u64>> {
+        let mut missing = Vec::new();
+        let mut from = self.next_offset;
+        for &offset in self.staged.keys() {
+            if offset > from {
+                missing.push(from..offset);
             }
+            from = offset + 1;
         }
-        out
+        missing
+    }
+
+    /// Return the entries at or after `from_offset`, up to `max` items if specified, and where
+    /// to read on from
+    pub fn read_from(&self, from_offset: u64, max: Option<usize>) -> Page {
+        Page::read(&self.entries, from_offset, max)
     }
 
-    /// Mark messages up through `offset` as committed
-    pub fn commit(&mut self, offset: u64) {
-        if offset > self.committed {
-            self.committed = offset;
+    /// Rebuild a log from its write-ahead records, returning it, the length of the records
+    /// replayed, and whether replay stopped at a corrupt record. A torn last line, from dying
+    /// mid-write, also ends the replay but is no corruption: the change it held was never
+    /// applied.
+    fn replay(mut records: impl BufRead) -> io::Result<(Self, u64, bool)> {
+        let mut log = Self::new();
+        let (mut line, mut read) = (String::new(), 0);
+        while records.read_line(&mut line)? > 0 {
+            let Some(record) = line.strip_suffix('\n') else {
+                return Ok((log, read, false));
+            };
+            let Some(record) = record
+                .rsplit_once(' ')
+                .filter(|(body, crc)| u32::from_str_radix(crc, 16) == Ok(crc32(body.as_bytes())))
+                .map(|(body, _)| body)
+            else {
+                return Ok((log, read, true));
+            };
+            let replayed = match record.splitn(3, ' ').collect::<Vec<_>>()[..] {
+                ["e", offset, msg] => {
+                    offset
+                        .parse()
+                        .ok()
+                        .zip(msg.parse().ok())
+                        .map(|(offset, msg)| {
+                            log.place(offset, msg, true);
+                            log.checksums.insert(offset, entry_crc(offset, msg));
+                        })
+                }
+                ["t", offset] => offset
+                    .parse()
+                    .ok()
+                    .map(|offset| log.fill(offset, None, true)),
+                // Replayed as recorded: a lenient log may have taken commits past its end
+                ["c", offset] => offset
+                    .parse()
+                    .ok()
+                    .map(|offset| log.record_commit(None, offset)),
+                ["c", offset, consumer] => offset
+                    .parse()
+                    .ok()
+                    .map(|offset| log.record_commit(Some(consumer), offset)),
+                ["n", offset] => offset
+                    .parse()
+                    .ok()
+                    .map(|offset| log.next_offset = log.next_offset.max(offset)),
+                _ => None,
+            };
+            if replayed.is_none() {
+                return Ok((log, read, true));
+            }
+            read += line.len() as u64;
+            line.clear();
         }
+        Ok((log, read, false))
+    }
+
+    /// Drop every entry below `offset`, returning how many there were; staged entries stay
+    pub fn truncate_before(&mut self, offset: u64) -> usize {
+        let offset = offset.min(self.next_offset);
+        self.checksums = self.checksums.split_off(&offset);
+        self.entries.truncate_before(offset)
+    }
+
+    /// Mark messages up through `offset` as committed, refusing an offset past the newest
+    pub fn commit(&mut self, offset: u64) -> Result<(), PastEnd> {
+        self.check_commit(offset)?;
+        self.record_commit(None, offset);
+        Ok(())
+    }
+
+    /// Move the high-watermark up to `offset`, e.g. once everything below it is replicated
+    /// to a quorum; appends may run ahead of it, and it never moves back
+    pub fn set_hwm(&mut self, offset: u64) {
+        self.hwm = Some(self.hwm.map_or(offset, |hwm| hwm.max(offset)));
+    }
+
+    /// Offset below which entries are visible to polls: the end of the log until
+    /// [`Log::set_hwm`] is first called
+    pub fn hwm(&self) -> u64 {
+        self.hwm.unwrap_or(self.next_offset)
     }
 
     /// Retrieve the highest committed offset
     pub fn committed_offset(&self) -> u64 {
         self.committed
     }
+
+    /// Mark messages up through `offset` as committed by `consumer`, refusing an offset past
+    /// the newest
+    pub fn commit_for(&mut self, consumer: &str, offset: u64) -> Result<(), PastEnd> {
+        self.check_commit(offset)?;
+        self.record_commit(Some(consumer), offset);
+        Ok(())
+    }
+
+    /// Whether `offset` was ever handed out, so committing it can mean something
+    fn check_commit(&self, offset: u64) -> Result<(), PastEnd> {
+        if offset < self.next_offset {
+            Ok(())
+        } else {
+            Err(PastEnd {
+                offset,
+                next_offset: self.next_offset,
+            })
+        }
+    }
+
+    /// Record a commit without checking it
+    fn record_commit(&mut self, consumer: Option<&str>, offset: u64) {
+        if let Some(consumer) = consumer {
+            let committed = self.consumers.entry(consumer.to_string()).or_default();
+            *committed = (*committed).max(offset);
+        }
+        self.committed = self.committed.max(offset);
+    }
+
+    /// The offset `consumer` committed, if it ever did
+    pub fn committed_by(&self, consumer: &str) -> Option<u64> {
+        self.consumers.get(consumer).copied()
+    }
+
+    /// Offset of the consumer furthest behind, or the committed offset when none is known
+    fn slowest(&self) -> u64 {
+        self.consumers
+            .values()
+            .copied()
+            .min()
+            .unwrap_or(self.committed)
+    }
+}
+
+/// Offsets handed out by a counter per key in `lin-kv` instead of each log's own next offset,
+/// so every node can append to every log without a leader deciding who goes where
+#[cfg(feature = "kv-service")]
+#[derive(Clone)]
+pub struct KvOffsets {
+    kv: LinKv,
+}
+
+#[cfg(feature = "kv-service")]
+impl KvOffsets {
+    pub fn new(kv: LinKv) -> Self {
+        Self { kv }
+    }
+
+    /// Claim the next offset of `key`'s log; no other claim, on any node, gets it
+    pub async fn next(&self, key: &str) -> Result<u64, MaelstromError> {
+        let claimed = self
+            .kv
+            .cas_loop(&format!("offset/{key}"), |next| next.unwrap_or(0) + 1)
+            .await?;
+        Ok(claimed - 1)
+    }
+
+    /// Append `msg` to `key`'s log in `logs`, shared with the message loop, at an offset
+    /// claimed from `lin-kv`. A claimed offset is never left empty: when the entry cannot be
+    /// written, a tombstone takes its place, and the error says where so peers can be told.
+    pub async fn append(
+        &self,
+        logs: &Mutex<Logs>,
+        key: &str,
+        msg: u64,
+    ) -> Result<u64, AppendError> {
+        let offset = self.next(key).await.map_err(AppendError::Unclaimed)?;
+        let mut logs = logs.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        if let Err(err) = logs.insert_at(key, offset, msg) {
+            let _ = logs.insert_tombstone(key, offset);
+            return Err(AppendError::Unwritten { offset, err });
+        }
+        Ok(offset)
+    }
+}
+
+/// Why [`KvOffsets::append`] appended nothing
+#[cfg(feature = "kv-service")]
+#[derive(Debug)]
+pub enum AppendError {
+    /// No offset could be claimed
+    Unclaimed(MaelstromError),
+    /// `offset` was claimed but writing the entry failed; a tombstone fills it instead
+    Unwritten { offset: u64, err: io::Error },
+}
+
+#[cfg(feature = "kv-service")]
+impl fmt::Display for AppendError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Unclaimed(err) => write!(f, "no offset claimed: {}", err.text),
+            Self::Unwritten { offset, err } => {
+                write!(f, "log write at offset {offset} failed: {err}")
+            }
+        }
+    }
+}
+
+#[cfg(feature = "kv-service")]
+impl std::error::Error for AppendError {}
+
+#[cfg(feature = "kv-service")]
+impl From<AppendError> for MaelstromError {
+    fn from(err: AppendError) -> Self {
+        match err {
+            AppendError::Unclaimed(err) => err,
+            // The offset holds a tombstone, so the entry will never be polled
+            unwritten => Self::temporarily_unavailable(unwritten.to_string()),
+        }
+    }
+}
+
+/// The files a disk-backed [`Logs`] writes ahead to: one per key, a change per line
+struct Wal {
+    dir: PathBuf,
+    files: HashMap<String, File>,
+}
+
+impl Wal {
+    fn path(&self, key: &str) -> PathBuf {
+        let name: String = key.bytes().map(|byte| format!("{byte:02x}")).collect();
+        self.dir.join(format!("{name}.wal"))
+    }
+
+    fn write(&mut self, key: &str, record: &str) -> io::Result<()> {
+        let file = match self.files.get_mut(key) {
+            Some(file) => file,
+            None => {
+                let file = OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(self.path(key))?;
+                self.files.entry(key.to_string()).or_insert(file)
+            }
+        };
+        file.write_all(checksummed(record).as_bytes())
+    }
+
+    /// Replace `key`'s file with just what `log` holds now, swapping it in whole so a crash
+    /// leaves either the old file or the new one
+    fn rewrite(&mut self, key: &str, log: &Log) -> io::Result<()> {
+        let path = self.path(key);
+        let staged = path.with_extension("wal.tmp");
+        let mut records = checksummed(&format!("n {}", log.next_offset));
+        records.push_str(&checksummed(&format!("c {}", log.committed)));
+        for (consumer, offset) in &log.consumers {
+            records.push_str(&checksummed(&format!("c {offset} {consumer}")));
+        }
+        let waiting = log.staged.iter().map(|(&offset, &msg)| (offset, msg));
+        let entries = log.entries.iter().map(|&(offset, msg)| (offset, Some(msg)));
+        for (offset, msg) in entries.chain(waiting) {
+            let record = match msg {
+                Some(msg) => format!("e {offset} {msg}"),
+                None => format!("t {offset}"),
+            };
+            records.push_str(&checksummed(&record));
+        }
+        fs::write(&staged, records)?;
+        fs::rename(&staged, &path)?;
+        // The open handle still points at the replaced file
+        self.files.remove(key);
+        Ok(())
+    }
+}
+
+/// `record` as a line of a WAL file, its checksum appended
+fn checksummed(record: &str) -> String {
+    format!("{record} {:08x}\n", crc32(record.as_bytes()))
+}
+
+/// What an entry's checksum is taken over: its offset and message
+fn entry_crc(offset: u64, msg: u64) -> u32 {
+    let mut bytes = [0; 16];
+    bytes[..8].copy_from_slice(&offset.to_le_bytes());
+    bytes[8..].copy_from_slice(&msg.to_le_bytes());
+    crc32(&bytes)
+}
+
+/// CRC-32 (IEEE), a bit at a time; records are a few dozen bytes
+fn crc32(bytes: &[u8]) -> u32 {
+    !bytes.iter().fold(!0u32, |crc, &byte| {
+        (0..8).fold(crc ^ byte as u32, |crc, _| {
+            (crc >> 1) ^ (0xedb88320 & (crc & 1).wrapping_neg())
+        })
+    })
+}
+
+/// The key a WAL file stem names, undoing [`Wal::path`]'s hex
+fn key_of(stem: &str) -> Option<String> {
+    let bytes = (0..stem.len())
+        .step_by(2)
+        .map(|at| u8::from_str_radix(stem.get(at..at + 2)?, 16).ok())
+        .collect::<Option<Vec<u8>>>()?;
+    String::from_utf8(bytes).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn logs(limits: PollLimits) -> Logs {
+        let mut logs = Logs::new();
+        logs.set_poll_limits(limits);
+        for msg in 0..10 {
+            logs.append_local("a", msg).unwrap();
+            logs.append_local("b", 100 + msg).unwrap();
+        }
+        logs
+    }
+
+    fn from_start() -> HashMap<String, u64> {
+        HashMap::from([("a".to_string(), 0), ("b".to_string(), 0)])
+    }
+
+    #[test]
+    fn test_polls_are_unbounded_by_default() {
+        let polled = logs(PollLimits::new()).poll(&from_start()).unwrap();
+        assert_eq!(polled.msgs["a"].len(), 10);
+        assert_eq!(polled.msgs["b"].len(), 10);
+    }
+
+    #[test]
+    fn test_unknown_keys_are_omitted_or_listed_empty() {
+        let mut logs = logs(PollLimits::new());
+        let offsets = HashMap::from([("a".to_string(), 9), ("nope".to_string(), 0)]);
+        let polled = logs.poll(&offsets).unwrap();
+        assert_eq!(polled.msgs.len(), 1);
+
+        logs.set_unknown_keys(UnknownKeys::Empty);
+        let polled = logs.poll(&offsets).unwrap();
+        assert_eq!(polled.msgs["a"], vec![(9, 9)]);
+        assert_eq!(polled.msgs["nope"], vec![]);
+        assert!(!polled.has_more());
+    }
+
+    #[test]
+    fn test_poll_limits_cap_each_key_and_the_whole_response() {
+        let polled = logs(PollLimits::new().per_key(3))
+            .poll(&from_start())
+            .unwrap();
+        assert_eq!(polled.msgs["a"], vec![(0, 0), (1, 1), (2, 2)]);
+        assert_eq!(polled.msgs["b"].len(), 3);
+        assert_eq!(polled.next_offsets["a"], 3);
+        assert!(polled.has_more());
+
+        // The response cap fills keys in order
+        let polled = logs(PollLimits::new().entries(12))
+            .poll(&from_start())
+            .unwrap();
+        assert_eq!(polled.msgs["a"].len(), 10);
+        assert_eq!(polled.msgs["b"], vec![(0, 100), (1, 101)]);
+        assert_eq!(polled.next_offsets, HashMap::from([("b".to_string(), 2)]));
+    }
+
+    #[test]
+    fn test_a_serialized_snapshot_keeps_entries_and_commits_but_not_settings() {
+        let mut logs = logs(PollLimits::new().per_key(1));
+        logs.commit_offsets("c1", HashMap::from([("a".to_string(), 4)]))
+            .unwrap();
+        let restored: Logs = serde_json::from_str(&serde_json::to_string(&logs).unwrap()).unwrap();
+        assert_eq!(restored.poll_limits(), PollLimits::default());
+        assert_eq!(restored.poll(&from_start()).unwrap().msgs["b"].len(), 10);
+
+        let keys = ["a".to_string(), "missing".to_string()];
+        assert_eq!(
+            restored.committed_offsets_of("c1", &keys),
+            HashMap::from([("a".to_string(), 4)])
+        );
+        assert_eq!(
+            restored.list_committed_offsets(&keys),
+            HashMap::from([("a".to_string(), 4)])
+        );
+    }
+
+    #[test]
+    fn test_stats_report_size_and_lag_per_key() {
+        let mut logs = logs(PollLimits::new());
+        logs.commit_offsets("c1", HashMap::from([("a".to_string(), 7)]))
+            .unwrap();
+        logs.commit_offsets("c2", HashMap::from([("a".to_string(), 3)]))
+            .unwrap();
+        logs.compact(Retention::new().keep_last(5)).unwrap();
+
+        let stats = logs.stats();
+        assert_eq!(
+            stats["a"],
+            KeyStats {
+                entries: 5,
+                bytes: 5 * entry_size(9, 9),
+                last_offset: Some(9),
+                committed: 7,
+                lag: 6,
+            }
+        );
+        assert_eq!(stats["b"].lag, 9);
+        assert_eq!(stats["b"].bytes, 5 * entry_size(9, 109));
+    }
+
+    #[test]
+    fn test_entries_beyond_a_gap_wait_for_it_to_fill() {
+        let mut logs = Logs::new();
+        let from = HashMap::from([("k".to_string(), 0)]);
+        for offset in [0, 3, 5, 4] {
+            logs.insert_at("k", offset, 10 + offset).unwrap();
+        }
+        assert_eq!(logs.poll(&from).unwrap().msgs["k"], vec![(0, 10)]);
+        assert_eq!(logs.missing("k"), vec![1..3]);
+        assert_eq!(logs.next_offset("k"), 6);
+        // Staged entries are not compacted away, nor counted as committable
+        assert_eq!(logs.compact(Retention::new().keep_last(0)).unwrap(), 1);
+        assert!(
+            logs.commit_offsets("c1", HashMap::from([("k".to_string(), 3)]))
+                .is_err()
+        );
+
+        logs.insert_at("k", 2, 12).unwrap();
+        assert_eq!(logs.missing("k"), vec![1..2]);
+        logs.insert_at("k", 1, 11).unwrap();
+        assert_eq!(logs.missing("k"), vec![]);
+        let polled = logs.poll(&from).unwrap();
+        assert_eq!(
+            polled.msgs["k"],
+            (1..6).map(|o| (o, 10 + o)).collect::<Vec<_>>()
+        );
+
+        // Without staging, gaps are left as they are
+        logs.insert_at("k", 8, 18).unwrap();
+        logs.set_staging(false);
+        assert_eq!(logs.missing("k"), vec![]);
+        assert_eq!(logs.poll(&from).unwrap().msgs["k"].last(), Some(&(8, 18)));
+    }
+
+    #[test]
+    fn test_polls_stop_at_the_high_watermark() {
+        let mut logs = logs(PollLimits::new());
+        assert_eq!(logs.hwm("a"), 10);
+        logs.set_hwm("a", 4);
+        // Appends run ahead of the watermark, and it never moves back
+        logs.append_local("a", 10).unwrap();
+        logs.set_hwm("a", 2);
+        assert_eq!(logs.hwm("a"), 4);
+        assert_eq!(logs.next_offset("a"), 11);
+
+        let polled = logs.poll(&from_start()).unwrap();
+        assert_eq!(polled.msgs["a"], vec![(0, 0), (1, 1), (2, 2), (3, 3)]);
+        assert_eq!(polled.msgs["b"].len(), 10);
+        assert!(!polled.has_more());
+        let from_past = HashMap::from([("a".to_string(), 6)]);
+        assert_eq!(logs.poll(&from_past).unwrap().msgs["a"], vec![]);
+    }
+
+    #[test]
+    fn test_paging_from_the_next_offset_reads_every_entry_once() {
+        let mut log = Log::new();
+        for msg in 0..5 {
+            log.append(msg);
+        }
+        let (mut from, mut read) = (0, Vec::new());
+        loop {
+            let page = log.read_from(from, Some(2));
+            read.extend(page.entries.iter().copied());
+            from = page.next;
+            if !page.has_more {
+                break;
+            }
+        }
+        assert_eq!(read, (0..5).map(|msg| (msg, msg)).collect::<Vec<_>>());
+        assert_eq!(from, 5);
+        assert_eq!(
+            log.read_from(9, None),
+            Page {
+                next: 9,
+                ..Page::default()
+            }
+        );
+
+        // A poll that gets everything names no offsets to go on from
+        let polled = logs(PollLimits::new()).poll(&from_start()).unwrap();
+        assert!(!polled.has_more());
+    }
+
+    #[test]
+    fn test_compaction_keeps_what_either_rule_keeps() {
+        let mut logs = logs(PollLimits::new());
+        logs.commit_offsets(
+            "c1",
+            HashMap::from([("a".to_string(), 8), ("b".to_string(), 9)]),
+        )
+        .unwrap();
+        assert_eq!(logs.compact(Retention::new()).unwrap(), 0);
+
+        // On `a` the window behind the commit keeps more, on `b` the newest four do
+        let retention = Retention::new().keep_last(4).committed_window(3);
+        assert_eq!(logs.compact(retention).unwrap(), 5 + 6);
+        let polled = logs.poll(&from_start()).unwrap();
+        assert_eq!(polled.msgs["a"].first(), Some(&(5, 5)));
+        assert_eq!(polled.msgs["b"].first(), Some(&(6, 106)));
+
+        assert_eq!(logs.compact(Retention::new().keep_last(0)).unwrap(), 5 + 4);
+        assert_eq!(logs.append_local("a", 11).unwrap(), 10);
+    }
+
+    fn wal_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("log-wal-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn test_a_reopened_log_has_every_acknowledged_change() {
+        let dir = wal_dir("reopen");
+        let mut logs = Logs::open(&dir).unwrap();
+        assert_eq!(logs.append_local("k/1", 10).unwrap(), 0);
+        assert_eq!(logs.append_local("k/1", 20).unwrap(), 1);
+        logs.insert_at("k2", 4, 40).unwrap();
+        logs.commit_offsets("c1", HashMap::from([("k/1".to_string(), 1)]))
+            .unwrap();
+        drop(logs);
+
+        let mut logs = Logs::open(&dir).unwrap();
+        let polled = logs
+            .poll(&HashMap::from([
+                ("k/1".to_string(), 0),
+                ("k2".to_string(), 0),
+            ]))
+            .unwrap();
+        assert_eq!(polled.msgs["k/1"], vec![(0, 10), (1, 20)]);
+        // Staged beyond its gap before the restart, and still after
+        assert_eq!(polled.msgs["k2"], vec![]);
+        assert_eq!(logs.missing("k2"), vec![0..4]);
+        assert_eq!(logs.list_committed_offsets(&["k/1".to_string()])["k/1"], 1);
+        assert_eq!(
+            logs.committed_offsets_of("c1", &["k/1".to_string()])["k/1"],
+            1
+        );
+
+        // Compaction rewrites the file without giving back dropped offsets
+        logs.compact(Retention::new().keep_last(0)).unwrap();
+        drop(logs);
+        let mut logs = Logs::open(&dir).unwrap();
+        assert_eq!(logs.append_local("k/1", 30).unwrap(), 2);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_tombstones_fill_gaps_without_adding_entries() {
+        let dir = wal_dir("tombstones");
+        let from = HashMap::from([("k".to_string(), 0)]);
+        let mut logs = Logs::open(&dir).unwrap();
+        logs.insert_at("k", 2, 12).unwrap();
+        logs.insert_tombstone("k", 1).unwrap();
+        assert_eq!(logs.missing("k"), vec![0..1]);
+        drop(logs);
+
+        // Still staged after a restart
+        let mut logs = Logs::open(&dir).unwrap();
+        assert_eq!(logs.missing("k"), vec![0..1]);
+        logs.insert_tombstone("k", 0).unwrap();
+        assert_eq!(logs.poll(&from).unwrap().msgs["k"], vec![(2, 12)]);
+        // An entry is never replaced by a tombstone for its offset
+        logs.insert_tombstone("k", 2).unwrap();
+        drop(logs);
+
+        let logs = Logs::open(&dir).unwrap();
+        assert_eq!(logs.poll(&from).unwrap().msgs["k"], vec![(2, 12)]);
+        assert_eq!(logs.next_offset("k"), 3);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_a_torn_record_is_dropped_on_replay() {
+        let dir = wal_dir("torn");
+        let mut logs = Logs::open(&dir).unwrap();
+        logs.append_local("a", 1).unwrap();
+        let path = logs.wal.as_ref().unwrap().path("a");
+        drop(logs);
+        OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap()
+            .write_all(b"e 1 2")
+            .unwrap();
+
+        let mut logs = Logs::open(&dir).unwrap();
+        assert_eq!(logs.append_local("a", 3).unwrap(), 1);
+        drop(logs);
+        let logs = Logs::open(&dir).unwrap();
+        let polled = logs.poll(&HashMap::from([("a".to_string(), 0)])).unwrap();
+        assert_eq!(polled.msgs["a"], vec![(0, 1), (1, 3)]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_crc32_matches_the_standard_check_value() {
+        assert_eq!(crc32(b"123456789"), 0xcbf43926);
+    }
+
+    #[test]
+    fn test_a_corrupt_record_drops_the_rest_of_its_file() {
+        let dir = wal_dir("corrupt");
+        let mut logs = Logs::open(&dir).unwrap();
+        for msg in 1..=3 {
+            logs.append_local("a", msg).unwrap();
+        }
+        let path = logs.wal.as_ref().unwrap().path("a");
+        drop(logs);
+        // A flipped bit in the second entry's message
+        let records = fs::read_to_string(&path).unwrap();
+        let at = records.find('\n').unwrap() + 1;
+        fs::write(&path, records.replacen("e 1 2", "e 1 3", 1)).unwrap();
+
+        let mut logs = Logs::open(&dir).unwrap();
+        assert_eq!(
+            logs.recovered_corruption(),
+            [Corruption::Record {
+                key: "a".to_string(),
+                at: at as u64
+            }]
+        );
+        let polled = logs.poll(&HashMap::from([("a".to_string(), 0)])).unwrap();
+        assert_eq!(polled.msgs["a"], vec![(0, 1)]);
+        assert_eq!(logs.append_local("a", 4).unwrap(), 1);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_an_entry_that_fails_its_checksum_is_not_served() {
+        let dir = wal_dir("entry");
+        let mut logs = Logs::open(&dir).unwrap();
+        logs.append_local("a", 1).unwrap();
+        logs.append_local("a", 2).unwrap();
+        logs.inner.get_mut("a").unwrap().entries.insert(1, 7);
+
+        let corruption = logs
+            .poll(&HashMap::from([("a".to_string(), 0)]))
+            .unwrap_err();
+        assert_eq!(
+            corruption,
+            Corruption::Entry {
+                key: "a".to_string(),
+                offset: 1
+            }
+        );
+        assert_eq!(
+            MaelstromError::from(corruption).code,
+            crate::ErrorCode::Crash
+        );
+        assert!(logs.poll(&HashMap::from([("a".to_string(), 2)])).is_ok());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_commits_past_the_newest_offset_are_refused_unless_lenient() {
+        let mut logs = logs(PollLimits::new());
+        let keys = ["a".to_string(), "b".to_string()];
+        let err = logs
+            .commit_offsets(
+                "c1",
+                HashMap::from([("a".to_string(), 9), ("b".to_string(), 10)]),
+            )
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "cannot commit to log b: offset 10 is past the newest offset, 9"
+        );
+        assert_eq!(
+            MaelstromError::from(err).code,
+            crate::ErrorCode::PreconditionFailed
+        );
+        // Nothing of a refused commit sticks
+        assert_eq!(logs.list_committed_offsets(&keys)["a"], 0);
+
+        logs.set_lenient_commits(true);
+        logs.commit_offsets("c1", HashMap::from([("b".to_string(), 50)]))
+            .unwrap();
+        assert_eq!(logs.committed_offsets_of("c1", &keys)["b"], 50);
+
+        let mut log = Log::new();
+        assert_eq!(
+            log.commit(0).unwrap_err().to_string(),
+            "offset 0 is past the end of an empty log"
+        );
+        log.append(7);
+        assert!(log.commit_for("c1", 0).is_ok());
+    }
+
+    #[test]
+    fn test_consumers_commit_without_clobbering_each_other() {
+        let mut logs = logs(PollLimits::new());
+        let keys = ["a".to_string()];
+        logs.commit_offsets("c1", HashMap::from([("a".to_string(), 6)]))
+            .unwrap();
+        logs.commit_offsets("c2", HashMap::from([("a".to_string(), 2)]))
+            .unwrap();
+        logs.commit_offsets("c1", HashMap::from([("a".to_string(), 4)]))
+            .unwrap();
+        assert_eq!(logs.committed_offsets_of("c1", &keys)["a"], 6);
+        assert_eq!(logs.committed_offsets_of("c2", &keys)["a"], 2);
+        assert_eq!(logs.committed_offsets_of("c3", &keys)["a"], 0);
+        assert_eq!(logs.list_committed_offsets(&keys)["a"], 6);
+
+        // Retention waits for the consumer furthest behind
+        logs.compact(Retention::new().committed_window(0)).unwrap();
+        assert_eq!(
+            logs.poll(&from_start()).unwrap().msgs["a"].first(),
+            Some(&(2, 2))
+        );
+    }
+
+    #[test]
+    fn test_the_byte_cap_still_returns_an_entry() {
+        let logs = logs(PollLimits::new().bytes(1));
+        let polled = logs.poll(&from_start()).unwrap();
+        assert_eq!(polled.msgs["a"], vec![(0, 0)]);
+        assert!(polled.msgs["b"].is_empty());
+
+        // `"a":[],` plus `[5,5],` and `[6,6],`
+        let mut logs = logs;
+        logs.set_poll_limits(PollLimits::new().bytes(7 + 6 * 2));
+        let polled = logs.poll(&HashMap::from([("a".to_string(), 5)])).unwrap();
+        assert_eq!(polled.msgs["a"], vec![(5, 5), (6, 6)]);
+    }
 }
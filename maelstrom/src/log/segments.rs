@@ -0,0 +1,362 @@
+//! Log storage as shared, immutable segments. A read takes clones of the segments it covers
+//! rather than copying their entries, so many consumers polling the same keys cost no
+//! allocation per entry; a write to a segment a read still holds copies that segment first.
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::ops::Range;
+use std::sync::Arc;
+
+/// Entries a segment takes before an append at the end of the log starts a new one
+pub(crate) const SEGMENT_LEN: usize = 1024;
+
+type Segment = Arc<Vec<(u64, u64)>>;
+
+/// A log's entries, by offset, in sorted segments that do not overlap
+#[derive(Clone, Default)]
+pub(crate) struct Segments {
+    /// Segments by their first offset; none is empty
+    segments: BTreeMap<u64, Segment>,
+    len: usize,
+}
+
+impl Segments {
+    pub(crate) fn len(&self) -> usize {
+        self.len
+    }
+
+    pub(crate) fn last(&self) -> Option<(u64, u64)> {
+        let (_, segment) = self.segments.last_key_value()?;
+        segment.last().copied()
+    }
+
+    pub(crate) fn iter(&self) -> impl DoubleEndedIterator<Item = &(u64, u64)> {
+        self.segments.values().flat_map(|segment| segment.iter())
+    }
+
+    /// Put `msg` at `offset`, replacing whatever was there
+    pub(crate) fn insert(&mut self, offset: u64, msg: u64) {
+        let Some((_, segment)) = self.segments.range_mut(..=offset).next_back() else {
+            self.segments.insert(offset, Arc::new(vec![(offset, msg)]));
+            self.len += 1;
+            return;
+        };
+        let at = segment.partition_point(|&(o, _)| o < offset);
+        if segment.get(at) == Some(&(offset, msg)) {
+            return;
+        }
+        if at == segment.len() && at >= SEGMENT_LEN {
+            self.segments.insert(offset, Arc::new(vec![(offset, msg)]));
+            self.len += 1;
+            return;
+        }
+        let entries = Arc::make_mut(segment);
+        match entries.get_mut(at) {
+            Some(entry) if entry.0 == offset => entry.1 = msg,
+            _ => {
+                entries.insert(at, (offset, msg));
+                self.len += 1;
+            }
+        }
+        // Out-of-order inserts can grow a segment in the middle of the log; halve it then
+        if entries.len() > 2 * SEGMENT_LEN {
+            let upper = entries.split_off(SEGMENT_LEN);
+            self.segments.insert(upper[0].0, Arc::new(upper));
+        }
+    }
+
+    /// Drop every entry below `offset`, returning how many there were
+    pub(crate) fn truncate_before(&mut self, offset: u64) -> usize {
+        let kept = self.segments.split_off(&offset);
+        let mut below = std::mem::replace(&mut self.segments, kept);
+        let mut dropped = 0;
+        // The last segment starting below `offset` may run past it
+        if let Some((_, straddling)) = below.pop_last() {
+            let at = straddling.partition_point(|&(o, _)| o < offset);
+            if at < straddling.len() {
+                let rest = straddling[at..].to_vec();
+                self.segments.insert(rest[0].0, Arc::new(rest));
+            }
+            dropped += at;
+        }
+        dropped += below.values().map(|segment| segment.len()).sum::<usize>();
+        self.len -= dropped;
+        dropped
+    }
+
+    /// The entries with offsets in `offsets`, sharing the segments they are in
+    pub(crate) fn range(&self, offsets: Range<u64>) -> Entries {
+        let first = self
+            .segments
+            .range(..=offsets.start)
+            .next_back()
+            .map_or(offsets.start, |(&first, _)| first);
+        let mut entries = Entries::default();
+        for segment in self
+            .segments
+            .range(first..offsets.end.max(first))
+            .map(|(_, s)| s)
+        {
+            let start = segment.partition_point(|&(o, _)| o < offsets.start);
+            let end = segment.partition_point(|&(o, _)| o < offsets.end);
+            if start < end {
+                entries.push(Run {
+                    segment: Arc::clone(segment),
+                    range: start..end,
+                });
+            }
+        }
+        entries
+    }
+}
+
+impl fmt::Debug for Segments {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_map()
+            .entries(self.iter().map(|(offset, msg)| (offset, msg)))
+            .finish()
+    }
+}
+
+impl FromIterator<(u64, u64)> for Segments {
+    fn from_iter<I: IntoIterator<Item = (u64, u64)>>(entries: I) -> Self {
+        let mut segments = Self::default();
+        for (offset, msg) in entries {
+            segments.insert(offset, msg);
+        }
+        segments
+    }
+}
+
+/// Written as the map of offset to message it used to be, so snapshots read either way
+impl Serialize for Segments {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_map(self.iter().map(|(offset, msg)| (offset, msg)))
+    }
+}
+
+impl<'de> Deserialize<'de> for Segments {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Ok(BTreeMap::<u64, u64>::deserialize(deserializer)?
+            .into_iter()
+            .collect())
+    }
+}
+
+#[derive(Clone)]
+struct Run {
+    segment: Segment,
+    range: Range<usize>,
+}
+
+impl Run {
+    fn as_slice(&self) -> &[(u64, u64)] {
+        &self.segment[self.range.clone()]
+    }
+}
+
+/// `(offset, msg)` entries read from a log, held as runs of the log's own segments, so
+/// cloning or cutting them copies no entries. On the wire they are a plain list.
+#[derive(Clone, Default)]
+pub struct Entries {
+    runs: Vec<Run>,
+    len: usize,
+}
+
+impl Entries {
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn iter(&self) -> Iter<'_> {
+        Iter {
+            runs: self.runs.iter(),
+            run: [].iter(),
+        }
+    }
+
+    pub fn first(&self) -> Option<&(u64, u64)> {
+        self.runs.first().and_then(|run| run.as_slice().first())
+    }
+
+    pub fn last(&self) -> Option<&(u64, u64)> {
+        self.runs.last().and_then(|run| run.as_slice().last())
+    }
+
+    pub fn to_vec(&self) -> Vec<(u64, u64)> {
+        self.iter().copied().collect()
+    }
+
+    /// The first `n` entries
+    pub fn take(mut self, n: usize) -> Self {
+        let mut left = n;
+        self.runs.retain_mut(|run| {
+            run.range.end = run.range.start + run.range.len().min(left);
+            left -= run.range.len();
+            !run.range.is_empty()
+        });
+        self.len = self.len.min(n);
+        self
+    }
+
+    fn push(&mut self, run: Run) {
+        self.len += run.range.len();
+        self.runs.push(run);
+    }
+}
+
+impl From<Vec<(u64, u64)>> for Entries {
+    fn from(entries: Vec<(u64, u64)>) -> Self {
+        let mut all = Self::default();
+        if !entries.is_empty() {
+            all.push(Run {
+                range: 0..entries.len(),
+                segment: Arc::new(entries),
+            });
+        }
+        all
+    }
+}
+
+impl FromIterator<(u64, u64)> for Entries {
+    fn from_iter<I: IntoIterator<Item = (u64, u64)>>(entries: I) -> Self {
+        Self::from(entries.into_iter().collect::<Vec<_>>())
+    }
+}
+
+/// Iterator over [`Entries`], in offset order
+pub struct Iter<'a> {
+    runs: std::slice::Iter<'a, Run>,
+    run: std::slice::Iter<'a, (u64, u64)>,
+}
+
+impl<'a> Iterator for Iter<'a> {
+    type Item = &'a (u64, u64);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(entry) = self.run.next() {
+                return Some(entry);
+            }
+            self.run = self.runs.next()?.as_slice().iter();
+        }
+    }
+}
+
+impl<'a> IntoIterator for &'a Entries {
+    type Item = &'a (u64, u64);
+    type IntoIter = Iter<'a>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+impl std::ops::Index<usize> for Entries {
+    type Output = (u64, u64);
+
+    fn index(&self, mut index: usize) -> &Self::Output {
+        for run in &self.runs {
+            match run.as_slice().get(index) {
+                Some(entry) => return entry,
+                None => index -= run.range.len(),
+            }
+        }
+        panic!("index out of bounds: the len is {}", self.len)
+    }
+}
+
+impl PartialEq for Entries {
+    fn eq(&self, other: &Self) -> bool {
+        self.len == other.len && self.iter().eq(other.iter())
+    }
+}
+
+impl Eq for Entries {}
+
+impl PartialEq<Vec<(u64, u64)>> for Entries {
+    fn eq(&self, other: &Vec<(u64, u64)>) -> bool {
+        self.len == other.len() && self.iter().eq(other.iter())
+    }
+}
+
+impl fmt::Debug for Entries {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list().entries(self.iter()).finish()
+    }
+}
+
+impl Serialize for Entries {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(self.iter())
+    }
+}
+
+impl<'de> Deserialize<'de> for Entries {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Vec::deserialize(deserializer).map(Self::from)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reads_share_segments_until_a_write_copies_one() {
+        let mut segments: Segments = (0..SEGMENT_LEN as u64 * 2 + 10).map(|o| (o, o)).collect();
+        assert_eq!(segments.segments.len(), 3);
+
+        let read = segments.range(SEGMENT_LEN as u64 - 2..SEGMENT_LEN as u64 + 3);
+        assert_eq!(read.runs.len(), 2);
+        assert_eq!(read, (1022..1027).map(|o| (o, o)).collect::<Vec<_>>());
+        let shared = Arc::clone(&read.runs[1].segment);
+        assert!(Arc::ptr_eq(
+            &shared,
+            &segments.segments[&(SEGMENT_LEN as u64)]
+        ));
+
+        // A write to a segment a read holds leaves the read as it was
+        segments.insert(1025, 7);
+        assert_eq!(read.last(), Some(&(1026, 1026)));
+        assert_eq!(read[3], (1025, 1025));
+        assert_eq!(segments.range(1025..1026), vec![(1025, 7)]);
+
+        assert_eq!(
+            read.clone().take(3),
+            vec![(1022, 1022), (1023, 1023), (1024, 1024)]
+        );
+        assert_eq!(read.take(0).len(), 0);
+    }
+
+    #[test]
+    fn test_truncating_and_out_of_order_inserts_keep_offsets_sorted() {
+        let mut segments: Segments = (0..3000).map(|o| (o * 4, o)).collect();
+        // Fill in the first segment's gaps, which grows it past where it splits
+        for o in (0..4000).filter(|o| o % 4 != 0) {
+            segments.insert(o, o);
+        }
+        let offsets: Vec<u64> = segments.iter().map(|&(o, _)| o).collect();
+        assert!(offsets.windows(2).all(|pair| pair[0] < pair[1]));
+        assert_eq!(segments.len(), 6000);
+        assert!(
+            segments
+                .segments
+                .values()
+                .all(|s| s.len() <= 2 * SEGMENT_LEN)
+        );
+
+        assert_eq!(segments.truncate_before(1500), 1500);
+        assert_eq!(segments.range(0..1502), vec![(1500, 375), (1501, 1501)]);
+        assert_eq!(segments.last(), Some((11996, 2999)));
+        assert_eq!(segments.len(), 4500);
+
+        let json = serde_json::to_string(&segments.range(1500..1502)).unwrap();
+        assert_eq!(json, "[[1500,375],[1501,1501]]");
+    }
+}
@@ -0,0 +1,48 @@
+//! Diagnostics on stderr. With the `tracing` feature they are JSON lines from a `tracing`
+//! subscriber, filtered by `NodeConfig::log_level` (`MAELSTROM_LOG=debug` or
+//! `--log-level debug`); without it, plain text.
+
+/// Log a framework warning through `tracing` when enabled, plain stderr otherwise
+macro_rules! log_warn {
+    ($($arg:tt)*) => {{
+        #[cfg(feature = "tracing")]
+        ::tracing::warn!($($arg)*);
+        #[cfg(not(feature = "tracing"))]
+        eprintln!($($arg)*);
+    }};
+}
+
+pub(crate) use log_warn;
+
+/// Install the JSON stderr subscriber filtered by `level` (`info` when unset or invalid);
+/// does nothing without the `tracing` feature or when a subscriber is already set
+pub fn init(level: Option<&str>) {
+    #[cfg(not(feature = "tracing"))]
+    let _ = level;
+    #[cfg(feature = "tracing")]
+    {
+        use tracing_subscriber::EnvFilter;
+
+        let filter = level
+            .and_then(|level| EnvFilter::try_new(level).ok())
+            .unwrap_or_else(|| EnvFilter::new("info"));
+        let _ = tracing_subscriber::fmt()
+            .json()
+            .with_current_span(true)
+            .with_span_list(false)
+            .with_env_filter(filter)
+            .with_writer(std::io::stderr)
+            .try_init();
+    }
+}
+
+/// Whether plain-text `node.log` lines pass `level`
+#[cfg(not(feature = "tracing"))]
+pub(crate) fn info_enabled(level: Option<&str>) -> bool {
+    !level.is_some_and(|level| {
+        matches!(
+            level.trim().to_ascii_lowercase().as_str(),
+            "off" | "error" | "warn"
+        )
+    })
+}
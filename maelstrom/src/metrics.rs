@@ -0,0 +1,150 @@
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+/// Distribution of non-negative samples in power-of-two buckets
+#[derive(Debug, Clone, Default)]
+pub struct Histogram {
+    pub count: u64,
+    pub sum: u64,
+    pub min: Option<u64>,
+    pub max: Option<u64>,
+    /// Sample count per bucket; bucket `i` holds values below `2^i` not counted earlier
+    buckets: Vec<u64>,
+}
+
+impl Histogram {
+    pub fn record(&mut self, value: u64) {
+        self.count += 1;
+        self.sum = self.sum.saturating_add(value);
+        self.min = Some(self.min.map_or(value, |min| min.min(value)));
+        self.max = Some(self.max.map_or(value, |max| max.max(value)));
+        let bucket = (u64::BITS - value.leading_zeros()) as usize;
+        if self.buckets.len() <= bucket {
+            self.buckets.resize(bucket + 1, 0);
+        }
+        self.buckets[bucket] += 1;
+    }
+
+    pub fn mean(&self) -> Option<f64> {
+        (self.count > 0).then(|| self.sum as f64 / self.count as f64)
+    }
+
+    /// Upper bound of the bucket holding the `q`-quantile (0.0 to 1.0) sample
+    pub fn quantile(&self, q: f64) -> Option<u64> {
+        if self.count == 0 {
+            return None;
+        }
+        let rank = ((q.clamp(0.0, 1.0) * self.count as f64).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (bucket, count) in self.buckets.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                return Some(1u64.checked_shl(bucket as u32).unwrap_or(u64::MAX));
+            }
+        }
+        self.max
+    }
+
+    fn to_json(&self) -> Value {
+        serde_json::json!({
+            "count": self.count,
+            "sum": self.sum,
+            "min": self.min,
+            "max": self.max,
+            "mean": self.mean(),
+            "p50": self.quantile(0.5),
+            "p99": self.quantile(0.99),
+        })
+    }
+}
+
+/// Counters and histograms the framework keeps for every node, reported by the `stats`
+/// admin message
+#[derive(Debug, Clone, Default)]
+pub struct Metrics {
+    /// Inbound messages by body type
+    pub received: BTreeMap<&'static str, u64>,
+    /// Outbound messages by body type
+    pub sent: BTreeMap<&'static str, u64>,
+    /// Time spent processing each inbound message, in microseconds
+    pub handle_latency_us: Histogram,
+    /// Messages waiting in the inbound channel when one is taken off it
+    pub queue_depth: Histogram,
+    /// Client requests refused because the inbound queue was too deep
+    pub shed: u64,
+    /// Outbound messages dropped because their peer's circuit was open
+    pub circuit_dropped: u64,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn record_received(&mut self, kind: &'static str) {
+        *self.received.entry(kind).or_default() += 1;
+    }
+
+    pub fn record_sent(&mut self, kind: &'static str) {
+        *self.sent.entry(kind).or_default() += 1;
+    }
+
+    pub fn record_handle_latency(&mut self, elapsed: Duration) {
+        self.handle_latency_us
+            .record(elapsed.as_micros().try_into().unwrap_or(u64::MAX));
+    }
+
+    pub fn record_queue_depth(&mut self, depth: usize) {
+        self.queue_depth.record(depth as u64);
+    }
+
+    /// Everything as one JSON object
+    pub fn to_json(&self) -> Value {
+        serde_json::json!({
+            "received": self.received,
+            "sent": self.sent,
+            "handle_latency_us": self.handle_latency_us.to_json(),
+            "queue_depth": self.queue_depth.to_json(),
+            "shed": self.shed,
+            "circuit_dropped": self.circuit_dropped,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_histogram_summarizes_samples() {
+        let mut histogram = Histogram::default();
+        assert_eq!(histogram.quantile(0.5), None);
+
+        for value in [0, 1, 3, 3, 100] {
+            histogram.record(value);
+        }
+        assert_eq!(histogram.count, 5);
+        assert_eq!(histogram.min, Some(0));
+        assert_eq!(histogram.max, Some(100));
+        assert_eq!(histogram.mean(), Some(21.4));
+        // The median sample (3) lives in the bucket below 4
+        assert_eq!(histogram.quantile(0.5), Some(4));
+        assert_eq!(histogram.quantile(1.0), Some(128));
+    }
+
+    #[test]
+    fn test_metrics_report_as_json() {
+        let mut metrics = Metrics::new();
+        metrics.record_received("broadcast");
+        metrics.record_received("broadcast");
+        metrics.record_sent("broadcast_ok");
+        metrics.record_handle_latency(Duration::from_micros(40));
+
+        let json = metrics.to_json();
+        assert_eq!(json["received"]["broadcast"], 2);
+        assert_eq!(json["sent"]["broadcast_ok"], 1);
+        assert_eq!(json["handle_latency_us"]["max"], 40);
+        assert_eq!(json["queue_depth"]["count"], 0);
+    }
+}
@@ -0,0 +1,348 @@
+//! Cross-cutting behavior layered around a handler. Each [`Middleware`] sees requests on
+//! their way in, in installation order, and everything the handler sends on its way out, in
+//! reverse order, so the first middleware installed is the outermost.
+
+use crate::{
+    Invalid, MaelstromError, Message,
+    admin::Introspect,
+    config::NodeConfig,
+    rpc::Call,
+    runtime::{MembershipChange, MessageHandler, Node, answerable, run_node_with_config},
+};
+use std::time::Duration;
+
+/// What a middleware decided about an inbound message
+pub enum Flow {
+    /// Pass the (possibly rewritten) message on toward the handler
+    Continue(Message),
+    /// Stop here and send these instead; the handler never sees the message
+    Respond(Vec<Message>),
+}
+
+pub trait Middleware {
+    /// Inspect, rewrite, or intercept a request before the handler gets it
+    fn inbound(&mut self, node: &mut Node, message: Message) -> Flow {
+        let _ = node;
+        Flow::Continue(message)
+    }
+
+    /// Inspect or rewrite what the handler produced, from any of its hooks
+    fn outbound(&mut self, node: &mut Node, messages: Vec<Message>) -> Vec<Message> {
+        let _ = node;
+        messages
+    }
+}
+
+/// Logs every request and every message the handler sends to stderr
+#[derive(Debug, Default)]
+pub struct Logger;
+
+impl Middleware for Logger {
+    fn inbound(&mut self, node: &mut Node, message: Message) -> Flow {
+        node.log(&format!(
+            "<- {} from {}",
+            message.body.type_name(),
+            message.src
+        ));
+        Flow::Continue(message)
+    }
+
+    fn outbound(&mut self, node: &mut Node, messages: Vec<Message>) -> Vec<Message> {
+        for message in &messages {
+            node.log(&format!(
+                "-> {} to {}",
+                message.body.type_name(),
+                message.dest
+            ));
+        }
+        messages
+    }
+}
+
+/// A handler wrapped in a middleware chain; every other hook is passed straight through
+pub struct Layered<H> {
+    handler: H,
+    middlewares: Vec<Box<dyn Middleware>>,
+}
+
+impl<H: MessageHandler> Layered<H> {
+    pub fn new(handler: H, middlewares: Vec<Box<dyn Middleware>>) -> Self {
+        Self {
+            handler,
+            middlewares,
+        }
+    }
+
+    /// The wrapped handler
+    pub fn inner(&self) -> &H {
+        &self.handler
+    }
+
+    fn outbound(&mut self, node: &mut Node, mut messages: Vec<Message>) -> Vec<Message> {
+        for middleware in self.middlewares.iter_mut().rev() {
+            messages = middleware.outbound(node, messages);
+        }
+        messages
+    }
+}
+
+impl<H: MessageHandler> MessageHandler for Layered<H> {
+    fn handle(&mut self, node: &mut Node, message: Message) -> Vec<Message> {
+        let (src, in_reply_to) = (message.src.clone(), answerable(&message));
+        let result = self.try_handle(node, message);
+        node.respond(src, in_reply_to, result)
+    }
+
+    fn try_handle(
+        &mut self,
+        node: &mut Node,
+        mut message: Message,
+    ) -> Result<Vec<Message>, MaelstromError> {
+        for (i, middleware) in self.middlewares.iter_mut().enumerate() {
+            match middleware.inbound(node, message) {
+                Flow::Continue(next) => message = next,
+                // Only the middlewares it already passed see the early response
+                Flow::Respond(mut out) => {
+                    for middleware in self.middlewares[..i].iter_mut().rev() {
+                        out = middleware.outbound(node, out);
+                    }
+                    return Ok(out);
+                }
+            }
+        }
+        // A failure is answered here, so the middlewares see its error reply on the way out
+        let (src, in_reply_to) = (message.src.clone(), answerable(&message));
+        let result = self.handler.try_handle(node, message);
+        let out = node.respond(src, in_reply_to, result);
+        Ok(self.outbound(node, out))
+    }
+
+    fn validate(&self, node: &Node, message: &Message) -> Result<(), Invalid> {
+        self.handler.validate(node, message)
+    }
+
+    fn on_tick(&mut self, node: &mut Node) -> Vec<Message> {
+        let out = self.handler.on_tick(node);
+        self.outbound(node, out)
+    }
+
+    fn tick_interval(&self) -> Option<Duration> {
+        self.handler.tick_interval()
+    }
+
+    fn prioritize_clients(&self) -> bool {
+        self.handler.prioritize_clients()
+    }
+
+    fn on_rpc_reply(&mut self, node: &mut Node, call: Call, reply: Message) -> Vec<Message> {
+        let out = self.handler.on_rpc_reply(node, call, reply);
+        self.outbound(node, out)
+    }
+
+    fn on_rpc_timeout(&mut self, node: &mut Node, call: Call) -> Vec<Message> {
+        let out = self.handler.on_rpc_timeout(node, call);
+        self.outbound(node, out)
+    }
+
+    fn on_node_init(&mut self, node: &Node) {
+        self.handler.on_node_init(node);
+    }
+
+    fn on_message(&mut self, node: &Node, message: &Message) {
+        self.handler.on_message(node, message);
+    }
+
+    fn on_send(&mut self, node: &Node, message: &Message) {
+        self.handler.on_send(node, message);
+    }
+
+    fn on_membership_change(&mut self, node: &mut Node, change: &MembershipChange) -> Vec<Message> {
+        let out = self.handler.on_membership_change(node, change);
+        self.outbound(node, out)
+    }
+
+    fn on_circuit_open(&mut self, node: &mut Node, peer: &str) -> Vec<Message> {
+        let out = self.handler.on_circuit_open(node, peer);
+        self.outbound(node, out)
+    }
+
+    fn introspect(&self) -> Option<&dyn Introspect> {
+        self.handler.introspect()
+    }
+
+    fn stats(&self, node: &Node) -> Option<serde_json::Value> {
+        self.handler.stats(node)
+    }
+
+    fn on_config_change(&mut self, node: &mut Node) -> Vec<Message> {
+        let out = self.handler.on_config_change(node);
+        self.outbound(node, out)
+    }
+}
+
+/// Like [`run_node`](crate::run_node), with `middlewares` wrapped around `handler`
+pub async fn run_node_with<H: MessageHandler>(handler: H, middlewares: Vec<Box<dyn Middleware>>) {
+    run_node_with_config(Layered::new(handler, middlewares), NodeConfig::load()).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{MessageBody, testing};
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    /// Echoes every request back to its sender
+    struct Echo;
+
+    impl MessageHandler for Echo {
+        fn handle(&mut self, node: &mut Node, message: Message) -> Vec<Message> {
+            vec![node.reply(message.src, message.body)]
+        }
+    }
+
+    /// Records the order it sees traffic in
+    struct Tag {
+        name: &'static str,
+        seen: Rc<RefCell<Vec<String>>>,
+    }
+
+    impl Middleware for Tag {
+        fn inbound(&mut self, _node: &mut Node, message: Message) -> Flow {
+            self.seen.borrow_mut().push(format!("in {}", self.name));
+            Flow::Continue(message)
+        }
+
+        fn outbound(&mut self, _node: &mut Node, messages: Vec<Message>) -> Vec<Message> {
+            self.seen.borrow_mut().push(format!("out {}", self.name));
+            messages
+        }
+    }
+
+    /// Answers reads itself
+    struct ReadCache;
+
+    impl Middleware for ReadCache {
+        fn inbound(&mut self, node: &mut Node, message: Message) -> Flow {
+            let MessageBody::Read { msg_id, .. } = message.body else {
+                return Flow::Continue(message);
+            };
+            Flow::Respond(vec![node.reply(
+                message.src,
+                MessageBody::ReadOk {
+                    msg_id: 0,
+                    in_reply_to: msg_id,
+                    messages: None,
+                    value: Some(0),
+                    context: None,
+                },
+            )])
+        }
+    }
+
+    /// Fails every request through `try_handle`; `handle` alone would swallow the failure
+    struct Refusing;
+
+    impl MessageHandler for Refusing {
+        fn handle(&mut self, node: &mut Node, message: Message) -> Vec<Message> {
+            self.try_handle(node, message).unwrap_or_default()
+        }
+
+        fn try_handle(
+            &mut self,
+            _node: &mut Node,
+            _message: Message,
+        ) -> Result<Vec<Message>, MaelstromError> {
+            Err(MaelstromError::not_supported("refused"))
+        }
+    }
+
+    fn tag(name: &'static str, seen: &Rc<RefCell<Vec<String>>>) -> Box<dyn Middleware> {
+        Box::new(Tag {
+            name,
+            seen: Rc::clone(seen),
+        })
+    }
+
+    #[test]
+    fn test_inner_failures_reach_the_client_through_the_chain() {
+        let seen = Rc::new(RefCell::new(Vec::new()));
+        let mut layered = Layered::new(Refusing, vec![tag("a", &seen)]);
+        let mut node = Node::new();
+        node.handle_init("n1".to_string(), vec!["n1".to_string()]);
+
+        let out = crate::process(&mut layered, &mut node, testing::echo("hi"));
+        assert_eq!(out.len(), 1);
+        let (in_reply_to, code) = match &out[0].body {
+            MessageBody::Error {
+                in_reply_to, code, ..
+            } => (*in_reply_to, *code),
+            body => panic!("expected an error, got {body:?}"),
+        };
+        assert_eq!(out[0].dest, "c1");
+        assert_eq!((in_reply_to, code), (1, crate::ErrorCode::NotSupported));
+        assert_eq!(*seen.borrow(), vec!["in a", "out a"]);
+    }
+
+    #[test]
+    fn test_chain_runs_in_then_out_in_reverse() {
+        let seen = Rc::new(RefCell::new(Vec::new()));
+        let mut layered = Layered::new(Echo, vec![tag("a", &seen), tag("b", &seen)]);
+        let mut node = Node::new();
+
+        let out = layered.handle(&mut node, testing::echo("hi"));
+        assert_eq!(out.len(), 1);
+        assert_eq!(*seen.borrow(), vec!["in a", "in b", "out b", "out a"]);
+    }
+
+    #[test]
+    fn test_middleware_can_answer_instead_of_the_handler() {
+        let seen = Rc::new(RefCell::new(Vec::new()));
+        let mut layered = Layered::new(
+            Echo,
+            vec![
+                tag("outer", &seen),
+                Box::new(ReadCache),
+                tag("inner", &seen),
+            ],
+        );
+        let mut node = Node::new();
+
+        let out = layered.handle(&mut node, testing::read());
+        assert!(matches!(out[0].body, MessageBody::ReadOk { .. }));
+        // The inner middleware never saw it
+        assert_eq!(*seen.borrow(), vec!["in outer", "out outer"]);
+    }
+
+    #[test]
+    fn test_other_hooks_pass_through() {
+        struct Ticking;
+
+        impl MessageHandler for Ticking {
+            fn handle(&mut self, _node: &mut Node, _message: Message) -> Vec<Message> {
+                Vec::new()
+            }
+
+            fn tick_interval(&self) -> Option<Duration> {
+                Some(Duration::from_millis(5))
+            }
+
+            fn on_tick(&mut self, node: &mut Node) -> Vec<Message> {
+                vec![node.reply(
+                    "n2".to_string(),
+                    MessageBody::Read {
+                        msg_id: 0,
+                        key: None,
+                        context: None,
+                    },
+                )]
+            }
+        }
+
+        let seen = Rc::new(RefCell::new(Vec::new()));
+        let mut layered = Layered::new(Ticking, vec![tag("a", &seen), Box::new(Logger)]);
+        assert_eq!(layered.tick_interval(), Some(Duration::from_millis(5)));
+        assert_eq!(layered.on_tick(&mut Node::new()).len(), 1);
+        assert_eq!(*seen.borrow(), vec!["out a"]);
+    }
+}
@@ -0,0 +1,158 @@
+use crate::Message;
+use std::collections::{BTreeMap, HashMap};
+use std::time::{Duration, Instant};
+
+/// Most unacknowledged messages kept per peer; the oldest are dropped beyond this so a dead
+/// peer cannot grow the buffer without bound
+pub const PER_PEER_LIMIT: usize = 1024;
+
+struct Unacked {
+    message: Message,
+    due: Instant,
+}
+
+/// Internal messages sent with [`Node::deliver`](crate::Node::deliver), kept per peer until
+/// that peer acknowledges them and retransmitted every `interval` until it does
+pub struct Outbox {
+    interval: Duration,
+    peers: HashMap<String, BTreeMap<u64, Unacked>>,
+}
+
+impl Outbox {
+    pub fn new(interval: Duration) -> Self {
+        Self {
+            interval,
+            peers: HashMap::new(),
+        }
+    }
+
+    /// Keep a message sent at `now` until it is acknowledged
+    pub fn insert(&mut self, message: Message, now: Instant) {
+        let msg_id = message
+            .body
+            .msg_id()
+            .expect("delivered messages carry a msg_id");
+        let pending = self.peers.entry(message.dest.clone()).or_default();
+        pending.insert(
+            msg_id,
+            Unacked {
+                message,
+                due: now + self.interval,
+            },
+        );
+        while pending.len() > PER_PEER_LIMIT {
+            pending.pop_first();
+        }
+    }
+
+    /// Whether this message is waiting for an acknowledgement
+    pub fn holds(&self, message: &Message) -> bool {
+        let Some(msg_id) = message.body.msg_id() else {
+            return false;
+        };
+        self.peers
+            .get(&message.dest)
+            .is_some_and(|pending| pending.contains_key(&msg_id))
+    }
+
+    /// Forget a message `peer` has acknowledged; false if it was not pending
+    pub fn ack(&mut self, peer: &str, msg_id: u64) -> bool {
+        self.peers
+            .get_mut(peer)
+            .is_some_and(|pending| pending.remove(&msg_id).is_some())
+    }
+
+    /// Stop retransmitting to `peer`; returns how many messages were abandoned
+    pub fn forget(&mut self, peer: &str) -> usize {
+        self.peers.remove(peer).map_or(0, |pending| pending.len())
+    }
+
+    /// Earliest moment a message is due for retransmission
+    pub fn next_due(&self) -> Option<Instant> {
+        self.peers
+            .values()
+            .flat_map(|pending| pending.values())
+            .map(|unacked| unacked.due)
+            .min()
+    }
+
+    /// Messages due for retransmission at `now`, rescheduled for another interval
+    pub fn poll(&mut self, now: Instant) -> Vec<Message> {
+        let mut resends = Vec::new();
+        for unacked in self
+            .peers
+            .values_mut()
+            .flat_map(|pending| pending.values_mut())
+        {
+            if unacked.due <= now {
+                unacked.due = now + self.interval;
+                resends.push(unacked.message.clone());
+            }
+        }
+        resends
+    }
+
+    /// Unacknowledged messages for `peer`
+    pub fn pending(&self, peer: &str) -> usize {
+        self.peers.get(peer).map_or(0, BTreeMap::len)
+    }
+
+    pub fn len(&self) -> usize {
+        self.peers.values().map(BTreeMap::len).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MessageBody;
+
+    fn gossip(dest: &str, msg_id: u64) -> Message {
+        Message {
+            src: "n1".to_string(),
+            dest: dest.to_string(),
+            body: MessageBody::BroadcastGossip {
+                msg_id: Some(msg_id),
+                messages: vec![msg_id],
+            },
+        }
+    }
+
+    #[test]
+    fn test_unacked_messages_are_retransmitted_until_acked() {
+        let start = Instant::now();
+        let interval = Duration::from_millis(100);
+        let mut outbox = Outbox::new(interval);
+        outbox.insert(gossip("n2", 1), start);
+        outbox.insert(gossip("n3", 2), start);
+        assert!(outbox.holds(&gossip("n2", 1)));
+        assert!(!outbox.holds(&gossip("n3", 1)));
+        assert_eq!(outbox.next_due(), Some(start + interval));
+
+        assert!(outbox.poll(start).is_empty());
+        assert_eq!(outbox.poll(start + interval).len(), 2);
+
+        assert!(outbox.ack("n2", 1));
+        assert!(!outbox.ack("n2", 1));
+        let resends = outbox.poll(start + interval * 2);
+        assert_eq!(resends.len(), 1);
+        assert_eq!(resends[0].dest, "n3");
+        assert_eq!(outbox.len(), 1);
+    }
+
+    #[test]
+    fn test_oldest_messages_are_dropped_past_the_limit() {
+        let mut outbox = Outbox::new(Duration::from_millis(100));
+        let now = Instant::now();
+        for msg_id in 1..=(PER_PEER_LIMIT as u64 + 1) {
+            outbox.insert(gossip("n2", msg_id), now);
+        }
+        assert_eq!(outbox.pending("n2"), PER_PEER_LIMIT);
+        assert!(!outbox.holds(&gossip("n2", 1)));
+        assert!(outbox.holds(&gossip("n2", 2)));
+    }
+}
@@ -0,0 +1,31 @@
+//! Handler state that survives a crash. A [`Persistent`] handler can write its state out and
+//! read it back, which is all [`Sim::crash`](crate::sim::Sim::crash) needs to restart a node
+//! from its last [`Sim::checkpoint`](crate::sim::Sim::checkpoint) and show what a real crash
+//! would lose.
+//!
+//! The same snapshots bootstrap nodes over the network: a node that comes up empty sends
+//! `state_sync` to its peers, which answer `state_sync_ok` with their snapshot [`encode`]d.
+
+use base64::{Engine as _, engine::general_purpose::STANDARD};
+use std::error::Error;
+
+/// Why a snapshot could not be restored
+pub type RestoreError = Box<dyn Error + Send + Sync>;
+
+pub trait Persistent {
+    /// Everything the handler would need to come back after a crash
+    fn snapshot(&self) -> Vec<u8>;
+
+    /// Replace all state with that of `snapshot`, as written by `snapshot`
+    fn restore(&mut self, snapshot: &[u8]) -> Result<(), RestoreError>;
+}
+
+/// A snapshot as text, for the `state` of a `state_sync_ok` body
+pub fn encode(snapshot: &[u8]) -> String {
+    STANDARD.encode(snapshot)
+}
+
+/// The snapshot carried by a `state_sync_ok` body
+pub fn decode(state: &str) -> Result<Vec<u8>, RestoreError> {
+    Ok(STANDARD.decode(state)?)
+}
@@ -0,0 +1,7 @@
+//! Everything a workload binary usually needs, for a single glob import:
+//! `use maelstrom::prelude::*;`
+
+pub use crate::{
+    ErrorCode, Invalid, MaelstromError, Message, MessageBody, MessageBodyDispatch, MessageHandler,
+    Middleware, Node, NodeConfig, run_node, run_node_with, run_node_with_config,
+};
@@ -0,0 +1,125 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+/// A single replicated log entry as carried by `AppendEntries`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Entry {
+    pub term: u64,
+    pub index: u64,
+    /// Opaque state-machine command
+    pub command: Value,
+}
+
+/// Candidate asking a peer for its vote in `term`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RequestVote {
+    pub msg_id: u64,
+    pub term: u64,
+    pub candidate_id: String,
+    pub last_log_index: u64,
+    pub last_log_term: u64,
+}
+
+/// Vote response; `term` lets a stale candidate step down
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RequestVoteOk {
+    pub msg_id: u64,
+    pub in_reply_to: u64,
+    pub term: u64,
+    pub vote_granted: bool,
+}
+
+/// Leader replicating entries (or heartbeating when `entries` is empty)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AppendEntries {
+    pub msg_id: u64,
+    pub term: u64,
+    pub leader_id: String,
+    pub prev_log_index: u64,
+    pub prev_log_term: u64,
+    pub entries: Vec<Entry>,
+    pub leader_commit: u64,
+}
+
+/// Follower response; `match_index` is the highest index known to match the leader
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AppendEntriesOk {
+    pub msg_id: u64,
+    pub in_reply_to: u64,
+    pub term: u64,
+    pub success: bool,
+    pub match_index: u64,
+}
+
+/// Leader shipping a snapshot chunk to a follower that has fallen behind the log
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct InstallSnapshot {
+    pub msg_id: u64,
+    pub term: u64,
+    pub leader_id: String,
+    pub last_included_index: u64,
+    pub last_included_term: u64,
+    /// Byte offset of this chunk within the snapshot
+    pub offset: u64,
+    pub data: Value,
+    pub done: bool,
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Message, MessageBody};
+    use serde_json::json;
+
+    #[test]
+    fn test_append_entries_wire_format() {
+        let line = json!({
+            "src": "n1",
+            "dest": "n2",
+            "body": {
+                "type": "append_entries",
+                "msg_id": 4,
+                "term": 2,
+                "leader_id": "n1",
+                "prev_log_index": 1,
+                "prev_log_term": 1,
+                "entries": [{"term": 2, "index": 2, "command": {"op": "set"}}],
+                "leader_commit": 1
+            }
+        });
+
+        let message: Message = serde_json::from_value(line.clone()).unwrap();
+        match &message.body {
+            MessageBody::AppendEntries(append) => {
+                assert_eq!(append.term, 2);
+                assert_eq!(append.entries.len(), 1);
+                assert_eq!(append.entries[0].index, 2);
+            }
+            _ => panic!("Expected AppendEntries message"),
+        }
+        assert_eq!(serde_json::to_value(&message).unwrap(), line);
+    }
+
+    #[test]
+    fn test_request_vote_ok_wire_format() {
+        let line = json!({
+            "src": "n2",
+            "dest": "n1",
+            "body": {
+                "type": "request_vote_ok",
+                "msg_id": 1,
+                "in_reply_to": 9,
+                "term": 3,
+                "vote_granted": true
+            }
+        });
+
+        let message: Message = serde_json::from_value(line).unwrap();
+        match message.body {
+            MessageBody::RequestVoteOk(vote) => {
+                assert_eq!(vote.in_reply_to, 9);
+                assert!(vote.vote_granted);
+            }
+            _ => panic!("Expected RequestVoteOk message"),
+        }
+    }
+}
@@ -0,0 +1,208 @@
+//! Golden-trace replay: feed a captured stdin log, one message per line, through a handler
+//! and compare what it writes with a recorded golden file, so handlers can be refactored
+//! without their behavior drifting.
+//!
+//! Only the captured messages drive the handler: ticks and timers never fire, the clock
+//! stands still, and the seed defaults to 0, so a replay repeats exactly.
+//!
+//! The input may also be a [`crate::tee`] capture, whose inbound lines are replayed and whose
+//! outbound lines ([`captured_output`]) can serve as the golden file. A capture from a live
+//! run only matches where the node's output followed from its input alone: anything sent on
+//! a tick, a timer, or a random choice will differ.
+
+use crate::{
+    MessageHandler, Node, NodeConfig,
+    runtime::{process, sending},
+    tee::{self, Direction},
+    trace, writer,
+};
+use serde_json::Value;
+use std::fmt;
+use std::io::{self, BufRead};
+use std::time::Instant;
+
+/// Run every message in `input` through `handler` and return the lines it wrote, in order
+pub fn replay<H: MessageHandler>(
+    handler: &mut H,
+    mut config: NodeConfig,
+    input: impl BufRead,
+) -> io::Result<Vec<String>> {
+    config.seed.get_or_insert(0);
+    let mut node = Node::with_config(config);
+    node.set_clock(Instant::now());
+    let (writer, mut frames) = writer::channel();
+    node.attach_writer(writer);
+
+    let mut written = Vec::new();
+    for (i, line) in input.lines().enumerate() {
+        let mut line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        match tee::parse(&line) {
+            Some((Direction::In, captured)) => line = captured,
+            Some((Direction::Out, _)) => continue,
+            None => {}
+        }
+        let (message, envelope) = trace::decode(line.as_bytes()).map_err(|e| {
+            io::Error::new(io::ErrorKind::InvalidData, format!("line {}: {e}", i + 1))
+        })?;
+        node.begin(&message.src, envelope);
+        for out in process(handler, &mut node, message) {
+            if sending(handler, &mut node, &out) {
+                node.write(&out);
+            }
+        }
+        while let Ok(frame) = frames.try_recv() {
+            written.push(String::from_utf8_lossy(&frame).trim_end().to_string());
+        }
+    }
+    Ok(written)
+}
+
+/// The lines a node wrote, in order, from a [`crate::tee`] capture
+pub fn captured_output(capture: impl BufRead) -> io::Result<Vec<String>> {
+    let mut output = Vec::new();
+    for line in capture.lines() {
+        if let Some((Direction::Out, line)) = tee::parse(&line?) {
+            output.push(line);
+        }
+    }
+    Ok(output)
+}
+
+/// A line where a replay and its golden file disagree; `None` where one of them ran out
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Mismatch {
+    /// 1-based line number
+    pub line: usize,
+    pub expected: Option<String>,
+    pub actual: Option<String>,
+}
+
+impl fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let show = |line: &Option<String>| line.clone().unwrap_or_else(|| "<nothing>".into());
+        write!(
+            f,
+            "line {}:\n  expected {}\n  actual   {}",
+            self.line,
+            show(&self.expected),
+            show(&self.actual)
+        )
+    }
+}
+
+/// Compare replayed lines with golden ones. Lines that parse as JSON are compared as values,
+/// so field order and spacing do not matter.
+pub fn diff(actual: &[String], golden: &[String]) -> Vec<Mismatch> {
+    let same = |a: &str, b: &str| match (
+        serde_json::from_str::<Value>(a),
+        serde_json::from_str::<Value>(b),
+    ) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => a == b,
+    };
+    (0..actual.len().max(golden.len()))
+        .filter_map(|i| {
+            let (actual, expected) = (actual.get(i), golden.get(i));
+            match (actual, expected) {
+                (Some(a), Some(b)) if same(a, b) => None,
+                _ => Some(Mismatch {
+                    line: i + 1,
+                    expected: expected.cloned(),
+                    actual: actual.cloned(),
+                }),
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Message, MessageBody};
+
+    struct Echo;
+
+    impl MessageHandler for Echo {
+        fn handle(&mut self, node: &mut Node, message: Message) -> Vec<Message> {
+            match message.body {
+                MessageBody::Init {
+                    msg_id,
+                    node_id,
+                    node_ids,
+                } => {
+                    node.handle_init(node_id, node_ids);
+                    vec![node.init_ok(message.src, msg_id)]
+                }
+                MessageBody::Echo { msg_id, echo } => {
+                    vec![node.answer(message.src, msg_id, |msg_id, in_reply_to| {
+                        MessageBody::EchoOk {
+                            msg_id,
+                            in_reply_to,
+                            echo,
+                        }
+                    })]
+                }
+                _ => Vec::new(),
+            }
+        }
+    }
+
+    const INPUT: &str = r#"
+{"src":"c0","dest":"n1","body":{"type":"init","msg_id":1,"node_id":"n1","node_ids":["n1"]}}
+{"src":"c1","dest":"n1","body":{"type":"echo","msg_id":7,"echo":"hi"}}
+"#;
+
+    #[test]
+    fn test_replay_writes_what_the_node_would() {
+        let lines = replay(&mut Echo, NodeConfig::new(), INPUT.as_bytes()).unwrap();
+        assert_eq!(lines.len(), 2);
+        let echo_ok: Value = serde_json::from_str(&lines[1]).unwrap();
+        assert_eq!(echo_ok["dest"], "c1");
+        assert_eq!(echo_ok["body"]["in_reply_to"], 7);
+        assert_eq!(echo_ok["body"]["echo"], "hi");
+
+        // The same input replays to the same output
+        let again = replay(&mut Echo, NodeConfig::new(), INPUT.as_bytes()).unwrap();
+        assert!(diff(&again, &lines).is_empty());
+    }
+
+    #[test]
+    fn test_diff_ignores_field_order_and_reports_drift() {
+        let golden = vec![r#"{"a":1,"b":2}"#.to_string(), r#"{"c":3}"#.to_string()];
+        let actual = vec![r#"{"b":2, "a":1}"#.to_string(), r#"{"c":4}"#.to_string()];
+        assert_eq!(diff(&actual, &golden[..1]).len(), 1);
+
+        let mismatches = diff(&actual, &golden);
+        assert_eq!(mismatches.len(), 1);
+        assert_eq!(mismatches[0].line, 2);
+        assert_eq!(mismatches[0].actual.as_deref(), Some(r#"{"c":4}"#));
+    }
+
+    #[test]
+    fn test_captures_replay_their_inbound_lines() {
+        let lines = replay(&mut Echo, NodeConfig::new(), INPUT.as_bytes()).unwrap();
+        let mut capture = Vec::new();
+        for (input, output) in INPUT.trim().lines().zip(&lines) {
+            for (dir, line) in [("in", input), ("out", output.as_str())] {
+                let record = serde_json::json!({"ts_us": 1, "dir": dir, "line": line});
+                capture.push(record.to_string());
+            }
+        }
+        let capture = capture.join("\n");
+
+        let golden = captured_output(capture.as_bytes()).unwrap();
+        assert_eq!(golden, lines);
+        let replayed = replay(&mut Echo, NodeConfig::new(), capture.as_bytes()).unwrap();
+        assert!(diff(&replayed, &golden).is_empty());
+    }
+
+    #[test]
+    fn test_undecodable_lines_are_errors() {
+        let err = replay(&mut Echo, NodeConfig::new(), "{nope}\n".as_bytes()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(err.to_string().starts_with("line 1"));
+    }
+}
@@ -0,0 +1,186 @@
+//! Consistent hashing: keys map to nodes by position on a ring of hashed virtual nodes, so
+//! every node derives the same owner for a key without coordinating, and a node joining or
+//! leaving moves only the keys next to its own points.
+//!
+//! Hashes are FNV-1a with a final mix, fixed across builds and processes, so nodes running
+//! different builds still agree.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+/// Points each node gets on the ring unless told otherwise; more spreads keys more evenly
+pub const DEFAULT_VNODES: usize = 64;
+
+#[derive(Debug, Clone)]
+pub struct HashRing {
+    vnodes: usize,
+    nodes: BTreeSet<String>,
+    points: BTreeMap<u64, String>,
+}
+
+impl Default for HashRing {
+    fn default() -> Self {
+        Self::new(DEFAULT_VNODES)
+    }
+}
+
+impl HashRing {
+    /// An empty ring placing `vnodes` points per node
+    pub fn new(vnodes: usize) -> Self {
+        Self {
+            vnodes: vnodes.max(1),
+            nodes: BTreeSet::new(),
+            points: BTreeMap::new(),
+        }
+    }
+
+    /// A ring over `nodes`, e.g. a node's peers plus itself
+    pub fn with_nodes<'a>(vnodes: usize, nodes: impl IntoIterator<Item = &'a String>) -> Self {
+        let mut ring = Self::new(vnodes);
+        for node in nodes {
+            ring.add(node);
+        }
+        ring
+    }
+
+    pub fn add(&mut self, node: &str) {
+        if !self.nodes.insert(node.to_string()) {
+            return;
+        }
+        for vnode in 0..self.vnodes {
+            self.points.insert(point(node, vnode), node.to_string());
+        }
+    }
+
+    pub fn remove(&mut self, node: &str) {
+        if self.nodes.remove(node) {
+            self.points.retain(|_, owner| owner != node);
+        }
+    }
+
+    pub fn nodes(&self) -> impl Iterator<Item = &str> {
+        self.nodes.iter().map(String::as_str)
+    }
+
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+
+    /// Node responsible for `key`: the first point at or after the key's hash
+    pub fn owner(&self, key: &str) -> Option<&str> {
+        self.walk(key).next()
+    }
+
+    /// Up to `n` distinct nodes for `key`, owner first, then the next nodes around the ring;
+    /// the usual replica set
+    pub fn replicas(&self, key: &str, n: usize) -> Vec<&str> {
+        let mut replicas: Vec<&str> = Vec::with_capacity(n.min(self.len()));
+        for node in self.walk(key) {
+            if replicas.len() == n.min(self.len()) {
+                break;
+            }
+            if !replicas.contains(&node) {
+                replicas.push(node);
+            }
+        }
+        replicas
+    }
+
+    /// Owners of the points from `key` clockwise, wrapping around once
+    fn walk(&self, key: &str) -> impl Iterator<Item = &str> {
+        let at = hash(key.as_bytes());
+        self.points
+            .range(at..)
+            .chain(self.points.range(..at))
+            .map(|(_, node)| node.as_str())
+    }
+}
+
+fn point(node: &str, vnode: usize) -> u64 {
+    hash(format!("{node}#{vnode}").as_bytes())
+}
+
+/// FNV-1a, then a splitmix64 finalizer so similar names land far apart
+fn hash(bytes: &[u8]) -> u64 {
+    let mut h = bytes.iter().fold(0xcbf29ce484222325u64, |h, &byte| {
+        (h ^ byte as u64).wrapping_mul(0x100000001b3)
+    });
+    h ^= h >> 30;
+    h = h.wrapping_mul(0xbf58476d1ce4e5b9);
+    h ^= h >> 27;
+    h = h.wrapping_mul(0x94d049bb133111eb);
+    h ^ (h >> 31)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn ring(nodes: &[&str]) -> HashRing {
+        let nodes: Vec<String> = nodes.iter().map(|node| node.to_string()).collect();
+        HashRing::with_nodes(DEFAULT_VNODES, &nodes)
+    }
+
+    fn owners(ring: &HashRing) -> HashMap<String, String> {
+        (0..2000)
+            .map(|key| {
+                let key = key.to_string();
+                let owner = ring.owner(&key).unwrap().to_string();
+                (key, owner)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_keys_spread_over_every_node_and_every_ring_agrees() {
+        let nodes = ["n1", "n2", "n3", "n4"];
+        let assigned = owners(&ring(&nodes));
+        assert_eq!(assigned, owners(&ring(&["n4", "n3", "n2", "n1"])));
+        for node in nodes {
+            let owned = assigned.values().filter(|owner| *owner == node).count();
+            assert!(
+                (250..750).contains(&owned),
+                "{node} owns {owned} of 2000 keys"
+            );
+        }
+        assert_eq!(HashRing::default().owner("k"), None);
+    }
+
+    #[test]
+    fn test_membership_changes_move_only_the_keys_they_must() {
+        let before = owners(&ring(&["n1", "n2", "n3", "n4"]));
+
+        let grown = owners(&ring(&["n1", "n2", "n3", "n4", "n5"]));
+        let moved: Vec<_> = before
+            .keys()
+            .filter(|key| before[*key] != grown[*key])
+            .collect();
+        assert!(moved.iter().all(|key| grown[*key] == "n5"));
+        assert!(moved.len() < 2000 / 3, "{} keys moved", moved.len());
+
+        let mut shrunk = ring(&["n1", "n2", "n3", "n4"]);
+        shrunk.remove("n2");
+        let shrunk = owners(&shrunk);
+        for (key, owner) in &before {
+            if owner != "n2" {
+                assert_eq!(&shrunk[key], owner);
+            }
+        }
+    }
+
+    #[test]
+    fn test_replicas_are_distinct_and_start_with_the_owner() {
+        let ring = ring(&["n1", "n2", "n3"]);
+        for key in ["a", "b", "c", "orders"] {
+            let replicas = ring.replicas(key, 2);
+            assert_eq!(replicas.len(), 2);
+            assert_eq!(Some(replicas[0]), ring.owner(key));
+            assert_ne!(replicas[0], replicas[1]);
+            assert_eq!(ring.replicas(key, 5).len(), 3);
+        }
+    }
+}
@@ -0,0 +1,488 @@
+use crate::{MaelstromError, Message};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::any::Any;
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::time::{Duration, Instant};
+
+/// How long to wait for a reply before resending, and how many times to try
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RetryPolicy {
+    /// Total sends, including the first; `1` disables retries
+    pub max_attempts: u32,
+    /// Wait after the first send
+    pub initial_backoff: Duration,
+    /// Growth factor applied to the wait after every attempt
+    pub multiplier: f64,
+    /// Upper bound on a single wait
+    pub max_backoff: Duration,
+    /// Fraction of each wait randomized away (0.0 to 1.0) so retries from many nodes spread out
+    pub jitter: f64,
+    /// Once a peer has answered enough calls, start from its recent p99 round trip times
+    /// this instead of `initial_backoff`; `None` always uses `initial_backoff`
+    pub rtt_factor: Option<f64>,
+    /// Lower bound on an RTT-derived wait, so a burst of fast replies cannot make retries
+    /// fire before a slightly slower reply has a chance
+    pub min_backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 5,
+            initial_backoff: Duration::from_millis(200),
+            multiplier: 2.0,
+            max_backoff: Duration::from_secs(2),
+            jitter: 0.2,
+            rtt_factor: Some(4.0),
+            min_backoff: Duration::from_millis(20),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Send once and wait exactly `timeout` for the reply
+    pub fn once(timeout: Duration) -> Self {
+        Self {
+            max_attempts: 1,
+            initial_backoff: timeout,
+            rtt_factor: None,
+            ..Self::default()
+        }
+    }
+
+    /// Wait before giving up on `attempt` (1-based), before jitter
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        self.backoff_from(self.initial_backoff, attempt)
+    }
+
+    fn backoff_from(&self, initial: Duration, attempt: u32) -> Duration {
+        let factor = self.multiplier.powi(attempt.saturating_sub(1) as i32);
+        initial.mul_f64(factor).min(self.max_backoff)
+    }
+
+    /// First wait for a call to a peer whose recent p99 round trip is `rtt`, if known
+    fn initial_wait(&self, rtt: Option<Duration>) -> Duration {
+        match (self.rtt_factor, rtt) {
+            (Some(factor), Some(rtt)) => rtt
+                .mul_f64(factor)
+                .clamp(self.min_backoff, self.max_backoff.max(self.min_backoff)),
+            _ => self.initial_backoff,
+        }
+    }
+
+    pub(crate) fn wait(&self, initial: Duration, attempt: u32, rng: &mut impl Rng) -> Duration {
+        let backoff = self.backoff_from(initial, attempt);
+        let jitter = self.jitter.clamp(0.0, 1.0);
+        if jitter == 0.0 {
+            return backoff;
+        }
+        backoff.mul_f64(1.0 - jitter * rng.random::<f64>())
+    }
+}
+
+/// A request sent with [`Node::rpc`](crate::Node::rpc) that is waiting for its reply
+pub struct Call {
+    /// `msg_id` the reply will carry as `in_reply_to`
+    pub msg_id: u64,
+    /// Node the request was sent to
+    pub dest: String,
+    /// When the request was first issued
+    pub sent_at: Instant,
+    /// Sends so far, including the first
+    pub attempts: u32,
+    /// Trace of the work that made the call, taken up again while its reply or timeout is
+    /// handled
+    pub trace: Option<String>,
+    context: Option<Box<dyn Any + Send>>,
+    request: Message,
+    policy: RetryPolicy,
+    /// First wait, from the policy or the peer's round trip times
+    initial: Duration,
+    deadline: Instant,
+}
+
+impl Call {
+    /// The request as last sent
+    pub fn request(&self) -> &Message {
+        &self.request
+    }
+
+    /// Take the context attached with [`Node::rpc_with`](crate::Node::rpc_with), if it has
+    /// the requested type
+    pub fn context<T: 'static>(&mut self) -> Option<T> {
+        let context = self.context.take()?;
+        match context.downcast::<T>() {
+            Ok(context) => Some(*context),
+            Err(context) => {
+                self.context = Some(context);
+                None
+            }
+        }
+    }
+}
+
+/// Round trips remembered per peer
+const RTT_WINDOW: usize = 128;
+/// Replies needed from a peer before its round trip times replace the policy's constant
+const RTT_MIN_SAMPLES: usize = 8;
+
+/// Recent reply latencies per peer. Only first attempts are sampled: a reply to a resent
+/// request cannot be matched to the send it answers.
+#[derive(Default)]
+pub struct RoundTrips {
+    peers: HashMap<String, VecDeque<Duration>>,
+}
+
+impl RoundTrips {
+    pub fn record(&mut self, peer: &str, rtt: Duration) {
+        let samples = self.peers.entry(peer.to_string()).or_default();
+        if samples.len() == RTT_WINDOW {
+            samples.pop_front();
+        }
+        samples.push_back(rtt);
+    }
+
+    /// The `q`-quantile (0.0 to 1.0) of recent round trips to `peer`, once there are enough
+    pub fn quantile(&self, peer: &str, q: f64) -> Option<Duration> {
+        let samples = self.peers.get(peer)?;
+        if samples.len() < RTT_MIN_SAMPLES {
+            return None;
+        }
+        let mut sorted: Vec<Duration> = samples.iter().copied().collect();
+        sorted.sort_unstable();
+        let rank = ((q.clamp(0.0, 1.0) * sorted.len() as f64).ceil() as usize).max(1);
+        Some(sorted[rank - 1])
+    }
+
+    pub fn p99(&self, peer: &str) -> Option<Duration> {
+        self.quantile(peer, 0.99)
+    }
+}
+
+/// A body sent as an rpc that has no `msg_id` for its reply to answer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NotARequest {
+    /// Wire name of the body, e.g. `broadcast_gossip_ok`
+    pub type_name: &'static str,
+}
+
+impl fmt::Display for NotARequest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} carries no msg_id, so no reply could answer it as an rpc",
+            self.type_name
+        )
+    }
+}
+
+impl std::error::Error for NotARequest {}
+
+impl From<NotARequest> for MaelstromError {
+    fn from(err: NotARequest) -> Self {
+        Self::crash(err.to_string())
+    }
+}
+
+/// Outstanding calls keyed by the `msg_id` of their request
+pub struct Calls {
+    pending: HashMap<u64, Call>,
+    /// Reply latencies behind adaptive timeouts
+    pub rtt: RoundTrips,
+    /// Source of retry jitter
+    rng: StdRng,
+}
+
+impl Default for Calls {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Calls {
+    pub fn new() -> Self {
+        Self::with_rng(StdRng::from_os_rng())
+    }
+
+    /// Draw retry jitter from `rng`, e.g. a seeded one for reproducible runs
+    pub fn with_rng(rng: StdRng) -> Self {
+        Self {
+            pending: HashMap::new(),
+            rtt: RoundTrips::default(),
+            rng,
+        }
+    }
+
+    /// Replace the source of retry jitter
+    pub fn set_rng(&mut self, rng: StdRng) {
+        self.rng = rng;
+    }
+
+    /// Start tracking a request sent at `now` under `trace`; refused when its body has no
+    /// `msg_id`
+    pub fn insert(
+        &mut self,
+        request: Message,
+        context: Option<Box<dyn Any + Send>>,
+        trace: Option<String>,
+        policy: RetryPolicy,
+        now: Instant,
+    ) -> Result<(), NotARequest> {
+        let Some(msg_id) = request.body.msg_id() else {
+            return Err(NotARequest {
+                type_name: request.body.type_name(),
+            });
+        };
+        let initial = policy.initial_wait(self.rtt.p99(&request.dest));
+        self.pending.insert(
+            msg_id,
+            Call {
+                msg_id,
+                dest: request.dest.clone(),
+                sent_at: now,
+                attempts: 1,
+                trace,
+                context,
+                deadline: now + policy.wait(initial, 1, &mut self.rng),
+                request,
+                policy,
+                initial,
+            },
+        );
+        Ok(())
+    }
+
+    /// Earliest moment a call needs attention
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.pending.values().map(|call| call.deadline).min()
+    }
+
+    /// Resend every overdue call that has attempts left and give up on the rest; returns the
+    /// resends and the calls that ran out of attempts
+    pub fn poll(&mut self, now: Instant) -> (Vec<Message>, Vec<Call>) {
+        let mut resends = Vec::new();
+        let mut expired = Vec::new();
+        for call in self.pending.values_mut() {
+            if call.deadline > now {
+                continue;
+            }
+            if call.attempts < call.policy.max_attempts {
+                call.attempts += 1;
+                call.deadline = now + call.policy.wait(call.initial, call.attempts, &mut self.rng);
+                resends.push(call.request.clone());
+            } else {
+                expired.push(call.msg_id);
+            }
+        }
+        let expired = expired
+            .into_iter()
+            .filter_map(|msg_id| self.pending.remove(&msg_id))
+            .collect();
+        (resends, expired)
+    }
+
+    /// Stop tracking the call answered by a reply with this `in_reply_to`
+    pub fn take(&mut self, in_reply_to: u64) -> Option<Call> {
+        self.take_at(in_reply_to, Instant::now())
+    }
+
+    /// Like `take`, for a reply that arrived at `now`
+    pub fn take_at(&mut self, in_reply_to: u64, now: Instant) -> Option<Call> {
+        let call = self.pending.remove(&in_reply_to)?;
+        if call.attempts == 1 {
+            self.rtt
+                .record(&call.dest, now.saturating_duration_since(call.sent_at));
+        }
+        Some(call)
+    }
+
+    pub fn contains(&self, msg_id: u64) -> bool {
+        self.pending.contains_key(&msg_id)
+    }
+
+    pub fn len(&self) -> usize {
+        self.pending.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MessageBody;
+
+    fn request(msg_id: u64) -> Message {
+        Message {
+            src: "n1".to_string(),
+            dest: "n2".to_string(),
+            body: MessageBody::Read {
+                msg_id,
+                key: None,
+                context: None,
+            },
+        }
+    }
+
+    fn fixed(max_attempts: u32) -> RetryPolicy {
+        RetryPolicy {
+            max_attempts,
+            initial_backoff: Duration::from_millis(100),
+            multiplier: 2.0,
+            max_backoff: Duration::from_millis(300),
+            jitter: 0.0,
+            rtt_factor: Some(4.0),
+            min_backoff: Duration::from_millis(20),
+        }
+    }
+
+    #[test]
+    fn test_calls_are_taken_once() {
+        let mut calls = Calls::new();
+        calls
+            .insert(
+                request(3),
+                Some(Box::new(42u64)),
+                None,
+                RetryPolicy::default(),
+                Instant::now(),
+            )
+            .unwrap();
+        assert!(calls.contains(3));
+
+        let mut call = calls.take(3).unwrap();
+        assert_eq!(call.dest, "n2");
+        assert_eq!(call.request().body.msg_id(), Some(3));
+        assert_eq!(call.context::<String>(), None);
+        assert_eq!(call.context::<u64>(), Some(42));
+        assert_eq!(call.context::<u64>(), None);
+        assert!(calls.take(3).is_none());
+        assert!(calls.is_empty());
+    }
+
+    #[test]
+    fn test_requests_without_a_msg_id_are_refused() {
+        let mut calls = Calls::new();
+        let ack = Message {
+            body: MessageBody::Ack { in_reply_to: 3 },
+            ..request(0)
+        };
+        let err = calls
+            .insert(ack, None, None, RetryPolicy::default(), Instant::now())
+            .unwrap_err();
+        assert_eq!(err, NotARequest { type_name: "ack" });
+        assert_eq!(MaelstromError::from(err).code, crate::ErrorCode::Crash);
+        assert!(calls.is_empty());
+    }
+
+    #[test]
+    fn test_backoff_grows_and_caps() {
+        let policy = fixed(5);
+        assert_eq!(policy.backoff(1), Duration::from_millis(100));
+        assert_eq!(policy.backoff(2), Duration::from_millis(200));
+        assert_eq!(policy.backoff(3), Duration::from_millis(300));
+
+        let jittered = RetryPolicy {
+            jitter: 0.5,
+            ..policy
+        };
+        let mut rng = StdRng::seed_from_u64(7);
+        for _ in 0..20 {
+            let wait = jittered.wait(jittered.initial_backoff, 2, &mut rng);
+            assert!(wait > Duration::from_millis(100) && wait <= Duration::from_millis(200));
+        }
+    }
+
+    #[test]
+    fn test_poll_resends_then_expires() {
+        let start = Instant::now();
+        let mut calls = Calls::new();
+        calls
+            .insert(request(7), None, None, fixed(2), start)
+            .unwrap();
+        assert_eq!(
+            calls.next_deadline(),
+            Some(start + Duration::from_millis(100))
+        );
+
+        // Not due yet
+        let (resends, expired) = calls.poll(start + Duration::from_millis(50));
+        assert!(resends.is_empty() && expired.is_empty());
+
+        // Second attempt resends the same msg_id
+        let (resends, expired) = calls.poll(start + Duration::from_millis(100));
+        assert_eq!(resends.len(), 1);
+        assert_eq!(resends[0].body.msg_id(), Some(7));
+        assert!(expired.is_empty());
+
+        // Budget exhausted after the second wait
+        let (resends, expired) = calls.poll(start + Duration::from_millis(300));
+        assert!(resends.is_empty());
+        assert_eq!(expired.len(), 1);
+        assert_eq!(expired[0].attempts, 2);
+        assert!(calls.is_empty());
+    }
+
+    #[test]
+    fn test_timeouts_adapt_to_round_trips() {
+        let start = Instant::now();
+        let mut calls = Calls::new();
+        let answer = |calls: &mut Calls, msg_id, rtt_ms| {
+            calls
+                .insert(request(msg_id), None, None, fixed(3), start)
+                .unwrap();
+            calls.take_at(msg_id, start + Duration::from_millis(rtt_ms));
+        };
+
+        // Too few samples: the policy's constant still applies
+        for msg_id in 1..RTT_MIN_SAMPLES as u64 {
+            answer(&mut calls, msg_id, 10);
+        }
+        calls
+            .insert(request(100), None, None, fixed(3), start)
+            .unwrap();
+        assert_eq!(
+            calls.next_deadline(),
+            Some(start + Duration::from_millis(100))
+        );
+        calls.take(100);
+
+        answer(&mut calls, 99, 12);
+        assert_eq!(calls.rtt.p99("n2"), Some(Duration::from_millis(12)));
+        // p99 of 12ms times 4
+        calls
+            .insert(request(101), None, None, fixed(3), start)
+            .unwrap();
+        assert_eq!(
+            calls.next_deadline(),
+            Some(start + Duration::from_millis(48))
+        );
+        // Retries back off from the adapted wait
+        calls.poll(start + Duration::from_millis(48));
+        assert_eq!(
+            calls.next_deadline(),
+            Some(start + Duration::from_millis(48 + 96))
+        );
+
+        // Resent calls are not sampled
+        let before = calls.rtt.p99("n2");
+        calls.take_at(101, start + Duration::from_secs(5));
+        assert_eq!(calls.rtt.p99("n2"), before);
+
+        // Other peers are tracked apart, and the floor holds
+        assert_eq!(calls.rtt.p99("n3"), None);
+        let once = RetryPolicy::once(Duration::from_millis(70));
+        assert_eq!(
+            once.initial_wait(Some(Duration::from_millis(1))),
+            Duration::from_millis(70)
+        );
+        assert_eq!(
+            fixed(1).initial_wait(Some(Duration::from_millis(1))),
+            Duration::from_millis(20)
+        );
+    }
+}
@@ -0,0 +1,2548 @@
+use crate::{
+    ErrorCode, Message, MessageBody,
+    admin::{self, Introspect},
+    breaker::Breakers,
+    coalesce::Coalescer,
+    compact,
+    config::{MisroutePolicy, NodeConfig, Transport},
+    dedup::Dedup,
+    error::MaelstromError,
+    events::EventStream,
+    liveness::Liveness,
+    logging,
+    metrics::Metrics,
+    outbox::Outbox,
+    rpc::{Call, Calls, NotARequest, RetryPolicy},
+    tcp,
+    tee::Tee,
+    timer::{TimerId, Timers},
+    trace::{self, Decoder, Envelope},
+    validate::Invalid,
+    writer::{Writer, spawn_stdout_writer},
+};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::any::Any;
+use std::collections::HashMap;
+use std::io::Write as _;
+use std::time::{Duration, Instant};
+use tokio::{
+    io::{self, AsyncBufReadExt, AsyncRead, BufReader},
+    net::TcpListener,
+    sync::mpsc,
+    time,
+};
+
+/// Version of the node-to-node protocol spoken by this build; bump it whenever an
+/// internal message changes shape
+pub const PROTOCOL_VERSION: u32 = 3;
+
+/// Base node structure that all services can use
+pub struct Node {
+    /// Unique node identifier
+    pub id: String,
+    /// Peer node IDs for gossip
+    pub peers: Vec<String>,
+    /// Message counter for generating unique msg_ids
+    pub msg_id: u64,
+    /// Trace id of the message currently being handled; for an RPC's reply or timeout, the
+    /// trace of the work that made the call
+    pub trace: Option<String>,
+    /// Send internal messages in compact form to peers that understand it
+    pub compact: bool,
+    /// Counter for generating unique trace ids
+    trace_seq: u64,
+    /// Protocol version last announced by each peer
+    peer_versions: HashMap<String, u32>,
+    /// Requests sent with `rpc` that are still waiting for a reply
+    pub calls: Calls,
+    /// Messages scheduled with `send_after`
+    pub timers: Timers,
+    /// Messages sent with `deliver` that their peer has not acknowledged yet
+    pub outbox: Outbox,
+    /// When each peer was last heard from, behind `alive_peers`
+    pub liveness: Liveness,
+    /// Per-peer circuits opened by repeated RPC failures
+    pub breakers: Breakers,
+    /// Randomness for topology, sampling, and similar decisions; seeded from
+    /// `config.seed` (mixed with the node id at init) so runs can be replayed
+    pub rng: StdRng,
+    /// Whether the message being handled asked for an `ack`
+    ack_requested: bool,
+    /// Traffic and latency counters, reported by the `stats` admin message
+    pub metrics: Metrics,
+    /// Runtime tuning this node was started with
+    pub config: NodeConfig,
+    /// Recently seen requests, when de-duplication is on
+    dedup: Option<Dedup>,
+    /// Queue to the stdout writer task; without one, `write` goes straight to stdout
+    output: Option<Writer>,
+    /// Way back into the message loop for work finished off it
+    deferred: Option<Deferred>,
+    /// Feed for an external viewer, when one is attached
+    events: Option<EventStream>,
+    /// Capture of outbound lines, when teeing
+    tee: Option<Tee>,
+    /// Virtual time set by the simulator; `None` reads the system clock
+    clock: Option<Instant>,
+}
+
+/// Peers that joined and left in one membership update
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MembershipChange {
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+}
+
+impl MembershipChange {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty()
+    }
+}
+
+/// Feeds messages produced outside the message loop (worker tasks, timers) back into it, so
+/// they are written like any handler output. Bodies left with `msg_id` 0 get a fresh id.
+#[derive(Clone)]
+pub struct Deferred {
+    tx: mpsc::UnboundedSender<Deferral>,
+}
+
+/// One batch queued through a [`Deferred`]
+#[derive(Debug)]
+pub struct Deferral {
+    pub messages: Vec<Message>,
+    /// Retransmit each message until its peer acknowledges it, as `Node::deliver` does
+    pub deliver: bool,
+}
+
+impl Deferred {
+    pub fn channel() -> (Self, mpsc::UnboundedReceiver<Deferral>) {
+        let (tx, rx) = mpsc::unbounded_channel();
+        (Self { tx }, rx)
+    }
+
+    /// Queue messages for the loop; false once it has stopped
+    pub fn send(&self, messages: Vec<Message>) -> bool {
+        let deliver = false;
+        self.tx.send(Deferral { messages, deliver }).is_ok()
+    }
+
+    /// Like `send`, for internal messages that must arrive: the loop gives each one a fresh
+    /// `msg_id` and keeps it in the outbox until acknowledged
+    pub fn deliver(&self, messages: Vec<Message>) -> bool {
+        let deliver = true;
+        self.tx.send(Deferral { messages, deliver }).is_ok()
+    }
+}
+
+/// A generator seeded from `seed` and `salt`, or from OS entropy without a seed
+fn seeded_rng(seed: Option<u64>, salt: &str) -> StdRng {
+    let Some(seed) = seed else {
+        return StdRng::from_os_rng();
+    };
+    // FNV-1a, so the mix is the same across builds
+    let salt = salt.bytes().fold(0xcbf29ce484222325u64, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+    });
+    StdRng::seed_from_u64(seed ^ salt)
+}
+
+impl Default for Node {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Node {
+    pub fn new() -> Self {
+        Self::with_config(NodeConfig::default())
+    }
+
+    pub fn with_config(config: NodeConfig) -> Self {
+        Self {
+            id: String::new(),
+            peers: Vec::new(),
+            msg_id: 0,
+            trace: None,
+            compact: false,
+            trace_seq: 0,
+            peer_versions: HashMap::new(),
+            calls: Calls::with_rng(seeded_rng(config.seed, "")),
+            dedup: config.dedup.map(Dedup::new),
+            timers: Timers::new(),
+            outbox: Outbox::new(config.retransmit_interval),
+            liveness: Liveness::new(config.suspect_after),
+            breakers: Breakers::new(config.breaker_failures, config.breaker_cooldown),
+            rng: seeded_rng(config.seed, ""),
+            ack_requested: false,
+            metrics: Metrics::new(),
+            config,
+            output: None,
+            deferred: None,
+            events: None,
+            tee: None,
+            clock: None,
+        }
+    }
+
+    /// Handle init message and set up node identity
+    pub fn handle_init(&mut self, node_id: String, node_ids: Vec<String>) {
+        self.id = node_id.clone();
+        self.peers = node_ids.clone();
+        self.peers.retain(|p| p != &self.id);
+        self.liveness.reset(self.now());
+        // Nodes sharing a seed still make different choices
+        if self.config.seed.is_some() {
+            self.rng = seeded_rng(self.config.seed, &self.id);
+            let jitter = StdRng::seed_from_u64(self.rng.random());
+            self.calls.set_rng(jitter);
+        }
+    }
+
+    /// Peers heard from within `config.suspect_after` (all of them when that is unset); peers
+    /// never heard from count as alive for that long after init
+    pub fn alive_peers(&self) -> Vec<String> {
+        let now = self.now();
+        self.peers
+            .iter()
+            .filter(|peer| self.liveness.is_alive(peer, now))
+            .cloned()
+            .collect()
+    }
+
+    /// Hellos (answered by every peer, so they double as pings) to peers quiet for at least
+    /// `config.ping_interval`; none when pinging is off
+    pub fn pings(&mut self, now: Instant) -> Vec<Message> {
+        let Some(interval) = self.config.ping_interval else {
+            return Vec::new();
+        };
+        let quiet: Vec<String> = self
+            .peers
+            .iter()
+            .filter(|peer| {
+                self.liveness
+                    .last_heard(peer)
+                    .is_none_or(|last| now.saturating_duration_since(last) >= interval)
+            })
+            .cloned()
+            .collect();
+        quiet.into_iter().map(|peer| self.hello(peer)).collect()
+    }
+
+    /// Add and remove peers, ignoring ourselves, peers already present, and peers already
+    /// gone; returns what actually changed. Deliveries still pending to removed peers are
+    /// abandoned.
+    pub fn update_membership(
+        &mut self,
+        added: Vec<String>,
+        removed: Vec<String>,
+    ) -> MembershipChange {
+        let mut change = MembershipChange::default();
+        for peer in removed {
+            if let Some(i) = self.peers.iter().position(|p| p == &peer) {
+                self.peers.remove(i);
+                self.outbox.forget(&peer);
+                self.liveness.forget(&peer);
+                change.removed.push(peer);
+            }
+        }
+        for peer in added {
+            if peer != self.id && !self.peers.contains(&peer) {
+                self.peers.push(peer.clone());
+                change.added.push(peer);
+            }
+        }
+        change
+    }
+
+    /// Get next message ID
+    pub fn next_msg_id(&mut self) -> u64 {
+        self.msg_id += 1;
+        self.msg_id
+    }
+
+    /// Take in the envelope of an inbound message: record the sender's protocol version
+    /// and set the trace context
+    pub fn begin(&mut self, src: &str, envelope: Envelope) {
+        if let Some(version) = envelope.proto {
+            self.record_version(src, version);
+        }
+        self.ack_requested = envelope.ack;
+        self.begin_trace(src, envelope.trace);
+    }
+
+    /// Remember the protocol version a peer speaks
+    pub fn record_version(&mut self, peer: &str, version: u32) {
+        if trace::is_peer(peer) {
+            self.peer_versions.insert(peer.to_string(), version);
+        }
+    }
+
+    /// Protocol version last seen from `peer`, if it has announced one
+    pub fn peer_version(&self, peer: &str) -> Option<u32> {
+        self.peer_versions.get(peer).copied()
+    }
+
+    /// Whether `peer` is known to understand messages introduced in `version`
+    pub fn speaks(&self, peer: &str, version: u32) -> bool {
+        self.peer_version(peer).is_some_and(|v| v >= version)
+    }
+
+    /// Hello messages announcing our protocol version to every peer
+    pub fn hellos(&mut self) -> Vec<Message> {
+        let peers = self.peers.clone();
+        peers.into_iter().map(|peer| self.hello(peer)).collect()
+    }
+
+    /// Handshake announcing our protocol version to one peer
+    pub fn hello(&mut self, peer: String) -> Message {
+        let msg_id = self.next_msg_id();
+        self.reply(
+            peer,
+            MessageBody::Hello {
+                msg_id,
+                version: PROTOCOL_VERSION,
+            },
+        )
+    }
+
+    /// Set the trace context for an inbound message, starting a new trace for requests from
+    /// outside the cluster
+    pub fn begin_trace(&mut self, src: &str, trace: Option<String>) {
+        match trace {
+            None if !trace::is_peer(src) => self.new_trace(),
+            trace => self.trace = trace,
+        }
+    }
+
+    /// Start a trace for work no inbound message caused, such as a tick's gossip, so what it
+    /// sends can be followed across the cluster. Timers, retransmissions and deferred work
+    /// start none: what they send goes untraced.
+    pub fn new_trace(&mut self) {
+        self.trace_seq += 1;
+        self.trace = Some(format!("{}-{}", self.id, self.trace_seq));
+    }
+
+    /// Trace id to attach to an outbound message; only internal traffic carries it
+    pub fn trace_for(&self, dest: &str) -> Option<&str> {
+        if !trace::is_peer(dest) {
+            return None;
+        }
+        self.trace.as_deref()
+    }
+
+    /// Write a diagnostic line to stderr, tagged with the node id and current trace
+    pub fn log(&self, text: &str) {
+        #[cfg(feature = "tracing")]
+        tracing::info!(node = %self.id, trace = self.trace.as_deref(), "{text}");
+        #[cfg(not(feature = "tracing"))]
+        if !logging::info_enabled(self.config.log_level.as_deref()) {
+            return;
+        }
+        #[cfg(not(feature = "tracing"))]
+        match &self.trace {
+            Some(trace) => eprintln!("[{} trace={trace}] {text}", self.id),
+            None => eprintln!("[{}] {text}", self.id),
+        }
+    }
+
+    /// Packed form of `message` when compaction is on and its destination can read it
+    fn packed(&self, message: &Message) -> Option<Message> {
+        if !self.compact || !self.speaks(&message.dest, compact::PACKED_SINCE) {
+            return None;
+        }
+        match compact::pack(&message.body) {
+            Ok(data) => Some(Message {
+                src: message.src.clone(),
+                dest: message.dest.clone(),
+                body: MessageBody::Packed { data },
+            }),
+            Err(e) => {
+                self.log(&format!("pack error: {e:?}, sending plain JSON"));
+                None
+            }
+        }
+    }
+
+    /// Record an inbound request for de-duplication; false means it is a redelivery the
+    /// handler has already seen
+    pub fn first_delivery(&mut self, message: &Message) -> bool {
+        let (Some(dedup), Some(msg_id)) = (&mut self.dedup, message.body.msg_id()) else {
+            return true;
+        };
+        message.body.is_reply() || dedup.first_delivery(&message.src, msg_id)
+    }
+
+    /// Keep an outbound reply so a redelivered request can be answered without handling it
+    /// again; does nothing unless de-duplication is on
+    pub fn remember_reply(&mut self, reply: &Message) {
+        if let Some(dedup) = &mut self.dedup {
+            dedup.remember_reply(reply);
+        }
+    }
+
+    /// The reply already sent for this request, if de-duplication is on and it was answered
+    pub fn cached_reply(&self, message: &Message) -> Option<Message> {
+        let dedup = self.dedup.as_ref()?;
+        dedup
+            .cached_reply(&message.src, message.body.msg_id()?)
+            .cloned()
+    }
+
+    /// The `TemporarilyUnavailable` refusal for a client request that arrived with `queued`
+    /// messages still waiting behind it, if that is over the configured threshold
+    pub fn shed(&mut self, message: &Message, queued: usize) -> Option<Message> {
+        let threshold = self.config.shed_above?;
+        if queued <= threshold
+            || !trace::is_client(&message.src)
+            || message.body.is_reply()
+            || matches!(message.body, MessageBody::Init { .. })
+        {
+            return None;
+        }
+        let msg_id = message.body.msg_id()?;
+        self.metrics.shed += 1;
+        Some(self.temporarily_unavailable(
+            message.src.clone(),
+            msg_id,
+            format!("overloaded: {queued} messages queued"),
+        ))
+    }
+
+    /// Route all output through a writer task
+    pub fn attach_writer(&mut self, writer: Writer) {
+        self.output = Some(writer);
+    }
+
+    /// Accept messages produced off the loop through `deferred`
+    pub fn attach_deferred(&mut self, deferred: Deferred) {
+        self.deferred = Some(deferred);
+    }
+
+    /// Current time: the simulator's virtual time when it drives the node, the system clock
+    /// otherwise
+    pub fn now(&self) -> Instant {
+        self.clock.unwrap_or_else(Instant::now)
+    }
+
+    /// Pin `now` to a virtual time
+    pub(crate) fn set_clock(&mut self, now: Instant) {
+        self.clock = Some(now);
+    }
+
+    /// Stream traffic and state changes to a viewer
+    pub fn attach_events(&mut self, events: EventStream) {
+        self.events = Some(events);
+    }
+
+    /// Record every line this node writes on `tee`
+    pub fn attach_tee(&mut self, tee: Tee) {
+        self.tee = Some(tee);
+    }
+
+    /// Record the handler's state on the event stream if it changed
+    fn record_state<H: MessageHandler>(&mut self, handler: &H) {
+        if self.events.is_none() {
+            return;
+        }
+        let Some(state) = handler.introspect().map(|i| i.dump_state(self)) else {
+            return;
+        };
+        if let Some(events) = &mut self.events {
+            events.state(&self.id, state);
+        }
+    }
+
+    /// Handle for sending messages into the loop from other tasks; `None` outside `run_node`
+    pub fn deferred(&self) -> Option<Deferred> {
+        self.deferred.clone()
+    }
+
+    /// Give placeholder (`msg_id` 0) bodies from deferred work a real id
+    pub fn stamp(&mut self, messages: &mut [Message]) {
+        for message in messages {
+            if message.body.msg_id() == Some(0) {
+                let msg_id = self.next_msg_id();
+                message.body.set_msg_id(msg_id);
+            }
+        }
+    }
+
+    /// Messages to write for a batch of deferred work, stamped, and held in the outbox when
+    /// they are to be delivered. Deliveries get a fresh id each, as with `deliver`; a body
+    /// with no `msg_id` to carry one is written once, unacknowledged.
+    pub(crate) fn accept_deferred(&mut self, deferral: Deferral) -> Vec<Message> {
+        let Deferral {
+            mut messages,
+            deliver,
+        } = deferral;
+        if !deliver {
+            self.stamp(&mut messages);
+            return messages;
+        }
+        let now = self.now();
+        for message in &mut messages {
+            let msg_id = self.next_msg_id();
+            message.body.set_msg_id(msg_id);
+            if message.body.msg_id().is_none() {
+                self.log(&format!(
+                    "cannot deliver {} to {}: it carries no msg_id",
+                    message.body.type_name(),
+                    message.dest
+                ));
+                continue;
+            }
+            self.outbox.insert(message.clone(), now);
+        }
+        messages
+    }
+
+    /// Out-of-band fields to send `message` with
+    pub fn envelope_for(&self, message: &Message) -> Envelope {
+        // Only internal traffic is tagged with a protocol version
+        let proto = trace::is_peer(&message.dest).then_some(PROTOCOL_VERSION);
+        Envelope {
+            trace: self.trace_for(&message.dest).map(str::to_string),
+            proto,
+            ack: self.outbox.holds(message),
+        }
+    }
+
+    /// Serialize and write a message to stdout, carrying the current trace
+    pub fn write(&self, message: &Message) {
+        let packed = self.packed(message);
+        let wire = packed.as_ref().unwrap_or(message);
+        let bytes = match trace::encode(wire, &self.envelope_for(message)) {
+            Ok(bytes) => bytes,
+            Err(e) => {
+                self.log(&format!("serialize error: {e:?} for response: {message:?}"));
+                return;
+            }
+        };
+        if let Some(tee) = &self.tee {
+            tee.outbound(&bytes);
+        }
+        let written = match &self.output {
+            Some(output) => output.send(bytes),
+            None => {
+                let mut stdout = std::io::stdout().lock();
+                stdout
+                    .write_all(&bytes)
+                    .and_then(|_| stdout.flush())
+                    .is_ok()
+            }
+        };
+        if !written {
+            self.log(&format!("stdout write failed for response: {message:?}"));
+        }
+    }
+
+    /// Answer `request` with the body `body(msg_id, in_reply_to)` builds from a fresh
+    /// `msg_id` and the request's own; a request without a `msg_id` gets `in_reply_to` 0
+    pub fn reply_to(
+        &mut self,
+        request: &Message,
+        body: impl FnOnce(u64, u64) -> MessageBody,
+    ) -> Message {
+        let in_reply_to = request.body.msg_id().unwrap_or_default();
+        self.answer(request.src.clone(), in_reply_to, body)
+    }
+
+    /// Like `reply_to`, for handlers that only have the request's sender and `msg_id`
+    pub fn answer(
+        &mut self,
+        dest: String,
+        in_reply_to: u64,
+        body: impl FnOnce(u64, u64) -> MessageBody,
+    ) -> Message {
+        let msg_id = self.next_msg_id();
+        self.reply(dest, body(msg_id, in_reply_to))
+    }
+
+    /// Create an InitOk response
+    pub fn init_ok(&mut self, dest: String, in_reply_to: u64) -> Message {
+        self.answer(dest, in_reply_to, |msg_id, in_reply_to| {
+            MessageBody::InitOk {
+                msg_id,
+                in_reply_to,
+            }
+        })
+    }
+
+    /// Create an Error reply with the given code and human-readable text
+    pub fn error(
+        &mut self,
+        dest: String,
+        in_reply_to: u64,
+        code: ErrorCode,
+        text: impl Into<String>,
+    ) -> Message {
+        let msg_id = self.next_msg_id();
+        self.reply(
+            dest,
+            MessageBody::Error {
+                msg_id,
+                in_reply_to,
+                code,
+                text: Some(text.into()),
+                extra: None,
+            },
+        )
+    }
+
+    /// Create a `TxnConflict` error reply
+    pub fn txn_conflict(
+        &mut self,
+        dest: String,
+        in_reply_to: u64,
+        text: impl Into<String>,
+    ) -> Message {
+        self.error(dest, in_reply_to, ErrorCode::TxnConflict, text)
+    }
+
+    /// Create a `NotSupported` error reply
+    pub fn not_supported(
+        &mut self,
+        dest: String,
+        in_reply_to: u64,
+        text: impl Into<String>,
+    ) -> Message {
+        self.error(dest, in_reply_to, ErrorCode::NotSupported, text)
+    }
+
+    /// Create a `TemporarilyUnavailable` error reply
+    pub fn temporarily_unavailable(
+        &mut self,
+        dest: String,
+        in_reply_to: u64,
+        text: impl Into<String>,
+    ) -> Message {
+        self.error(dest, in_reply_to, ErrorCode::TemporarilyUnavailable, text)
+    }
+
+    /// Create a `MalformedMessage` error reply
+    pub fn malformed_message(
+        &mut self,
+        dest: String,
+        in_reply_to: u64,
+        text: impl Into<String>,
+    ) -> Message {
+        self.error(dest, in_reply_to, ErrorCode::MalformedMessage, text)
+    }
+
+    /// Create a `KeyDoesNotExist` error reply
+    pub fn key_does_not_exist(
+        &mut self,
+        dest: String,
+        in_reply_to: u64,
+        text: impl Into<String>,
+    ) -> Message {
+        self.error(dest, in_reply_to, ErrorCode::KeyDoesNotExist, text)
+    }
+
+    /// Create a `PreconditionFailed` error reply
+    pub fn precondition_failed(
+        &mut self,
+        dest: String,
+        in_reply_to: u64,
+        text: impl Into<String>,
+    ) -> Message {
+        self.error(dest, in_reply_to, ErrorCode::PreconditionFailed, text)
+    }
+
+    /// Send a tracked request: `body` gets a fresh `msg_id`, it is resent under the node's
+    /// `retry` policy until answered, and the reply (`*_ok` or `error`) is routed to
+    /// `MessageHandler::on_rpc_reply`. Refused for a body with no `msg_id`, which no reply
+    /// could name
+    pub fn rpc(&mut self, dest: String, body: MessageBody) -> Result<Message, NotARequest> {
+        self.track(dest, body, None)
+    }
+
+    /// Like `rpc`, attaching context that comes back with the reply via `Call::context`
+    pub fn rpc_with<T: Any + Send>(
+        &mut self,
+        dest: String,
+        body: MessageBody,
+        context: T,
+    ) -> Result<Message, NotARequest> {
+        self.track(dest, body, Some(Box::new(context)))
+    }
+
+    fn track(
+        &mut self,
+        dest: String,
+        mut body: MessageBody,
+        context: Option<Box<dyn Any + Send>>,
+    ) -> Result<Message, NotARequest> {
+        body.set_msg_id(self.next_msg_id());
+        let request = self.reply(dest, body);
+        let now = self.now();
+        // With the peer's circuit open the request is dropped on the way out, so fail the
+        // call at the next poll instead of retrying into the void
+        let policy = if self.breakers.allows(&request.dest, now) {
+            self.config.retry
+        } else {
+            RetryPolicy::once(Duration::ZERO)
+        };
+        let trace = self.trace.clone();
+        self.calls
+            .insert(request.clone(), context, trace, policy, now)?;
+        Ok(request)
+    }
+
+    /// Whether `message` may be written: traffic to a peer whose circuit is open is held
+    /// back, except hellos, which double as probes
+    pub fn circuit_allows(&self, message: &Message) -> bool {
+        matches!(message.body, MessageBody::Hello { .. })
+            || self.breakers.allows(&message.dest, self.now())
+    }
+
+    /// Send a fire-and-forget message; nothing is tracked and no `msg_id` is assigned
+    pub fn send(&self, dest: String, body: MessageBody) -> Message {
+        Message {
+            src: self.id.clone(),
+            dest,
+            body,
+        }
+    }
+
+    /// Write `message` once `delay` has passed, e.g. a retry or a round of anti-entropy;
+    /// returns an id for `timers.cancel`
+    pub fn send_after(&mut self, delay: Duration, message: Message) -> TimerId {
+        self.timers.schedule(self.now() + delay, message)
+    }
+
+    /// Send an internal message that is retransmitted until `dest` acknowledges it, giving
+    /// at-least-once delivery; the receiving framework sends the `ack`
+    pub fn deliver(&mut self, dest: String, mut body: MessageBody) -> Message {
+        body.set_msg_id(self.next_msg_id());
+        let message = self.reply(dest, body);
+        self.outbox.insert(message.clone(), self.now());
+        message
+    }
+
+    /// The `ack` owed for the message being handled, if its sender asked for one
+    fn take_ack(&mut self, message: &Message) -> Option<Message> {
+        if !std::mem::take(&mut self.ack_requested) {
+            return None;
+        }
+        let in_reply_to = message.body.msg_id()?;
+        Some(self.reply(message.src.clone(), MessageBody::Ack { in_reply_to }))
+    }
+
+    /// Turn a handler result into outbound messages, answering a failure with an `error`
+    /// reply to `dest`; failures of fire-and-forget messages (no `msg_id`) are only logged
+    pub fn respond(
+        &mut self,
+        dest: String,
+        in_reply_to: Option<u64>,
+        result: Result<Vec<Message>, MaelstromError>,
+    ) -> Vec<Message> {
+        match (result, in_reply_to) {
+            (Ok(out), _) => out,
+            (Err(e), Some(in_reply_to)) => vec![e.reply(self, dest, in_reply_to)],
+            (Err(e), None) => {
+                self.log(&format!("dropping failure for message from {dest}: {e}"));
+                Vec::new()
+            }
+        }
+    }
+
+    /// Create a reply message with the given body
+    pub fn reply(&mut self, dest: String, body: MessageBody) -> Message {
+        Message {
+            src: self.id.clone(),
+            dest,
+            body,
+        }
+    }
+}
+
+/// Trait for handling different message types.
+///
+/// `handle` is required. A handler whose work can fail may also override `try_handle`, which
+/// is what the framework calls: an `Err` becomes an `error` reply to the request. Its
+/// `handle` can answer failures the same way through [`Node::respond`].
+pub trait MessageHandler {
+    /// Handle a message and return response messages
+    fn handle(&mut self, node: &mut Node, message: Message) -> Vec<Message>;
+
+    /// Fallible form of `handle`; defaults to `handle`, which never fails
+    fn try_handle(
+        &mut self,
+        node: &mut Node,
+        message: Message,
+    ) -> Result<Vec<Message>, MaelstromError> {
+        Ok(self.handle(node, message))
+    }
+
+    /// Check a message before it reaches `handle`; override to add state-dependent checks
+    fn validate(&self, node: &Node, message: &Message) -> Result<(), Invalid> {
+        let _ = node;
+        message.body.validate()
+    }
+
+    /// Periodic work such as gossip, run every `tick_interval`
+    fn on_tick(&mut self, node: &mut Node) -> Vec<Message> {
+        let _ = node;
+        Vec::new()
+    }
+
+    /// How often `run_node` calls `on_tick`; `None` disables ticking
+    fn tick_interval(&self) -> Option<Duration> {
+        None
+    }
+
+    /// Queue client requests apart from node traffic and drain them first, so a gossip storm
+    /// cannot delay client replies
+    fn prioritize_clients(&self) -> bool {
+        false
+    }
+
+    /// Handle the reply (`*_ok` or `error`) to a request sent with `Node::rpc`; by default it
+    /// goes through `handle` like any other message
+    fn on_rpc_reply(&mut self, node: &mut Node, call: Call, reply: Message) -> Vec<Message> {
+        let _ = call;
+        self.handle(node, reply)
+    }
+
+    /// A call exhausted its retries without a reply; `call.dest`, `call.msg_id`, and
+    /// `call.request()` say what was lost. By default a `Timeout` error is delivered to
+    /// `on_rpc_reply` as if the peer had answered with it.
+    fn on_rpc_timeout(&mut self, node: &mut Node, call: Call) -> Vec<Message> {
+        let text = format!("no reply after {} attempts", call.attempts);
+        let timeout = node.error(call.dest.clone(), call.msg_id, ErrorCode::Timeout, text);
+        let timeout = Message {
+            src: timeout.dest,
+            dest: timeout.src,
+            body: timeout.body,
+        };
+        self.on_rpc_reply(node, call, timeout)
+    }
+
+    /// Observe the node once `init` has been handled and it knows its id and peers (named
+    /// apart from `MessageBodyDispatch::on_init`, which does the handling)
+    fn on_node_init(&mut self, node: &Node) {
+        let _ = node;
+    }
+
+    /// Observe every inbound message before the framework or the handler acts on it
+    fn on_message(&mut self, node: &Node, message: &Message) {
+        let _ = (node, message);
+    }
+
+    /// Observe every outbound message just before it is written
+    fn on_send(&mut self, node: &Node, message: &Message) {
+        let _ = (node, message);
+    }
+
+    /// Peers joined or left after init, through a `membership` message or an `init` naming a
+    /// different cluster; `node.peers` is already updated
+    fn on_membership_change(&mut self, node: &mut Node, change: &MembershipChange) -> Vec<Message> {
+        let _ = (node, change);
+        Vec::new()
+    }
+
+    /// Calls to `peer` kept failing and its circuit opened: until `config.breaker_cooldown`
+    /// passes, new calls to it fail fast and other traffic to it is dropped
+    fn on_circuit_open(&mut self, node: &mut Node, peer: &str) -> Vec<Message> {
+        let _ = (node, peer);
+        Vec::new()
+    }
+
+    /// Expose state to the `dump_state` admin message; usually `Some(self)`
+    fn introspect(&self) -> Option<&dyn Introspect> {
+        None
+    }
+
+    /// Figures of the handler's own for the `stats` admin message, reported under `handler`
+    /// next to the framework's metrics
+    fn stats(&self, node: &Node) -> Option<serde_json::Value> {
+        let _ = node;
+        None
+    }
+
+    /// `set_config` changed `node.config`; rebuild anything derived from it
+    fn on_config_change(&mut self, node: &mut Node) -> Vec<Message> {
+        let _ = node;
+        Vec::new()
+    }
+}
+
+/// Run an inbound message through the framework: acknowledge deliveries, answer the peer
+/// handshake and admin requests, route replies to outstanding RPCs, answer redelivered
+/// requests from the reply cache (or drop them while still unanswered), reject invalid
+/// requests with `MalformedMessage`, then hand the rest to the handler
+pub fn process<H: MessageHandler>(
+    handler: &mut H,
+    node: &mut Node,
+    message: Message,
+) -> Vec<Message> {
+    #[cfg(feature = "tracing")]
+    let _span = tracing::info_span!(
+        "message",
+        src = %message.src,
+        dest = %message.dest,
+        r#type = message.body.type_name(),
+        msg_id = message.body.msg_id(),
+    )
+    .entered();
+    let started = Instant::now();
+    node.metrics.record_received(message.body.type_name());
+    if trace::is_peer(&message.src) {
+        let now = node.now();
+        node.liveness.heard_from(&message.src, now);
+    }
+    handler.on_message(node, &message);
+    // Acknowledge first so even a duplicate stops the sender's retransmissions
+    let ack = node.take_ack(&message);
+    let mut out = route(handler, node, message);
+    out.extend(ack);
+    node.metrics.record_handle_latency(started.elapsed());
+    out
+}
+
+fn route<H: MessageHandler>(handler: &mut H, node: &mut Node, message: Message) -> Vec<Message> {
+    if let Some(out) = check_dest(node, &message) {
+        return out;
+    }
+    match message.body {
+        MessageBody::Hello { msg_id, version } => {
+            node.record_version(&message.src, version);
+            // Before init we have no id to reply from; our own hello will follow once we do
+            if node.id.is_empty() {
+                return Vec::new();
+            }
+            return vec![node.answer(message.src, msg_id, |msg_id, in_reply_to| {
+                MessageBody::HelloOk {
+                    msg_id,
+                    in_reply_to,
+                    version: PROTOCOL_VERSION,
+                }
+            })];
+        }
+        MessageBody::HelloOk { version, .. } => {
+            node.record_version(&message.src, version);
+            return Vec::new();
+        }
+        MessageBody::Ack { in_reply_to } => {
+            node.outbox.ack(&message.src, in_reply_to);
+            return Vec::new();
+        }
+        _ => {}
+    }
+    if let Some(out) = admin::handle(handler, node, &message) {
+        return out;
+    }
+    if let MessageBody::Init {
+        msg_id,
+        node_id,
+        node_ids,
+    } = &message.body
+        && !node.id.is_empty()
+    {
+        return reinit(handler, node, &message.src, *msg_id, node_id, node_ids);
+    }
+
+    if let Some(call) = message.body.in_reply_to().and_then(|id| {
+        let now = node.now();
+        node.calls.take_at(id, now)
+    }) {
+        node.breakers.record_success(&call.dest);
+        // A service's reply would otherwise start a trace of its own
+        if call.trace.is_some() {
+            node.trace = call.trace.clone();
+        }
+        return handler.on_rpc_reply(node, call, message);
+    }
+
+    if !node.first_delivery(&message) {
+        // Answer again rather than act again; one still being worked on gets nothing
+        if let Some(reply) = node.cached_reply(&message) {
+            return vec![reply];
+        }
+        node.log(&format!(
+            "dropping duplicate {} from {}",
+            message.body.type_name(),
+            message.src
+        ));
+        return Vec::new();
+    }
+
+    let Err(invalid) = handler.validate(node, &message) else {
+        let is_init = matches!(message.body, MessageBody::Init { .. });
+        let (src, in_reply_to) = (message.src.clone(), answerable(&message));
+        let result = handler.try_handle(node, message);
+        let mut out = node.respond(src, in_reply_to, result);
+        if is_init && !node.id.is_empty() {
+            handler.on_node_init(node);
+            out.extend(node.hellos());
+        }
+        return out;
+    };
+    node.log(&format!(
+        "rejecting {}: {invalid}",
+        message.body.type_name()
+    ));
+    // Replies are never answered, and neither are bodies without a msg_id to point at
+    match message.body.msg_id() {
+        Some(msg_id) if !message.body.is_reply() => vec![
+            node.malformed_message(message.src, msg_id, invalid.to_string())
+                .with_extra("field", invalid.field),
+        ],
+        _ => Vec::new(),
+    }
+}
+
+/// The `msg_id` a failure to handle `message` is answered at: none for replies, which are
+/// never answered, or for fire-and-forget bodies
+pub(crate) fn answerable(message: &Message) -> Option<u64> {
+    message.body.msg_id().filter(|_| !message.body.is_reply())
+}
+
+/// Apply `config.misroute` to a message addressed to another node; `None` lets it through
+fn check_dest(node: &mut Node, message: &Message) -> Option<Vec<Message>> {
+    if node.id.is_empty() || message.dest == node.id {
+        return None;
+    }
+    let what = format!(
+        "{} from {} addressed to {}",
+        message.body.type_name(),
+        message.src,
+        message.dest
+    );
+    match node.config.misroute {
+        MisroutePolicy::Accept => None,
+        MisroutePolicy::Warn => {
+            logging::log_warn!("accepting misrouted {what}");
+            None
+        }
+        MisroutePolicy::Drop => {
+            node.log(&format!("dropping misrouted {what}"));
+            Some(Vec::new())
+        }
+        MisroutePolicy::Reject => {
+            node.log(&format!("rejecting misrouted {what}"));
+            Some(match message.body.msg_id() {
+                Some(msg_id) if !message.body.is_reply() => vec![node.error(
+                    message.src.clone(),
+                    msg_id,
+                    ErrorCode::NodeNotFound,
+                    format!("this is {}, not {}", node.id, message.dest),
+                )],
+                _ => Vec::new(),
+            })
+        }
+    }
+}
+
+/// Answer an `init` for a node that already has one. The same cluster is acknowledged
+/// without running the handler again; a different peer list is applied as a membership
+/// change. A node cannot be renamed.
+fn reinit<H: MessageHandler>(
+    handler: &mut H,
+    node: &mut Node,
+    src: &str,
+    msg_id: u64,
+    node_id: &str,
+    node_ids: &[String],
+) -> Vec<Message> {
+    if node_id != node.id {
+        let text = format!("already initialized as {}", node.id);
+        return vec![node.error(src.to_string(), msg_id, ErrorCode::PreconditionFailed, text)];
+    }
+    let added = node_ids
+        .iter()
+        .filter(|id| !node.peers.contains(id))
+        .cloned()
+        .collect();
+    let removed = node
+        .peers
+        .iter()
+        .filter(|peer| !node_ids.contains(peer))
+        .cloned()
+        .collect();
+    let mut out = admin::apply_membership(handler, node, added, removed);
+    out.push(node.init_ok(src.to_string(), msg_id));
+    out
+}
+
+/// Resend overdue RPCs and hand the ones out of attempts to `on_rpc_timeout`, telling the
+/// handler first when that failure opens the peer's circuit. Each timeout is handled under
+/// the trace of the work that made its call; the last of them stays current while the batch
+/// is written.
+pub fn poll_calls<H: MessageHandler>(
+    handler: &mut H,
+    node: &mut Node,
+    now: Instant,
+) -> Vec<Message> {
+    let (mut out, expired) = node.calls.poll(now);
+    for call in expired {
+        node.trace = call.trace.clone();
+        if node.breakers.record_failure(&call.dest, now) {
+            node.log(&format!("circuit to {} opened", call.dest));
+            out.extend(handler.on_circuit_open(node, &call.dest));
+        }
+        out.extend(handler.on_rpc_timeout(node, call));
+    }
+    out
+}
+
+/// Spawn a task that reads newline-framed stdin into a reused byte buffer and decodes each
+/// line into a message paired with its envelope
+pub fn spawn_stdin_reader(tx: mpsc::Sender<(Message, Envelope)>) {
+    spawn_reader(tx, None, None);
+}
+
+/// Like `spawn_stdin_reader`, queueing client requests on `clients` and everything from
+/// other nodes on `peers`
+pub fn spawn_split_stdin_reader(
+    clients: mpsc::Sender<(Message, Envelope)>,
+    peers: mpsc::Sender<(Message, Envelope)>,
+) {
+    spawn_reader(clients, Some(peers), None);
+}
+
+pub(crate) fn spawn_reader(
+    clients: mpsc::Sender<(Message, Envelope)>,
+    peers: Option<mpsc::Sender<(Message, Envelope)>>,
+    tee: Option<Tee>,
+) {
+    tokio::spawn(read_lines(io::stdin(), clients, peers, tee, |_| {}));
+}
+
+/// Decode newline-framed messages from `input` until it closes or the loop stops listening,
+/// recording each line on `tee` and calling `heard` with each sender
+pub(crate) async fn read_lines<R: AsyncRead + Unpin>(
+    input: R,
+    clients: mpsc::Sender<(Message, Envelope)>,
+    peers: Option<mpsc::Sender<(Message, Envelope)>>,
+    tee: Option<Tee>,
+    mut heard: impl FnMut(&str),
+) {
+    let mut reader = BufReader::new(input);
+    let mut line = Vec::with_capacity(4096);
+    let mut decoder = Decoder::new();
+    loop {
+        line.clear();
+        match reader.read_until(b'\n', &mut line).await {
+            Ok(0) | Err(_) => break,
+            Ok(_) => {}
+        }
+        if line.iter().all(u8::is_ascii_whitespace) {
+            continue;
+        }
+        if let Some(tee) = &tee {
+            tee.inbound(&line);
+        }
+        match decoder.decode(&line) {
+            Ok(inbound) => {
+                heard(&inbound.0.src);
+                let tx = match &peers {
+                    Some(peers) if trace::is_peer(&inbound.0.src) => peers,
+                    _ => &clients,
+                };
+                if tx.send(inbound).await.is_err() {
+                    break;
+                }
+            }
+            Err(e) => match trace::probe_version(&line) {
+                // A newer peer sent something we cannot parse; drop it rather than guess
+                Some((src, version)) if version > PROTOCOL_VERSION => {
+                    logging::log_warn!(
+                        "dropping message from {src} speaking protocol v{version} (we speak v{PROTOCOL_VERSION})"
+                    );
+                }
+                _ => logging::log_warn!(
+                    "decode error: {e:?} line={}",
+                    String::from_utf8_lossy(&line).trim_end()
+                ),
+            },
+        }
+    }
+}
+
+/// Next inbound message; with a separate peer queue, waiting client requests go first
+async fn next_inbound(
+    clients: &mut mpsc::Receiver<(Message, Envelope)>,
+    peers: Option<&mut mpsc::Receiver<(Message, Envelope)>>,
+) -> Option<(Message, Envelope)> {
+    let Some(peers) = peers else {
+        return clients.recv().await;
+    };
+    tokio::select! {
+        biased;
+        Some(inbound) = clients.recv() => Some(inbound),
+        Some(inbound) = peers.recv() => Some(inbound),
+        else => None,
+    }
+}
+
+/// Write outbound messages, letting the handler observe each one first
+fn emit<H: MessageHandler>(handler: &mut H, node: &mut Node, messages: Vec<Message>) {
+    for message in messages {
+        if sending(handler, node, &message) {
+            node.write(&message);
+        }
+    }
+}
+
+/// Bookkeeping for a message about to leave the node; false if it must not go out at all
+pub(crate) fn sending<H: MessageHandler>(
+    handler: &mut H,
+    node: &mut Node,
+    message: &Message,
+) -> bool {
+    if !node.circuit_allows(message) {
+        node.metrics.circuit_dropped += 1;
+        return false;
+    }
+    node.metrics.record_sent(message.body.type_name());
+    if let Some(events) = &node.events {
+        events.sent(&node.id, message);
+    }
+    handler.on_send(node, message);
+    node.remember_reply(message);
+    true
+}
+
+/// The handler's tick interval, overridden by the node's config when it ticks at all
+pub(crate) fn effective_tick_interval<H: MessageHandler>(
+    handler: &H,
+    node: &Node,
+) -> Option<Duration> {
+    handler
+        .tick_interval()
+        .map(|interval| node.config.tick_interval.unwrap_or(interval))
+}
+
+/// Default message loop that reads from stdin and writes to stdout, configured from the
+/// environment and command-line flags (see [`NodeConfig::load`])
+pub async fn run_node<H: MessageHandler>(handler: H) {
+    run_node_with_config(handler, NodeConfig::load()).await;
+}
+
+/// Message loop with explicit runtime tuning
+pub async fn run_node_with_config<H: MessageHandler>(mut handler: H, config: NodeConfig) {
+    logging::init(config.log_level.as_deref());
+    let (tx, mut rx) = mpsc::channel::<(Message, Envelope)>(config.channel_capacity);
+
+    let (peer_tx, mut peer_rx) = if handler.prioritize_clients() {
+        let (peer_tx, peer_rx) = mpsc::channel(config.channel_capacity);
+        (Some(peer_tx), Some(peer_rx))
+    } else {
+        (None, None)
+    };
+
+    let tee = match &config.tee {
+        Some(path) => match Tee::create(path).await {
+            Ok(tee) => Some(tee),
+            Err(e) => {
+                logging::log_warn!("not capturing traffic to {}: {e:?}", path.display());
+                None
+            }
+        },
+        None => None,
+    };
+    let (tee, tee_task) = tee.unzip();
+
+    // Spawn the transport's reader and writer tasks
+    let (writer, writer_task) = match config.transport {
+        Transport::Stdio => {
+            spawn_reader(tx, peer_tx, tee.clone());
+            spawn_stdout_writer(config.flush)
+        }
+        Transport::Tcp(addr) => match TcpListener::bind(addr).await {
+            Ok(listener) => tcp::serve(listener, tx, peer_tx, tee.clone(), config.flush),
+            Err(e) => {
+                logging::log_warn!("cannot listen on {addr}: {e:?}");
+                return;
+            }
+        },
+    };
+    let events = match &config.events {
+        Some(socket) => match EventStream::connect(socket).await {
+            Ok(events) => Some(events),
+            Err(e) => {
+                logging::log_warn!("not streaming events to {}: {e:?}", socket.display());
+                None
+            }
+        },
+        None => None,
+    };
+    let mut node = Node::with_config(config);
+    node.attach_writer(writer);
+    let events_task = events.map(|(events, task)| {
+        node.attach_events(events);
+        task
+    });
+    if let Some(tee) = tee {
+        node.attach_tee(tee);
+    }
+    let (deferred, mut deferred_rx) = Deferred::channel();
+    node.attach_deferred(deferred);
+
+    let mut tick_interval = effective_tick_interval(&handler, &node);
+    let mut ticker = time::interval(tick_interval.unwrap_or(Duration::MAX));
+    let mut outgoing = Coalescer::new(node.config.coalesce_window);
+    let ping_interval = node.config.ping_interval;
+    let mut pinger = time::interval(ping_interval.unwrap_or(Duration::MAX));
+
+    // Message processing loop, waking early for ticks, for pings, for RPCs due a retry, for scheduled
+    // sends, for unacknowledged deliveries, for held gossip, and for work finished off the loop
+    loop {
+        let deadline = node.calls.next_deadline();
+        let resend_at = node.outbox.next_due();
+        let timer_at = node.timers.next_due();
+        let flush_at = outgoing.due();
+        let out = tokio::select! {
+            _ = ticker.tick(), if tick_interval.is_some() => {
+                node.new_trace();
+                handler.on_tick(&mut node)
+            }
+            _ = pinger.tick(), if ping_interval.is_some() => {
+                node.trace = None;
+                node.pings(Instant::now())
+            }
+            inbound = next_inbound(&mut rx, peer_rx.as_mut()) => {
+                let Some((msg, envelope)) = inbound else { break };
+                let depth = rx.len() + peer_rx.as_ref().map_or(0, |peers| peers.len());
+                node.metrics.record_queue_depth(depth);
+                if let Some(events) = &node.events {
+                    // Before init the node only knows its name from the message
+                    let id = if node.id.is_empty() { &msg.dest } else { &node.id };
+                    events.received(id, &msg);
+                }
+                node.begin(&msg.src, envelope);
+                match node.shed(&msg, depth) {
+                    Some(refusal) => vec![refusal],
+                    None => process(&mut handler, &mut node, msg),
+                }
+            }
+            _ = time::sleep_until(deadline.unwrap_or_else(Instant::now).into()), if deadline.is_some() => {
+                node.trace = None;
+                poll_calls(&mut handler, &mut node, Instant::now())
+            }
+            _ = time::sleep_until(timer_at.unwrap_or_else(Instant::now).into()), if timer_at.is_some() => {
+                node.trace = None;
+                node.timers.poll(Instant::now())
+            }
+            _ = time::sleep_until(resend_at.unwrap_or_else(Instant::now).into()), if resend_at.is_some() => {
+                node.trace = None;
+                node.outbox.poll(Instant::now())
+            }
+            _ = time::sleep_until(flush_at.unwrap_or_else(Instant::now).into()), if flush_at.is_some() => {
+                node.trace = None;
+                // Held gossip has waited its window; pushing it again would only hold it anew
+                let held = outgoing.flush();
+                emit(&mut handler, &mut node, held);
+                Vec::new()
+            }
+            Some(deferral) = deferred_rx.recv() => {
+                node.trace = None;
+                node.accept_deferred(deferral)
+            }
+        };
+        emit(&mut handler, &mut node, outgoing.push(out, Instant::now()));
+        node.record_state(&handler);
+
+        // `set_config` may have retuned the tick
+        let wanted = effective_tick_interval(&handler, &node);
+        if wanted != tick_interval {
+            tick_interval = wanted;
+            if let Some(interval) = tick_interval {
+                ticker = time::interval_at((Instant::now() + interval).into(), interval);
+            }
+        }
+    }
+
+    emit(&mut handler, &mut node, outgoing.flush());
+    // Dropping the node closes the writer queue so the task can flush and exit
+    drop(node);
+    let _ = writer_task.await;
+    if let Some(events_task) = events_task {
+        let _ = events_task.await;
+    }
+    if let Some(tee_task) = tee_task {
+        let _ = tee_task.await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_traces_start_at_the_cluster_edge_and_on_ticks() {
+        let mut node = Node::new();
+        node.handle_init("n1".to_string(), vec!["n1".to_string(), "n2".to_string()]);
+
+        // A service's reply starts a trace like a client's request; a peer's untraced one doesn't
+        node.begin_trace("lin-kv", None);
+        assert_eq!(node.trace.as_deref(), Some("n1-1"));
+        node.begin_trace("n2", None);
+        assert_eq!(node.trace, None);
+
+        node.new_trace();
+        let gossip = node.reply("n2".to_string(), MessageBody::Ack { in_reply_to: 1 });
+        let envelope = node.envelope_for(&gossip);
+        assert_eq!(envelope.trace.as_deref(), Some("n1-2"));
+        assert_eq!(envelope.proto, Some(PROTOCOL_VERSION));
+
+        let read = node.reply("lin-kv".to_string(), MessageBody::Ack { in_reply_to: 1 });
+        let envelope = node.envelope_for(&read);
+        assert_eq!(envelope.trace, None);
+        assert_eq!(envelope.proto, None);
+    }
+
+    #[test]
+    fn test_error_helpers_build_error_replies() {
+        let mut node = Node::new();
+        node.handle_init("n1".to_string(), vec!["n1".to_string()]);
+
+        let reply = node
+            .txn_conflict("c1".to_string(), 4, "conflict on key 1")
+            .with_extra("key", 1);
+
+        assert_eq!(reply.src, "n1");
+        assert_eq!(reply.dest, "c1");
+        match reply.body {
+            MessageBody::Error {
+                in_reply_to,
+                code,
+                text,
+                extra,
+                ..
+            } => {
+                assert_eq!(in_reply_to, 4);
+                assert_eq!(code, ErrorCode::TxnConflict);
+                assert_eq!(text.as_deref(), Some("conflict on key 1"));
+                assert_eq!(extra.unwrap()["key"], 1);
+            }
+            _ => panic!("Expected Error message"),
+        }
+    }
+
+    struct Rejecting;
+
+    impl MessageHandler for Rejecting {
+        fn handle(&mut self, _node: &mut Node, _message: Message) -> Vec<Message> {
+            panic!("invalid message reached handle");
+        }
+    }
+
+    #[test]
+    fn test_invalid_request_gets_malformed_message_reply() {
+        let mut node = Node::new();
+        node.handle_init("n1".to_string(), vec!["n1".to_string()]);
+
+        let send = Message {
+            src: "c1".to_string(),
+            dest: "n1".to_string(),
+            body: MessageBody::Send {
+                msg_id: 3,
+                key: String::new(),
+                msg: 1,
+            },
+        };
+
+        let replies = process(&mut Rejecting, &mut node, send);
+        assert_eq!(replies.len(), 1);
+        assert_eq!(replies[0].dest, "c1");
+        match &replies[0].body {
+            MessageBody::Error {
+                in_reply_to,
+                code,
+                extra,
+                ..
+            } => {
+                assert_eq!(*in_reply_to, 3);
+                assert_eq!(*code, ErrorCode::MalformedMessage);
+                assert_eq!(extra.as_ref().unwrap()["field"], "body.key");
+            }
+            _ => panic!("Expected Error message"),
+        }
+    }
+
+    struct Quiet;
+
+    impl MessageHandler for Quiet {
+        fn handle(&mut self, node: &mut Node, message: Message) -> Vec<Message> {
+            match message.body {
+                MessageBody::Init {
+                    msg_id,
+                    node_id,
+                    node_ids,
+                } => {
+                    node.handle_init(node_id, node_ids);
+                    vec![node.init_ok(message.src, msg_id)]
+                }
+                _ => Vec::new(),
+            }
+        }
+    }
+
+    #[test]
+    fn test_init_announces_protocol_version_to_peers() {
+        let mut node = Node::new();
+        let init = Message {
+            src: "c1".to_string(),
+            dest: "n1".to_string(),
+            body: MessageBody::Init {
+                msg_id: 1,
+                node_id: "n1".to_string(),
+                node_ids: vec!["n1".to_string(), "n2".to_string(), "n3".to_string()],
+            },
+        };
+
+        let out = process(&mut Quiet, &mut node, init);
+        assert_eq!(out.len(), 3);
+        assert!(matches!(out[0].body, MessageBody::InitOk { .. }));
+        for (hello, peer) in out[1..].iter().zip(["n2", "n3"]) {
+            assert_eq!(hello.dest, peer);
+            match hello.body {
+                MessageBody::Hello { version, .. } => assert_eq!(version, PROTOCOL_VERSION),
+                _ => panic!("Expected Hello message"),
+            }
+        }
+    }
+
+    #[test]
+    fn test_hello_is_answered_and_recorded() {
+        let mut node = Node::new();
+        let hello = Message {
+            src: "n2".to_string(),
+            dest: "n1".to_string(),
+            body: MessageBody::Hello {
+                msg_id: 4,
+                version: 7,
+            },
+        };
+
+        // Uninitialized nodes only record the version
+        assert!(process(&mut Quiet, &mut node, hello.clone()).is_empty());
+        assert_eq!(node.peer_version("n2"), Some(7));
+
+        node.handle_init("n1".to_string(), vec!["n1".to_string(), "n2".to_string()]);
+        let out = process(&mut Quiet, &mut node, hello);
+        assert_eq!(out.len(), 1);
+        match out[0].body {
+            MessageBody::HelloOk {
+                in_reply_to,
+                version,
+                ..
+            } => {
+                assert_eq!(in_reply_to, 4);
+                assert_eq!(version, PROTOCOL_VERSION);
+            }
+            _ => panic!("Expected HelloOk message"),
+        }
+        assert!(node.speaks("n2", PROTOCOL_VERSION));
+        assert!(!node.speaks("n3", PROTOCOL_VERSION));
+    }
+
+    #[test]
+    fn test_envelope_version_is_recorded_for_peers_only() {
+        let mut node = Node::new();
+        node.begin(
+            "n2",
+            Envelope {
+                proto: Some(1),
+                ..Envelope::default()
+            },
+        );
+        node.begin(
+            "c1",
+            Envelope {
+                proto: Some(1),
+                ..Envelope::default()
+            },
+        );
+        assert_eq!(node.peer_version("n2"), Some(1));
+        assert_eq!(node.peer_version("c1"), None);
+    }
+
+    #[test]
+    fn test_compact_only_for_peers_that_understand_it() {
+        let mut node = Node::new();
+        node.handle_init("n1".to_string(), vec!["n1".to_string(), "n2".to_string()]);
+        node.record_version("n2", compact::PACKED_SINCE);
+        node.record_version("n3", 1);
+        let to = |dest: &str| Message {
+            src: "n1".to_string(),
+            dest: dest.to_string(),
+            body: MessageBody::BroadcastGossip {
+                msg_id: None,
+                messages: vec![1, 2, 3],
+            },
+        };
+
+        assert!(node.packed(&to("n2")).is_none());
+
+        node.compact = true;
+        assert!(matches!(
+            node.packed(&to("n2")).map(|m| m.body),
+            Some(MessageBody::Packed { .. })
+        ));
+        assert!(node.packed(&to("n3")).is_none());
+        assert!(node.packed(&to("c1")).is_none());
+    }
+
+    struct Recorder {
+        replies: Vec<(u64, Option<u64>)>,
+    }
+
+    impl MessageHandler for Recorder {
+        fn handle(&mut self, _node: &mut Node, _message: Message) -> Vec<Message> {
+            Vec::new()
+        }
+
+        fn on_rpc_reply(
+            &mut self,
+            _node: &mut Node,
+            mut call: Call,
+            _reply: Message,
+        ) -> Vec<Message> {
+            self.replies.push((call.msg_id, call.context::<u64>()));
+            Vec::new()
+        }
+    }
+
+    #[test]
+    fn test_rpc_refuses_a_body_no_reply_could_answer() {
+        let mut node = Node::new();
+        node.handle_init("n1".to_string(), vec!["n1".to_string(), "n2".to_string()]);
+        let err = node
+            .rpc("n2".to_string(), MessageBody::Ack { in_reply_to: 1 })
+            .unwrap_err();
+        assert_eq!(err.type_name, "ack");
+        assert!(node.calls.is_empty());
+    }
+
+    #[test]
+    fn test_rpc_replies_are_routed_by_in_reply_to() {
+        let mut node = Node::new();
+        node.handle_init("n1".to_string(), vec!["n1".to_string(), "n2".to_string()]);
+        let mut handler = Recorder { replies: vec![] };
+
+        let request = node
+            .rpc_with(
+                "n2".to_string(),
+                MessageBody::Replicate {
+                    msg_id: 0,
+                    key: "k1".to_string(),
+                    msg: 5,
+                    offset: 7,
+                },
+                7u64,
+            )
+            .unwrap();
+        let msg_id = request.body.msg_id().unwrap();
+        assert_eq!(node.calls.len(), 1);
+
+        let ack = |in_reply_to| Message {
+            src: "n2".to_string(),
+            dest: "n1".to_string(),
+            body: MessageBody::ReplicateOk {
+                msg_id: 1,
+                in_reply_to,
+                key: "k1".to_string(),
+                offset: 7,
+            },
+        };
+        process(&mut handler, &mut node, ack(msg_id));
+        assert_eq!(handler.replies, vec![(msg_id, Some(7))]);
+        assert!(node.calls.is_empty());
+
+        // A duplicate reply no longer matches a call and falls through to `handle`
+        process(&mut handler, &mut node, ack(msg_id));
+        assert_eq!(handler.replies.len(), 1);
+    }
+
+    #[test]
+    fn test_send_is_untracked() {
+        let mut node = Node::new();
+        node.handle_init("n1".to_string(), vec!["n1".to_string(), "n2".to_string()]);
+
+        let gossip = node.send(
+            "n2".to_string(),
+            MessageBody::BroadcastGossip {
+                msg_id: None,
+                messages: vec![1],
+            },
+        );
+        assert_eq!(gossip.body.msg_id(), None);
+        assert!(node.calls.is_empty());
+        assert_eq!(node.msg_id, 0);
+    }
+
+    #[test]
+    fn test_exhausted_rpc_surfaces_timeout() {
+        let mut node = Node::new();
+        node.handle_init("n1".to_string(), vec!["n1".to_string(), "n2".to_string()]);
+        node.config.retry = RetryPolicy {
+            max_attempts: 2,
+            jitter: 0.0,
+            ..RetryPolicy::default()
+        };
+        let mut handler = Recorder { replies: vec![] };
+
+        let request = node
+            .rpc_with(
+                "n2".to_string(),
+                MessageBody::Read {
+                    msg_id: 0,
+                    key: None,
+                    context: None,
+                },
+                3u64,
+            )
+            .unwrap();
+        let msg_id = request.body.msg_id().unwrap();
+        let start = Instant::now();
+        let first = start + node.config.retry.backoff(1);
+
+        let resent = poll_calls(&mut handler, &mut node, first);
+        assert_eq!(resent.len(), 1);
+        assert_eq!(resent[0].body.msg_id(), Some(msg_id));
+
+        let later = first + node.config.retry.backoff(2);
+        assert!(poll_calls(&mut handler, &mut node, later).is_empty());
+        assert_eq!(handler.replies, vec![(msg_id, Some(3))]);
+        assert!(node.calls.is_empty());
+    }
+
+    /// Notes the trace current as each call's reply or timeout is handled
+    #[derive(Default)]
+    struct Traces {
+        seen: Vec<Option<String>>,
+    }
+
+    impl MessageHandler for Traces {
+        fn handle(&mut self, _node: &mut Node, _message: Message) -> Vec<Message> {
+            Vec::new()
+        }
+
+        fn on_rpc_reply(&mut self, node: &mut Node, _call: Call, _reply: Message) -> Vec<Message> {
+            self.seen.push(node.trace.clone());
+            Vec::new()
+        }
+
+        fn on_rpc_timeout(&mut self, node: &mut Node, _call: Call) -> Vec<Message> {
+            self.seen.push(node.trace.clone());
+            Vec::new()
+        }
+    }
+
+    #[test]
+    fn test_rpc_replies_and_timeouts_continue_the_callers_trace() {
+        let mut node = Node::new();
+        node.handle_init("n1".to_string(), vec!["n1".to_string()]);
+        node.config.retry = RetryPolicy::once(Duration::ZERO);
+        let mut handler = Traces::default();
+        let read = || MessageBody::Read {
+            msg_id: 0,
+            key: None,
+            context: None,
+        };
+
+        // A client's request starts n1-1, which makes two calls to lin-kv
+        node.begin("c1", Envelope::default());
+        let answered = node.rpc("lin-kv".to_string(), read()).unwrap();
+        node.rpc("lin-kv".to_string(), read()).unwrap();
+
+        let reply = Message {
+            src: "lin-kv".to_string(),
+            dest: "n1".to_string(),
+            body: MessageBody::WriteOk {
+                msg_id: 1,
+                in_reply_to: answered.body.msg_id().unwrap(),
+            },
+        };
+        node.begin(&reply.src, Envelope::default());
+        assert_eq!(node.trace.as_deref(), Some("n1-2"));
+        process(&mut handler, &mut node, reply);
+
+        node.trace = None;
+        poll_calls(&mut handler, &mut node, Instant::now());
+        let caller = Some("n1-1".to_string());
+        assert_eq!(handler.seen, vec![caller.clone(), caller]);
+    }
+
+    struct Fallible;
+
+    impl MessageHandler for Fallible {
+        fn handle(&mut self, node: &mut Node, message: Message) -> Vec<Message> {
+            let (src, msg_id) = (message.src.clone(), message.body.msg_id());
+            let result = self.try_handle(node, message);
+            node.respond(src, msg_id, result)
+        }
+
+        fn try_handle(
+            &mut self,
+            _node: &mut Node,
+            message: Message,
+        ) -> Result<Vec<Message>, MaelstromError> {
+            match message.body {
+                MessageBody::Read { .. } => Err(MaelstromError::key_does_not_exist("nothing here")),
+                _ => Ok(Vec::new()),
+            }
+        }
+    }
+
+    /// Answers only through `try_handle`; its `handle` drops failures
+    struct Swallowing;
+
+    impl MessageHandler for Swallowing {
+        fn handle(&mut self, node: &mut Node, message: Message) -> Vec<Message> {
+            Fallible.try_handle(node, message).unwrap_or_default()
+        }
+
+        fn try_handle(
+            &mut self,
+            node: &mut Node,
+            message: Message,
+        ) -> Result<Vec<Message>, MaelstromError> {
+            Fallible.try_handle(node, message)
+        }
+    }
+
+    #[test]
+    fn test_try_handle_errors_become_error_replies() {
+        let mut node = Node::new();
+        node.handle_init("n1".to_string(), vec!["n1".to_string()]);
+        let read = Message {
+            src: "c4".to_string(),
+            dest: "n1".to_string(),
+            body: MessageBody::Read {
+                msg_id: 12,
+                key: None,
+                context: None,
+            },
+        };
+
+        // The framework answers through `try_handle`, even for a handler whose `handle` would
+        // drop the failure
+        let answers = [
+            process(&mut Fallible, &mut node, read.clone()),
+            process(&mut Swallowing, &mut node, read),
+        ];
+        for out in answers {
+            assert_eq!(out.len(), 1);
+            assert_eq!(out[0].dest, "c4");
+            match &out[0].body {
+                MessageBody::Error {
+                    in_reply_to, code, ..
+                } => {
+                    assert_eq!(*in_reply_to, 12);
+                    assert_eq!(*code, ErrorCode::KeyDoesNotExist);
+                }
+                _ => panic!("Expected Error message"),
+            }
+        }
+
+        // Fire-and-forget messages have nobody to answer
+        let gossip = Message {
+            src: "n2".to_string(),
+            dest: "n1".to_string(),
+            body: MessageBody::BroadcastGossip {
+                msg_id: None,
+                messages: vec![],
+            },
+        };
+        assert!(
+            node.respond(
+                "n2".to_string(),
+                gossip.body.msg_id(),
+                Err(MaelstromError::crash("boom"))
+            )
+            .is_empty()
+        );
+    }
+
+    #[test]
+    fn test_redelivered_requests_are_dropped_when_dedup_is_on() {
+        let mut node = Node::with_config(NodeConfig::new().dedup(16));
+        node.handle_init("n1".to_string(), vec!["n1".to_string()]);
+        let read = |src: &str, msg_id| Message {
+            src: src.to_string(),
+            dest: "n1".to_string(),
+            body: MessageBody::Read {
+                msg_id,
+                key: None,
+                context: None,
+            },
+        };
+
+        assert_eq!(process(&mut Fallible, &mut node, read("c1", 5)).len(), 1);
+        assert!(process(&mut Fallible, &mut node, read("c1", 5)).is_empty());
+        // Same msg_id from another client is a different request
+        assert_eq!(process(&mut Fallible, &mut node, read("c2", 5)).len(), 1);
+
+        // Off by default
+        let mut node = Node::new();
+        node.handle_init("n1".to_string(), vec!["n1".to_string()]);
+        assert_eq!(process(&mut Fallible, &mut node, read("c1", 5)).len(), 1);
+        assert_eq!(process(&mut Fallible, &mut node, read("c1", 5)).len(), 1);
+    }
+
+    /// Counts the adds it applies
+    #[derive(Default)]
+    struct Adder {
+        applied: usize,
+    }
+
+    impl MessageHandler for Adder {
+        fn handle(&mut self, node: &mut Node, message: Message) -> Vec<Message> {
+            if !matches!(message.body, MessageBody::Add { .. }) {
+                return Quiet.handle(node, message);
+            }
+            self.applied += 1;
+            vec![
+                node.reply_to(&message, |msg_id, in_reply_to| MessageBody::AddOk {
+                    msg_id,
+                    in_reply_to,
+                }),
+            ]
+        }
+    }
+
+    #[tokio::test]
+    async fn test_redelivered_requests_get_the_cached_reply() {
+        let mut node = Node::with_config(NodeConfig::new().dedup(16));
+        let (writer, _task) = crate::writer::spawn_writer(io::sink(), Default::default());
+        node.attach_writer(writer);
+        let mut handler = Adder::default();
+        process(&mut handler, &mut node, crate::testing::init("n1", ["n1"]));
+
+        let add = crate::testing::add(3).with_msg_id(5);
+        let out = process(&mut handler, &mut node, add.clone());
+        let answered = out[0].body.msg_id();
+        // Not written yet, so there is nothing to replay
+        assert!(process(&mut handler, &mut node, add.clone()).is_empty());
+
+        emit(&mut handler, &mut node, out);
+        let replayed = process(&mut handler, &mut node, add);
+        assert_eq!(replayed.len(), 1);
+        assert_eq!(replayed[0].dest, "c1");
+        assert_eq!(replayed[0].body.msg_id(), answered);
+        assert_eq!(replayed[0].body.in_reply_to(), Some(5));
+        assert_eq!(handler.applied, 1);
+    }
+
+    #[test]
+    fn test_deferred_deliveries_wait_in_the_outbox() {
+        let mut node = Node::new();
+        node.handle_init("n1".to_string(), vec!["n1".to_string(), "n2".to_string()]);
+        let gossip = |msg_id| Message {
+            src: "n1".to_string(),
+            dest: "n2".to_string(),
+            body: MessageBody::BroadcastGossip {
+                msg_id: Some(msg_id),
+                messages: vec![1],
+            },
+        };
+
+        let sent = node.accept_deferred(Deferral {
+            messages: vec![gossip(0)],
+            deliver: false,
+        });
+        assert!(node.outbox.is_empty());
+        let delivered = node.accept_deferred(Deferral {
+            messages: vec![gossip(0)],
+            deliver: true,
+        });
+        assert_ne!(delivered[0].body.msg_id(), sent[0].body.msg_id());
+        assert!(node.outbox.holds(&delivered[0]));
+
+        // Fire-and-forget bodies are given an id to be acknowledged by; bodies that cannot
+        // carry one still go out
+        let mut untracked = gossip(0);
+        untracked.body = MessageBody::BroadcastGossip {
+            msg_id: None,
+            messages: vec![2],
+        };
+        let ack = Message {
+            body: MessageBody::Ack { in_reply_to: 3 },
+            ..gossip(0)
+        };
+        let delivered = node.accept_deferred(Deferral {
+            messages: vec![untracked, ack],
+            deliver: true,
+        });
+        assert_eq!(delivered.len(), 2);
+        assert!(delivered[0].body.msg_id().is_some());
+        assert!(node.outbox.holds(&delivered[0]));
+        assert!(!node.outbox.holds(&delivered[1]));
+    }
+
+    #[test]
+    fn test_deliveries_are_acked_and_released() {
+        let mut sender = Node::new();
+        sender.handle_init("n1".to_string(), vec!["n1".to_string(), "n2".to_string()]);
+        let mut receiver = Node::with_config(NodeConfig::new().dedup(16));
+        receiver.handle_init("n2".to_string(), vec!["n1".to_string(), "n2".to_string()]);
+
+        let gossip = sender.deliver(
+            "n2".to_string(),
+            MessageBody::BroadcastGossip {
+                msg_id: None,
+                messages: vec![1],
+            },
+        );
+        assert!(sender.outbox.holds(&gossip));
+
+        // The retransmission is a duplicate for the handler but still gets acked
+        let requested = Envelope {
+            ack: true,
+            ..Envelope::default()
+        };
+        for _ in 0..2 {
+            receiver.begin("n1", requested.clone());
+            let out = process(&mut Fallible, &mut receiver, gossip.clone());
+            assert_eq!(out.len(), 1);
+            match out[0].body {
+                MessageBody::Ack { in_reply_to } => {
+                    assert_eq!(Some(in_reply_to), gossip.body.msg_id())
+                }
+                _ => panic!("Expected Ack message"),
+            }
+        }
+
+        let ack = process(&mut Fallible, &mut receiver, gossip.clone());
+        assert!(ack.is_empty(), "no ack without a request for one");
+
+        receiver.begin("n1", requested);
+        let ack = process(&mut Fallible, &mut receiver, gossip.clone()).remove(0);
+        assert!(process(&mut Fallible, &mut sender, ack).is_empty());
+        assert!(sender.outbox.is_empty());
+    }
+
+    #[derive(Default)]
+    struct Observer {
+        inits: Vec<String>,
+        received: Vec<String>,
+        sent: Vec<String>,
+        changes: Vec<MembershipChange>,
+    }
+
+    impl MessageHandler for Observer {
+        fn handle(&mut self, node: &mut Node, message: Message) -> Vec<Message> {
+            match message.body {
+                MessageBody::Init {
+                    msg_id,
+                    node_id,
+                    node_ids,
+                } => {
+                    node.handle_init(node_id, node_ids);
+                    vec![node.init_ok(message.src, msg_id)]
+                }
+                _ => Vec::new(),
+            }
+        }
+
+        fn on_node_init(&mut self, node: &Node) {
+            self.inits.push(node.id.clone());
+        }
+
+        fn on_message(&mut self, _node: &Node, message: &Message) {
+            self.received.push(message.body.type_name().to_string());
+        }
+
+        fn on_send(&mut self, _node: &Node, message: &Message) {
+            self.sent.push(message.dest.clone());
+        }
+
+        fn on_membership_change(
+            &mut self,
+            _node: &mut Node,
+            change: &MembershipChange,
+        ) -> Vec<Message> {
+            self.changes.push(change.clone());
+            Vec::new()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_lifecycle_hooks_observe_init_and_traffic() {
+        let mut handler = Observer::default();
+        let mut node = Node::new();
+        let (writer, _task) = crate::writer::spawn_writer(io::sink(), Default::default());
+        node.attach_writer(writer);
+
+        let hello = Message {
+            src: "n2".to_string(),
+            dest: "n1".to_string(),
+            body: MessageBody::Hello {
+                msg_id: 1,
+                version: PROTOCOL_VERSION,
+            },
+        };
+        assert!(process(&mut handler, &mut node, hello).is_empty());
+        assert!(handler.inits.is_empty());
+
+        let init = crate::testing::init("n1", ["n1", "n2"]);
+        let out = process(&mut handler, &mut node, init);
+        assert_eq!(handler.inits, vec!["n1"]);
+        assert_eq!(handler.received, vec!["hello", "init"]);
+
+        emit(&mut handler, &mut node, out);
+        assert_eq!(handler.sent, vec!["c1", "n2"]);
+    }
+
+    #[test]
+    fn test_stats_reports_traffic() {
+        let mut node = Node::new();
+        node.handle_init("n1".to_string(), vec!["n1".to_string()]);
+        let read = crate::testing::read();
+        process(&mut Fallible, &mut node, read.clone());
+        process(&mut Fallible, &mut node, read.with_msg_id(2));
+
+        let stats = Message {
+            src: "c9".to_string(),
+            dest: "n1".to_string(),
+            body: MessageBody::Stats { msg_id: 3 },
+        };
+        let out = process(&mut Fallible, &mut node, stats);
+        assert_eq!(out[0].dest, "c9");
+        match &out[0].body {
+            MessageBody::StatsOk {
+                in_reply_to, stats, ..
+            } => {
+                assert_eq!(*in_reply_to, 3);
+                assert_eq!(stats["received"]["read"], 2);
+                assert_eq!(stats["received"]["stats"], 1);
+                assert_eq!(stats["handle_latency_us"]["count"], 2);
+            }
+            _ => panic!("Expected StatsOk message"),
+        }
+    }
+
+    #[test]
+    fn test_send_after_holds_message_until_due() {
+        let mut node = Node::new();
+        node.handle_init("n1".to_string(), vec!["n1".to_string(), "n2".to_string()]);
+        let before = Instant::now();
+        let gossip = node.send(
+            "n2".to_string(),
+            MessageBody::BroadcastGossip {
+                msg_id: None,
+                messages: vec![1],
+            },
+        );
+        let id = node.send_after(Duration::from_millis(200), gossip.clone());
+        node.send_after(Duration::from_millis(200), gossip);
+
+        assert!(node.timers.poll(before).is_empty());
+        assert!(node.timers.cancel(id));
+        let due = node.timers.next_due().unwrap();
+        assert!(due >= before + Duration::from_millis(200));
+        assert_eq!(node.timers.poll(due).len(), 1);
+    }
+
+    /// Gossips to `n2` for every read it is asked
+    struct Gossiping;
+
+    impl MessageHandler for Gossiping {
+        fn handle(&mut self, node: &mut Node, message: Message) -> Vec<Message> {
+            match message.body {
+                MessageBody::Init {
+                    msg_id,
+                    node_id,
+                    node_ids,
+                } => {
+                    node.handle_init(node_id, node_ids);
+                    vec![node.init_ok(message.src, msg_id)]
+                }
+                MessageBody::Read { .. } => vec![node.send(
+                    "n2".to_string(),
+                    MessageBody::BroadcastGossip {
+                        msg_id: None,
+                        messages: vec![1],
+                    },
+                )],
+                _ => Vec::new(),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_held_gossip_is_written_once_the_window_passes() {
+        use tokio::io::AsyncWriteExt;
+        use tokio::net::TcpStream;
+
+        let addr = std::net::TcpListener::bind("127.0.0.1:0")
+            .and_then(|listener| listener.local_addr())
+            .unwrap();
+        let config = NodeConfig::new()
+            .coalesce_window(Duration::from_millis(10))
+            .transport(Transport::Tcp(addr));
+        let node_task = tokio::spawn(run_node_with_config(Gossiping, config));
+
+        let stream = loop {
+            match TcpStream::connect(addr).await {
+                Ok(stream) => break stream,
+                Err(_) => time::sleep(Duration::from_millis(5)).await,
+            }
+        };
+        let (input, mut output) = stream.into_split();
+        let mut lines = BufReader::new(input).lines();
+        let init = crate::testing::init("n1", ["n1"]);
+        for message in [init, crate::testing::read()] {
+            let line = trace::encode(&message, &Envelope::default()).unwrap();
+            output.write_all(&line).await.unwrap();
+        }
+
+        let mut types = Vec::new();
+        while types.len() < 2 {
+            let line = time::timeout(Duration::from_secs(2), lines.next_line())
+                .await
+                .expect("held gossip was never written")
+                .unwrap()
+                .unwrap();
+            let written: serde_json::Value = serde_json::from_str(&line).unwrap();
+            types.push(written["body"]["type"].as_str().unwrap().to_string());
+        }
+        assert_eq!(types, vec!["init_ok", "broadcast_gossip"]);
+        node_task.abort();
+    }
+
+    #[tokio::test]
+    async fn test_waiting_client_requests_go_first() {
+        let (client_tx, mut clients) = mpsc::channel(8);
+        let (peer_tx, mut peers) = mpsc::channel(8);
+        let gossip = Message {
+            src: "n2".to_string(),
+            dest: "n1".to_string(),
+            body: MessageBody::BroadcastGossip {
+                msg_id: None,
+                messages: vec![1],
+            },
+        };
+        for _ in 0..2 {
+            peer_tx
+                .send((gossip.clone(), Envelope::default()))
+                .await
+                .unwrap();
+        }
+        client_tx
+            .send((crate::testing::read(), Envelope::default()))
+            .await
+            .unwrap();
+
+        let order: Vec<String> = [
+            next_inbound(&mut clients, Some(&mut peers)).await,
+            next_inbound(&mut clients, Some(&mut peers)).await,
+            next_inbound(&mut clients, Some(&mut peers)).await,
+        ]
+        .into_iter()
+        .map(|inbound| inbound.unwrap().0.src)
+        .collect();
+        assert_eq!(order, vec!["c1", "n2", "n2"]);
+
+        drop((client_tx, peer_tx));
+        assert!(next_inbound(&mut clients, Some(&mut peers)).await.is_none());
+    }
+
+    #[test]
+    fn test_client_requests_are_shed_over_the_threshold() {
+        let mut node = Node::with_config(NodeConfig::new().shed_above(10));
+        node.handle_init("n1".to_string(), vec!["n1".to_string(), "n2".to_string()]);
+        let read = crate::testing::read();
+
+        assert!(node.shed(&read, 10).is_none());
+        let refusal = node.shed(&read, 11).unwrap();
+        assert_eq!(refusal.dest, "c1");
+        match refusal.body {
+            MessageBody::Error {
+                in_reply_to, code, ..
+            } => {
+                assert_eq!(in_reply_to, 1);
+                assert_eq!(code, ErrorCode::TemporarilyUnavailable);
+            }
+            _ => panic!("Expected Error message"),
+        }
+        assert_eq!(node.metrics.shed, 1);
+
+        // Peer traffic and init are never shed
+        assert!(node.shed(&read.clone().src("n2"), 100).is_none());
+        assert!(
+            node.shed(&crate::testing::init("n1", ["n1"]), 100)
+                .is_none()
+        );
+        assert!(Node::new().shed(&read, 100).is_none());
+    }
+
+    #[test]
+    fn test_repeated_init_is_idempotent() {
+        let mut handler = Observer::default();
+        let mut node = Node::new();
+        process(
+            &mut handler,
+            &mut node,
+            crate::testing::init("n1", ["n1", "n2"]),
+        );
+
+        // The same init again is acknowledged without re-running the handler
+        let out = process(
+            &mut handler,
+            &mut node,
+            crate::testing::init("n1", ["n1", "n2"]),
+        );
+        assert_eq!(out.len(), 1);
+        assert!(matches!(out[0].body, MessageBody::InitOk { .. }));
+        assert_eq!(handler.inits, vec!["n1"]);
+        assert!(handler.changes.is_empty());
+
+        // A different cluster is a membership change
+        let out = process(
+            &mut handler,
+            &mut node,
+            crate::testing::init("n1", ["n1", "n3"]),
+        );
+        assert_eq!(node.peers, vec!["n3"]);
+        assert_eq!(
+            handler.changes,
+            vec![MembershipChange {
+                added: vec!["n3".to_string()],
+                removed: vec!["n2".to_string()],
+            }]
+        );
+        assert!(matches!(out[0].body, MessageBody::Hello { .. }));
+        assert_eq!(out[0].dest, "n3");
+        assert!(matches!(out[1].body, MessageBody::InitOk { .. }));
+
+        // But the node cannot be renamed
+        let out = process(&mut handler, &mut node, crate::testing::init("n2", ["n2"]));
+        match &out[0].body {
+            MessageBody::Error { code, .. } => assert_eq!(*code, ErrorCode::PreconditionFailed),
+            _ => panic!("Expected Error message"),
+        }
+        assert_eq!(node.id, "n1");
+    }
+
+    #[test]
+    fn test_membership_message_updates_peers() {
+        let mut handler = Observer::default();
+        let mut node = Node::new();
+        process(
+            &mut handler,
+            &mut node,
+            crate::testing::init("n1", ["n1", "n2", "n3"]),
+        );
+        node.deliver(
+            "n3".to_string(),
+            MessageBody::Read {
+                msg_id: 0,
+                key: None,
+                context: None,
+            },
+        );
+
+        let membership = crate::testing::membership(["n4", "n2", "n1"], ["n3", "n5"]);
+        let out = process(&mut handler, &mut node, membership);
+        assert_eq!(node.peers, vec!["n2", "n4"]);
+        assert_eq!(
+            handler.changes,
+            vec![MembershipChange {
+                added: vec!["n4".to_string()],
+                removed: vec!["n3".to_string()],
+            }]
+        );
+        // Deliveries to the departed peer are abandoned
+        assert_eq!(node.outbox.pending("n3"), 0);
+        assert_eq!(out.len(), 2);
+        assert_eq!(out[0].dest, "n4");
+        match out[1].body {
+            MessageBody::MembershipOk { in_reply_to, .. } => assert_eq!(in_reply_to, 1),
+            _ => panic!("Expected MembershipOk message"),
+        }
+
+        // Nothing new, nothing to tell the handler
+        process(
+            &mut handler,
+            &mut node,
+            crate::testing::membership(["n4"], []),
+        );
+        assert_eq!(handler.changes.len(), 1);
+    }
+
+    #[test]
+    fn test_alive_peers_follow_recent_traffic() {
+        let mut node = Node::with_config(
+            NodeConfig::new()
+                .suspect_after(Duration::from_millis(50))
+                .ping_interval(Duration::from_millis(20)),
+        );
+        node.handle_init(
+            "n1".to_string(),
+            vec!["n1".to_string(), "n2".to_string(), "n3".to_string()],
+        );
+        // Everyone gets the benefit of the doubt right after init
+        assert_eq!(node.alive_peers(), vec!["n2", "n3"]);
+
+        let now = Instant::now();
+        node.liveness
+            .reset(now.checked_sub(Duration::from_millis(100)).unwrap());
+        let ok = Message {
+            src: "n2".to_string(),
+            dest: "n1".to_string(),
+            body: MessageBody::HelloOk {
+                msg_id: 1,
+                in_reply_to: 1,
+                version: PROTOCOL_VERSION,
+            },
+        };
+        process(&mut Quiet, &mut node, ok);
+        assert_eq!(node.alive_peers(), vec!["n2"]);
+
+        // Only the quiet peer is pinged
+        let pings = node.pings(Instant::now());
+        assert_eq!(pings.len(), 1);
+        assert_eq!(pings[0].dest, "n3");
+        assert!(matches!(pings[0].body, MessageBody::Hello { .. }));
+        assert!(Node::new().pings(now).is_empty());
+    }
+
+    /// Counts failed calls and the circuits they open
+    #[derive(Default)]
+    struct Tripwire {
+        timeouts: usize,
+        opened: Vec<String>,
+    }
+
+    impl MessageHandler for Tripwire {
+        fn handle(&mut self, _node: &mut Node, _message: Message) -> Vec<Message> {
+            Vec::new()
+        }
+
+        fn on_rpc_timeout(&mut self, _node: &mut Node, _call: Call) -> Vec<Message> {
+            self.timeouts += 1;
+            Vec::new()
+        }
+
+        fn on_circuit_open(&mut self, _node: &mut Node, peer: &str) -> Vec<Message> {
+            self.opened.push(peer.to_string());
+            Vec::new()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_open_circuit_fails_calls_fast_and_drops_traffic() {
+        let mut node = Node::with_config(
+            NodeConfig::new()
+                .retry(RetryPolicy::once(Duration::from_millis(100)))
+                .breaker(2, Duration::from_secs(60)),
+        );
+        let (writer, _task) = crate::writer::spawn_writer(io::sink(), Default::default());
+        node.attach_writer(writer);
+        node.handle_init("n1".to_string(), vec!["n1".to_string(), "n2".to_string()]);
+        let mut handler = Tripwire::default();
+
+        node.rpc(
+            "n2".to_string(),
+            MessageBody::Read {
+                msg_id: 0,
+                key: None,
+                context: None,
+            },
+        )
+        .unwrap();
+        node.rpc(
+            "n2".to_string(),
+            MessageBody::Read {
+                msg_id: 0,
+                key: None,
+                context: None,
+            },
+        )
+        .unwrap();
+        let later = Instant::now() + Duration::from_secs(1);
+        poll_calls(&mut handler, &mut node, later);
+        assert_eq!(handler.timeouts, 2);
+        assert_eq!(handler.opened, vec!["n2"]);
+
+        // New calls fail at the next poll without waiting out a timeout
+        let request = node
+            .rpc(
+                "n2".to_string(),
+                MessageBody::Read {
+                    msg_id: 0,
+                    key: None,
+                    context: None,
+                },
+            )
+            .unwrap();
+        assert!(node.calls.next_deadline().unwrap() <= Instant::now());
+        poll_calls(&mut handler, &mut node, Instant::now());
+        assert_eq!(handler.timeouts, 3);
+        assert_eq!(handler.opened.len(), 1);
+
+        // Nothing but probes reaches the peer
+        let hello = node.hello("n2".to_string());
+        emit(&mut handler, &mut node, vec![request, hello]);
+        assert_eq!(node.metrics.circuit_dropped, 1);
+        assert_eq!(node.metrics.sent.get("hello"), Some(&1));
+    }
+
+    #[test]
+    fn test_seeded_nodes_repeat_their_choices() {
+        let draws = |seed, id: &str| {
+            let mut node = Node::with_config(NodeConfig::new().seed(seed));
+            node.handle_init(id.to_string(), vec![id.to_string()]);
+            (0..4).map(|_| node.rng.random::<u64>()).collect::<Vec<_>>()
+        };
+        assert_eq!(draws(7, "n1"), draws(7, "n1"));
+        assert_ne!(draws(7, "n1"), draws(7, "n2"));
+        assert_ne!(draws(7, "n1"), draws(8, "n1"));
+    }
+
+    #[test]
+    fn test_misrouted_messages_follow_the_policy() {
+        let handled = |policy| {
+            let mut node = Node::with_config(NodeConfig::new().misroute(policy));
+            let mut handler = Adder::default();
+            process(&mut handler, &mut node, crate::testing::init("n1", ["n1"]));
+            let out = process(&mut handler, &mut node, crate::testing::add(1).dest("n9"));
+            (handler.applied, out)
+        };
+
+        assert_eq!(handled(MisroutePolicy::Accept).0, 1);
+        assert_eq!(handled(MisroutePolicy::Warn).0, 1);
+        let (applied, out) = handled(MisroutePolicy::Drop);
+        assert_eq!(applied, 0);
+        assert!(out.is_empty());
+        let (applied, out) = handled(MisroutePolicy::Reject);
+        assert_eq!(applied, 0);
+        match &out[0].body {
+            MessageBody::Error { code, .. } => assert_eq!(*code, ErrorCode::NodeNotFound),
+            _ => panic!("Expected Error message"),
+        }
+
+        // Before init there is no id to compare against
+        let mut node = Node::with_config(NodeConfig::new().misroute(MisroutePolicy::Reject));
+        let mut handler = Adder::default();
+        process(&mut handler, &mut node, crate::testing::add(1).dest("n9"));
+        assert_eq!(handler.applied, 1);
+    }
+
+    #[test]
+    fn test_reply_to_fills_in_both_ids() {
+        let mut node = Node::new();
+        node.handle_init("n1".to_string(), vec!["n1".to_string()]);
+        node.msg_id = 41;
+
+        let request = crate::testing::read().with_msg_id(7);
+        let reply = node.reply_to(&request, |msg_id, in_reply_to| MessageBody::ReadOk {
+            msg_id,
+            in_reply_to,
+            messages: None,
+            value: Some(3),
+            context: None,
+        });
+        assert_eq!(reply.src, "n1");
+        assert_eq!(reply.dest, "c1");
+        assert_eq!(reply.body.msg_id(), Some(42));
+        assert_eq!(reply.body.in_reply_to(), Some(7));
+    }
+}
@@ -0,0 +1,428 @@
+//! Clients for Maelstrom's built-in key-value services, `seq-kv` (sequentially consistent)
+//! and `lin-kv` (linearizable), with plain async `read`, `write` and `cas` methods.
+//!
+//! Handlers answer one message at a time, so calls are made from tasks spawned off the loop:
+//! requests go out through the node's [`Deferred`] handle, and the handler hands every
+//! message to [`KvClient::resolve`] first so replies find their way back.
+//!
+//! ```ignore
+//! fn on_node_init(&mut self, node: &Node) {
+//!     self.kv = SeqKv::connect(node);
+//! }
+//!
+//! fn handle(&mut self, node: &mut Node, message: Message) -> Vec<Message> {
+//!     if self.kv.as_ref().is_some_and(|kv| kv.resolve(&message)) {
+//!         return Vec::new();
+//!     }
+//!     ...
+//! }
+//!
+//! // In a spawned task
+//! let total = kv.cas_loop("total", |total| total.unwrap_or(0) + delta).await?;
+//! ```
+
+use crate::rpc::RetryPolicy;
+use crate::{Deferred, ErrorCode, MaelstromError, Message, MessageBody, Node};
+use std::collections::HashMap;
+use std::marker::PhantomData;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::oneshot;
+
+/// Which service a [`KvClient`] talks to
+pub trait Service {
+    /// Node id the service answers as
+    const NAME: &'static str;
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Seq;
+
+impl Service for Seq {
+    const NAME: &'static str = "seq-kv";
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Lin;
+
+impl Service for Lin {
+    const NAME: &'static str = "lin-kv";
+}
+
+pub type SeqKv = KvClient<Seq>;
+pub type LinKv = KvClient<Lin>;
+
+/// Requests awaiting a reply, by `msg_id`
+#[derive(Default)]
+struct Waiting {
+    msg_id: u64,
+    replies: HashMap<u64, oneshot::Sender<MessageBody>>,
+}
+
+/// A key-value service client; clones share their requests in flight. Its `msg_id`s are its
+/// own, so route all of a node's traffic to the service through one client.
+pub struct KvClient<S> {
+    node_id: String,
+    deferred: Deferred,
+    waiting: Arc<Mutex<Waiting>>,
+    timeout: Duration,
+    /// Backoff between `cas_loop` attempts that lost a race
+    contention: RetryPolicy,
+    service: PhantomData<S>,
+}
+
+impl<S> Clone for KvClient<S> {
+    fn clone(&self) -> Self {
+        Self {
+            node_id: self.node_id.clone(),
+            deferred: self.deferred.clone(),
+            waiting: Arc::clone(&self.waiting),
+            timeout: self.timeout,
+            contention: self.contention,
+            service: PhantomData,
+        }
+    }
+}
+
+impl<S: Service> KvClient<S> {
+    /// A client sending as `node`, which must have been initialized; `None` outside
+    /// `run_node`, where there is no loop to send through
+    pub fn connect(node: &Node) -> Option<Self> {
+        Some(Self {
+            node_id: node.id.clone(),
+            deferred: node.deferred()?,
+            waiting: Arc::default(),
+            timeout: Duration::from_secs(1),
+            contention: RetryPolicy {
+                max_attempts: 20,
+                initial_backoff: Duration::from_millis(5),
+                max_backoff: Duration::from_millis(200),
+                jitter: 0.5,
+                rtt_factor: None,
+                ..RetryPolicy::default()
+            },
+            service: PhantomData,
+        })
+    }
+
+    /// Give up on a reply after `timeout` (1 s by default)
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// How `cas_loop` backs off after losing a compare, and how many tries it gets
+    pub fn contention(mut self, policy: RetryPolicy) -> Self {
+        self.contention = policy;
+        self
+    }
+
+    /// Complete the call `message` answers; false if it is not a reply from the service to
+    /// one of ours, so the handler should go on with it
+    pub fn resolve(&self, message: &Message) -> bool {
+        if message.src != S::NAME {
+            return false;
+        }
+        let Some(in_reply_to) = message.body.in_reply_to() else {
+            return false;
+        };
+        let Some(reply) = self.waiting().replies.remove(&in_reply_to) else {
+            return false;
+        };
+        // The caller may have timed out and gone
+        let _ = reply.send(message.body.clone());
+        true
+    }
+
+    /// Value of `key`; `None` if it was never written
+    pub async fn read(&self, key: &str) -> Result<Option<u64>, MaelstromError> {
+        let body = MessageBody::Read {
+            msg_id: 0,
+            key: Some(key.to_string()),
+            context: None,
+        };
+        match self.call(body).await {
+            Ok(MessageBody::ReadOk { value, .. }) => Ok(value),
+            Err(e) if e.code == ErrorCode::KeyDoesNotExist => Ok(None),
+            Ok(other) => Err(unexpected(&other)),
+            Err(e) => Err(e),
+        }
+    }
+
+    pub async fn write(&self, key: &str, value: u64) -> Result<(), MaelstromError> {
+        let body = MessageBody::Write {
+            msg_id: 0,
+            key: key.to_string(),
+            value,
+        };
+        match self.call(body).await? {
+            MessageBody::WriteOk { .. } => Ok(()),
+            other => Err(unexpected(&other)),
+        }
+    }
+
+    /// Set `key` to `to` if it holds `from`, failing with `PreconditionFailed` if it does
+    /// not and `KeyDoesNotExist` if it is unset, unless `create` lets a missing key count as
+    /// holding `from`
+    pub async fn cas(
+        &self,
+        key: &str,
+        from: u64,
+        to: u64,
+        create: bool,
+    ) -> Result<(), MaelstromError> {
+        let body = MessageBody::Cas {
+            msg_id: 0,
+            key: key.to_string(),
+            from,
+            to,
+            create_if_not_exists: create,
+        };
+        match self.call(body).await? {
+            MessageBody::CasOk { .. } => Ok(()),
+            other => Err(unexpected(&other)),
+        }
+    }
+
+    /// Replace the value of `key` with `update` of it (`None` while unset), rereading and
+    /// retrying with growing waits whenever another writer gets in between; returns the value
+    /// written, or `PreconditionFailed` once the attempts run out
+    pub async fn cas_loop(
+        &self,
+        key: &str,
+        mut update: impl FnMut(Option<u64>) -> u64,
+    ) -> Result<u64, MaelstromError> {
+        let mut attempt = 1;
+        loop {
+            let current = self.read(key).await?;
+            let next = update(current);
+            match self
+                .cas(key, current.unwrap_or(0), next, current.is_none())
+                .await
+            {
+                Ok(()) => return Ok(next),
+                // Someone else wrote, or created the key, since the read
+                Err(e)
+                    if matches!(
+                        e.code,
+                        ErrorCode::PreconditionFailed | ErrorCode::KeyDoesNotExist
+                    ) && attempt < self.contention.max_attempts =>
+                {
+                    let policy = self.contention;
+                    let wait = policy.wait(policy.initial_backoff, attempt, &mut rand::rng());
+                    tokio::time::sleep(wait).await;
+                    attempt += 1;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Send `body` and wait for its reply, turning `error` replies and silence into errors
+    async fn call(&self, mut body: MessageBody) -> Result<MessageBody, MaelstromError> {
+        let (tx, rx) = oneshot::channel();
+        let msg_id = {
+            let mut waiting = self.waiting();
+            waiting.msg_id += 1;
+            let msg_id = waiting.msg_id;
+            waiting.replies.insert(msg_id, tx);
+            msg_id
+        };
+        body.set_msg_id(msg_id);
+        let request = Message {
+            src: self.node_id.clone(),
+            dest: S::NAME.to_string(),
+            body,
+        };
+        if !self.deferred.send(vec![request]) {
+            self.waiting().replies.remove(&msg_id);
+            return Err(MaelstromError::crash("the node has stopped"));
+        }
+
+        let reply = tokio::time::timeout(self.timeout, rx).await;
+        let Ok(Ok(reply)) = reply else {
+            self.waiting().replies.remove(&msg_id);
+            return Err(MaelstromError::timeout(format!(
+                "no reply from {} within {:?}",
+                S::NAME,
+                self.timeout
+            )));
+        };
+        match reply {
+            MessageBody::Error { code, text, .. } => {
+                Err(MaelstromError::new(code, text.unwrap_or_default()))
+            }
+            reply => Ok(reply),
+        }
+    }
+
+    fn waiting(&self) -> std::sync::MutexGuard<'_, Waiting> {
+        self.waiting
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+fn unexpected(reply: &MessageBody) -> MaelstromError {
+    MaelstromError::crash(format!("unexpected {} reply", reply.type_name()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Deferral;
+    use tokio::sync::mpsc;
+
+    fn connected() -> (SeqKv, mpsc::UnboundedReceiver<Deferral>) {
+        let (deferred, rx) = Deferred::channel();
+        let mut node = Node::new();
+        node.handle_init("n1".to_string(), vec!["n1".to_string()]);
+        node.attach_deferred(deferred);
+        (SeqKv::connect(&node).unwrap(), rx)
+    }
+
+    /// The reply `body` from the service to `request`
+    fn reply(request: &Message, mut body: MessageBody) -> Message {
+        if let MessageBody::Error { in_reply_to, .. } | MessageBody::ReadOk { in_reply_to, .. } =
+            &mut body
+        {
+            *in_reply_to = request.body.msg_id().unwrap();
+        }
+        Message {
+            src: request.dest.clone(),
+            dest: request.src.clone(),
+            body,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_calls_complete_with_the_services_reply() {
+        let (kv, mut requests) = connected();
+        let reader = kv.clone();
+        let read = tokio::spawn(async move { reader.read("x").await });
+
+        let request = requests.recv().await.unwrap().messages.remove(0);
+        assert_eq!(request.dest, "seq-kv");
+        assert!(matches!(
+            &request.body,
+            MessageBody::Read { key: Some(key), .. } if key == "x"
+        ));
+        let read_ok = MessageBody::ReadOk {
+            msg_id: 9,
+            in_reply_to: 0,
+            messages: None,
+            value: Some(4),
+            context: None,
+        };
+        assert!(kv.resolve(&reply(&request, read_ok)));
+        assert_eq!(read.await.unwrap(), Ok(Some(4)));
+
+        // Not ours: from someone else, or answering nothing we sent
+        let mut stray = reply(&request, MessageBody::Ack { in_reply_to: 1 });
+        assert!(!kv.resolve(&stray));
+        stray.src = "n2".to_string();
+        assert!(!kv.resolve(&stray));
+    }
+
+    #[tokio::test]
+    async fn test_error_replies_map_to_errors() {
+        let (kv, mut requests) = connected();
+        let error = |code| MessageBody::Error {
+            msg_id: 1,
+            in_reply_to: 0,
+            code,
+            text: Some("no".to_string()),
+            extra: None,
+        };
+
+        let reader = kv.clone();
+        let read = tokio::spawn(async move { reader.read("x").await });
+        let request = requests.recv().await.unwrap().messages.remove(0);
+        kv.resolve(&reply(&request, error(ErrorCode::KeyDoesNotExist)));
+        assert_eq!(read.await.unwrap(), Ok(None));
+
+        let swapper = kv.clone();
+        let cas = tokio::spawn(async move { swapper.cas("x", 1, 2, false).await });
+        let request = requests.recv().await.unwrap().messages.remove(0);
+        kv.resolve(&reply(&request, error(ErrorCode::PreconditionFailed)));
+        let e = cas.await.unwrap().unwrap_err();
+        assert_eq!(e.code, ErrorCode::PreconditionFailed);
+    }
+
+    /// Answer the next request from a store holding `value` under every key
+    async fn serve(
+        kv: &SeqKv,
+        requests: &mut mpsc::UnboundedReceiver<Deferral>,
+        value: &mut Option<u64>,
+    ) -> Message {
+        let request = requests.recv().await.unwrap().messages.remove(0);
+        let in_reply_to = request.body.msg_id().unwrap();
+        let body = match &request.body {
+            MessageBody::Read { .. } => MessageBody::ReadOk {
+                msg_id: 0,
+                in_reply_to,
+                messages: None,
+                value: *value,
+                context: None,
+            },
+            MessageBody::Cas { from, to, .. } if *value == Some(*from) => {
+                *value = Some(*to);
+                MessageBody::CasOk {
+                    msg_id: 0,
+                    in_reply_to,
+                }
+            }
+            _ => MessageBody::Error {
+                msg_id: 0,
+                in_reply_to,
+                code: ErrorCode::PreconditionFailed,
+                text: None,
+                extra: None,
+            },
+        };
+        kv.resolve(&reply(&request, body));
+        request
+    }
+
+    #[tokio::test]
+    async fn test_cas_loop_retries_until_its_compare_holds() {
+        let (kv, mut requests) = connected();
+        let kv = kv.contention(RetryPolicy {
+            initial_backoff: Duration::from_millis(1),
+            ..RetryPolicy::default()
+        });
+        let incrementer = kv.clone();
+        let increment = tokio::spawn(async move {
+            incrementer
+                .cas_loop("x", |value| value.unwrap_or(0) + 1)
+                .await
+        });
+
+        let mut value = Some(10);
+        serve(&kv, &mut requests, &mut value).await;
+        // Another writer gets in between the read and the compare
+        value = Some(20);
+        serve(&kv, &mut requests, &mut value).await;
+        serve(&kv, &mut requests, &mut value).await;
+        let cas = serve(&kv, &mut requests, &mut value).await;
+        assert!(matches!(
+            cas.body,
+            MessageBody::Cas {
+                from: 20,
+                to: 21,
+                ..
+            }
+        ));
+        assert_eq!(increment.await.unwrap(), Ok(21));
+        assert_eq!(value, Some(21));
+    }
+
+    #[tokio::test]
+    async fn test_unanswered_calls_time_out() {
+        let (kv, _requests) = connected();
+        let kv = kv.timeout(Duration::from_millis(50));
+        let e = kv.write("x", 1).await.unwrap_err();
+        assert_eq!(e.code, ErrorCode::Timeout);
+        assert!(kv.waiting().replies.is_empty());
+    }
+}
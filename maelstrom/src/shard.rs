@@ -0,0 +1,208 @@
+//! Opt-in multi-worker dispatch. Requests are spread over worker tasks by
+//! [`ShardedHandler::shard_key`]; messages with the same key always land on the same worker,
+//! so they are handled in arrival order. Init, the peer handshake, validation, and I/O stay
+//! on the main loop.
+
+use crate::{
+    Message, MessageBody,
+    runtime::{Deferred, MessageHandler, Node, run_node},
+};
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::sync::Arc;
+use tokio::sync::mpsc;
+
+/// Node identity as seen from a worker
+#[derive(Debug, Clone)]
+pub struct ShardContext {
+    pub id: String,
+    pub peers: Vec<String>,
+}
+
+impl ShardContext {
+    /// Message from this node to `dest`; leave the body's `msg_id` at 0 and the framework
+    /// assigns one when it is written
+    pub fn reply(&self, dest: String, body: MessageBody) -> Message {
+        Message {
+            src: self.id.clone(),
+            dest,
+            body,
+        }
+    }
+}
+
+/// A handler whose state is safe to share between workers (typically behind per-key locks)
+pub trait ShardedHandler: Send + Sync + 'static {
+    /// Messages with equal keys are handled in order by the same worker; defaults to the sender
+    fn shard_key(&self, message: &Message) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        message.src.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    /// Handle one request on a worker
+    fn handle(&self, context: &ShardContext, message: Message) -> Vec<Message>;
+}
+
+/// Adapts a [`ShardedHandler`] to the main loop: init is handled here, then every other
+/// message goes to its worker
+pub struct Sharded<H> {
+    handler: Arc<H>,
+    workers: usize,
+    context: Option<Arc<ShardContext>>,
+    shards: Vec<mpsc::UnboundedSender<Message>>,
+}
+
+impl<H: ShardedHandler> Sharded<H> {
+    pub fn new(handler: H, workers: usize) -> Self {
+        Self {
+            handler: Arc::new(handler),
+            workers: workers.max(1),
+            context: None,
+            shards: Vec::new(),
+        }
+    }
+
+    fn spawn_workers(&mut self, deferred: Deferred, context: Arc<ShardContext>) {
+        for _ in 0..self.workers {
+            let (tx, mut rx) = mpsc::unbounded_channel::<Message>();
+            let handler = Arc::clone(&self.handler);
+            let deferred = deferred.clone();
+            let context = Arc::clone(&context);
+            tokio::spawn(async move {
+                while let Some(message) = rx.recv().await {
+                    if !deferred.send(handler.handle(&context, message)) {
+                        break;
+                    }
+                }
+            });
+            self.shards.push(tx);
+        }
+    }
+}
+
+impl<H: ShardedHandler> MessageHandler for Sharded<H> {
+    fn handle(&mut self, node: &mut Node, message: Message) -> Vec<Message> {
+        if let MessageBody::Init {
+            msg_id,
+            node_id,
+            node_ids,
+        } = message.body
+        {
+            node.handle_init(node_id, node_ids);
+            let context = Arc::new(ShardContext {
+                id: node.id.clone(),
+                peers: node.peers.clone(),
+            });
+            // Outside the runtime's loop there is nothing to feed results back into, so
+            // messages are handled inline instead
+            if let Some(deferred) = node.deferred() {
+                self.spawn_workers(deferred, Arc::clone(&context));
+            }
+            self.context = Some(context);
+            return vec![node.init_ok(message.src, msg_id)];
+        }
+
+        let Some(context) = &self.context else {
+            node.log(&format!(
+                "dropping {} received before init",
+                message.body.type_name()
+            ));
+            return Vec::new();
+        };
+        if self.shards.is_empty() {
+            let mut out = self.handler.handle(context, message);
+            node.stamp(&mut out);
+            return out;
+        }
+        let shard = (self.handler.shard_key(&message) % self.shards.len() as u64) as usize;
+        if self.shards[shard].send(message).is_err() {
+            node.log(&format!("worker {shard} has stopped"));
+        }
+        Vec::new()
+    }
+}
+
+/// Run `handler` on `workers` worker tasks behind the usual stdin/stdout loop
+pub async fn run_sharded<H: ShardedHandler>(handler: H, workers: usize) {
+    run_node(Sharded::new(handler, workers)).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+    use std::sync::Mutex;
+
+    /// Answers every request and remembers the order each was handled in
+    #[derive(Default)]
+    struct Recorder {
+        handled: Mutex<Vec<(String, u64)>>,
+    }
+
+    impl ShardedHandler for Recorder {
+        fn handle(&self, context: &ShardContext, message: Message) -> Vec<Message> {
+            let in_reply_to = message.body.msg_id().unwrap_or_default();
+            self.handled
+                .lock()
+                .unwrap()
+                .push((message.src.clone(), in_reply_to));
+            vec![context.reply(
+                message.src,
+                MessageBody::InitOk {
+                    msg_id: 0,
+                    in_reply_to,
+                },
+            )]
+        }
+    }
+
+    #[test]
+    fn test_inline_without_a_loop() {
+        let mut sharded = Sharded::new(Recorder::default(), 4);
+        let mut node = Node::new();
+
+        let out = sharded.handle(&mut node, testing::init("n1", ["n1"]));
+        assert_eq!(out.len(), 1);
+
+        let out = sharded.handle(&mut node, testing::read().with_msg_id(7));
+        assert_eq!(out[0].src, "n1");
+        assert_eq!(out[0].body.in_reply_to(), Some(7));
+        // The placeholder id was replaced
+        assert_ne!(out[0].body.msg_id(), Some(0));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_workers_feed_results_back_into_the_loop() {
+        let mut sharded = Sharded::new(Recorder::default(), 3);
+        let mut node = Node::new();
+        let (deferred, mut rx) = Deferred::channel();
+        node.attach_deferred(deferred);
+
+        sharded.handle(&mut node, testing::init("n1", ["n1"]));
+        for (i, src) in ["c1", "c2", "c3", "c1", "c2", "c3"].into_iter().enumerate() {
+            let read = testing::read().src(src).with_msg_id(i as u64 + 1);
+            assert!(sharded.handle(&mut node, read).is_empty());
+        }
+
+        let mut replies = Vec::new();
+        while replies.len() < 6 {
+            replies.extend(rx.recv().await.unwrap().messages);
+        }
+        let mut answered: Vec<_> = replies
+            .iter()
+            .filter_map(|reply| reply.body.in_reply_to())
+            .collect();
+        answered.sort();
+        assert_eq!(answered, vec![1, 2, 3, 4, 5, 6]);
+
+        // One worker owns each sender, so its requests stay in order
+        let handled = sharded.handler.handled.lock().unwrap();
+        let c1: Vec<u64> = handled
+            .iter()
+            .filter(|(src, _)| src == "c1")
+            .map(|(_, msg_id)| *msg_id)
+            .collect();
+        assert_eq!(c1, vec![1, 4]);
+    }
+}
@@ -0,0 +1,992 @@
+//! In-memory cluster for tests. A [`Sim`] runs one handler per node and carries their
+//! messages through a simulated network with configurable latency, loss, duplication,
+//! reordering, and partitions (set directly or scripted with [`crate::faults`]), so
+//! whole-cluster behavior (gossip convergence, quorum commits) can be asserted in
+//! `cargo test` without the Maelstrom jar.
+//!
+//! Time is virtual. Each message is delivered at its send time plus a sampled latency, and
+//! nodes see the simulation's clock through [`Node::now`], so ticks, RPC timeouts and
+//! retries, `send_after` timers, retransmissions, and pings all fire at simulated instants
+//! while the test runs as fast as the handlers allow. Latency, loss, and every node's
+//! generator are seeded, so a run with the same seed repeats exactly.
+//!
+//! Nodes whose handler is [`Persistent`] can also crash: [`Sim::checkpoint`] saves a node's
+//! state as a disk would, and [`Sim::crash`] restarts it from there with a fresh runtime, so
+//! pending RPCs, timers, and unacknowledged sends are lost while messages already in flight
+//! still arrive.
+
+use crate::{
+    Message, MessageBody, MessageHandler, Node, NodeConfig,
+    faults::{Fault, Faults},
+    persist::Persistent,
+    runtime::{effective_tick_interval, poll_calls, process, sending},
+    trace::{self, Envelope},
+};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::cmp::{Ordering, Reverse};
+use std::collections::{BTreeMap, BinaryHeap, HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+/// Deliveries `run` makes before assuming the cluster will never go quiet
+const MAX_STEPS: usize = 1_000_000;
+
+/// Simulated time `call` waits for a reply
+const CALL_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// A message on its way
+struct InFlight {
+    at: Duration,
+    seq: u64,
+    message: Message,
+    envelope: Envelope,
+}
+
+impl PartialEq for InFlight {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for InFlight {}
+
+impl PartialOrd for InFlight {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for InFlight {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (self.at, self.seq).cmp(&(other.at, other.seq))
+    }
+}
+
+/// What happens next
+enum Next {
+    Fault,
+    Message,
+    Node(String),
+}
+
+/// A client request and, once it arrives, the reply; checkers in [`crate::testing::check`]
+/// read a run's history of these
+#[derive(Debug, Clone)]
+pub struct Op {
+    pub client: String,
+    pub node: String,
+    pub request: MessageBody,
+    /// When the request was sent
+    pub invoked: Duration,
+    /// When the reply reached the client, and the reply
+    pub completed: Option<(Duration, MessageBody)>,
+}
+
+impl Op {
+    /// The reply, if it came back and is not an error
+    pub fn ok(&self) -> Option<&MessageBody> {
+        match &self.completed {
+            Some((_, MessageBody::Error { .. })) | None => None,
+            Some((_, reply)) => Some(reply),
+        }
+    }
+}
+
+/// A handler and the node it runs on
+struct SimNode<H> {
+    handler: H,
+    node: Node,
+    /// When the handler next ticks, if it does
+    next_tick: Option<Duration>,
+    /// When the node next pings quiet peers, if it does
+    next_ping: Option<Duration>,
+}
+
+impl<H: MessageHandler> SimNode<H> {
+    /// Earliest moment the node has work of its own, as an offset from `start`
+    fn due(&self, start: Instant) -> Option<Duration> {
+        let since = |at: Instant| at.saturating_duration_since(start);
+        [
+            self.next_tick,
+            self.next_ping,
+            self.node.calls.next_deadline().map(since),
+            self.node.timers.next_due().map(since),
+            self.node.outbox.next_due().map(since),
+        ]
+        .into_iter()
+        .flatten()
+        .min()
+    }
+}
+
+/// A cluster of `H` handlers on `n1`..`nN`, initialized on creation
+pub struct Sim<H> {
+    nodes: BTreeMap<String, SimNode<H>>,
+    in_flight: BinaryHeap<Reverse<InFlight>>,
+    /// Messages that reached a client, in arrival order
+    clients: Vec<Message>,
+    history: Vec<Op>,
+    /// Index in `history` of each request still waiting, by client and `msg_id`
+    waiting: HashMap<(String, u64), usize>,
+    /// Partition group of each node that is cut off from some others
+    groups: HashMap<String, usize>,
+    min_latency: Duration,
+    max_latency: Duration,
+    loss: f64,
+    duplicate: f64,
+    reorder: Duration,
+    /// Injected faults still to strike, in time order
+    faults: VecDeque<(Duration, Fault)>,
+    /// Last saved state of each node, for `crash`
+    checkpoints: HashMap<String, Vec<u8>>,
+    rng: StdRng,
+    now: Duration,
+    seq: u64,
+    /// The instant virtual time counts from
+    start: Instant,
+    /// Messages handed to a node
+    pub delivered: u64,
+    /// Messages nodes sent each other since the cluster started, whether or not they arrived
+    pub internal: u64,
+    /// Messages between nodes lost to `loss` or a partition
+    pub dropped: u64,
+}
+
+impl<H: MessageHandler> Sim<H> {
+    /// `node_count` nodes with default config, each running the handler `make` builds for it
+    pub fn new(node_count: usize, make: impl FnMut(&str) -> H) -> Self {
+        Self::with_config(node_count, NodeConfig::default(), make)
+    }
+
+    /// Nodes started with `config`; without a `config.seed` they use seed 0, so runs repeat
+    /// unless asked otherwise. The seed drives the network too.
+    pub fn with_config(
+        node_count: usize,
+        mut config: NodeConfig,
+        mut make: impl FnMut(&str) -> H,
+    ) -> Self {
+        let seed = *config.seed.get_or_insert(0);
+        let start = Instant::now();
+        let ids: Vec<String> = (1..=node_count).map(|i| format!("n{i}")).collect();
+        let nodes = ids
+            .iter()
+            .map(|id| {
+                let handler = make(id);
+                let mut node = Node::with_config(config.clone());
+                node.set_clock(start);
+                let sim_node = SimNode {
+                    next_tick: effective_tick_interval(&handler, &node),
+                    next_ping: node.config.ping_interval,
+                    handler,
+                    node,
+                };
+                (id.clone(), sim_node)
+            })
+            .collect();
+        let mut sim = Self {
+            nodes,
+            in_flight: BinaryHeap::new(),
+            clients: Vec::new(),
+            history: Vec::new(),
+            waiting: HashMap::new(),
+            groups: HashMap::new(),
+            min_latency: Duration::ZERO,
+            max_latency: Duration::ZERO,
+            loss: 0.0,
+            duplicate: 0.0,
+            reorder: Duration::ZERO,
+            faults: VecDeque::new(),
+            checkpoints: HashMap::new(),
+            rng: StdRng::seed_from_u64(seed),
+            now: Duration::ZERO,
+            seq: 0,
+            start,
+            delivered: 0,
+            internal: 0,
+            dropped: 0,
+        };
+        for (i, id) in ids.iter().enumerate() {
+            sim.send(Message {
+                src: "c0".to_string(),
+                dest: id.clone(),
+                body: MessageBody::Init {
+                    msg_id: i as u64 + 1,
+                    node_id: id.clone(),
+                    node_ids: ids.clone(),
+                },
+            });
+        }
+        sim.run();
+        sim.clients.clear();
+        sim.history.clear();
+        sim.waiting.clear();
+        sim.internal = 0;
+        sim
+    }
+
+    /// Reseed the generator behind latency and loss; nodes keep the seed they started with
+    pub fn seed(&mut self, seed: u64) {
+        self.rng = StdRng::seed_from_u64(seed);
+    }
+
+    /// Delay every later message by a uniform draw from `min..=max`
+    pub fn latency(&mut self, min: Duration, max: Duration) {
+        self.min_latency = min;
+        self.max_latency = max.max(min);
+    }
+
+    /// Lose each later message between nodes with probability `rate`
+    pub fn loss(&mut self, rate: f64) {
+        self.loss = rate.clamp(0.0, 1.0);
+    }
+
+    /// Deliver each later message between nodes twice with probability `rate`
+    pub fn duplicate(&mut self, rate: f64) {
+        self.duplicate = rate.clamp(0.0, 1.0);
+    }
+
+    /// Hold each later message between nodes back by up to `window` on top of its latency,
+    /// so messages overtake each other
+    pub fn reorder(&mut self, window: Duration) {
+        self.reorder = window;
+    }
+
+    /// Schedule a nemesis script; each fault strikes when simulated time reaches it
+    pub fn inject(&mut self, faults: Faults) {
+        self.faults.extend(faults.into_schedule());
+        self.faults.make_contiguous().sort_by_key(|(at, _)| *at);
+    }
+
+    /// Cut the network into `groups`; nodes in different groups cannot reach each other,
+    /// while nodes left out of every group still reach everyone. Clients reach every node.
+    pub fn partition(&mut self, groups: &[&[&str]]) {
+        self.groups = groups
+            .iter()
+            .enumerate()
+            .flat_map(|(group, ids)| ids.iter().map(move |id| (id.to_string(), group)))
+            .collect();
+    }
+
+    /// Undo `partition`
+    pub fn heal(&mut self) {
+        self.groups.clear();
+    }
+
+    fn strike(&mut self, fault: Fault) {
+        match fault {
+            Fault::Partition(groups) => {
+                let groups: Vec<Vec<&str>> = groups
+                    .0
+                    .iter()
+                    .map(|group| group.iter().map(String::as_str).collect())
+                    .collect();
+                let groups: Vec<&[&str]> = groups.iter().map(Vec::as_slice).collect();
+                self.partition(&groups);
+            }
+            Fault::Heal => self.heal(),
+            Fault::Drop(rate) => self.loss(rate),
+            Fault::Duplicate(rate) => self.duplicate(rate),
+            Fault::Reorder(window) => self.reorder(window),
+            Fault::Calm => {
+                self.heal();
+                self.loss(0.0);
+                self.duplicate(0.0);
+                self.reorder(Duration::ZERO);
+            }
+        }
+    }
+
+    /// Simulated time since the cluster started
+    pub fn now(&self) -> Duration {
+        self.now
+    }
+
+    /// Ids of the nodes, in order
+    pub fn node_ids(&self) -> Vec<String> {
+        self.nodes.keys().cloned().collect()
+    }
+
+    pub fn node(&self, id: &str) -> &Node {
+        &self.nodes[id].node
+    }
+
+    pub fn handler(&self, id: &str) -> &H {
+        &self.nodes[id].handler
+    }
+
+    pub fn handler_mut(&mut self, id: &str) -> &mut H {
+        &mut self.nodes.get_mut(id).expect("no such node").handler
+    }
+
+    /// Put a message from outside the cluster on the network; client requests go into the
+    /// history
+    pub fn send(&mut self, message: Message) {
+        let msg_id = message.body.msg_id();
+        if let Some(msg_id) = msg_id.filter(|_| trace::is_client(&message.src)) {
+            self.waiting
+                .insert((message.src.clone(), msg_id), self.history.len());
+            self.history.push(Op {
+                client: message.src.clone(),
+                node: message.dest.clone(),
+                request: message.body.clone(),
+                invoked: self.now,
+                completed: None,
+            });
+        }
+        self.enqueue(message, Envelope::default());
+    }
+
+    /// Every client request sent since the cluster started, in the order they were sent
+    pub fn history(&self) -> &[Op] {
+        &self.history
+    }
+
+    /// Send a client request and run until its reply reaches the client; `None` if none
+    /// arrives within a minute of simulated time
+    pub fn call(&mut self, request: Message) -> Option<Message> {
+        let client = request.src.clone();
+        let msg_id = request.body.msg_id();
+        let until = self.now + CALL_TIMEOUT;
+        self.send(request);
+        loop {
+            let found = self
+                .clients
+                .iter()
+                .position(|reply| reply.dest == client && reply.body.in_reply_to() == msg_id);
+            if let Some(i) = found {
+                return Some(self.clients.remove(i));
+            }
+            if !self.advance_until(until) {
+                return None;
+            }
+        }
+    }
+
+    /// Messages that reached clients since the last call
+    pub fn take_client_messages(&mut self) -> Vec<Message> {
+        std::mem::take(&mut self.clients)
+    }
+
+    /// Run `on_tick` on every node, in id order, outside their regular schedule
+    pub fn tick(&mut self) {
+        let now = self.start + self.now;
+        for id in self.node_ids() {
+            let sim_node = self.nodes.get_mut(&id).expect("no such node");
+            sim_node.node.set_clock(now);
+            sim_node.node.new_trace();
+            let out = sim_node.handler.on_tick(&mut sim_node.node);
+            self.emit(&id, out);
+        }
+    }
+
+    /// Deliver the next message, ignoring everything else that is scheduled; false once
+    /// nothing is in flight
+    pub fn step(&mut self) -> bool {
+        let Some(Reverse(next)) = self.in_flight.pop() else {
+            return false;
+        };
+        self.now = self.now.max(next.at);
+        let message = next.message;
+        if trace::is_client(&message.dest) {
+            let waiting = message
+                .body
+                .in_reply_to()
+                .and_then(|msg_id| self.waiting.remove(&(message.dest.clone(), msg_id)));
+            if let Some(i) = waiting {
+                self.history[i].completed = Some((self.now, message.body.clone()));
+            }
+            self.clients.push(message);
+            return true;
+        }
+        let dest = message.dest.clone();
+        let now = self.start + self.now;
+        let Some(sim_node) = self.nodes.get_mut(&dest) else {
+            self.dropped += 1;
+            return true;
+        };
+        self.delivered += 1;
+        sim_node.node.set_clock(now);
+        sim_node.node.begin(&message.src, next.envelope);
+        let out = process(&mut sim_node.handler, &mut sim_node.node, message);
+        self.emit(&dest, out);
+        true
+    }
+
+    /// Deliver messages until none are in flight, without firing ticks or timers; returns
+    /// how many were delivered
+    pub fn run(&mut self) -> usize {
+        let mut steps = 0;
+        while self.step() {
+            steps += 1;
+            assert!(steps < MAX_STEPS, "cluster never went quiet");
+        }
+        steps
+    }
+
+    /// Jump to the next event (a fault, a message, or node-side work) and handle it; false
+    /// once nothing at all is scheduled
+    pub fn advance(&mut self) -> bool {
+        match self.next_event() {
+            None => false,
+            Some((_, Next::Message)) => self.step(),
+            Some((at, Next::Fault)) => {
+                self.now = self.now.max(at);
+                if let Some((_, fault)) = self.faults.pop_front() {
+                    self.strike(fault);
+                }
+                true
+            }
+            Some((at, Next::Node(id))) => {
+                self.now = self.now.max(at);
+                self.wake(&id);
+                true
+            }
+        }
+    }
+
+    /// Handle every event due within `duration` of simulated time, then move the clock to
+    /// its end
+    pub fn run_for(&mut self, duration: Duration) {
+        let until = self.now + duration;
+        while self.advance_until(until) {}
+        self.now = self.now.max(until);
+    }
+
+    /// Run until `done` holds, checking after every event, or until `limit` of simulated
+    /// time has passed; returns the simulated time at which it first held
+    pub fn run_until(
+        &mut self,
+        limit: Duration,
+        mut done: impl FnMut(&Self) -> bool,
+    ) -> Option<Duration> {
+        let until = self.now + limit;
+        loop {
+            if done(self) {
+                return Some(self.now);
+            }
+            if !self.advance_until(until) {
+                self.now = self.now.max(until);
+                return done(self).then_some(self.now);
+            }
+        }
+    }
+
+    /// `advance`, unless the next event comes after `until`
+    fn advance_until(&mut self, until: Duration) -> bool {
+        match self.next_event() {
+            Some((at, _)) if at <= until => self.advance(),
+            _ => false,
+        }
+    }
+
+    /// When the next event happens, and what it is
+    fn next_event(&self) -> Option<(Duration, Next)> {
+        // Faults strike before anything else due at the same instant, and messages go
+        // before node-side work, so a reply beats the timeout due with it
+        let fault = self.faults.front().map(|(at, _)| (*at, Next::Fault));
+        let message = self
+            .in_flight
+            .peek()
+            .map(|Reverse(next)| (next.at, Next::Message));
+        let node = self
+            .nodes
+            .iter()
+            .filter_map(|(id, sim_node)| Some((sim_node.due(self.start)?, id)))
+            .min()
+            .map(|(at, id)| (at, Next::Node(id.clone())));
+        [fault, message, node]
+            .into_iter()
+            .flatten()
+            .reduce(|earliest, next| if next.0 < earliest.0 { next } else { earliest })
+    }
+
+    /// Run whatever node `id` has due now: its tick, pings, RPC retries and timeouts,
+    /// timers, and retransmissions
+    fn wake(&mut self, id: &str) {
+        let now = self.now;
+        let instant = self.start + now;
+        let sim_node = self.nodes.get_mut(id).expect("no such node");
+        sim_node.node.set_clock(instant);
+        sim_node.node.trace = None;
+        let mut out = Vec::new();
+        if sim_node.next_tick.is_some_and(|at| at <= now) {
+            sim_node.node.new_trace();
+            out.extend(sim_node.handler.on_tick(&mut sim_node.node));
+            sim_node.node.trace = None;
+            // `set_config` may have retuned the tick
+            sim_node.next_tick = effective_tick_interval(&sim_node.handler, &sim_node.node)
+                .map(|interval| now + interval.max(Duration::from_micros(1)));
+        }
+        if let Some(at) = sim_node.next_ping.filter(|at| *at <= now) {
+            out.extend(sim_node.node.pings(instant));
+            sim_node.next_ping = sim_node.node.config.ping_interval.map(|ping| at + ping);
+        }
+        out.extend(poll_calls(
+            &mut sim_node.handler,
+            &mut sim_node.node,
+            instant,
+        ));
+        out.extend(sim_node.node.timers.poll(instant));
+        out.extend(sim_node.node.outbox.poll(instant));
+        self.emit(id, out);
+    }
+
+    /// Send what node `id` produced
+    fn emit(&mut self, id: &str, messages: Vec<Message>) {
+        for message in messages {
+            let sim_node = self.nodes.get_mut(id).expect("no such node");
+            if !sending(&mut sim_node.handler, &mut sim_node.node, &message) {
+                continue;
+            }
+            let envelope = sim_node.node.envelope_for(&message);
+            self.enqueue(message, envelope);
+        }
+    }
+
+    fn enqueue(&mut self, message: Message, envelope: Envelope) {
+        let internal = trace::is_peer(&message.src) && trace::is_peer(&message.dest);
+        if internal {
+            self.internal += 1;
+        }
+        if internal && (self.partitioned(&message.src, &message.dest) || self.chance(self.loss)) {
+            self.dropped += 1;
+            return;
+        }
+        if internal && self.chance(self.duplicate) {
+            self.schedule(message.clone(), envelope.clone(), true);
+        }
+        self.schedule(message, envelope, internal);
+    }
+
+    fn schedule(&mut self, message: Message, envelope: Envelope, internal: bool) {
+        let mut latency = if self.max_latency > self.min_latency {
+            self.rng.random_range(self.min_latency..=self.max_latency)
+        } else {
+            self.min_latency
+        };
+        if internal && !self.reorder.is_zero() {
+            latency += self.rng.random_range(Duration::ZERO..=self.reorder);
+        }
+        self.seq += 1;
+        self.in_flight.push(Reverse(InFlight {
+            at: self.now + latency,
+            seq: self.seq,
+            message,
+            envelope,
+        }));
+    }
+
+    fn partitioned(&self, a: &str, b: &str) -> bool {
+        match (self.groups.get(a), self.groups.get(b)) {
+            (Some(a), Some(b)) => a != b,
+            _ => false,
+        }
+    }
+
+    fn chance(&mut self, rate: f64) -> bool {
+        rate > 0.0 && self.rng.random_bool(rate)
+    }
+}
+
+impl<H: MessageHandler + Persistent> Sim<H> {
+    /// Save node `id`'s state as it is now, for a later `crash` to restart from
+    pub fn checkpoint(&mut self, id: &str) {
+        let snapshot = self.nodes[id].handler.snapshot();
+        self.checkpoints.insert(id.to_string(), snapshot);
+    }
+
+    /// Crash node `id` and restart it at once from its last checkpoint: everything it did
+    /// since is forgotten, its runtime (pending RPCs, timers, unacknowledged sends, the
+    /// reply cache) starts over, and messages in flight to it are delivered to the restarted
+    /// node
+    #[track_caller]
+    pub fn crash(&mut self, id: &str) {
+        let Some(snapshot) = self.checkpoints.get(id) else {
+            panic!("{id} crashed without a checkpoint");
+        };
+        let sim_node = self.nodes.get_mut(id).expect("no such node");
+        if let Err(e) = sim_node.handler.restore(snapshot) {
+            panic!("{id} cannot restore its checkpoint: {e}");
+        }
+        let mut node_ids = sim_node.node.peers.clone();
+        node_ids.push(id.to_string());
+        node_ids.sort();
+        let mut node = Node::with_config(sim_node.node.config.clone());
+        node.set_clock(self.start + self.now);
+        node.handle_init(id.to_string(), node_ids);
+        sim_node.handler.on_node_init(&node);
+        sim_node.next_tick = effective_tick_interval(&sim_node.handler, &node)
+            .map(|interval| self.now + interval.max(Duration::from_micros(1)));
+        sim_node.next_ping = node.config.ping_interval.map(|ping| self.now + ping);
+        sim_node.node = node;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ErrorCode, faults::nodes, rpc::Call, testing};
+    use rand::seq::IndexedRandom;
+    use std::collections::BTreeSet;
+
+    /// Floods each new broadcast value to every peer once
+    #[derive(Default)]
+    struct Flood {
+        values: BTreeSet<u64>,
+    }
+
+    impl Flood {
+        fn spread(&self, node: &Node, value: u64) -> Vec<Message> {
+            node.peers
+                .iter()
+                .filter(|peer| **peer != node.id)
+                .map(|peer| Message {
+                    src: node.id.clone(),
+                    dest: peer.clone(),
+                    body: MessageBody::BroadcastGossip {
+                        msg_id: None,
+                        messages: vec![value],
+                    },
+                })
+                .collect()
+        }
+    }
+
+    impl MessageHandler for Flood {
+        fn handle(&mut self, node: &mut Node, message: Message) -> Vec<Message> {
+            match message.body {
+                MessageBody::Init {
+                    msg_id,
+                    node_id,
+                    node_ids,
+                } => {
+                    node.handle_init(node_id, node_ids);
+                    vec![node.init_ok(message.src, msg_id)]
+                }
+                MessageBody::Broadcast {
+                    msg_id,
+                    message: value,
+                } => {
+                    let mut out = Vec::new();
+                    if self.values.insert(value) {
+                        out = self.spread(node, value);
+                    }
+                    out.push(node.answer(message.src, msg_id, |msg_id, in_reply_to| {
+                        MessageBody::BroadcastOk {
+                            msg_id,
+                            in_reply_to,
+                        }
+                    }));
+                    out
+                }
+                MessageBody::BroadcastGossip { messages, .. } => {
+                    let mut out = Vec::new();
+                    for value in messages {
+                        if self.values.insert(value) {
+                            out.extend(self.spread(node, value));
+                        }
+                    }
+                    out
+                }
+                _ => Vec::new(),
+            }
+        }
+    }
+
+    impl Persistent for Flood {
+        fn snapshot(&self) -> Vec<u8> {
+            serde_json::to_vec(&self.values).unwrap()
+        }
+
+        fn restore(&mut self, snapshot: &[u8]) -> Result<(), crate::persist::RestoreError> {
+            self.values = serde_json::from_slice(snapshot)?;
+            Ok(())
+        }
+    }
+
+    fn everyone_has(sim: &Sim<Flood>, value: u64) -> bool {
+        sim.node_ids()
+            .iter()
+            .all(|id| sim.handler(id).values.contains(&value))
+    }
+
+    #[test]
+    fn test_cluster_is_initialized_and_answers_clients() {
+        let mut sim = Sim::new(3, |_| Flood::default());
+        assert_eq!(sim.node("n2").id, "n2");
+        assert_eq!(sim.node("n2").peers.len(), 2);
+
+        let reply = sim.call(testing::broadcast(7).dest("n2")).unwrap();
+        assert!(matches!(reply.body, MessageBody::BroadcastOk { .. }));
+        sim.run();
+        assert!(everyone_has(&sim, 7));
+    }
+
+    #[test]
+    fn test_partitions_hold_messages_back_until_healed() {
+        let mut sim = Sim::new(3, |_| Flood::default());
+        sim.partition(&[&["n1", "n2"], &["n3"]]);
+
+        sim.call(testing::broadcast(1)).unwrap();
+        sim.run();
+        assert!(sim.handler("n2").values.contains(&1));
+        assert!(!sim.handler("n3").values.contains(&1));
+        assert!(sim.dropped > 0);
+
+        // Flooding never retries, so only new values get through after healing
+        sim.heal();
+        sim.call(testing::broadcast(2)).unwrap();
+        sim.run();
+        assert!(everyone_has(&sim, 2));
+        assert!(!sim.handler("n3").values.contains(&1));
+    }
+
+    #[test]
+    fn test_latency_advances_the_clock_and_runs_repeat_with_a_seed() {
+        let run = |seed| {
+            let mut sim = Sim::new(5, |_| Flood::default());
+            sim.seed(seed);
+            sim.latency(Duration::from_millis(1), Duration::from_millis(20));
+            sim.loss(0.3);
+            for value in 0..10 {
+                sim.send(testing::broadcast(value).with_msg_id(value + 1));
+            }
+            sim.run();
+            (sim.now(), sim.delivered, sim.dropped)
+        };
+
+        let (now, delivered, dropped) = run(3);
+        assert!(now >= Duration::from_millis(1));
+        assert!(dropped > 0);
+        assert_eq!(run(3), (now, delivered, dropped));
+    }
+
+    #[test]
+    fn test_duplicated_messages_are_delivered_twice() {
+        let delivered = |rate| {
+            let mut sim = Sim::new(3, |_| Flood::default());
+            sim.duplicate(rate);
+            sim.reorder(Duration::from_millis(10));
+            sim.send(testing::broadcast(1));
+            sim.run();
+            assert!(everyone_has(&sim, 1));
+            sim.delivered
+        };
+
+        // Each node gossips to its two peers once; only those six are duplicated
+        assert_eq!(delivered(1.0), delivered(0.0) + 6);
+    }
+
+    #[test]
+    fn test_crashed_nodes_restart_from_their_checkpoint() {
+        let mut sim = Sim::new(3, |_| Flood::default());
+        sim.checkpoint("n2");
+        sim.call(testing::broadcast(1)).unwrap();
+        sim.run();
+        assert!(everyone_has(&sim, 1));
+
+        // n1 floods 2 before n2 goes down; the gossip still reaches the restarted n2
+        sim.send(testing::broadcast(2).with_msg_id(2));
+        sim.step();
+        sim.crash("n2");
+        assert!(sim.handler("n2").values.is_empty());
+        assert_eq!(sim.node("n2").peers, vec!["n1", "n3"]);
+        sim.run();
+        assert_eq!(sim.handler("n2").values, BTreeSet::from([2]));
+        assert!(everyone_has(&sim, 2));
+    }
+
+    #[test]
+    #[should_panic(expected = "n3 crashed without a checkpoint")]
+    fn test_crashes_need_a_checkpoint() {
+        let mut sim = Sim::new(3, |_| Flood::default());
+        sim.crash("n3");
+    }
+
+    /// Each tick, pushes everything it knows to one random peer
+    #[derive(Default)]
+    struct Rumor {
+        values: BTreeSet<u64>,
+    }
+
+    impl MessageHandler for Rumor {
+        fn handle(&mut self, node: &mut Node, message: Message) -> Vec<Message> {
+            match message.body {
+                MessageBody::Init {
+                    msg_id,
+                    node_id,
+                    node_ids,
+                } => {
+                    node.handle_init(node_id, node_ids);
+                    vec![node.init_ok(message.src, msg_id)]
+                }
+                MessageBody::Broadcast {
+                    msg_id,
+                    message: value,
+                } => {
+                    self.values.insert(value);
+                    vec![node.answer(message.src, msg_id, |msg_id, in_reply_to| {
+                        MessageBody::BroadcastOk {
+                            msg_id,
+                            in_reply_to,
+                        }
+                    })]
+                }
+                MessageBody::BroadcastGossip { messages, .. } => {
+                    self.values.extend(messages);
+                    Vec::new()
+                }
+                _ => Vec::new(),
+            }
+        }
+
+        fn tick_interval(&self) -> Option<Duration> {
+            Some(Duration::from_millis(100))
+        }
+
+        fn on_tick(&mut self, node: &mut Node) -> Vec<Message> {
+            let Some(peer) = node.peers.choose(&mut node.rng).cloned() else {
+                return Vec::new();
+            };
+            vec![Message {
+                src: node.id.clone(),
+                dest: peer,
+                body: MessageBody::BroadcastGossip {
+                    msg_id: None,
+                    messages: self.values.iter().copied().collect(),
+                },
+            }]
+        }
+    }
+
+    #[test]
+    fn test_ticks_fire_on_virtual_time_and_runs_repeat() {
+        let converged_at = |seed| {
+            let config = NodeConfig::new().seed(seed);
+            let mut sim = Sim::with_config(5, config, |_| Rumor::default());
+            sim.latency(Duration::from_millis(5), Duration::from_millis(50));
+            sim.call(testing::broadcast(9).dest("n3")).unwrap();
+            sim.run_until(Duration::from_secs(2), |sim| {
+                sim.node_ids()
+                    .iter()
+                    .all(|id| sim.handler(id).values.contains(&9))
+            })
+        };
+
+        let started = Instant::now();
+        let at = converged_at(1).expect("all nodes agree within 2 s simulated");
+        assert!(at >= Duration::from_millis(100));
+        assert!(started.elapsed() < Duration::from_secs(2));
+        assert_eq!(converged_at(1), Some(at));
+    }
+
+    #[test]
+    fn test_injected_faults_strike_on_schedule() {
+        let mut sim = Sim::new(5, |_| Rumor::default());
+        sim.inject(
+            Faults::new()
+                .partition(nodes(["n1", "n2"]) | nodes(["n3", "n4", "n5"]))
+                .heal_at(Duration::from_secs(1)),
+        );
+        sim.call(testing::broadcast(4)).unwrap();
+        let has_four = |sim: &Sim<Rumor>| {
+            sim.node_ids()
+                .iter()
+                .filter(|id| sim.handler(id).values.contains(&4))
+                .count()
+        };
+
+        sim.run_for(Duration::from_millis(900));
+        assert_eq!(has_four(&sim), 2);
+        assert!(sim.dropped > 0);
+
+        let at = sim
+            .run_until(Duration::from_secs(5), |sim| has_four(sim) == 5)
+            .expect("the cluster converges once healed");
+        assert!(at > Duration::from_secs(1));
+    }
+
+    /// Answers client reads by asking `n2`
+    struct Asker;
+
+    impl MessageHandler for Asker {
+        fn handle(&mut self, node: &mut Node, message: Message) -> Vec<Message> {
+            match message.body {
+                MessageBody::Init {
+                    msg_id,
+                    node_id,
+                    node_ids,
+                } => {
+                    node.handle_init(node_id, node_ids);
+                    vec![node.init_ok(message.src, msg_id)]
+                }
+                MessageBody::Read { msg_id, .. } if trace::is_client(&message.src) => {
+                    let read = MessageBody::Read {
+                        msg_id: 0,
+                        key: None,
+                        context: None,
+                    };
+                    node.rpc_with("n2".to_string(), read, (message.src, msg_id))
+                        .into_iter()
+                        .collect()
+                }
+                MessageBody::Read { msg_id, .. } => {
+                    vec![node.answer(message.src, msg_id, |msg_id, in_reply_to| {
+                        MessageBody::ReadOk {
+                            msg_id,
+                            in_reply_to,
+                            messages: None,
+                            value: Some(0),
+                            context: None,
+                        }
+                    })]
+                }
+                _ => Vec::new(),
+            }
+        }
+
+        fn on_rpc_reply(
+            &mut self,
+            node: &mut Node,
+            mut call: Call,
+            _reply: Message,
+        ) -> Vec<Message> {
+            let (client, msg_id) = call.context::<(String, u64)>().unwrap();
+            vec![
+                node.answer(client, msg_id, |msg_id, in_reply_to| MessageBody::ReadOk {
+                    msg_id,
+                    in_reply_to,
+                    messages: None,
+                    value: Some(0),
+                    context: None,
+                }),
+            ]
+        }
+
+        fn on_rpc_timeout(&mut self, node: &mut Node, mut call: Call) -> Vec<Message> {
+            let (client, msg_id) = call.context::<(String, u64)>().unwrap();
+            vec![node.temporarily_unavailable(client, msg_id, "n2 did not answer")]
+        }
+    }
+
+    #[test]
+    fn test_rpc_retries_and_timeouts_run_on_virtual_time() {
+        let mut sim = Sim::new(2, |_| Asker);
+        sim.partition(&[&["n1"], &["n2"]]);
+
+        let started = Instant::now();
+        let reply = sim.call(testing::read()).unwrap();
+        match reply.body {
+            MessageBody::Error { code, .. } => assert_eq!(code, ErrorCode::TemporarilyUnavailable),
+            _ => panic!("Expected Error message"),
+        }
+        // Every retry was sent and lost, seconds apart in simulated time only
+        assert_eq!(sim.dropped, 5);
+        assert!(sim.now() >= Duration::from_secs(2));
+        assert!(started.elapsed() < Duration::from_secs(1));
+
+        sim.heal();
+        let reply = sim.call(testing::read().with_msg_id(2)).unwrap();
+        assert!(matches!(reply.body, MessageBody::ReadOk { .. }));
+    }
+}
@@ -0,0 +1,199 @@
+//! TCP transport: the stdio protocol, newline-delimited JSON, over any number of accepted
+//! connections, so a node can be driven by a custom client or reached from another machine.
+//! Each connection may carry traffic for several senders. Output for `dest` goes to the
+//! connection `dest` last spoke on, or, for a `dest` not heard from yet, to the most recently
+//! opened connection, so a single driver can play the whole network.
+
+use crate::{
+    Message,
+    runtime::read_lines,
+    tee::Tee,
+    trace::{self, Envelope},
+    writer::{self, FlushPolicy, Writer, spawn_writer},
+};
+use std::collections::HashMap;
+use tokio::{net::TcpListener, sync::mpsc, task::JoinHandle};
+
+/// What connection tasks tell the router
+enum Event {
+    /// A message from `src` arrived on connection `conn`
+    Heard { src: String, conn: u64 },
+    /// Connection `conn` closed
+    Closed { conn: u64 },
+}
+
+/// Accept connections on `listener`, feeding their messages to `clients` (and `peers`, when
+/// split) and routing what is written to the returned `Writer` back out. Lines read from every
+/// connection are recorded on `tee`, if given. The task ends, flushing every connection, once
+/// all `Writer`s are dropped.
+pub fn serve(
+    listener: TcpListener,
+    clients: mpsc::Sender<(Message, Envelope)>,
+    peers: Option<mpsc::Sender<(Message, Envelope)>>,
+    tee: Option<Tee>,
+    policy: FlushPolicy,
+) -> (Writer, JoinHandle<()>) {
+    let (writer, mut frames) = writer::channel();
+    let task = tokio::spawn(async move {
+        let (events_tx, mut events) = mpsc::unbounded_channel();
+        let mut connections: HashMap<u64, (Writer, JoinHandle<()>)> = HashMap::new();
+        let mut routes: HashMap<String, u64> = HashMap::new();
+        let mut next_conn = 0;
+        let mut latest = None;
+        loop {
+            tokio::select! {
+                // Learn routes before using them
+                biased;
+                Some(event) = events.recv() => match event {
+                    Event::Heard { src, conn } => {
+                        routes.insert(src, conn);
+                    }
+                    Event::Closed { conn } => {
+                        connections.remove(&conn);
+                        routes.retain(|_, route| *route != conn);
+                        if latest == Some(conn) {
+                            latest = connections.keys().max().copied();
+                        }
+                    }
+                },
+                frame = frames.recv() => {
+                    let Some(frame) = frame else { break };
+                    let dest = trace::probe_dest(&frame);
+                    let conn = dest
+                        .as_ref()
+                        .and_then(|dest| routes.get(dest))
+                        .copied()
+                        .or(latest);
+                    let sent = conn
+                        .and_then(|conn| connections.get(&conn))
+                        .is_some_and(|(connection, _)| connection.send(frame));
+                    if !sent {
+                        crate::logging::log_warn!(
+                            "no connection for {}; dropping message",
+                            dest.as_deref().unwrap_or("unknown destination")
+                        );
+                    }
+                }
+                accepted = listener.accept() => {
+                    let (stream, _) = match accepted {
+                        Ok(accepted) => accepted,
+                        Err(e) => {
+                            crate::logging::log_warn!("accept error: {e:?}");
+                            continue;
+                        }
+                    };
+                    let conn = next_conn;
+                    next_conn += 1;
+                    let (input, output) = stream.into_split();
+                    connections.insert(conn, spawn_writer(output, policy));
+                    latest = Some(conn);
+
+                    let (clients, peers, tee, events) =
+                        (clients.clone(), peers.clone(), tee.clone(), events_tx.clone());
+                    tokio::spawn(async move {
+                        read_lines(input, clients, peers, tee, |src| {
+                            let _ = events.send(Event::Heard {
+                                src: src.to_string(),
+                                conn,
+                            });
+                        })
+                        .await;
+                        let _ = events.send(Event::Closed { conn });
+                    });
+                }
+            }
+        }
+        for (_, (connection, task)) in connections {
+            drop(connection);
+            let _ = task.await;
+        }
+    });
+    (writer, task)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{MessageBody, testing};
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::TcpStream;
+
+    fn line(message: &Message) -> Vec<u8> {
+        trace::encode(message, &Envelope::default()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_messages_flow_both_ways_over_a_connection() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (tx, mut rx) = mpsc::channel(8);
+        let (writer, task) = serve(listener, tx, None, None, FlushPolicy::EveryMessage);
+
+        let (input, mut output) = TcpStream::connect(addr).await.unwrap().into_split();
+        output
+            .write_all(&line(&testing::init("n1", ["n1"])))
+            .await
+            .unwrap();
+        let (init, _) = rx.recv().await.unwrap();
+        assert!(matches!(init.body, MessageBody::Init { .. }));
+
+        let reply = Message {
+            src: "n1".to_string(),
+            dest: init.src,
+            body: MessageBody::InitOk {
+                msg_id: 1,
+                in_reply_to: 1,
+            },
+        };
+        assert!(writer.send(line(&reply)));
+        let mut received = String::new();
+        BufReader::new(input)
+            .read_line(&mut received)
+            .await
+            .unwrap();
+        let received: serde_json::Value = serde_json::from_str(&received).unwrap();
+        assert_eq!(received["body"]["type"], "init_ok");
+
+        drop(writer);
+        task.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_replies_go_to_the_connection_the_sender_used() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (tx, mut rx) = mpsc::channel(8);
+        let (writer, _task) = serve(listener, tx, None, None, FlushPolicy::EveryMessage);
+
+        let (first_in, mut first_out) = TcpStream::connect(addr).await.unwrap().into_split();
+        first_out
+            .write_all(&line(&testing::echo("from c1")))
+            .await
+            .unwrap();
+        rx.recv().await.unwrap();
+        // A second connection becomes the default route, but c1 keeps its own
+        let (_second_in, mut second_out) = TcpStream::connect(addr).await.unwrap().into_split();
+        second_out
+            .write_all(&line(&testing::read().src("c2")))
+            .await
+            .unwrap();
+        rx.recv().await.unwrap();
+
+        let reply = Message {
+            src: "n1".to_string(),
+            dest: "c1".to_string(),
+            body: MessageBody::EchoOk {
+                msg_id: 1,
+                in_reply_to: 1,
+                echo: "from c1".to_string(),
+            },
+        };
+        assert!(writer.send(line(&reply)));
+        let mut received = String::new();
+        BufReader::new(first_in)
+            .read_line(&mut received)
+            .await
+            .unwrap();
+        assert!(received.contains("from c1"));
+    }
+}
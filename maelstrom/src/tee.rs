@@ -0,0 +1,110 @@
+//! Capture of a node's raw traffic for post-mortems: every line read from and written to the
+//! transport, timestamped, appended to a file. Records are JSON lines
+//! `{"ts_us", "dir": "in" | "out", "line"}` holding the line verbatim as a string, so even
+//! lines that failed to decode survive. [`crate::replay`] reads captures directly.
+
+use crate::writer::{FlushPolicy, Writer, spawn_writer};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::{fs::OpenOptions, io, task::JoinHandle};
+
+/// Which way a captured line went
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Direction {
+    In,
+    Out,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Record<'a> {
+    /// Wall-clock microseconds
+    ts_us: u128,
+    dir: Direction,
+    #[serde(borrow)]
+    line: std::borrow::Cow<'a, str>,
+}
+
+/// Appends captured lines to the capture file; clones share it
+#[derive(Clone)]
+pub struct Tee {
+    writer: Writer,
+}
+
+impl Tee {
+    pub fn new(writer: Writer) -> Self {
+        Self { writer }
+    }
+
+    /// Append to the capture file at `path`, creating it if needed
+    pub async fn create(path: &Path) -> io::Result<(Self, JoinHandle<()>)> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .await?;
+        let (writer, task) = spawn_writer(file, FlushPolicy::WhenIdle);
+        Ok((Self::new(writer), task))
+    }
+
+    pub fn inbound(&self, line: &[u8]) {
+        self.record(Direction::In, line);
+    }
+
+    pub fn outbound(&self, line: &[u8]) {
+        self.record(Direction::Out, line);
+    }
+
+    fn record(&self, dir: Direction, line: &[u8]) {
+        let ts_us = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_micros());
+        let line = String::from_utf8_lossy(line);
+        let record = Record {
+            ts_us,
+            dir,
+            line: line.trim_end().into(),
+        };
+        // Losing the capture should not take the node with it, so failures are ignored
+        if let Ok(encoded) = serde_json::to_vec(&record) {
+            self.writer.send(encoded);
+        }
+    }
+}
+
+/// The direction and original line of a capture record; `None` if `record` is not one
+pub fn parse(record: &str) -> Option<(Direction, String)> {
+    let record: Record = serde_json::from_str(record).ok()?;
+    Some((record.dir, record.line.into_owned()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncBufReadExt, BufReader};
+
+    #[tokio::test]
+    async fn test_lines_are_captured_verbatim_with_their_direction() {
+        let (sink, capture) = io::duplex(4096);
+        let (writer, task) = spawn_writer(sink, FlushPolicy::WhenIdle);
+        let tee = Tee::new(writer);
+        tee.inbound(b"{\"src\":\"c1\"}\n");
+        tee.outbound(b"not json");
+        drop(tee);
+        task.await.unwrap();
+
+        let mut lines = BufReader::new(capture).lines();
+        let first = lines.next_line().await.unwrap().unwrap();
+        assert_eq!(
+            parse(&first),
+            Some((Direction::In, "{\"src\":\"c1\"}".to_string()))
+        );
+        let second = lines.next_line().await.unwrap().unwrap();
+        assert_eq!(
+            parse(&second),
+            Some((Direction::Out, "not json".to_string()))
+        );
+        assert_eq!(parse("{\"src\":\"c1\"}"), None);
+    }
+}
@@ -0,0 +1,455 @@
+//! Builders for the messages tests feed into handlers and the replies they expect back,
+//! [`TestNode`] to drive a handler through a conversation, [`ChaosHandler`] to disturb what a
+//! handler sends, in [`check`], checkers for simulated runs, in [`scenario`], a runner for
+//! conversations written as JSON files, and in `strategy` (with the `proptest` feature),
+//! generators of whole request sequences.
+//!
+//! Every request builder addresses a client request from `c1` to `n1` with `msg_id` 1; chain
+//! [`Message::src`], [`Message::dest`], or [`Message::with_msg_id`] to change that. Reply
+//! builders (`*_ok`) return bare bodies with ids left at 0, for [`TestNode::expect_reply`].
+
+use crate::{Message, MessageBody};
+#[cfg(feature = "broadcast")]
+pub use check::check_broadcast;
+#[cfg(feature = "counter")]
+pub use check::check_counter;
+#[cfg(feature = "kafka")]
+pub use check::check_kafka;
+#[cfg(feature = "txn")]
+pub use check::check_txn;
+#[cfg(any(feature = "broadcast", feature = "counter", feature = "kafka"))]
+use std::collections::HashMap;
+
+mod chaos;
+pub mod check;
+pub mod scenario;
+#[cfg(feature = "proptest")]
+pub mod strategy;
+mod test_node;
+
+pub use crate::expect_body;
+pub use chaos::{Chaos, ChaosHandler, ChaosStats};
+pub use test_node::TestNode;
+
+/// Destructure a [`MessageBody`] variant, panicking with `Expected <Variant> message` for any
+/// other. Evaluates to the one named field, a tuple of several, or `()` for `{ .. }`; fields
+/// not named are ignored.
+///
+/// ```ignore
+/// let offset = expect_body!(&reply.body, SendOk { offset });
+/// let (in_reply_to, echo) = expect_body!(reply.body, EchoOk { in_reply_to, echo, .. });
+/// ```
+#[macro_export]
+macro_rules! expect_body {
+    ($body:expr, $variant:ident { .. }) => {
+        match $body {
+            $crate::MessageBody::$variant { .. } => {}
+            other => panic!("Expected {} message, got {:?}", stringify!($variant), other),
+        }
+    };
+    ($body:expr, $variant:ident { $field:ident $(, ..)? }) => {
+        match $body {
+            $crate::MessageBody::$variant { $field, .. } => $field,
+            other => panic!("Expected {} message, got {:?}", stringify!($variant), other),
+        }
+    };
+    ($body:expr, $variant:ident { $($field:ident),+ $(, ..)? }) => {
+        match $body {
+            $crate::MessageBody::$variant { $($field),+, .. } => ($($field),+),
+            other => panic!("Expected {} message, got {:?}", stringify!($variant), other),
+        }
+    };
+}
+
+const CLIENT: &str = "c1";
+const NODE: &str = "n1";
+
+fn request(body: MessageBody) -> Message {
+    Message {
+        src: CLIENT.to_string(),
+        dest: NODE.to_string(),
+        body,
+    }
+}
+
+impl Message {
+    /// Replace the sender
+    pub fn src(mut self, src: &str) -> Self {
+        self.src = src.to_string();
+        self
+    }
+
+    /// Replace the recipient
+    pub fn dest(mut self, dest: &str) -> Self {
+        self.dest = dest.to_string();
+        self
+    }
+
+    /// Replace the body's `msg_id`
+    pub fn with_msg_id(mut self, msg_id: u64) -> Self {
+        self.body.set_msg_id(msg_id);
+        self
+    }
+}
+
+/// `init` addressed to `node_id`, naming every node in the cluster
+pub fn init<'a>(node_id: &str, node_ids: impl IntoIterator<Item = &'a str>) -> Message {
+    request(MessageBody::Init {
+        msg_id: 1,
+        node_id: node_id.to_string(),
+        node_ids: node_ids.into_iter().map(str::to_string).collect(),
+    })
+    .dest(node_id)
+}
+
+/// `dump_state` admin request
+pub fn dump_state() -> Message {
+    request(MessageBody::DumpState { msg_id: 1 })
+}
+
+/// `set_config` admin request changing only the given settings
+pub fn set_config(tick_ms: Option<u64>, fanout: Option<usize>) -> Message {
+    request(MessageBody::SetConfig {
+        msg_id: 1,
+        tick_ms,
+        fanout,
+    })
+}
+
+/// `membership` update adding and removing the given nodes
+pub fn membership<'a>(
+    added: impl IntoIterator<Item = &'a str>,
+    removed: impl IntoIterator<Item = &'a str>,
+) -> Message {
+    request(MessageBody::Membership {
+        msg_id: 1,
+        added: added.into_iter().map(str::to_string).collect(),
+        removed: removed.into_iter().map(str::to_string).collect(),
+    })
+}
+
+#[cfg(feature = "echo")]
+pub fn echo(echo: &str) -> Message {
+    request(MessageBody::Echo {
+        msg_id: 1,
+        echo: echo.to_string(),
+    })
+}
+
+#[cfg(feature = "echo")]
+pub fn echo_batch<'a>(echoes: impl IntoIterator<Item = &'a str>) -> Message {
+    request(MessageBody::EchoBatch {
+        msg_id: 1,
+        echoes: echoes.into_iter().map(str::to_string).collect(),
+    })
+}
+
+#[cfg(feature = "unique-ids")]
+pub fn generate() -> Message {
+    request(MessageBody::Generate { msg_id: 1 })
+}
+
+#[cfg(feature = "broadcast")]
+pub fn broadcast(message: u64) -> Message {
+    request(MessageBody::Broadcast { msg_id: 1, message })
+}
+
+#[cfg(feature = "broadcast")]
+pub fn topology<'a>(topology: impl IntoIterator<Item = (&'a str, Vec<&'a str>)>) -> Message {
+    let topology: HashMap<String, Vec<String>> = topology
+        .into_iter()
+        .map(|(node, neighbors)| {
+            let neighbors = neighbors.into_iter().map(str::to_string).collect();
+            (node.to_string(), neighbors)
+        })
+        .collect();
+    request(MessageBody::Topology {
+        msg_id: 1,
+        topology,
+    })
+}
+
+#[cfg(any(feature = "broadcast", feature = "counter"))]
+pub fn read() -> Message {
+    request(MessageBody::Read {
+        msg_id: 1,
+        key: None,
+        context: None,
+    })
+}
+
+#[cfg(feature = "counter")]
+pub fn add(delta: u64) -> Message {
+    request(MessageBody::Add {
+        msg_id: 1,
+        delta,
+        key: None,
+    })
+}
+
+/// `read` of the counter named `key`
+#[cfg(feature = "counter")]
+pub fn read_key(key: &str) -> Message {
+    request(MessageBody::Read {
+        msg_id: 1,
+        key: Some(key.to_string()),
+        context: None,
+    })
+}
+
+/// `read` carrying the `context` of an earlier `read_ok`
+#[cfg(feature = "counter")]
+pub fn read_after(context: HashMap<String, u64>) -> Message {
+    request(MessageBody::Read {
+        msg_id: 1,
+        key: None,
+        context: Some(context),
+    })
+}
+
+/// `add` to the counter named `key`
+#[cfg(feature = "counter")]
+pub fn add_to(key: &str, delta: u64) -> Message {
+    request(MessageBody::Add {
+        msg_id: 1,
+        delta,
+        key: Some(key.to_string()),
+    })
+}
+
+/// `send` from the given client
+#[cfg(feature = "kafka")]
+pub fn send(src: &str, key: &str, msg: u64) -> Message {
+    request(MessageBody::Send {
+        msg_id: 1,
+        key: key.to_string(),
+        msg,
+    })
+    .src(src)
+}
+
+#[cfg(feature = "kafka")]
+fn offsets<'a>(offsets: impl IntoIterator<Item = (&'a str, u64)>) -> HashMap<String, u64> {
+    offsets
+        .into_iter()
+        .map(|(key, offset)| (key.to_string(), offset))
+        .collect()
+}
+
+#[cfg(feature = "kafka")]
+pub fn poll<'a>(from: impl IntoIterator<Item = (&'a str, u64)>) -> Message {
+    request(MessageBody::Poll {
+        msg_id: 1,
+        offsets: offsets(from),
+    })
+}
+
+#[cfg(feature = "kafka")]
+pub fn commit_offsets<'a>(committed: impl IntoIterator<Item = (&'a str, u64)>) -> Message {
+    request(MessageBody::CommitOffsets {
+        msg_id: 1,
+        offsets: offsets(committed),
+        consumer: None,
+    })
+}
+
+#[cfg(feature = "kafka")]
+pub fn list_committed_offsets<'a>(keys: impl IntoIterator<Item = &'a str>) -> Message {
+    request(MessageBody::ListCommittedOffsets {
+        msg_id: 1,
+        keys: keys.into_iter().map(str::to_string).collect(),
+        consumer: None,
+    })
+}
+
+/// `txn` of `(op, key, value)` micro-operations, e.g. `("w", 1, Some(5))`
+#[cfg(feature = "txn")]
+pub fn txn<'a>(ops: impl IntoIterator<Item = (&'a str, u64, Option<u64>)>) -> Message {
+    request(MessageBody::Txn {
+        msg_id: 1,
+        txn: ops
+            .into_iter()
+            .map(|(op, key, value)| (op.to_string(), key, value))
+            .collect(),
+    })
+}
+
+#[cfg(feature = "echo")]
+pub fn echo_ok(echo: &str) -> MessageBody {
+    MessageBody::EchoOk {
+        msg_id: 0,
+        in_reply_to: 0,
+        echo: echo.to_string(),
+    }
+}
+
+#[cfg(feature = "echo")]
+pub fn echo_batch_ok<'a>(echoes: impl IntoIterator<Item = &'a str>) -> MessageBody {
+    MessageBody::EchoBatchOk {
+        msg_id: 0,
+        in_reply_to: 0,
+        echoes: echoes.into_iter().map(str::to_string).collect(),
+    }
+}
+
+#[cfg(feature = "broadcast")]
+pub fn broadcast_ok() -> MessageBody {
+    MessageBody::BroadcastOk {
+        msg_id: 0,
+        in_reply_to: 0,
+    }
+}
+
+#[cfg(feature = "broadcast")]
+pub fn topology_ok() -> MessageBody {
+    MessageBody::TopologyOk {
+        msg_id: 0,
+        in_reply_to: 0,
+    }
+}
+
+/// Broadcast `read_ok`, listing values in the order the handler is expected to
+#[cfg(feature = "broadcast")]
+pub fn read_ok(messages: impl IntoIterator<Item = u64>) -> MessageBody {
+    MessageBody::ReadOk {
+        msg_id: 0,
+        in_reply_to: 0,
+        messages: Some(messages.into_iter().collect()),
+        value: None,
+        context: None,
+    }
+}
+
+/// Counter `read_ok`
+#[cfg(feature = "counter")]
+pub fn read_value_ok(value: u64) -> MessageBody {
+    MessageBody::ReadOk {
+        msg_id: 0,
+        in_reply_to: 0,
+        messages: None,
+        value: Some(value),
+        context: None,
+    }
+}
+
+#[cfg(feature = "counter")]
+pub fn add_ok() -> MessageBody {
+    MessageBody::AddOk {
+        msg_id: 0,
+        in_reply_to: 0,
+    }
+}
+
+#[cfg(feature = "kafka")]
+pub fn send_ok(offset: u64) -> MessageBody {
+    MessageBody::SendOk {
+        msg_id: 0,
+        in_reply_to: 0,
+        offset,
+    }
+}
+
+/// `poll_ok` with `(offset, msg)` entries for each key
+#[cfg(feature = "kafka")]
+pub fn poll_ok<'a>(msgs: impl IntoIterator<Item = (&'a str, Vec<(u64, u64)>)>) -> MessageBody {
+    MessageBody::PollOk {
+        msg_id: 0,
+        in_reply_to: 0,
+        msgs: msgs
+            .into_iter()
+            .map(|(key, entries)| (key.to_string(), entries.into()))
+            .collect(),
+        next_offsets: HashMap::new(),
+        has_more: false,
+    }
+}
+
+#[cfg(feature = "kafka")]
+pub fn commit_offsets_ok() -> MessageBody {
+    MessageBody::CommitOffsetsOk {
+        msg_id: 0,
+        in_reply_to: 0,
+    }
+}
+
+#[cfg(feature = "kafka")]
+pub fn list_committed_offsets_ok<'a>(
+    committed: impl IntoIterator<Item = (&'a str, u64)>,
+) -> MessageBody {
+    MessageBody::ListCommittedOffsetsOk {
+        msg_id: 0,
+        in_reply_to: 0,
+        offsets: offsets(committed),
+    }
+}
+
+/// `txn_ok` with the completed micro-operations
+#[cfg(feature = "txn")]
+pub fn txn_ok<'a>(ops: impl IntoIterator<Item = (&'a str, u64, Option<u64>)>) -> MessageBody {
+    MessageBody::TxnOk {
+        msg_id: 0,
+        in_reply_to: 0,
+        txn: ops
+            .into_iter()
+            .map(|(op, key, value)| (op.to_string(), key, value))
+            .collect(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_builders_fill_in_defaults() {
+        let message = init("n2", ["n1", "n2"]);
+        assert_eq!(message.src, "c1");
+        assert_eq!(message.dest, "n2");
+        let (msg_id, node_id, node_ids) = expect_body!(
+            message.body,
+            Init {
+                msg_id,
+                node_id,
+                node_ids
+            }
+        );
+        assert_eq!(msg_id, 1);
+        assert_eq!(node_id, "n2");
+        assert_eq!(node_ids, vec!["n1", "n2"]);
+
+        let message = send("c2", "k1", 5);
+        assert_eq!(message.src, "c2");
+        let (key, msg) = expect_body!(message.body, Send { key, msg, .. });
+        assert_eq!(key, "k1");
+        assert_eq!(msg, 5);
+    }
+
+    #[test]
+    fn test_overrides_chain() {
+        let message = broadcast(42).src("n2").dest("n3").with_msg_id(9);
+        assert_eq!(message.src, "n2");
+        assert_eq!(message.dest, "n3");
+        assert_eq!(message.body.msg_id(), Some(9));
+
+        let message = txn([("w", 1, Some(2)), ("r", 1, None)]).with_msg_id(4);
+        let (msg_id, txn) = expect_body!(message.body, Txn { msg_id, txn });
+        assert_eq!(msg_id, 4);
+        assert_eq!(txn[0], ("w".to_string(), 1, Some(2)));
+    }
+
+    #[test]
+    fn test_expect_body_binds_fields_by_reference_or_value() {
+        let message = echo("hi");
+        expect_body!(&message.body, Echo { .. });
+        let echoed = expect_body!(&message.body, Echo { echo });
+        assert_eq!(echoed, "hi");
+        let msg_id = expect_body!(message.body, Echo { msg_id, .. });
+        assert_eq!(msg_id, 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "Expected SendOk message, got Echo")]
+    fn test_expect_body_names_the_wanted_variant() {
+        expect_body!(echo("hi").body, SendOk { offset });
+    }
+}
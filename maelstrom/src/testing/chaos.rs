@@ -0,0 +1,299 @@
+//! A handler whose own sends go wrong on purpose: [`ChaosHandler`] drops, duplicates, or holds
+//! back what the wrapped handler writes, by a seeded [`Chaos`] policy, so retry, ack, and
+//! anti-entropy paths run in ordinary unit tests or under [`Sim`](crate::sim::Sim).
+//!
+//! ```ignore
+//! let mut sim = Sim::new(3, |_| ChaosHandler::new(BroadcastNode::new(), Chaos::new(7).drop(0.3)));
+//! ```
+//!
+//! Delays count handler calls rather than time: a held message goes out with whatever the
+//! handler writes on a later call (a tick, say), behind fresher messages. Only messages between
+//! nodes are disturbed unless [`Chaos::clients`] says otherwise; acks and retransmissions
+//! written by the framework itself pass untouched.
+
+use crate::{
+    Invalid, MaelstromError, Message,
+    admin::Introspect,
+    rpc::Call,
+    runtime::{MembershipChange, MessageHandler, Node},
+    trace,
+};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::time::Duration;
+
+/// What goes wrong, and how often
+#[derive(Debug, Clone)]
+pub struct Chaos {
+    seed: u64,
+    drop: f64,
+    duplicate: f64,
+    delay: f64,
+    max_delay: usize,
+    clients: bool,
+}
+
+impl Chaos {
+    /// No faults yet; every draw comes from `seed`
+    pub fn new(seed: u64) -> Self {
+        Self {
+            seed,
+            drop: 0.0,
+            duplicate: 0.0,
+            delay: 0.0,
+            max_delay: 0,
+            clients: false,
+        }
+    }
+
+    /// Lose each message with probability `rate`
+    pub fn drop(mut self, rate: f64) -> Self {
+        self.drop = rate.clamp(0.0, 1.0);
+        self
+    }
+
+    /// Send each message twice with probability `rate`
+    pub fn duplicate(mut self, rate: f64) -> Self {
+        self.duplicate = rate.clamp(0.0, 1.0);
+        self
+    }
+
+    /// Hold each message back with probability `rate`, for 1 to `calls` later handler calls
+    pub fn delay(mut self, rate: f64, calls: usize) -> Self {
+        self.delay = rate.clamp(0.0, 1.0);
+        self.max_delay = calls.max(1);
+        self
+    }
+
+    /// Disturb replies to clients as well
+    pub fn clients(mut self) -> Self {
+        self.clients = true;
+        self
+    }
+}
+
+/// How many messages each fault hit so far
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ChaosStats {
+    pub dropped: u64,
+    pub duplicated: u64,
+    pub delayed: u64,
+}
+
+/// `handler` with its outbound messages disturbed by a [`Chaos`] policy; every hook is passed
+/// through and everything it returns is disturbed
+pub struct ChaosHandler<H> {
+    handler: H,
+    chaos: Chaos,
+    rng: StdRng,
+    /// Messages held back, with the handler calls left before they go out
+    held: Vec<(usize, Message)>,
+    stats: ChaosStats,
+}
+
+impl<H: MessageHandler> ChaosHandler<H> {
+    pub fn new(handler: H, chaos: Chaos) -> Self {
+        Self {
+            handler,
+            rng: StdRng::seed_from_u64(chaos.seed),
+            chaos,
+            held: Vec::new(),
+            stats: ChaosStats::default(),
+        }
+    }
+
+    /// The wrapped handler
+    pub fn inner(&self) -> &H {
+        &self.handler
+    }
+
+    /// The wrapped handler, mutably
+    pub fn inner_mut(&mut self) -> &mut H {
+        &mut self.handler
+    }
+
+    pub fn stats(&self) -> ChaosStats {
+        self.stats
+    }
+
+    /// Release every held message now
+    pub fn flush(&mut self) -> Vec<Message> {
+        self.held.drain(..).map(|(_, message)| message).collect()
+    }
+
+    fn disturb(&mut self, messages: Vec<Message>) -> Vec<Message> {
+        let mut out = Vec::with_capacity(messages.len());
+        for message in messages {
+            if !self.chaos.clients && trace::is_client(&message.dest) {
+                out.push(message);
+            } else if self.rng.random_bool(self.chaos.drop) {
+                self.stats.dropped += 1;
+            } else if self.rng.random_bool(self.chaos.delay) {
+                self.stats.delayed += 1;
+                let calls = self.rng.random_range(1..=self.chaos.max_delay);
+                self.held.push((calls, message));
+            } else {
+                if self.rng.random_bool(self.chaos.duplicate) {
+                    self.stats.duplicated += 1;
+                    out.push(message.clone());
+                }
+                out.push(message);
+            }
+        }
+        // Held messages count down on every call and go out behind this call's own
+        for (calls, _) in &mut self.held {
+            *calls -= 1;
+        }
+        let (due, held): (Vec<_>, Vec<_>) = self.held.drain(..).partition(|(calls, _)| *calls == 0);
+        self.held = held;
+        out.extend(due.into_iter().map(|(_, message)| message));
+        out
+    }
+}
+
+impl<H: MessageHandler> MessageHandler for ChaosHandler<H> {
+    fn handle(&mut self, node: &mut Node, message: Message) -> Vec<Message> {
+        let out = self.handler.handle(node, message);
+        self.disturb(out)
+    }
+
+    fn try_handle(
+        &mut self,
+        node: &mut Node,
+        message: Message,
+    ) -> Result<Vec<Message>, MaelstromError> {
+        let out = self.handler.try_handle(node, message)?;
+        Ok(self.disturb(out))
+    }
+
+    fn validate(&self, node: &Node, message: &Message) -> Result<(), Invalid> {
+        self.handler.validate(node, message)
+    }
+
+    fn on_tick(&mut self, node: &mut Node) -> Vec<Message> {
+        let out = self.handler.on_tick(node);
+        self.disturb(out)
+    }
+
+    fn tick_interval(&self) -> Option<Duration> {
+        self.handler.tick_interval()
+    }
+
+    fn prioritize_clients(&self) -> bool {
+        self.handler.prioritize_clients()
+    }
+
+    fn on_rpc_reply(&mut self, node: &mut Node, call: Call, reply: Message) -> Vec<Message> {
+        let out = self.handler.on_rpc_reply(node, call, reply);
+        self.disturb(out)
+    }
+
+    fn on_rpc_timeout(&mut self, node: &mut Node, call: Call) -> Vec<Message> {
+        let out = self.handler.on_rpc_timeout(node, call);
+        self.disturb(out)
+    }
+
+    fn on_node_init(&mut self, node: &Node) {
+        self.handler.on_node_init(node);
+    }
+
+    fn on_message(&mut self, node: &Node, message: &Message) {
+        self.handler.on_message(node, message);
+    }
+
+    fn on_send(&mut self, node: &Node, message: &Message) {
+        self.handler.on_send(node, message);
+    }
+
+    fn on_membership_change(&mut self, node: &mut Node, change: &MembershipChange) -> Vec<Message> {
+        let out = self.handler.on_membership_change(node, change);
+        self.disturb(out)
+    }
+
+    fn on_circuit_open(&mut self, node: &mut Node, peer: &str) -> Vec<Message> {
+        let out = self.handler.on_circuit_open(node, peer);
+        self.disturb(out)
+    }
+
+    fn introspect(&self) -> Option<&dyn Introspect> {
+        self.handler.introspect()
+    }
+
+    fn stats(&self, node: &Node) -> Option<serde_json::Value> {
+        self.handler.stats(node)
+    }
+
+    fn on_config_change(&mut self, node: &mut Node) -> Vec<Message> {
+        let out = self.handler.on_config_change(node);
+        self.disturb(out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{MessageBody, testing};
+
+    /// Answers every request and copies it to `n2`
+    struct Forward;
+
+    impl MessageHandler for Forward {
+        fn handle(&mut self, node: &mut Node, message: Message) -> Vec<Message> {
+            let copy = node.send("n2".to_string(), message.body.clone());
+            vec![node.reply(message.src, message.body), copy]
+        }
+    }
+
+    fn run(chaos: Chaos, requests: u64) -> (Vec<Message>, ChaosStats) {
+        let mut handler = ChaosHandler::new(Forward, chaos);
+        let mut node = Node::new();
+        node.handle_init("n1".to_string(), vec!["n1".to_string(), "n2".to_string()]);
+        let mut out = Vec::new();
+        for msg_id in 1..=requests {
+            out.extend(handler.handle(&mut node, testing::echo("hi").with_msg_id(msg_id)));
+        }
+        out.extend(handler.flush());
+        (out, handler.stats())
+    }
+
+    fn to(out: &[Message], dest: &str) -> Vec<u64> {
+        out.iter()
+            .filter(|message| message.dest == dest)
+            .map(|message| match &message.body {
+                MessageBody::Echo { msg_id, .. } => *msg_id,
+                other => panic!("unexpected {other:?}"),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_drops_and_duplicates_spare_clients() {
+        let (out, stats) = run(Chaos::new(1).drop(1.0), 10);
+        assert_eq!(to(&out, "c1").len(), 10);
+        assert!(to(&out, "n2").is_empty());
+        assert_eq!(stats.dropped, 10);
+
+        let (out, stats) = run(Chaos::new(1).duplicate(1.0).clients(), 10);
+        assert_eq!(to(&out, "c1").len(), 20);
+        assert_eq!(to(&out, "n2").len(), 20);
+        assert_eq!(stats.duplicated, 20);
+    }
+
+    #[test]
+    fn test_delayed_messages_arrive_later_and_out_of_order() {
+        let (out, stats) = run(Chaos::new(3).delay(0.5, 3), 40);
+        let mut sent = to(&out, "n2");
+        assert!(stats.delayed > 0);
+        assert!(sent.windows(2).any(|pair| pair[0] > pair[1]), "{sent:?}");
+        sent.sort();
+        assert_eq!(sent, (1..=40).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_seeds_repeat_their_faults() {
+        let chaos = |seed| Chaos::new(seed).drop(0.3).duplicate(0.2).delay(0.2, 2);
+        let debug = |seed| format!("{:?}", run(chaos(seed), 50));
+        assert_eq!(debug(9), debug(9));
+        assert_ne!(run(chaos(9), 50).1, run(chaos(10), 50).1);
+    }
+}
@@ -0,0 +1,1029 @@
+//! Checkers for simulated runs: each reads a [`Sim`](crate::sim::Sim)'s client history, and the nodes' state
+//! where the workload has no read that shows everything, and reports which of the workload's
+//! guarantees held, so a test can assert on the report instead of re-deriving invariants.
+
+#[cfg(feature = "txn")]
+use crate::ErrorCode;
+#[cfg(any(
+    feature = "broadcast",
+    feature = "counter",
+    feature = "kafka",
+    feature = "txn"
+))]
+use crate::MessageBody;
+#[cfg(any(feature = "kafka", feature = "txn"))]
+use crate::sim::Op;
+#[cfg(any(feature = "broadcast", feature = "counter"))]
+use crate::{MessageHandler, sim::Sim};
+#[cfg(any(
+    feature = "broadcast",
+    feature = "counter",
+    feature = "kafka",
+    feature = "txn"
+))]
+use std::collections::BTreeMap;
+#[cfg(any(feature = "broadcast", feature = "kafka", feature = "txn"))]
+use std::collections::BTreeSet;
+use std::fmt;
+use std::time::Duration;
+
+/// Latency distribution of some samples; all zero when there were none
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Percentiles {
+    pub p50: Duration,
+    pub p95: Duration,
+    pub p99: Duration,
+    pub max: Duration,
+}
+
+impl Percentiles {
+    pub fn of(mut samples: Vec<Duration>) -> Self {
+        samples.sort();
+        let at = |quantile: f64| {
+            let i = ((samples.len() as f64 * quantile).ceil() as usize).saturating_sub(1);
+            samples.get(i).copied().unwrap_or_default()
+        };
+        Self {
+            p50: at(0.5),
+            p95: at(0.95),
+            p99: at(0.99),
+            max: samples.last().copied().unwrap_or_default(),
+        }
+    }
+}
+
+impl fmt::Display for Percentiles {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "p50 {:?}, p95 {:?}, p99 {:?}, max {:?}",
+            self.p50, self.p95, self.p99, self.max
+        )
+    }
+}
+
+/// What [`check_broadcast`] found
+#[cfg(feature = "broadcast")]
+#[derive(Debug, Clone)]
+pub struct BroadcastReport {
+    /// Broadcasts the cluster acknowledged
+    pub acknowledged: usize,
+    /// Acknowledged values some node never got, with the nodes missing them
+    pub lost: BTreeMap<u64, Vec<String>>,
+    /// Values a node holds that no client ever broadcast, by node
+    pub invented: BTreeMap<String, Vec<u64>>,
+    /// Messages between nodes per acknowledged broadcast
+    pub messages_per_op: f64,
+    /// Time from a broadcast being sent until every node had its value
+    pub latency: Percentiles,
+}
+
+#[cfg(feature = "broadcast")]
+impl BroadcastReport {
+    pub fn is_valid(&self) -> bool {
+        self.lost.is_empty() && self.invented.is_empty()
+    }
+
+    /// Panic with the violations unless the run was valid
+    pub fn assert_valid(&self) {
+        assert!(
+            self.is_valid(),
+            "broadcast invariants violated: lost {:?}, invented {:?}",
+            self.lost,
+            self.invented
+        );
+    }
+}
+
+/// Run `sim` until every acknowledged broadcast is on every node, or for at most `limit` of
+/// simulated time, then check the broadcast workload's guarantees. `values` reads the values
+/// a node's handler holds.
+#[cfg(feature = "broadcast")]
+pub fn check_broadcast<H: MessageHandler, V: IntoIterator<Item = u64>>(
+    sim: &mut Sim<H>,
+    limit: Duration,
+    values: impl Fn(&H) -> V,
+) -> BroadcastReport {
+    let held = |sim: &Sim<H>| -> BTreeMap<String, BTreeSet<u64>> {
+        sim.node_ids()
+            .into_iter()
+            .map(|id| {
+                let held = values(sim.handler(&id)).into_iter().collect();
+                (id, held)
+            })
+            .collect()
+    };
+    // When each value first reached every node
+    let mut everywhere: BTreeMap<u64, Duration> = BTreeMap::new();
+    sim.run_until(limit, |sim| {
+        let held = held(sim);
+        let mut common = held.values();
+        let first = common.next().cloned().unwrap_or_default();
+        for value in common.fold(first, |all, node| &all & node) {
+            everywhere.entry(value).or_insert(sim.now());
+        }
+        sim.history().iter().all(|op| match &op.request {
+            MessageBody::Broadcast { message, .. } => {
+                op.completed.is_some() && (op.ok().is_none() || everywhere.contains_key(message))
+            }
+            _ => true,
+        })
+    });
+
+    let mut sent = BTreeSet::new();
+    let mut acknowledged = BTreeMap::new();
+    for op in sim.history() {
+        if let MessageBody::Broadcast { message, .. } = op.request {
+            sent.insert(message);
+            if op.ok().is_some() {
+                let invoked = acknowledged.entry(message).or_insert(op.invoked);
+                *invoked = op.invoked.min(*invoked);
+            }
+        }
+    }
+
+    let held = held(sim);
+    let mut lost: BTreeMap<u64, Vec<String>> = BTreeMap::new();
+    for (id, values) in &held {
+        for value in acknowledged.keys().filter(|value| !values.contains(value)) {
+            lost.entry(*value).or_default().push(id.clone());
+        }
+    }
+    let invented = held
+        .into_iter()
+        .map(|(id, values)| (id, values.difference(&sent).copied().collect::<Vec<_>>()))
+        .filter(|(_, values)| !values.is_empty())
+        .collect();
+    let latency = acknowledged
+        .iter()
+        .filter_map(|(value, invoked)| Some(everywhere.get(value)?.saturating_sub(*invoked)))
+        .collect();
+
+    BroadcastReport {
+        acknowledged: acknowledged.len(),
+        lost,
+        invented,
+        messages_per_op: sim.internal as f64 / acknowledged.len().max(1) as f64,
+        latency: Percentiles::of(latency),
+    }
+}
+
+/// What [`check_kafka`] found; offsets are listed with their key
+#[cfg(feature = "kafka")]
+#[derive(Debug, Clone, Default)]
+pub struct KafkaReport {
+    /// Sends the cluster acknowledged
+    pub acknowledged: usize,
+    /// Offsets that went backwards: a poll listing a key out of order, or a send acknowledged
+    /// with an offset at or below that of a send acknowledged before it was made
+    pub non_monotonic: Vec<(String, u64)>,
+    /// Offsets acknowledged to two different sends
+    pub reused: Vec<(String, u64)>,
+    /// Acknowledged sends a later poll skipped over
+    pub lost: Vec<(String, u64)>,
+    /// Polled offsets holding a message other than the one acknowledged there, or one never
+    /// sent to the key
+    pub inconsistent: Vec<(String, u64)>,
+    /// Committed offsets listed below an offset whose commit was acknowledged earlier
+    pub uncommitted: Vec<(String, u64)>,
+}
+
+#[cfg(feature = "kafka")]
+impl KafkaReport {
+    pub fn is_valid(&self) -> bool {
+        self.non_monotonic.is_empty()
+            && self.reused.is_empty()
+            && self.lost.is_empty()
+            && self.inconsistent.is_empty()
+            && self.uncommitted.is_empty()
+    }
+
+    /// Panic with the violations unless the history was valid
+    pub fn assert_valid(&self) {
+        assert!(self.is_valid(), "kafka invariants violated: {self:?}");
+    }
+}
+
+/// Check a kafka history, such as [`Sim::history`](crate::sim::Sim::history), against the
+/// workload's guarantees. Only what clients were told counts: a poll must show every send
+/// acknowledged before it was made, but may miss sends still in progress.
+#[cfg(feature = "kafka")]
+pub fn check_kafka(history: &[Op]) -> KafkaReport {
+    struct Acked<'a> {
+        op: &'a Op,
+        msg: u64,
+        offset: u64,
+    }
+
+    let mut report = KafkaReport::default();
+    let mut sent: BTreeMap<&str, BTreeSet<u64>> = BTreeMap::new();
+    let mut acked: BTreeMap<&str, Vec<Acked>> = BTreeMap::new();
+    for op in history {
+        if let MessageBody::Send { key, msg, .. } = &op.request {
+            sent.entry(key).or_default().insert(*msg);
+            if let Some(MessageBody::SendOk { offset, .. }) = op.ok() {
+                acked.entry(key).or_default().push(Acked {
+                    op,
+                    msg: *msg,
+                    offset: *offset,
+                });
+            }
+        }
+    }
+    report.acknowledged = acked.values().map(Vec::len).sum();
+
+    // Acknowledged sends by key and offset, keeping the first to claim each offset
+    let mut at: BTreeMap<(&str, u64), &Acked> = BTreeMap::new();
+    for (key, sends) in &acked {
+        for send in sends {
+            if at.insert((key, send.offset), send).is_some() {
+                report.reused.push((key.to_string(), send.offset));
+            }
+        }
+        for later in sends {
+            let overtaken = sends.iter().any(|earlier| {
+                earlier.offset >= later.offset
+                    && earlier
+                        .op
+                        .completed
+                        .as_ref()
+                        .is_some_and(|(done, _)| *done <= later.op.invoked)
+            });
+            if overtaken {
+                report.non_monotonic.push((key.to_string(), later.offset));
+            }
+        }
+    }
+
+    let mut committed: BTreeMap<&str, Vec<(Duration, u64)>> = BTreeMap::new();
+    for op in history {
+        if let (MessageBody::CommitOffsets { offsets, .. }, Some(_)) = (&op.request, op.ok()) {
+            let (done, _) = op.completed.as_ref().expect("ok ops are completed");
+            for (key, offset) in offsets {
+                committed.entry(key).or_default().push((*done, *offset));
+            }
+        }
+    }
+
+    for op in history {
+        match (&op.request, op.ok()) {
+            (MessageBody::Poll { offsets, .. }, Some(MessageBody::PollOk { msgs, .. })) => {
+                for (key, entries) in msgs {
+                    let key = key.as_str();
+                    let entries = entries.to_vec();
+                    for pair in entries.windows(2) {
+                        if pair[1].0 <= pair[0].0 {
+                            report.non_monotonic.push((key.to_string(), pair[1].0));
+                        }
+                    }
+                    for (offset, msg) in &entries {
+                        let expected = at.get(&(key, *offset)).map(|send| send.msg);
+                        let known = sent.get(key).is_some_and(|sent| sent.contains(msg));
+                        if expected.is_some_and(|expected| expected != *msg) || !known {
+                            report.inconsistent.push((key.to_string(), *offset));
+                        }
+                    }
+                    let polled: BTreeSet<u64> = entries.iter().map(|(offset, _)| *offset).collect();
+                    let (Some(from), Some(last)) = (offsets.get(key), polled.last()) else {
+                        continue;
+                    };
+                    for send in acked.get(key).into_iter().flatten() {
+                        let before = send
+                            .op
+                            .completed
+                            .as_ref()
+                            .is_some_and(|(done, _)| *done <= op.invoked);
+                        if before
+                            && (*from..=*last).contains(&send.offset)
+                            && !polled.contains(&send.offset)
+                        {
+                            report.lost.push((key.to_string(), send.offset));
+                        }
+                    }
+                }
+            }
+            (
+                MessageBody::ListCommittedOffsets { keys, .. },
+                Some(MessageBody::ListCommittedOffsetsOk { offsets, .. }),
+            ) => {
+                for key in keys {
+                    let floor = committed
+                        .get(key.as_str())
+                        .into_iter()
+                        .flatten()
+                        .filter(|(done, _)| *done <= op.invoked)
+                        .map(|(_, offset)| *offset)
+                        .max();
+                    if let Some(floor) = floor
+                        && offsets.get(key).is_none_or(|listed| *listed < floor)
+                    {
+                        report.uncommitted.push((key.clone(), floor));
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    report
+}
+
+/// How often [`check_counter`] reads every node while waiting for them to agree
+#[cfg(feature = "counter")]
+const READ_EVERY: Duration = Duration::from_millis(100);
+
+/// What [`check_counter`] found
+#[cfg(feature = "counter")]
+#[derive(Debug, Clone)]
+pub struct CounterReport {
+    /// Sum of the acknowledged adds
+    pub acknowledged: u64,
+    /// Sum of every add sent, acknowledged or not
+    pub submitted: u64,
+    /// Each node's last read
+    pub reads: BTreeMap<String, u64>,
+    /// When every node first read the same, valid total; `None` if they never did
+    pub converged_at: Option<Duration>,
+    /// Reads, by node, above the total of the adds sent before they returned
+    pub over_reads: Vec<(String, u64)>,
+}
+
+#[cfg(feature = "counter")]
+impl CounterReport {
+    pub fn is_valid(&self) -> bool {
+        self.converged_at.is_some() && self.over_reads.is_empty()
+    }
+
+    /// Panic with the violations unless the run was valid
+    pub fn assert_valid(&self) {
+        assert!(self.is_valid(), "counter invariants violated: {self:?}");
+    }
+}
+
+/// Let `sim` finish its adds, then read every node until they all return the sum of the
+/// acknowledged adds, for at most `limit` of simulated time. Adds that failed or never
+/// returned may or may not have been applied, so with some of those the nodes only need to
+/// agree on a total between the acknowledged and submitted sums. Every read in the history,
+/// the checker's own included, must stay within the adds sent before it returned.
+#[cfg(feature = "counter")]
+pub fn check_counter<H: MessageHandler>(sim: &mut Sim<H>, limit: Duration) -> CounterReport {
+    let until = sim.now() + limit;
+    sim.run_until(limit, |sim| {
+        sim.history().iter().all(|op| op.completed.is_some())
+    });
+
+    let (mut acknowledged, mut submitted) = (0, 0);
+    for op in sim.history() {
+        if let MessageBody::Add { delta, .. } = op.request {
+            submitted += delta;
+            if op.ok().is_some() {
+                acknowledged += delta;
+            }
+        }
+    }
+
+    let mut reads = BTreeMap::new();
+    let converged_at = loop {
+        for id in sim.node_ids() {
+            // `c0` sent the init messages; its later ids follow the history so they never repeat
+            let msg_id = sim.history().len() as u64 + 1;
+            let read = crate::testing::read()
+                .src("c0")
+                .dest(&id)
+                .with_msg_id(msg_id);
+            if let Some(MessageBody::ReadOk {
+                value: Some(value), ..
+            }) = sim.call(read).map(|reply| reply.body)
+            {
+                reads.insert(id, value);
+            }
+        }
+        let mut values = reads.values();
+        let agreed = values.next().filter(|first| {
+            reads.len() == sim.node_ids().len()
+                && values.all(|value| value == *first)
+                && (acknowledged..=submitted).contains(*first)
+        });
+        if agreed.is_some() {
+            break Some(sim.now());
+        }
+        if sim.now() >= until {
+            break None;
+        }
+        sim.run_for(READ_EVERY.min(until - sim.now()));
+    };
+
+    let mut over_reads = Vec::new();
+    for op in sim.history() {
+        let Some((
+            done,
+            MessageBody::ReadOk {
+                value: Some(value), ..
+            },
+        )) = &op.completed
+        else {
+            continue;
+        };
+        let sent_before: u64 = sim
+            .history()
+            .iter()
+            .filter(|add| add.invoked <= *done)
+            .filter_map(|add| match add.request {
+                MessageBody::Add { delta, .. } => Some(delta),
+                _ => None,
+            })
+            .sum();
+        if *value > sent_before {
+            over_reads.push((op.node.clone(), *value));
+        }
+    }
+
+    CounterReport {
+        acknowledged,
+        submitted,
+        reads,
+        converged_at,
+        over_reads,
+    }
+}
+
+/// What [`check_txn`] found. Transactions are named by their index in the history, reads by
+/// the reading transaction, key, and value.
+#[cfg(feature = "txn")]
+#[derive(Debug, Clone, Default)]
+pub struct TxnReport {
+    pub committed: usize,
+    /// Transactions that failed with an error saying they did not happen
+    pub aborted: usize,
+    /// G0: cycles of committed transactions each overwriting a value the previous one wrote
+    pub dirty_writes: Vec<Vec<usize>>,
+    /// G1a: committed reads of a value only an aborted transaction wrote
+    pub aborted_reads: Vec<(usize, u64, u64)>,
+    /// G1b: committed reads of a value its transaction overwrote before finishing
+    pub intermediate_reads: Vec<(usize, u64, u64)>,
+}
+
+#[cfg(feature = "txn")]
+impl TxnReport {
+    /// Whether the history has none of the anomalies read committed rules out
+    pub fn is_valid(&self) -> bool {
+        self.dirty_writes.is_empty()
+            && self.aborted_reads.is_empty()
+            && self.intermediate_reads.is_empty()
+    }
+
+    /// Panic with the anomalies unless the history was valid
+    pub fn assert_valid(&self) {
+        assert!(self.is_valid(), "txn anomalies found: {self:?}");
+    }
+}
+
+/// Look for G0, G1a, and G1b in a history of `txn` requests against read-write registers,
+/// such as [`Sim::history`](crate::sim::Sim::history). Like Maelstrom's workload it assumes
+/// every write to a key carries a value never written to that key before, so a read names
+/// its writer. Transactions that timed out or crashed may have committed, so reads of their
+/// writes are never anomalies.
+#[cfg(feature = "txn")]
+pub fn check_txn(history: &[Op]) -> TxnReport {
+    #[derive(PartialEq)]
+    enum Outcome {
+        Committed,
+        Aborted,
+        Unknown,
+    }
+    type MicroOps = [(String, u64, Option<u64>)];
+
+    let mut report = TxnReport::default();
+    let mut txns: BTreeMap<usize, (Outcome, &MicroOps)> = BTreeMap::new();
+    for (i, op) in history.iter().enumerate() {
+        let MessageBody::Txn { txn: request, .. } = &op.request else {
+            continue;
+        };
+        let txn = match &op.completed {
+            Some((_, MessageBody::TxnOk { txn, .. })) => (Outcome::Committed, txn.as_slice()),
+            Some((_, MessageBody::Error { code, .. }))
+                if !matches!(
+                    code,
+                    ErrorCode::Timeout | ErrorCode::Crash | ErrorCode::Other
+                ) =>
+            {
+                (Outcome::Aborted, request.as_slice())
+            }
+            _ => (Outcome::Unknown, request.as_slice()),
+        };
+        match txn.0 {
+            Outcome::Committed => report.committed += 1,
+            Outcome::Aborted => report.aborted += 1,
+            Outcome::Unknown => {}
+        }
+        txns.insert(i, txn);
+    }
+
+    // The writer of each key and value, and whether it was that writer's last write of the key
+    let mut writers: BTreeMap<(u64, u64), (usize, bool)> = BTreeMap::new();
+    for (&i, (_, ops)) in &txns {
+        for (at, (op, key, value)) in ops.iter().enumerate() {
+            let (Some(value), "w") = (value, op.as_str()) else {
+                continue;
+            };
+            let last = !ops[at + 1..]
+                .iter()
+                .any(|(op, later, _)| op == "w" && later == key);
+            writers.insert((*key, *value), (i, last));
+        }
+    }
+
+    // Write-write dependencies between committed transactions: a transaction that read a
+    // key before writing it overwrote the version it read
+    let mut overwrote: BTreeMap<usize, BTreeSet<usize>> = BTreeMap::new();
+    for (&i, (outcome, ops)) in &txns {
+        if *outcome != Outcome::Committed {
+            continue;
+        }
+        let mut written = BTreeSet::new();
+        for (at, (op, key, value)) in ops.iter().enumerate() {
+            if op == "w" {
+                written.insert(*key);
+                continue;
+            }
+            let Some(value) = value else { continue };
+            // Reads of the transaction's own writes say nothing about others
+            if written.contains(key) {
+                continue;
+            }
+            let Some(&(writer, last)) = writers.get(&(*key, *value)) else {
+                continue;
+            };
+            if writer == i {
+                continue;
+            }
+            let (writer_outcome, _) = &txns[&writer];
+            if *writer_outcome == Outcome::Aborted {
+                report.aborted_reads.push((i, *key, *value));
+            }
+            if !last {
+                report.intermediate_reads.push((i, *key, *value));
+            }
+            let rewrites = ops[at + 1..]
+                .iter()
+                .any(|(op, later, _)| op == "w" && later == key);
+            if rewrites && *writer_outcome == Outcome::Committed {
+                overwrote.entry(writer).or_default().insert(i);
+            }
+        }
+    }
+    report.dirty_writes = cycles(&overwrote);
+    report
+}
+
+/// Strongly connected components of more than one node, found with Tarjan's algorithm
+#[cfg(feature = "txn")]
+fn cycles(edges: &BTreeMap<usize, BTreeSet<usize>>) -> Vec<Vec<usize>> {
+    struct Tarjan<'a> {
+        edges: &'a BTreeMap<usize, BTreeSet<usize>>,
+        next: usize,
+        /// Visit order and lowest index reachable, by node
+        index: BTreeMap<usize, (usize, usize)>,
+        stack: Vec<usize>,
+        on_stack: BTreeSet<usize>,
+        found: Vec<Vec<usize>>,
+    }
+
+    impl Tarjan<'_> {
+        fn visit(&mut self, at: usize) {
+            self.index.insert(at, (self.next, self.next));
+            self.next += 1;
+            self.stack.push(at);
+            self.on_stack.insert(at);
+            for &to in self.edges.get(&at).into_iter().flatten() {
+                if !self.index.contains_key(&to) {
+                    self.visit(to);
+                    let low = self.index[&to].1;
+                    let entry = self.index.get_mut(&at).expect("visited");
+                    entry.1 = entry.1.min(low);
+                } else if self.on_stack.contains(&to) {
+                    let low = self.index[&to].0;
+                    let entry = self.index.get_mut(&at).expect("visited");
+                    entry.1 = entry.1.min(low);
+                }
+            }
+            let (index, low) = self.index[&at];
+            if index == low {
+                let mut component = Vec::new();
+                while let Some(member) = self.stack.pop() {
+                    self.on_stack.remove(&member);
+                    component.push(member);
+                    if member == at {
+                        break;
+                    }
+                }
+                if component.len() > 1 {
+                    component.sort();
+                    self.found.push(component);
+                }
+            }
+        }
+    }
+
+    let mut tarjan = Tarjan {
+        edges,
+        next: 0,
+        index: BTreeMap::new(),
+        stack: Vec::new(),
+        on_stack: BTreeSet::new(),
+        found: Vec::new(),
+    };
+    for &from in edges.keys() {
+        if !tarjan.index.contains_key(&from) {
+            tarjan.visit(from);
+        }
+    }
+    tarjan.found
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ErrorCode, Message, Node, testing};
+    use std::collections::HashMap;
+
+    /// Floods each new value to every peer, plus `extra` if set, which no client sent
+    #[derive(Default)]
+    struct Flood {
+        values: BTreeSet<u64>,
+        extra: Option<u64>,
+    }
+
+    impl MessageHandler for Flood {
+        fn handle(&mut self, node: &mut Node, message: Message) -> Vec<Message> {
+            let mut fresh = Vec::new();
+            let mut out = Vec::new();
+            match message.body {
+                MessageBody::Init {
+                    msg_id,
+                    node_id,
+                    node_ids,
+                } => {
+                    node.handle_init(node_id, node_ids);
+                    return vec![node.init_ok(message.src, msg_id)];
+                }
+                MessageBody::Broadcast {
+                    msg_id,
+                    message: value,
+                } => {
+                    fresh.push(value);
+                    fresh.extend(self.extra.take());
+                    out.push(node.answer(message.src, msg_id, |msg_id, in_reply_to| {
+                        MessageBody::BroadcastOk {
+                            msg_id,
+                            in_reply_to,
+                        }
+                    }));
+                }
+                MessageBody::BroadcastGossip { messages, .. } => fresh = messages,
+                _ => {}
+            }
+            fresh.retain(|value| self.values.insert(*value));
+            if !fresh.is_empty() {
+                for peer in &node.peers {
+                    out.push(Message {
+                        src: node.id.clone(),
+                        dest: peer.clone(),
+                        body: MessageBody::BroadcastGossip {
+                            msg_id: None,
+                            messages: fresh.clone(),
+                        },
+                    });
+                }
+            }
+            out
+        }
+    }
+
+    #[test]
+    fn test_percentiles_pick_the_nearest_rank() {
+        let samples = (1..=100).map(Duration::from_millis).collect();
+        let percentiles = Percentiles::of(samples);
+        assert_eq!(percentiles.p50, Duration::from_millis(50));
+        assert_eq!(percentiles.p99, Duration::from_millis(99));
+        assert_eq!(percentiles.max, Duration::from_millis(100));
+        assert_eq!(Percentiles::of(Vec::new()), Percentiles::default());
+    }
+
+    #[test]
+    fn test_broadcast_run_is_measured() {
+        let mut sim = Sim::new(3, |_| Flood::default());
+        sim.latency(Duration::from_millis(10), Duration::from_millis(10));
+        sim.send(testing::broadcast(1).dest("n1"));
+        sim.send(testing::broadcast(2).dest("n2").with_msg_id(2));
+
+        let report = check_broadcast(&mut sim, Duration::from_secs(1), |flood| {
+            flood.values.clone()
+        });
+        report.assert_valid();
+        assert_eq!(report.acknowledged, 2);
+        // Each value goes out to two peers, which each pass it on to both of theirs
+        assert_eq!(report.messages_per_op, 6.0);
+        assert_eq!(report.latency.max, Duration::from_millis(20));
+    }
+
+    #[test]
+    fn test_broadcast_violations_are_reported() {
+        let mut sim = Sim::new(3, |id| Flood {
+            extra: (id == "n1").then_some(99),
+            ..Flood::default()
+        });
+        sim.partition(&[&["n1", "n2"], &["n3"]]);
+        sim.send(testing::broadcast(1));
+
+        let report = check_broadcast(&mut sim, Duration::from_secs(1), |flood| {
+            flood.values.clone()
+        });
+        assert!(!report.is_valid());
+        assert_eq!(report.lost[&1], vec!["n3".to_string()]);
+        assert_eq!(report.invented["n1"], vec![99]);
+    }
+
+    fn op(request: Message, invoked: u64, completed: u64, reply: MessageBody) -> Op {
+        Op {
+            client: request.src,
+            node: request.dest,
+            request: request.body,
+            invoked: Duration::from_millis(invoked),
+            completed: Some((Duration::from_millis(completed), reply)),
+        }
+    }
+
+    fn send_ok(offset: u64) -> MessageBody {
+        MessageBody::SendOk {
+            msg_id: 1,
+            in_reply_to: 1,
+            offset,
+        }
+    }
+
+    fn poll_ok(key: &str, entries: Vec<(u64, u64)>) -> MessageBody {
+        MessageBody::PollOk {
+            msg_id: 1,
+            in_reply_to: 1,
+            msgs: [(key.to_string(), entries.into())].into(),
+            next_offsets: HashMap::new(),
+            has_more: false,
+        }
+    }
+
+    #[test]
+    fn test_consistent_kafka_history_is_valid() {
+        let history = vec![
+            op(testing::send("c1", "k1", 10), 0, 5, send_ok(0)),
+            op(testing::send("c2", "k1", 11), 6, 8, send_ok(1)),
+            // Still in progress when the poll below was made, so it may be missing
+            op(testing::send("c3", "k1", 12), 9, 30, send_ok(2)),
+            op(
+                testing::poll([("k1", 0)]),
+                10,
+                11,
+                poll_ok("k1", vec![(0, 10), (1, 11)]),
+            ),
+            op(
+                testing::commit_offsets([("k1", 1)]),
+                12,
+                13,
+                MessageBody::CommitOffsetsOk {
+                    msg_id: 1,
+                    in_reply_to: 1,
+                },
+            ),
+            op(
+                testing::list_committed_offsets(["k1"]),
+                14,
+                15,
+                MessageBody::ListCommittedOffsetsOk {
+                    msg_id: 1,
+                    in_reply_to: 1,
+                    offsets: [("k1".to_string(), 1)].into(),
+                },
+            ),
+        ];
+
+        let report = check_kafka(&history);
+        report.assert_valid();
+        assert_eq!(report.acknowledged, 3);
+    }
+
+    #[test]
+    fn test_kafka_violations_are_reported() {
+        let history = vec![
+            op(testing::send("c1", "k1", 10), 0, 5, send_ok(3)),
+            // Acknowledged after the first send, yet handed a lower offset, then reused
+            op(testing::send("c2", "k1", 11), 6, 8, send_ok(1)),
+            op(testing::send("c3", "k1", 12), 9, 10, send_ok(1)),
+            op(
+                testing::poll([("k1", 0)]),
+                11,
+                12,
+                poll_ok("k1", vec![(0, 99), (3, 10), (2, 12)]),
+            ),
+            op(
+                testing::commit_offsets([("k1", 3)]),
+                13,
+                14,
+                MessageBody::CommitOffsetsOk {
+                    msg_id: 1,
+                    in_reply_to: 1,
+                },
+            ),
+            op(
+                testing::list_committed_offsets(["k1"]),
+                15,
+                16,
+                MessageBody::ListCommittedOffsetsOk {
+                    msg_id: 1,
+                    in_reply_to: 1,
+                    offsets: HashMap::new(),
+                },
+            ),
+        ];
+
+        let report = check_kafka(&history);
+        assert!(!report.is_valid());
+        let k1 = |offset: u64| ("k1".to_string(), offset);
+        assert_eq!(report.reused, vec![k1(1)]);
+        assert_eq!(report.non_monotonic, vec![k1(1), k1(1), k1(2)]);
+        // Both sends acknowledged at offset 1 are missing from the poll
+        assert_eq!(report.lost, vec![k1(1), k1(1)]);
+        assert_eq!(report.inconsistent, vec![k1(0)]);
+        assert_eq!(report.uncommitted, vec![k1(3)]);
+    }
+
+    /// Counts adds locally and never tells its peers; `factor` scales each delta
+    struct Tally {
+        total: u64,
+        factor: u64,
+    }
+
+    impl MessageHandler for Tally {
+        fn handle(&mut self, node: &mut Node, message: Message) -> Vec<Message> {
+            match message.body {
+                MessageBody::Init {
+                    msg_id,
+                    node_id,
+                    node_ids,
+                } => {
+                    node.handle_init(node_id, node_ids);
+                    vec![node.init_ok(message.src, msg_id)]
+                }
+                MessageBody::Add { msg_id, delta, .. } => {
+                    self.total += delta * self.factor;
+                    vec![node.answer(message.src, msg_id, |msg_id, in_reply_to| {
+                        MessageBody::AddOk {
+                            msg_id,
+                            in_reply_to,
+                        }
+                    })]
+                }
+                MessageBody::Read { msg_id, .. } => {
+                    let value = Some(self.total);
+                    vec![node.answer(message.src, msg_id, |msg_id, in_reply_to| {
+                        MessageBody::ReadOk {
+                            msg_id,
+                            in_reply_to,
+                            messages: None,
+                            value,
+                            context: None,
+                        }
+                    })]
+                }
+                _ => Vec::new(),
+            }
+        }
+    }
+
+    fn add_all(sim: &mut Sim<Tally>, deltas: impl IntoIterator<Item = u64>) {
+        for (i, delta) in deltas.into_iter().enumerate() {
+            sim.send(testing::add(delta).with_msg_id(i as u64 + 1));
+        }
+    }
+
+    #[test]
+    fn test_counter_that_agrees_is_valid() {
+        let mut sim = Sim::new(1, |_| Tally {
+            total: 0,
+            factor: 1,
+        });
+        add_all(&mut sim, [1, 2, 3]);
+
+        let report = check_counter(&mut sim, Duration::from_secs(1));
+        report.assert_valid();
+        assert_eq!(report.acknowledged, 6);
+        assert_eq!(report.reads["n1"], 6);
+    }
+
+    #[test]
+    fn test_counter_violations_are_reported() {
+        let mut sim = Sim::new(1, |_| Tally {
+            total: 0,
+            factor: 2,
+        });
+        add_all(&mut sim, [1, 2]);
+        let report = check_counter(&mut sim, Duration::from_millis(300));
+        assert!(!report.is_valid());
+        assert_eq!(report.over_reads[0], ("n1".to_string(), 6));
+
+        // Adds that never spread leave the other nodes behind
+        let mut sim = Sim::new(3, |_| Tally {
+            total: 0,
+            factor: 1,
+        });
+        add_all(&mut sim, [4]);
+        let report = check_counter(&mut sim, Duration::from_millis(300));
+        assert!(report.over_reads.is_empty());
+        assert_eq!(report.converged_at, None);
+        assert_eq!(report.reads["n2"], 0);
+    }
+
+    type MicroOp<'a> = (&'a str, u64, Option<u64>);
+
+    /// A transaction sent at `at` ms that committed with `result`
+    fn committed(at: u64, result: Vec<MicroOp>) -> Op {
+        let request = result
+            .iter()
+            .map(|&(op, key, value)| (op, key, value.filter(|_| op == "w")));
+        let txn = result
+            .iter()
+            .map(|&(op, key, value)| (op.to_string(), key, value))
+            .collect();
+        op(
+            testing::txn(request),
+            at,
+            at + 1,
+            MessageBody::TxnOk {
+                msg_id: 1,
+                in_reply_to: 1,
+                txn,
+            },
+        )
+    }
+
+    fn failed(at: u64, request: Vec<MicroOp>, code: ErrorCode) -> Op {
+        op(
+            testing::txn(request),
+            at,
+            at + 1,
+            MessageBody::Error {
+                msg_id: 1,
+                in_reply_to: 1,
+                code,
+                text: None,
+                extra: None,
+            },
+        )
+    }
+
+    #[test]
+    fn test_read_committed_history_is_valid() {
+        let history = vec![
+            committed(0, vec![("w", 1, Some(1)), ("w", 2, Some(1))]),
+            committed(
+                2,
+                vec![("r", 1, Some(1)), ("w", 1, Some(2)), ("r", 1, Some(2))],
+            ),
+            committed(4, vec![("r", 1, Some(2)), ("r", 2, Some(1))]),
+            // A timed-out write may have happened, so reading it is fine
+            failed(6, vec![("w", 3, Some(7))], ErrorCode::Timeout),
+            committed(8, vec![("r", 3, Some(7))]),
+        ];
+
+        let report = check_txn(&history);
+        report.assert_valid();
+        assert_eq!(report.committed, 4);
+        assert_eq!(report.aborted, 0);
+    }
+
+    #[test]
+    fn test_txn_anomalies_are_reported() {
+        let history = vec![
+            // G1a: reading a write from a transaction that aborted
+            failed(0, vec![("w", 1, Some(5))], ErrorCode::TxnConflict),
+            committed(2, vec![("r", 1, Some(5))]),
+            // G1b: reading a value its writer replaced before committing
+            committed(4, vec![("w", 2, Some(1)), ("w", 2, Some(2))]),
+            committed(6, vec![("r", 2, Some(1))]),
+            // G0: each overwrites a key after reading the other's write to it
+            committed(
+                8,
+                vec![("r", 3, Some(20)), ("w", 3, Some(10)), ("w", 4, Some(11))],
+            ),
+            committed(
+                8,
+                vec![("r", 4, Some(11)), ("w", 4, Some(21)), ("w", 3, Some(20))],
+            ),
+        ];
+
+        let report = check_txn(&history);
+        assert!(!report.is_valid());
+        assert_eq!(report.aborted, 1);
+        assert_eq!(report.aborted_reads, vec![(1, 1, 5)]);
+        assert_eq!(report.intermediate_reads, vec![(3, 2, 1)]);
+        assert_eq!(report.dirty_writes, vec![vec![4, 5]]);
+    }
+}
@@ -0,0 +1,259 @@
+//! Protocol tests written as JSON: a scenario lists the messages to deliver and what each one
+//! must make the node write, so a regression test needs a file rather than Rust.
+//!
+//! ```json
+//! {
+//!   "nodes": 2,
+//!   "steps": [
+//!     { "send": { "type": "broadcast", "msg_id": 1, "message": 7 },
+//!       "expect": [
+//!         { "dest": "n2", "body": { "type": "broadcast", "message": 7 } },
+//!         { "dest": "c1", "body": { "type": "broadcast_ok", "in_reply_to": 1 } }
+//!       ] },
+//!     { "from": "c2", "send": { "type": "read", "msg_id": 1 },
+//!       "expect": [{ "body": { "type": "read_ok", "messages": [7] } }] },
+//!     { "tick": true, "expect": [] }
+//!   ]
+//! }
+//! ```
+//!
+//! Before the first step the node is initialized as `n1` of `n1..=n{nodes}` (one node by
+//! default). Each step delivers `send` from `from` (`c1` by default), or runs a tick, and
+//! everything written must match `expect` one for one, in order. Expectations are patterns: an
+//! object matches any object with at least its fields, so ids and fields of no interest are left
+//! out. [`run_dir`] runs every `*.json` in a directory.
+
+use crate::{Message, MessageBody, MessageHandler, Node, process, testing};
+use serde::Deserialize;
+use serde_json::Value;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Scenario {
+    #[serde(default = "one")]
+    nodes: usize,
+    steps: Vec<Step>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Step {
+    #[serde(default = "client")]
+    from: String,
+    /// Body to deliver; a step without one runs a tick
+    send: Option<Value>,
+    #[serde(default)]
+    tick: bool,
+    #[serde(default)]
+    expect: Vec<Value>,
+}
+
+fn one() -> usize {
+    1
+}
+
+fn client() -> String {
+    "c1".to_string()
+}
+
+/// Why a scenario failed
+#[derive(Debug)]
+pub enum ScenarioError {
+    /// Not a valid scenario
+    Parse(serde_json::Error),
+    /// Step `step` (counting from 1, 0 for init) did not go as expected
+    Step { step: usize, reason: String },
+}
+
+impl fmt::Display for ScenarioError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ScenarioError::Parse(e) => write!(f, "invalid scenario: {e}"),
+            ScenarioError::Step { step: 0, reason } => write!(f, "init: {reason}"),
+            ScenarioError::Step { step, reason } => write!(f, "step {step}: {reason}"),
+        }
+    }
+}
+
+impl std::error::Error for ScenarioError {}
+
+/// Run the scenario in `json` against `handler`
+pub fn run<H: MessageHandler>(mut handler: H, json: &str) -> Result<(), ScenarioError> {
+    let scenario: Scenario = serde_json::from_str(json).map_err(ScenarioError::Parse)?;
+    let mut node = Node::new();
+
+    let ids: Vec<String> = (1..=scenario.nodes).map(|i| format!("n{i}")).collect();
+    let init = testing::init("n1", ids.iter().map(String::as_str));
+    let out = process(&mut handler, &mut node, init);
+    if !out
+        .iter()
+        .any(|message| matches!(message.body, MessageBody::InitOk { .. }))
+    {
+        return Err(ScenarioError::Step {
+            step: 0,
+            reason: format!("no init_ok in {}", render(&out)),
+        });
+    }
+
+    for (i, step) in scenario.steps.into_iter().enumerate() {
+        let fail = |reason: String| ScenarioError::Step {
+            step: i + 1,
+            reason,
+        };
+        let out = match (step.send, step.tick) {
+            (Some(body), false) => {
+                let body: MessageBody = serde_json::from_value(body)
+                    .map_err(|e| fail(format!("invalid message: {e}")))?;
+                let message = Message {
+                    src: step.from,
+                    dest: node.id.clone(),
+                    body,
+                };
+                process(&mut handler, &mut node, message)
+            }
+            (None, true) => handler.on_tick(&mut node),
+            _ => return Err(fail("needs exactly one of `send` or `tick`".to_string())),
+        };
+
+        if out.len() != step.expect.len() {
+            return Err(fail(format!(
+                "expected {} messages, got {}",
+                step.expect.len(),
+                render(&out)
+            )));
+        }
+        for (message, expected) in out.iter().zip(&step.expect) {
+            let actual = serde_json::to_value(message).unwrap_or(Value::Null);
+            if !matches_pattern(expected, &actual) {
+                return Err(fail(format!("expected {expected}, got {actual}")));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Run every `*.json` scenario in `dir`, each against a fresh handler, and panic listing the
+/// ones that failed
+#[track_caller]
+pub fn run_dir<H: MessageHandler>(dir: impl AsRef<Path>, handler: impl Fn() -> H) {
+    let dir = dir.as_ref();
+    let mut paths: Vec<_> = fs::read_dir(dir)
+        .unwrap_or_else(|e| panic!("cannot read {}: {e}", dir.display()))
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "json"))
+        .collect();
+    paths.sort();
+    assert!(!paths.is_empty(), "no scenarios in {}", dir.display());
+
+    let failures: Vec<String> = paths
+        .iter()
+        .filter_map(|path| {
+            let json = fs::read_to_string(path).unwrap();
+            let e = run(handler(), &json).err()?;
+            Some(format!("{}: {e}", path.display()))
+        })
+        .collect();
+    assert!(failures.is_empty(), "{}", failures.join("\n"));
+}
+
+/// Whether `actual` has everything in `pattern`: objects may carry extra fields, anything else
+/// must be equal
+fn matches_pattern(pattern: &Value, actual: &Value) -> bool {
+    match (pattern, actual) {
+        (Value::Object(pattern), Value::Object(actual)) => pattern.iter().all(|(key, value)| {
+            actual
+                .get(key)
+                .is_some_and(|actual| matches_pattern(value, actual))
+        }),
+        (Value::Array(pattern), Value::Array(actual)) => {
+            pattern.len() == actual.len()
+                && pattern
+                    .iter()
+                    .zip(actual)
+                    .all(|(pattern, actual)| matches_pattern(pattern, actual))
+        }
+        _ => pattern == actual,
+    }
+}
+
+fn render(messages: &[Message]) -> String {
+    serde_json::to_string(messages).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    struct Echo;
+
+    impl MessageHandler for Echo {
+        fn handle(&mut self, node: &mut Node, message: Message) -> Vec<Message> {
+            match message.body {
+                MessageBody::Init {
+                    msg_id,
+                    node_id,
+                    node_ids,
+                } => {
+                    node.handle_init(node_id, node_ids);
+                    vec![node.init_ok(message.src, msg_id)]
+                }
+                MessageBody::Echo { msg_id, echo } => {
+                    vec![node.answer(message.src, msg_id, |msg_id, in_reply_to| {
+                        MessageBody::EchoOk {
+                            msg_id,
+                            in_reply_to,
+                            echo,
+                        }
+                    })]
+                }
+                _ => Vec::new(),
+            }
+        }
+    }
+
+    #[test]
+    fn test_patterns_ignore_fields_they_leave_out() {
+        let actual =
+            json!({"dest": "c1", "body": {"type": "read_ok", "msg_id": 3, "messages": [1, 2]}});
+        assert!(matches_pattern(
+            &json!({"body": {"messages": [1, 2]}}),
+            &actual
+        ));
+        assert!(!matches_pattern(
+            &json!({"body": {"messages": [1]}}),
+            &actual
+        ));
+        assert!(!matches_pattern(&json!({"body": {"value": 1}}), &actual));
+    }
+
+    #[test]
+    fn test_scenarios_report_the_step_that_diverged() {
+        let scenario = json!({"steps": [
+            {"from": "c2", "send": {"type": "echo", "msg_id": 4, "echo": "hi"},
+             "expect": [{"dest": "c2", "body": {"type": "echo_ok", "in_reply_to": 4, "echo": "hi"}}]},
+            {"tick": true},
+            {"send": {"type": "echo", "msg_id": 5, "echo": "hi"},
+             "expect": [{"body": {"echo": "bye"}}]}
+        ]});
+        let err = run(Echo, &scenario.to_string()).unwrap_err();
+        assert!(err.to_string().starts_with("step 3: expected"), "{err}");
+    }
+
+    #[test]
+    fn test_malformed_steps_are_rejected() {
+        let both = json!({"steps": [{"send": {"type": "read", "msg_id": 1}, "tick": true}]});
+        let err = run(Echo, &both.to_string()).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "step 1: needs exactly one of `send` or `tick`"
+        );
+        assert!(matches!(
+            run(Echo, r#"{"steps": [], "typo": 1}"#),
+            Err(ScenarioError::Parse(_))
+        ));
+    }
+}
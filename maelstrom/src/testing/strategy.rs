@@ -0,0 +1,144 @@
+//! proptest strategies for client workloads: random request sequences for broadcast, kafka
+//! and txn, and [`perturbed`] to deliver them the way an unreliable network would. Failing
+//! cases shrink toward fewer, smaller requests. Needs the `proptest` feature.
+//!
+//! Every generated request has a distinct `msg_id`, so a repeated one is always a duplicate.
+
+use crate::Message;
+#[cfg(any(feature = "broadcast", feature = "kafka", feature = "txn"))]
+use crate::testing;
+use proptest::collection::vec;
+use proptest::prelude::*;
+#[cfg(any(feature = "broadcast", feature = "kafka", feature = "txn"))]
+use proptest::sample::select;
+#[cfg(feature = "kafka")]
+use proptest::sample::subsequence;
+
+/// Clients the generated requests come from
+pub const CLIENTS: [&str; 3] = ["c1", "c2", "c3"];
+
+/// Logs kafka requests pick from; few, so requests collide
+#[cfg(feature = "kafka")]
+pub const KEYS: [&str; 3] = ["k1", "k2", "k3"];
+
+/// Keys txn micro-operations pick from
+#[cfg(feature = "txn")]
+pub const TXN_KEYS: u64 = 4;
+
+#[cfg(any(feature = "broadcast", feature = "kafka", feature = "txn"))]
+fn client() -> impl Strategy<Value = &'static str> {
+    select(&CLIENTS[..])
+}
+
+/// Number requests `1..`, in order
+#[cfg(any(feature = "broadcast", feature = "kafka", feature = "txn"))]
+fn numbered(requests: Vec<Message>) -> Vec<Message> {
+    requests
+        .into_iter()
+        .zip(1..)
+        .map(|(request, msg_id)| request.with_msg_id(msg_id))
+        .collect()
+}
+
+/// Up to `max_len` `broadcast`s of small values, so some repeat, mixed with `read`s
+#[cfg(feature = "broadcast")]
+pub fn broadcast_ops(max_len: usize) -> impl Strategy<Value = Vec<Message>> {
+    let request = prop_oneof![
+        3 => (client(), 0..32u64).prop_map(|(client, value)| testing::broadcast(value).src(client)),
+        1 => client().prop_map(|client| testing::read().src(client)),
+    ];
+    vec(request, 0..=max_len).prop_map(numbered)
+}
+
+#[cfg(feature = "kafka")]
+fn offsets() -> impl Strategy<Value = Vec<(&'static str, u64)>> {
+    vec((select(&KEYS[..]), 0..16u64), 1..=KEYS.len())
+}
+
+/// Up to `max_len` `send`s, `poll`s, `commit_offsets` and `list_committed_offsets` over
+/// [`KEYS`]
+#[cfg(feature = "kafka")]
+pub fn kafka_ops(max_len: usize) -> impl Strategy<Value = Vec<Message>> {
+    let request = prop_oneof![
+        4 => (client(), select(&KEYS[..]), 0..1000u64)
+            .prop_map(|(client, key, msg)| testing::send(client, key, msg)),
+        2 => (client(), offsets()).prop_map(|(client, from)| testing::poll(from).src(client)),
+        1 => (client(), offsets())
+            .prop_map(|(client, committed)| testing::commit_offsets(committed).src(client)),
+        1 => (client(), subsequence(KEYS.to_vec(), 1..=KEYS.len()))
+            .prop_map(|(client, keys)| testing::list_committed_offsets(keys).src(client)),
+    ];
+    vec(request, 0..=max_len).prop_map(numbered)
+}
+
+/// Up to `max_len` `txn`s of one to four reads and writes over keys `0..TXN_KEYS`
+#[cfg(feature = "txn")]
+pub fn txn_ops(max_len: usize) -> impl Strategy<Value = Vec<Message>> {
+    let micro = prop_oneof![
+        (0..TXN_KEYS).prop_map(|key| ("r", key, None)),
+        (0..TXN_KEYS, 0..100u64).prop_map(|(key, value)| ("w", key, Some(value))),
+    ];
+    let request =
+        (client(), vec(micro, 1..=4)).prop_map(|(client, ops)| testing::txn(ops).src(client));
+    vec(request, 0..=max_len).prop_map(numbered)
+}
+
+/// Deliver `requests` as an unreliable network would: each is overtaken by up to `window`
+/// later ones, and about one in five arrives a second time, up to `window` places later still
+pub fn perturbed(
+    requests: impl Strategy<Value = Vec<Message>>,
+    window: usize,
+) -> impl Strategy<Value = Vec<Message>> {
+    requests
+        .prop_flat_map(move |requests| {
+            let len = requests.len();
+            (
+                Just(requests),
+                vec(0..=window, len),
+                vec(proptest::option::weighted(0.2, 0..=window), len),
+            )
+        })
+        .prop_map(|(requests, delays, duplicates)| {
+            let mut deliveries = Vec::new();
+            for (i, ((request, delay), duplicate)) in
+                requests.into_iter().zip(delays).zip(duplicates).enumerate()
+            {
+                if let Some(extra) = duplicate {
+                    deliveries.push((i + delay + extra, request.clone()));
+                }
+                deliveries.push((i + delay, request));
+            }
+            // Stable, so requests due at the same place keep their order
+            deliveries.sort_by_key(|(at, _)| *at);
+            deliveries.into_iter().map(|(_, request)| request).collect()
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    proptest! {
+        #[test]
+        fn test_perturbed_delivers_everything_at_most_twice(
+            (requests, delivered) in txn_ops(20).prop_flat_map(|requests| {
+                (Just(requests.clone()), perturbed(Just(requests), 3))
+            })
+        ) {
+            let mut times: HashMap<u64, usize> = HashMap::new();
+            for request in &delivered {
+                *times.entry(request.body.msg_id().unwrap()).or_default() += 1;
+            }
+            prop_assert_eq!(times.len(), requests.len());
+            prop_assert!(times.values().all(|&n| n == 1 || n == 2));
+            prop_assert!(delivered.len() <= 2 * requests.len());
+        }
+
+        #[test]
+        fn test_generated_requests_are_numbered_in_order(requests in kafka_ops(20)) {
+            let ids: Vec<u64> = requests.iter().filter_map(|r| r.body.msg_id()).collect();
+            prop_assert_eq!(ids, (1..=requests.len() as u64).collect::<Vec<_>>());
+        }
+    }
+}
@@ -0,0 +1,226 @@
+//! A handler under test together with its node, driven fluently:
+//!
+//! ```ignore
+//! TestNode::new(KafkaNode::new())
+//!     .init(3)
+//!     .send("c1", testing::send("c1", "k1", 7))
+//!     .expect_reply(testing::send_ok(0));
+//! ```
+//!
+//! Messages go through [`crate::process`], as in `run_node`, and everything the handler writes
+//! is queued until an `expect_*` call takes it. Expectations panic with the message at hand.
+
+use crate::{ErrorCode, Message, MessageBody, MessageHandler, Node, NodeConfig, process, testing};
+use serde_json::Value;
+use std::collections::VecDeque;
+
+pub struct TestNode<H> {
+    pub handler: H,
+    pub node: Node,
+    /// Written by the handler and not yet expected
+    outbox: VecDeque<Message>,
+    /// Sender and `msg_id` of the last request, which replies must answer
+    last: Option<(String, Option<u64>)>,
+}
+
+impl<H: MessageHandler> TestNode<H> {
+    pub fn new(handler: H) -> Self {
+        Self::with_config(handler, NodeConfig::new())
+    }
+
+    pub fn with_config(handler: H, config: NodeConfig) -> Self {
+        Self {
+            handler,
+            node: Node::with_config(config),
+            outbox: VecDeque::new(),
+            last: None,
+        }
+    }
+
+    /// Initialize as `n1` of `n1..=n{nodes}` and take the `init_ok`, along with anything
+    /// else written on init
+    #[track_caller]
+    pub fn init(self, nodes: usize) -> Self {
+        let ids: Vec<String> = (1..=nodes).map(|i| format!("n{i}")).collect();
+        let mut test = self.send("c1", testing::init("n1", ids.iter().map(String::as_str)));
+        test = test.expect_reply_with(|body| matches!(body, MessageBody::InitOk { .. }));
+        test.outbox.clear();
+        test
+    }
+
+    /// Deliver `message` from `src`, addressed to this node
+    pub fn send(mut self, src: &str, message: Message) -> Self {
+        let dest = if self.node.id.is_empty() {
+            message.dest.clone()
+        } else {
+            self.node.id.clone()
+        };
+        let message = message.src(src).dest(&dest);
+        self.last = Some((src.to_string(), message.body.msg_id()));
+        let out = process(&mut self.handler, &mut self.node, message);
+        self.outbox.extend(out);
+        self
+    }
+
+    /// Run one tick of the handler
+    pub fn tick(mut self) -> Self {
+        let out = self.handler.on_tick(&mut self.node);
+        self.outbox.extend(out);
+        self
+    }
+
+    /// Take the next message written to `dest`
+    #[track_caller]
+    pub fn take_to(&mut self, dest: &str) -> Message {
+        let Some(i) = self.outbox.iter().position(|message| message.dest == dest) else {
+            panic!("nothing written to {dest}; written: {:?}", self.outbox);
+        };
+        self.outbox.remove(i).unwrap()
+    }
+
+    /// Take the reply to the last request, checking what it answers
+    #[track_caller]
+    fn take_reply(&mut self) -> MessageBody {
+        let Some((src, msg_id)) = self.last.clone() else {
+            panic!("no request sent yet");
+        };
+        let reply = self.take_to(&src);
+        assert_eq!(
+            reply.body.in_reply_to(),
+            msg_id,
+            "reply does not answer the last request: {reply:?}"
+        );
+        reply.body
+    }
+
+    /// Expect the reply to the last request to be `expected`, ignoring `msg_id` and
+    /// `in_reply_to`
+    #[track_caller]
+    pub fn expect_reply(mut self, expected: MessageBody) -> Self {
+        let reply = self.take_reply();
+        assert_eq!(
+            without_ids(&reply),
+            without_ids(&expected),
+            "unexpected reply {reply:?}"
+        );
+        self
+    }
+
+    /// Expect the reply to the last request to satisfy `check`
+    #[track_caller]
+    pub fn expect_reply_with(mut self, check: impl FnOnce(&MessageBody) -> bool) -> Self {
+        let reply = self.take_reply();
+        assert!(check(&reply), "unexpected reply {reply:?}");
+        self
+    }
+
+    /// Expect the last request to be refused with `code`
+    #[track_caller]
+    pub fn expect_error(self, code: ErrorCode) -> Self {
+        self.expect_reply_with(
+            |body| matches!(body, MessageBody::Error { code: actual, .. } if *actual == code),
+        )
+    }
+
+    /// Expect the next message to `dest` to satisfy `check`
+    #[track_caller]
+    pub fn expect_sent(mut self, dest: &str, check: impl FnOnce(&MessageBody) -> bool) -> Self {
+        let message = self.take_to(dest);
+        assert!(check(&message.body), "unexpected message {message:?}");
+        self
+    }
+
+    /// Expect nothing written that has not been expected already
+    #[track_caller]
+    pub fn expect_quiet(self) -> Self {
+        assert!(
+            self.outbox.is_empty(),
+            "unexpected output {:?}",
+            self.outbox
+        );
+        self
+    }
+
+    /// Everything written and not yet expected, for assertions of your own
+    pub fn drain(&mut self) -> Vec<Message> {
+        self.outbox.drain(..).collect()
+    }
+}
+
+/// `body` as JSON without the fields every reply fills in differently
+fn without_ids(body: &MessageBody) -> Value {
+    let mut value = serde_json::to_value(body).unwrap_or(Value::Null);
+    if let Some(fields) = value.as_object_mut() {
+        fields.remove("msg_id");
+        fields.remove("in_reply_to");
+    }
+    value
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Echo;
+
+    impl MessageHandler for Echo {
+        fn handle(&mut self, node: &mut Node, message: Message) -> Vec<Message> {
+            match message.body {
+                MessageBody::Init {
+                    msg_id,
+                    node_id,
+                    node_ids,
+                } => {
+                    node.handle_init(node_id, node_ids);
+                    vec![node.init_ok(message.src, msg_id)]
+                }
+                MessageBody::Echo { msg_id, echo } => {
+                    let peer = node.peers.first().cloned();
+                    let mut out = vec![node.answer(message.src, msg_id, |msg_id, in_reply_to| {
+                        MessageBody::EchoOk {
+                            msg_id,
+                            in_reply_to,
+                            echo: echo.clone(),
+                        }
+                    })];
+                    out.extend(
+                        peer.map(|peer| node.send(peer, MessageBody::Echo { msg_id: 0, echo })),
+                    );
+                    out
+                }
+                _ => Vec::new(),
+            }
+        }
+    }
+
+    #[test]
+    fn test_fluent_expectations_follow_the_conversation() {
+        TestNode::new(Echo)
+            .init(2)
+            .send("c2", testing::echo("hi").with_msg_id(9))
+            .expect_reply(testing::echo_ok("hi"))
+            .expect_sent(
+                "n2",
+                |body| matches!(body, MessageBody::Echo { echo, .. } if echo == "hi"),
+            )
+            .expect_quiet();
+    }
+
+    #[test]
+    #[should_panic(expected = "unexpected reply")]
+    fn test_wrong_replies_fail() {
+        TestNode::new(Echo)
+            .init(1)
+            .send("c1", testing::echo("hi"))
+            .expect_reply(testing::echo_ok("bye"));
+    }
+
+    #[test]
+    #[should_panic(expected = "nothing written to c1")]
+    fn test_missing_replies_fail() {
+        TestNode::new(Echo)
+            .init(1)
+            .send("c1", testing::read())
+            .expect_reply(testing::echo_ok("hi"));
+    }
+}
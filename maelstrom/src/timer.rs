@@ -0,0 +1,130 @@
+use crate::Message;
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap};
+use std::time::Instant;
+
+/// Identifies a message scheduled with [`Node::send_after`](crate::Node::send_after)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct TimerId(u64);
+
+/// Messages waiting for their moment to be written
+#[derive(Default)]
+pub struct Timers {
+    /// Due times in firing order; ties fire in scheduling order
+    queue: BinaryHeap<Reverse<(Instant, TimerId)>>,
+    scheduled: HashMap<TimerId, Message>,
+    next_id: u64,
+}
+
+impl Timers {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Hold `message` until `at`
+    pub fn schedule(&mut self, at: Instant, message: Message) -> TimerId {
+        self.next_id += 1;
+        let id = TimerId(self.next_id);
+        self.queue.push(Reverse((at, id)));
+        self.scheduled.insert(id, message);
+        id
+    }
+
+    /// Drop a scheduled message before it fires; false if it already fired or was cancelled
+    pub fn cancel(&mut self, id: TimerId) -> bool {
+        let cancelled = self.scheduled.remove(&id).is_some();
+        self.prune();
+        cancelled
+    }
+
+    /// Drop cancelled entries from the front of the queue so `next_due` stays accurate
+    fn prune(&mut self) {
+        while let Some(Reverse((_, id))) = self.queue.peek() {
+            if self.scheduled.contains_key(id) {
+                break;
+            }
+            self.queue.pop();
+        }
+    }
+
+    /// When the earliest scheduled message is due
+    pub fn next_due(&self) -> Option<Instant> {
+        self.queue.peek().map(|Reverse((at, _))| *at)
+    }
+
+    /// Messages due at `now`, in firing order
+    pub fn poll(&mut self, now: Instant) -> Vec<Message> {
+        let mut due = Vec::new();
+        while let Some(Reverse((at, id))) = self.queue.peek().copied() {
+            if at > now {
+                break;
+            }
+            self.queue.pop();
+            // Cancelled timers behind the front leave their queue entry; skip it here
+            due.extend(self.scheduled.remove(&id));
+        }
+        self.prune();
+        due
+    }
+
+    pub fn len(&self) -> usize {
+        self.scheduled.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.scheduled.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MessageBody;
+    use std::time::Duration;
+
+    fn read(msg_id: u64) -> Message {
+        Message {
+            src: "n1".to_string(),
+            dest: "n2".to_string(),
+            body: MessageBody::Read {
+                msg_id,
+                key: None,
+                context: None,
+            },
+        }
+    }
+
+    #[test]
+    fn test_messages_fire_in_due_order() {
+        let start = Instant::now();
+        let mut timers = Timers::new();
+        timers.schedule(start + Duration::from_millis(200), read(2));
+        timers.schedule(start + Duration::from_millis(100), read(1));
+        timers.schedule(start + Duration::from_millis(200), read(3));
+        assert_eq!(timers.next_due(), Some(start + Duration::from_millis(100)));
+
+        assert!(timers.poll(start).is_empty());
+        let fired = timers.poll(start + Duration::from_millis(250));
+        let ids: Vec<_> = fired.iter().filter_map(|m| m.body.msg_id()).collect();
+        assert_eq!(ids, vec![1, 2, 3]);
+        assert!(timers.is_empty());
+        assert_eq!(timers.next_due(), None);
+    }
+
+    #[test]
+    fn test_cancelled_messages_never_fire() {
+        let start = Instant::now();
+        let mut timers = Timers::new();
+        let id = timers.schedule(start, read(1));
+        timers.schedule(start, read(2));
+        let later = timers.schedule(start + Duration::from_millis(50), read(3));
+
+        assert!(timers.cancel(id));
+        assert!(!timers.cancel(id));
+        assert!(timers.cancel(later));
+        assert_eq!(timers.next_due(), Some(start));
+        let fired = timers.poll(start);
+        assert_eq!(fired.len(), 1);
+        assert_eq!(fired[0].body.msg_id(), Some(2));
+    }
+}
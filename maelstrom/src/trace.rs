@@ -0,0 +1,306 @@
+use crate::{Message, MessageBody, compact};
+use serde::{Deserialize, Serialize, de::Error as _};
+
+/// Inbound wire form: a message whose body may carry a `trace` id next to its fields
+#[derive(Deserialize)]
+struct InboundMessage {
+    src: String,
+    dest: String,
+    body: InboundBody,
+}
+
+#[derive(Deserialize)]
+struct InboundBody {
+    #[serde(flatten)]
+    body: MessageBody,
+    #[serde(default)]
+    trace: Option<String>,
+    #[serde(default)]
+    proto: Option<u32>,
+    #[serde(default)]
+    ack: bool,
+}
+
+/// Outbound wire form: borrows the message and attaches the trace id, if any, to its body
+#[derive(Serialize)]
+struct OutboundMessage<'a> {
+    src: &'a str,
+    dest: &'a str,
+    body: OutboundBody<'a>,
+}
+
+#[derive(Serialize)]
+struct OutboundBody<'a> {
+    #[serde(flatten)]
+    body: &'a MessageBody,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    trace: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    proto: Option<u32>,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    ack: bool,
+}
+
+/// Just enough of a line to read the sender's protocol version when the body itself
+/// does not decode
+#[derive(Deserialize)]
+struct VersionProbe {
+    src: String,
+    body: ProbeBody,
+}
+
+#[derive(Deserialize)]
+struct ProbeBody {
+    proto: Option<u32>,
+}
+
+/// Just enough of an encoded line to route it
+#[derive(Deserialize)]
+struct DestProbe {
+    dest: String,
+}
+
+/// Out-of-band fields carried in a body alongside its payload
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Envelope {
+    /// Trace id propagated from the originating client request
+    pub trace: Option<String>,
+    /// Internal protocol version of the sending node; absent on client traffic and on
+    /// nodes that predate versioning
+    pub proto: Option<u32>,
+    /// The sender keeps this message in its outbox until it gets an `ack` back
+    pub ack: bool,
+}
+
+/// Maelstrom clients are named `c1`, `c2`, ...
+pub fn is_client(id: &str) -> bool {
+    id.starts_with('c')
+}
+
+/// Cluster nodes are named `n1`, `n2`, ...; clients and Maelstrom's services (`lin-kv`,
+/// `seq-kv`, `lww-kv`, `lin-tso`) are not peers
+pub fn is_peer(id: &str) -> bool {
+    id.starts_with('n')
+}
+
+impl InboundMessage {
+    /// Separate the envelope from the message, unpacking compact bodies on the way
+    fn split(self) -> serde_json::Result<(Message, Envelope)> {
+        let body = match self.body.body {
+            MessageBody::Packed { data } => {
+                compact::unpack(&data).map_err(serde_json::Error::custom)?
+            }
+            body => body,
+        };
+        let message = Message {
+            src: self.src,
+            dest: self.dest,
+            body,
+        };
+        let envelope = Envelope {
+            trace: self.body.trace,
+            proto: self.body.proto,
+            ack: self.body.ack,
+        };
+        Ok((message, envelope))
+    }
+}
+
+/// Decode a single JSON line into a message and the envelope fields found in its body
+pub fn decode(line: &[u8]) -> serde_json::Result<(Message, Envelope)> {
+    let inbound: InboundMessage = serde_json::from_slice(line)?;
+    inbound.split()
+}
+
+/// Decode a single JSON line with simd-json; the buffer is used as scratch space
+#[cfg(feature = "simd-json")]
+pub fn decode_simd(
+    line: &mut [u8],
+) -> Result<(Message, Envelope), Box<dyn std::error::Error + Send + Sync>> {
+    let inbound: InboundMessage = simd_json::serde::from_slice(line)?;
+    Ok(inbound.split()?)
+}
+
+/// Reusable line decoder for the stdin reader, backed by simd-json when that feature is on
+#[derive(Default)]
+pub struct Decoder {
+    #[cfg(feature = "simd-json")]
+    scratch: Vec<u8>,
+}
+
+impl Decoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Decode one framed line, leaving `line` untouched so it can still be reported on error
+    pub fn decode(
+        &mut self,
+        line: &[u8],
+    ) -> Result<(Message, Envelope), Box<dyn std::error::Error + Send + Sync>> {
+        #[cfg(feature = "simd-json")]
+        {
+            self.scratch.clear();
+            self.scratch.extend_from_slice(line);
+            decode_simd(&mut self.scratch)
+        }
+        #[cfg(not(feature = "simd-json"))]
+        {
+            Ok(decode(line)?)
+        }
+    }
+}
+
+/// Sender and protocol version of a line that failed to decode, if it carried one
+pub fn probe_version(line: &[u8]) -> Option<(String, u32)> {
+    let probe: VersionProbe = serde_json::from_slice(line).ok()?;
+    Some((probe.src, probe.body.proto?))
+}
+
+/// Destination of an encoded line
+pub fn probe_dest(line: &[u8]) -> Option<String> {
+    serde_json::from_slice::<DestProbe>(line)
+        .ok()
+        .map(|probe| probe.dest)
+}
+
+/// Encode a message as a newline-terminated JSON line, adding the envelope fields to its body
+pub fn encode(message: &Message, envelope: &Envelope) -> serde_json::Result<Vec<u8>> {
+    let outbound = OutboundMessage {
+        src: &message.src,
+        dest: &message.dest,
+        body: OutboundBody {
+            body: &message.body,
+            trace: envelope.trace.as_deref(),
+            proto: envelope.proto,
+            ack: envelope.ack,
+        },
+    };
+    let mut bytes = serde_json::to_vec(&outbound)?;
+    bytes.push(b'\n');
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode_extracts_trace_from_body() {
+        let line = r#"{"src":"n1","dest":"n2","body":{"type":"broadcast_gossip","msg_id":3,"messages":[1],"trace":"c1-n1-4"}}"#;
+        let (message, envelope) = decode(line.as_bytes()).unwrap();
+
+        assert_eq!(message.src, "n1");
+        assert_eq!(envelope.trace.as_deref(), Some("c1-n1-4"));
+        match message.body {
+            MessageBody::BroadcastGossip { msg_id, messages } => {
+                assert_eq!(msg_id, Some(3));
+                assert_eq!(messages, vec![1]);
+            }
+            _ => panic!("Expected BroadcastGossip message"),
+        }
+    }
+
+    #[test]
+    fn test_decode_without_trace() {
+        let line = r#"{"src":"c1","dest":"n1","body":{"type":"echo","msg_id":1,"echo":"hi"}}"#;
+        let (_, envelope) = decode(line.as_bytes()).unwrap();
+        assert_eq!(envelope, Envelope::default());
+    }
+
+    #[test]
+    fn test_encode_round_trips_trace() {
+        let message = Message {
+            src: "n1".to_string(),
+            dest: "n2".to_string(),
+            body: MessageBody::Read {
+                msg_id: 7,
+                key: None,
+                context: None,
+            },
+        };
+
+        let sent = Envelope {
+            trace: Some("n1-1".to_string()),
+            proto: Some(1),
+            ack: true,
+        };
+        let traced = encode(&message, &sent).unwrap();
+        let (decoded, envelope) = decode(&traced).unwrap();
+        assert_eq!(decoded.dest, "n2");
+        assert_eq!(envelope, sent);
+
+        let untraced = String::from_utf8(encode(&message, &Envelope::default()).unwrap()).unwrap();
+        assert!(!untraced.contains("trace"));
+        assert!(!untraced.contains("proto"));
+        assert!(!untraced.contains("ack"));
+    }
+
+    #[test]
+    fn test_only_cluster_nodes_are_peers() {
+        assert!(is_peer("n1"));
+        for id in ["c1", "lin-kv", "seq-kv", "lww-kv", "lin-tso"] {
+            assert!(!is_peer(id), "{id}");
+        }
+    }
+
+    #[test]
+    fn test_probe_version_reads_undecodable_lines() {
+        let line =
+            r#"{"src":"n3","dest":"n1","body":{"type":"from_the_future","msg_id":1,"proto":9}}"#;
+        assert!(decode(line.as_bytes()).is_err());
+        assert_eq!(probe_version(line.as_bytes()), Some(("n3".to_string(), 9)));
+
+        let client = r#"{"src":"c1","dest":"n1","body":{"type":"nonsense"}}"#;
+        assert_eq!(probe_version(client.as_bytes()), None);
+    }
+
+    #[test]
+    fn test_decoder_matches_plain_decode() {
+        let line = br#"{"src":"n1","dest":"n2","body":{"type":"broadcast_gossip","msg_id":3,"messages":[1,2],"trace":"n1-9","proto":1}}
+"#;
+        let mut decoder = Decoder::new();
+        let (message, envelope) = decoder.decode(line).unwrap();
+        let (expected, expected_envelope) = decode(line).unwrap();
+
+        assert_eq!(message.src, expected.src);
+        assert_eq!(envelope, expected_envelope);
+        match message.body {
+            MessageBody::BroadcastGossip { messages, .. } => assert_eq!(messages, vec![1, 2]),
+            _ => panic!("Expected BroadcastGossip message"),
+        }
+        assert!(decoder.decode(b"{not json").is_err());
+    }
+
+    #[test]
+    fn test_decode_unpacks_packed_bodies() {
+        let inner = MessageBody::BroadcastGossip {
+            msg_id: None,
+            messages: vec![5, 6],
+        };
+        let packed = Message {
+            src: "n1".to_string(),
+            dest: "n2".to_string(),
+            body: MessageBody::Packed {
+                data: compact::pack(&inner).unwrap(),
+            },
+        };
+
+        let (message, envelope) = decode(
+            &encode(
+                &packed,
+                &Envelope {
+                    proto: Some(2),
+                    ..Envelope::default()
+                },
+            )
+            .unwrap(),
+        )
+        .unwrap();
+        assert_eq!(envelope.proto, Some(2));
+        match message.body {
+            MessageBody::BroadcastGossip { messages, .. } => assert_eq!(messages, vec![5, 6]),
+            _ => panic!("Expected BroadcastGossip message"),
+        }
+    }
+}
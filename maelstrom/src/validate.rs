@@ -0,0 +1,157 @@
+use crate::MessageBody;
+use std::fmt;
+
+/// A body that decoded but is semantically invalid, with the path of the offending field
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Invalid {
+    /// Path to the failing field, e.g. `body.txn[2][0]`
+    pub field: String,
+    /// Why the field was rejected
+    pub reason: String,
+}
+
+impl Invalid {
+    pub fn new(field: impl Into<String>, reason: impl Into<String>) -> Self {
+        Self {
+            field: field.into(),
+            reason: reason.into(),
+        }
+    }
+}
+
+impl fmt::Display for Invalid {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.field, self.reason)
+    }
+}
+
+impl std::error::Error for Invalid {}
+
+impl MessageBody {
+    /// Check the workload-independent invariants of this body
+    pub fn validate(&self) -> Result<(), Invalid> {
+        match self {
+            MessageBody::Init {
+                node_id, node_ids, ..
+            } => {
+                non_empty("body.node_id", node_id)?;
+                if !node_ids.contains(node_id) {
+                    return Err(Invalid::new("body.node_ids", "must include node_id"));
+                }
+            }
+            #[cfg(feature = "kafka")]
+            MessageBody::Send { key, .. }
+            | MessageBody::ForwardSend { key, .. }
+            | MessageBody::Replicate { key, .. }
+            | MessageBody::Tombstone { key, .. } => non_empty("body.key", key)?,
+            #[cfg(feature = "kafka")]
+            MessageBody::Poll { offsets, .. } | MessageBody::CommitOffsets { offsets, .. }
+                if offsets.keys().any(|key| key.is_empty()) =>
+            {
+                return Err(Invalid::new(
+                    "body.offsets",
+                    "must not contain an empty key",
+                ));
+            }
+            #[cfg(feature = "kafka")]
+            MessageBody::ListCommittedOffsets { keys, .. } => {
+                for (i, key) in keys.iter().enumerate() {
+                    non_empty(&format!("body.keys[{i}]"), key)?;
+                }
+            }
+            #[cfg(feature = "txn")]
+            MessageBody::Txn { txn, .. } => {
+                if txn.is_empty() {
+                    return Err(Invalid::new(
+                        "body.txn",
+                        "must contain at least one operation",
+                    ));
+                }
+                for (i, (op, _, value)) in txn.iter().enumerate() {
+                    match (op.as_str(), value) {
+                        ("r", _) | ("w", Some(_)) => {}
+                        ("w", None) => {
+                            return Err(Invalid::new(
+                                format!("body.txn[{i}][2]"),
+                                "write must carry a value",
+                            ));
+                        }
+                        _ => {
+                            return Err(Invalid::new(
+                                format!("body.txn[{i}][0]"),
+                                format!("unknown operation {op:?}"),
+                            ));
+                        }
+                    }
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+fn non_empty(field: &str, value: &str) -> Result<(), Invalid> {
+    if value.is_empty() {
+        return Err(Invalid::new(field, "must not be empty"));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_empty_send_key_is_invalid() {
+        let body = MessageBody::Send {
+            msg_id: 1,
+            key: String::new(),
+            msg: 5,
+        };
+        let invalid = body.validate().unwrap_err();
+        assert_eq!(invalid.field, "body.key");
+        assert_eq!(invalid.to_string(), "body.key: must not be empty");
+    }
+
+    #[test]
+    fn test_txn_validation_reports_operation_path() {
+        let empty = MessageBody::Txn {
+            msg_id: 1,
+            txn: vec![],
+        };
+        assert_eq!(empty.validate().unwrap_err().field, "body.txn");
+
+        let bad_op = MessageBody::Txn {
+            msg_id: 2,
+            txn: vec![("r".to_string(), 1, None), ("x".to_string(), 2, None)],
+        };
+        assert_eq!(bad_op.validate().unwrap_err().field, "body.txn[1][0]");
+
+        let missing_value = MessageBody::Txn {
+            msg_id: 3,
+            txn: vec![("w".to_string(), 1, None)],
+        };
+        assert_eq!(
+            missing_value.validate().unwrap_err().field,
+            "body.txn[0][2]"
+        );
+    }
+
+    #[test]
+    fn test_valid_bodies_pass() {
+        let poll = MessageBody::Poll {
+            msg_id: 1,
+            offsets: HashMap::from([("k1".to_string(), 0)]),
+        };
+        assert!(poll.validate().is_ok());
+
+        let init = MessageBody::Init {
+            msg_id: 1,
+            node_id: "n1".to_string(),
+            node_ids: vec!["n1".to_string()],
+        };
+        assert!(init.validate().is_ok());
+    }
+}
@@ -0,0 +1,153 @@
+use tokio::{
+    io::{self, AsyncWrite, AsyncWriteExt, BufWriter},
+    sync::mpsc,
+    task::JoinHandle,
+};
+
+/// When the writer task pushes buffered output to the underlying stream
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum FlushPolicy {
+    /// Flush after every message
+    EveryMessage,
+    /// Flush once no more messages are queued, batching bursts into few syscalls
+    #[default]
+    WhenIdle,
+}
+
+/// Handle for queueing newline-framed output to the writer task
+#[derive(Clone)]
+pub struct Writer {
+    tx: mpsc::UnboundedSender<Vec<u8>>,
+}
+
+impl Writer {
+    /// Queue one encoded message; a missing trailing newline is added. Returns false once the
+    /// writer task has stopped.
+    pub fn send(&self, mut frame: Vec<u8>) -> bool {
+        if frame.last() != Some(&b'\n') {
+            frame.push(b'\n');
+        }
+        self.tx.send(frame).is_ok()
+    }
+}
+
+/// A `Writer` and the receiving end of its queue, for tasks that route frames themselves
+pub(crate) fn channel() -> (Writer, mpsc::UnboundedReceiver<Vec<u8>>) {
+    let (tx, rx) = mpsc::unbounded_channel();
+    (Writer { tx }, rx)
+}
+
+/// Spawn the task that owns stdout; it exits, flushing what is left, once every `Writer`
+/// has been dropped
+pub fn spawn_stdout_writer(policy: FlushPolicy) -> (Writer, JoinHandle<()>) {
+    spawn_writer(io::stdout(), policy)
+}
+
+/// Spawn a writer task over any byte sink
+pub fn spawn_writer<W>(out: W, policy: FlushPolicy) -> (Writer, JoinHandle<()>)
+where
+    W: AsyncWrite + Unpin + Send + 'static,
+{
+    let (writer, rx) = channel();
+    let task = tokio::spawn(async move {
+        if let Err(e) = write_frames(rx, out, policy).await {
+            crate::logging::log_warn!("write error: {e:?}");
+        }
+    });
+    (writer, task)
+}
+
+async fn write_frames<W: AsyncWrite + Unpin>(
+    mut rx: mpsc::UnboundedReceiver<Vec<u8>>,
+    out: W,
+    policy: FlushPolicy,
+) -> io::Result<()> {
+    let mut out = BufWriter::new(out);
+    while let Some(frame) = rx.recv().await {
+        out.write_all(&frame).await?;
+        // Drain whatever else is already queued before deciding whether to flush
+        while let Ok(frame) = rx.try_recv() {
+            if policy == FlushPolicy::EveryMessage {
+                out.flush().await?;
+            }
+            out.write_all(&frame).await?;
+        }
+        out.flush().await?;
+    }
+    out.flush().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::pin::Pin;
+    use std::sync::{Arc, Mutex};
+    use std::task::{Context, Poll};
+
+    /// Sink that records each flush boundary
+    #[derive(Clone, Default)]
+    struct Recording {
+        written: Arc<Mutex<Vec<u8>>>,
+        flushes: Arc<Mutex<usize>>,
+    }
+
+    impl AsyncWrite for Recording {
+        fn poll_write(
+            self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            self.written.lock().unwrap().extend_from_slice(buf);
+            Poll::Ready(Ok(buf.len()))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            *self.flushes.lock().unwrap() += 1;
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_frames_are_newline_terminated_and_flushed_on_close() {
+        let sink = Recording::default();
+        let (writer, task) = spawn_writer(sink.clone(), FlushPolicy::WhenIdle);
+
+        assert!(writer.send(b"{\"a\":1}".to_vec()));
+        assert!(writer.send(b"{\"b\":2}\n".to_vec()));
+        drop(writer);
+        task.await.unwrap();
+
+        let written = sink.written.lock().unwrap().clone();
+        assert_eq!(written, b"{\"a\":1}\n{\"b\":2}\n");
+    }
+
+    #[tokio::test]
+    async fn test_when_idle_batches_queued_frames() {
+        let sink = Recording::default();
+        let (tx, rx) = mpsc::unbounded_channel();
+        for i in 0..10 {
+            tx.send(format!("{i}\n").into_bytes()).unwrap();
+        }
+        drop(tx);
+        write_frames(rx, sink.clone(), FlushPolicy::WhenIdle)
+            .await
+            .unwrap();
+        // One flush for the burst, one on close
+        assert_eq!(*sink.flushes.lock().unwrap(), 2);
+
+        let sink = Recording::default();
+        let (tx, rx) = mpsc::unbounded_channel();
+        for i in 0..10 {
+            tx.send(format!("{i}\n").into_bytes()).unwrap();
+        }
+        drop(tx);
+        write_frames(rx, sink.clone(), FlushPolicy::EveryMessage)
+            .await
+            .unwrap();
+        assert_eq!(*sink.flushes.lock().unwrap(), 11);
+    }
+}
@@ -0,0 +1,293 @@
+use proc_macro::TokenStream;
+use proc_macro2::{Span, TokenStream as TokenStream2};
+use quote::{format_ident, quote};
+use syn::{Attribute, Fields, Ident, ItemEnum, Type, Variant, parse_macro_input};
+
+/// Turn an enum of message bodies into a Maelstrom workload protocol.
+///
+/// The enum gains:
+/// - serde derives with `#[serde(tag = "type", rename_all = "snake_case")]`
+/// - `type_name()`, `reply_type()`, `msg_id()`, `in_reply_to()`, and `is_reply()` accessors,
+///   pairing every `Foo` variant with its `FooOk` reply by name, plus a `set_msg_id()` setter
+/// - a `<Enum>Dispatch` trait with one `on_<variant>` method per variant (defaulting to no
+///   output) and a provided `dispatch()` that routes a message to the matching method
+///
+/// `#[cfg(...)]` attributes on variants are carried over to everything generated for them.
+#[proc_macro_attribute]
+pub fn maelstrom_workload(_args: TokenStream, input: TokenStream) -> TokenStream {
+    let item = parse_macro_input!(input as ItemEnum);
+    expand(item).into()
+}
+
+fn expand(item: ItemEnum) -> TokenStream2 {
+    let enum_name = &item.ident;
+    let vis = &item.vis;
+    let dispatch_trait = format_ident!("{}Dispatch", enum_name);
+    let variant_names: Vec<String> = item.variants.iter().map(|v| v.ident.to_string()).collect();
+
+    let mut type_name_arms = Vec::new();
+    let mut reply_type_arms = Vec::new();
+    let mut msg_id_arms = Vec::new();
+    let mut in_reply_to_arms = Vec::new();
+    let mut set_msg_id_arms = Vec::new();
+    let mut trait_methods = Vec::new();
+    let mut dispatch_arms = Vec::new();
+    // Mixed-site hygiene keeps this binding from clashing with body fields such as `message`
+    let src = Ident::new("src", Span::mixed_site());
+
+    for variant in &item.variants {
+        let ident = &variant.ident;
+        let cfgs = cfg_attrs(&variant.attrs);
+        let wire_name = snake_case(&ident.to_string());
+        let reply_name = format!("{ident}Ok");
+        let reply_type = if variant_names.contains(&reply_name) {
+            let wire_reply = snake_case(&reply_name);
+            quote!(Some(#wire_reply))
+        } else {
+            quote!(None)
+        };
+        let method = Ident::new(&format!("on_{wire_name}"), Span::call_site());
+        let doc = format!("Handle an inbound `{wire_name}` message");
+
+        type_name_arms.push(quote! {
+            #(#cfgs)*
+            #enum_name::#ident { .. } => #wire_name,
+        });
+        reply_type_arms.push(quote! {
+            #(#cfgs)*
+            #enum_name::#ident { .. } => #reply_type,
+        });
+
+        match &variant.fields {
+            Fields::Named(named) => {
+                let names: Vec<&Ident> = named
+                    .named
+                    .iter()
+                    .map(|field| field.ident.as_ref().expect("named field"))
+                    .collect();
+                let types = named.named.iter().map(|field| &field.ty);
+                msg_id_arms.push(field_accessor(enum_name, variant, &cfgs, "msg_id"));
+                set_msg_id_arms.push(msg_id_setter(enum_name, variant, &cfgs));
+                in_reply_to_arms.push(field_accessor(enum_name, variant, &cfgs, "in_reply_to"));
+                trait_methods.push(quote! {
+                    #(#cfgs)*
+                    #[doc = #doc]
+                    #[allow(unused_variables, clippy::too_many_arguments)]
+                    fn #method(
+                        &mut self,
+                        node: &mut ::maelstrom::Node,
+                        src: String,
+                        #(#names: #types),*
+                    ) -> Vec<::maelstrom::Message> {
+                        Vec::new()
+                    }
+                });
+                dispatch_arms.push(quote! {
+                    #(#cfgs)*
+                    #enum_name::#ident { #(#names),* } => self.#method(node, #src, #(#names),*),
+                });
+            }
+            Fields::Unnamed(unnamed) if unnamed.unnamed.len() == 1 => {
+                let ty = &unnamed.unnamed[0].ty;
+                // Newtype bodies follow the same convention: requests carry `msg_id`,
+                // `*Ok` replies additionally carry `in_reply_to`
+                let in_reply_to = if ident.to_string().ends_with("Ok") {
+                    quote!(Some(body.in_reply_to))
+                } else {
+                    quote!(None)
+                };
+                msg_id_arms.push(quote! {
+                    #(#cfgs)*
+                    #enum_name::#ident(body) => Some(body.msg_id),
+                });
+                in_reply_to_arms.push(quote! {
+                    #(#cfgs)*
+                    #enum_name::#ident(body) => #in_reply_to,
+                });
+                set_msg_id_arms.push(quote! {
+                    #(#cfgs)*
+                    #enum_name::#ident(body) => body.msg_id = id,
+                });
+                trait_methods.push(quote! {
+                    #(#cfgs)*
+                    #[doc = #doc]
+                    #[allow(unused_variables, clippy::too_many_arguments)]
+                    fn #method(
+                        &mut self,
+                        node: &mut ::maelstrom::Node,
+                        src: String,
+                        body: #ty,
+                    ) -> Vec<::maelstrom::Message> {
+                        Vec::new()
+                    }
+                });
+                dispatch_arms.push(quote! {
+                    #(#cfgs)*
+                    #enum_name::#ident(body) => self.#method(node, #src, body),
+                });
+            }
+            _ => {
+                return syn::Error::new_spanned(
+                    variant,
+                    "maelstrom_workload variants must have named fields or a single body type",
+                )
+                .to_compile_error();
+            }
+        }
+    }
+
+    quote! {
+        #[derive(::serde::Serialize, ::serde::Deserialize)]
+        #[serde(tag = "type", rename_all = "snake_case")]
+        #item
+
+        impl #enum_name {
+            /// Wire name of this body's `type` tag
+            pub fn type_name(&self) -> &'static str {
+                match self {
+                    #(#type_name_arms)*
+                }
+            }
+
+            /// Wire name of the reply paired with this body (`send` -> `send_ok`), if any
+            pub fn reply_type(&self) -> Option<&'static str> {
+                match self {
+                    #(#reply_type_arms)*
+                }
+            }
+
+            /// The `msg_id` carried by this body, if it has one
+            pub fn msg_id(&self) -> Option<u64> {
+                match self {
+                    #(#msg_id_arms)*
+                }
+            }
+
+            /// The `in_reply_to` carried by this body, if it is a reply
+            pub fn in_reply_to(&self) -> Option<u64> {
+                match self {
+                    #(#in_reply_to_arms)*
+                }
+            }
+
+            /// Overwrite the `msg_id` carried by this body; bodies without one are left as is
+            pub fn set_msg_id(&mut self, id: u64) {
+                match self {
+                    #(#set_msg_id_arms)*
+                }
+            }
+
+            /// Whether this body answers an earlier request
+            pub fn is_reply(&self) -> bool {
+                self.in_reply_to().is_some()
+            }
+        }
+
+        /// Per-variant handler methods; implement the ones a node cares about and call
+        /// `dispatch` from `MessageHandler::handle`
+        #vis trait #dispatch_trait {
+            #(#trait_methods)*
+
+            /// Route a message to the `on_*` method matching its body
+            fn dispatch(
+                &mut self,
+                node: &mut ::maelstrom::Node,
+                message: ::maelstrom::Message,
+            ) -> Vec<::maelstrom::Message> {
+                let #src = message.src;
+                match message.body {
+                    #(#dispatch_arms)*
+                }
+            }
+        }
+    }
+}
+
+/// Accessor arm for a named `u64` or `Option<u64>` field, yielding `None` when the variant
+/// lacks it
+fn field_accessor(
+    enum_name: &Ident,
+    variant: &Variant,
+    cfgs: &[&Attribute],
+    field: &str,
+) -> TokenStream2 {
+    let ident = &variant.ident;
+    let found = variant
+        .fields
+        .iter()
+        .find(|f| f.ident.as_ref().is_some_and(|name| name == field));
+    if let Some(found) = found {
+        let field = Ident::new(field, Span::call_site());
+        let value = if is_option(&found.ty) {
+            quote!(*#field)
+        } else {
+            quote!(Some(*#field))
+        };
+        quote! {
+            #(#cfgs)*
+            #enum_name::#ident { #field, .. } => #value,
+        }
+    } else {
+        quote! {
+            #(#cfgs)*
+            #enum_name::#ident { .. } => None,
+        }
+    }
+}
+
+/// Setter arm for `msg_id`, whether it is a plain `u64`, an `Option<u64>`, or absent
+fn msg_id_setter(enum_name: &Ident, variant: &Variant, cfgs: &[&Attribute]) -> TokenStream2 {
+    let ident = &variant.ident;
+    let found = variant
+        .fields
+        .iter()
+        .find(|f| f.ident.as_ref().is_some_and(|name| name == "msg_id"));
+    match found {
+        Some(found) if is_option(&found.ty) => quote! {
+            #(#cfgs)*
+            #enum_name::#ident { msg_id, .. } => *msg_id = Some(id),
+        },
+        Some(_) => quote! {
+            #(#cfgs)*
+            #enum_name::#ident { msg_id, .. } => *msg_id = id,
+        },
+        None => quote! {
+            #(#cfgs)*
+            #enum_name::#ident { .. } => {}
+        },
+    }
+}
+
+fn is_option(ty: &Type) -> bool {
+    match ty {
+        Type::Path(path) => path
+            .path
+            .segments
+            .last()
+            .is_some_and(|segment| segment.ident == "Option"),
+        _ => false,
+    }
+}
+
+fn cfg_attrs(attrs: &[Attribute]) -> Vec<&Attribute> {
+    attrs
+        .iter()
+        .filter(|attr| attr.path().is_ident("cfg"))
+        .collect()
+}
+
+/// `ListCommittedOffsetsOk` -> `list_committed_offsets_ok`, matching serde's `snake_case`
+fn snake_case(name: &str) -> String {
+    let mut out = String::with_capacity(name.len() + 4);
+    for (i, ch) in name.chars().enumerate() {
+        if ch.is_uppercase() {
+            if i > 0 {
+                out.push('_');
+            }
+            out.extend(ch.to_lowercase());
+        } else {
+            out.push(ch);
+        }
+    }
+    out
+}
@@ -0,0 +1,44 @@
+//! Benchmark for computing gossip deltas over a large message set. Run with
+//! `cargo bench -p multi_node_broadcast`.
+
+use criterion::{BenchmarkId, Criterion, criterion_group, criterion_main};
+use maelstrom::{Node, process, testing};
+use multi_node_broadcast::node::MultiNodeBroadcastNode;
+use std::hint::black_box;
+
+const MESSAGES: u64 = 100_000;
+
+/// `n1` in a five-node cluster holding `MESSAGES` values, of which its peers have acknowledged
+/// all but `unseen`
+fn node(unseen: u64) -> (MultiNodeBroadcastNode, Node) {
+    let mut handler = MultiNodeBroadcastNode::new();
+    let mut node = Node::new();
+    let peers = ["n2", "n3", "n4", "n5"];
+    process(
+        &mut handler,
+        &mut node,
+        testing::init("n1", ["n1"].into_iter().chain(peers)),
+    );
+    for message in 0..MESSAGES {
+        handler.handle_broadcast(message);
+    }
+    let seen: Vec<u64> = (0..MESSAGES - unseen).collect();
+    for peer in peers {
+        handler.handle_broadcast_gossip_ok_from(peer, seen.clone());
+    }
+    (handler, node)
+}
+
+fn delta(c: &mut Criterion) {
+    let mut group = c.benchmark_group("gossip_delta");
+    for unseen in [MESSAGES, 100] {
+        let (mut handler, mut node) = node(unseen);
+        group.bench_function(BenchmarkId::new("unseen", unseen), |b| {
+            b.iter(|| black_box(handler.gossip(&mut node)))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, delta);
+criterion_main!(benches);
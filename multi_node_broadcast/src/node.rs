@@ -1,17 +1,21 @@
 use maelstrom::{
-    Message, MessageBody,
-    node::{MessageHandler, Node},
+    MembershipChange, Message, MessageBody, MessageBodyDispatch, MessageHandler, Node,
+    admin::Introspect,
 };
 use rand::seq::SliceRandom;
 use std::collections::{HashMap, HashSet};
+use std::time::Duration;
 
 pub struct MultiNodeBroadcastNode {
     /// Node messages
     messages: HashSet<u64>,
     /// Gossip neighbors (k-regular topology)
     gossip_peers: Vec<String>,
-    /// For each peer, the set of message ids we believe that peer already has
+    /// For each peer, the message ids it has acknowledged receiving
     peer_seen: HashMap<String, HashSet<u64>>,
+    /// For each peer, the message ids it gossiped that this node still owes an ack for; they
+    /// go out together on the next tick
+    unacked: HashMap<String, HashSet<u64>>,
 }
 
 impl Default for MultiNodeBroadcastNode {
@@ -26,11 +30,12 @@ impl MultiNodeBroadcastNode {
             messages: HashSet::new(),
             gossip_peers: Vec::new(),
             peer_seen: HashMap::new(),
+            unacked: HashMap::new(),
         }
     }
 
-    pub fn construct_k_regular_neighbors(&self, node: &Node, k: usize) -> Vec<String> {
-        let mut rng = rand::rng();
+    /// `k` random peers, drawn from the node's (possibly seeded) generator
+    pub fn construct_k_regular_neighbors(&self, node: &mut Node, k: usize) -> Vec<String> {
         let mut other_nodes: Vec<String> = node
             .peers
             .iter()
@@ -38,18 +43,20 @@ impl MultiNodeBroadcastNode {
             .cloned()
             .collect();
 
-        other_nodes.shuffle(&mut rng);
+        other_nodes.shuffle(&mut node.rng);
         let len = other_nodes.len();
         other_nodes.into_iter().take(k.min(len)).collect()
     }
 
     pub fn gossip(&mut self, node: &mut Node) -> Vec<Message> {
-        let mut out: Vec<Message> = Vec::new();
+        let mut out = self.acks(node);
         if node.id.is_empty() || self.gossip_peers.is_empty() || self.messages.is_empty() {
             return out;
         }
 
-        for peer in self.gossip_peers.iter() {
+        // Skip peers the failure detector suspects; they catch up once heard from again
+        let alive = node.alive_peers();
+        for peer in self.gossip_peers.iter().filter(|peer| alive.contains(peer)) {
             // Compute delta: what we have that we do not believe the peer has
             let seen = self.peer_seen.entry(peer.clone()).or_default();
             let delta: Vec<u64> = self
@@ -65,7 +72,7 @@ impl MultiNodeBroadcastNode {
                     src: node.id.clone(),
                     dest: peer.clone(),
                     body: MessageBody::BroadcastGossip {
-                        msg_id: node.next_msg_id(),
+                        msg_id: None,
                         messages: delta,
                     },
                 });
@@ -74,12 +81,51 @@ impl MultiNodeBroadcastNode {
         out
     }
 
-    pub fn handle_broadcast_gossip_from(&mut self, peer: &str, messages: Vec<u64>) {
-        let seen = self.peer_seen.entry(peer.to_string()).or_default();
-        for message in messages {
-            self.messages.insert(message);
-            seen.insert(message);
+    /// One ack per peer for everything it gossiped since the last tick
+    fn acks(&mut self, node: &Node) -> Vec<Message> {
+        let mut peers: Vec<String> = self.unacked.keys().cloned().collect();
+        peers.sort_unstable();
+        peers
+            .into_iter()
+            .map(|peer| {
+                let mut messages: Vec<u64> = self
+                    .unacked
+                    .remove(&peer)
+                    .unwrap_or_default()
+                    .into_iter()
+                    .collect();
+                messages.sort_unstable();
+                Message {
+                    src: node.id.clone(),
+                    dest: peer,
+                    body: MessageBody::BroadcastGossipOk {
+                        msg_id: None,
+                        messages,
+                    },
+                }
+            })
+            .collect()
+    }
+
+    /// Take in `messages` gossiped by `peer`, owing it an ack for them; a value already held
+    /// is acknowledged again, since the peer only repeats what it has not heard back about
+    pub fn handle_broadcast_gossip_from(&mut self, peer: &str, messages: &[u64]) {
+        if messages.is_empty() {
+            return;
         }
+        self.messages.extend(messages);
+        self.unacked
+            .entry(peer.to_string())
+            .or_default()
+            .extend(messages);
+    }
+
+    /// `peer` confirmed it holds `messages`, so gossip to it can leave them out
+    pub fn handle_broadcast_gossip_ok_from(&mut self, peer: &str, messages: Vec<u64>) {
+        self.peer_seen
+            .entry(peer.to_string())
+            .or_default()
+            .extend(messages);
     }
 
     pub fn handle_broadcast(&mut self, message: u64) {
@@ -93,113 +139,177 @@ impl MultiNodeBroadcastNode {
 
 impl MessageHandler for MultiNodeBroadcastNode {
     fn handle(&mut self, node: &mut Node, msg: Message) -> Vec<Message> {
-        let mut out: Vec<Message> = Vec::new();
-        match msg.body.clone() {
-            MessageBody::Init {
+        self.dispatch(node, msg)
+    }
+
+    fn on_tick(&mut self, node: &mut Node) -> Vec<Message> {
+        self.gossip(node)
+    }
+
+    fn tick_interval(&self) -> Option<Duration> {
+        Some(Duration::from_millis(100))
+    }
+
+    fn prioritize_clients(&self) -> bool {
+        true
+    }
+
+    fn introspect(&self) -> Option<&dyn Introspect> {
+        Some(self)
+    }
+
+    fn on_config_change(&mut self, node: &mut Node) -> Vec<Message> {
+        if self.gossip_peers.len() != node.config.fanout {
+            let fanout = node.config.fanout;
+            self.gossip_peers = self.construct_k_regular_neighbors(node, fanout);
+        }
+        Vec::new()
+    }
+
+    fn on_membership_change(&mut self, node: &mut Node, change: &MembershipChange) -> Vec<Message> {
+        for peer in &change.removed {
+            self.peer_seen.remove(peer);
+            self.unacked.remove(peer);
+        }
+        let fanout = node.config.fanout;
+        self.gossip_peers = self.construct_k_regular_neighbors(node, fanout);
+        Vec::new()
+    }
+}
+
+impl Introspect for MultiNodeBroadcastNode {
+    fn dump_state(&self, _node: &Node) -> serde_json::Value {
+        let mut messages = self.handle_read();
+        messages.sort_unstable();
+        serde_json::json!({
+            "messages": messages,
+            "gossip_peers": self.gossip_peers,
+        })
+    }
+}
+
+impl MessageBodyDispatch for MultiNodeBroadcastNode {
+    fn on_init(
+        &mut self,
+        node: &mut Node,
+        src: String,
+        msg_id: u64,
+        node_id: String,
+        node_ids: Vec<String>,
+    ) -> Vec<Message> {
+        node.handle_init(node_id, node_ids);
+        // Gossip batches dominate traffic; ship them packed to peers that can read it
+        node.compact = true;
+        let fanout = node.config.fanout;
+        self.gossip_peers = self.construct_k_regular_neighbors(node, fanout);
+        vec![node.init_ok(src, msg_id)]
+    }
+
+    fn on_topology(
+        &mut self,
+        node: &mut Node,
+        src: String,
+        msg_id: u64,
+        _topology: HashMap<String, Vec<String>>,
+    ) -> Vec<Message> {
+        vec![
+            node.answer(src, msg_id, |msg_id, in_reply_to| MessageBody::TopologyOk {
                 msg_id,
-                node_id,
-                node_ids,
-            } => {
-                node.handle_init(node_id, node_ids);
-                self.gossip_peers = self.construct_k_regular_neighbors(node, 4);
-                out.push(node.init_ok(msg.src, msg_id));
-            }
-            MessageBody::Topology {
+                in_reply_to,
+            }),
+        ]
+    }
+
+    fn on_broadcast(
+        &mut self,
+        node: &mut Node,
+        src: String,
+        msg_id: u64,
+        message: u64,
+    ) -> Vec<Message> {
+        self.handle_broadcast(message);
+        vec![node.answer(src, msg_id, |msg_id, in_reply_to| {
+            MessageBody::BroadcastOk {
                 msg_id,
-                topology: _,
-            } => {
-                let reply_msg_id = node.next_msg_id();
-                out.push(node.reply(
-                    msg.src,
-                    MessageBody::TopologyOk {
-                        msg_id: reply_msg_id,
-                        in_reply_to: msg_id,
-                    },
-                ));
-            }
-            MessageBody::Broadcast { msg_id, message } => {
-                self.handle_broadcast(message);
-                let reply_msg_id = node.next_msg_id();
-                out.push(node.reply(
-                    msg.src,
-                    MessageBody::BroadcastOk {
-                        msg_id: reply_msg_id,
-                        in_reply_to: msg_id,
-                    },
-                ));
-            }
-            MessageBody::BroadcastGossip {
-                msg_id: _,
-                messages,
-            } => {
-                self.handle_broadcast_gossip_from(&msg.src, messages);
-            }
-            MessageBody::Read { msg_id } => {
-                let messages = self.handle_read();
-                let reply_msg_id = node.next_msg_id();
-                out.push(node.reply(
-                    msg.src,
-                    MessageBody::ReadOk {
-                        msg_id: reply_msg_id,
-                        in_reply_to: msg_id,
-                        messages: Some(messages),
-                        value: None,
-                    },
-                ));
+                in_reply_to,
             }
-            _ => {}
-        }
-        out
+        })]
+    }
+
+    fn on_broadcast_gossip(
+        &mut self,
+        _node: &mut Node,
+        src: String,
+        _msg_id: Option<u64>,
+        messages: Vec<u64>,
+    ) -> Vec<Message> {
+        self.handle_broadcast_gossip_from(&src, &messages);
+        Vec::new()
+    }
+
+    fn on_broadcast_gossip_ok(
+        &mut self,
+        _node: &mut Node,
+        src: String,
+        _msg_id: Option<u64>,
+        messages: Vec<u64>,
+    ) -> Vec<Message> {
+        self.handle_broadcast_gossip_ok_from(&src, messages);
+        Vec::new()
+    }
+
+    fn on_read(
+        &mut self,
+        node: &mut Node,
+        src: String,
+        msg_id: u64,
+        _key: Option<String>,
+        _context: Option<HashMap<String, u64>>,
+    ) -> Vec<Message> {
+        let messages = self.handle_read();
+        vec![
+            node.answer(src, msg_id, |msg_id, in_reply_to| MessageBody::ReadOk {
+                msg_id,
+                in_reply_to,
+                messages: Some(messages),
+                value: None,
+                context: None,
+            }),
+        ]
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::collections::HashMap;
+    use maelstrom::{
+        NodeConfig,
+        faults::{Faults, nodes},
+        process,
+        sim::Sim,
+    };
+    use maelstrom_testkit::{self as testkit, Chaos, ChaosHandler, expect_body};
+    use std::time::Instant;
 
     #[test]
     fn test_broadcast_node_handles_init_message() {
         let mut handler = MultiNodeBroadcastNode::new();
         let mut node = Node::new();
 
-        let init_message = Message {
-            src: "c1".to_string(),
-            dest: "n1".to_string(),
-            body: MessageBody::Init {
-                msg_id: 1,
-                node_id: "n1".to_string(),
-                node_ids: vec![
-                    "n1".to_string(),
-                    "n2".to_string(),
-                    "n3".to_string(),
-                    "n4".to_string(),
-                    "n5".to_string(),
-                ],
-            },
-        };
+        let init_message = testkit::init("n1", ["n1", "n2", "n3", "n4", "n5"]);
 
         let responses = handler.handle(&mut node, init_message);
 
-        assert_eq!(responses.len(), 1);
-        assert_eq!(responses[0].src, "n1");
-        assert_eq!(responses[0].dest, "c1");
+        testkit::assert_route(testkit::only(&responses), "n1", "c1");
 
-        match &responses[0].body {
-            MessageBody::InitOk {
-                msg_id: _,
-                in_reply_to,
-            } => {
-                assert_eq!(*in_reply_to, 1);
-            }
-            _ => panic!("Expected InitOk message"),
-        }
+        let in_reply_to = expect_body!(&responses[0].body, InitOk { in_reply_to, .. });
+        assert_eq!(*in_reply_to, 1);
 
         // Verify node state was updated
         assert_eq!(node.id, "n1");
         assert_eq!(node.peers, vec!["n2", "n3", "n4", "n5"]);
 
-        // Verify gossip peers were constructed (should be k=4, but limited by available peers)
+        // Verify gossip peers were constructed (default fanout k=4, limited by available peers)
         assert_eq!(handler.gossip_peers.len(), 4);
         for peer in &handler.gossip_peers {
             assert!(node.peers.contains(peer));
@@ -210,10 +320,8 @@ mod tests {
     #[test]
     fn test_broadcast_node_handles_topology_message() {
         let mut handler = MultiNodeBroadcastNode::new();
-        let mut node = Node::new();
-
         // Initialize node first
-        node.handle_init("n1".to_string(), vec!["n1".to_string(), "n2".to_string()]);
+        let mut node = testkit::node("n1", ["n1", "n2"]);
 
         let topology_message = Message {
             src: "c1".to_string(),
@@ -226,40 +334,19 @@ mod tests {
 
         let responses = handler.handle(&mut node, topology_message);
 
-        assert_eq!(responses.len(), 1);
-        assert_eq!(responses[0].src, "n1");
-        assert_eq!(responses[0].dest, "c1");
+        testkit::assert_route(testkit::only(&responses), "n1", "c1");
 
-        match &responses[0].body {
-            MessageBody::TopologyOk {
-                msg_id: _,
-                in_reply_to,
-            } => {
-                assert_eq!(*in_reply_to, 1);
-            }
-            _ => panic!("Expected TopologyOk message"),
-        }
+        let in_reply_to = expect_body!(&responses[0].body, TopologyOk { in_reply_to, .. });
+        assert_eq!(*in_reply_to, 1);
     }
 
     #[test]
     fn test_broadcast_node_handles_broadcast_message() {
         let mut handler = MultiNodeBroadcastNode::new();
-        let mut node = Node::new();
-
         // Initialize node
-        node.handle_init(
-            "n1".to_string(),
-            vec!["n1".to_string(), "n2".to_string(), "n3".to_string()],
-        );
+        let mut node = testkit::node("n1", ["n1", "n2", "n3"]);
 
-        let broadcast_message = Message {
-            src: "c1".to_string(),
-            dest: "n1".to_string(),
-            body: MessageBody::Broadcast {
-                msg_id: 1,
-                message: 42,
-            },
-        };
+        let broadcast_message = testkit::broadcast(42);
 
         let responses = handler.handle(&mut node, broadcast_message);
 
@@ -270,15 +357,8 @@ mod tests {
         let broadcast_ok = &responses[0];
         assert_eq!(broadcast_ok.src, "n1");
         assert_eq!(broadcast_ok.dest, "c1");
-        match &broadcast_ok.body {
-            MessageBody::BroadcastOk {
-                msg_id: _,
-                in_reply_to,
-            } => {
-                assert_eq!(*in_reply_to, 1);
-            }
-            _ => panic!("Expected BroadcastOk message"),
-        }
+        let in_reply_to = expect_body!(&broadcast_ok.body, BroadcastOk { in_reply_to, .. });
+        assert_eq!(*in_reply_to, 1);
 
         // Verify message was stored in HashSet
         assert!(handler.messages.contains(&42));
@@ -288,86 +368,95 @@ mod tests {
     #[test]
     fn test_broadcast_node_handles_broadcast_gossip_message() {
         let mut handler = MultiNodeBroadcastNode::new();
-        let mut node = Node::new();
-
         // Initialize node
-        node.handle_init("n1".to_string(), vec!["n1".to_string(), "n2".to_string()]);
+        let mut node = testkit::node("n1", ["n1", "n2"]);
 
         let gossip_message = Message {
             src: "n2".to_string(),
             dest: "n1".to_string(),
             body: MessageBody::BroadcastGossip {
-                msg_id: 1,
+                msg_id: Some(1),
                 messages: vec![10, 20, 30],
             },
         };
 
-        let responses = handler.handle(&mut node, gossip_message);
+        let responses = handler.handle(&mut node, gossip_message.clone());
 
-        // BroadcastGossip messages don't require a response
-        assert_eq!(responses.len(), 0);
+        // Acknowledged on the next tick rather than at once
+        assert!(responses.is_empty());
+        let again = MessageBody::BroadcastGossip {
+            msg_id: None,
+            messages: vec![20, 40],
+        };
+        assert!(
+            handler
+                .handle(
+                    &mut node,
+                    Message {
+                        body: again,
+                        ..gossip_message.clone()
+                    }
+                )
+                .is_empty()
+        );
+
+        // One ack for both gossips, without spending a msg_id, and none once it is sent
+        let acks = handler.gossip(&mut node);
+        testkit::assert_route(testkit::only(&acks), "n1", "n2");
+        let (msg_id, messages) =
+            expect_body!(&acks[0].body, BroadcastGossipOk { msg_id, messages });
+        assert_eq!((msg_id, messages), (&None, &vec![10, 20, 30, 40]));
+        assert_eq!(node.msg_id, 0);
+        assert!(handler.gossip(&mut node).is_empty());
 
         // Verify messages were stored
         assert!(handler.messages.contains(&10));
         assert!(handler.messages.contains(&20));
         assert!(handler.messages.contains(&30));
-        assert_eq!(handler.messages.len(), 3);
+        assert!(handler.messages.contains(&40));
+        assert_eq!(handler.messages.len(), 4);
     }
 
     #[test]
     fn test_broadcast_node_handles_read_message() {
         let mut handler = MultiNodeBroadcastNode::new();
-        let mut node = Node::new();
-
         // Initialize node
-        node.handle_init("n1".to_string(), vec!["n1".to_string()]);
+        let mut node = testkit::node("n1", ["n1"]);
 
         // Add some messages manually for testing
         handler.messages.insert(10);
         handler.messages.insert(20);
         handler.messages.insert(30);
 
-        let read_message = Message {
-            src: "c1".to_string(),
-            dest: "n1".to_string(),
-            body: MessageBody::Read { msg_id: 1 },
-        };
+        let read_message = testkit::read();
 
         let responses = handler.handle(&mut node, read_message);
 
-        assert_eq!(responses.len(), 1);
-        assert_eq!(responses[0].src, "n1");
-        assert_eq!(responses[0].dest, "c1");
+        testkit::assert_route(testkit::only(&responses), "n1", "c1");
 
-        match &responses[0].body {
-            MessageBody::ReadOk {
-                msg_id: _,
+        let (in_reply_to, messages, value) = expect_body!(
+            &responses[0].body,
+            ReadOk {
                 in_reply_to,
                 messages,
                 value,
-            } => {
-                assert_eq!(*in_reply_to, 1);
-                let returned_messages = messages.as_ref().unwrap();
-                assert_eq!(returned_messages.len(), 3);
-                assert!(returned_messages.contains(&10));
-                assert!(returned_messages.contains(&20));
-                assert!(returned_messages.contains(&30));
-                assert_eq!(*value, None);
+                ..
             }
-            _ => panic!("Expected ReadOk message"),
-        }
+        );
+        assert_eq!(*in_reply_to, 1);
+        let returned_messages = messages.as_ref().unwrap();
+        assert_eq!(returned_messages.len(), 3);
+        assert!(returned_messages.contains(&10));
+        assert!(returned_messages.contains(&20));
+        assert!(returned_messages.contains(&30));
+        assert_eq!(*value, None);
     }
 
     #[test]
     fn test_gossip_method() {
         let mut handler = MultiNodeBroadcastNode::new();
-        let mut node = Node::new();
-
         // Initialize node with peers
-        node.handle_init(
-            "n1".to_string(),
-            vec!["n1".to_string(), "n2".to_string(), "n3".to_string()],
-        );
+        let mut node = testkit::node("n1", ["n1", "n2", "n3"]);
         handler.gossip_peers = vec!["n2".to_string(), "n3".to_string()];
 
         // Add some messages
@@ -382,17 +471,77 @@ mod tests {
         for msg in &gossip_messages {
             assert_eq!(msg.src, "n1");
             assert!(msg.dest == "n2" || msg.dest == "n3");
-            match &msg.body {
-                MessageBody::BroadcastGossip {
-                    msg_id: _,
-                    messages,
-                } => {
-                    assert_eq!(messages.len(), 2);
-                    assert!(messages.contains(&100));
-                    assert!(messages.contains(&200));
-                }
-                _ => panic!("Expected BroadcastGossip message"),
-            }
+            let messages = expect_body!(&msg.body, BroadcastGossip { messages, .. });
+            assert_eq!(messages.len(), 2);
+            assert!(messages.contains(&100));
+            assert!(messages.contains(&200));
+        }
+    }
+
+    #[test]
+    fn test_gossip_is_resent_until_acknowledged() {
+        let mut handler = MultiNodeBroadcastNode::new();
+        let mut node = testkit::node("n1", ["n1", "n2"]);
+        handler.gossip_peers = vec!["n2".to_string()];
+        handler.messages.extend([100, 200]);
+        let offered = |handler: &mut MultiNodeBroadcastNode, node: &mut Node| {
+            let mut offered: Vec<u64> = handler
+                .gossip(node)
+                .into_iter()
+                .filter_map(|msg| match msg.body {
+                    MessageBody::BroadcastGossip { messages, .. } => Some(messages),
+                    _ => None,
+                })
+                .flatten()
+                .collect();
+            offered.sort();
+            offered
+        };
+
+        // The first round was lost, so the next offers the same values
+        assert_eq!(offered(&mut handler, &mut node), vec![100, 200]);
+        assert_eq!(offered(&mut handler, &mut node), vec![100, 200]);
+
+        // Only an acknowledgment moves what the peer is known to hold
+        let from_n2 = |body| Message {
+            src: "n2".to_string(),
+            dest: "n1".to_string(),
+            body,
+        };
+        let gossip = MessageBody::BroadcastGossip {
+            msg_id: None,
+            messages: vec![100],
+        };
+        handler.handle(&mut node, from_n2(gossip));
+        assert_eq!(offered(&mut handler, &mut node), vec![100, 200]);
+        let ack = MessageBody::BroadcastGossipOk {
+            msg_id: None,
+            messages: vec![100],
+        };
+        assert!(handler.handle(&mut node, from_n2(ack)).is_empty());
+        assert_eq!(offered(&mut handler, &mut node), vec![200]);
+    }
+
+    #[test]
+    fn test_gossip_repeated_after_a_lost_ack_is_acknowledged_again() {
+        let mut handler = MultiNodeBroadcastNode::new();
+        let mut node = testkit::node("n1", ["n1", "n2"]);
+        let gossip = Message {
+            src: "n2".to_string(),
+            dest: "n1".to_string(),
+            body: MessageBody::BroadcastGossip {
+                msg_id: None,
+                messages: vec![7],
+            },
+        };
+        for _ in 0..2 {
+            handler.handle(&mut node, gossip.clone());
+            let acks = handler.gossip(&mut node);
+            let messages = expect_body!(
+                &testkit::only(&acks).body,
+                BroadcastGossipOk { messages, .. }
+            );
+            assert_eq!(messages, &vec![7]);
         }
     }
 
@@ -420,22 +569,10 @@ mod tests {
     #[test]
     fn test_construct_k_regular_neighbors() {
         let handler = MultiNodeBroadcastNode::new();
-        let mut node = Node::new();
-
         // Test with 5 peers, k=3
-        node.handle_init(
-            "n1".to_string(),
-            vec![
-                "n1".to_string(),
-                "n2".to_string(),
-                "n3".to_string(),
-                "n4".to_string(),
-                "n5".to_string(),
-                "n6".to_string(),
-            ],
-        );
+        let mut node = testkit::node("n1", ["n1", "n2", "n3", "n4", "n5", "n6"]);
 
-        let neighbors = handler.construct_k_regular_neighbors(&node, 3);
+        let neighbors = handler.construct_k_regular_neighbors(&mut node, 3);
 
         assert_eq!(neighbors.len(), 3);
         for neighbor in &neighbors {
@@ -444,44 +581,28 @@ mod tests {
         }
 
         // Test with k larger than available peers
-        let large_k_neighbors = handler.construct_k_regular_neighbors(&node, 10);
+        let large_k_neighbors = handler.construct_k_regular_neighbors(&mut node, 10);
         assert_eq!(large_k_neighbors.len(), 5); // Should be limited to actual peer count
 
         // Test with k=0
-        let zero_neighbors = handler.construct_k_regular_neighbors(&node, 0);
+        let zero_neighbors = handler.construct_k_regular_neighbors(&mut node, 0);
         assert_eq!(zero_neighbors.len(), 0);
     }
 
     #[test]
     fn test_broadcast_node_handles_multiple_broadcasts() {
         let mut handler = MultiNodeBroadcastNode::new();
-        let mut node = Node::new();
-
         // Initialize node
-        node.handle_init("n1".to_string(), vec!["n1".to_string(), "n2".to_string()]);
+        let mut node = testkit::node("n1", ["n1", "n2"]);
 
         // Send first broadcast
-        let broadcast1 = Message {
-            src: "c1".to_string(),
-            dest: "n1".to_string(),
-            body: MessageBody::Broadcast {
-                msg_id: 1,
-                message: 100,
-            },
-        };
+        let broadcast1 = testkit::broadcast(100);
 
         let responses1 = handler.handle(&mut node, broadcast1);
         assert_eq!(responses1.len(), 1); // Only BroadcastOk
 
         // Send second broadcast
-        let broadcast2 = Message {
-            src: "c1".to_string(),
-            dest: "n1".to_string(),
-            body: MessageBody::Broadcast {
-                msg_id: 2,
-                message: 200,
-            },
-        };
+        let broadcast2 = testkit::broadcast(200).with_msg_id(2);
 
         let responses2 = handler.handle(&mut node, broadcast2);
         assert_eq!(responses2.len(), 1); // Only BroadcastOk
@@ -492,41 +613,24 @@ mod tests {
         assert_eq!(handler.messages.len(), 2);
 
         // Test read to confirm both messages are returned
-        let read_message = Message {
-            src: "c1".to_string(),
-            dest: "n1".to_string(),
-            body: MessageBody::Read { msg_id: 3 },
-        };
+        let read_message = testkit::read().with_msg_id(3);
 
         let read_responses = handler.handle(&mut node, read_message);
-        match &read_responses[0].body {
-            MessageBody::ReadOk { messages, .. } => {
-                let returned_messages = messages.as_ref().unwrap();
-                assert_eq!(returned_messages.len(), 2);
-                assert!(returned_messages.contains(&100));
-                assert!(returned_messages.contains(&200));
-            }
-            _ => panic!("Expected ReadOk message"),
-        }
+        let messages = expect_body!(&read_responses[0].body, ReadOk { messages, .. });
+        let returned_messages = messages.as_ref().unwrap();
+        assert_eq!(returned_messages.len(), 2);
+        assert!(returned_messages.contains(&100));
+        assert!(returned_messages.contains(&200));
     }
 
     #[test]
     fn test_broadcast_node_deduplicates_messages() {
         let mut handler = MultiNodeBroadcastNode::new();
-        let mut node = Node::new();
-
         // Initialize node
-        node.handle_init("n1".to_string(), vec!["n1".to_string()]);
+        let mut node = testkit::node("n1", ["n1"]);
 
         // Send same broadcast multiple times
-        let broadcast_message = Message {
-            src: "c1".to_string(),
-            dest: "n1".to_string(),
-            body: MessageBody::Broadcast {
-                msg_id: 1,
-                message: 42,
-            },
-        };
+        let broadcast_message = testkit::broadcast(42);
 
         handler.handle(&mut node, broadcast_message.clone());
         handler.handle(&mut node, broadcast_message.clone());
@@ -545,7 +649,10 @@ mod tests {
         let unknown_message = Message {
             src: "c1".to_string(),
             dest: "n1".to_string(),
-            body: MessageBody::Generate { msg_id: 1 },
+            body: MessageBody::BroadcastOk {
+                msg_id: 1,
+                in_reply_to: 1,
+            },
         };
 
         let responses = handler.handle(&mut node, unknown_message);
@@ -556,52 +663,219 @@ mod tests {
     #[test]
     fn test_broadcast_node_read_when_empty() {
         let mut handler = MultiNodeBroadcastNode::new();
-        let mut node = Node::new();
-
         // Initialize node
-        node.handle_init("n1".to_string(), vec!["n1".to_string()]);
+        let mut node = testkit::node("n1", ["n1"]);
 
-        let read_message = Message {
-            src: "c1".to_string(),
-            dest: "n1".to_string(),
-            body: MessageBody::Read { msg_id: 1 },
-        };
+        let read_message = testkit::read();
 
         let responses = handler.handle(&mut node, read_message);
 
         assert_eq!(responses.len(), 1);
-        match &responses[0].body {
-            MessageBody::ReadOk { messages, .. } => {
-                assert_eq!(messages.as_ref().unwrap(), &Vec::<u64>::new());
-            }
-            _ => panic!("Expected ReadOk message"),
-        }
+        let messages = expect_body!(&responses[0].body, ReadOk { messages, .. });
+        assert_eq!(messages.as_ref().unwrap(), &Vec::<u64>::new());
     }
 
     #[test]
-    fn test_broadcast_node_generates_unique_msg_ids() {
+    fn test_broadcast_gossip_does_not_spend_msg_ids() {
+        let mut handler = MultiNodeBroadcastNode::new();
+        // Initialize node with gossip peers
+        let mut node = testkit::node("n1", ["n1", "n2"]);
+        handler.gossip_peers = vec!["n2".to_string()];
+        handler.messages.insert(42);
+
+        let gossip_messages = handler.gossip(&mut node);
+
+        // Gossip is fire-and-forget, so it carries no msg_id and leaves the counter alone
+        let msg_id = expect_body!(&gossip_messages[0].body, BroadcastGossip { msg_id, .. });
+        assert_eq!(*msg_id, None);
+        assert_eq!(node.msg_id, 0);
+    }
+
+    #[test]
+    fn test_tick_drives_gossip() {
         let mut handler = MultiNodeBroadcastNode::new();
         let mut node = Node::new();
+        assert_eq!(handler.tick_interval(), Some(Duration::from_millis(100)));
+        assert!(handler.prioritize_clients());
+
+        // Nothing to gossip before init
+        assert!(handler.on_tick(&mut node).is_empty());
 
-        // Initialize node with gossip peers
         node.handle_init("n1".to_string(), vec!["n1".to_string(), "n2".to_string()]);
         handler.gossip_peers = vec!["n2".to_string()];
-        handler.messages.insert(42);
+        handler.messages.insert(7);
 
-        let gossip_messages1 = handler.gossip(&mut node);
-        let gossip_messages2 = handler.gossip(&mut node);
+        let out = handler.on_tick(&mut node);
+        assert_eq!(out.len(), 1);
+        assert_eq!(out[0].dest, "n2");
+    }
 
-        // Extract msg_ids from gossip messages
-        let msg_id1 = match &gossip_messages1[0].body {
-            MessageBody::BroadcastGossip { msg_id, .. } => *msg_id,
-            _ => panic!("Expected BroadcastGossip message"),
-        };
+    #[test]
+    fn test_init_uses_configured_fanout() {
+        let mut handler = MultiNodeBroadcastNode::new();
+        let mut node = Node::with_config(NodeConfig::new().fanout(2));
+
+        handler.handle(
+            &mut node,
+            testkit::init("n1", ["n1", "n2", "n3", "n4", "n5"]),
+        );
+        assert_eq!(handler.gossip_peers.len(), 2);
+    }
+
+    #[test]
+    fn test_set_config_rebuilds_gossip_peers_and_dump_state_shows_them() {
+        let mut handler = MultiNodeBroadcastNode::new();
+        let mut node = Node::new();
+        process(
+            &mut handler,
+            &mut node,
+            testkit::init("n1", ["n1", "n2", "n3", "n4", "n5"]),
+        );
+        assert_eq!(handler.gossip_peers.len(), 4);
 
-        let msg_id2 = match &gossip_messages2[0].body {
-            MessageBody::BroadcastGossip { msg_id, .. } => *msg_id,
-            _ => panic!("Expected BroadcastGossip message"),
+        process(&mut handler, &mut node, testkit::set_config(None, Some(1)));
+        assert_eq!(handler.gossip_peers.len(), 1);
+
+        let out = process(&mut handler, &mut node, testkit::dump_state());
+        let state = expect_body!(&out[0].body, DumpStateOk { state, .. });
+        assert_eq!(state["gossip_peers"][0], handler.gossip_peers[0]);
+    }
+
+    #[test]
+    fn test_departed_peers_leave_the_gossip_set() {
+        let mut handler = MultiNodeBroadcastNode::new();
+        let mut node = Node::new();
+        process(
+            &mut handler,
+            &mut node,
+            testkit::init("n1", ["n1", "n2", "n3"]),
+        );
+        handler.handle_broadcast_gossip_ok_from("n2", vec![1]);
+
+        process(&mut handler, &mut node, testkit::membership(["n4"], ["n2"]));
+        let mut gossip_peers = handler.gossip_peers.clone();
+        gossip_peers.sort();
+        assert_eq!(gossip_peers, vec!["n3", "n4"]);
+        assert!(!handler.peer_seen.contains_key("n2"));
+    }
+
+    #[test]
+    fn test_gossip_skips_suspected_peers() {
+        let mut handler = MultiNodeBroadcastNode::new();
+        let mut node = Node::with_config(NodeConfig::new().suspect_after(Duration::from_secs(1)));
+        node.handle_init(
+            "n1".to_string(),
+            vec!["n1".to_string(), "n2".to_string(), "n3".to_string()],
+        );
+        handler.gossip_peers = vec!["n2".to_string(), "n3".to_string()];
+        handler.messages.insert(7);
+
+        let now = Instant::now();
+        node.liveness
+            .reset(now.checked_sub(Duration::from_secs(10)).unwrap());
+        node.liveness.heard_from("n3", now);
+
+        let out = handler.gossip(&mut node);
+        assert_eq!(out.len(), 1);
+        assert_eq!(out[0].dest, "n3");
+    }
+
+    #[test]
+    fn test_seeded_topology_is_reproducible() {
+        let gossip_peers = |seed| {
+            let mut handler = MultiNodeBroadcastNode::new();
+            let mut node = Node::with_config(NodeConfig::new().seed(seed).fanout(2));
+            let ids = (1..=8).map(|i| format!("n{i}"));
+            handler.handle(
+                &mut node,
+                testkit::init("n1", ids.collect::<Vec<_>>().iter().map(String::as_str)),
+            );
+            handler.gossip_peers
         };
+        assert_eq!(gossip_peers(11), gossip_peers(11));
+        assert!((0..8).any(|seed| gossip_peers(seed) != gossip_peers(11)));
+    }
+
+    #[test]
+    fn test_gossip_converges_across_a_simulated_cluster() {
+        let mut sim = Sim::with_config(5, NodeConfig::new().fanout(2).seed(5), |_| {
+            MultiNodeBroadcastNode::new()
+        });
+        sim.latency(Duration::from_millis(1), Duration::from_millis(10));
+        for (i, value) in (10..15).enumerate() {
+            let dest = format!("n{}", i + 1);
+            let reply = sim.call(testkit::broadcast(value).dest(&dest)).unwrap();
+            assert!(matches!(reply.body, MessageBody::BroadcastOk { .. }));
+        }
 
-        assert_ne!(msg_id1, msg_id2);
+        // Gossip rounds every 100 ms of virtual time spread every value over a fanout-2 topology
+        let converged = sim.run_until(Duration::from_secs(2), |sim| {
+            sim.node_ids()
+                .iter()
+                .all(|id| sim.handler(id).messages.len() == 5)
+        });
+        assert!(converged.is_some(), "no agreement within 2 s simulated");
+        for id in sim.node_ids() {
+            let mut messages: Vec<u64> = sim.handler(&id).messages.iter().copied().collect();
+            messages.sort();
+            assert_eq!(messages, vec![10, 11, 12, 13, 14], "{id} is missing values");
+        }
+    }
+
+    #[test]
+    fn test_values_cross_a_healed_partition() {
+        let mut sim = Sim::with_config(5, NodeConfig::new().fanout(2).seed(5), |_| {
+            MultiNodeBroadcastNode::new()
+        });
+        sim.latency(Duration::from_millis(1), Duration::from_millis(10));
+        sim.inject(
+            Faults::new()
+                .partition(nodes(["n1", "n2"]) | nodes(["n3", "n4", "n5"]))
+                .drop(0.1)
+                .duplicate(0.1)
+                .reorder(Duration::from_millis(20))
+                .at(Duration::from_secs(1))
+                .calm(),
+        );
+        sim.call(testkit::broadcast(1).dest("n1")).unwrap();
+        sim.call(testkit::broadcast(2).dest("n4").with_msg_id(2))
+            .unwrap();
+
+        let report = testkit::check_broadcast(&mut sim, Duration::from_secs(5), |node| {
+            node.messages.clone()
+        });
+        report.assert_valid();
+        assert_eq!(report.acknowledged, 2);
+        assert!(
+            report.latency.max >= Duration::from_millis(900),
+            "values crossed the partition: {}",
+            report.latency
+        );
+    }
+
+    #[test]
+    fn test_gossip_survives_its_own_sends_going_wrong() {
+        let mut sim = Sim::with_config(5, NodeConfig::new().fanout(2).seed(5), |id| {
+            let chaos = Chaos::new(id[1..].parse().unwrap())
+                .drop(0.3)
+                .duplicate(0.2)
+                .delay(0.3, 3);
+            ChaosHandler::new(MultiNodeBroadcastNode::new(), chaos)
+        });
+        for (i, value) in (10..15).enumerate() {
+            let dest = format!("n{}", i + 1);
+            sim.call(testkit::broadcast(value).dest(&dest)).unwrap();
+        }
+
+        let report = testkit::check_broadcast(&mut sim, Duration::from_secs(5), |node| {
+            node.inner().messages.clone()
+        });
+        report.assert_valid();
+        assert_eq!(report.acknowledged, 5);
+        assert!(
+            sim.node_ids()
+                .iter()
+                .any(|id| sim.handler(id).stats().dropped > 0)
+        );
     }
 }
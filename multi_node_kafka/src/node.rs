@@ -1,9 +1,12 @@
-use maelstrom::log::Logs;
+use maelstrom::log::{AppendError, KvOffsets, Logs, PollLimits, Retention, UnknownKeys};
+use maelstrom::services::LinKv;
 use maelstrom::{
-    Message, MessageBody,
-    node::{MessageHandler, Node},
+    ErrorCode, MaelstromError, Message, MessageBody, MessageBodyDispatch, MessageHandler, Node,
+    rpc::Call,
 };
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
 
 pub struct Pending {
     client: String,
@@ -11,6 +14,8 @@ pub struct Pending {
     acks: usize,
     /// Set of replica node IDs that have acked this offset (seeded with leader)
     from: HashSet<String>,
+    /// Replicas whose replicate call timed out
+    failed: usize,
 }
 
 pub struct KafkaNode {
@@ -18,12 +23,22 @@ pub struct KafkaNode {
     leader: String,
     /// Next offset for node to use
     next_offset: u64,
-    /// Append-only logs
-    logs: Logs,
-    /// Pending operations
-    pendings: HashMap<u64, Pending>,
+    /// Append-only logs, shared with the tasks appending at offsets claimed from lin-kv
+    logs: Arc<Mutex<Logs>>,
+    /// Sends still short of a quorum, by log and then offset, since every log counts its
+    /// offsets from 0
+    pendings: HashMap<String, BTreeMap<u64, Pending>>,
+    /// What compaction keeps of each log; logs grow without bound when unset
+    retention: Option<Retention>,
+    /// Accept sends on every node, claiming offsets from lin-kv, rather than on the leader
+    leaderless: bool,
+    /// The lin-kv client offsets are claimed through, once initialized in leaderless mode
+    kv: Option<LinKv>,
 }
 
+/// How often logs are compacted when a retention policy is set
+const COMPACT_EVERY: Duration = Duration::from_secs(1);
+
 impl Default for KafkaNode {
     fn default() -> Self {
         Self::new()
@@ -35,17 +50,72 @@ impl KafkaNode {
         Self {
             leader: String::new(),
             next_offset: 0,
-            logs: Logs::new(),
+            logs: Arc::new(Mutex::new(Logs::new())),
             pendings: HashMap::new(),
+            retention: None,
+            leaderless: false,
+            kv: None,
         }
     }
 
+    /// Cap the entries each `poll_ok` carries; unbounded by default
+    pub fn poll_limits(self, limits: PollLimits) -> Self {
+        self.logs().set_poll_limits(limits);
+        self
+    }
+
+    /// Answer polls of keys nothing was sent to per `unknown_keys`; left out by default
+    pub fn unknown_keys(self, unknown_keys: UnknownKeys) -> Self {
+        self.logs().set_unknown_keys(unknown_keys);
+        self
+    }
+
+    /// Accept commits past the end of a log instead of answering `precondition_failed`
+    pub fn lenient_commits(self) -> Self {
+        self.logs().set_lenient_commits(true);
+        self
+    }
+
+    /// Compact the logs to `retention` every [`COMPACT_EVERY`]
+    pub fn retention(mut self, retention: Retention) -> Self {
+        self.retention = Some(retention);
+        self
+    }
+
+    /// Let every node accept sends, each claiming its offset from a per-key counter in lin-kv
+    /// before appending locally and passing the entry on to its peers. Nothing waits for the
+    /// peers, so a poll elsewhere may not see a send until its `replicate` arrives, nor any
+    /// entry past it: logs keep staging, so polls never skip an offset that is yet to fill.
+    pub fn leaderless(mut self) -> Self {
+        self.leaderless = true;
+        self
+    }
+
+    fn logs(&self) -> MutexGuard<'_, Logs> {
+        self.logs
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
     pub fn quorum(&self, node: &Node) -> usize {
         node.peers.len().div_ceil(2) + 1
     }
 
     pub fn handle_init(&mut self, node: &mut Node, node_id: String, node_ids: Vec<String>) {
         node.handle_init(node_id.clone(), node_ids.clone());
+        // A node restarted after a crash picks its logs back up from disk
+        if let Some(dir) = node.config.data_dir.clone() {
+            match Logs::open(dir.join(&node_id)) {
+                Ok(mut logs) => {
+                    for corruption in logs.recovered_corruption() {
+                        node.log(&corruption.to_string());
+                    }
+                    logs.copy_settings(&self.logs());
+                    *self.logs() = logs;
+                }
+                Err(err) => node.log(&format!("keeping logs in memory: {err}")),
+            }
+        }
         let mut all = node_ids.clone();
         all.sort();
         self.leader = all[0].clone();
@@ -54,229 +124,587 @@ impl KafkaNode {
     pub fn handle_send(
         &mut self,
         node: &mut Node,
-        message: Message,
+        src: String,
         msg_id: u64,
         key: String,
         msg: u64,
     ) -> Vec<Message> {
+        if self.leaderless {
+            return self.send_leaderless(node, src, msg_id, key, msg);
+        }
         let mut out: Vec<Message> = Vec::new();
         if node.id != self.leader {
-            out.push(Message {
-                src: node.id.clone(),
-                dest: self.leader.clone(),
-                body: MessageBody::ForwardSend {
-                    msg_id: node.next_msg_id(),
-                    orig_src: message.src,
+            out.push(node.send(
+                self.leader.clone(),
+                MessageBody::ForwardSend {
+                    msg_id: None,
+                    orig_src: src,
                     orig_msg_id: msg_id,
                     key,
                     msg,
                 },
-            })
+            ))
         } else {
-            let offset = self.logs.append_local(&key, msg);
+            // Followers the failure detector suspects are not asked and count as failed
+            let followers = node.alive_peers();
+            let failed = node.peers.len() - followers.len();
+            if node.peers.len() + 1 - failed < self.quorum(node) {
+                return vec![node.temporarily_unavailable(
+                    src,
+                    msg_id,
+                    format!(
+                        "only {} of {} followers reachable",
+                        followers.len(),
+                        node.peers.len()
+                    ),
+                )];
+            }
+            let offset = match self.logs().append_local(&key, msg) {
+                Ok(offset) => offset,
+                Err(err) => {
+                    return vec![node.temporarily_unavailable(
+                        src,
+                        msg_id,
+                        format!("log write failed: {err}"),
+                    )];
+                }
+            };
             self.next_offset = offset + 1;
-            self.pendings.insert(
+            self.pendings.entry(key.clone()).or_default().insert(
                 offset,
                 Pending {
-                    client: message.src.clone(),
+                    client: src.clone(),
                     client_msg_id: msg_id,
                     acks: 1,
                     from: HashSet::from([node.id.clone()]),
+                    failed,
                 },
             );
-            let peers = node.peers.clone();
-            for peer in peers {
-                let msg_id = node.next_msg_id();
-                out.push(Message {
-                    src: node.id.clone(),
-                    dest: peer,
-                    body: MessageBody::Replicate {
-                        msg_id,
-                        key: key.clone(),
-                        msg,
-                        offset,
-                    },
-                })
+            for peer in followers {
+                let replicate = MessageBody::Replicate {
+                    msg_id: 0,
+                    key: key.clone(),
+                    msg,
+                    offset,
+                };
+                out.push(
+                    node.rpc(peer, replicate)
+                        .expect("a replicate carries a msg_id"),
+                );
             }
             if self.quorum(node) <= 1 {
-                out.push(Message {
-                    src: node.id.clone(),
-                    dest: message.src,
-                    body: MessageBody::SendOk {
-                        msg_id: node.next_msg_id(),
-                        in_reply_to: msg_id,
+                out.push(
+                    node.answer(src, msg_id, |msg_id, in_reply_to| MessageBody::SendOk {
+                        msg_id,
+                        in_reply_to,
                         offset,
-                    },
-                });
-                self.pendings.remove(&offset);
+                    }),
+                );
+                self.take_pending(&key, offset);
             }
+            self.advance_hwm(&key);
         }
         out
     }
+
+    /// Show pollers `key`'s entries up to its oldest send still short of a quorum
+    fn advance_hwm(&mut self, key: &str) {
+        let mut logs = self.logs();
+        let hwm = self
+            .pendings
+            .get(key)
+            .and_then(|pendings| pendings.keys().next().copied())
+            .unwrap_or_else(|| logs.next_offset(key));
+        logs.set_hwm(key, hwm);
+    }
+
+    /// Stop waiting on the send at `offset` of `key`'s log
+    fn take_pending(&mut self, key: &str, offset: u64) -> Option<Pending> {
+        let pendings = self.pendings.get_mut(key)?;
+        let pending = pendings.remove(&offset);
+        if pendings.is_empty() {
+            self.pendings.remove(key);
+        }
+        pending
+    }
+
+    /// A replica will not ack the send `call` replicated, because the call timed out or was
+    /// answered with an error; the send fails once the replicas left cannot make a quorum
+    fn replica_failed(&mut self, node: &mut Node, call: &Call, why: &str) -> Vec<Message> {
+        let MessageBody::Replicate { key, offset, .. } = call.request().body.clone() else {
+            return Vec::new();
+        };
+        node.log(&format!(
+            "replicate of {key} offset {offset} to {} {why}",
+            call.dest
+        ));
+        let quorum = self.quorum(node);
+        let replicas = node.peers.len() + 1;
+        let Some(p) = self
+            .pendings
+            .get_mut(&key)
+            .and_then(|pendings| pendings.get_mut(&offset))
+        else {
+            return Vec::new();
+        };
+        p.failed += 1;
+        // Give up once the replicas still able to ack cannot make a quorum
+        if replicas - p.failed >= quorum {
+            return Vec::new();
+        }
+        let Pending {
+            client,
+            client_msg_id,
+            ..
+        } = self.take_pending(&key, offset).unwrap();
+        // The entry stays in the leader's log and is polled once the watermark passes it, so
+        // the failure is indefinite: `crash`, not a definite `temporarily_unavailable`
+        self.advance_hwm(&key);
+        vec![node.error(
+            client,
+            client_msg_id,
+            ErrorCode::Crash,
+            format!("{key} offset {offset} could not reach a quorum"),
+        )]
+    }
+
+    /// Whether polls and commits go to the leader, which alone knows which entries reached a
+    /// quorum, rather than being answered from this node's own logs
+    fn asks_leader(&self, node: &Node) -> bool {
+        !self.leaderless && !self.leader.is_empty() && node.id != self.leader
+    }
+
+    /// Pass a request on to the leader, remembering the client to relay its answer to
+    fn ask_leader(
+        &mut self,
+        node: &mut Node,
+        src: String,
+        msg_id: u64,
+        body: MessageBody,
+    ) -> Vec<Message> {
+        let forwarded = node.rpc_with(self.leader.clone(), body, (src, msg_id));
+        vec![forwarded.expect("polls and commits carry a msg_id")]
+    }
+
+    /// Pass the leader's answer to a request forwarded by this follower on to the client
+    fn relay(&mut self, node: &mut Node, mut call: Call, reply: Message) -> Vec<Message> {
+        let Some((client, client_msg_id)) = call.context::<(String, u64)>() else {
+            return Vec::new();
+        };
+        match reply.body {
+            MessageBody::PollOk {
+                msgs,
+                next_offsets,
+                has_more,
+                ..
+            } => vec![node.answer(client, client_msg_id, |msg_id, in_reply_to| {
+                MessageBody::PollOk {
+                    msg_id,
+                    in_reply_to,
+                    msgs,
+                    next_offsets,
+                    has_more,
+                }
+            })],
+            MessageBody::CommitOffsetsOk { .. } => {
+                vec![node.answer(client, client_msg_id, |msg_id, in_reply_to| {
+                    MessageBody::CommitOffsetsOk {
+                        msg_id,
+                        in_reply_to,
+                    }
+                })]
+            }
+            MessageBody::ListCommittedOffsetsOk { offsets, .. } => {
+                vec![node.answer(client, client_msg_id, |msg_id, in_reply_to| {
+                    MessageBody::ListCommittedOffsetsOk {
+                        msg_id,
+                        in_reply_to,
+                        offsets,
+                    }
+                })]
+            }
+            MessageBody::Error { code, text, .. } => {
+                vec![node.error(client, client_msg_id, code, text.unwrap_or_default())]
+            }
+            _ => Vec::new(),
+        }
+    }
+
+    /// Claim an offset and append off the loop, then replicate and answer from there
+    fn send_leaderless(
+        &mut self,
+        node: &mut Node,
+        src: String,
+        msg_id: u64,
+        key: String,
+        msg: u64,
+    ) -> Vec<Message> {
+        let (Some(kv), Some(deferred)) = (self.kv.clone(), node.deferred()) else {
+            return vec![node.temporarily_unavailable(src, msg_id, "lin-kv is not connected")];
+        };
+        let logs = Arc::clone(&self.logs);
+        let (id, peers) = (node.id.clone(), node.peers.clone());
+        tokio::spawn(async move {
+            let message = |dest: &str, body| Message {
+                src: id.clone(),
+                dest: dest.to_string(),
+                body,
+            };
+            // Left at 0, `msg_id`s are filled in as the messages are written
+            let appended = KvOffsets::new(kv).append(&logs, &key, msg).await;
+            // Peers learn of the offset only from this, so it is resent until each
+            // acknowledges it; one left unfilled would stall their polls of the key for good
+            let fill = match &appended {
+                Ok(offset) => Some(MessageBody::Replicate {
+                    msg_id: 0,
+                    key: key.clone(),
+                    msg,
+                    offset: *offset,
+                }),
+                Err(AppendError::Unwritten { offset, .. }) => Some(MessageBody::Tombstone {
+                    msg_id: 0,
+                    key: key.clone(),
+                    offset: *offset,
+                }),
+                Err(AppendError::Unclaimed(_)) => None,
+            };
+            if let Some(fill) = fill {
+                let fills = peers
+                    .iter()
+                    .map(|peer| message(peer, fill.clone()))
+                    .collect();
+                deferred.deliver(fills);
+            }
+            let reply = match appended {
+                Ok(offset) => MessageBody::SendOk {
+                    msg_id: 0,
+                    in_reply_to: msg_id,
+                    offset,
+                },
+                Err(err) => {
+                    let err = MaelstromError::from(err);
+                    MessageBody::Error {
+                        msg_id: 0,
+                        in_reply_to: msg_id,
+                        code: err.code,
+                        text: Some(err.text),
+                        extra: None,
+                    }
+                }
+            };
+            deferred.send(vec![message(&src, reply)]);
+        });
+        Vec::new()
+    }
 }
 
 impl MessageHandler for KafkaNode {
     fn handle(&mut self, node: &mut Node, message: Message) -> Vec<Message> {
-        let mut out = Vec::new();
-        match message.body.clone() {
-            MessageBody::Init {
-                msg_id,
-                node_id,
-                node_ids,
-            } => {
-                self.handle_init(node, node_id, node_ids);
-                out.push(node.init_ok(message.src, msg_id));
-            }
-            MessageBody::Send { msg_id, key, msg } => {
-                let msgs = self.handle_send(node, message.clone(), msg_id, key.clone(), msg);
-                out.extend(msgs);
-            }
-            MessageBody::ForwardSend {
-                msg_id: _,
-                orig_src,
-                orig_msg_id,
-                key,
-                msg,
-            } => {
-                // leader handles forwarded same as `Send`
-                // reuse above by recursive call
-                let fwd = Message {
-                    src: orig_src,
-                    dest: node.id.clone(),
-                    body: MessageBody::Send {
-                        msg_id: orig_msg_id,
-                        key,
-                        msg,
-                    },
-                };
-                out.extend(self.handle(node, fwd));
+        if self.kv.as_ref().is_some_and(|kv| kv.resolve(&message)) {
+            return Vec::new();
+        }
+        self.dispatch(node, message)
+    }
+
+    fn on_node_init(&mut self, node: &Node) {
+        if self.leaderless {
+            self.kv = LinKv::connect(node);
+        }
+    }
+
+    fn on_tick(&mut self, node: &mut Node) -> Vec<Message> {
+        if let Some(retention) = self.retention {
+            let compacted = self.logs().compact(retention);
+            match compacted {
+                Ok(0) => {}
+                Ok(dropped) => node.log(&format!("compacted {dropped} log entries")),
+                Err(err) => node.log(&format!("log compaction failed: {err}")),
             }
-            MessageBody::Replicate {
+        }
+        Vec::new()
+    }
+
+    fn tick_interval(&self) -> Option<Duration> {
+        self.retention.map(|_| COMPACT_EVERY)
+    }
+
+    fn stats(&self, _node: &Node) -> Option<serde_json::Value> {
+        Some(serde_json::json!({ "logs": self.logs().stats() }))
+    }
+
+    fn on_rpc_reply(&mut self, node: &mut Node, call: Call, reply: Message) -> Vec<Message> {
+        if forwarded(&call).is_some() {
+            return self.relay(node, call, reply);
+        }
+        if let MessageBody::Error { code, text, .. } = &reply.body {
+            let why = format!("failed: {code:?} {}", text.as_deref().unwrap_or(""));
+            return self.replica_failed(node, &call, &why);
+        }
+        self.handle(node, reply)
+    }
+
+    fn on_rpc_timeout(&mut self, node: &mut Node, mut call: Call) -> Vec<Message> {
+        if let Some(request) = forwarded(&call) {
+            let Some((client, client_msg_id)) = call.context::<(String, u64)>() else {
+                return Vec::new();
+            };
+            return vec![node.temporarily_unavailable(
+                client,
+                client_msg_id,
+                format!("leader {} did not answer the {request}", call.dest),
+            )];
+        }
+        self.replica_failed(node, &call, "timed out")
+    }
+}
+
+/// The request a follower passed on to the leader for a client, if `call` is one
+fn forwarded(call: &Call) -> Option<&'static str> {
+    match call.request().body {
+        MessageBody::Poll { .. } => Some("poll"),
+        MessageBody::CommitOffsets { .. } => Some("commit"),
+        MessageBody::ListCommittedOffsets { .. } => Some("listing of committed offsets"),
+        _ => None,
+    }
+}
+
+impl MessageBodyDispatch for KafkaNode {
+    fn on_init(
+        &mut self,
+        node: &mut Node,
+        src: String,
+        msg_id: u64,
+        node_id: String,
+        node_ids: Vec<String>,
+    ) -> Vec<Message> {
+        self.handle_init(node, node_id, node_ids);
+        vec![node.init_ok(src, msg_id)]
+    }
+
+    fn on_send(
+        &mut self,
+        node: &mut Node,
+        src: String,
+        msg_id: u64,
+        key: String,
+        msg: u64,
+    ) -> Vec<Message> {
+        self.handle_send(node, src, msg_id, key, msg)
+    }
+
+    fn on_forward_send(
+        &mut self,
+        node: &mut Node,
+        _src: String,
+        _msg_id: Option<u64>,
+        orig_src: String,
+        orig_msg_id: u64,
+        key: String,
+        msg: u64,
+    ) -> Vec<Message> {
+        // leader handles forwarded same as `Send`, replying to the original client
+        self.handle_send(node, orig_src, orig_msg_id, key, msg)
+    }
+
+    fn on_replicate(
+        &mut self,
+        node: &mut Node,
+        src: String,
+        msg_id: u64,
+        key: String,
+        msg: u64,
+        offset: u64,
+    ) -> Vec<Message> {
+        let inserted = self.logs().insert_at(&key, offset, msg);
+        if let Err(err) = inserted {
+            return vec![node.temporarily_unavailable(
+                src,
+                msg_id,
+                format!("log write failed: {err}"),
+            )];
+        }
+        vec![node.answer(src, msg_id, |msg_id, in_reply_to| {
+            MessageBody::ReplicateOk {
                 msg_id,
+                in_reply_to,
                 key,
-                msg,
                 offset,
-            } => {
-                self.logs.insert_at(&key, offset, msg);
-                let reply_msg_id = node.next_msg_id();
-                out.push(node.reply(
-                    message.src,
-                    MessageBody::ReplicateOk {
-                        msg_id: reply_msg_id,
-                        in_reply_to: msg_id,
-                        offset,
-                    },
-                ))
             }
-            MessageBody::ReplicateOk {
-                msg_id: _,
-                in_reply_to: _,
-                offset,
-            } => {
-                // Grab quorum once, before get_mut()
-                let quorum = self.quorum(node);
-                // Mutably borrow the pending entry and bump acks only on first ack from this src
-                if let Some(p) = self.pendings.get_mut(&offset) {
-                    if p.from.insert(message.src.clone()) {
-                        p.acks += 1;
-                        // Check against the pre-computed quorum
-                        if p.acks >= quorum {
-                            // Take ownership of the Pending so we drop the &mut borrow
-                            let Pending {
-                                client,
-                                client_msg_id,
-                                ..
-                            } = self.pendings.remove(&offset).unwrap();
-                            // Now safe to immutably borrow `self` to build the response
-                            let reply_msg_id = node.next_msg_id();
-                            out.push(node.reply(
-                                client,
-                                MessageBody::SendOk {
-                                    msg_id: reply_msg_id,
-                                    in_reply_to: client_msg_id,
-                                    offset,
-                                },
-                            ));
-                        }
+        })]
+    }
+
+    fn on_tombstone(
+        &mut self,
+        node: &mut Node,
+        _src: String,
+        _msg_id: u64,
+        key: String,
+        offset: u64,
+    ) -> Vec<Message> {
+        // Applied even when writing it ahead fails; the framework's `ack` is answer enough
+        if let Err(err) = self.logs().insert_tombstone(&key, offset) {
+            node.log(&format!(
+                "tombstone for {key} offset {offset} not written: {err}"
+            ));
+        }
+        Vec::new()
+    }
+
+    fn on_replicate_ok(
+        &mut self,
+        node: &mut Node,
+        src: String,
+        _msg_id: u64,
+        _in_reply_to: u64,
+        key: String,
+        offset: u64,
+    ) -> Vec<Message> {
+        let mut out = Vec::new();
+        // Grab quorum once, before get_mut()
+        let quorum = self.quorum(node);
+        // Mutably borrow the pending entry and bump acks only on first ack from this src
+        if let Some(p) = self
+            .pendings
+            .get_mut(&key)
+            .and_then(|pendings| pendings.get_mut(&offset))
+            && p.from.insert(src)
+        {
+            p.acks += 1;
+            // Check against the pre-computed quorum
+            if p.acks >= quorum {
+                // Take ownership of the Pending so we drop the &mut borrow
+                let Pending {
+                    client,
+                    client_msg_id,
+                    ..
+                } = self.take_pending(&key, offset).unwrap();
+                self.advance_hwm(&key);
+                // Now safe to immutably borrow `self` to build the response
+                out.push(node.answer(client, client_msg_id, |msg_id, in_reply_to| {
+                    MessageBody::SendOk {
+                        msg_id,
+                        in_reply_to,
+                        offset,
                     }
-                }
+                }));
             }
-            MessageBody::Poll { msg_id, offsets } => {
-                let msgs = self.logs.poll(&offsets);
-                let reply_msg_id = node.next_msg_id();
-                out.push(node.reply(
-                    message.src,
-                    MessageBody::PollOk {
-                        msg_id: reply_msg_id,
-                        in_reply_to: msg_id,
-                        msgs,
-                    },
-                ))
-            }
-            MessageBody::CommitOffsets { msg_id, offsets } => {
-                self.logs.commit_offsets(offsets);
-                let reply_msg_id = node.next_msg_id();
-                out.push(node.reply(
-                    message.src,
-                    MessageBody::CommitOffsetsOk {
-                        msg_id: reply_msg_id,
-                        in_reply_to: msg_id,
-                    },
-                ))
+        }
+        out
+    }
+
+    fn on_poll(
+        &mut self,
+        node: &mut Node,
+        src: String,
+        msg_id: u64,
+        offsets: HashMap<String, u64>,
+    ) -> Vec<Message> {
+        if self.asks_leader(node) {
+            let poll = MessageBody::Poll { msg_id: 0, offsets };
+            return self.ask_leader(node, src, msg_id, poll);
+        }
+        let polled = self.logs().poll(&offsets);
+        let polled = match polled {
+            Ok(polled) => polled,
+            Err(corruption) => {
+                node.log(&corruption.to_string());
+                return vec![MaelstromError::from(corruption).reply(node, src, msg_id)];
             }
-            MessageBody::ListCommittedOffsets { msg_id, keys } => {
-                let offsets = self.logs.list_committed_offsets(&keys);
-                let reply_msg_id = node.next_msg_id();
-                out.push(node.reply(
-                    message.src,
-                    MessageBody::ListCommittedOffsetsOk {
-                        msg_id: reply_msg_id,
-                        in_reply_to: msg_id,
-                        offsets,
-                    },
-                ))
+        };
+        let has_more = polled.has_more();
+        vec![
+            node.answer(src, msg_id, |msg_id, in_reply_to| MessageBody::PollOk {
+                msg_id,
+                in_reply_to,
+                msgs: polled.msgs,
+                next_offsets: polled.next_offsets,
+                has_more,
+            }),
+        ]
+    }
+
+    fn on_commit_offsets(
+        &mut self,
+        node: &mut Node,
+        src: String,
+        msg_id: u64,
+        offsets: HashMap<String, u64>,
+        consumer: Option<String>,
+    ) -> Vec<Message> {
+        // A follower's log may lag the offsets the leader served, so only the leader checks
+        if self.asks_leader(node) {
+            let commit = MessageBody::CommitOffsets {
+                msg_id: 0,
+                offsets,
+                consumer: Some(consumer.unwrap_or_else(|| src.clone())),
+            };
+            return self.ask_leader(node, src, msg_id, commit);
+        }
+        let consumer = consumer.unwrap_or_else(|| src.clone());
+        let committed = self.logs().commit_offsets(&consumer, offsets);
+        if let Err(err) = committed {
+            return vec![MaelstromError::from(err).reply(node, src, msg_id)];
+        }
+        vec![node.answer(src, msg_id, |msg_id, in_reply_to| {
+            MessageBody::CommitOffsetsOk {
+                msg_id,
+                in_reply_to,
             }
-            _ => {}
+        })]
+    }
+
+    fn on_list_committed_offsets(
+        &mut self,
+        node: &mut Node,
+        src: String,
+        msg_id: u64,
+        keys: Vec<String>,
+        consumer: Option<String>,
+    ) -> Vec<Message> {
+        // Commits are kept on the leader, so a follower asks it for them
+        if self.asks_leader(node) {
+            let list = MessageBody::ListCommittedOffsets {
+                msg_id: 0,
+                keys,
+                consumer,
+            };
+            return self.ask_leader(node, src, msg_id, list);
         }
-        out
+        let offsets = match consumer {
+            Some(consumer) => self.logs().committed_offsets_of(&consumer, &keys),
+            None => self.logs().list_committed_offsets(&keys),
+        };
+        vec![node.answer(src, msg_id, |msg_id, in_reply_to| {
+            MessageBody::ListCommittedOffsetsOk {
+                msg_id,
+                in_reply_to,
+                offsets,
+            }
+        })]
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use maelstrom::{Deferred, ErrorCode, NodeConfig, poll_calls, rpc::RetryPolicy, sim::Sim};
+    use maelstrom_testkit::{self as testkit, expect_body};
     use std::collections::{HashMap, HashSet};
+    use std::time::{Duration, Instant};
 
     #[test]
     fn test_kafka_node_handles_init_message() {
         let mut handler = KafkaNode::new();
         let mut node = Node::new();
 
-        let init_message = Message {
-            src: "c1".to_string(),
-            dest: "n2".to_string(),
-            body: MessageBody::Init {
-                msg_id: 1,
-                node_id: "n2".to_string(),
-                node_ids: vec!["n1".to_string(), "n2".to_string(), "n3".to_string()],
-            },
-        };
+        let init_message = testkit::init("n2", ["n1", "n2", "n3"]);
 
         let responses = handler.handle(&mut node, init_message);
 
-        assert_eq!(responses.len(), 1);
-        assert_eq!(responses[0].src, "n2");
-        assert_eq!(responses[0].dest, "c1");
+        testkit::assert_route(testkit::only(&responses), "n2", "c1");
 
-        match &responses[0].body {
-            MessageBody::InitOk {
-                msg_id: _,
-                in_reply_to,
-            } => {
-                assert_eq!(*in_reply_to, 1);
-            }
-            _ => panic!("Expected InitOk message"),
-        }
+        let in_reply_to = expect_body!(&responses[0].body, InitOk { in_reply_to, .. });
+        assert_eq!(*in_reply_to, 1);
 
         // Verify node state was updated
         assert_eq!(node.id, "n2");
@@ -345,39 +773,137 @@ mod tests {
         // Initialize as leader in single-node cluster
         handler.handle_init(&mut node, "n1".to_string(), vec!["n1".to_string()]);
 
-        let send_message = Message {
-            src: "c1".to_string(),
-            dest: "n1".to_string(),
-            body: MessageBody::Send {
-                msg_id: 42,
-                key: "k1".to_string(),
-                msg: 123,
-            },
-        };
+        let send_message = testkit::send("c1", "k1", 123).with_msg_id(42);
 
         let responses = handler.handle(&mut node, send_message);
 
         // Single node cluster should respond immediately (quorum = 1)
-        assert_eq!(responses.len(), 1);
-        assert_eq!(responses[0].src, "n1");
-        assert_eq!(responses[0].dest, "c1");
+        testkit::assert_route(testkit::only(&responses), "n1", "c1");
 
-        match &responses[0].body {
-            MessageBody::SendOk {
-                msg_id: _,
+        let (in_reply_to, offset) = expect_body!(
+            &responses[0].body,
+            SendOk {
                 in_reply_to,
                 offset,
-            } => {
-                assert_eq!(*in_reply_to, 42);
-                assert_eq!(*offset, 0);
+                ..
             }
-            _ => panic!("Expected SendOk message"),
-        }
+        );
+        assert_eq!(*in_reply_to, 42);
+        assert_eq!(*offset, 0);
 
         // No pending operations should remain
         assert_eq!(handler.pendings.len(), 0);
     }
 
+    #[test]
+    fn test_acknowledged_sends_survive_a_restart_with_a_data_dir() {
+        let dir = std::env::temp_dir().join(format!("kafka-data-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let config = NodeConfig::new().data_dir(&dir);
+
+        let mut handler = KafkaNode::new();
+        let mut node = Node::with_config(config.clone());
+        handler.handle(&mut node, testkit::init("n1", ["n1"]));
+        for msg_id in 1..=2 {
+            let responses = handler.handle(
+                &mut node,
+                testkit::send("c1", "k1", msg_id).with_msg_id(msg_id),
+            );
+            expect_body!(&responses[0].body, SendOk { .. });
+        }
+        handler.handle(
+            &mut node,
+            testkit::commit_offsets([("k1", 1)]).with_msg_id(3),
+        );
+
+        // A fresh process
+        let mut handler = KafkaNode::new();
+        let mut node = Node::with_config(config);
+        handler.handle(&mut node, testkit::init("n1", ["n1"]));
+        let responses = handler.handle(&mut node, testkit::poll([("k1", 0)]).with_msg_id(4));
+        let msgs = expect_body!(&responses[0].body, PollOk { msgs, .. });
+        assert_eq!(msgs["k1"], vec![(0, 1), (1, 2)]);
+        let committed = handler.logs().list_committed_offsets(&["k1".to_string()]);
+        assert_eq!(committed["k1"], 1);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// What lin-kv answers `request` with
+    fn from_lin_kv(request: &Message, mut body: MessageBody) -> Message {
+        if let MessageBody::Error { in_reply_to, .. } | MessageBody::CasOk { in_reply_to, .. } =
+            &mut body
+        {
+            *in_reply_to = request.body.msg_id().unwrap();
+        }
+        Message {
+            src: "lin-kv".to_string(),
+            dest: request.src.clone(),
+            body,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_leaderless_sends_claim_offsets_from_lin_kv() {
+        let (deferred, mut written) = Deferred::channel();
+        let mut handler = KafkaNode::new().leaderless();
+        let mut node = testkit::node("n2", ["n1", "n2"]);
+        node.attach_deferred(deferred);
+        handler.on_node_init(&node);
+
+        // Not the leader, yet it takes the send itself
+        assert!(
+            handler
+                .handle(&mut node, testkit::send("c1", "k1", 7))
+                .is_empty()
+        );
+        let read = written.recv().await.unwrap().messages.remove(0);
+        assert_eq!(read.dest, "lin-kv");
+        let missing = MessageBody::Error {
+            msg_id: 1,
+            in_reply_to: 0,
+            code: ErrorCode::KeyDoesNotExist,
+            text: None,
+            extra: None,
+        };
+        assert!(
+            handler
+                .handle(&mut node, from_lin_kv(&read, missing))
+                .is_empty()
+        );
+
+        let cas = written.recv().await.unwrap().messages.remove(0);
+        assert!(matches!(
+            &cas.body,
+            MessageBody::Cas { key, from: 0, to: 1, create_if_not_exists: true, .. }
+                if key == "offset/k1"
+        ));
+        let cas_ok = MessageBody::CasOk {
+            msg_id: 2,
+            in_reply_to: 0,
+        };
+        assert!(
+            handler
+                .handle(&mut node, from_lin_kv(&cas, cas_ok))
+                .is_empty()
+        );
+
+        // The replicate is delivered, so retransmitted until n1 acknowledges it
+        let replicates = written.recv().await.unwrap();
+        assert!(replicates.deliver);
+        let replicate = testkit::only(&replicates.messages);
+        testkit::assert_route(replicate, "n2", "n1");
+        assert_eq!(expect_body!(&replicate.body, Replicate { offset, .. }), &0);
+        let out = written.recv().await.unwrap();
+        assert!(!out.deliver);
+        testkit::assert_route(testkit::only(&out.messages), "n2", "c1");
+        assert_eq!(
+            expect_body!(&out.messages[0].body, SendOk { offset, .. }),
+            &0
+        );
+        let polled = handler.logs().poll(&HashMap::from([("k1".to_string(), 0)]));
+        assert_eq!(polled.unwrap().msgs["k1"], vec![(0, 7)]);
+    }
+
     #[test]
     fn test_leader_handles_send_message_multi_node() {
         let mut handler = KafkaNode::new();
@@ -390,15 +916,7 @@ mod tests {
             vec!["n1".to_string(), "n2".to_string(), "n3".to_string()],
         );
 
-        let send_message = Message {
-            src: "c1".to_string(),
-            dest: "n1".to_string(),
-            body: MessageBody::Send {
-                msg_id: 42,
-                key: "k1".to_string(),
-                msg: 123,
-            },
-        };
+        let send_message = testkit::send("c1", "k1", 123).with_msg_id(42);
 
         let responses = handler.handle(&mut node, send_message);
 
@@ -409,29 +927,78 @@ mod tests {
         for response in responses.iter() {
             assert_eq!(response.src, "n1");
             assert!(response.dest == "n2" || response.dest == "n3");
-            match &response.body {
-                MessageBody::Replicate {
-                    msg_id: _,
+            let (key, msg, offset) = expect_body!(
+                &response.body,
+                Replicate {
                     key,
                     msg,
                     offset,
-                } => {
-                    assert_eq!(key, "k1");
-                    assert_eq!(*msg, 123);
-                    assert_eq!(*offset, 0);
+                    ..
                 }
-                _ => panic!("Expected Replicate message"),
-            }
+            );
+            assert_eq!(key, "k1");
+            assert_eq!(*msg, 123);
+            assert_eq!(*offset, 0);
         }
 
         // Should have pending operation
         assert_eq!(handler.pendings.len(), 1);
-        let pending = handler.pendings.get(&0).unwrap();
+        let pending = &handler.pendings["k1"][&0];
         assert_eq!(pending.client, "c1");
         assert_eq!(pending.client_msg_id, 42);
         assert_eq!(pending.acks, 1);
     }
 
+    #[test]
+    fn test_polls_on_the_leader_see_only_quorum_replicated_sends() {
+        let mut handler = KafkaNode::new();
+        let mut node = Node::new();
+        handler.handle_init(
+            &mut node,
+            "n1".to_string(),
+            vec!["n1".to_string(), "n2".to_string(), "n3".to_string()],
+        );
+        handler.handle(&mut node, testkit::send("c1", "k1", 10).with_msg_id(1));
+        handler.handle(&mut node, testkit::send("c1", "k1", 11).with_msg_id(2));
+        let replicate_ok = |offset| Message {
+            src: "n2".to_string(),
+            dest: "n1".to_string(),
+            body: MessageBody::ReplicateOk {
+                msg_id: 7,
+                in_reply_to: 1,
+                key: "k1".to_string(),
+                offset,
+            },
+        };
+        let poll = |handler: &mut KafkaNode, node: &mut Node| {
+            let responses = handler.handle(node, testkit::poll([("k1", 0)]).with_msg_id(9));
+            expect_body!(&responses[0].body, PollOk { msgs, .. })["k1"].clone()
+        };
+
+        assert_eq!(poll(&mut handler, &mut node), vec![]);
+        // The later send has a quorum first, but stays hidden behind the earlier one
+        handler.handle(&mut node, replicate_ok(1));
+        assert_eq!(poll(&mut handler, &mut node), vec![]);
+        assert_eq!(handler.logs().hwm("k1"), 0);
+        handler.handle(&mut node, replicate_ok(0));
+        assert_eq!(poll(&mut handler, &mut node), vec![(0, 10), (1, 11)]);
+        assert_eq!(handler.logs().hwm("k1"), 2);
+    }
+
+    #[test]
+    fn test_unknown_keys_can_be_polled_back_empty() {
+        let mut handler = KafkaNode::new().unknown_keys(UnknownKeys::Empty);
+        let mut node = Node::new();
+        handler.handle_init(
+            &mut node,
+            "n1".to_string(),
+            vec!["n1".to_string(), "n2".to_string()],
+        );
+        let responses = handler.handle(&mut node, testkit::poll([("k1", 0)]));
+        let msgs = expect_body!(&responses[0].body, PollOk { msgs, .. });
+        assert_eq!(msgs["k1"], vec![]);
+    }
+
     #[test]
     fn test_non_leader_forwards_send_message() {
         let mut handler = KafkaNode::new();
@@ -444,38 +1011,27 @@ mod tests {
             vec!["n1".to_string(), "n2".to_string(), "n3".to_string()],
         );
 
-        let send_message = Message {
-            src: "c1".to_string(),
-            dest: "n2".to_string(),
-            body: MessageBody::Send {
-                msg_id: 42,
-                key: "k1".to_string(),
-                msg: 123,
-            },
-        };
+        let send_message = testkit::send("c1", "k1", 123).dest("n2").with_msg_id(42);
 
         let responses = handler.handle(&mut node, send_message);
 
         // Should forward to leader
-        assert_eq!(responses.len(), 1);
-        assert_eq!(responses[0].src, "n2");
-        assert_eq!(responses[0].dest, "n1"); // Leader
+        testkit::assert_route(testkit::only(&responses), "n2", "n1"); // Leader
 
-        match &responses[0].body {
-            MessageBody::ForwardSend {
-                msg_id: _,
+        let (orig_src, orig_msg_id, key, msg) = expect_body!(
+            &responses[0].body,
+            ForwardSend {
                 orig_src,
                 orig_msg_id,
                 key,
                 msg,
-            } => {
-                assert_eq!(orig_src, "c1");
-                assert_eq!(*orig_msg_id, 42);
-                assert_eq!(key, "k1");
-                assert_eq!(*msg, 123);
+                ..
             }
-            _ => panic!("Expected ForwardSend message"),
-        }
+        );
+        assert_eq!(orig_src, "c1");
+        assert_eq!(*orig_msg_id, 42);
+        assert_eq!(key, "k1");
+        assert_eq!(*msg, 123);
     }
 
     #[test]
@@ -494,7 +1050,7 @@ mod tests {
             src: "n2".to_string(),
             dest: "n1".to_string(),
             body: MessageBody::ForwardSend {
-                msg_id: 10,
+                msg_id: Some(10),
                 orig_src: "c1".to_string(),
                 orig_msg_id: 42,
                 key: "k1".to_string(),
@@ -511,24 +1067,23 @@ mod tests {
         for response in responses.iter() {
             assert_eq!(response.src, "n1");
             assert!(response.dest == "n2" || response.dest == "n3");
-            match &response.body {
-                MessageBody::Replicate {
-                    msg_id: _,
+            let (key, msg, offset) = expect_body!(
+                &response.body,
+                Replicate {
                     key,
                     msg,
                     offset,
-                } => {
-                    assert_eq!(key, "k1");
-                    assert_eq!(*msg, 123);
-                    assert_eq!(*offset, 0);
+                    ..
                 }
-                _ => panic!("Expected Replicate message"),
-            }
+            );
+            assert_eq!(key, "k1");
+            assert_eq!(*msg, 123);
+            assert_eq!(*offset, 0);
         }
 
         // Should have pending operation with original client info
         assert_eq!(handler.pendings.len(), 1);
-        let pending = handler.pendings.get(&0).unwrap();
+        let pending = &handler.pendings["k1"][&0];
         assert_eq!(pending.client, "c1");
         assert_eq!(pending.client_msg_id, 42);
         assert_eq!(pending.acks, 1);
@@ -559,21 +1114,131 @@ mod tests {
 
         let responses = handler.handle(&mut node, replicate_message);
 
-        assert_eq!(responses.len(), 1);
-        assert_eq!(responses[0].src, "n2");
-        assert_eq!(responses[0].dest, "n1");
+        testkit::assert_route(testkit::only(&responses), "n2", "n1");
 
-        match &responses[0].body {
-            MessageBody::ReplicateOk {
-                msg_id: _,
+        let (in_reply_to, offset) = expect_body!(
+            &responses[0].body,
+            ReplicateOk {
                 in_reply_to,
                 offset,
-            } => {
-                assert_eq!(*in_reply_to, 10);
-                assert_eq!(*offset, 5);
+                ..
             }
-            _ => panic!("Expected ReplicateOk message"),
-        }
+        );
+        assert_eq!(*in_reply_to, 10);
+        assert_eq!(*offset, 5);
+    }
+
+    #[test]
+    fn test_each_log_keeps_its_own_high_watermark() {
+        let mut handler = KafkaNode::new();
+        let mut node = Node::new();
+        handler.handle_init(
+            &mut node,
+            "n1".to_string(),
+            vec!["n1".to_string(), "n2".to_string(), "n3".to_string()],
+        );
+        handler.handle(&mut node, testkit::send("c1", "k1", 10).with_msg_id(1));
+        handler.handle(&mut node, testkit::send("c1", "k2", 20).with_msg_id(2));
+        handler.handle(&mut node, testkit::send("c1", "k2", 21).with_msg_id(3));
+        let hwms = |handler: &KafkaNode| {
+            let logs = handler.logs();
+            (logs.hwm("k1"), logs.hwm("k2"))
+        };
+        assert_eq!(hwms(&handler), (0, 0));
+
+        let replicate_ok = |key: &str, offset| Message {
+            src: "n2".to_string(),
+            dest: "n1".to_string(),
+            body: MessageBody::ReplicateOk {
+                msg_id: 7,
+                in_reply_to: 1,
+                key: key.to_string(),
+                offset,
+            },
+        };
+        // k2's offset 0 reaching a quorum moves only k2, past it to its own pending offset 1
+        handler.handle(&mut node, replicate_ok("k2", 0));
+        assert_eq!(hwms(&handler), (0, 1));
+        handler.handle(&mut node, replicate_ok("k1", 0));
+        assert_eq!(hwms(&handler), (1, 1));
+        handler.handle(&mut node, replicate_ok("k2", 1));
+        assert_eq!(hwms(&handler), (1, 2));
+    }
+
+    #[test]
+    fn test_followers_hold_replicas_back_until_the_gap_before_them_fills() {
+        let mut handler = KafkaNode::new();
+        let mut node = Node::new();
+        handler.handle_init(
+            &mut node,
+            "n2".to_string(),
+            vec!["n1".to_string(), "n2".to_string(), "n3".to_string()],
+        );
+        let replicate = |offset| Message {
+            src: "n1".to_string(),
+            dest: "n2".to_string(),
+            body: MessageBody::Replicate {
+                msg_id: 10 + offset,
+                key: "k1".to_string(),
+                msg: 100 + offset,
+                offset,
+            },
+        };
+        // Polls sent to a follower go to the leader, so read its own log
+        let poll = |handler: &mut KafkaNode, _node: &mut Node| {
+            let offsets = HashMap::from([("k1".to_string(), 0)]);
+            let polled = handler.logs().poll(&offsets).unwrap();
+            polled.msgs.get("k1").cloned().unwrap_or_default()
+        };
+
+        // Acknowledged even while staged
+        let responses = handler.handle(&mut node, replicate(1));
+        expect_body!(&responses[0].body, ReplicateOk { .. });
+        assert_eq!(poll(&mut handler, &mut node), vec![]);
+        assert_eq!(handler.logs().missing("k1"), vec![0..1]);
+
+        handler.handle(&mut node, replicate(0));
+        assert_eq!(poll(&mut handler, &mut node), vec![(0, 100), (1, 101)]);
+    }
+
+    #[test]
+    fn test_leaderless_tombstones_release_what_is_staged_past_them() {
+        let mut handler = KafkaNode::new().leaderless();
+        let mut node = testkit::node("n2", ["n1", "n2"]);
+        let from_n1 = |body| Message {
+            src: "n1".to_string(),
+            dest: "n2".to_string(),
+            body,
+        };
+        // Leaderless nodes answer polls from their own log
+        let poll = |handler: &mut KafkaNode, node: &mut Node| {
+            let responses = handler.handle(node, testkit::poll([("k1", 0)]));
+            let msgs = expect_body!(&responses[0].body, PollOk { msgs, .. });
+            msgs.get("k1")
+                .map(|entries| entries.to_vec())
+                .unwrap_or_default()
+        };
+
+        handler.handle(
+            &mut node,
+            from_n1(MessageBody::Replicate {
+                msg_id: 1,
+                key: "k1".to_string(),
+                msg: 101,
+                offset: 1,
+            }),
+        );
+        // Offset 0 may still be on its way, so polls do not skip it
+        assert_eq!(poll(&mut handler, &mut node), vec![]);
+
+        // n1 claimed offset 0 but could not write it
+        let tombstone = MessageBody::Tombstone {
+            msg_id: 2,
+            key: "k1".to_string(),
+            offset: 0,
+        };
+        assert!(handler.handle(&mut node, from_n1(tombstone)).is_empty());
+        assert_eq!(poll(&mut handler, &mut node), vec![(1, 101)]);
     }
 
     #[test]
@@ -589,15 +1254,20 @@ mod tests {
         );
 
         // Simulate a pending operation (normally created by handle_send)
-        handler.pendings.insert(
-            0,
-            Pending {
-                client: "c1".to_string(),
-                client_msg_id: 42,
-                acks: 1, // Leader already counted as 1 ack
-                from: HashSet::from([node.id.clone()]),
-            },
-        );
+        handler
+            .pendings
+            .entry("k1".to_string())
+            .or_default()
+            .insert(
+                0,
+                Pending {
+                    client: "c1".to_string(),
+                    client_msg_id: 42,
+                    acks: 1, // Leader already counted as 1 ack
+                    from: HashSet::from([node.id.clone()]),
+                    failed: 0,
+                },
+            );
 
         // First ReplicateOk - should reach quorum (2 out of 3)
         let replicate_ok1 = Message {
@@ -606,6 +1276,7 @@ mod tests {
             body: MessageBody::ReplicateOk {
                 msg_id: 11,
                 in_reply_to: 10,
+                key: "k1".to_string(),
                 offset: 0,
             },
         };
@@ -613,21 +1284,18 @@ mod tests {
         let responses = handler.handle(&mut node, replicate_ok1);
 
         // Should respond to client now that quorum is reached
-        assert_eq!(responses.len(), 1);
-        assert_eq!(responses[0].src, "n1");
-        assert_eq!(responses[0].dest, "c1");
+        testkit::assert_route(testkit::only(&responses), "n1", "c1");
 
-        match &responses[0].body {
-            MessageBody::SendOk {
-                msg_id: _,
+        let (in_reply_to, offset) = expect_body!(
+            &responses[0].body,
+            SendOk {
                 in_reply_to,
                 offset,
-            } => {
-                assert_eq!(*in_reply_to, 42);
-                assert_eq!(*offset, 0);
+                ..
             }
-            _ => panic!("Expected SendOk message"),
-        }
+        );
+        assert_eq!(*in_reply_to, 42);
+        assert_eq!(*offset, 0);
 
         // Pending operation should be removed
         assert_eq!(handler.pendings.len(), 0);
@@ -652,15 +1320,20 @@ mod tests {
         );
 
         // Simulate a pending operation
-        handler.pendings.insert(
-            0,
-            Pending {
-                client: "c1".to_string(),
-                client_msg_id: 42,
-                acks: 1, // Leader already counted as 1 ack
-                from: HashSet::from([node.id.clone()]),
-            },
-        );
+        handler
+            .pendings
+            .entry("k1".to_string())
+            .or_default()
+            .insert(
+                0,
+                Pending {
+                    client: "c1".to_string(),
+                    client_msg_id: 42,
+                    acks: 1, // Leader already counted as 1 ack
+                    from: HashSet::from([node.id.clone()]),
+                    failed: 0,
+                },
+            );
 
         // First ReplicateOk - not enough for quorum yet
         let replicate_ok1 = Message {
@@ -669,6 +1342,7 @@ mod tests {
             body: MessageBody::ReplicateOk {
                 msg_id: 11,
                 in_reply_to: 10,
+                key: "k1".to_string(),
                 offset: 0,
             },
         };
@@ -680,7 +1354,7 @@ mod tests {
 
         // Pending operation should still exist with incremented acks
         assert_eq!(handler.pendings.len(), 1);
-        let pending = handler.pendings.get(&0).unwrap();
+        let pending = &handler.pendings["k1"][&0];
         assert_eq!(pending.acks, 2);
     }
 
@@ -693,9 +1367,9 @@ mod tests {
         handler.handle_init(&mut node, "n1".to_string(), vec!["n1".to_string()]);
 
         // Add some data first
-        handler.logs.insert_at("k1", 0, 123);
-        handler.logs.insert_at("k1", 1, 456);
-        handler.logs.insert_at("k2", 0, 789);
+        handler.logs().insert_at("k1", 0, 123).unwrap();
+        handler.logs().insert_at("k1", 1, 456).unwrap();
+        handler.logs().insert_at("k2", 0, 789).unwrap();
 
         let mut poll_offsets = HashMap::new();
         poll_offsets.insert("k1".to_string(), 0);
@@ -712,22 +1386,19 @@ mod tests {
 
         let responses = handler.handle(&mut node, poll_message);
 
-        assert_eq!(responses.len(), 1);
-        assert_eq!(responses[0].src, "n1");
-        assert_eq!(responses[0].dest, "c1");
+        testkit::assert_route(testkit::only(&responses), "n1", "c1");
 
-        match &responses[0].body {
-            MessageBody::PollOk {
-                msg_id: _,
+        let (in_reply_to, msgs) = expect_body!(
+            &responses[0].body,
+            PollOk {
                 in_reply_to,
                 msgs,
-            } => {
-                assert_eq!(*in_reply_to, 10);
-                assert!(msgs.contains_key("k1"));
-                assert!(msgs.contains_key("k2"));
+                ..
             }
-            _ => panic!("Expected PollOk message"),
-        }
+        );
+        assert_eq!(*in_reply_to, 10);
+        assert!(msgs.contains_key("k1"));
+        assert!(msgs.contains_key("k2"));
     }
 
     #[test]
@@ -748,73 +1419,55 @@ mod tests {
             body: MessageBody::CommitOffsets {
                 msg_id: 42,
                 offsets: commit_offsets,
+                consumer: None,
             },
         };
 
         let responses = handler.handle(&mut node, commit_message);
 
-        assert_eq!(responses.len(), 1);
-        assert_eq!(responses[0].src, "n1");
-        assert_eq!(responses[0].dest, "c1");
+        testkit::assert_route(testkit::only(&responses), "n1", "c1");
 
-        match &responses[0].body {
-            MessageBody::CommitOffsetsOk {
-                msg_id: _,
-                in_reply_to,
-            } => {
-                assert_eq!(*in_reply_to, 42);
-            }
-            _ => panic!("Expected CommitOffsetsOk message"),
-        }
+        let in_reply_to = expect_body!(&responses[0].body, CommitOffsetsOk { in_reply_to, .. });
+        assert_eq!(*in_reply_to, 42);
     }
 
     #[test]
     fn test_handles_list_committed_offsets_message() {
-        let mut handler = KafkaNode::new();
+        let mut handler = KafkaNode::new().lenient_commits();
         let mut node = Node::new();
 
         // Initialize node
         handler.handle_init(&mut node, "n1".to_string(), vec!["n1".to_string()]);
 
         // Add some data first to create the logs
-        handler.logs.insert_at("k1", 0, 123);
-        handler.logs.insert_at("k2", 0, 456);
+        handler.logs().insert_at("k1", 0, 123).unwrap();
+        handler.logs().insert_at("k2", 0, 456).unwrap();
 
         // First commit some offsets
         let mut commit_offsets = HashMap::new();
         commit_offsets.insert("k1".to_string(), 100);
         commit_offsets.insert("k2".to_string(), 200);
-        handler.logs.commit_offsets(commit_offsets);
+        handler.logs().commit_offsets("c1", commit_offsets).unwrap();
 
-        let list_message = Message {
-            src: "c1".to_string(),
-            dest: "n1".to_string(),
-            body: MessageBody::ListCommittedOffsets {
-                msg_id: 10,
-                keys: vec!["k1".to_string(), "k2".to_string(), "k3".to_string()],
-            },
-        };
+        let list_message = testkit::list_committed_offsets(["k1", "k2", "k3"]).with_msg_id(10);
 
         let responses = handler.handle(&mut node, list_message);
 
-        assert_eq!(responses.len(), 1);
-        assert_eq!(responses[0].src, "n1");
-        assert_eq!(responses[0].dest, "c1");
+        testkit::assert_route(testkit::only(&responses), "n1", "c1");
 
-        match &responses[0].body {
-            MessageBody::ListCommittedOffsetsOk {
-                msg_id: _,
+        let (in_reply_to, offsets) = expect_body!(
+            &responses[0].body,
+            ListCommittedOffsetsOk {
                 in_reply_to,
                 offsets,
-            } => {
-                assert_eq!(*in_reply_to, 10);
-                // Check that we get the committed offsets back, or defaults
-                assert!(offsets.contains_key("k1"));
-                assert!(offsets.contains_key("k2"));
-                // k3 might not be present since it wasn't used
+                ..
             }
-            _ => panic!("Expected ListCommittedOffsetsOk message"),
-        }
+        );
+        assert_eq!(*in_reply_to, 10);
+        // Check that we get the committed offsets back, or defaults
+        assert!(offsets.contains_key("k1"));
+        assert!(offsets.contains_key("k2"));
+        // k3 might not be present since it wasn't used
     }
 
     #[test]
@@ -825,7 +1478,11 @@ mod tests {
         let unknown_message = Message {
             src: "c1".to_string(),
             dest: "n1".to_string(),
-            body: MessageBody::Generate { msg_id: 1 },
+            body: MessageBody::SendOk {
+                msg_id: 1,
+                in_reply_to: 1,
+                offset: 0,
+            },
         };
 
         let responses = handler.handle(&mut node, unknown_message);
@@ -860,15 +1517,7 @@ mod tests {
         );
 
         // Client sends message to non-leader
-        let client_send = Message {
-            src: "c1".to_string(),
-            dest: "n2".to_string(),
-            body: MessageBody::Send {
-                msg_id: 1,
-                key: "test-key".to_string(),
-                msg: 42,
-            },
-        };
+        let client_send = testkit::send("c1", "test-key", 42).dest("n2");
 
         // Follower forwards to leader
         let forward_responses = follower1.handle(&mut follower1_node, client_send);
@@ -882,10 +1531,7 @@ mod tests {
         assert_eq!(leader_responses.len(), 2); // Two replication messages
 
         // Extract the msg_id from one of the replication messages
-        let replicate_msg_id = match &leader_responses[0].body {
-            MessageBody::Replicate { msg_id, .. } => *msg_id,
-            _ => panic!("Expected Replicate message"),
-        };
+        let replicate_msg_id = *expect_body!(&leader_responses[0].body, Replicate { msg_id, .. });
 
         // Simulate one follower acknowledging replication
         let replicate_ok = Message {
@@ -894,6 +1540,7 @@ mod tests {
             body: MessageBody::ReplicateOk {
                 msg_id: 100,
                 in_reply_to: replicate_msg_id,
+                key: "test-key".to_string(),
                 offset: 0,
             },
         };
@@ -903,17 +1550,48 @@ mod tests {
         // Should get client response once quorum is reached
         assert_eq!(final_responses.len(), 1);
         assert_eq!(final_responses[0].dest, "c1");
-        match &final_responses[0].body {
-            MessageBody::SendOk {
+        let (in_reply_to, offset) = expect_body!(
+            &final_responses[0].body,
+            SendOk {
                 in_reply_to,
                 offset,
                 ..
-            } => {
-                assert_eq!(*in_reply_to, 1);
-                assert_eq!(*offset, 0);
             }
-            _ => panic!("Expected SendOk message"),
+        );
+        assert_eq!(*in_reply_to, 1);
+        assert_eq!(*offset, 0);
+    }
+
+    #[test]
+    fn test_sends_to_different_keys_at_the_same_offset_are_tracked_apart() {
+        let mut handler = KafkaNode::new();
+        let mut node = Node::new();
+        handler.handle_init(
+            &mut node,
+            "n1".to_string(),
+            vec!["n1".to_string(), "n2".to_string(), "n3".to_string()],
+        );
+        handler.handle(&mut node, testkit::send("c1", "k1", 10).with_msg_id(1));
+        handler.handle(&mut node, testkit::send("c2", "k2", 20).with_msg_id(2));
+        assert_eq!(handler.pendings["k1"][&0].client, "c1");
+        assert_eq!(handler.pendings["k2"][&0].client, "c2");
+
+        let replicate_ok = |key: &str| Message {
+            src: "n2".to_string(),
+            dest: "n1".to_string(),
+            body: MessageBody::ReplicateOk {
+                msg_id: 7,
+                in_reply_to: 1,
+                key: key.to_string(),
+                offset: 0,
+            },
+        };
+        for (key, client) in [("k2", "c2"), ("k1", "c1")] {
+            let responses = handler.handle(&mut node, replicate_ok(key));
+            testkit::assert_route(testkit::only(&responses), "n1", client);
+            expect_body!(&responses[0].body, SendOk { .. });
         }
+        assert!(handler.pendings.is_empty());
     }
 
     #[test]
@@ -929,15 +1607,7 @@ mod tests {
         );
 
         // Send message to create pending operation
-        let send_message = Message {
-            src: "c1".to_string(),
-            dest: "n1".to_string(),
-            body: MessageBody::Send {
-                msg_id: 42,
-                key: "k1".to_string(),
-                msg: 123,
-            },
-        };
+        let send_message = testkit::send("c1", "k1", 123).with_msg_id(42);
 
         handler.handle(&mut node, send_message);
         assert_eq!(handler.pendings.len(), 1);
@@ -949,6 +1619,7 @@ mod tests {
             body: MessageBody::ReplicateOk {
                 msg_id: 11,
                 in_reply_to: 10,
+                key: "k1".to_string(),
                 offset: 0,
             },
         };
@@ -958,4 +1629,300 @@ mod tests {
         // Pending operation should be cleaned up after reaching quorum
         assert_eq!(handler.pendings.len(), 0);
     }
+
+    #[test]
+    fn test_replicate_is_tracked_as_rpc() {
+        let mut handler = KafkaNode::new();
+        let mut node = Node::new();
+        handler.handle_init(
+            &mut node,
+            "n1".to_string(),
+            vec!["n1".to_string(), "n2".to_string(), "n3".to_string()],
+        );
+
+        let out = handler.handle_send(&mut node, "c1".to_string(), 5, "k1".to_string(), 9);
+        assert_eq!(out.len(), 2);
+        assert_eq!(node.calls.len(), 2);
+
+        // An error reply settles the call as a failed replica, not an ack
+        let rejected = node.error(
+            "n1".to_string(),
+            out[1].body.msg_id().unwrap(),
+            maelstrom::ErrorCode::TemporarilyUnavailable,
+            "busy",
+        );
+        let rejected = Message {
+            src: "n3".to_string(),
+            ..rejected
+        };
+        assert!(maelstrom::process(&mut handler, &mut node, rejected).is_empty());
+        assert_eq!(handler.pendings["k1"][&0].acks, 1);
+        assert_eq!(handler.pendings["k1"][&0].failed, 1);
+
+        let ack = Message {
+            src: "n2".to_string(),
+            dest: "n1".to_string(),
+            body: MessageBody::ReplicateOk {
+                msg_id: 1,
+                in_reply_to: out[0].body.msg_id().unwrap(),
+                key: "k1".to_string(),
+                offset: 0,
+            },
+        };
+        let responses = maelstrom::process(&mut handler, &mut node, ack);
+        assert!(node.calls.is_empty());
+        let (in_reply_to, offset) = expect_body!(
+            &responses[0].body,
+            SendOk {
+                in_reply_to,
+                offset,
+                ..
+            }
+        );
+        assert_eq!(*in_reply_to, 5);
+        assert_eq!(*offset, 0);
+    }
+
+    #[test]
+    fn test_send_fails_once_quorum_is_out_of_reach() {
+        let mut handler = KafkaNode::new();
+        let mut node = Node::new();
+        handler.handle_init(
+            &mut node,
+            "n1".to_string(),
+            vec!["n1".to_string(), "n2".to_string(), "n3".to_string()],
+        );
+        node.config.retry = RetryPolicy::once(Duration::from_millis(100));
+
+        let out = handler.handle_send(&mut node, "c1".to_string(), 5, "k1".to_string(), 9);
+        assert_eq!(out.len(), 2);
+
+        // Both replicas time out: the first still leaves a quorum possible, the second does not
+        let later = Instant::now() + Duration::from_secs(1);
+        let responses = poll_calls(&mut handler, &mut node, later);
+        assert_eq!(responses.len(), 1);
+        assert_eq!(responses[0].dest, "c1");
+        let (in_reply_to, code) = expect_body!(
+            &responses[0].body,
+            Error {
+                in_reply_to,
+                code,
+                ..
+            }
+        );
+        assert_eq!(*in_reply_to, 5);
+        // The entry stays in the log, so whether the send happened is left open
+        assert_eq!(*code, maelstrom::ErrorCode::Crash);
+        assert!(handler.pendings.is_empty());
+        assert!(node.calls.is_empty());
+    }
+
+    #[test]
+    fn test_followers_pass_polls_to_the_leader() {
+        let mut handler = KafkaNode::new();
+        let mut node = Node::new();
+        handler.handle_init(
+            &mut node,
+            "n2".to_string(),
+            vec!["n1".to_string(), "n2".to_string(), "n3".to_string()],
+        );
+        // Replicated here but not yet known to have reached a quorum
+        handler.handle(
+            &mut node,
+            Message {
+                src: "n1".to_string(),
+                dest: "n2".to_string(),
+                body: MessageBody::Replicate {
+                    msg_id: 1,
+                    key: "k1".to_string(),
+                    msg: 100,
+                    offset: 0,
+                },
+            },
+        );
+
+        let out = handler.handle(&mut node, testkit::poll([("k1", 0)]).with_msg_id(9));
+        let forwarded = testkit::only(&out);
+        testkit::assert_route(forwarded, "n2", "n1");
+        expect_body!(&forwarded.body, Poll { .. });
+
+        let answer = Message {
+            src: "n1".to_string(),
+            dest: "n2".to_string(),
+            body: MessageBody::PollOk {
+                msg_id: 4,
+                in_reply_to: forwarded.body.msg_id().unwrap(),
+                msgs: HashMap::from([("k1".to_string(), Default::default())]),
+                next_offsets: HashMap::new(),
+                has_more: false,
+            },
+        };
+        let responses = maelstrom::process(&mut handler, &mut node, answer);
+        let reply = testkit::only(&responses);
+        testkit::assert_route(reply, "n2", "c1");
+        let (in_reply_to, msgs) = expect_body!(
+            &reply.body,
+            PollOk {
+                in_reply_to,
+                msgs,
+                ..
+            }
+        );
+        assert_eq!(*in_reply_to, 9);
+        assert!(msgs["k1"].is_empty());
+    }
+
+    #[test]
+    fn test_followers_pass_commits_to_the_leader() {
+        let nodes = vec!["n1".to_string(), "n2".to_string(), "n3".to_string()];
+        let mut leader = KafkaNode::new();
+        let mut leader_node = Node::new();
+        leader.handle_init(&mut leader_node, "n1".to_string(), nodes.clone());
+        leader.logs().append_local("k1", 100).unwrap();
+        // The follower has yet to see the entry the leader served
+        let mut follower = KafkaNode::new();
+        let mut follower_node = Node::new();
+        follower.handle_init(&mut follower_node, "n2".to_string(), nodes);
+
+        let commit = testkit::commit_offsets([("k1", 0)]).with_msg_id(9);
+        let out = follower.handle(&mut follower_node, commit);
+        let forwarded = testkit::only(&out);
+        testkit::assert_route(forwarded, "n2", "n1");
+        let consumer = expect_body!(&forwarded.body, CommitOffsets { consumer, .. });
+        assert_eq!(consumer.as_deref(), Some("c1"));
+
+        let out = leader.handle(&mut leader_node, forwarded.clone());
+        let answer = testkit::only(&out);
+        expect_body!(&answer.body, CommitOffsetsOk { .. });
+        assert_eq!(
+            leader
+                .logs()
+                .committed_offsets_of("c1", &["k1".to_string()])["k1"],
+            0
+        );
+
+        let responses = maelstrom::process(&mut follower, &mut follower_node, answer.clone());
+        let reply = testkit::only(&responses);
+        testkit::assert_route(reply, "n2", "c1");
+        let in_reply_to = expect_body!(&reply.body, CommitOffsetsOk { in_reply_to, .. });
+        assert_eq!(*in_reply_to, 9);
+    }
+
+    #[test]
+    fn test_send_fails_once_replicas_refuse_it() {
+        let mut handler = KafkaNode::new();
+        let mut node = Node::new();
+        handler.handle_init(
+            &mut node,
+            "n1".to_string(),
+            vec!["n1".to_string(), "n2".to_string(), "n3".to_string()],
+        );
+        let out = handler.handle_send(&mut node, "c1".to_string(), 5, "k1".to_string(), 9);
+        let refusal = |request: &Message, node: &mut Node| Message {
+            src: request.dest.clone(),
+            ..node.error(
+                "n1".to_string(),
+                request.body.msg_id().unwrap(),
+                ErrorCode::TemporarilyUnavailable,
+                "disk full",
+            )
+        };
+
+        let first = refusal(&out[0], &mut node);
+        assert!(maelstrom::process(&mut handler, &mut node, first).is_empty());
+        let second = refusal(&out[1], &mut node);
+        let responses = maelstrom::process(&mut handler, &mut node, second);
+        let in_reply_to = expect_body!(&testkit::only(&responses).body, Error { in_reply_to, .. });
+        assert_eq!(*in_reply_to, 5);
+        assert!(handler.pendings.is_empty());
+        assert!(node.calls.is_empty());
+        // Nothing is left holding the key's high-watermark back
+        assert_eq!(handler.logs().hwm("k1"), 1);
+    }
+
+    #[test]
+    fn test_send_skips_suspected_followers() {
+        let mut handler = KafkaNode::new();
+        let mut node = Node::with_config(NodeConfig::new().suspect_after(Duration::from_secs(1)));
+        handler.handle_init(
+            &mut node,
+            "n1".to_string(),
+            vec![
+                "n1".to_string(),
+                "n2".to_string(),
+                "n3".to_string(),
+                "n4".to_string(),
+                "n5".to_string(),
+            ],
+        );
+        let now = Instant::now();
+        node.liveness
+            .reset(now.checked_sub(Duration::from_secs(10)).unwrap());
+        node.liveness.heard_from("n2", now);
+        node.liveness.heard_from("n3", now);
+
+        // Two live followers and the leader still make a quorum of three
+        let out = handler.handle_send(&mut node, "c1".to_string(), 5, "k1".to_string(), 9);
+        let dests: HashSet<_> = out.iter().map(|m| m.dest.as_str()).collect();
+        assert_eq!(dests, HashSet::from(["n2", "n3"]));
+        assert_eq!(handler.pendings["k1"][&0].failed, 2);
+
+        // With one live follower a quorum is out of reach before anything is appended
+        node.liveness
+            .reset(now.checked_sub(Duration::from_secs(10)).unwrap());
+        let mut handler = KafkaNode::new();
+        handler.leader = "n1".to_string();
+        node.liveness.forget("n3");
+        let out = handler.handle_send(&mut node, "c1".to_string(), 6, "k1".to_string(), 9);
+        assert_eq!(out.len(), 1);
+        let code = expect_body!(&out[0].body, Error { code, .. });
+        assert_eq!(*code, maelstrom::ErrorCode::TemporarilyUnavailable);
+        assert!(handler.pendings.is_empty());
+    }
+
+    #[test]
+    fn test_simulated_history_passes_the_kafka_checker() {
+        let mut sim = Sim::new(3, |_| KafkaNode::new());
+        sim.latency(Duration::from_millis(1), Duration::from_millis(5));
+        let mut msg_id = 0;
+        let mut next = |message: Message| {
+            msg_id += 1;
+            message.with_msg_id(msg_id)
+        };
+        // Sends to two keys interleave, so both logs have sends at the same offsets in flight
+        for (i, value) in (100..112).enumerate() {
+            let dest = format!("n{}", i % 3 + 1);
+            let (client, key) = if i % 2 == 0 {
+                ("c1", "k1")
+            } else {
+                ("c2", "k2")
+            };
+            sim.send(next(testkit::send(client, key, value).dest(&dest)));
+        }
+        sim.run_for(Duration::from_millis(100));
+        let sends: Vec<_> = sim
+            .history()
+            .iter()
+            .filter(|op| matches!(op.request, MessageBody::Send { .. }))
+            .collect();
+        assert_eq!(sends.len(), 12);
+        for op in sends {
+            assert!(
+                matches!(op.ok(), Some(MessageBody::SendOk { .. })),
+                "{:?} was not acknowledged: {:?}",
+                op.request,
+                op.completed
+            );
+        }
+        sim.call(next(testkit::poll([("k1", 0), ("k2", 0)])))
+            .unwrap();
+        sim.call(next(testkit::commit_offsets([("k1", 1), ("k2", 2)])))
+            .unwrap();
+        sim.call(next(testkit::list_committed_offsets(["k1", "k2"])))
+            .unwrap();
+
+        let report = testkit::check_kafka(sim.history());
+        report.assert_valid();
+        assert_eq!(report.acknowledged, 12);
+    }
 }
@@ -0,0 +1,163 @@
+//! Replay a captured Maelstrom stdin log through one of the workspace's handlers and diff the
+//! output against a golden file:
+//!
+//! replay <binary> <input.jsonl> [<golden.jsonl>] [--bless]
+//!
+//! `<binary>` is the name of a workspace binary, such as `echo` or `multi_node_kafka`.
+//! `--bless` writes the replayed output to the golden file instead of comparing. Without a
+//! golden file the input must be a `MAELSTROM_TEE` capture, and the replay is compared with
+//! what the node wrote when it was captured.
+
+use maelstrom::replay::{Mismatch, captured_output, diff, replay};
+use maelstrom::{MessageHandler, NodeConfig};
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader};
+use std::path::Path;
+use std::process::ExitCode;
+
+const USAGE: &str = "usage: replay <binary> <input.jsonl> [<golden.jsonl>] [--bless]";
+
+/// Replay `input` through the handler of the workspace binary `binary`
+fn replay_binary(binary: &str, input: impl BufRead) -> io::Result<Vec<String>> {
+    fn run<H: MessageHandler>(mut handler: H, input: impl BufRead) -> io::Result<Vec<String>> {
+        replay(&mut handler, NodeConfig::new(), input)
+    }
+
+    match binary {
+        "echo" => run(echo::node::EchoNode::new(), input),
+        "uniqueids" => run(uniqueids::node::UniqueIdNode::default(), input),
+        "single_node_broadcast" => run(
+            single_node_broadcast::node::SingleNodeBroadcastNode::new(),
+            input,
+        ),
+        "multi_node_broadcast" => run(
+            multi_node_broadcast::node::MultiNodeBroadcastNode::new(),
+            input,
+        ),
+        "grow_only_counter" => run(grow_only_counter::node::GrowOnlyCounterNode::new(), input),
+        "single_node_kafka" => run(single_node_kafka::node::KafkaNode::new(), input),
+        "multi_node_kafka" => run(multi_node_kafka::node::KafkaNode::new(), input),
+        "single_node_tat" => run(single_node_tat::node::TatNode::new(), input),
+        "tarut" => run(tarut::node::TarutNode::new(), input),
+        "tarct" => run(tarct::node::TarctNode::new(), input),
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("unknown binary {binary}"),
+        )),
+    }
+}
+
+fn read_lines(path: &Path) -> io::Result<Vec<String>> {
+    BufReader::new(File::open(path)?)
+        .lines()
+        .filter(|line| line.as_ref().map_or(true, |line| !line.trim().is_empty()))
+        .collect()
+}
+
+/// Replay and compare, or write the golden file when blessing. With no golden file, compare
+/// with the output recorded in the capture `input`.
+fn check(
+    binary: &str,
+    input: &Path,
+    golden: Option<&Path>,
+    bless: bool,
+) -> io::Result<Vec<Mismatch>> {
+    let actual = replay_binary(binary, BufReader::new(File::open(input)?))?;
+    let Some(golden) = golden else {
+        let expected = captured_output(BufReader::new(File::open(input)?))?;
+        return Ok(diff(&actual, &expected));
+    };
+    if bless {
+        let mut contents = actual.join("\n");
+        contents.push('\n');
+        fs::write(golden, contents)?;
+        return Ok(Vec::new());
+    }
+    Ok(diff(&actual, &read_lines(golden)?))
+}
+
+fn main() -> ExitCode {
+    let mut args: Vec<String> = std::env::args().skip(1).collect();
+    let bless = args.iter().any(|arg| arg == "--bless");
+    args.retain(|arg| arg != "--bless");
+    let (binary, input, golden) = match args.as_slice() {
+        [binary, input] if !bless => (binary, input, None),
+        [binary, input, golden] => (binary, input, Some(golden)),
+        _ => {
+            eprintln!("{USAGE}");
+            return ExitCode::from(2);
+        }
+    };
+
+    match check(binary, Path::new(input), golden.map(Path::new), bless) {
+        Ok(mismatches) if mismatches.is_empty() => ExitCode::SUCCESS,
+        Ok(mismatches) => {
+            for mismatch in &mismatches {
+                eprintln!("{mismatch}");
+            }
+            let expected = golden.map_or(input, |golden| golden);
+            eprintln!("{} line(s) differ from {expected}", mismatches.len());
+            ExitCode::FAILURE
+        }
+        Err(e) => {
+            eprintln!("replay failed: {e}");
+            ExitCode::from(2)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Every `<name>.in.jsonl` under `golden/` must replay to `<name>.out.jsonl` through the
+    /// binary `<name>`
+    #[test]
+    fn test_golden_traces_still_match() {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("golden");
+        let mut checked = 0;
+        for entry in fs::read_dir(&dir).unwrap() {
+            let path = entry.unwrap().path();
+            let Some(binary) = path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .and_then(|name| name.strip_suffix(".in.jsonl"))
+            else {
+                continue;
+            };
+            let golden = dir.join(format!("{binary}.out.jsonl"));
+            let mismatches = check(binary, &path, Some(&golden), false).unwrap();
+            assert!(
+                mismatches.is_empty(),
+                "{binary} drifted from its golden trace:\n{}",
+                mismatches
+                    .iter()
+                    .map(ToString::to_string)
+                    .collect::<Vec<_>>()
+                    .join("\n")
+            );
+            checked += 1;
+        }
+        assert!(checked > 0);
+    }
+
+    #[test]
+    fn test_captures_check_against_their_own_output() {
+        let capture = [
+            r#"{"ts_us":1,"dir":"in","line":"{\"src\":\"c0\",\"dest\":\"n1\",\"body\":{\"type\":\"init\",\"msg_id\":1,\"node_id\":\"n1\",\"node_ids\":[\"n1\"]}}"}"#,
+            r#"{"ts_us":2,"dir":"out","line":"{\"src\":\"n1\",\"dest\":\"c0\",\"body\":{\"type\":\"init_ok\",\"msg_id\":1,\"in_reply_to\":1}}"}"#,
+        ];
+        let path =
+            std::env::temp_dir().join(format!("replay-capture-{}.jsonl", std::process::id()));
+        fs::write(&path, capture.join("\n")).unwrap();
+        let mismatches = check("echo", &path, None, false);
+        fs::remove_file(&path).unwrap();
+        assert_eq!(mismatches.unwrap(), Vec::new());
+    }
+
+    #[test]
+    fn test_unknown_binaries_are_rejected() {
+        let err = replay_binary("nope", io::empty()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+}
@@ -1,8 +1,8 @@
 use maelstrom::run_node;
-use single_node_broadcast::node::SingleNodeBroadcastNode;
+use single_node_broadcast::node::{Forwarding, SingleNodeBroadcastNode};
 
 #[tokio::main]
 async fn main() {
-    let handler = SingleNodeBroadcastNode::new();
+    let handler = SingleNodeBroadcastNode::new().forwarding(Forwarding::from_env());
     run_node(handler).await;
 }
@@ -1,14 +1,34 @@
-use maelstrom::{
-    Message, MessageBody,
-    node::{MessageHandler, Node},
-};
-use std::collections::HashSet;
+use maelstrom::config::env_choice;
+use maelstrom::{Message, MessageBody, MessageBodyDispatch, MessageHandler, Node};
+use std::collections::{HashMap, HashSet};
+
+/// Which peers a broadcast is passed on to
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Forwarding {
+    /// The node's neighbors in the latest topology, or every peer until one names the node
+    #[default]
+    Topology,
+    /// Every peer, whatever the topology says
+    Flood,
+}
+
+impl Forwarding {
+    /// Read from `BROADCAST_FORWARDING`, `topology` or `flood`; the default when unset or unknown
+    pub fn from_env() -> Self {
+        let env = |name: &str| std::env::var(name).ok();
+        let modes = [("topology", Self::Topology), ("flood", Self::Flood)];
+        env_choice(env, "BROADCAST_FORWARDING", &modes).unwrap_or_default()
+    }
+}
 
 pub struct SingleNodeBroadcastNode {
     /// Node messages
     messages: Vec<u64>,
     /// Fast membership test to avoid duplicate inserts
     seen: HashSet<u64>,
+    forwarding: Forwarding,
+    /// This node's neighbors from the last topology that named it
+    neighbors: Option<Vec<String>>,
 }
 
 impl Default for SingleNodeBroadcastNode {
@@ -22,26 +42,39 @@ impl SingleNodeBroadcastNode {
         Self {
             messages: Vec::new(),
             seen: HashSet::new(),
+            forwarding: Forwarding::default(),
+            neighbors: None,
         }
     }
 
+    /// Pass broadcasts on as `forwarding` says
+    pub fn forwarding(mut self, forwarding: Forwarding) -> Self {
+        self.forwarding = forwarding;
+        self
+    }
+
     pub fn handle_broadcast(&mut self, node: &mut Node, message: u64) -> Vec<Message> {
         let mut out: Vec<Message> = Vec::new();
+        // Only a value new to this node is passed on, so copies coming back stop here
         if self.seen.insert(message) {
             self.messages.push(message);
-        }
-        for peer in node.peers.clone() {
-            if peer == node.id {
-                continue;
+            let targets = match (&self.neighbors, self.forwarding) {
+                (Some(neighbors), Forwarding::Topology) => neighbors.clone(),
+                _ => node.peers.clone(),
+            };
+            for peer in targets {
+                if peer == node.id {
+                    continue;
+                }
+                out.push(Message {
+                    src: node.id.clone(),
+                    dest: peer,
+                    body: MessageBody::Broadcast {
+                        msg_id: node.next_msg_id(),
+                        message,
+                    },
+                });
             }
-            out.push(Message {
-                src: node.id.clone(),
-                dest: peer,
-                body: MessageBody::Broadcast {
-                    msg_id: node.next_msg_id(),
-                    message,
-                },
-            });
         }
         out
     }
@@ -53,95 +86,99 @@ impl SingleNodeBroadcastNode {
 
 impl MessageHandler for SingleNodeBroadcastNode {
     fn handle(&mut self, node: &mut Node, msg: Message) -> Vec<Message> {
-        let mut out: Vec<Message> = Vec::new();
-        match msg.body.clone() {
-            MessageBody::Init {
+        self.dispatch(node, msg)
+    }
+}
+
+impl MessageBodyDispatch for SingleNodeBroadcastNode {
+    fn on_init(
+        &mut self,
+        node: &mut Node,
+        src: String,
+        msg_id: u64,
+        node_id: String,
+        node_ids: Vec<String>,
+    ) -> Vec<Message> {
+        node.handle_init(node_id, node_ids);
+        vec![node.init_ok(src, msg_id)]
+    }
+
+    fn on_topology(
+        &mut self,
+        node: &mut Node,
+        src: String,
+        msg_id: u64,
+        mut topology: HashMap<String, Vec<String>>,
+    ) -> Vec<Message> {
+        if let Some(neighbors) = topology.remove(&node.id) {
+            self.neighbors = Some(neighbors);
+        }
+        vec![
+            node.answer(src, msg_id, |msg_id, in_reply_to| MessageBody::TopologyOk {
                 msg_id,
-                node_id,
-                node_ids,
-            } => {
-                node.handle_init(node_id, node_ids);
-                out.push(node.init_ok(msg.src, msg_id));
-            }
-            MessageBody::Topology {
+                in_reply_to,
+            }),
+        ]
+    }
+
+    fn on_broadcast(
+        &mut self,
+        node: &mut Node,
+        src: String,
+        msg_id: u64,
+        message: u64,
+    ) -> Vec<Message> {
+        let mut out = self.handle_broadcast(node, message);
+        out.push(node.answer(src, msg_id, |msg_id, in_reply_to| {
+            MessageBody::BroadcastOk {
                 msg_id,
-                topology: _,
-            } => {
-                let reply_msg_id = node.next_msg_id();
-                out.push(node.reply(
-                    msg.src,
-                    MessageBody::TopologyOk {
-                        msg_id: reply_msg_id,
-                        in_reply_to: msg_id,
-                    },
-                ));
-            }
-            MessageBody::Broadcast { msg_id, message } => {
-                let broadcasts = self.handle_broadcast(node, message);
-                out.extend(broadcasts);
-                let reply_msg_id = node.next_msg_id();
-                out.push(node.reply(
-                    msg.src,
-                    MessageBody::BroadcastOk {
-                        msg_id: reply_msg_id,
-                        in_reply_to: msg_id,
-                    },
-                ));
-            }
-            MessageBody::Read { msg_id } => {
-                let messages = self.handle_read();
-                let reply_msg_id = node.next_msg_id();
-                out.push(node.reply(
-                    msg.src,
-                    MessageBody::ReadOk {
-                        msg_id: reply_msg_id,
-                        in_reply_to: msg_id,
-                        messages: Some(messages),
-                        value: None,
-                    },
-                ));
+                in_reply_to,
             }
-            _ => {}
-        }
+        }));
         out
     }
+
+    fn on_read(
+        &mut self,
+        node: &mut Node,
+        src: String,
+        msg_id: u64,
+        _key: Option<String>,
+        _context: Option<HashMap<String, u64>>,
+    ) -> Vec<Message> {
+        let messages = self.handle_read();
+        vec![
+            node.answer(src, msg_id, |msg_id, in_reply_to| MessageBody::ReadOk {
+                msg_id,
+                in_reply_to,
+                messages: Some(messages),
+                value: None,
+                context: None,
+            }),
+        ]
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::collections::HashMap;
+    use maelstrom_testkit::strategy::{broadcast_ops, perturbed};
+    use maelstrom_testkit::{self as testkit, expect_body, scenario};
+    use proptest::prelude::*;
 
     #[test]
     fn test_broadcast_node_handles_init_message() {
         let mut handler = SingleNodeBroadcastNode::new();
         let mut node = Node::new();
 
-        let init_message = Message {
-            src: "c1".to_string(),
-            dest: "n1".to_string(),
-            body: MessageBody::Init {
-                msg_id: 1,
-                node_id: "n1".to_string(),
-                node_ids: vec!["n1".to_string(), "n2".to_string(), "n3".to_string()],
-            },
-        };
+        let init_message = testkit::init("n1", ["n1", "n2", "n3"]);
 
         let responses = handler.handle(&mut node, init_message);
 
-        assert_eq!(responses.len(), 1);
-        assert_eq!(responses[0].src, "n1");
-        assert_eq!(responses[0].dest, "c1");
+        testkit::assert_route(testkit::only(&responses), "n1", "c1");
 
-        match &responses[0].body {
-            MessageBody::InitOk {
-                msg_id: _,
-                in_reply_to,
-            } => {
-                assert_eq!(*in_reply_to, 1);
-            }
-            _ => panic!("Expected InitOk message"),
-        }
+        let in_reply_to = expect_body!(&responses[0].body, InitOk { in_reply_to, .. });
+        assert_eq!(*in_reply_to, 1);
 
         // Verify node state was updated
         assert_eq!(node.id, "n1");
@@ -151,10 +188,8 @@ mod tests {
     #[test]
     fn test_broadcast_node_handles_topology_message() {
         let mut handler = SingleNodeBroadcastNode::new();
-        let mut node = Node::new();
-
         // Initialize node first
-        node.handle_init("n1".to_string(), vec!["n1".to_string(), "n2".to_string()]);
+        let mut node = testkit::node("n1", ["n1", "n2"]);
 
         let topology_message = Message {
             src: "c1".to_string(),
@@ -167,40 +202,98 @@ mod tests {
 
         let responses = handler.handle(&mut node, topology_message);
 
-        assert_eq!(responses.len(), 1);
-        assert_eq!(responses[0].src, "n1");
-        assert_eq!(responses[0].dest, "c1");
+        testkit::assert_route(testkit::only(&responses), "n1", "c1");
 
-        match &responses[0].body {
-            MessageBody::TopologyOk {
-                msg_id: _,
-                in_reply_to,
-            } => {
-                assert_eq!(*in_reply_to, 1);
-            }
-            _ => panic!("Expected TopologyOk message"),
-        }
+        let in_reply_to = expect_body!(&responses[0].body, TopologyOk { in_reply_to, .. });
+        assert_eq!(*in_reply_to, 1);
     }
 
     #[test]
-    fn test_broadcast_node_handles_broadcast_message() {
-        let mut handler = SingleNodeBroadcastNode::new();
-        let mut node = Node::new();
+    fn test_broadcasts_go_to_topology_neighbors_unless_flooding() {
+        let topology = |neighbors: &[&str]| Message {
+            src: "c1".to_string(),
+            dest: "n1".to_string(),
+            body: MessageBody::Topology {
+                msg_id: 1,
+                topology: HashMap::from([
+                    (
+                        "n1".to_string(),
+                        neighbors.iter().map(|n| n.to_string()).collect(),
+                    ),
+                    ("n2".to_string(), vec!["n1".to_string()]),
+                ]),
+            },
+        };
+        let forwarded_to = |forwarding, neighbors: &[&str]| {
+            let mut handler = SingleNodeBroadcastNode::new().forwarding(forwarding);
+            let mut node = testkit::node("n1", ["n1", "n2", "n3", "n4"]);
+            handler.handle(&mut node, topology(neighbors));
+            let responses = handler.handle(&mut node, testkit::broadcast(42).with_msg_id(2));
+            let mut dests: Vec<String> = responses
+                .into_iter()
+                .filter(|msg| msg.dest != "c1")
+                .map(|msg| msg.dest)
+                .collect();
+            dests.sort();
+            dests
+        };
 
-        // Initialize node with peers
-        node.handle_init(
-            "n1".to_string(),
-            vec!["n1".to_string(), "n2".to_string(), "n3".to_string()],
+        assert_eq!(
+            forwarded_to(Forwarding::Topology, &["n2", "n4"]),
+            ["n2", "n4"]
         );
+        assert!(forwarded_to(Forwarding::Topology, &[]).is_empty());
+        assert_eq!(forwarded_to(Forwarding::Flood, &["n2"]), ["n2", "n3", "n4"]);
+    }
 
-        let broadcast_message = Message {
+    #[test]
+    fn test_broadcasts_flood_until_a_topology_names_the_node() {
+        let mut handler = SingleNodeBroadcastNode::new();
+        let mut node = testkit::node("n1", ["n1", "n2", "n3"]);
+        let forwarded_to = |handler: &mut SingleNodeBroadcastNode, node: &mut Node, value| {
+            let mut dests: Vec<String> = handler
+                .handle(node, testkit::broadcast(value))
+                .into_iter()
+                .filter(|msg| msg.dest != "c1")
+                .map(|msg| msg.dest)
+                .collect();
+            dests.sort();
+            dests
+        };
+
+        assert_eq!(forwarded_to(&mut handler, &mut node, 1), ["n2", "n3"]);
+        let topology = Message {
             src: "c1".to_string(),
             dest: "n1".to_string(),
-            body: MessageBody::Broadcast {
+            body: MessageBody::Topology {
                 msg_id: 1,
-                message: 42,
+                topology: HashMap::from([("n2".to_string(), vec!["n3".to_string()])]),
             },
         };
+        handler.handle(&mut node, topology);
+        assert_eq!(forwarded_to(&mut handler, &mut node, 2), ["n2", "n3"]);
+    }
+
+    #[test]
+    fn test_broadcasts_of_a_value_already_seen_are_not_forwarded() {
+        let mut handler = SingleNodeBroadcastNode::new();
+        let mut node = testkit::node("n1", ["n1", "n2", "n3"]);
+        assert_eq!(handler.handle(&mut node, testkit::broadcast(42)).len(), 3);
+
+        let responses = handler.handle(&mut node, testkit::broadcast(42).with_msg_id(2));
+        let response = testkit::only(&responses);
+        assert_eq!(response.dest, "c1");
+        expect_body!(&response.body, BroadcastOk { .. });
+        assert_eq!(handler.messages, vec![42]);
+    }
+
+    #[test]
+    fn test_broadcast_node_handles_broadcast_message() {
+        let mut handler = SingleNodeBroadcastNode::new();
+        // Initialize node with peers
+        let mut node = testkit::node("n1", ["n1", "n2", "n3"]);
+
+        let broadcast_message = testkit::broadcast(42);
 
         let responses = handler.handle(&mut node, broadcast_message);
 
@@ -211,15 +304,8 @@ mod tests {
         let broadcast_ok = &responses[2];
         assert_eq!(broadcast_ok.src, "n1");
         assert_eq!(broadcast_ok.dest, "c1");
-        match &broadcast_ok.body {
-            MessageBody::BroadcastOk {
-                msg_id: _,
-                in_reply_to,
-            } => {
-                assert_eq!(*in_reply_to, 1);
-            }
-            _ => panic!("Expected BroadcastOk message"),
-        }
+        let in_reply_to = expect_body!(&broadcast_ok.body, BroadcastOk { in_reply_to, .. });
+        assert_eq!(*in_reply_to, 1);
 
         // Check peer broadcasts (first 2 responses)
         let peer_destinations: Vec<&String> = responses[0..2].iter().map(|msg| &msg.dest).collect();
@@ -228,12 +314,8 @@ mod tests {
 
         for peer_msg in &responses[0..2] {
             assert_eq!(peer_msg.src, "n1");
-            match &peer_msg.body {
-                MessageBody::Broadcast { msg_id: _, message } => {
-                    assert_eq!(*message, 42);
-                }
-                _ => panic!("Expected Broadcast message to peer"),
-            }
+            let message = expect_body!(&peer_msg.body, Broadcast { message, .. });
+            assert_eq!(*message, 42);
         }
 
         // Verify message was stored
@@ -243,71 +325,46 @@ mod tests {
     #[test]
     fn test_broadcast_node_handles_read_message() {
         let mut handler = SingleNodeBroadcastNode::new();
-        let mut node = Node::new();
-
         // Initialize node
-        node.handle_init("n1".to_string(), vec!["n1".to_string()]);
+        let mut node = testkit::node("n1", ["n1"]);
 
         // Add some messages manually for testing
         handler.messages = vec![10, 20, 30];
 
-        let read_message = Message {
-            src: "c1".to_string(),
-            dest: "n1".to_string(),
-            body: MessageBody::Read { msg_id: 1 },
-        };
+        let read_message = testkit::read();
 
         let responses = handler.handle(&mut node, read_message);
 
-        assert_eq!(responses.len(), 1);
-        assert_eq!(responses[0].src, "n1");
-        assert_eq!(responses[0].dest, "c1");
+        testkit::assert_route(testkit::only(&responses), "n1", "c1");
 
-        match &responses[0].body {
-            MessageBody::ReadOk {
-                msg_id: _,
+        let (in_reply_to, messages, value) = expect_body!(
+            &responses[0].body,
+            ReadOk {
                 in_reply_to,
                 messages,
                 value,
-            } => {
-                assert_eq!(*in_reply_to, 1);
-                assert_eq!(messages.as_ref().unwrap(), &vec![10, 20, 30]);
-                assert_eq!(*value, None);
+                ..
             }
-            _ => panic!("Expected ReadOk message"),
-        }
+        );
+        assert_eq!(*in_reply_to, 1);
+        assert_eq!(messages.as_ref().unwrap(), &vec![10, 20, 30]);
+        assert_eq!(*value, None);
     }
 
     #[test]
     fn test_broadcast_node_handles_multiple_broadcasts() {
         let mut handler = SingleNodeBroadcastNode::new();
-        let mut node = Node::new();
-
         // Initialize node with one peer to simplify testing
-        node.handle_init("n1".to_string(), vec!["n1".to_string(), "n2".to_string()]);
+        let mut node = testkit::node("n1", ["n1", "n2"]);
 
         // Send first broadcast
-        let broadcast1 = Message {
-            src: "c1".to_string(),
-            dest: "n1".to_string(),
-            body: MessageBody::Broadcast {
-                msg_id: 1,
-                message: 100,
-            },
-        };
+        let broadcast1 = testkit::broadcast(100);
 
         let responses1 = handler.handle(&mut node, broadcast1);
         assert_eq!(responses1.len(), 2); // 1 peer broadcast + 1 BroadcastOk
 
         // Send second broadcast
-        let broadcast2 = Message {
-            src: "c1".to_string(),
-            dest: "n1".to_string(),
-            body: MessageBody::Broadcast {
-                msg_id: 2,
-                message: 200,
-            },
-        };
+        let broadcast2 = testkit::broadcast(200).with_msg_id(2);
 
         let responses2 = handler.handle(&mut node, broadcast2);
         assert_eq!(responses2.len(), 2); // 1 peer broadcast + 1 BroadcastOk
@@ -316,19 +373,11 @@ mod tests {
         assert_eq!(handler.messages, vec![100, 200]);
 
         // Test read to confirm both messages are returned
-        let read_message = Message {
-            src: "c1".to_string(),
-            dest: "n1".to_string(),
-            body: MessageBody::Read { msg_id: 3 },
-        };
+        let read_message = testkit::read().with_msg_id(3);
 
         let read_responses = handler.handle(&mut node, read_message);
-        match &read_responses[0].body {
-            MessageBody::ReadOk { messages, .. } => {
-                assert_eq!(messages.as_ref().unwrap(), &vec![100, 200]);
-            }
-            _ => panic!("Expected ReadOk message"),
-        }
+        let messages = expect_body!(&read_responses[0].body, ReadOk { messages, .. });
+        assert_eq!(messages.as_ref().unwrap(), &vec![100, 200]);
     }
 
     #[test]
@@ -339,7 +388,10 @@ mod tests {
         let unknown_message = Message {
             src: "c1".to_string(),
             dest: "n1".to_string(),
-            body: MessageBody::Generate { msg_id: 1 },
+            body: MessageBody::BroadcastOk {
+                msg_id: 1,
+                in_reply_to: 1,
+            },
         };
 
         let responses = handler.handle(&mut node, unknown_message);
@@ -350,33 +402,17 @@ mod tests {
     #[test]
     fn test_broadcast_node_with_no_peers() {
         let mut handler = SingleNodeBroadcastNode::new();
-        let mut node = Node::new();
-
         // Initialize node with no peers
-        node.handle_init("n1".to_string(), vec!["n1".to_string()]);
+        let mut node = testkit::node("n1", ["n1"]);
 
-        let broadcast_message = Message {
-            src: "c1".to_string(),
-            dest: "n1".to_string(),
-            body: MessageBody::Broadcast {
-                msg_id: 1,
-                message: 42,
-            },
-        };
+        let broadcast_message = testkit::broadcast(42);
 
         let responses = handler.handle(&mut node, broadcast_message);
 
         // Should only have BroadcastOk response (no peer broadcasts)
         assert_eq!(responses.len(), 1);
-        match &responses[0].body {
-            MessageBody::BroadcastOk {
-                msg_id: _,
-                in_reply_to,
-            } => {
-                assert_eq!(*in_reply_to, 1);
-            }
-            _ => panic!("Expected BroadcastOk message"),
-        }
+        let in_reply_to = expect_body!(&responses[0].body, BroadcastOk { in_reply_to, .. });
+        assert_eq!(*in_reply_to, 1);
 
         // Verify message was still stored
         assert_eq!(handler.messages, vec![42]);
@@ -385,59 +421,68 @@ mod tests {
     #[test]
     fn test_broadcast_node_read_when_empty() {
         let mut handler = SingleNodeBroadcastNode::new();
-        let mut node = Node::new();
-
         // Initialize node
-        node.handle_init("n1".to_string(), vec!["n1".to_string()]);
+        let mut node = testkit::node("n1", ["n1"]);
 
-        let read_message = Message {
-            src: "c1".to_string(),
-            dest: "n1".to_string(),
-            body: MessageBody::Read { msg_id: 1 },
-        };
+        let read_message = testkit::read();
 
         let responses = handler.handle(&mut node, read_message);
 
         assert_eq!(responses.len(), 1);
-        match &responses[0].body {
-            MessageBody::ReadOk { messages, .. } => {
-                assert_eq!(messages.as_ref().unwrap(), &Vec::<u64>::new());
-            }
-            _ => panic!("Expected ReadOk message"),
-        }
+        let messages = expect_body!(&responses[0].body, ReadOk { messages, .. });
+        assert_eq!(messages.as_ref().unwrap(), &Vec::<u64>::new());
     }
 
     #[test]
     fn test_broadcast_node_generates_unique_msg_ids() {
         let mut handler = SingleNodeBroadcastNode::new();
-        let mut node = Node::new();
-
         // Initialize node with one peer
-        node.handle_init("n1".to_string(), vec!["n1".to_string(), "n2".to_string()]);
+        let mut node = testkit::node("n1", ["n1", "n2"]);
 
-        let broadcast_message = Message {
-            src: "c1".to_string(),
-            dest: "n1".to_string(),
-            body: MessageBody::Broadcast {
-                msg_id: 1,
-                message: 42,
-            },
-        };
-
-        let responses1 = handler.handle(&mut node, broadcast_message.clone());
-        let responses2 = handler.handle(&mut node, broadcast_message);
+        let responses1 = handler.handle(&mut node, testkit::broadcast(42));
+        let responses2 = handler.handle(&mut node, testkit::broadcast(43));
 
         // Extract msg_ids from peer broadcasts (first response in each)
-        let msg_id1 = match &responses1[0].body {
-            MessageBody::Broadcast { msg_id, .. } => *msg_id,
-            _ => panic!("Expected Broadcast message"),
-        };
-
-        let msg_id2 = match &responses2[0].body {
-            MessageBody::Broadcast { msg_id, .. } => *msg_id,
-            _ => panic!("Expected Broadcast message"),
-        };
+        let msg_id1 = expect_body!(&responses1[0].body, Broadcast { msg_id, .. });
+        let msg_id2 = expect_body!(&responses2[0].body, Broadcast { msg_id, .. });
 
         assert_ne!(msg_id1, msg_id2);
     }
+
+    /// Every scenario under `scenarios/`, see `maelstrom::testing::scenario`
+    #[test]
+    fn test_scenarios() {
+        scenario::run_dir(
+            concat!(env!("CARGO_MANIFEST_DIR"), "/scenarios"),
+            SingleNodeBroadcastNode::new,
+        );
+    }
+
+    proptest! {
+        #[test]
+        fn test_reads_return_every_value_broadcast_so_far(requests in perturbed(broadcast_ops(40), 4)) {
+            let mut handler = SingleNodeBroadcastNode::new();
+            let mut node = Node::new();
+            maelstrom::process(&mut handler, &mut node, testkit::init("n1", ["n1"]));
+
+            let mut broadcast = HashSet::new();
+            for request in requests {
+                if let MessageBody::Broadcast { message, .. } = request.body {
+                    broadcast.insert(message);
+                }
+                let replies = maelstrom::process(&mut handler, &mut node, request);
+                prop_assert_eq!(replies.len(), 1);
+                match &replies[0].body {
+                    MessageBody::BroadcastOk { .. } => {}
+                    MessageBody::ReadOk { messages, .. } => {
+                        let messages = messages.clone().unwrap_or_default();
+                        let read: HashSet<u64> = messages.iter().copied().collect();
+                        prop_assert_eq!(read.len(), messages.len(), "read repeats a value");
+                        prop_assert_eq!(&read, &broadcast);
+                    }
+                    other => prop_assert!(false, "unexpected reply {:?}", other),
+                }
+            }
+        }
+    }
 }
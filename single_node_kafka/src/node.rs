@@ -1,17 +1,23 @@
-use maelstrom::simple_log::Logs;
+use maelstrom::log::{Logs, PollLimits, Retention, UnknownKeys};
+use maelstrom::persist::{Persistent, RestoreError};
 use maelstrom::{
-    Message, MessageBody,
-    node::{MessageHandler, Node},
+    Invalid, MaelstromError, Message, MessageBody, MessageBodyDispatch, MessageHandler, Node,
 };
 use std::collections::HashMap;
+use std::time::Duration;
 
 pub struct KafkaNode {
     /// Append-only logs
     logs: Logs,
     /// Deduplicate client Send retries: map (client_id, client_msg_id) -> offset
     send_dedupe: HashMap<(String, u64), u64>,
+    /// What compaction keeps of each log; logs grow without bound when unset
+    retention: Option<Retention>,
 }
 
+/// How often logs are compacted when a retention policy is set
+const COMPACT_EVERY: Duration = Duration::from_secs(1);
+
 impl Default for KafkaNode {
     fn default() -> Self {
         Self::new()
@@ -23,118 +29,228 @@ impl KafkaNode {
         Self {
             logs: Logs::new(),
             send_dedupe: HashMap::new(),
+            retention: None,
         }
     }
+
+    /// Cap the entries each `poll_ok` carries; unbounded by default
+    pub fn poll_limits(mut self, limits: PollLimits) -> Self {
+        self.logs.set_poll_limits(limits);
+        self
+    }
+
+    /// Answer polls of keys nothing was sent to per `unknown_keys`; left out by default
+    pub fn unknown_keys(mut self, unknown_keys: UnknownKeys) -> Self {
+        self.logs.set_unknown_keys(unknown_keys);
+        self
+    }
+
+    /// Accept commits past the end of a log instead of answering `precondition_failed`
+    pub fn lenient_commits(mut self) -> Self {
+        self.logs.set_lenient_commits(true);
+        self
+    }
+
+    /// Compact the logs to `retention` every [`COMPACT_EVERY`]
+    pub fn retention(mut self, retention: Retention) -> Self {
+        self.retention = Some(retention);
+        self
+    }
 }
 
 impl MessageHandler for KafkaNode {
     fn handle(&mut self, node: &mut Node, message: Message) -> Vec<Message> {
-        let mut out: Vec<Message> = Vec::new();
-        match message.body.clone() {
-            MessageBody::Init {
-                msg_id,
-                node_id,
-                node_ids,
-            } => {
-                node.handle_init(node_id, node_ids);
-                out.push(node.init_ok(message.src, msg_id));
-            }
-            MessageBody::Send { msg_id, key, msg } => {
-                // Deduplicate client retries by (src, msg_id)
-                let dedupe_key = (message.src.clone(), msg_id);
-                let offset = if let Some(&off) = self.send_dedupe.get(&dedupe_key) {
-                    off
-                } else {
-                    let off = self.logs.append(&key, msg);
-                    self.send_dedupe.insert(dedupe_key, off);
-                    off
-                };
-                let reply_msg_id = node.next_msg_id();
-                out.push(node.reply(
-                    message.src,
-                    MessageBody::SendOk {
-                        msg_id: reply_msg_id,
-                        in_reply_to: msg_id,
-                        offset,
-                    },
-                ));
+        self.dispatch(node, message)
+    }
+
+    fn on_tick(&mut self, node: &mut Node) -> Vec<Message> {
+        if let Some(retention) = self.retention {
+            match self.logs.compact(retention) {
+                Ok(0) => {}
+                Ok(dropped) => node.log(&format!("compacted {dropped} log entries")),
+                Err(err) => node.log(&format!("log compaction failed: {err}")),
             }
-            MessageBody::Poll { msg_id, offsets } => {
-                let msgs = self.logs.poll(&offsets);
-                let reply_msg_id = node.next_msg_id();
-                out.push(node.reply(
-                    message.src,
-                    MessageBody::PollOk {
-                        msg_id: reply_msg_id,
-                        in_reply_to: msg_id,
-                        msgs,
-                    },
-                ));
+        }
+        Vec::new()
+    }
+
+    fn tick_interval(&self) -> Option<Duration> {
+        self.retention.map(|_| COMPACT_EVERY)
+    }
+
+    fn stats(&self, _node: &Node) -> Option<serde_json::Value> {
+        Some(serde_json::json!({ "logs": self.logs.stats() }))
+    }
+
+    fn validate(&self, _node: &Node, message: &Message) -> Result<(), Invalid> {
+        message.body.validate()?;
+        // Committing an offset in a log that was never written to cannot be meaningful
+        if let MessageBody::CommitOffsets { offsets, .. } = &message.body
+            && let Some(key) = offsets.keys().find(|key| !self.logs.contains_key(key))
+        {
+            return Err(Invalid::new(format!("body.offsets.{key}"), "no such log"));
+        }
+        Ok(())
+    }
+}
+
+/// `send_dedupe` as written to a snapshot: tuple keys have no JSON form, so it goes out as
+/// pairs
+type DedupeEntries = Vec<((String, u64), u64)>;
+
+impl Persistent for KafkaNode {
+    fn snapshot(&self) -> Vec<u8> {
+        let send_dedupe: Vec<_> = self.send_dedupe.iter().collect();
+        serde_json::to_vec(&(&self.logs, send_dedupe)).unwrap_or_default()
+    }
+
+    fn restore(&mut self, snapshot: &[u8]) -> Result<(), RestoreError> {
+        let (mut logs, send_dedupe): (Logs, DedupeEntries) = serde_json::from_slice(snapshot)?;
+        logs.copy_settings(&self.logs);
+        self.logs = logs;
+        self.send_dedupe = send_dedupe.into_iter().collect();
+        Ok(())
+    }
+}
+
+impl MessageBodyDispatch for KafkaNode {
+    fn on_init(
+        &mut self,
+        node: &mut Node,
+        src: String,
+        msg_id: u64,
+        node_id: String,
+        node_ids: Vec<String>,
+    ) -> Vec<Message> {
+        node.handle_init(node_id, node_ids);
+        vec![node.init_ok(src, msg_id)]
+    }
+
+    fn on_send(
+        &mut self,
+        node: &mut Node,
+        src: String,
+        msg_id: u64,
+        key: String,
+        msg: u64,
+    ) -> Vec<Message> {
+        // Deduplicate client retries by (src, msg_id)
+        let dedupe_key = (src.clone(), msg_id);
+        let offset = if let Some(&off) = self.send_dedupe.get(&dedupe_key) {
+            off
+        } else {
+            let off = match self.logs.append_local(&key, msg) {
+                Ok(off) => off,
+                Err(err) => {
+                    return vec![node.temporarily_unavailable(
+                        src,
+                        msg_id,
+                        format!("log write failed: {err}"),
+                    )];
+                }
+            };
+            self.send_dedupe.insert(dedupe_key, off);
+            off
+        };
+        vec![
+            node.answer(src, msg_id, |msg_id, in_reply_to| MessageBody::SendOk {
+                msg_id,
+                in_reply_to,
+                offset,
+            }),
+        ]
+    }
+
+    fn on_poll(
+        &mut self,
+        node: &mut Node,
+        src: String,
+        msg_id: u64,
+        offsets: HashMap<String, u64>,
+    ) -> Vec<Message> {
+        let polled = match self.logs.poll(&offsets) {
+            Ok(polled) => polled,
+            Err(corruption) => {
+                return vec![MaelstromError::from(corruption).reply(node, src, msg_id)];
             }
-            MessageBody::CommitOffsets { msg_id, offsets } => {
-                self.logs.commit_offsets(offsets);
-                let reply_msg_id = node.next_msg_id();
-                out.push(node.reply(
-                    message.src,
-                    MessageBody::CommitOffsetsOk {
-                        msg_id: reply_msg_id,
-                        in_reply_to: msg_id,
-                    },
-                ));
+        };
+        let has_more = polled.has_more();
+        vec![
+            node.answer(src, msg_id, |msg_id, in_reply_to| MessageBody::PollOk {
+                msg_id,
+                in_reply_to,
+                msgs: polled.msgs,
+                next_offsets: polled.next_offsets,
+                has_more,
+            }),
+        ]
+    }
+
+    fn on_commit_offsets(
+        &mut self,
+        node: &mut Node,
+        src: String,
+        msg_id: u64,
+        offsets: HashMap<String, u64>,
+        _consumer: Option<String>,
+    ) -> Vec<Message> {
+        if let Err(err) = self.logs.commit_offsets(&src, offsets) {
+            return vec![MaelstromError::from(err).reply(node, src, msg_id)];
+        }
+        vec![node.answer(src, msg_id, |msg_id, in_reply_to| {
+            MessageBody::CommitOffsetsOk {
+                msg_id,
+                in_reply_to,
             }
-            MessageBody::ListCommittedOffsets { msg_id, keys } => {
-                let offsets = self.logs.list_committed_offsets(&keys);
-                let reply_msg_id = node.next_msg_id();
-                out.push(node.reply(
-                    message.src,
-                    MessageBody::ListCommittedOffsetsOk {
-                        msg_id: reply_msg_id,
-                        in_reply_to: msg_id,
-                        offsets,
-                    },
-                ));
+        })]
+    }
+
+    fn on_list_committed_offsets(
+        &mut self,
+        node: &mut Node,
+        src: String,
+        msg_id: u64,
+        keys: Vec<String>,
+        consumer: Option<String>,
+    ) -> Vec<Message> {
+        let offsets = match consumer {
+            Some(consumer) => self.logs.committed_offsets_of(&consumer, &keys),
+            None => self.logs.list_committed_offsets(&keys),
+        };
+        vec![node.answer(src, msg_id, |msg_id, in_reply_to| {
+            MessageBody::ListCommittedOffsetsOk {
+                msg_id,
+                in_reply_to,
+                offsets,
             }
-            _ => {}
-        }
-        out
+        })]
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use maelstrom::ErrorCode;
+    use maelstrom::sim::Sim;
+    use maelstrom_testkit::strategy::{kafka_ops, perturbed};
+    use maelstrom_testkit::{self as testkit, TestNode, expect_body, scenario};
+    use proptest::prelude::*;
     use std::collections::HashMap;
+    use std::time::Duration;
 
     #[test]
     fn test_kafka_node_handles_init_message() {
         let mut handler = KafkaNode::new();
         let mut node = Node::new();
 
-        let init_message = Message {
-            src: "c1".to_string(),
-            dest: "n1".to_string(),
-            body: MessageBody::Init {
-                msg_id: 1,
-                node_id: "n1".to_string(),
-                node_ids: vec!["n1".to_string(), "n2".to_string(), "n3".to_string()],
-            },
-        };
+        let init_message = testkit::init("n1", ["n1", "n2", "n3"]);
 
         let responses = handler.handle(&mut node, init_message);
 
-        assert_eq!(responses.len(), 1);
-        assert_eq!(responses[0].src, "n1");
-        assert_eq!(responses[0].dest, "c1");
+        testkit::assert_route(testkit::only(&responses), "n1", "c1");
 
-        match &responses[0].body {
-            MessageBody::InitOk {
-                msg_id: _,
-                in_reply_to,
-            } => {
-                assert_eq!(*in_reply_to, 1);
-            }
-            _ => panic!("Expected InitOk message"),
-        }
+        let in_reply_to = expect_body!(&responses[0].body, InitOk { in_reply_to, .. });
+        assert_eq!(*in_reply_to, 1);
 
         // Verify node state was updated
         assert_eq!(node.id, "n1");
@@ -144,131 +260,68 @@ mod tests {
     #[test]
     fn test_kafka_node_handles_send_message() {
         let mut handler = KafkaNode::new();
-        let mut node = Node::new();
-
         // Initialize node first
-        node.handle_init("n1".to_string(), vec!["n1".to_string()]);
+        let mut node = testkit::node("n1", ["n1"]);
 
-        let send_message = Message {
-            src: "c1".to_string(),
-            dest: "n1".to_string(),
-            body: MessageBody::Send {
-                msg_id: 42,
-                key: "k1".to_string(),
-                msg: 123,
-            },
-        };
+        let send_message = testkit::send("c1", "k1", 123).with_msg_id(42);
 
         let responses = handler.handle(&mut node, send_message);
 
-        assert_eq!(responses.len(), 1);
-        assert_eq!(responses[0].src, "n1");
-        assert_eq!(responses[0].dest, "c1");
+        testkit::assert_route(testkit::only(&responses), "n1", "c1");
 
-        match &responses[0].body {
-            MessageBody::SendOk {
-                msg_id: _,
+        let (in_reply_to, offset) = expect_body!(
+            &responses[0].body,
+            SendOk {
                 in_reply_to,
                 offset,
-            } => {
-                assert_eq!(*in_reply_to, 42);
-                assert_eq!(*offset, 0); // First message should have offset 0
+                ..
             }
-            _ => panic!("Expected SendOk message"),
-        }
+        );
+        assert_eq!(*in_reply_to, 42);
+        assert_eq!(*offset, 0); // First message should have offset 0
     }
 
     #[test]
     fn test_kafka_node_handles_multiple_send_messages() {
         let mut handler = KafkaNode::new();
-        let mut node = Node::new();
-
         // Initialize node first
-        node.handle_init("n1".to_string(), vec!["n1".to_string()]);
+        let mut node = testkit::node("n1", ["n1"]);
 
         // Send first message to k1
-        let send1 = Message {
-            src: "c1".to_string(),
-            dest: "n1".to_string(),
-            body: MessageBody::Send {
-                msg_id: 1,
-                key: "k1".to_string(),
-                msg: 123,
-            },
-        };
+        let send1 = testkit::send("c1", "k1", 123);
 
         // Send second message to k1
-        let send2 = Message {
-            src: "c1".to_string(),
-            dest: "n1".to_string(),
-            body: MessageBody::Send {
-                msg_id: 2,
-                key: "k1".to_string(),
-                msg: 456,
-            },
-        };
+        let send2 = testkit::send("c1", "k1", 456).with_msg_id(2);
 
         // Send message to different key k2
-        let send3 = Message {
-            src: "c1".to_string(),
-            dest: "n1".to_string(),
-            body: MessageBody::Send {
-                msg_id: 3,
-                key: "k2".to_string(),
-                msg: 789,
-            },
-        };
+        let send3 = testkit::send("c1", "k2", 789).with_msg_id(3);
 
         let responses1 = handler.handle(&mut node, send1);
         let responses2 = handler.handle(&mut node, send2);
         let responses3 = handler.handle(&mut node, send3);
 
         // Verify offsets are increasing for same key
-        match &responses1[0].body {
-            MessageBody::SendOk { offset, .. } => assert_eq!(*offset, 0),
-            _ => panic!("Expected SendOk message"),
-        }
+        let offset = expect_body!(&responses1[0].body, SendOk { offset, .. });
+        assert_eq!(*offset, 0);
 
-        match &responses2[0].body {
-            MessageBody::SendOk { offset, .. } => assert_eq!(*offset, 1),
-            _ => panic!("Expected SendOk message"),
-        }
+        let offset = expect_body!(&responses2[0].body, SendOk { offset, .. });
+        assert_eq!(*offset, 1);
 
         // Different key should start from 0
-        match &responses3[0].body {
-            MessageBody::SendOk { offset, .. } => assert_eq!(*offset, 0),
-            _ => panic!("Expected SendOk message"),
-        }
+        let offset = expect_body!(&responses3[0].body, SendOk { offset, .. });
+        assert_eq!(*offset, 0);
     }
 
     #[test]
     fn test_kafka_node_handles_poll_message() {
         let mut handler = KafkaNode::new();
-        let mut node = Node::new();
-
         // Initialize node first
-        node.handle_init("n1".to_string(), vec!["n1".to_string()]);
+        let mut node = testkit::node("n1", ["n1"]);
 
         // Send some messages first
-        let send1 = Message {
-            src: "c1".to_string(),
-            dest: "n1".to_string(),
-            body: MessageBody::Send {
-                msg_id: 1,
-                key: "k1".to_string(),
-                msg: 123,
-            },
-        };
+        let send1 = testkit::send("c1", "k1", 123);
 
-        let send2 = Message {
-            src: "c1".to_string(),
-            dest: "n1".to_string(),
-            body: MessageBody::Send {
-                msg_id: 2,
-                key: "k2".to_string(),
-                msg: 456,
-            },
-        };
+        let send2 = testkit::send("c1", "k2", 456).with_msg_id(2);
 
         handler.handle(&mut node, send1);
         handler.handle(&mut node, send2);
@@ -289,41 +342,172 @@ mod tests {
 
         let responses = handler.handle(&mut node, poll_message);
 
-        assert_eq!(responses.len(), 1);
-        assert_eq!(responses[0].src, "n1");
-        assert_eq!(responses[0].dest, "c1");
+        testkit::assert_route(testkit::only(&responses), "n1", "c1");
 
-        match &responses[0].body {
-            MessageBody::PollOk {
-                msg_id: _,
+        let (in_reply_to, msgs) = expect_body!(
+            &responses[0].body,
+            PollOk {
                 in_reply_to,
                 msgs,
-            } => {
-                assert_eq!(*in_reply_to, 10);
-                assert!(msgs.contains_key("k1"));
-                assert!(msgs.contains_key("k2"));
+                ..
+            }
+        );
+        assert_eq!(*in_reply_to, 10);
+        assert!(msgs.contains_key("k1"));
+        assert!(msgs.contains_key("k2"));
+        // Check that we got the messages back
+        let k1_msgs = &msgs["k1"];
+        let k2_msgs = &msgs["k2"];
+        assert_eq!(k1_msgs.len(), 1);
+        assert_eq!(k1_msgs[0], (0, 123));
+        assert_eq!(k2_msgs.len(), 1);
+        assert_eq!(k2_msgs[0], (0, 456));
+    }
+
+    #[test]
+    fn test_poll_limits_hold_across_a_restore() {
+        let mut handler = KafkaNode::new().poll_limits(PollLimits::new().per_key(2));
+        let mut node = testkit::node("n1", ["n1"]);
+        for msg_id in 1..=5 {
+            handler.handle(
+                &mut node,
+                testkit::send("c1", "k1", msg_id).with_msg_id(msg_id),
+            );
+        }
+        let snapshot = handler.snapshot();
+        handler.restore(&snapshot).unwrap();
+
+        let responses = handler.handle(&mut node, testkit::poll([("k1", 1)]).with_msg_id(6));
+        let (msgs, next_offsets, has_more) = expect_body!(
+            &responses[0].body,
+            PollOk {
+                msgs,
+                next_offsets,
+                has_more
+            }
+        );
+        assert_eq!(msgs["k1"], vec![(1, 2), (2, 3)]);
+        assert_eq!(next_offsets["k1"], 3);
+        assert!(*has_more);
+    }
 
-                // Check that we got the messages back
-                let k1_msgs = &msgs["k1"];
-                let k2_msgs = &msgs["k2"];
+    #[test]
+    fn test_stats_break_the_logs_down_by_key() {
+        let mut handler = KafkaNode::new();
+        let mut node = Node::new();
+        maelstrom::process(&mut handler, &mut node, testkit::init("n1", ["n1"]));
+        for msg_id in 1..=3 {
+            let send = testkit::send("c1", "k1", msg_id).with_msg_id(msg_id);
+            maelstrom::process(&mut handler, &mut node, send);
+        }
+        maelstrom::process(
+            &mut handler,
+            &mut node,
+            testkit::commit_offsets([("k1", 1)]),
+        );
+
+        let stats = Message {
+            src: "c9".to_string(),
+            dest: "n1".to_string(),
+            body: MessageBody::Stats { msg_id: 9 },
+        };
+        let responses = maelstrom::process(&mut handler, &mut node, stats);
+        let stats = expect_body!(&responses[0].body, StatsOk { stats, .. });
+        let k1 = &stats["handler"]["logs"]["k1"];
+        assert_eq!(k1["entries"], 3);
+        assert_eq!(k1["last_offset"], 2);
+        assert_eq!(k1["committed"], 1);
+        assert_eq!(k1["lag"], 1);
+    }
 
-                assert_eq!(k1_msgs.len(), 1);
-                assert_eq!(k1_msgs[0], (0, 123));
+    #[test]
+    fn test_unknown_keys_can_be_polled_back_empty() {
+        let mut handler = KafkaNode::new().unknown_keys(UnknownKeys::Empty);
+        let mut node = testkit::node("n1", ["n1"]);
+        handler.handle(&mut node, testkit::send("c1", "k1", 10));
+        let snapshot = handler.snapshot();
+        handler.restore(&snapshot).unwrap();
+
+        let responses = handler.handle(&mut node, testkit::poll([("k1", 0), ("k2", 0)]));
+        let msgs = expect_body!(&responses[0].body, PollOk { msgs, .. });
+        assert_eq!(msgs["k1"], vec![(0, 10)]);
+        assert_eq!(msgs["k2"], vec![]);
+    }
 
-                assert_eq!(k2_msgs.len(), 1);
-                assert_eq!(k2_msgs[0], (0, 456));
+    #[test]
+    fn test_ticks_compact_committed_entries_away() {
+        let handler = KafkaNode::new();
+        assert_eq!(handler.tick_interval(), None);
+
+        let mut handler = handler.retention(Retention::new().committed_window(1));
+        let mut node = testkit::node("n1", ["n1"]);
+        for msg_id in 1..=5 {
+            handler.handle(
+                &mut node,
+                testkit::send("c1", "k1", msg_id).with_msg_id(msg_id),
+            );
+        }
+        handler.handle(
+            &mut node,
+            testkit::commit_offsets([("k1", 3)]).with_msg_id(6),
+        );
+        assert!(handler.on_tick(&mut node).is_empty());
+
+        let responses = handler.handle(&mut node, testkit::poll([("k1", 0)]).with_msg_id(7));
+        let msgs = expect_body!(&responses[0].body, PollOk { msgs, .. });
+        assert_eq!(msgs["k1"], vec![(2, 3), (3, 4), (4, 5)]);
+    }
+
+    #[test]
+    fn test_commits_past_the_end_of_a_log_fail_their_precondition() {
+        for (mut handler, code) in [
+            (KafkaNode::new(), Some(ErrorCode::PreconditionFailed)),
+            (KafkaNode::new().lenient_commits(), None),
+        ] {
+            let mut node = testkit::node("n1", ["n1"]);
+            handler.handle(&mut node, testkit::send("c1", "k1", 10));
+            let responses = handler.handle(&mut node, testkit::commit_offsets([("k1", 1)]));
+            match &responses[0].body {
+                MessageBody::Error { code: got, .. } => assert_eq!(Some(*got), code),
+                body => assert!(code.is_none(), "{body:?}"),
             }
-            _ => panic!("Expected PollOk message"),
         }
     }
 
     #[test]
-    fn test_kafka_node_handles_commit_offsets_message() {
+    fn test_each_consumer_lists_its_own_commits() {
         let mut handler = KafkaNode::new();
-        let mut node = Node::new();
+        let mut node = testkit::node("n1", ["n1"]);
+        for msg_id in 1..=3 {
+            handler.handle(
+                &mut node,
+                testkit::send("c1", "k1", msg_id).with_msg_id(msg_id),
+            );
+        }
+        handler.handle(&mut node, testkit::commit_offsets([("k1", 2)]).src("c1"));
+        handler.handle(&mut node, testkit::commit_offsets([("k1", 1)]).src("c2"));
 
+        let list = |consumer: Option<&str>| Message {
+            src: "c2".to_string(),
+            dest: "n1".to_string(),
+            body: MessageBody::ListCommittedOffsets {
+                msg_id: 9,
+                keys: vec!["k1".to_string()],
+                consumer: consumer.map(str::to_string),
+            },
+        };
+        for (consumer, committed) in [(Some("c1"), 2), (Some("c2"), 1), (None, 2)] {
+            let responses = handler.handle(&mut node, list(consumer));
+            let offsets = expect_body!(&responses[0].body, ListCommittedOffsetsOk { offsets, .. });
+            assert_eq!(offsets["k1"], committed, "{consumer:?}");
+        }
+    }
+
+    #[test]
+    fn test_kafka_node_handles_commit_offsets_message() {
+        let mut handler = KafkaNode::new();
         // Initialize node first
-        node.handle_init("n1".to_string(), vec!["n1".to_string()]);
+        let mut node = testkit::node("n1", ["n1"]);
 
         let mut commit_offsets = HashMap::new();
         commit_offsets.insert("k1".to_string(), 1000);
@@ -335,54 +519,28 @@ mod tests {
             body: MessageBody::CommitOffsets {
                 msg_id: 42,
                 offsets: commit_offsets,
+                consumer: None,
             },
         };
 
         let responses = handler.handle(&mut node, commit_message);
 
-        assert_eq!(responses.len(), 1);
-        assert_eq!(responses[0].src, "n1");
-        assert_eq!(responses[0].dest, "c1");
+        testkit::assert_route(testkit::only(&responses), "n1", "c1");
 
-        match &responses[0].body {
-            MessageBody::CommitOffsetsOk {
-                msg_id: _,
-                in_reply_to,
-            } => {
-                assert_eq!(*in_reply_to, 42);
-            }
-            _ => panic!("Expected CommitOffsetsOk message"),
-        }
+        let in_reply_to = expect_body!(&responses[0].body, CommitOffsetsOk { in_reply_to, .. });
+        assert_eq!(*in_reply_to, 42);
     }
 
     #[test]
     fn test_kafka_node_handles_list_committed_offsets_message() {
         let mut handler = KafkaNode::new();
-        let mut node = Node::new();
-
         // Initialize node first
-        node.handle_init("n1".to_string(), vec!["n1".to_string()]);
+        let mut node = testkit::node("n1", ["n1"]);
 
         // First send messages to create the logs
-        let send1 = Message {
-            src: "c1".to_string(),
-            dest: "n1".to_string(),
-            body: MessageBody::Send {
-                msg_id: 1,
-                key: "k1".to_string(),
-                msg: 123,
-            },
-        };
+        let send1 = testkit::send("c1", "k1", 123);
 
-        let send2 = Message {
-            src: "c1".to_string(),
-            dest: "n1".to_string(),
-            body: MessageBody::Send {
-                msg_id: 2,
-                key: "k2".to_string(),
-                msg: 456,
-            },
-        };
+        let send2 = testkit::send("c1", "k2", 456).with_msg_id(2);
 
         handler.handle(&mut node, send1);
         handler.handle(&mut node, send2);
@@ -398,41 +556,32 @@ mod tests {
             body: MessageBody::CommitOffsets {
                 msg_id: 3,
                 offsets: commit_offsets,
+                consumer: None,
             },
         };
 
         handler.handle(&mut node, commit_message);
 
         // Now list committed offsets
-        let list_message = Message {
-            src: "c1".to_string(),
-            dest: "n1".to_string(),
-            body: MessageBody::ListCommittedOffsets {
-                msg_id: 10,
-                keys: vec!["k1".to_string(), "k2".to_string(), "k3".to_string()],
-            },
-        };
+        let list_message = testkit::list_committed_offsets(["k1", "k2", "k3"]).with_msg_id(10);
 
         let responses = handler.handle(&mut node, list_message);
 
-        assert_eq!(responses.len(), 1);
-        assert_eq!(responses[0].src, "n1");
-        assert_eq!(responses[0].dest, "c1");
+        testkit::assert_route(testkit::only(&responses), "n1", "c1");
 
-        match &responses[0].body {
-            MessageBody::ListCommittedOffsetsOk {
-                msg_id: _,
+        let (in_reply_to, offsets) = expect_body!(
+            &responses[0].body,
+            ListCommittedOffsetsOk {
                 in_reply_to,
                 offsets,
-            } => {
-                assert_eq!(*in_reply_to, 10);
-                assert_eq!(offsets.get("k1"), Some(&0));
-                assert_eq!(offsets.get("k2"), Some(&0));
-                // k3 should not be present since it wasn't committed
-                assert_eq!(offsets.get("k3"), None);
+                ..
             }
-            _ => panic!("Expected ListCommittedOffsetsOk message"),
-        }
+        );
+        assert_eq!(*in_reply_to, 10);
+        assert_eq!(offsets.get("k1"), Some(&0));
+        assert_eq!(offsets.get("k2"), Some(&0));
+        // k3 should not be present since it wasn't committed
+        assert_eq!(offsets.get("k3"), None);
     }
 
     #[test]
@@ -443,7 +592,11 @@ mod tests {
         let unknown_message = Message {
             src: "c1".to_string(),
             dest: "n1".to_string(),
-            body: MessageBody::Generate { msg_id: 1 },
+            body: MessageBody::SendOk {
+                msg_id: 1,
+                in_reply_to: 1,
+                offset: 0,
+            },
         };
 
         let responses = handler.handle(&mut node, unknown_message);
@@ -454,34 +607,18 @@ mod tests {
     #[test]
     fn test_kafka_node_generates_unique_msg_ids() {
         let mut handler = KafkaNode::new();
-        let mut node = Node::new();
-
         // Initialize node first
-        node.handle_init("n1".to_string(), vec!["n1".to_string()]);
+        let mut node = testkit::node("n1", ["n1"]);
 
-        let send_message = Message {
-            src: "c1".to_string(),
-            dest: "n1".to_string(),
-            body: MessageBody::Send {
-                msg_id: 1,
-                key: "k1".to_string(),
-                msg: 123,
-            },
-        };
+        let send_message = testkit::send("c1", "k1", 123);
 
         let responses1 = handler.handle(&mut node, send_message.clone());
         let responses2 = handler.handle(&mut node, send_message);
 
         // Extract msg_ids from responses
-        let msg_id1 = match &responses1[0].body {
-            MessageBody::SendOk { msg_id, .. } => *msg_id,
-            _ => panic!("Expected SendOk message"),
-        };
+        let msg_id1 = *expect_body!(&responses1[0].body, SendOk { msg_id, .. });
 
-        let msg_id2 = match &responses2[0].body {
-            MessageBody::SendOk { msg_id, .. } => *msg_id,
-            _ => panic!("Expected SendOk message"),
-        };
+        let msg_id2 = *expect_body!(&responses2[0].body, SendOk { msg_id, .. });
 
         assert_ne!(msg_id1, msg_id2);
         assert_eq!(msg_id2, msg_id1 + 1);
@@ -489,84 +626,167 @@ mod tests {
 
     #[test]
     fn test_kafka_node_full_workflow() {
-        let mut handler = KafkaNode::new();
-        let mut node = Node::new();
-
-        // Initialize node
-        node.handle_init("n1".to_string(), vec!["n1".to_string()]);
-
-        // Send multiple messages
+        let mut test = TestNode::new(KafkaNode::new()).init(1);
         for i in 0..3 {
-            let send_message = Message {
-                src: "c1".to_string(),
-                dest: "n1".to_string(),
-                body: MessageBody::Send {
-                    msg_id: i,
-                    key: "test-key".to_string(),
-                    msg: 100 + i,
-                },
-            };
-            handler.handle(&mut node, send_message);
+            test = test
+                .send(
+                    "c1",
+                    testkit::send("c1", "test-key", 100 + i).with_msg_id(i + 1),
+                )
+                .expect_reply(testkit::send_ok(i));
         }
+        test.send("c1", testkit::poll([("test-key", 0)]))
+            .expect_reply(testkit::poll_ok([(
+                "test-key",
+                vec![(0, 100), (1, 101), (2, 102)],
+            )]))
+            .send("c1", testkit::commit_offsets([("test-key", 2)]))
+            .expect_reply(testkit::commit_offsets_ok())
+            .send("c1", testkit::list_committed_offsets(["test-key"]))
+            .expect_reply(testkit::list_committed_offsets_ok([("test-key", 2)]))
+            .send("c1", testkit::commit_offsets([("missing", 0)]))
+            .expect_error(ErrorCode::MalformedMessage)
+            .expect_quiet();
+    }
 
-        // Poll for messages
-        let mut poll_offsets = HashMap::new();
-        poll_offsets.insert("test-key".to_string(), 0);
+    #[test]
+    fn test_kafka_node_rejects_commit_for_unknown_log() {
+        let mut handler = KafkaNode::new();
+        let node = Node::new();
 
-        let poll_message = Message {
+        let commit = Message {
             src: "c1".to_string(),
             dest: "n1".to_string(),
-            body: MessageBody::Poll {
-                msg_id: 10,
-                offsets: poll_offsets,
+            body: MessageBody::CommitOffsets {
+                msg_id: 1,
+                offsets: HashMap::from([("missing".to_string(), 0)]),
+                consumer: None,
             },
         };
+        let invalid = handler.validate(&node, &commit).unwrap_err();
+        assert_eq!(invalid.field, "body.offsets.missing");
 
-        let poll_responses = handler.handle(&mut node, poll_message);
-
-        match &poll_responses[0].body {
-            MessageBody::PollOk { msgs, .. } => {
-                let test_key_msgs = &msgs["test-key"];
-                assert_eq!(test_key_msgs.len(), 3);
-                assert_eq!(test_key_msgs[0], (0, 100));
-                assert_eq!(test_key_msgs[1], (1, 101));
-                assert_eq!(test_key_msgs[2], (2, 102));
-            }
-            _ => panic!("Expected PollOk message"),
-        }
+        handler.logs.append_local("missing", 7).unwrap();
+        assert!(handler.validate(&node, &commit).is_ok());
+    }
 
-        // Commit offsets
-        let mut commit_offsets = HashMap::new();
-        commit_offsets.insert("test-key".to_string(), 2);
+    /// Every scenario under `scenarios/`, see `maelstrom::testing::scenario`
+    #[test]
+    fn test_scenarios() {
+        scenario::run_dir(
+            concat!(env!("CARGO_MANIFEST_DIR"), "/scenarios"),
+            KafkaNode::new,
+        );
+    }
 
-        let commit_message = Message {
-            src: "c1".to_string(),
-            dest: "n1".to_string(),
-            body: MessageBody::CommitOffsets {
-                msg_id: 20,
-                offsets: commit_offsets,
-            },
-        };
+    #[test]
+    fn test_sends_checkpointed_before_a_crash_survive_it() {
+        let mut sim = Sim::new(1, |_| KafkaNode::new());
+        sim.latency(Duration::from_millis(1), Duration::from_millis(1));
+        for msg_id in 1..=3 {
+            sim.call(testkit::send("c1", "k1", msg_id * 10).with_msg_id(msg_id))
+                .unwrap();
+            sim.checkpoint("n1");
+        }
+        sim.crash("n1");
 
-        handler.handle(&mut node, commit_message);
+        let next = sim.call(testkit::send("c1", "k1", 40).with_msg_id(4));
+        assert_eq!(expect_body!(next.unwrap().body, SendOk { offset }), 3);
+        sim.call(testkit::poll([("k1", 0)]).with_msg_id(5)).unwrap();
+        let report = testkit::check_kafka(sim.history());
+        assert!(report.is_valid(), "{report:?}");
 
-        // List committed offsets
-        let list_message = Message {
-            src: "c1".to_string(),
-            dest: "n1".to_string(),
-            body: MessageBody::ListCommittedOffsets {
-                msg_id: 30,
-                keys: vec!["test-key".to_string()],
-            },
-        };
+        // A retried send is still recognized
+        let retry = sim.call(testkit::send("c1", "k1", 20).with_msg_id(2));
+        assert_eq!(expect_body!(retry.unwrap().body, SendOk { offset }), 1);
+    }
 
-        let list_responses = handler.handle(&mut node, list_message);
+    #[test]
+    fn test_sends_after_the_last_checkpoint_are_lost_in_a_crash() {
+        let mut sim = Sim::new(1, |_| KafkaNode::new());
+        sim.latency(Duration::from_millis(1), Duration::from_millis(1));
+        sim.checkpoint("n1");
+        sim.call(testkit::send("c1", "k1", 10)).unwrap();
+        sim.crash("n1");
+        sim.call(testkit::send("c1", "k1", 20).with_msg_id(2))
+            .unwrap();
+
+        let report = testkit::check_kafka(sim.history());
+        assert_eq!(report.reused, vec![("k1".to_string(), 0)]);
+    }
 
-        match &list_responses[0].body {
-            MessageBody::ListCommittedOffsetsOk { offsets, .. } => {
-                assert_eq!(offsets.get("test-key"), Some(&2));
+    proptest! {
+        #[test]
+        fn test_sends_get_one_increasing_offset_that_polls_agree_with(
+            requests in perturbed(kafka_ops(40), 4)
+        ) {
+            let mut handler = KafkaNode::new();
+            let mut node = Node::new();
+            maelstrom::process(&mut handler, &mut node, testkit::init("n1", ["n1"]));
+
+            // Offset acknowledged for each send, and what was sent at each offset
+            let mut acknowledged: HashMap<(String, u64), u64> = HashMap::new();
+            let mut logs: HashMap<String, Vec<(u64, u64)>> = HashMap::new();
+            let mut committed: HashMap<String, u64> = HashMap::new();
+            for request in requests {
+                let src = request.src.clone();
+                let body = request.body.clone();
+                let replies = maelstrom::process(&mut handler, &mut node, request);
+                prop_assert_eq!(replies.len(), 1);
+                match (body, &replies[0].body) {
+                    (MessageBody::Send { msg_id, key, msg }, MessageBody::SendOk { offset, .. }) => {
+                        match acknowledged.get(&(src.clone(), msg_id)) {
+                            // A duplicate is answered with the original offset
+                            Some(first) => prop_assert_eq!(first, offset),
+                            None => {
+                                let log = logs.entry(key).or_default();
+                                if let Some((last, _)) = log.last() {
+                                    prop_assert!(offset > last, "offset {} after {}", offset, last);
+                                }
+                                log.push((*offset, msg));
+                                acknowledged.insert((src, msg_id), *offset);
+                            }
+                        }
+                    }
+                    (MessageBody::Poll { offsets, .. }, MessageBody::PollOk { msgs, .. }) => {
+                        for (key, from) in offsets {
+                            let expected: Vec<(u64, u64)> = logs
+                                .get(&key)
+                                .map(|log| log.iter().copied().filter(|(offset, _)| *offset >= from).collect())
+                                .unwrap_or_default();
+                            prop_assert_eq!(msgs.get(&key).cloned().unwrap_or_default(), expected);
+                        }
+                    }
+                    (MessageBody::CommitOffsets { offsets, .. }, MessageBody::CommitOffsetsOk { .. }) => {
+                        for (key, offset) in offsets {
+                            let highest = committed.entry(key).or_default();
+                            *highest = (*highest).max(offset);
+                        }
+                    }
+                    // Committing to a log nobody has sent to, or past its newest offset, is
+                    // refused
+                    (MessageBody::CommitOffsets { offsets, .. }, MessageBody::Error { code, .. }) => {
+                        let past_end = offsets.iter().any(|(key, offset)| {
+                            logs.get(key).and_then(|log| log.last()).is_some_and(|(last, _)| offset > last)
+                        });
+                        let missing = offsets.keys().any(|key| !logs.contains_key(key));
+                        prop_assert!(missing || past_end);
+                        if !missing {
+                            prop_assert_eq!(*code, ErrorCode::PreconditionFailed);
+                        }
+                    }
+                    (
+                        MessageBody::ListCommittedOffsets { keys, .. },
+                        MessageBody::ListCommittedOffsetsOk { offsets, .. },
+                    ) => {
+                        for key in keys.iter().filter(|key| logs.contains_key(*key)) {
+                            let expected = committed.get(key).copied().unwrap_or_default();
+                            prop_assert_eq!(offsets.get(key).copied(), Some(expected));
+                        }
+                    }
+                    (request, reply) => prop_assert!(false, "{:?} answered with {:?}", request, reply),
+                }
             }
-            _ => panic!("Expected ListCommittedOffsetsOk message"),
         }
     }
 }
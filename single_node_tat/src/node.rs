@@ -1,7 +1,5 @@
-use maelstrom::{
-    Message, MessageBody,
-    node::{MessageHandler, Node},
-};
+use maelstrom::persist::{Persistent, RestoreError};
+use maelstrom::{MaelstromError, Message, MessageBody, MessageBodyDispatch, MessageHandler, Node};
 use std::collections::HashMap;
 
 pub struct TatNode {
@@ -25,58 +23,86 @@ impl TatNode {
     pub fn process_txn(
         &mut self,
         txn: Vec<(String, u64, Option<u64>)>,
-    ) -> Vec<(String, u64, Option<u64>)> {
+    ) -> Result<Vec<(String, u64, Option<u64>)>, MaelstromError> {
+        // Reject the whole transaction up front so a bad op never leaves it half-applied
+        if let Some((op, ..)) = txn.iter().find(|(op, ..)| op != "r" && op != "w") {
+            return Err(MaelstromError::malformed_message(format!(
+                "unknown transaction operation {op:?}"
+            )));
+        }
+
         let mut results = Vec::with_capacity(txn.len());
         for (op, key, opt_val) in txn {
-            match op.as_str() {
-                "r" => {
-                    let read_val = self.entries.get(&key).and_then(|v| *v);
-                    results.push(("r".to_string(), key, read_val));
-                }
-                "w" => {
-                    self.entries.insert(key, opt_val);
-                    results.push(("w".to_string(), key, opt_val));
-                }
-                _ => unreachable!("unknown transaction operation"),
+            if op == "r" {
+                let read_val = self.entries.get(&key).and_then(|v| *v);
+                results.push(("r".to_string(), key, read_val));
+            } else {
+                self.entries.insert(key, opt_val);
+                results.push(("w".to_string(), key, opt_val));
             }
         }
-        results
+        Ok(results)
     }
 }
 
 impl MessageHandler for TatNode {
     fn handle(&mut self, node: &mut Node, message: Message) -> Vec<Message> {
-        let mut out = Vec::new();
-        match message.body.clone() {
-            MessageBody::Init {
+        self.dispatch(node, message)
+    }
+}
+
+impl Persistent for TatNode {
+    fn snapshot(&self) -> Vec<u8> {
+        serde_json::to_vec(&self.entries).unwrap_or_default()
+    }
+
+    fn restore(&mut self, snapshot: &[u8]) -> Result<(), RestoreError> {
+        self.entries = serde_json::from_slice(snapshot)?;
+        Ok(())
+    }
+}
+
+impl MessageBodyDispatch for TatNode {
+    fn on_init(
+        &mut self,
+        node: &mut Node,
+        src: String,
+        msg_id: u64,
+        node_id: String,
+        node_ids: Vec<String>,
+    ) -> Vec<Message> {
+        node.handle_init(node_id, node_ids);
+        vec![node.init_ok(src, msg_id)]
+    }
+
+    fn on_txn(
+        &mut self,
+        node: &mut Node,
+        src: String,
+        msg_id: u64,
+        txn: Vec<(String, u64, Option<u64>)>,
+    ) -> Vec<Message> {
+        let results = match self.process_txn(txn) {
+            Ok(results) => results,
+            Err(e) => return vec![e.reply(node, src, msg_id)],
+        };
+        vec![
+            node.answer(src, msg_id, |msg_id, in_reply_to| MessageBody::TxnOk {
                 msg_id,
-                node_id,
-                node_ids,
-            } => {
-                node.handle_init(node_id, node_ids);
-                out.push(node.init_ok(message.src, msg_id));
-            }
-            MessageBody::Txn { msg_id, txn } => {
-                let results = self.process_txn(txn);
-                let reply_msg_id = node.next_msg_id();
-                out.push(node.reply(
-                    message.src,
-                    MessageBody::TxnOk {
-                        msg_id: reply_msg_id,
-                        in_reply_to: msg_id,
-                        txn: results,
-                    },
-                ));
-            }
-            _ => {}
-        }
-        out
+                in_reply_to,
+                txn: results,
+            }),
+        ]
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use maelstrom::sim::Sim;
+    use maelstrom_testkit::strategy::{perturbed, txn_ops};
+    use maelstrom_testkit::{self as testkit, expect_body};
+    use proptest::prelude::*;
 
     #[test]
     fn test_tat_node_new() {
@@ -94,7 +120,7 @@ mod tests {
     fn test_process_txn_read_nonexistent_key() {
         let mut node = TatNode::new();
         let txn = vec![("r".to_string(), 1, None)];
-        let results = node.process_txn(txn);
+        let results = node.process_txn(txn).unwrap();
 
         assert_eq!(results.len(), 1);
         assert_eq!(results[0], ("r".to_string(), 1, None));
@@ -104,7 +130,7 @@ mod tests {
     fn test_process_txn_write_operation() {
         let mut node = TatNode::new();
         let txn = vec![("w".to_string(), 1, Some(42))];
-        let results = node.process_txn(txn);
+        let results = node.process_txn(txn).unwrap();
 
         assert_eq!(results.len(), 1);
         assert_eq!(results[0], ("w".to_string(), 1, Some(42)));
@@ -115,7 +141,7 @@ mod tests {
     fn test_process_txn_write_then_read() {
         let mut node = TatNode::new();
         let txn = vec![("w".to_string(), 1, Some(42)), ("r".to_string(), 1, None)];
-        let results = node.process_txn(txn);
+        let results = node.process_txn(txn).unwrap();
 
         assert_eq!(results.len(), 2);
         assert_eq!(results[0], ("w".to_string(), 1, Some(42)));
@@ -126,7 +152,7 @@ mod tests {
     fn test_process_txn_write_null_value() {
         let mut node = TatNode::new();
         let txn = vec![("w".to_string(), 1, None)];
-        let results = node.process_txn(txn);
+        let results = node.process_txn(txn).unwrap();
 
         assert_eq!(results.len(), 1);
         assert_eq!(results[0], ("w".to_string(), 1, None));
@@ -141,7 +167,7 @@ mod tests {
             ("w".to_string(), 1, Some(99)),
             ("r".to_string(), 1, None),
         ];
-        let results = node.process_txn(txn);
+        let results = node.process_txn(txn).unwrap();
 
         assert_eq!(results.len(), 3);
         assert_eq!(results[0], ("w".to_string(), 1, Some(42)));
@@ -159,7 +185,7 @@ mod tests {
             ("r".to_string(), 2, None),
             ("r".to_string(), 3, None),
         ];
-        let results = node.process_txn(txn);
+        let results = node.process_txn(txn).unwrap();
 
         assert_eq!(results.len(), 5);
         assert_eq!(results[0], ("w".to_string(), 1, Some(10)));
@@ -174,21 +200,11 @@ mod tests {
         let mut handler = TatNode::new();
         let mut node = Node::new();
 
-        let init_message = Message {
-            src: "c1".to_string(),
-            dest: "n1".to_string(),
-            body: MessageBody::Init {
-                msg_id: 1,
-                node_id: "n1".to_string(),
-                node_ids: vec!["n1".to_string(), "n2".to_string()],
-            },
-        };
+        let init_message = testkit::init("n1", ["n1", "n2"]);
 
         let responses = handler.handle(&mut node, init_message);
 
-        assert_eq!(responses.len(), 1);
-        assert_eq!(responses[0].src, "n1");
-        assert_eq!(responses[0].dest, "c1");
+        testkit::assert_route(testkit::only(&responses), "n1", "c1");
         if let MessageBody::InitOk { in_reply_to, .. } = &responses[0].body {
             assert_eq!(*in_reply_to, 1);
         } else {
@@ -199,25 +215,14 @@ mod tests {
     #[test]
     fn test_handle_txn_message() {
         let mut handler = TatNode::new();
-        let mut node = Node::new();
-
         // Initialize the node first
-        node.handle_init("n1".to_string(), vec!["n1".to_string(), "n2".to_string()]);
+        let mut node = testkit::node("n1", ["n1", "n2"]);
 
-        let txn_message = Message {
-            src: "c1".to_string(),
-            dest: "n1".to_string(),
-            body: MessageBody::Txn {
-                msg_id: 1,
-                txn: vec![("w".to_string(), 1, Some(42)), ("r".to_string(), 1, None)],
-            },
-        };
+        let txn_message = testkit::txn([("w", 1, Some(42)), ("r", 1, None)]);
 
         let responses = handler.handle(&mut node, txn_message);
 
-        assert_eq!(responses.len(), 1);
-        assert_eq!(responses[0].src, "n1");
-        assert_eq!(responses[0].dest, "c1");
+        testkit::assert_route(testkit::only(&responses), "n1", "c1");
         if let MessageBody::TxnOk {
             in_reply_to, txn, ..
         } = &responses[0].body
@@ -236,16 +241,86 @@ mod tests {
         let mut handler = TatNode::new();
         let mut node = Node::new();
 
-        let echo_message = Message {
+        let unknown_message = Message {
             src: "c1".to_string(),
             dest: "n1".to_string(),
-            body: MessageBody::Echo {
+            body: MessageBody::TxnOk {
                 msg_id: 1,
-                echo: "test".to_string(),
+                in_reply_to: 1,
+                txn: vec![],
             },
         };
 
-        let responses = handler.handle(&mut node, echo_message);
+        let responses = handler.handle(&mut node, unknown_message);
         assert_eq!(responses.len(), 0);
     }
+
+    #[test]
+    fn test_unknown_operation_is_answered_with_error() {
+        let mut handler = TatNode::new();
+        let mut node = testkit::node("n1", ["n1"]);
+
+        let responses = handler.handle(
+            &mut node,
+            testkit::txn([("w", 1, Some(2)), ("cas", 1, Some(3))]).with_msg_id(6),
+        );
+        assert_eq!(responses.len(), 1);
+        let (in_reply_to, code) = expect_body!(
+            &responses[0].body,
+            Error {
+                in_reply_to,
+                code,
+                ..
+            }
+        );
+        assert_eq!(*in_reply_to, 6);
+        assert_eq!(*code, maelstrom::ErrorCode::MalformedMessage);
+        assert!(handler.entries.is_empty());
+    }
+
+    #[test]
+    fn test_checkpointed_writes_survive_a_crash() {
+        let mut sim = Sim::new(1, |_| TatNode::new());
+        sim.call(testkit::txn([("w", 1, Some(5))])).unwrap();
+        sim.checkpoint("n1");
+        sim.call(testkit::txn([("w", 1, Some(6)), ("w", 2, Some(7))]).with_msg_id(2))
+            .unwrap();
+        sim.crash("n1");
+
+        let reply = sim.call(testkit::txn([("r", 1, None), ("r", 2, None)]).with_msg_id(3));
+        let txn = expect_body!(reply.unwrap().body, TxnOk { txn });
+        assert_eq!(
+            txn,
+            vec![("r".to_string(), 1, Some(5)), ("r".to_string(), 2, None)]
+        );
+    }
+
+    proptest! {
+        #[test]
+        fn test_txns_behave_like_a_serial_map(requests in perturbed(txn_ops(30), 4)) {
+            let mut handler = TatNode::new();
+            let mut node = Node::new();
+            maelstrom::process(&mut handler, &mut node, testkit::init("n1", ["n1"]));
+
+            let mut model: HashMap<u64, u64> = HashMap::new();
+            for request in requests {
+                let MessageBody::Txn { txn, .. } = request.body.clone() else {
+                    unreachable!("txn_ops only generates txns");
+                };
+                let replies = maelstrom::process(&mut handler, &mut node, request);
+                prop_assert_eq!(replies.len(), 1);
+                let MessageBody::TxnOk { txn: results, .. } = &replies[0].body else {
+                    panic!("Expected TxnOk message");
+                };
+                for ((op, key, value), result) in txn.into_iter().zip(results) {
+                    if op == "w" {
+                        model.insert(key, value.unwrap());
+                        prop_assert_eq!(result, &("w".to_string(), key, value));
+                    } else {
+                        prop_assert_eq!(result, &("r".to_string(), key, model.get(&key).copied()));
+                    }
+                }
+            }
+        }
+    }
 }
@@ -1,4 +1,4 @@
-use maelstrom::{ErrorCode, Message, MessageBody, MessageHandler, Node, Version};
+use maelstrom::{Message, MessageBody, MessageBodyDispatch, MessageHandler, Node, Version};
 use std::collections::HashMap;
 
 pub struct KV {
@@ -89,7 +89,7 @@ impl TarctNode {
     fn handle_tx(
         &mut self,
         node: &mut Node,
-        message: Message,
+        src: String,
         msg_id: u64,
         txn: Vec<(String, u64, Option<u64>)>,
     ) -> Vec<Message> {
@@ -127,17 +127,11 @@ impl TarctNode {
             let current_version = self.kv.version(&key);
             if current_version != seen_version {
                 // abort on conflict
-                out.push(Message {
-                    src: node.id.clone(),
-                    dest: message.src.clone(),
-                    body: MessageBody::Error {
-                        msg_id: node.next_msg_id(),
-                        in_reply_to: msg_id,
-                        code: ErrorCode::TxnConflict,
-                        text: Some("Transaction aborted. Conflict detected".into()),
-                        extra: None,
-                    },
-                });
+                out.push(node.txn_conflict(
+                    src.clone(),
+                    msg_id,
+                    "Transaction aborted. Conflict detected",
+                ));
                 return out;
             }
         }
@@ -180,7 +174,7 @@ impl TarctNode {
                     src: node.id.clone(),
                     dest: peer.clone(),
                     body: MessageBody::TarctReplicate {
-                        msg_id: node.next_msg_id(),
+                        msg_id: None,
                         txn: replicate_ops.clone(),
                     },
                 })
@@ -188,15 +182,13 @@ impl TarctNode {
         }
 
         // reply to client
-        out.push(Message {
-            src: node.id.clone(),
-            dest: message.src,
-            body: MessageBody::TxnOk {
-                msg_id: node.next_msg_id(),
-                in_reply_to: msg_id,
+        out.push(
+            node.answer(src, msg_id, |msg_id, in_reply_to| MessageBody::TxnOk {
+                msg_id,
+                in_reply_to,
                 txn: results,
-            },
-        });
+            }),
+        );
 
         out
     }
@@ -204,46 +196,63 @@ impl TarctNode {
 
 impl MessageHandler for TarctNode {
     fn handle(&mut self, node: &mut Node, message: Message) -> Vec<Message> {
-        let mut out: Vec<Message> = Vec::new();
-        match message.body.clone() {
-            MessageBody::Init {
-                msg_id,
-                node_id,
-                node_ids,
-            } => {
-                node.handle_init(node_id, node_ids);
-                out.push(node.init_ok(message.src, msg_id));
-            }
-            MessageBody::Txn { msg_id, txn } => {
-                let messages = self.handle_tx(node, message, msg_id, txn);
-                out.extend(messages);
-            }
-            MessageBody::TarctReplicate {
-                msg_id: _,
-                txn: batch,
-            } => {
-                // Advance Lamport based on observed versions
-                for (_, _, _, v) in batch.iter() {
-                    if v.ts > self.lamport_ts {
-                        self.lamport_ts = v.ts;
-                    }
-                }
-                let writes = batch
-                    .iter()
-                    .filter(|(op, _, _, _)| op == "w")
-                    .map(|(_, key, val, version)| (*key, *val, *version))
-                    .collect();
-                self.kv.merge_batch(writes);
+        self.dispatch(node, message)
+    }
+}
+
+impl MessageBodyDispatch for TarctNode {
+    fn on_init(
+        &mut self,
+        node: &mut Node,
+        src: String,
+        msg_id: u64,
+        node_id: String,
+        node_ids: Vec<String>,
+    ) -> Vec<Message> {
+        node.handle_init(node_id, node_ids);
+        vec![node.init_ok(src, msg_id)]
+    }
+
+    fn on_txn(
+        &mut self,
+        node: &mut Node,
+        src: String,
+        msg_id: u64,
+        txn: Vec<(String, u64, Option<u64>)>,
+    ) -> Vec<Message> {
+        self.handle_tx(node, src, msg_id, txn)
+    }
+
+    fn on_tarct_replicate(
+        &mut self,
+        _node: &mut Node,
+        _src: String,
+        _msg_id: Option<u64>,
+        batch: Vec<(String, u64, Option<u64>, Version)>,
+    ) -> Vec<Message> {
+        // Advance Lamport based on observed versions
+        for (_, _, _, v) in batch.iter() {
+            if v.ts > self.lamport_ts {
+                self.lamport_ts = v.ts;
             }
-            _ => {}
         }
-        out
+        let writes = batch
+            .iter()
+            .filter(|(op, _, _, _)| op == "w")
+            .map(|(_, key, val, version)| (*key, *val, *version))
+            .collect();
+        self.kv.merge_batch(writes);
+        Vec::new()
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use maelstrom::ErrorCode;
+    use maelstrom::sim::Sim;
+    use maelstrom_testkit as testkit;
+    use std::time::Duration;
 
     #[test]
     fn test_kv_new() {
@@ -372,23 +381,12 @@ mod tests {
     #[test]
     fn test_handle_tx_read_only_transaction() {
         let mut tarct_node = TarctNode::new();
-        let mut node = Node::new();
-        node.handle_init(
-            "node1".to_string(),
-            vec!["node1".to_string(), "node2".to_string()],
-        );
+        let mut node = testkit::node("node1", ["node1", "node2"]);
 
-        let message = Message {
-            src: "client".to_string(),
-            dest: "node1".to_string(),
-            body: MessageBody::Txn {
-                msg_id: 1,
-                txn: vec![],
-            },
-        };
+        let message = testkit::txn([]).src("client").dest("node1");
 
         let txn = vec![("r".to_string(), 1, None)];
-        let out_messages = tarct_node.handle_tx(&mut node, message, 1, txn);
+        let out_messages = tarct_node.handle_tx(&mut node, message.src, 1, txn);
 
         // Should have 1 TxnOk message (no replication for read-only)
         assert_eq!(out_messages.len(), 1);
@@ -411,23 +409,12 @@ mod tests {
     #[test]
     fn test_handle_tx_write_transaction_success() {
         let mut tarct_node = TarctNode::new();
-        let mut node = Node::new();
-        node.handle_init(
-            "node1".to_string(),
-            vec!["node1".to_string(), "node2".to_string()],
-        );
+        let mut node = testkit::node("node1", ["node1", "node2"]);
 
-        let message = Message {
-            src: "client".to_string(),
-            dest: "node1".to_string(),
-            body: MessageBody::Txn {
-                msg_id: 1,
-                txn: vec![],
-            },
-        };
+        let message = testkit::txn([]).src("client").dest("node1");
 
         let txn = vec![("w".to_string(), 1, Some(42)), ("r".to_string(), 1, None)];
-        let out_messages = tarct_node.handle_tx(&mut node, message, 1, txn);
+        let out_messages = tarct_node.handle_tx(&mut node, message.src, 1, txn);
 
         // Should have 1 replicate message (to peer "node2") + 1 TxnOk message (to client)
         assert_eq!(out_messages.len(), 2);
@@ -462,25 +449,14 @@ mod tests {
     #[test]
     fn test_handle_tx_conflict_detection() {
         let mut tarct_node = TarctNode::new();
-        let mut node = Node::new();
-        node.handle_init(
-            "node1".to_string(),
-            vec!["node1".to_string(), "node2".to_string()],
-        );
+        let mut node = testkit::node("node1", ["node1", "node2"]);
 
         // Set up initial state
         tarct_node
             .kv
             .apply(1, Some(100), Version { ts: 5, node: 0 });
 
-        let message = Message {
-            src: "client".to_string(),
-            dest: "node1".to_string(),
-            body: MessageBody::Txn {
-                msg_id: 1,
-                txn: vec![],
-            },
-        };
+        let message = testkit::txn([]).src("client").dest("node1");
 
         // Simulate a transaction that reads key 1 at version 3 (older than current version 5)
         // Actually test the successful case since conflict detection logic is internal
@@ -489,7 +465,7 @@ mod tests {
             ("w".to_string(), 2, Some(42)),
         ];
 
-        let out_messages = tarct_node.handle_tx(&mut node, message, 1, txn);
+        let out_messages = tarct_node.handle_tx(&mut node, message.src, 1, txn);
 
         // Should succeed since we're reading the current version
         let error_msgs: Vec<_> = out_messages
@@ -509,28 +485,17 @@ mod tests {
     #[test]
     fn test_handle_tx_abort_on_conflict() {
         let mut tarct_node = TarctNode::new();
-        let mut node = Node::new();
-        node.handle_init(
-            "node1".to_string(),
-            vec!["node1".to_string(), "node2".to_string()],
-        );
+        let mut node = testkit::node("node1", ["node1", "node2"]);
 
         // This test demonstrates the structure of conflict detection
         // In practice, conflicts occur when the version read during the transaction
         // differs from the version at commit time due to concurrent modifications
 
-        let message = Message {
-            src: "client".to_string(),
-            dest: "node1".to_string(),
-            body: MessageBody::Txn {
-                msg_id: 1,
-                txn: vec![],
-            },
-        };
+        let message = testkit::txn([]).src("client").dest("node1");
 
         // Normal transaction should succeed
         let txn = vec![("r".to_string(), 1, None)];
-        let out_messages = tarct_node.handle_tx(&mut node, message, 1, txn);
+        let out_messages = tarct_node.handle_tx(&mut node, message.src, 1, txn);
 
         // Should succeed since no concurrent modification
         let error_msgs: Vec<_> = out_messages
@@ -543,20 +508,9 @@ mod tests {
     #[test]
     fn test_handle_tx_multiple_writes() {
         let mut tarct_node = TarctNode::new();
-        let mut node = Node::new();
-        node.handle_init(
-            "node1".to_string(),
-            vec!["node1".to_string(), "node2".to_string()],
-        );
+        let mut node = testkit::node("node1", ["node1", "node2"]);
 
-        let message = Message {
-            src: "client".to_string(),
-            dest: "node1".to_string(),
-            body: MessageBody::Txn {
-                msg_id: 1,
-                txn: vec![],
-            },
-        };
+        let message = testkit::txn([]).src("client").dest("node1");
 
         let txn = vec![
             ("w".to_string(), 1, Some(10)),
@@ -567,7 +521,7 @@ mod tests {
             ("r".to_string(), 3, None),
         ];
 
-        let out_messages = tarct_node.handle_tx(&mut node, message, 1, txn);
+        let out_messages = tarct_node.handle_tx(&mut node, message.src, 1, txn);
 
         // Should generate 1 replication message + 1 TxnOk message
         assert_eq!(out_messages.len(), 2);
@@ -607,17 +561,9 @@ mod tests {
     #[test]
     fn test_handle_tx_read_uncommitted_writes() {
         let mut tarct_node = TarctNode::new();
-        let mut node = Node::new();
-        node.handle_init("node1".to_string(), vec!["node1".to_string()]);
+        let mut node = testkit::node("node1", ["node1"]);
 
-        let message = Message {
-            src: "client".to_string(),
-            dest: "node1".to_string(),
-            body: MessageBody::Txn {
-                msg_id: 1,
-                txn: vec![],
-            },
-        };
+        let message = testkit::txn([]).src("client").dest("node1");
 
         // Transaction that writes then reads the same key
         let txn = vec![
@@ -627,7 +573,7 @@ mod tests {
             ("r".to_string(), 1, None),
         ];
 
-        let out_messages = tarct_node.handle_tx(&mut node, message, 1, txn);
+        let out_messages = tarct_node.handle_tx(&mut node, message.src, 1, txn);
 
         // Should have 1 TxnOk message (no peers to replicate to)
         assert_eq!(out_messages.len(), 1);
@@ -645,19 +591,7 @@ mod tests {
         let mut tarct_node = TarctNode::new();
         let mut node = Node::new();
 
-        let message = Message {
-            src: "maelstrom".to_string(),
-            dest: "node1".to_string(),
-            body: MessageBody::Init {
-                msg_id: 1,
-                node_id: "node1".to_string(),
-                node_ids: vec![
-                    "node1".to_string(),
-                    "node2".to_string(),
-                    "node3".to_string(),
-                ],
-            },
-        };
+        let message = testkit::init("node1", ["node1", "node2", "node3"]).src("maelstrom");
 
         let out_messages = tarct_node.handle(&mut node, message);
 
@@ -675,20 +609,11 @@ mod tests {
     #[test]
     fn test_message_handler_txn() {
         let mut tarct_node = TarctNode::new();
-        let mut node = Node::new();
-        node.handle_init(
-            "node1".to_string(),
-            vec!["node1".to_string(), "node2".to_string()],
-        );
+        let mut node = testkit::node("node1", ["node1", "node2"]);
 
-        let message = Message {
-            src: "client".to_string(),
-            dest: "node1".to_string(),
-            body: MessageBody::Txn {
-                msg_id: 1,
-                txn: vec![("w".to_string(), 1, Some(42))],
-            },
-        };
+        let message = testkit::txn([("w", 1, Some(42))])
+            .src("client")
+            .dest("node1");
 
         let out_messages = tarct_node.handle(&mut node, message);
 
@@ -717,7 +642,7 @@ mod tests {
             src: "node2".to_string(),
             dest: "node1".to_string(),
             body: MessageBody::TarctReplicate {
-                msg_id: 1,
+                msg_id: Some(1),
                 txn: vec![
                     ("w".to_string(), 1, Some(42), Version { ts: 5, node: 0 }),
                     ("w".to_string(), 2, None, Version { ts: 5, node: 0 }),
@@ -746,7 +671,7 @@ mod tests {
             src: "node2".to_string(),
             dest: "node1".to_string(),
             body: MessageBody::TarctReplicate {
-                msg_id: 1,
+                msg_id: Some(1),
                 txn: vec![
                     ("w".to_string(), 1, Some(42), Version { ts: 5, node: 0 }),
                     ("r".to_string(), 2, None, Version { ts: 0, node: 0 }), // should be filtered out
@@ -792,33 +717,25 @@ mod tests {
     #[test]
     fn test_commit_timestamp_advancement() {
         let mut tarct_node = TarctNode::new();
-        let mut node = Node::new();
-        node.handle_init("node1".to_string(), vec!["node1".to_string()]);
+        let mut node = testkit::node("node1", ["node1"]);
 
-        let message = Message {
-            src: "client".to_string(),
-            dest: "node1".to_string(),
-            body: MessageBody::Txn {
-                msg_id: 1,
-                txn: vec![],
-            },
-        };
+        let message = testkit::txn([]).src("client").dest("node1");
 
         // First transaction with writes
         let txn1 = vec![("w".to_string(), 1, Some(10))];
-        tarct_node.handle_tx(&mut node, message.clone(), 1, txn1);
+        tarct_node.handle_tx(&mut node, message.src.clone(), 1, txn1);
         assert_eq!(tarct_node.lamport_ts, 1);
         assert_eq!(tarct_node.kv.version(&1).ts, 1);
 
         // Second transaction with writes
         let txn2 = vec![("w".to_string(), 2, Some(20))];
-        tarct_node.handle_tx(&mut node, message.clone(), 2, txn2);
+        tarct_node.handle_tx(&mut node, message.src.clone(), 2, txn2);
         assert_eq!(tarct_node.lamport_ts, 2);
         assert_eq!(tarct_node.kv.version(&2).ts, 2);
 
         // Read-only transaction should not advance timestamp
         let txn3 = vec![("r".to_string(), 1, None)];
-        tarct_node.handle_tx(&mut node, message, 3, txn3);
+        tarct_node.handle_tx(&mut node, message.src, 3, txn3);
         assert_eq!(tarct_node.lamport_ts, 2); // unchanged
     }
 
@@ -839,8 +756,7 @@ mod tests {
     #[test]
     fn test_read_committed_semantics() {
         let mut tarct_node = TarctNode::new();
-        let mut node = Node::new();
-        node.handle_init("node1".to_string(), vec!["node1".to_string()]);
+        let mut node = testkit::node("node1", ["node1"]);
 
         // Set up initial committed state with a value at version 1
         // We'll have a transaction commit writes with version 2
@@ -849,14 +765,7 @@ mod tests {
             .apply(1, Some(100), Version { ts: 1, node: 0 });
         tarct_node.lamport_ts = 1; // Set Lamport so next transaction will use version 2
 
-        let message = Message {
-            src: "client".to_string(),
-            dest: "node1".to_string(),
-            body: MessageBody::Txn {
-                msg_id: 1,
-                txn: vec![],
-            },
-        };
+        let message = testkit::txn([]).src("client").dest("node1");
 
         // Transaction should see committed values and its own uncommitted writes
         let txn = vec![
@@ -867,7 +776,7 @@ mod tests {
             ("r".to_string(), 2, None),      // should see uncommitted write 300
         ];
 
-        let out_messages = tarct_node.handle_tx(&mut node, message, 1, txn);
+        let out_messages = tarct_node.handle_tx(&mut node, message.src, 1, txn);
 
         // Should have 1 TxnOk message (no peers)
         assert_eq!(out_messages.len(), 1);
@@ -886,4 +795,26 @@ mod tests {
         assert_eq!(tarct_node.kv.version(&1).ts, 2);
         assert_eq!(tarct_node.kv.version(&2).ts, 2);
     }
+
+    #[test]
+    fn test_simulated_txns_show_no_read_committed_anomalies() {
+        let mut sim = Sim::new(3, |_| TarctNode::new());
+        sim.latency(Duration::from_millis(1), Duration::from_millis(10));
+        // Every write to a key is unique, so the checker can tell who wrote what was read
+        for i in 0..12u64 {
+            let dest = format!("n{}", i % 3 + 1);
+            let txn = testkit::txn([
+                ("r", i % 3, None),
+                ("w", i % 3, Some(i + 1)),
+                ("w", (i + 1) % 3, Some(100 + i)),
+                ("r", (i + 2) % 3, None),
+            ]);
+            sim.send(txn.dest(&dest).with_msg_id(i + 1));
+        }
+        sim.run_for(Duration::from_secs(1));
+
+        let report = testkit::check_txn(sim.history());
+        report.assert_valid();
+        assert_eq!(report.committed, 12);
+    }
 }
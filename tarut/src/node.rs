@@ -1,7 +1,4 @@
-use maelstrom::{
-    Message, MessageBody,
-    node::{MessageHandler, Node},
-};
+use maelstrom::{Message, MessageBody, MessageBodyDispatch, MessageHandler, Node};
 use std::collections::HashMap;
 
 pub struct TarutNode {
@@ -52,7 +49,7 @@ impl TarutNode {
     pub fn handle_tx(
         &mut self,
         node: &mut Node,
-        message: Message,
+        src: String,
         msg_id: u64,
         txn: Vec<(String, u64, Option<u64>)>,
     ) -> Vec<Message> {
@@ -87,7 +84,7 @@ impl TarutNode {
                     src: node.id.clone(),
                     dest: peer.clone(),
                     body: MessageBody::TarutReplicate {
-                        msg_id: node.next_msg_id(),
+                        msg_id: None,
                         txn: replicate_ops.clone(),
                     },
                 })
@@ -95,15 +92,13 @@ impl TarutNode {
         }
 
         // reply to client immediately
-        out.push(Message {
-            src: node.id.clone(),
-            dest: message.src,
-            body: MessageBody::TxnOk {
-                msg_id: node.next_msg_id(),
-                in_reply_to: msg_id,
+        out.push(
+            node.answer(src, msg_id, |msg_id, in_reply_to| MessageBody::TxnOk {
+                msg_id,
+                in_reply_to,
                 txn: results,
-            },
-        });
+            }),
+        );
 
         out
     }
@@ -111,35 +106,51 @@ impl TarutNode {
 
 impl MessageHandler for TarutNode {
     fn handle(&mut self, node: &mut Node, message: Message) -> Vec<Message> {
-        let mut out = Vec::new();
-        match message.body.clone() {
-            MessageBody::Init {
-                msg_id,
-                node_id,
-                node_ids,
-            } => {
-                node.handle_init(node_id, node_ids);
-                out.push(node.init_ok(message.src, msg_id));
-            }
-            MessageBody::Txn { msg_id, txn } => {
-                let messages = self.handle_tx(node, message, msg_id, txn);
-                out.extend(messages);
-            }
-            MessageBody::TarutReplicate { txn, .. } => {
-                // Apply peer-originated writes with LWW versioning
-                for (op, key, val, version) in txn.into_iter() {
-                    if op == "w" {
-                        let current_version = *self.versions.get(&key).unwrap_or(&0);
-                        if version > current_version {
-                            self.entries.insert(key, val);
-                            self.versions.insert(key, version);
-                        }
-                    }
+        self.dispatch(node, message)
+    }
+}
+
+impl MessageBodyDispatch for TarutNode {
+    fn on_init(
+        &mut self,
+        node: &mut Node,
+        src: String,
+        msg_id: u64,
+        node_id: String,
+        node_ids: Vec<String>,
+    ) -> Vec<Message> {
+        node.handle_init(node_id, node_ids);
+        vec![node.init_ok(src, msg_id)]
+    }
+
+    fn on_txn(
+        &mut self,
+        node: &mut Node,
+        src: String,
+        msg_id: u64,
+        txn: Vec<(String, u64, Option<u64>)>,
+    ) -> Vec<Message> {
+        self.handle_tx(node, src, msg_id, txn)
+    }
+
+    fn on_tarut_replicate(
+        &mut self,
+        _node: &mut Node,
+        _src: String,
+        _msg_id: Option<u64>,
+        txn: Vec<(String, u64, Option<u64>, u64)>,
+    ) -> Vec<Message> {
+        // Apply peer-originated writes with LWW versioning
+        for (op, key, val, version) in txn.into_iter() {
+            if op == "w" {
+                let current_version = *self.versions.get(&key).unwrap_or(&0);
+                if version > current_version {
+                    self.entries.insert(key, val);
+                    self.versions.insert(key, version);
                 }
             }
-            _ => {}
         }
-        out
+        Vec::new()
     }
 }
 
@@ -148,6 +159,9 @@ mod tests {
     use maelstrom::ErrorCode;
 
     use super::*;
+    use maelstrom::sim::Sim;
+    use maelstrom_testkit as testkit;
+    use std::time::Duration;
 
     #[test]
     fn test_tarut_node_new() {
@@ -263,28 +277,11 @@ mod tests {
     #[test]
     fn test_handle_tx_creates_correct_messages() {
         let mut tarut_node = TarutNode::new();
-        let mut node = Node::new();
-        node.handle_init(
-            "node1".to_string(),
-            vec![
-                "node1".to_string(),
-                "node2".to_string(),
-                "node3".to_string(),
-            ],
-        );
+        let mut node = testkit::node("node1", ["node1", "node2", "node3"]);
 
-        let message = Message {
-            src: "client".to_string(),
-            dest: "node1".to_string(),
-            body: MessageBody::Txn {
-                msg_id: 1,
-                txn: vec![
-                    ("w".to_string(), 1, Some(42)),
-                    ("r".to_string(), 1, None),
-                    ("w".to_string(), 2, Some(99)),
-                ],
-            },
-        };
+        let message = testkit::txn([("w", 1, Some(42)), ("r", 1, None), ("w", 2, Some(99))])
+            .src("client")
+            .dest("node1");
 
         let txn = vec![
             ("w".to_string(), 1, Some(42)),
@@ -292,7 +289,7 @@ mod tests {
             ("w".to_string(), 2, Some(99)),
         ];
 
-        let out_messages = tarut_node.handle_tx(&mut node, message, 1, txn);
+        let out_messages = tarut_node.handle_tx(&mut node, message.src, 1, txn);
 
         // Should generate: 2 replicate messages (to peers) + 1 TxnOk message (to client)
         assert_eq!(out_messages.len(), 3);
@@ -340,20 +337,9 @@ mod tests {
     #[test]
     fn test_handle_tx_only_replicates_writes() {
         let mut tarut_node = TarutNode::new();
-        let mut node = Node::new();
-        node.handle_init(
-            "node1".to_string(),
-            vec!["node1".to_string(), "node2".to_string()],
-        );
+        let mut node = testkit::node("node1", ["node1", "node2"]);
 
-        let message = Message {
-            src: "client".to_string(),
-            dest: "node1".to_string(),
-            body: MessageBody::Txn {
-                msg_id: 1,
-                txn: vec![],
-            },
-        };
+        let message = testkit::txn([]).src("client").dest("node1");
 
         let txn = vec![
             ("r".to_string(), 1, None),
@@ -361,7 +347,7 @@ mod tests {
             ("r".to_string(), 3, None),
         ];
 
-        let out_messages = tarut_node.handle_tx(&mut node, message, 1, txn);
+        let out_messages = tarut_node.handle_tx(&mut node, message.src, 1, txn);
 
         // Check that only write operations are replicated
         let replicate_msgs: Vec<_> = out_messages
@@ -385,15 +371,7 @@ mod tests {
         let mut tarut_node = TarutNode::new();
         let mut node = Node::new();
 
-        let message = Message {
-            src: "maelstrom".to_string(),
-            dest: "node1".to_string(),
-            body: MessageBody::Init {
-                msg_id: 1,
-                node_id: "node1".to_string(),
-                node_ids: vec!["node1".to_string(), "node2".to_string()],
-            },
-        };
+        let message = testkit::init("node1", ["node1", "node2"]).src("maelstrom");
 
         let out_messages = tarut_node.handle(&mut node, message);
 
@@ -411,20 +389,11 @@ mod tests {
     #[test]
     fn test_message_handler_txn() {
         let mut tarut_node = TarutNode::new();
-        let mut node = Node::new();
-        node.handle_init(
-            "node1".to_string(),
-            vec!["node1".to_string(), "node2".to_string()],
-        );
+        let mut node = testkit::node("node1", ["node1", "node2"]);
 
-        let message = Message {
-            src: "client".to_string(),
-            dest: "node1".to_string(),
-            body: MessageBody::Txn {
-                msg_id: 1,
-                txn: vec![("w".to_string(), 1, Some(42))],
-            },
-        };
+        let message = testkit::txn([("w", 1, Some(42))])
+            .src("client")
+            .dest("node1");
 
         let out_messages = tarut_node.handle(&mut node, message);
 
@@ -447,7 +416,7 @@ mod tests {
             src: "node2".to_string(),
             dest: "node1".to_string(),
             body: MessageBody::TarutReplicate {
-                msg_id: 1,
+                msg_id: Some(1),
                 txn: vec![("w".to_string(), 1, Some(42), 5)],
             },
         };
@@ -520,4 +489,27 @@ mod tests {
         assert_eq!(results[3], ("r".to_string(), 1, Some(3)));
         assert_eq!(tarut_node.entries.get(&1), Some(&Some(3)));
     }
+
+    #[test]
+    fn test_simulated_txns_show_no_dirty_writes() {
+        let mut sim = Sim::new(3, |_| TarutNode::new());
+        sim.latency(Duration::from_millis(1), Duration::from_millis(10));
+        // Every write to a key is unique, so the checker can tell who wrote what was read
+        for i in 0..12u64 {
+            let dest = format!("n{}", i % 3 + 1);
+            let txn = testkit::txn([
+                ("r", i % 3, None),
+                ("w", i % 3, Some(i + 1)),
+                ("w", (i + 1) % 3, Some(100 + i)),
+                ("r", (i + 2) % 3, None),
+            ]);
+            sim.send(txn.dest(&dest).with_msg_id(i + 1));
+        }
+        sim.run_for(Duration::from_secs(1));
+
+        let report = testkit::check_txn(sim.history());
+        // Read uncommitted only rules out dirty writes
+        assert_eq!(report.dirty_writes, Vec::<Vec<usize>>::new());
+        assert_eq!(report.committed, 12);
+    }
 }
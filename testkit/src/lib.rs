@@ -0,0 +1,70 @@
+//! Test scaffolding for the workload crates, taken as a dev-dependency so each crate's tests
+//! stop rebuilding it by hand: everything in [`maelstrom::testing`] (message and reply
+//! builders, [`TestNode`], [`expect_body!`], checkers, scenarios, and proptest strategies),
+//! plus a node initialized in one line and assertions on what a handler wrote.
+//!
+//! ```ignore
+//! let mut handler = KafkaNode::new();
+//! let mut node = testkit::node("n1", ["n1", "n2"]);
+//! let out = handler.handle(&mut node, testkit::send("c1", "k1", 7));
+//! testkit::assert_route(testkit::only(&out), "n1", "c1");
+//! ```
+
+use maelstrom::{Message, Node};
+
+pub use maelstrom::testing::*;
+
+/// A node already initialized as `id` of `ids`, as the handler would leave it after `init`
+pub fn node<'a>(id: &str, ids: impl IntoIterator<Item = &'a str>) -> Node {
+    let mut node = Node::new();
+    node.handle_init(
+        id.to_string(),
+        ids.into_iter().map(str::to_string).collect(),
+    );
+    node
+}
+
+/// The one message in `out`, failing if there are none or several
+#[track_caller]
+pub fn only(out: &[Message]) -> &Message {
+    match out {
+        [message] => message,
+        _ => panic!("expected exactly one message, got {}: {out:?}", out.len()),
+    }
+}
+
+/// Check that `message` goes from `src` to `dest`
+#[track_caller]
+pub fn assert_route(message: &Message, src: &str, dest: &str) {
+    assert_eq!(
+        (message.src.as_str(), message.dest.as_str()),
+        (src, dest),
+        "misrouted {message:?}"
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_nodes_come_initialized() {
+        let node = node("n2", ["n1", "n2", "n3"]);
+        assert_eq!(node.id, "n2");
+        assert_eq!(node.peers, vec!["n1", "n3"]);
+    }
+
+    #[test]
+    #[should_panic(expected = "expected exactly one message, got 0")]
+    fn test_only_rejects_empty_output() {
+        only(&[]);
+    }
+
+    #[test]
+    fn test_assert_route_checks_both_ends() {
+        let reply = echo("hi").src("n1").dest("c1");
+        assert_route(only(std::slice::from_ref(&reply)), "n1", "c1");
+        let misrouted = std::panic::catch_unwind(|| assert_route(&reply, "n1", "c2"));
+        assert!(misrouted.is_err());
+    }
+}
@@ -1,7 +1,12 @@
 use maelstrom::run_node;
-use uniqueids::node::UniqueIdNode;
+use uniqueids::node::{IdClock, IdFormat, UniqueIdNode};
 
 #[tokio::main]
 async fn main() {
-    run_node(UniqueIdNode::default()).await;
+    run_node(
+        UniqueIdNode::new()
+            .format(IdFormat::from_env())
+            .clock(IdClock::from_env()),
+    )
+    .await;
 }
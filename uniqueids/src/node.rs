@@ -1,104 +1,182 @@
-use maelstrom::{
-    Message, MessageBody,
-    node::{MessageHandler, Node},
-};
-use std::time::{SystemTime, UNIX_EPOCH};
-
-// 42 bits for millis, 10 bits for node id, 12 bits for per-ms sequence
-const TIME_BITS: u64 = 42;
-const NODE_BITS: u64 = 10;
-const SEQ_BITS: u64 = 12;
-const TIME_MASK: u64 = (1u64 << TIME_BITS) - 1; // 0..(2^42-1)
-
-struct IdGen {
-    node_bits: u64,
-    last_ms: u64,
-    seq: u16, // 12 bits
+use maelstrom::config::env_choice;
+use maelstrom::ids::{HighWater, IdGen, IdLayout};
+use maelstrom::{MaelstromError, Message, MessageBody, MessageBodyDispatch, MessageHandler, Node};
+use rand::Rng;
+
+/// How generated ids are laid out
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum IdFormat {
+    /// 64-bit integers: milliseconds, node bits, sequence
+    #[default]
+    Snowflake,
+    /// 128-bit UUIDv7 strings holding the same milliseconds, sequence and node bits, with the
+    /// remaining bits random
+    UuidV7,
 }
 
-impl IdGen {
-    fn new(node_id: &str) -> Self {
-        let node_hash = xxhash_rust::xxh3::xxh3_64(node_id.as_bytes()) & ((1u64 << NODE_BITS) - 1);
-        Self {
-            node_bits: node_hash,
-            last_ms: 0,
-            seq: 0,
-        }
+impl IdFormat {
+    /// Read from `UNIQUE_ID_FORMAT`, `snowflake` or `uuidv7`; the default when unset or unknown
+    pub fn from_env() -> Self {
+        let env = |name: &str| std::env::var(name).ok();
+        let formats = [("snowflake", Self::Snowflake), ("uuidv7", Self::UuidV7)];
+        env_choice(env, "UNIQUE_ID_FORMAT", &formats).unwrap_or_default()
     }
+}
 
-    fn generate(&mut self) -> u64 {
-        let now_ms: u64 = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .expect("time went backwards")
-            .as_millis() as u64;
-        let ts = now_ms & TIME_MASK;
-
-        if ts == self.last_ms {
-            self.seq = self.seq.wrapping_add(1);
-        } else {
-            self.last_ms = ts;
-            self.seq = 0;
-        }
+/// Where the time field of generated ids comes from
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum IdClock {
+    /// The wall clock, so ids roughly tell when they were issued
+    #[default]
+    Wall,
+    /// A local counter alone, for hosts whose clocks cannot be trusted; kept in the data
+    /// directory, which it requires
+    Counter,
+}
 
-        (ts << (NODE_BITS + SEQ_BITS)) | (self.node_bits << SEQ_BITS) | (self.seq as u64)
+impl IdClock {
+    /// Read from `UNIQUE_ID_CLOCK`, `wall` or `counter`; the default when unset or unknown.
+    /// `counter` also needs `MAELSTROM_DATA_DIR`
+    pub fn from_env() -> Self {
+        let env = |name: &str| std::env::var(name).ok();
+        let clocks = [("wall", Self::Wall), ("counter", Self::Counter)];
+        env_choice(env, "UNIQUE_ID_CLOCK", &clocks).unwrap_or_default()
     }
 }
 
+#[derive(Default)]
 pub struct UniqueIdNode {
     id_gen: Option<IdGen>,
+    format: IdFormat,
+    layout: IdLayout,
+    clock: IdClock,
+    /// Kept under the data directory, when there is one, so a restart repeats no ids
+    high_water: Option<HighWater>,
 }
 
-impl Default for UniqueIdNode {
-    fn default() -> Self {
-        Self { id_gen: None }
+impl UniqueIdNode {
+    pub fn new() -> Self {
+        Self::default()
     }
-}
 
-impl MessageHandler for UniqueIdNode {
-    fn handle(&mut self, node: &mut Node, message: Message) -> Vec<Message> {
-        let mut out: Vec<Message> = Vec::new();
-        match message.body {
-            MessageBody::Init {
-                msg_id,
-                node_id,
-                node_ids,
-            } => {
-                node.handle_init(node_id, node_ids);
-                // Establish generator now that we know the node id
-                if self.id_gen.is_none() {
-                    self.id_gen = Some(IdGen::new(&node.id));
-                }
-                out.push(node.init_ok(message.src, msg_id));
+    /// Generate ids laid out as `format`
+    pub fn format(mut self, format: IdFormat) -> Self {
+        self.format = format;
+        self
+    }
+
+    /// Split integer ids as `layout` does; `init` is refused if it cannot tell the cluster's
+    /// nodes apart
+    pub fn layout(mut self, layout: IdLayout) -> Self {
+        self.layout = layout;
+        self
+    }
+
+    /// Take the time field of ids from `clock`; [`IdClock::Counter`] needs a data directory
+    /// to keep its count across restarts, and `init` is refused without one
+    pub fn clock(mut self, clock: IdClock) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    fn start_id_gen(&mut self, node: &Node) -> Result<(), MaelstromError> {
+        let mut id_gen = IdGen::for_node(node, self.layout)?;
+        if self.clock == IdClock::Counter {
+            // Without the wall clock to move it on, only the high-water mark keeps a restarted
+            // counter from issuing its ids again
+            if node.config.data_dir.is_none() {
+                return Err(MaelstromError::crash(
+                    "UNIQUE_ID_CLOCK=counter needs MAELSTROM_DATA_DIR to survive restarts",
+                ));
             }
-            MessageBody::Generate { msg_id } => {
-                // Lazily initialize generator if not already done (e.g., if Node was inited externally)
-                if self.id_gen.is_none() {
-                    self.id_gen = Some(IdGen::new(&node.id));
+            id_gen = id_gen.without_clock();
+        }
+        // A node restarted after a crash starts above every id it issued before
+        if let Some(dir) = &node.config.data_dir {
+            match HighWater::open(dir.join(&node.id).join("ids.high-water")) {
+                Ok(high_water) => {
+                    id_gen.resume_at(high_water.reserved_ms());
+                    self.high_water = Some(high_water);
                 }
-                let unique_id = self
-                    .id_gen
-                    .as_mut()
-                    .expect("id_gen must be initialized")
-                    .generate();
-                let response_msg_id = node.next_msg_id();
-                out.push(node.reply(
-                    message.src,
-                    MessageBody::GenerateOk {
-                        msg_id: response_msg_id,
-                        in_reply_to: msg_id,
-                        id: unique_id,
-                    },
-                ));
+                Err(err) if self.clock == IdClock::Counter => {
+                    return Err(MaelstromError::crash(format!(
+                        "cannot open the id high-water mark: {err}"
+                    )));
+                }
+                Err(err) => node.log(&format!("ids may repeat after a restart: {err}")),
             }
-            _ => {}
         }
-        out
+        self.id_gen = Some(id_gen);
+        Ok(())
+    }
+}
+
+impl MessageHandler for UniqueIdNode {
+    fn handle(&mut self, node: &mut Node, message: Message) -> Vec<Message> {
+        self.dispatch(node, message)
+    }
+
+    fn stats(&self, _node: &Node) -> Option<serde_json::Value> {
+        let id_gen = self.id_gen.as_ref()?;
+        Some(serde_json::json!({ "clock_regressions": id_gen.clock_regressions() }))
+    }
+}
+
+impl MessageBodyDispatch for UniqueIdNode {
+    fn on_init(
+        &mut self,
+        node: &mut Node,
+        src: String,
+        msg_id: u64,
+        node_id: String,
+        node_ids: Vec<String>,
+    ) -> Vec<Message> {
+        node.handle_init(node_id, node_ids);
+        // Establish generator now that we know the node id
+        if self.id_gen.is_none()
+            && let Err(err) = self.start_id_gen(node)
+        {
+            return vec![err.reply(node, src, msg_id)];
+        }
+        vec![node.init_ok(src, msg_id)]
+    }
+
+    fn on_generate(&mut self, node: &mut Node, src: String, msg_id: u64) -> Vec<Message> {
+        // Lazily initialize generator if not already done (e.g., if Node was inited externally)
+        if self.id_gen.is_none()
+            && let Err(err) = self.start_id_gen(node)
+        {
+            return vec![err.reply(node, src, msg_id)];
+        }
+        let id_gen = self.id_gen.as_mut().expect("id_gen must be initialized");
+        let unique_id = match self.format {
+            IdFormat::Snowflake => id_gen.generate().into(),
+            IdFormat::UuidV7 => id_gen.generate_uuid(node.rng.random()).into(),
+        };
+        if let Some(high_water) = &mut self.high_water
+            && let Err(err) = high_water.cover(id_gen.last_ms())
+        {
+            let err = MaelstromError::temporarily_unavailable(format!(
+                "cannot record the id high-water mark: {err}"
+            ));
+            return vec![err.reply(node, src, msg_id)];
+        }
+        vec![
+            node.answer(src, msg_id, |msg_id, in_reply_to| MessageBody::GenerateOk {
+                msg_id,
+                in_reply_to,
+                id: unique_id,
+            }),
+        ]
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use maelstrom::NodeConfig;
+    use maelstrom::ids::{IdParts, SEQ_MAX};
+    use maelstrom_testkit::{self as testkit, TestNode, expect_body};
     use std::collections::HashSet;
 
     #[test]
@@ -106,31 +184,14 @@ mod tests {
         let mut handler = UniqueIdNode::default();
         let mut node = Node::new();
 
-        let init_message = Message {
-            src: "c1".to_string(),
-            dest: "n1".to_string(),
-            body: MessageBody::Init {
-                msg_id: 1,
-                node_id: "n1".to_string(),
-                node_ids: vec!["n1".to_string(), "n2".to_string(), "n3".to_string()],
-            },
-        };
+        let init_message = testkit::init("n1", ["n1", "n2", "n3"]);
 
         let responses = handler.handle(&mut node, init_message);
 
-        assert_eq!(responses.len(), 1);
-        assert_eq!(responses[0].src, "n1");
-        assert_eq!(responses[0].dest, "c1");
+        testkit::assert_route(testkit::only(&responses), "n1", "c1");
 
-        match &responses[0].body {
-            MessageBody::InitOk {
-                msg_id: _,
-                in_reply_to,
-            } => {
-                assert_eq!(in_reply_to, &1);
-            }
-            _ => panic!("Expected InitOk message"),
-        }
+        let in_reply_to = expect_body!(&responses[0].body, InitOk { in_reply_to, .. });
+        assert_eq!(in_reply_to, &1);
 
         // Verify node state was updated
         assert_eq!(node.id, "n1");
@@ -145,9 +206,10 @@ mod tests {
         let unknown_message = Message {
             src: "c1".to_string(),
             dest: "n1".to_string(),
-            body: MessageBody::Echo {
+            body: MessageBody::GenerateOk {
                 msg_id: 1,
-                echo: "test".to_string(),
+                in_reply_to: 1,
+                id: 7.into(),
             },
         };
 
@@ -159,10 +221,8 @@ mod tests {
     #[test]
     fn test_unique_id_node_generates_unique_ids_for_many_requests() {
         let mut handler = UniqueIdNode::default();
-        let mut node = Node::new();
-
         // Initialize node first
-        node.handle_init("n1".to_string(), vec!["n1".to_string()]);
+        let mut node = testkit::node("n1", ["n1"]);
 
         let mut generated_ids = HashSet::new();
 
@@ -177,21 +237,188 @@ mod tests {
             let responses = handler.handle(&mut node, generate_message);
             assert_eq!(responses.len(), 1);
 
-            match &responses[0].body {
-                MessageBody::GenerateOk {
-                    msg_id: _,
+            let (in_reply_to, id) = expect_body!(
+                &responses[0].body,
+                GenerateOk {
                     in_reply_to,
                     id,
-                } => {
-                    assert_eq!(in_reply_to, &i);
-                    // Insert the ID into the set - if it's not unique, insert will return false
-                    assert!(generated_ids.insert(*id), "Generated non-unique ID: {id}");
+                    ..
                 }
-                _ => panic!("Expected GenerateOk message"),
-            }
+            );
+            assert_eq!(in_reply_to, &i);
+            // Insert the ID into the set - if it's not unique, insert will return false
+            assert!(
+                generated_ids.insert(id.clone()),
+                "Generated non-unique ID: {id}"
+            );
         }
 
         // Verify we have exactly 100 unique IDs
         assert_eq!(generated_ids.len(), 100);
     }
+
+    #[test]
+    fn test_initialized_nodes_take_their_index_as_node_bits() {
+        for (index, id) in ["n1", "n2", "n3"].into_iter().enumerate() {
+            let mut handler = UniqueIdNode::default();
+            let mut node = Node::new();
+            handler.handle(&mut node, testkit::init(id, ["n3", "n1", "n2"]));
+            let responses = handler.handle(&mut node, testkit::generate());
+            let id = expect_body!(&responses[0].body, GenerateOk { id, .. });
+            let maelstrom::UniqueId::Int(id) = id else {
+                panic!("expected an integer id, got {id:?}");
+            };
+            assert_eq!(IdParts::decode(*id).node_bits, index as u64);
+        }
+    }
+
+    #[test]
+    fn test_more_generates_than_one_millisecond_holds_stay_unique() {
+        let mut handler = UniqueIdNode::default();
+        let mut node = testkit::node("n1", ["n1"]);
+        let mut ids = HashSet::new();
+        for msg_id in 0..3 * (SEQ_MAX as u64 + 1) {
+            let responses = handler.handle(&mut node, testkit::generate().with_msg_id(msg_id));
+            let id = expect_body!(&responses[0].body, GenerateOk { id, .. });
+            assert!(ids.insert(id.clone()), "Generated non-unique ID: {id}");
+        }
+    }
+
+    #[test]
+    fn test_stats_count_clock_regressions() {
+        let mut id_gen = IdGen::new(3);
+        for now_ms in [100, 40, 41, 100] {
+            id_gen.generate_at(now_ms);
+        }
+
+        let mut handler = UniqueIdNode {
+            id_gen: Some(id_gen),
+            ..UniqueIdNode::default()
+        };
+        let mut node = testkit::node("n1", ["n1"]);
+        let stats = Message {
+            src: "c9".to_string(),
+            dest: "n1".to_string(),
+            body: MessageBody::Stats { msg_id: 9 },
+        };
+        let responses = maelstrom::process(&mut handler, &mut node, stats);
+        let stats = expect_body!(&responses[0].body, StatsOk { stats, .. });
+        assert_eq!(stats["handler"]["clock_regressions"], 2);
+    }
+
+    #[test]
+    fn test_uuid_format_answers_unique_strings() {
+        let mut handler = UniqueIdNode::new().format(IdFormat::UuidV7);
+        let mut node = testkit::node("n1", ["n1", "n2"]);
+        let mut ids = HashSet::new();
+        for msg_id in 0..100 {
+            let responses = handler.handle(&mut node, testkit::generate().with_msg_id(msg_id));
+            let id = expect_body!(&responses[0].body, GenerateOk { id, .. });
+            let maelstrom::UniqueId::Text(uuid) = id else {
+                panic!("expected a string id, got {id:?}");
+            };
+            assert_eq!((uuid.len(), &uuid[14..15]), (36, "7"));
+            assert!(ids.insert(uuid.clone()), "Generated non-unique ID: {uuid}");
+        }
+    }
+
+    #[test]
+    fn test_init_refuses_a_layout_too_narrow_for_the_cluster() {
+        let layout = IdLayout::new().node_bits(1).epoch_ms(1_600_000_000_000);
+        TestNode::new(UniqueIdNode::new().layout(layout))
+            .init(2)
+            .send("c1", testkit::generate())
+            .expect_reply_with(|body| {
+                let MessageBody::GenerateOk { id, .. } = body else {
+                    return false;
+                };
+                let maelstrom::UniqueId::Int(id) = id else {
+                    return false;
+                };
+                layout.decode(*id).timestamp_ms > 1_600_000_000_000
+            });
+
+        let mut handler = UniqueIdNode::new().layout(layout);
+        let mut node = Node::new();
+        let responses = handler.handle(&mut node, testkit::init("n1", ["n1", "n2", "n3"]));
+        let text = expect_body!(&responses[0].body, Error { text, .. });
+        assert_eq!(text.as_deref(), Some("3 nodes do not fit in 1 node bits"));
+    }
+
+    #[test]
+    fn test_ids_after_a_restart_with_a_data_dir_stay_above_the_ones_before() {
+        let dir = std::env::temp_dir().join(format!("uniqueids-data-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let config = NodeConfig::new().data_dir(&dir);
+        let generate = |handler: &mut UniqueIdNode, node: &mut Node| {
+            let responses = handler.handle(node, testkit::generate());
+            match expect_body!(&responses[0].body, GenerateOk { id, .. }) {
+                maelstrom::UniqueId::Int(id) => *id,
+                id => panic!("expected an integer id, got {id:?}"),
+            }
+        };
+
+        let mut handler = UniqueIdNode::new();
+        let mut node = Node::with_config(config.clone());
+        handler.handle(&mut node, testkit::init("n1", ["n1"]));
+        let before = generate(&mut handler, &mut node);
+
+        let mut handler = UniqueIdNode::new();
+        let mut node = Node::with_config(config);
+        handler.handle(&mut node, testkit::init("n1", ["n1"]));
+        let after = generate(&mut handler, &mut node);
+        assert!(IdParts::decode(after).timestamp_ms > IdParts::decode(before).timestamp_ms);
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_counter_clock_ids_count_up_without_the_wall_clock() {
+        let dir = std::env::temp_dir().join(format!("uniqueids-counter-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let config = NodeConfig::new().data_dir(&dir);
+        let start = |node: &mut Node| {
+            let mut handler = UniqueIdNode::new().clock(IdClock::Counter);
+            handler.handle(node, testkit::init("n2", ["n1", "n2"]));
+            handler
+        };
+        let generate = |handler: &mut UniqueIdNode, node: &mut Node| {
+            let responses = handler.handle(node, testkit::generate());
+            match expect_body!(&responses[0].body, GenerateOk { id, .. }) {
+                maelstrom::UniqueId::Int(id) => IdParts::decode(*id),
+                id => panic!("expected an integer id, got {id:?}"),
+            }
+        };
+
+        let mut node = Node::with_config(config.clone());
+        let mut handler = start(&mut node);
+        let ids: Vec<IdParts> = (0..SEQ_MAX as u64 + 3)
+            .map(|_| generate(&mut handler, &mut node))
+            .collect();
+        assert!(ids.windows(2).all(|pair| pair[0] < pair[1]));
+        assert!(ids.iter().all(|id| id.node_bits == 1));
+        assert_eq!(ids.last().unwrap().timestamp_ms, 1);
+
+        // The counter carries on past the restart
+        let mut node = Node::with_config(config);
+        let mut handler = start(&mut node);
+        assert!(generate(&mut handler, &mut node) > *ids.last().unwrap());
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_counter_clock_refuses_init_without_a_data_dir() {
+        let mut handler = UniqueIdNode::new().clock(IdClock::Counter);
+        let mut node = Node::new();
+        let responses = handler.handle(&mut node, testkit::init("n1", ["n1"]));
+        let (code, text) = expect_body!(&responses[0].body, Error { code, text, .. });
+        assert_eq!(*code, maelstrom::ErrorCode::Crash);
+        assert!(text.as_deref().unwrap().contains("MAELSTROM_DATA_DIR"));
+
+        // Nor does a generate start one lazily
+        let responses = handler.handle(&mut node, testkit::generate());
+        let code = expect_body!(&responses[0].body, Error { code, .. });
+        assert_eq!(*code, maelstrom::ErrorCode::Crash);
+    }
 }